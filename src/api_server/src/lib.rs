@@ -8,10 +8,13 @@
 //! It is constructed on top of an HTTP Server that uses Unix Domain Sockets and `EPOLL` to
 //! handle multiple connections on the same thread.
 mod parsed_request;
+mod read_only_server;
 mod request;
+#[cfg(test)]
+mod test_client;
 
 use std::path::PathBuf;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 
 use logger::{
     debug, error, info, update_metric_with_elapsed_time, warn, ProcessTimeReporter, METRICS,
@@ -26,7 +29,12 @@ use utils::eventfd::EventFd;
 use vmm::rpc_interface::{VmmAction, VmmActionError, VmmData};
 use vmm::vmm_config::snapshot::SnapshotType;
 
-use crate::parsed_request::{ParsedRequest, RequestAction};
+use crate::parsed_request::{ParsedRequest, ParsingInfo, RequestAction};
+pub use crate::read_only_server::ReadOnlyApiServer;
+use crate::request::audit::AuditLog;
+#[cfg(all(feature = "balloon", feature = "faascale-mem"))]
+use crate::request::balloon::balloon_to_faascale_mem;
+use crate::request::routes::memory_routes;
 use crate::Error::ServerCreation;
 
 /// Shorthand type for a request containing a boxed VmmAction.
@@ -43,8 +51,8 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
-/// Structure associated with the API server implementation.
-pub struct ApiServer {
+/// Channel between an API server and the VMM.
+struct VmmChannel {
     /// Sender which allows passing messages to the VMM.
     api_request_sender: mpsc::Sender<ApiRequest>,
     /// Receiver which collects messages from the VMM.
@@ -52,8 +60,33 @@ pub struct ApiServer {
     /// FD on which we notify the VMM that we have sent at least one
     /// `VmmRequest`.
     to_vmm_fd: EventFd,
+}
+
+impl VmmChannel {
+    // Forwards `vmm_action` to the VMM thread and waits for its outcome.
+    fn send(&self, vmm_action: ApiRequest) -> std::result::Result<VmmData, VmmActionError> {
+        self.api_request_sender
+            .send(vmm_action)
+            .expect("Failed to send VMM message");
+        self.to_vmm_fd.write(1).expect("Cannot update send VMM fd");
+        *(self.vmm_response_receiver.recv().expect("VMM disconnected"))
+    }
+}
+
+/// Structure associated with the API server implementation.
+pub struct ApiServer {
+    /// Channel to the VMM.
+    vmm_channel: VmmChannel,
+    /// Requests changing the memory devices, shared by the API servers of the process.
+    audit_log: Arc<Mutex<AuditLog>>,
     /// If this flag is set, the API thread will go down.
     shutdown_flag: bool,
+    /// If this flag is set, the `/balloon` requests are served by the faascale-mem device.
+    #[cfg_attr(
+        not(all(feature = "balloon", feature = "faascale-mem")),
+        allow(dead_code)
+    )]
+    balloon_compat: bool,
 }
 
 impl ApiServer {
@@ -66,13 +99,42 @@ impl ApiServer {
         to_vmm_fd: EventFd,
     ) -> Self {
         ApiServer {
-            api_request_sender,
-            vmm_response_receiver,
-            to_vmm_fd,
+            vmm_channel: VmmChannel {
+                api_request_sender,
+                vmm_response_receiver,
+                to_vmm_fd,
+            },
+            audit_log: Arc::new(Mutex::new(AuditLog::default())),
             shutdown_flag: false,
+            balloon_compat: false,
         }
     }
 
+    /// Returns a server only serving the GET requests, for monitoring agents which must not be
+    /// able to change the microVM. It records the requests it receives in the audit log of this
+    /// server, and reaches the VMM through the channel given.
+    pub fn read_only_server(
+        &self,
+        api_request_sender: mpsc::Sender<ApiRequest>,
+        vmm_response_receiver: mpsc::Receiver<ApiResponse>,
+        to_vmm_fd: EventFd,
+    ) -> ReadOnlyApiServer {
+        ReadOnlyApiServer::new(
+            VmmChannel {
+                api_request_sender,
+                vmm_response_receiver,
+                to_vmm_fd,
+            },
+            self.audit_log.clone(),
+        )
+    }
+
+    /// Serves the `/balloon` requests with the faascale-mem device, for orchestrators only
+    /// speaking the upstream balloon API.
+    pub fn set_balloon_compat(&mut self, balloon_compat: bool) {
+        self.balloon_compat = balloon_compat;
+    }
+
     /// Starts the HTTP Server by binding to the socket path provided as
     /// an argument.
     ///
@@ -225,28 +287,48 @@ impl ApiServer {
         request: &Request,
         request_processing_start_us: u64,
     ) -> Response {
-        match ParsedRequest::try_from_request(request).map(|r| r.into_parts()) {
-            Ok((req_action, mut parsing_info)) => {
+        let parsed_request = ParsedRequest::try_from_request(request).map(|r| r.into_parts());
+        #[cfg(all(feature = "balloon", feature = "faascale-mem"))]
+        let parsed_request =
+            parsed_request.and_then(|(req_action, parsing_info)| match req_action {
+                RequestAction::Sync(vmm_action) if self.balloon_compat => Ok((
+                    RequestAction::Sync(Box::new(balloon_to_faascale_mem(*vmm_action, || {
+                        self.guest_mem_mib()
+                    })?)),
+                    parsing_info,
+                )),
+                req_action => Ok((req_action, parsing_info)),
+            });
+        let response = match parsed_request {
+            Ok((req_action, parsing_info)) => {
                 let mut response = match req_action {
                     RequestAction::Sync(vmm_action) => {
                         self.serve_vmm_action_request(vmm_action, request_processing_start_us)
                     }
+                    RequestAction::GetAuditLog => ParsedRequest::success_response_with_data(
+                        &*self.audit_log.lock().expect("Poisoned lock"),
+                    ),
+                    RequestAction::GetRoutes => {
+                        ParsedRequest::success_response_with_data(&memory_routes())
+                    }
                     RequestAction::ShutdownInternal => {
                         self.shutdown_flag = true;
                         Response::new(Version::Http11, StatusCode::NoContent)
                     }
                 };
-                if let Some(message) = parsing_info.take_deprecation_message() {
-                    warn!("{}", message);
-                    response.set_deprecation();
-                }
+                Self::annotate_response(&mut response, parsing_info);
                 response
             }
             Err(err) => {
                 error!("{}", err);
                 err.into()
             }
-        }
+        };
+        self.audit_log
+            .lock()
+            .expect("Poisoned lock")
+            .record(request, response.status());
+        response
     }
 
     fn serve_vmm_action_request(
@@ -273,11 +355,7 @@ impl ApiServer {
             _ => None,
         };
 
-        self.api_request_sender
-            .send(vmm_action)
-            .expect("Failed to send VMM message");
-        self.to_vmm_fd.write(1).expect("Cannot update send VMM fd");
-        let vmm_outcome = *(self.vmm_response_receiver.recv().expect("VMM disconnected"));
+        let vmm_outcome = self.send_vmm_action(vmm_action);
         let response = ParsedRequest::convert_to_response(&vmm_outcome);
 
         if vmm_outcome.is_ok() {
@@ -290,6 +368,38 @@ impl ApiServer {
         response
     }
 
+    // Forwards `vmm_action` to the VMM thread and waits for its outcome.
+    fn send_vmm_action(
+        &self,
+        vmm_action: ApiRequest,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        self.vmm_channel.send(vmm_action)
+    }
+
+    // Flags the deprecated requests in the response, and tells the agents which version of the
+    // faascale-mem API served the request.
+    fn annotate_response(response: &mut Response, mut parsing_info: ParsingInfo) {
+        if let Some(message) = parsing_info.take_deprecation_message() {
+            warn!("{}", message);
+            response.set_deprecation();
+        }
+        if let Some(version) = parsing_info.api_version() {
+            response.set_server(&format!("Firecracker API faascale-mem/{}", version));
+        }
+    }
+
+    // Returns the size of the guest memory, which the balloon requests are relative to.
+    #[cfg(all(feature = "balloon", feature = "faascale-mem"))]
+    fn guest_mem_mib(&self) -> std::result::Result<usize, parsed_request::Error> {
+        match self.send_vmm_action(Box::new(VmmAction::GetVmMachineConfig)) {
+            Ok(VmmData::MachineConfiguration(machine_config)) => Ok(machine_config.mem_size_mib),
+            outcome => Err(parsed_request::Error::Generic(
+                StatusCode::InternalServerError,
+                format!("Cannot read the guest memory size: {:?}", outcome),
+            )),
+        }
+    }
+
     /// An HTTP response which also includes a body.
     pub(crate) fn json_response<T: Into<String>>(status: StatusCode, body: T) -> Response {
         let mut response = Response::new(Version::Http11, status);
@@ -316,10 +426,18 @@ mod tests {
     use vmm::builder::StartMicrovmError;
     use vmm::rpc_interface::VmmActionError;
     use vmm::seccomp_filters::{get_filters, SeccompConfig};
+    #[cfg(all(feature = "balloon", feature = "faascale-mem"))]
+    use vmm::vmm_config::balloon::{BalloonUpdateConfig, BalloonUpdateStatsConfig};
+    #[cfg(all(feature = "balloon", feature = "faascale-mem"))]
+    use vmm::vmm_config::faascale_mem::FaascaleMemUpdateConfig;
     use vmm::vmm_config::instance_info::InstanceInfo;
+    #[cfg(all(feature = "balloon", feature = "faascale-mem"))]
+    use vmm::vmm_config::machine_config::MachineConfig;
     use vmm::vmm_config::snapshot::CreateSnapshotParams;
 
     use super::*;
+    #[cfg(all(feature = "balloon", feature = "faascale-mem"))]
+    use crate::test_client::{ApiResponse, MemoryDeviceRequest, TestClient};
 
     #[test]
     fn test_serve_vmm_action_request() {
@@ -359,6 +477,7 @@ mod tests {
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
                 version: None,
+                sparse: false,
             })),
             start_time_us,
         );
@@ -373,6 +492,7 @@ mod tests {
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
                 version: None,
+                sparse: false,
             })),
             start_time_us,
         );
@@ -419,6 +539,13 @@ mod tests {
         let response = api_server.handle_request(&req, 0);
         assert_eq!(response.status(), StatusCode::OK);
 
+        // Test a Get Routes request, answered without involving the VMM.
+        sender.write_all(b"GET /routes HTTP/1.1\r\n\r\n").unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        let response = api_server.handle_request(&req, 0);
+        assert_eq!(response.status(), StatusCode::OK);
+
         // Test erroneous request.
         sender
             .write_all(
@@ -433,6 +560,184 @@ mod tests {
         assert_eq!(response.status(), StatusCode::BadRequest);
     }
 
+    #[test]
+    #[cfg(all(feature = "balloon", feature = "faascale-mem"))]
+    fn test_handle_request_balloon_compat() {
+        let to_vmm_fd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let (api_request_sender, from_api) = channel();
+        let (to_api, vmm_response_receiver) = channel();
+
+        let mut api_server = ApiServer::new(api_request_sender, vmm_response_receiver, to_vmm_fd);
+        api_server.set_balloon_compat(true);
+        let mut client = TestClient::new();
+
+        // A statistics update is forwarded to the faascale-mem device.
+        to_api.send(Box::new(Ok(VmmData::Empty))).unwrap();
+        let req = client.send(&MemoryDeviceRequest::PatchBalloonStats(
+            BalloonUpdateStatsConfig {
+                stats_polling_interval_s: 1,
+                if_match_epoch: None,
+            },
+        ));
+        let response = api_server.handle_request(&req, 0);
+        assert_eq!(response.status(), StatusCode::NoContent);
+        assert!(matches!(
+            *from_api.try_recv().unwrap(),
+            VmmAction::UpdateFaascaleMemStatistics(_)
+        ));
+
+        // A resize asks the guest to keep the rest of its memory populated.
+        to_api
+            .send(Box::new(Ok(VmmData::MachineConfiguration(MachineConfig {
+                mem_size_mib: 1024,
+                ..Default::default()
+            }))))
+            .unwrap();
+        to_api.send(Box::new(Ok(VmmData::Empty))).unwrap();
+        let req = client.send(&MemoryDeviceRequest::PatchBalloon(BalloonUpdateConfig {
+            amount_mib: 64,
+            if_match_epoch: None,
+        }));
+        let response = api_server.handle_request(&req, 0);
+        assert_eq!(response.status(), StatusCode::NoContent);
+        assert_eq!(*from_api.try_recv().unwrap(), VmmAction::GetVmMachineConfig);
+        assert_eq!(
+            *from_api.try_recv().unwrap(),
+            VmmAction::UpdateFaascaleMem(FaascaleMemUpdateConfig { target_mib: 960 })
+        );
+
+        // The GET requests are answered by the faascale-mem device.
+        to_api.send(Box::new(Ok(VmmData::Empty))).unwrap();
+        let req = client.send(&MemoryDeviceRequest::GetBalloonStats);
+        api_server.handle_request(&req, 0);
+        assert_eq!(
+            *from_api.try_recv().unwrap(),
+            VmmAction::GetFaascaleMemStats
+        );
+
+        // A resize the faascale-mem device cannot serve is refused without reaching the VMM.
+        let req = client.send(&MemoryDeviceRequest::PatchBalloon(BalloonUpdateConfig {
+            amount_mib: 64,
+            if_match_epoch: Some(1),
+        }));
+        let response = ApiResponse::from_response(&api_server.handle_request(&req, 0));
+        assert_eq!(response.status, StatusCode::BadRequest);
+        assert!(response.fault_message().is_some());
+        assert!(from_api.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_handle_request_read_only() {
+        let to_vmm_fd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let (api_request_sender, from_api) = channel();
+        let (_to_api, vmm_response_receiver) = channel();
+        let api_server = ApiServer::new(api_request_sender, vmm_response_receiver, to_vmm_fd);
+
+        let to_vmm_fd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let (api_request_sender, from_read_only_api) = channel();
+        let (to_read_only_api, vmm_response_receiver) = channel();
+        let mut read_only_server =
+            api_server.read_only_server(api_request_sender, vmm_response_receiver, to_vmm_fd);
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+
+        // The GET requests reach the VMM through the channel of the read-only server.
+        to_read_only_api
+            .send(Box::new(Ok(VmmData::InstanceInformation(
+                InstanceInfo::default(),
+            ))))
+            .unwrap();
+        sender.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        let response = read_only_server.handle_request(&req);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            *from_read_only_api.try_recv().unwrap(),
+            VmmAction::GetVmInstanceInfo
+        );
+        assert!(from_api.try_recv().is_err());
+
+        // The other requests are refused without reaching the VMM.
+        sender
+            .write_all(
+                b"PATCH /vm HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 21\r\n\r\n{ \"state\": \"Paused\" }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        let response = read_only_server.handle_request(&req);
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        sender
+            .write_all(b"PUT /shutdown-internal HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        let response = read_only_server.handle_request(&req);
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        assert!(from_read_only_api.try_recv().is_err());
+        assert!(from_api.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_handle_request_audit_log() {
+        let to_vmm_fd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let (api_request_sender, from_api) = channel();
+        let (_to_api, vmm_response_receiver) = channel();
+
+        let mut api_server = ApiServer::new(api_request_sender, vmm_response_receiver, to_vmm_fd);
+        let (read_only_request_sender, from_read_only_api) = channel();
+        let (_to_read_only_api, read_only_response_receiver) = channel();
+        let mut read_only_server = api_server.read_only_server(
+            read_only_request_sender,
+            read_only_response_receiver,
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        );
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+
+        // Failed requests are recorded too, along with the ones refused by the read-only
+        // socket.
+        let body = b"{ \"token\": \"bogus\" }";
+        let mut patch_request = || {
+            sender
+                .write_all(
+                    b"PATCH /memory-devices/resume HTTP/1.1\r\n\
+                    Content-Type: application/json\r\n\
+                    Content-Length: 20\r\n\r\n",
+                )
+                .unwrap();
+            sender.write_all(body).unwrap();
+            assert!(connection.try_read().is_ok());
+            connection.pop_parsed_request().unwrap()
+        };
+        let response = api_server.handle_request(&patch_request(), 0);
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        let response = read_only_server.handle_request(&patch_request());
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        assert!(from_api.try_recv().is_err());
+        assert!(from_read_only_api.try_recv().is_err());
+
+        // Both sockets serve the log without involving the VMM.
+        sender
+            .write_all(b"GET /audit/memory-devices HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        let response = read_only_server.handle_request(&req);
+        assert_eq!(response.status(), StatusCode::OK);
+        let audit_log = api_server.audit_log.lock().unwrap();
+        assert_eq!(audit_log.records.len(), 2);
+        for record in &audit_log.records {
+            assert_eq!(record.method, "PATCH");
+            assert_eq!(record.path, "/memory-devices/resume");
+            assert_eq!(record.body.as_bytes(), body);
+            assert_eq!(record.result, "BadRequest");
+        }
+    }
+
     #[test]
     fn test_bind_and_run() {
         let mut tmp_socket = TempFile::new().unwrap();
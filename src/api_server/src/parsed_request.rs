@@ -9,21 +9,30 @@ use vmm::rpc_interface::{VmmAction, VmmActionError};
 
 use super::VmmData;
 use crate::request::actions::parse_put_actions;
+use crate::request::audit::parse_get_audit;
+#[cfg(feature = "balloon")]
 use crate::request::balloon::{parse_get_balloon, parse_patch_balloon, parse_put_balloon};
-use crate::request::faascale_mem::{parse_get_faascale_mem, parse_patch_faascale_mem, parse_put_faascale_mem};
 use crate::request::boot_source::parse_put_boot_source;
 use crate::request::cpu_configuration::parse_put_cpu_config;
+use crate::request::debug::parse_get_debug;
 use crate::request::drive::{parse_patch_drive, parse_put_drive};
 use crate::request::entropy::parse_put_entropy;
+#[cfg(feature = "faascale-mem")]
+use crate::request::faascale_mem::{
+    faascale_mem_api_version, parse_get_faascale_mem, parse_patch_faascale_mem,
+    parse_patch_faascale_mem_without_body, parse_put_faascale_mem,
+};
 use crate::request::instance_info::parse_get_instance_info;
 use crate::request::logger::parse_put_logger;
 use crate::request::machine_configuration::{
     parse_get_machine_config, parse_patch_machine_config, parse_put_machine_config,
 };
+use crate::request::memory_devices::{parse_get_memory_devices, parse_patch_memory_devices};
 use crate::request::metrics::parse_put_metrics;
 use crate::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
 use crate::request::net::{parse_patch_net, parse_put_net};
-use crate::request::snapshot::{parse_patch_vm_state, parse_put_snapshot};
+use crate::request::routes::parse_get_routes;
+use crate::request::snapshot::{parse_get_snapshot, parse_patch_vm_state, parse_put_snapshot};
 use crate::request::version::parse_get_version;
 use crate::request::vsock::parse_put_vsock;
 use crate::ApiServer;
@@ -31,6 +40,8 @@ use crate::ApiServer;
 #[cfg_attr(test, derive(Debug))]
 pub(crate) enum RequestAction {
     Sync(Box<VmmAction>),
+    GetAuditLog,
+    GetRoutes,
     ShutdownInternal, // !!! not an API, used by shutdown to thread::join the API thread
 }
 
@@ -38,6 +49,8 @@ pub(crate) enum RequestAction {
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub(crate) struct ParsingInfo {
     deprecation_message: Option<String>,
+    // Version of the faascale-mem API serving the request, for the faascale-mem routes.
+    api_version: Option<&'static str>,
 }
 
 impl ParsingInfo {
@@ -51,6 +64,10 @@ impl ParsingInfo {
     pub fn take_deprecation_message(&mut self) -> Option<String> {
         self.deprecation_message.take()
     }
+
+    pub fn api_version(&self) -> Option<&'static str> {
+        self.api_version
+    }
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -75,6 +92,13 @@ impl ParsedRequest {
         &mut self.parsing_info
     }
 
+    // Records the version of the faascale-mem API serving the request, if any.
+    #[cfg(feature = "faascale-mem")]
+    fn with_api_version(mut self, api_version: Option<&'static str>) -> Self {
+        self.parsing_info.api_version = api_version;
+        self
+    }
+
     pub(crate) fn try_from_request(request: &Request) -> Result<ParsedRequest, Error> {
         let request_uri = request.uri().get_abs_path().to_string();
         log_received_api_request(describe(
@@ -83,33 +107,22 @@ impl ParsedRequest {
             request.body.as_ref(),
         ));
 
-        // Split request uri by '/' by doing:
-        // 1. Trim starting '/' characters
-        // 2. Splitting by '/'
-        let path_tokens: Vec<&str> = request_uri
-            .trim_start_matches('/')
-            .split_terminator('/')
-            .collect();
-        let path = if path_tokens.is_empty() {
-            ""
-        } else {
-            path_tokens[0]
-        };
+        let path_tokens = split_path(&request_uri);
+        #[cfg(feature = "faascale-mem")]
+        let (path_tokens, api_version) = faascale_mem_api_version(path_tokens)?;
+        let path = path_tokens.first().copied().unwrap_or("");
 
-        match (request.method(), path, request.body.as_ref()) {
-            (Method::Get, "", None) => parse_get_instance_info(),
-            (Method::Get, "balloon", None) => parse_get_balloon(path_tokens.get(1)),
-            (Method::Get, "faascale_mem", None) => parse_get_faascale_mem(path_tokens.get(1)),
-            (Method::Get, "version", None) => parse_get_version(),
-            (Method::Get, "vm", None) if path_tokens.get(1) == Some(&"config") => {
-                Ok(ParsedRequest::new_sync(VmmAction::GetFullVmConfig))
-            }
-            (Method::Get, "machine-config", None) => parse_get_machine_config(),
+        let parsed_request = match (request.method(), path, request.body.as_ref()) {
             (Method::Get, "mmds", None) => parse_get_mmds(),
+            (Method::Get, _, None) => parse_read_only_get(path, &path_tokens),
             (Method::Get, _, Some(_)) => method_to_error(Method::Get),
             (Method::Put, "actions", Some(body)) => parse_put_actions(body),
+            #[cfg(feature = "balloon")]
             (Method::Put, "balloon", Some(body)) => parse_put_balloon(body),
-            (Method::Put, "faascale_mem", Some(body)) => parse_put_faascale_mem(body),
+            #[cfg(feature = "faascale-mem")]
+            (Method::Put, "faascale_mem", Some(body)) => {
+                parse_put_faascale_mem(body, path_tokens.get(1), path_tokens.get(2))
+            }
             (Method::Put, "boot-source", Some(body)) => parse_put_boot_source(body),
             (Method::Put, "cpu-config", Some(body)) => parse_put_cpu_config(body),
             (Method::Put, "drives", Some(body)) => parse_put_drive(body, path_tokens.get(1)),
@@ -127,10 +140,21 @@ impl ParsedRequest {
             (Method::Put, "vsock", Some(body)) => parse_put_vsock(body),
             (Method::Put, "entropy", Some(body)) => parse_put_entropy(body),
             (Method::Put, _, None) => method_to_error(Method::Put),
+            #[cfg(feature = "balloon")]
             (Method::Patch, "balloon", Some(body)) => parse_patch_balloon(body, path_tokens.get(1)),
-            (Method::Patch, "faascale_mem", Some(body)) => parse_patch_faascale_mem(body, path_tokens.get(1)),
+            #[cfg(feature = "faascale-mem")]
+            (Method::Patch, "faascale_mem", Some(body)) => {
+                parse_patch_faascale_mem(body, path_tokens.get(1), path_tokens.get(2))
+            }
+            #[cfg(feature = "faascale-mem")]
+            (Method::Patch, "faascale_mem", None) => {
+                parse_patch_faascale_mem_without_body(path_tokens.get(1), path_tokens.get(2))
+            }
             (Method::Patch, "drives", Some(body)) => parse_patch_drive(body, path_tokens.get(1)),
             (Method::Patch, "machine-config", Some(body)) => parse_patch_machine_config(body),
+            (Method::Patch, "memory-devices", Some(body)) => {
+                parse_patch_memory_devices(body, path_tokens.get(1))
+            }
             (Method::Patch, "mmds", Some(body)) => parse_patch_mmds(body),
             (Method::Patch, "network-interfaces", Some(body)) => {
                 parse_patch_net(body, path_tokens.get(1))
@@ -140,7 +164,39 @@ impl ParsedRequest {
             (method, unknown_uri, _) => {
                 Err(Error::InvalidPathMethod(unknown_uri.to_string(), method))
             }
-        }
+        }?;
+        #[cfg(feature = "faascale-mem")]
+        let parsed_request = parsed_request.with_api_version(api_version);
+        Ok(parsed_request)
+    }
+
+    /// Parses a request received by a read-only API server, which only serves the GET requests
+    /// reporting the state of the microVM. The MMDS data store is left out, as it may hold
+    /// secrets meant for the guest.
+    pub(crate) fn try_from_read_only_request(request: &Request) -> Result<ParsedRequest, Error> {
+        let request_uri = request.uri().get_abs_path().to_string();
+        log_received_api_request(describe(
+            request.method(),
+            request_uri.as_str(),
+            request.body.as_ref(),
+        ));
+
+        let path_tokens = split_path(&request_uri);
+        #[cfg(feature = "faascale-mem")]
+        let (path_tokens, api_version) = faascale_mem_api_version(path_tokens)?;
+        let path = path_tokens.first().copied().unwrap_or("");
+
+        let parsed_request = match (request.method(), path, request.body.as_ref()) {
+            (Method::Get, "mmds", _) => {
+                Err(Error::InvalidPathMethod(path.to_string(), Method::Get))
+            }
+            (Method::Get, _, None) => parse_read_only_get(path, &path_tokens),
+            (Method::Get, _, Some(_)) => method_to_error(Method::Get),
+            (method, path, _) => Err(Error::InvalidPathMethod(path.to_string(), method)),
+        }?;
+        #[cfg(feature = "faascale-mem")]
+        let parsed_request = parsed_request.with_api_version(api_version);
+        Ok(parsed_request)
     }
 
     pub(crate) fn success_response_with_data<T>(body_data: &T) -> Response
@@ -176,16 +232,62 @@ impl ParsedRequest {
                 VmmData::MachineConfiguration(vm_config) => {
                     Self::success_response_with_data(vm_config)
                 }
+                VmmData::MemoryDevicesQuiesced(token) => Self::success_response_with_data(token),
+                VmmData::MemoryDevicesVerification(verification) => {
+                    Self::success_response_with_data(verification)
+                }
+                #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+                VmmData::MemoryOverlays(overlays) => Self::success_response_with_data(overlays),
                 VmmData::MmdsValue(value) => Self::success_response_with_mmds_value(value),
+                #[cfg(feature = "balloon")]
                 VmmData::BalloonConfig(balloon_config) => {
                     Self::success_response_with_data(balloon_config)
                 }
+                #[cfg(feature = "faascale-mem")]
                 VmmData::FaascaleMemConfig(faascale_mem_config) => {
                     Self::success_response_with_data(faascale_mem_config)
                 }
+                #[cfg(feature = "balloon")]
                 VmmData::BalloonStats(stats) => Self::success_response_with_data(stats),
+                #[cfg(feature = "balloon")]
+                VmmData::BalloonConfigSpace(config_space) => {
+                    Self::success_response_with_data(config_space)
+                }
+                #[cfg(feature = "balloon")]
+                VmmData::BalloonUpdateClamped(clamped) => Self::success_response_with_data(clamped),
+                #[cfg(feature = "faascale-mem")]
                 VmmData::FaascaleMemStats(stats) => Self::success_response_with_data(stats),
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemHealth(health) => Self::success_response_with_data(health),
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemBudget(budget) => Self::success_response_with_data(budget),
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemFootprint(footprint) => {
+                    Self::success_response_with_data(footprint)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemBlocks(blocks) => Self::success_response_with_data(blocks),
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemHeatmap(heatmap) => Self::success_response_with_data(heatmap),
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemErrors(errors) => Self::success_response_with_data(errors),
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemWarmReport(report) => Self::success_response_with_data(report),
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemMetadata(metadata) => {
+                    Self::success_response_with_data(metadata)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemEstimate(estimate) => {
+                    Self::success_response_with_data(estimate)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemConfigSpace(config_space) => {
+                    Self::success_response_with_data(config_space)
+                }
                 VmmData::InstanceInformation(info) => Self::success_response_with_data(info),
+                VmmData::SnapshotCreated(info) => Self::success_response_with_data(info),
+                VmmData::SnapshotMemoryInfo(info) => Self::success_response_with_data(info),
                 VmmData::VmmVersion(version) => Self::success_response_with_data(
                     &serde_json::json!({ "firecracker_version": version.as_str() }),
                 ),
@@ -222,6 +324,42 @@ impl ParsedRequest {
     }
 }
 
+// Splits the request uri by '/' by doing:
+// 1. Trim starting '/' characters
+// 2. Splitting by '/'
+pub(crate) fn split_path(request_uri: &str) -> Vec<&str> {
+    request_uri
+        .trim_start_matches('/')
+        .split_terminator('/')
+        .collect()
+}
+
+// Parses the GET requests which only report the state of the microVM, served by both the API
+// socket and the read-only API socket.
+fn parse_read_only_get(path: &str, path_tokens: &[&str]) -> Result<ParsedRequest, Error> {
+    match path {
+        "" => parse_get_instance_info(),
+        "audit" => parse_get_audit(path_tokens.get(1)),
+        #[cfg(feature = "balloon")]
+        "balloon" => parse_get_balloon(path_tokens.get(1)),
+        #[cfg(feature = "faascale-mem")]
+        "faascale_mem" => parse_get_faascale_mem(path_tokens.get(1)),
+        "debug" => parse_get_debug(path_tokens.get(1), path_tokens.get(2)),
+        "version" => parse_get_version(),
+        "vm" if path_tokens.get(1) == Some(&"config") => {
+            Ok(ParsedRequest::new_sync(VmmAction::GetFullVmConfig))
+        }
+        "machine-config" => parse_get_machine_config(),
+        "memory-devices" => parse_get_memory_devices(path_tokens.get(1)),
+        "routes" => parse_get_routes(),
+        "snapshot" => parse_get_snapshot(path_tokens.get(1)),
+        unknown_uri => Err(Error::InvalidPathMethod(
+            unknown_uri.to_string(),
+            Method::Get,
+        )),
+    }
+}
+
 /// Helper function for writing the received API requests to the log.
 ///
 /// The `info` macro is used for logging.
@@ -328,11 +466,37 @@ pub mod tests {
     use vmm::cpu_config::templates::test_utils::build_test_template;
     use vmm::resources::VmmConfig;
     use vmm::rpc_interface::VmmActionError;
-    use vmm::vmm_config::balloon::{BalloonDeviceConfig, BalloonStats};
+    #[cfg(feature = "balloon")]
+    use vmm::vmm_config::balloon::{
+        BalloonConfigSpace, BalloonDeviceConfig, BalloonStats, BalloonUpdateClamped,
+    };
+    #[cfg(feature = "faascale-mem")]
+    use vmm::vmm_config::faascale_mem::{
+        FaascaleMemBlock, FaascaleMemBlocks, FaascaleMemBudget, FaascaleMemConfigSpace,
+        FaascaleMemErrorRecord, FaascaleMemErrors, FaascaleMemEstimate, FaascaleMemEstimateConfig,
+        FaascaleMemEstimateLimit, FaascaleMemFootprint, FaascaleMemHealth, FaascaleMemHeatmap,
+        FaascaleMemHeatmapBucket, FaascaleMemHotplugSlotConfig, FaascaleMemMetadata,
+        FaascaleMemMlockConfig, FaascaleMemOperation, FaascaleMemPollStatsConfig,
+        FaascaleMemPopulateConfig, FaascaleMemPopulationConfig, FaascaleMemRateLimiterConfig,
+        FaascaleMemRehydrateConfig, FaascaleMemTemplateBlock, FaascaleMemUpdateConfig,
+        FaascaleMemWarmReport,
+    };
     use vmm::vmm_config::instance_info::InstanceInfo;
     use vmm::vmm_config::machine_config::MachineConfig;
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    use vmm::vmm_config::memory_devices::MemoryOverlaysInfo;
+    use vmm::vmm_config::memory_devices::{
+        MemoryDevicesInconsistency, MemoryDevicesQuiesceToken, MemoryDevicesVerification,
+    };
+    use vmm::vmm_config::snapshot::{
+        MemBackendType, SnapshotCreateInfo, SnapshotMemoryInfo, SnapshotMemoryRange,
+    };
+    #[cfg(feature = "faascale-mem")]
+    use vmm::vmm_config::{RateLimiterConfig, TokenBucketConfig};
 
     use super::*;
+    #[cfg(feature = "faascale-mem")]
+    use crate::test_client::{MemoryDeviceRequest, TestClient};
 
     impl PartialEq for ParsedRequest {
         fn eq(&self, other: &ParsedRequest) -> bool {
@@ -388,7 +552,7 @@ pub mod tests {
         }
     }
 
-    fn http_request(request_type: &str, endpoint: &str, body: Option<&str>) -> String {
+    pub(crate) fn http_request(request_type: &str, endpoint: &str, body: Option<&str>) -> String {
         let req_no_body = format!(
             "{} {} HTTP/1.1\r\nContent-Type: application/json\r\n",
             request_type, endpoint
@@ -486,6 +650,57 @@ pub mod tests {
         };
     }
 
+    #[test]
+    fn test_try_from_read_only_request() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let mut parse = |method, path, body| {
+            sender
+                .write_all(http_request(method, path, body).as_bytes())
+                .unwrap();
+            assert!(connection.try_read().is_ok());
+            let req = connection.pop_parsed_request().unwrap();
+            ParsedRequest::try_from_read_only_request(&req).map(|r| r.into_parts().0)
+        };
+
+        // The GET requests are served as on the API socket.
+        assert!(matches!(
+            parse("GET", "/machine-config", None),
+            Ok(RequestAction::Sync(action)) if *action == VmmAction::GetVmMachineConfig
+        ));
+        assert!(matches!(
+            parse("GET", "/routes", None),
+            Ok(RequestAction::GetRoutes)
+        ));
+        assert!(matches!(
+            parse("GET", "/audit/memory-devices", None),
+            Ok(RequestAction::GetAuditLog)
+        ));
+        #[cfg(feature = "faascale-mem")]
+        assert!(matches!(
+            parse("GET", "/faascale_mem/health", None),
+            Ok(RequestAction::Sync(action)) if *action == VmmAction::GetFaascaleMemHealth
+        ));
+
+        // The MMDS data store and the other methods are not.
+        assert!(matches!(
+            parse("GET", "/mmds", None),
+            Err(Error::InvalidPathMethod(_, Method::Get))
+        ));
+        assert!(matches!(
+            parse("PATCH", "/vm", Some(r#"{ "state": "Paused" }"#)),
+            Err(Error::InvalidPathMethod(_, Method::Patch))
+        ));
+        assert!(matches!(
+            parse("PUT", "/shutdown-internal", None),
+            Err(Error::InvalidPathMethod(_, Method::Put))
+        ));
+        assert!(matches!(
+            parse("GET", "/machine-config", Some("body")),
+            Err(Error::Generic(StatusCode::BadRequest, _))
+        ));
+    }
+
     #[test]
     fn test_error_into_response() {
         // Generic error.
@@ -562,18 +777,70 @@ pub mod tests {
             let data = Ok(vmm_data);
             let mut buf = Cursor::new(vec![0]);
             let expected_response = match data.as_ref().unwrap() {
+                #[cfg(feature = "balloon")]
                 VmmData::BalloonConfig(cfg) => {
                     http_response(&serde_json::to_string(cfg).unwrap(), 200)
                 }
+                #[cfg(feature = "balloon")]
                 VmmData::BalloonStats(stats) => {
                     http_response(&serde_json::to_string(stats).unwrap(), 200)
                 }
+                #[cfg(feature = "balloon")]
+                VmmData::BalloonConfigSpace(config_space) => {
+                    http_response(&serde_json::to_string(config_space).unwrap(), 200)
+                }
+                #[cfg(feature = "balloon")]
+                VmmData::BalloonUpdateClamped(clamped) => {
+                    http_response(&serde_json::to_string(clamped).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
                 VmmData::FaascaleMemConfig(cfg) => {
                     http_response(&serde_json::to_string(cfg).unwrap(), 200)
                 }
+                #[cfg(feature = "faascale-mem")]
                 VmmData::FaascaleMemStats(stats) => {
                     http_response(&serde_json::to_string(stats).unwrap(), 200)
                 }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemHealth(health) => {
+                    http_response(&serde_json::to_string(health).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemBudget(budget) => {
+                    http_response(&serde_json::to_string(budget).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemFootprint(footprint) => {
+                    http_response(&serde_json::to_string(footprint).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemBlocks(blocks) => {
+                    http_response(&serde_json::to_string(blocks).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemHeatmap(heatmap) => {
+                    http_response(&serde_json::to_string(heatmap).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemErrors(errors) => {
+                    http_response(&serde_json::to_string(errors).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemWarmReport(report) => {
+                    http_response(&serde_json::to_string(report).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemMetadata(metadata) => {
+                    http_response(&serde_json::to_string(metadata).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemEstimate(estimate) => {
+                    http_response(&serde_json::to_string(estimate).unwrap(), 200)
+                }
+                #[cfg(feature = "faascale-mem")]
+                VmmData::FaascaleMemConfigSpace(config_space) => {
+                    http_response(&serde_json::to_string(config_space).unwrap(), 200)
+                }
                 VmmData::Empty => http_response("", 204),
                 VmmData::FullVmConfig(cfg) => {
                     http_response(&serde_json::to_string(cfg).unwrap(), 200)
@@ -581,12 +848,28 @@ pub mod tests {
                 VmmData::MachineConfiguration(cfg) => {
                     http_response(&serde_json::to_string(cfg).unwrap(), 200)
                 }
+                VmmData::MemoryDevicesQuiesced(token) => {
+                    http_response(&serde_json::to_string(token).unwrap(), 200)
+                }
+                VmmData::MemoryDevicesVerification(verification) => {
+                    http_response(&serde_json::to_string(verification).unwrap(), 200)
+                }
+                #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+                VmmData::MemoryOverlays(overlays) => {
+                    http_response(&serde_json::to_string(overlays).unwrap(), 200)
+                }
                 VmmData::MmdsValue(value) => {
                     http_response(&serde_json::to_string(value).unwrap(), 200)
                 }
                 VmmData::InstanceInformation(info) => {
                     http_response(&serde_json::to_string(info).unwrap(), 200)
                 }
+                VmmData::SnapshotCreated(info) => {
+                    http_response(&serde_json::to_string(info).unwrap(), 200)
+                }
+                VmmData::SnapshotMemoryInfo(info) => {
+                    http_response(&serde_json::to_string(info).unwrap(), 200)
+                }
                 VmmData::VmmVersion(version) => http_response(
                     &serde_json::json!({ "firecracker_version": version.as_str() }).to_string(),
                     200,
@@ -597,16 +880,125 @@ pub mod tests {
             assert_eq!(buf.into_inner(), expected_response.as_bytes());
         };
 
+        #[cfg(feature = "balloon")]
         verify_ok_response_with(VmmData::BalloonConfig(BalloonDeviceConfig::default()));
+        #[cfg(feature = "balloon")]
         verify_ok_response_with(VmmData::BalloonStats(BalloonStats {
             swap_in: Some(1),
             swap_out: Some(1),
             ..Default::default()
         }));
+        #[cfg(feature = "balloon")]
+        verify_ok_response_with(VmmData::BalloonConfigSpace(BalloonConfigSpace {
+            raw: "0001000000010000".to_string(),
+            num_pages: 256,
+            actual_pages: 256,
+        }));
+        #[cfg(feature = "balloon")]
+        verify_ok_response_with(VmmData::BalloonUpdateClamped(BalloonUpdateClamped::new(
+            256, 128,
+        )));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemHealth(FaascaleMemHealth::default()));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemBudget(FaascaleMemBudget::default()));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemFootprint(
+            FaascaleMemFootprint::default(),
+        ));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemBlocks(FaascaleMemBlocks {
+            populated_mib: 1,
+            blocks: vec![FaascaleMemBlock {
+                start_pfn: 0x6000,
+                num_pages: 256,
+            }],
+        }));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemHeatmap(FaascaleMemHeatmap {
+            bucket_mib: 64,
+            buckets: vec![FaascaleMemHeatmapBucket {
+                start_pfn: 0x4000,
+                populate_count: 1,
+                populated_pages: 256,
+                depopulate_count: 0,
+                depopulated_pages: 0,
+            }],
+        }));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemErrors(FaascaleMemErrors {
+            total: 1,
+            errors: vec![FaascaleMemErrorRecord {
+                timestamp_us: 1_000_000,
+                operation: FaascaleMemOperation::Populate,
+                start_pfn: 0x4000,
+                num_pages: 256,
+                errno: Some(12),
+                error: "MadviseFail".to_string(),
+            }],
+        }));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemWarmReport(FaascaleMemWarmReport {
+            blocks: 2,
+            pages: 512,
+            failed_blocks: 0,
+            elapsed_us: 1200,
+        }));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemMetadata(FaascaleMemMetadata::default()));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemEstimate(FaascaleMemEstimate {
+            target_mib: 512,
+            populated_mib: 256,
+            additional_mib: 256,
+            satisfiable: false,
+            limited_by: vec![FaascaleMemEstimateLimit::Budget],
+            max_mib: Some(384),
+            ..Default::default()
+        }));
+        #[cfg(feature = "faascale-mem")]
+        verify_ok_response_with(VmmData::FaascaleMemConfigSpace(
+            FaascaleMemConfigSpace::default(),
+        ));
         verify_ok_response_with(VmmData::Empty);
         verify_ok_response_with(VmmData::FullVmConfig(VmmConfig::default()));
         verify_ok_response_with(VmmData::MachineConfiguration(MachineConfig::default()));
+        verify_ok_response_with(VmmData::MemoryDevicesQuiesced(MemoryDevicesQuiesceToken {
+            token: 1,
+        }));
+        verify_ok_response_with(VmmData::MemoryDevicesVerification(
+            MemoryDevicesVerification {
+                consistent: false,
+                inconsistencies: vec![MemoryDevicesInconsistency {
+                    device: "balloon".to_string(),
+                    check: "actual_within_guest_memory".to_string(),
+                    detail: String::new(),
+                }],
+            },
+        ));
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        verify_ok_response_with(VmmData::MemoryOverlays(MemoryOverlaysInfo {
+            overlay_count: 2,
+            overlay_bytes: 0x3000,
+        }));
         verify_ok_response_with(VmmData::MmdsValue(serde_json::from_str("{}").unwrap()));
+        verify_ok_response_with(VmmData::SnapshotCreated(SnapshotCreateInfo {
+            mem_pages_written: 1,
+            mem_pages_skipped: 2,
+            ..Default::default()
+        }));
+        verify_ok_response_with(VmmData::SnapshotMemoryInfo(SnapshotMemoryInfo {
+            snapshot_data_version: 9,
+            firecracker_version: Some("1.5.0".to_string()),
+            mem_backend_type: MemBackendType::File,
+            mem_size_bytes: 0x800_0000,
+            mem_file_size: Some(0x800_0000),
+            mem_file_hole_bytes: Some(0x600_0000),
+            faascale_populated_ranges: Some(vec![SnapshotMemoryRange {
+                start_addr: 0x600_0000,
+                size_bytes: 0x10_0000,
+            }]),
+        }));
         verify_ok_response_with(VmmData::InstanceInformation(InstanceInfo::default()));
         verify_ok_response_with(VmmData::VmmVersion(String::default()));
 
@@ -634,6 +1026,7 @@ pub mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_try_from_get_balloon() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
         let mut connection = HttpConnection::new(receiver);
@@ -646,6 +1039,7 @@ pub mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_try_from_get_balloon_stats() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
         let mut connection = HttpConnection::new(receiver);
@@ -657,6 +1051,196 @@ pub mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_get_faascale_mem_health() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/faascale_mem/health", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_faascale_mem_api_version() {
+        let mut client = TestClient::new();
+        let mut parse = |path, read_only| {
+            let req = client.send_raw(http_request("GET", path, None).as_bytes());
+            let parsed = if read_only {
+                ParsedRequest::try_from_read_only_request(&req)
+            } else {
+                ParsedRequest::try_from_request(&req)
+            };
+            parsed.map(|r| {
+                let (action, info) = r.into_parts();
+                (action, info.api_version())
+            })
+        };
+
+        for read_only in [false, true] {
+            // The versioned and the unversioned routes are both served as the first version.
+            for path in ["/v1/faascale-mem/health", "/faascale_mem/health"] {
+                assert!(matches!(
+                    parse(path, read_only),
+                    Ok((RequestAction::Sync(action), Some("v1")))
+                        if *action == VmmAction::GetFaascaleMemHealth
+                ));
+            }
+            assert!(matches!(
+                parse("/v2/faascale-mem/health", read_only),
+                Err(Error::Generic(StatusCode::BadRequest, _))
+            ));
+            assert!(matches!(
+                parse("/machine-config", read_only),
+                Ok((RequestAction::Sync(_), None))
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_get_faascale_mem_budget() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/faascale_mem/budget", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_get_faascale_mem_footprint() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/faascale_mem/footprint", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_get_faascale_mem_blocks() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/faascale_mem/blocks", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_get_faascale_mem_heatmap() {
+        let mut client = TestClient::new();
+        let req = client.send(&MemoryDeviceRequest::GetFaascaleMemHeatmap);
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::GetFaascaleMemHeatmap
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_get_faascale_mem_errors() {
+        let mut client = TestClient::new();
+        let req = client.send(&MemoryDeviceRequest::GetFaascaleMemErrors);
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::GetFaascaleMemErrors
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_get_faascale_mem_metadata() {
+        let mut client = TestClient::new();
+        let req = client.send(&MemoryDeviceRequest::GetFaascaleMemMetadata);
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::GetFaascaleMemMetadata
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_get_faascale_mem_estimate() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                http_request("GET", "/faascale_mem/estimate?target_mib=512", None).as_bytes(),
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match ParsedRequest::try_from_request(&req).unwrap().into_parts() {
+            (RequestAction::Sync(action), _) => assert_eq!(
+                *action,
+                VmmAction::GetFaascaleMemEstimate(FaascaleMemEstimateConfig { target_mib: 512 })
+            ),
+            _ => panic!("wrong parsed request"),
+        };
+
+        // The target is required, as a number of MiB.
+        for path in [
+            "/faascale_mem/estimate",
+            "/faascale_mem/estimate?target_mib=lots",
+            "/faascale_mem/estimate?target_mib=512&size=2",
+        ] {
+            sender
+                .write_all(http_request("GET", path, None).as_bytes())
+                .unwrap();
+            assert!(connection.try_read().is_ok());
+            let req = connection.pop_parsed_request().unwrap();
+            assert!(matches!(
+                ParsedRequest::try_from_request(&req),
+                Err(Error::Generic(StatusCode::BadRequest, _))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_try_from_get_debug() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/debug/balloon/config-space", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        #[cfg(feature = "balloon")]
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+        #[cfg(not(feature = "balloon"))]
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    fn test_try_from_get_routes() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/routes", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match ParsedRequest::try_from_request(&req).unwrap().into_parts() {
+            (RequestAction::GetRoutes, _) => (),
+            _ => panic!("wrong parsed request"),
+        };
+    }
+
     #[test]
     fn test_try_from_get_machine_config() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -669,6 +1253,31 @@ pub mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    fn test_try_from_get_memory_overlays() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/memory-devices/overlays", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_get_snapshot_memory_info() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/snapshot/memory-info", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_get_mmds() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -707,6 +1316,7 @@ pub mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_try_from_put_balloon() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
         let mut connection = HttpConnection::new(receiver);
@@ -931,6 +1541,7 @@ pub mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_try_from_patch_balloon() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
         let mut connection = HttpConnection::new(receiver);
@@ -950,6 +1561,360 @@ pub mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_patch_faascale_mem_pin() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"start_pfn\": 24576, \"num_pages\": 256, \"pinned\": true }";
+        sender
+            .write_all(http_request("PATCH", "/faascale_mem/pin", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+        let body = "{ \"start_pfn\": 24576, \"num_pages\": 256 }";
+        sender
+            .write_all(http_request("PATCH", "/faascale_mem/pin", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_patch_faascale_mem_mlock() {
+        let mut client = TestClient::new();
+        let mlock_cfg = FaascaleMemMlockConfig {
+            start_pfn: 0x6000,
+            num_pages: 16,
+            locked: true,
+        };
+        let req = client.send(&MemoryDeviceRequest::PatchFaascaleMemMlock(
+            mlock_cfg.clone(),
+        ));
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::UpdateFaascaleMemMlock(mlock_cfg)
+        );
+        let body = "{ \"start_pfn\": 24576, \"num_pages\": 16 }";
+        let req =
+            client.send_raw(http_request("PATCH", "/faascale_mem/mlock", Some(body)).as_bytes());
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_patch_faascale_mem_fence() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"fenced\": true }";
+        sender
+            .write_all(http_request("PATCH", "/faascale_mem/fence", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+        let body = "{ \"fenced\": 1 }";
+        sender
+            .write_all(http_request("PATCH", "/faascale_mem/fence", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_patch_faascale_mem_population() {
+        let mut client = TestClient::new();
+        let population_cfg = FaascaleMemPopulationConfig {
+            pre_alloc_mem: None,
+            pre_tdp_fault: Some(true),
+        };
+        let req = client.send(&MemoryDeviceRequest::PatchFaascaleMemPopulation(
+            population_cfg.clone(),
+        ));
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::UpdateFaascaleMemPopulation(population_cfg)
+        );
+        let body = "{ \"pre_fault\": true }";
+        let req = client
+            .send_raw(http_request("PATCH", "/faascale_mem/population", Some(body)).as_bytes());
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_patch_faascale_mem_rate_limiter() {
+        let mut client = TestClient::new();
+        let rate_limiter_cfg = FaascaleMemRateLimiterConfig {
+            rate_limiter: Some(RateLimiterConfig {
+                bandwidth: Some(TokenBucketConfig {
+                    size: 1 << 30,
+                    one_time_burst: None,
+                    refill_time: 1000,
+                }),
+                ops: None,
+            }),
+        };
+        let req = client.send(&MemoryDeviceRequest::PatchFaascaleMemRateLimiter(
+            rate_limiter_cfg.clone(),
+        ));
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::UpdateFaascaleMemRateLimiter(rate_limiter_cfg)
+        );
+        let body = "{ \"rate_limiter\": { \"blocks\": { \"size\": 1, \"refill_time\": 1 } } }";
+        let req = client
+            .send_raw(http_request("PATCH", "/faascale_mem/rate_limiter", Some(body)).as_bytes());
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_patch_faascale_mem() {
+        let mut client = TestClient::new();
+        let req = client.send(&MemoryDeviceRequest::PatchFaascaleMem(
+            FaascaleMemUpdateConfig { target_mib: 256 },
+        ));
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::UpdateFaascaleMem(FaascaleMemUpdateConfig { target_mib: 256 })
+        );
+        let body = "{ \"amount_mib\": 256 }";
+        let req = client.send_raw(http_request("PATCH", "/faascale_mem", Some(body)).as_bytes());
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_patch_faascale_mem_budget() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"budget_mib\": 512 }";
+        sender
+            .write_all(http_request("PATCH", "/faascale_mem/budget", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+        let body = "{ \"budget_mib\": -1 }";
+        sender
+            .write_all(http_request("PATCH", "/faascale_mem/budget", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_put_faascale_mem_depopulate() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"start_pfn\": 24576, \"num_pages\": 256 }";
+        sender
+            .write_all(http_request("PUT", "/faascale_mem/depopulate", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+        let body = "{ \"release_mib\": 64 }";
+        sender
+            .write_all(http_request("PUT", "/faascale_mem/depopulate", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+        let body = "{ \"release_pages\": 64 }";
+        sender
+            .write_all(http_request("PUT", "/faascale_mem/depopulate", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+        let body = "{ \"release_mib\": 64 }";
+        sender
+            .write_all(http_request("PUT", "/faascale_mem/reclaim", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_put_faascale_mem_hotplug_slot() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"reserved\": true }";
+        sender
+            .write_all(http_request("PUT", "/faascale_mem/hotplug-slot", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::SetFaascaleMemHotplugSlot(FaascaleMemHotplugSlotConfig { reserved: true })
+        );
+        let body = "{ \"reserved\": true, \"slots\": 2 }";
+        sender
+            .write_all(http_request("PUT", "/faascale_mem/hotplug-slot", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_put_faascale_mem_rehydrate() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        // Without blocks, all the populated memory is faulted in.
+        sender
+            .write_all(http_request("PUT", "/faascale_mem/rehydrate", Some("{}")).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::RehydrateFaascaleMem(FaascaleMemRehydrateConfig::default())
+        );
+        let body = "{ \"blocks\": [{ \"start_pfn\": 24576 }] }";
+        sender
+            .write_all(http_request("PUT", "/faascale_mem/rehydrate", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_put_faascale_mem_poll_stats() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let path = "/faascale_mem/statistics/poll-now";
+        sender
+            .write_all(http_request("PUT", path, Some("{}")).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::PollFaascaleMemStats(FaascaleMemPollStatsConfig::default())
+        );
+        let body = "{ \"timeout_ms\": 50 }";
+        sender
+            .write_all(http_request("PUT", path, Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::PollFaascaleMemStats(FaascaleMemPollStatsConfig {
+                timeout_ms: Some(50)
+            })
+        );
+        let body = "{ \"timeout_s\": 1 }";
+        sender
+            .write_all(http_request("PUT", path, Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+        sender
+            .write_all(http_request("PUT", "/faascale_mem/statistics", Some("{}")).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_put_faascale_mem_populate() {
+        let mut client = TestClient::new();
+        let populate_cfg = FaascaleMemPopulateConfig {
+            amount_mib: Some(64),
+            ..Default::default()
+        };
+        let req = client.send(&MemoryDeviceRequest::PutFaascaleMemPopulate(
+            populate_cfg.clone(),
+        ));
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::PopulateFaascaleMem(populate_cfg)
+        );
+        let populate_cfg = FaascaleMemPopulateConfig {
+            start_pfn: Some(0x6000),
+            num_pages: Some(256),
+            ..Default::default()
+        };
+        let req = client.send(&MemoryDeviceRequest::PutFaascaleMemPopulate(
+            populate_cfg.clone(),
+        ));
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::PopulateFaascaleMem(populate_cfg)
+        );
+        // The blocks of the template of a warm pool.
+        let body = "{ \"blocks\": [{ \"start_pfn\": 24576, \"num_pages\": 256 }] }";
+        let req =
+            client.send_raw(http_request("PUT", "/faascale_mem/populate", Some(body)).as_bytes());
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::PopulateFaascaleMem(FaascaleMemPopulateConfig {
+                blocks: vec![FaascaleMemTemplateBlock {
+                    start_pfn: 24576,
+                    num_pages: 256,
+                }],
+                ..Default::default()
+            })
+        );
+        let body = "{ \"blocks\": [{ \"start_pfn\": 24576 }] }";
+        let req =
+            client.send_raw(http_request("PUT", "/faascale_mem/populate", Some(body)).as_bytes());
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_try_from_patch_faascale_mem_refresh_stats() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let path = "/faascale_mem/statistics/refresh";
+        sender
+            .write_all(http_request("PATCH", path, None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::RefreshFaascaleMemStats
+        );
+        // The body of a refresh request is ignored.
+        sender
+            .write_all(http_request("PATCH", path, Some("{}")).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert_eq!(
+            vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()),
+            VmmAction::RefreshFaascaleMemStats
+        );
+        sender
+            .write_all(http_request("PATCH", "/faascale_mem/statistics/poll", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
     #[test]
     fn test_try_from_patch_drives() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -1008,6 +1973,31 @@ pub mod tests {
         assert!(request_result.is_ok(), "{}", request_result.err().unwrap());
     }
 
+    #[test]
+    fn test_try_from_patch_memory_devices() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("PATCH", "/memory-devices/quiesce", Some("{}")).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+        let body = "{ \"token\": 1 }";
+        sender
+            .write_all(http_request("PATCH", "/memory-devices/resume", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+        sender
+            .write_all(http_request("PATCH", "/memory-devices/resume", Some("{}")).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
     #[test]
     fn test_try_from_patch_mmds() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -0,0 +1,132 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! API server only serving the GET requests which report the state of the microVM.
+//!
+//! Monitoring agents can be granted access to its socket without being able to change the
+//! microVM: its parser has no route other than the GET ones, and it reaches the VMM through a
+//! channel of its own rather than the one of the API server.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use logger::{debug, error};
+use micro_http::{HttpServer, Request, Response, StatusCode, Version};
+use seccompiler::BpfProgramRef;
+
+use crate::parsed_request::{ParsedRequest, RequestAction};
+use crate::request::audit::AuditLog;
+use crate::request::routes::read_only_routes;
+use crate::Error::ServerCreation;
+use crate::{ApiServer, Result, VmmChannel};
+
+/// Structure associated with the read-only API server implementation.
+pub struct ReadOnlyApiServer {
+    /// Channel to the VMM.
+    vmm_channel: VmmChannel,
+    /// Requests changing the memory devices, shared with the API server.
+    audit_log: Arc<Mutex<AuditLog>>,
+}
+
+impl ReadOnlyApiServer {
+    pub(crate) fn new(vmm_channel: VmmChannel, audit_log: Arc<Mutex<AuditLog>>) -> Self {
+        ReadOnlyApiServer {
+            vmm_channel,
+            audit_log,
+        }
+    }
+
+    /// Starts the HTTP Server by binding to the socket path provided as an argument. The
+    /// server runs until the process exits.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the socket path on which the server will wait for requests.
+    /// * `seccomp_filter` - the seccomp filter to apply.
+    /// * `api_payload_limit` - the maximum size of the request payloads.
+    pub fn bind_and_run(
+        &mut self,
+        path: &PathBuf,
+        seccomp_filter: BpfProgramRef,
+        api_payload_limit: usize,
+    ) -> Result<()> {
+        let mut server = HttpServer::new(path).map_err(ServerCreation)?;
+        server.set_payload_max_size(api_payload_limit);
+
+        if let Err(err) = seccompiler::apply_filter(seccomp_filter) {
+            panic!(
+                "Failed to set the requested seccomp filters on the read-only API thread: {}",
+                err
+            );
+        }
+
+        server.start_server().expect("Cannot start HTTP server");
+
+        loop {
+            let request_vec = match server.requests() {
+                Ok(vec) => vec,
+                Err(err) => {
+                    // print request error, but keep server running
+                    error!(
+                        "Read-only API Server error on retrieving incoming request: {}",
+                        err
+                    );
+                    continue;
+                }
+            };
+            for server_request in request_vec {
+                let request_processing_start_us =
+                    utils::time::get_time_us(utils::time::ClockType::Monotonic);
+                server
+                    .respond(server_request.process(|request| self.handle_request(request)))
+                    .or_else(|err| {
+                        error!(
+                            "Read-only API Server encountered an error on response: {}",
+                            err
+                        );
+                        Ok(())
+                    })?;
+
+                let delta_us = utils::time::get_time_us(utils::time::ClockType::Monotonic)
+                    - request_processing_start_us;
+                debug!("Total previous read-only API call duration: {} us.", delta_us);
+            }
+        }
+    }
+
+    /// Handles an API request received through the associated socket.
+    pub fn handle_request(&mut self, request: &Request) -> Response {
+        let response = match ParsedRequest::try_from_read_only_request(request)
+            .map(|r| r.into_parts())
+        {
+            Ok((req_action, parsing_info)) => {
+                let mut response = match req_action {
+                    RequestAction::Sync(vmm_action) => {
+                        ParsedRequest::convert_to_response(&self.vmm_channel.send(vmm_action))
+                    }
+                    RequestAction::GetAuditLog => ParsedRequest::success_response_with_data(
+                        &*self.audit_log.lock().expect("Poisoned lock"),
+                    ),
+                    RequestAction::GetRoutes => {
+                        ParsedRequest::success_response_with_data(&read_only_routes())
+                    }
+                    // The read-only parser has no route asking the server to go down.
+                    RequestAction::ShutdownInternal => {
+                        Response::new(Version::Http11, StatusCode::BadRequest)
+                    }
+                };
+                ApiServer::annotate_response(&mut response, parsing_info);
+                response
+            }
+            Err(err) => {
+                error!("{}", err);
+                err.into()
+            }
+        };
+        self.audit_log
+            .lock()
+            .expect("Poisoned lock")
+            .record(request, response.status());
+        response
+    }
+}
@@ -15,9 +15,12 @@ use crate::request::StatusCode;
 // struct from the Serde deserialization process.
 #[derive(Debug, Deserialize, Serialize)]
 enum ActionType {
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    ConsolidateMemoryOverlays,
     FlushMetrics,
     InstanceStart,
     SendCtrlAltDel,
+    VerifyMemoryDevices,
 }
 
 // The model of the json body from a sync request. We use Serde to transform each associated
@@ -36,6 +39,10 @@ pub(crate) fn parse_put_actions(body: &Body) -> Result<ParsedRequest, Error> {
     })?;
 
     match action_body.action_type {
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        ActionType::ConsolidateMemoryOverlays => Ok(ParsedRequest::new_sync(
+            VmmAction::ConsolidateMemoryOverlays,
+        )),
         ActionType::FlushMetrics => Ok(ParsedRequest::new_sync(VmmAction::FlushMetrics)),
         ActionType::InstanceStart => Ok(ParsedRequest::new_sync(VmmAction::StartMicroVm)),
         ActionType::SendCtrlAltDel => {
@@ -49,6 +56,9 @@ pub(crate) fn parse_put_actions(body: &Body) -> Result<ParsedRequest, Error> {
             #[cfg(target_arch = "x86_64")]
             Ok(ParsedRequest::new_sync(VmmAction::SendCtrlAltDel))
         }
+        ActionType::VerifyMemoryDevices => {
+            Ok(ParsedRequest::new_sync(VmmAction::VerifyMemoryDevices))
+        }
     }
 }
 
@@ -103,5 +113,28 @@ mod tests {
             assert!(result.is_ok());
             assert!(result.unwrap().eq(&req));
         }
+
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        {
+            let json = r#"{
+                "action_type": "ConsolidateMemoryOverlays"
+            }"#;
+
+            let req: ParsedRequest = ParsedRequest::new_sync(VmmAction::ConsolidateMemoryOverlays);
+            let result = parse_put_actions(&Body::new(json));
+            assert!(result.is_ok());
+            assert!(result.unwrap().eq(&req));
+        }
+
+        {
+            let json = r#"{
+                "action_type": "VerifyMemoryDevices"
+            }"#;
+
+            let req: ParsedRequest = ParsedRequest::new_sync(VmmAction::VerifyMemoryDevices);
+            let result = parse_put_actions(&Body::new(json));
+            assert!(result.is_ok());
+            assert!(result.unwrap().eq(&req));
+        }
     }
 }
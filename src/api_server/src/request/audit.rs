@@ -0,0 +1,202 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+
+use logger::{IncMetric, METRICS};
+use micro_http::{Method, Request, StatusCode};
+use serde::Serialize;
+use utils::time::{get_time_us, ClockType};
+
+use crate::parsed_request::{split_path, Error, ParsedRequest, RequestAction};
+#[cfg(feature = "faascale-mem")]
+use crate::request::faascale_mem::faascale_mem_api_version;
+
+/// Number of requests kept in the audit log, the oldest ones are dropped first.
+pub(crate) const MAX_AUDIT_RECORDS: usize = 256;
+
+// First path tokens of the requests changing the memory devices.
+const AUDITED_PATHS: &[&str] = &["balloon", "faascale_mem", "memory-devices"];
+
+/// A request which changed, or tried to change, the memory devices.
+///
+/// The caller is not recorded: the HTTP server keeps the client connections private, so the
+/// socket credentials of the peer are not available to the API server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct AuditRecord {
+    /// When the request was answered, in microseconds since the epoch.
+    pub timestamp_us: u64,
+    pub method: &'static str,
+    pub path: String,
+    pub body: String,
+    /// Status of the response.
+    pub result: String,
+}
+
+/// Body of the `GET /audit/memory-devices` response.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct AuditLog {
+    /// Number of records dropped to make room for newer ones.
+    pub dropped: u64,
+    /// The requests kept, oldest first.
+    pub records: VecDeque<AuditRecord>,
+}
+
+impl AuditLog {
+    /// Records the PUT and PATCH requests on the memory devices along with the status they
+    /// were answered with. The other requests are left out.
+    pub(crate) fn record(&mut self, request: &Request, status: StatusCode) {
+        let method = match request.method() {
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Get => return,
+        };
+        let path = request.uri().get_abs_path().to_string();
+        if !is_audited(&path) {
+            return;
+        }
+
+        if self.records.len() == MAX_AUDIT_RECORDS {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        self.records.push_back(AuditRecord {
+            timestamp_us: get_time_us(ClockType::Real),
+            method,
+            path,
+            body: request
+                .body
+                .as_ref()
+                .map(|body| String::from_utf8_lossy(body.raw()).into_owned())
+                .unwrap_or_default(),
+            result: format!("{:?}", status),
+        });
+    }
+}
+
+// Whether the request path is one of the memory devices, the versioned faascale-mem routes being
+// mapped to the unversioned ones as they are parsed.
+fn is_audited(path: &str) -> bool {
+    let path_tokens = split_path(path);
+    #[cfg(feature = "faascale-mem")]
+    let path_tokens = match faascale_mem_api_version(path_tokens) {
+        Ok((path_tokens, _)) => path_tokens,
+        // The versions which are not served are still requests on the faascale-mem device.
+        Err(_) => return true,
+    };
+    path_tokens
+        .first()
+        .map_or(false, |resource| AUDITED_PATHS.contains(resource))
+}
+
+pub(crate) fn parse_get_audit(path_second_token: Option<&&str>) -> Result<ParsedRequest, Error> {
+    match path_second_token {
+        Some(&"memory-devices") => {
+            METRICS.get_api_requests.audit_count.inc();
+            Ok(ParsedRequest::new(RequestAction::GetAuditLog))
+        }
+        Some(unrecognized) => Err(Error::Generic(
+            StatusCode::BadRequest,
+            format!("Unrecognized GET request path `{}`.", unrecognized),
+        )),
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing audit log resource.".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    use micro_http::HttpConnection;
+
+    use super::*;
+    use crate::parsed_request::tests::http_request;
+
+    fn request(method: &str, path: &str, body: Option<&str>) -> Request {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request(method, path, body).as_bytes())
+            .unwrap();
+        connection.try_read().unwrap();
+        connection.pop_parsed_request().unwrap()
+    }
+
+    #[test]
+    fn test_parse_get_audit_request() {
+        assert!(parse_get_audit(None).is_err());
+        assert!(parse_get_audit(Some(&"balloon")).is_err());
+        match parse_get_audit(Some(&"memory-devices"))
+            .unwrap()
+            .into_parts()
+        {
+            (RequestAction::GetAuditLog, _) => {}
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let mut audit_log = AuditLog::default();
+        let body = r#"{ "stats_polling_interval_s": 1 }"#;
+        audit_log.record(
+            &request("PATCH", "/faascale_mem/statistics", Some(body)),
+            StatusCode::NoContent,
+        );
+        // Reads and requests on other resources are left out.
+        audit_log.record(&request("GET", "/faascale_mem", None), StatusCode::OK);
+        audit_log.record(
+            &request("PUT", "/machine-config", Some("{}")),
+            StatusCode::BadRequest,
+        );
+        assert_eq!(audit_log.records.len(), 1);
+        let record = &audit_log.records[0];
+        assert_eq!(record.method, "PATCH");
+        assert_eq!(record.path, "/faascale_mem/statistics");
+        assert_eq!(record.body, body);
+        assert_eq!(record.result, "NoContent");
+        assert!(record.timestamp_us > 0);
+
+        // The oldest records make room for the newer ones.
+        for _ in 0..MAX_AUDIT_RECORDS {
+            audit_log.record(
+                &request("PATCH", "/memory-devices/quiesce", Some("{}")),
+                StatusCode::BadRequest,
+            );
+        }
+        assert_eq!(audit_log.records.len(), MAX_AUDIT_RECORDS);
+        assert_eq!(audit_log.dropped, 1);
+        assert!(audit_log
+            .records
+            .iter()
+            .all(|record| record.path == "/memory-devices/quiesce"));
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_audit_log_versioned_routes() {
+        let mut audit_log = AuditLog::default();
+        let body = r#"{ "stats_polling_interval_s": 1 }"#;
+        // The versioned faascale-mem routes are audited, the versions which are not served too.
+        for path in ["/v1/faascale-mem/statistics", "/v2/faascale-mem/statistics"] {
+            audit_log.record(&request("PATCH", path, Some(body)), StatusCode::NoContent);
+        }
+        // Versioned paths of the other resources are not.
+        audit_log.record(
+            &request("PUT", "/v1/machine-config", Some("{}")),
+            StatusCode::BadRequest,
+        );
+        assert_eq!(
+            audit_log
+                .records
+                .iter()
+                .map(|record| record.path.as_str())
+                .collect::<Vec<_>>(),
+            ["/v1/faascale-mem/statistics", "/v2/faascale-mem/statistics"]
+        );
+    }
+}
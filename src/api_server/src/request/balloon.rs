@@ -5,6 +5,10 @@ use micro_http::StatusCode;
 use vmm::vmm_config::balloon::{
     BalloonDeviceConfig, BalloonUpdateConfig, BalloonUpdateStatsConfig,
 };
+#[cfg(feature = "faascale-mem")]
+use vmm::vmm_config::faascale_mem::{
+    FaascaleMemDeviceConfig, FaascaleMemUpdateConfig, FaascaleMemUpdateStatsConfig,
+};
 
 use super::super::VmmAction;
 use crate::parsed_request::{Error, ParsedRequest};
@@ -49,6 +53,70 @@ pub(crate) fn parse_patch_balloon(
     }
 }
 
+/// Translates the `/balloon` requests into the matching faascale-mem requests, for
+/// orchestrators only speaking the upstream balloon API. Other actions are left unchanged.
+///
+/// The faascale-mem device is sized by the memory the guest keeps populated, not by the memory
+/// it gives back: only a deflated balloon can be configured, and a balloon of `amount_mib`
+/// asks the guest to keep the rest of its memory, of `guest_mem_mib()` MiB, populated. The GET
+/// requests return the faascale-mem configuration and statistics.
+#[cfg(feature = "faascale-mem")]
+pub(crate) fn balloon_to_faascale_mem<F>(
+    action: VmmAction,
+    guest_mem_mib: F,
+) -> Result<VmmAction, Error>
+where
+    F: FnOnce() -> Result<usize, Error>,
+{
+    match action {
+        VmmAction::GetBalloonConfig => Ok(VmmAction::GetFaascaleMemConfig),
+        VmmAction::GetBalloonStats => Ok(VmmAction::GetFaascaleMemStats),
+        VmmAction::SetBalloonDevice(config) if config.amount_mib == 0 => {
+            Ok(VmmAction::SetFaascaleMemDevice(FaascaleMemDeviceConfig {
+                stats_polling_interval_s: config.stats_polling_interval_s,
+                ..Default::default()
+            }))
+        }
+        VmmAction::SetBalloonDevice(_) => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "The balloon requests are served by the faascale-mem device, which is configured \
+             deflated and resized through `PATCH /balloon`."
+                .to_string(),
+        )),
+        VmmAction::UpdateBalloon(update) => {
+            if update.if_match_epoch.is_some() {
+                return Err(Error::Generic(
+                    StatusCode::BadRequest,
+                    "The faascale-mem device serving the balloon requests cannot be resized \
+                     conditionally."
+                        .to_string(),
+                ));
+            }
+            let target_mib = guest_mem_mib()?
+                .checked_sub(update.amount_mib as usize)
+                .ok_or_else(|| {
+                    Error::Generic(
+                        StatusCode::BadRequest,
+                        format!(
+                            "The balloon size of {} MiB exceeds the guest memory.",
+                            update.amount_mib
+                        ),
+                    )
+                })?;
+            Ok(VmmAction::UpdateFaascaleMem(FaascaleMemUpdateConfig {
+                target_mib: u32::try_from(target_mib).unwrap_or(u32::MAX),
+            }))
+        }
+        VmmAction::UpdateBalloonStatistics(update) => Ok(VmmAction::UpdateFaascaleMemStatistics(
+            FaascaleMemUpdateStatsConfig {
+                stats_polling_interval_s: update.stats_polling_interval_s,
+                if_match_epoch: update.if_match_epoch,
+            },
+        )),
+        action => Ok(action),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -145,6 +213,19 @@ mod tests {
             }
             _ => panic!("Test failed: Invalid parameters"),
         };
+
+        // Conditional PATCH.
+        let body = r#"{
+                "amount_mib": 1,
+                "if_match_epoch": 4
+              }"#;
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_patch_balloon(&Body::new(body), None).unwrap()) {
+            VmmAction::UpdateBalloon(balloon_cfg) => {
+                assert_eq!(balloon_cfg.if_match_epoch, Some(4))
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
     }
 
     #[test]
@@ -166,4 +247,83 @@ mod tests {
             }"#;
         assert!(parse_put_balloon(&Body::new(body)).is_ok());
     }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_balloon_to_faascale_mem() {
+        let guest_mem_mib = || Ok(1024);
+
+        let body = r#"{
+                "amount_mib": 0,
+                "deflate_on_oom": true,
+                "stats_polling_interval_s": 5
+            }"#;
+        let action = vmm_action_from_request(parse_put_balloon(&Body::new(body)).unwrap());
+        assert_eq!(
+            balloon_to_faascale_mem(action, guest_mem_mib).unwrap(),
+            VmmAction::SetFaascaleMemDevice(FaascaleMemDeviceConfig {
+                stats_polling_interval_s: 5,
+                ..Default::default()
+            })
+        );
+
+        let body = r#"{
+                "stats_polling_interval_s": 1,
+                "if_match_epoch": 2
+            }"#;
+        let action = vmm_action_from_request(
+            parse_patch_balloon(&Body::new(body), Some(&"statistics")).unwrap(),
+        );
+        assert_eq!(
+            balloon_to_faascale_mem(action, guest_mem_mib).unwrap(),
+            VmmAction::UpdateFaascaleMemStatistics(FaascaleMemUpdateStatsConfig {
+                stats_polling_interval_s: 1,
+                if_match_epoch: Some(2),
+            })
+        );
+
+        // The balloon size is what the guest does not keep populated.
+        let body = r#"{
+                "amount_mib": 256
+            }"#;
+        let action = vmm_action_from_request(parse_patch_balloon(&Body::new(body), None).unwrap());
+        assert_eq!(
+            balloon_to_faascale_mem(action, guest_mem_mib).unwrap(),
+            VmmAction::UpdateFaascaleMem(FaascaleMemUpdateConfig { target_mib: 768 })
+        );
+        let body = r#"{
+                "amount_mib": 2048
+            }"#;
+        let action = vmm_action_from_request(parse_patch_balloon(&Body::new(body), None).unwrap());
+        assert!(balloon_to_faascale_mem(action, guest_mem_mib).is_err());
+        let body = r#"{
+                "amount_mib": 256,
+                "if_match_epoch": 4
+            }"#;
+        let action = vmm_action_from_request(parse_patch_balloon(&Body::new(body), None).unwrap());
+        assert!(balloon_to_faascale_mem(action, guest_mem_mib).is_err());
+
+        // The device is only configured deflated.
+        let body = r#"{
+                "amount_mib": 1000,
+                "deflate_on_oom": true
+            }"#;
+        let action = vmm_action_from_request(parse_put_balloon(&Body::new(body)).unwrap());
+        assert!(balloon_to_faascale_mem(action, guest_mem_mib).is_err());
+
+        assert_eq!(
+            balloon_to_faascale_mem(VmmAction::GetBalloonConfig, guest_mem_mib).unwrap(),
+            VmmAction::GetFaascaleMemConfig
+        );
+        assert_eq!(
+            balloon_to_faascale_mem(VmmAction::GetBalloonStats, guest_mem_mib).unwrap(),
+            VmmAction::GetFaascaleMemStats
+        );
+
+        // Other requests are left unchanged.
+        assert_eq!(
+            balloon_to_faascale_mem(VmmAction::GetBalloonConfigSpace, guest_mem_mib).unwrap(),
+            VmmAction::GetBalloonConfigSpace
+        );
+    }
 }
@@ -0,0 +1,61 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use micro_http::StatusCode;
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+
+pub(crate) fn parse_get_debug(
+    device: Option<&&str>,
+    resource: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match (device, resource) {
+        #[cfg(feature = "balloon")]
+        (Some(&"balloon"), Some(&"config-space")) => {
+            Ok(ParsedRequest::new_sync(VmmAction::GetBalloonConfigSpace))
+        }
+        #[cfg(feature = "faascale-mem")]
+        (Some(&"faascale-mem"), Some(&"config-space")) => Ok(ParsedRequest::new_sync(
+            VmmAction::GetFaascaleMemConfigSpace,
+        )),
+        (Some(device), Some(resource)) => Err(Error::Generic(
+            StatusCode::BadRequest,
+            format!("Unrecognized GET request path `{}/{}`.", device, resource),
+        )),
+        _ => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing debug resource.".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_get_debug_request() {
+        assert!(parse_get_debug(None, None).is_err());
+        assert!(parse_get_debug(Some(&"balloon"), None).is_err());
+        assert!(parse_get_debug(Some(&"balloon"), Some(&"statistics")).is_err());
+        assert!(parse_get_debug(Some(&"net"), Some(&"config-space")).is_err());
+
+        #[cfg(feature = "balloon")]
+        assert_eq!(
+            vmm_action_from_request(
+                parse_get_debug(Some(&"balloon"), Some(&"config-space")).unwrap()
+            ),
+            VmmAction::GetBalloonConfigSpace
+        );
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(
+            vmm_action_from_request(
+                parse_get_debug(Some(&"faascale-mem"), Some(&"config-space")).unwrap()
+            ),
+            VmmAction::GetFaascaleMemConfigSpace
+        );
+    }
+}
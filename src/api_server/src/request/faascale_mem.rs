@@ -1,51 +1,259 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use micro_http::StatusCode;
+use micro_http::{Method, StatusCode};
 use vmm::vmm_config::faascale_mem::{
-    FaascaleMemDeviceConfig,  FaascaleMemUpdateStatsConfig,
+    FaascaleMemBudgetConfig, FaascaleMemDepopulateConfig, FaascaleMemDeviceConfig,
+    FaascaleMemEstimateConfig, FaascaleMemFenceConfig, FaascaleMemHotplugSlotConfig,
+    FaascaleMemMlockConfig, FaascaleMemPinConfig, FaascaleMemPollStatsConfig,
+    FaascaleMemPopulateConfig, FaascaleMemPopulationConfig, FaascaleMemRateLimiterConfig,
+    FaascaleMemRehydrateConfig, FaascaleMemUpdateConfig, FaascaleMemUpdateStatsConfig,
 };
 
 use super::super::VmmAction;
-use crate::parsed_request::{Error, ParsedRequest};
+use crate::parsed_request::{method_to_error, Error, ParsedRequest};
 use crate::request::Body;
 
 pub(crate) fn parse_get_faascale_mem(path_second_token: Option<&&str>) -> Result<ParsedRequest, Error> {
     match path_second_token {
         Some(stats_path) => match *stats_path {
             "statistics" => Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemStats)),
-            _ => Err(Error::Generic(
-                StatusCode::BadRequest,
-                format!("Unrecognized GET request path `{}`.", *stats_path),
-            )),
+            "health" => Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemHealth)),
+            "budget" => Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemBudget)),
+            "footprint" => Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemFootprint)),
+            "blocks" => Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemBlocks)),
+            "heatmap" => Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemHeatmap)),
+            "errors" => Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemErrors)),
+            "metadata" => Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemMetadata)),
+            "estimate" => parse_get_faascale_mem_estimate(""),
+            _ => match stats_path.split_once('?') {
+                Some(("estimate", query)) => parse_get_faascale_mem_estimate(query),
+                _ => Err(Error::Generic(
+                    StatusCode::BadRequest,
+                    format!("Unrecognized GET request path `{}`.", *stats_path),
+                )),
+            },
         },
         None => Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemConfig)),
     }
 }
 
-pub(crate) fn parse_put_faascale_mem(body: &Body) -> Result<ParsedRequest, Error> {
-    Ok(ParsedRequest::new_sync(VmmAction::SetFaascaleMemDevice(
-        serde_json::from_slice::<FaascaleMemDeviceConfig>(body.raw())?,
+// Parses the query string of a capacity estimate, which holds the target in MiB.
+fn parse_get_faascale_mem_estimate(query: &str) -> Result<ParsedRequest, Error> {
+    let mut target_mib = None;
+    for param in query.split_terminator('&') {
+        match param.split_once('=') {
+            Some(("target_mib", value)) => {
+                target_mib = Some(value.parse::<u32>().map_err(|_| {
+                    Error::Generic(
+                        StatusCode::BadRequest,
+                        format!("Invalid query parameter `target_mib`: `{}`.", value),
+                    )
+                })?)
+            }
+            _ => {
+                return Err(Error::Generic(
+                    StatusCode::BadRequest,
+                    format!("Unrecognized query parameter `{}`.", param),
+                ))
+            }
+        }
+    }
+    let target_mib = target_mib.ok_or_else(|| {
+        Error::Generic(
+            StatusCode::BadRequest,
+            "Missing query parameter `target_mib`.".to_string(),
+        )
+    })?;
+    Ok(ParsedRequest::new_sync(VmmAction::GetFaascaleMemEstimate(
+        FaascaleMemEstimateConfig { target_mib },
     )))
 }
 
+pub(crate) fn parse_put_faascale_mem(
+    body: &Body,
+    path_second_token: Option<&&str>,
+    path_third_token: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match path_second_token {
+        Some(&"depopulate") => Ok(ParsedRequest::new_sync(VmmAction::DepopulateFaascaleMem(
+            serde_json::from_slice::<FaascaleMemDepopulateConfig>(body.raw())?,
+        ))),
+        Some(&"rehydrate") => Ok(ParsedRequest::new_sync(VmmAction::RehydrateFaascaleMem(
+            serde_json::from_slice::<FaascaleMemRehydrateConfig>(body.raw())?,
+        ))),
+        Some(&"hotplug-slot") => Ok(ParsedRequest::new_sync(
+            VmmAction::SetFaascaleMemHotplugSlot(serde_json::from_slice::<
+                FaascaleMemHotplugSlotConfig,
+            >(body.raw())?),
+        )),
+        Some(&"statistics") => match path_third_token {
+            Some(&"poll-now") => Ok(ParsedRequest::new_sync(VmmAction::PollFaascaleMemStats(
+                serde_json::from_slice::<FaascaleMemPollStatsConfig>(body.raw())?,
+            ))),
+            _ => Err(Error::Generic(
+                StatusCode::BadRequest,
+                "Unrecognized PUT request path `statistics`.".to_string(),
+            )),
+        },
+        Some(&"populate") => Ok(ParsedRequest::new_sync(VmmAction::PopulateFaascaleMem(
+            serde_json::from_slice::<FaascaleMemPopulateConfig>(body.raw())?,
+        ))),
+        Some(config_path) => Err(Error::Generic(
+            StatusCode::BadRequest,
+            format!("Unrecognized PUT request path `{}`.", *config_path),
+        )),
+        None => Ok(ParsedRequest::new_sync(VmmAction::SetFaascaleMemDevice(
+            serde_json::from_slice::<FaascaleMemDeviceConfig>(body.raw())?,
+        ))),
+    }
+}
+
 pub(crate) fn parse_patch_faascale_mem(
     body: &Body,
     path_second_token: Option<&&str>,
+    path_third_token: Option<&&str>,
 ) -> Result<ParsedRequest, Error> {
     match path_second_token {
         Some(config_path) => match *config_path {
-            "statistics" => Ok(ParsedRequest::new_sync(VmmAction::UpdateFaascaleMemStatistics(
-                serde_json::from_slice::<FaascaleMemUpdateStatsConfig>(body.raw())?,
+            "statistics" => match path_third_token {
+                // The body of a refresh request, if any, is ignored.
+                Some(&"refresh") => {
+                    parse_patch_faascale_mem_without_body(path_second_token, path_third_token)
+                }
+                Some(stats_path) => Err(Error::Generic(
+                    StatusCode::BadRequest,
+                    format!(
+                        "Unrecognized PATCH request path `statistics/{}`.",
+                        *stats_path
+                    ),
+                )),
+                None => Ok(ParsedRequest::new_sync(
+                    VmmAction::UpdateFaascaleMemStatistics(serde_json::from_slice::<
+                        FaascaleMemUpdateStatsConfig,
+                    >(body.raw())?),
+                )),
+            },
+            "pin" => Ok(ParsedRequest::new_sync(VmmAction::UpdateFaascaleMemPin(
+                serde_json::from_slice::<FaascaleMemPinConfig>(body.raw())?,
             ))),
+            "mlock" => Ok(ParsedRequest::new_sync(VmmAction::UpdateFaascaleMemMlock(
+                serde_json::from_slice::<FaascaleMemMlockConfig>(body.raw())?,
+            ))),
+            "rate_limiter" => Ok(ParsedRequest::new_sync(
+                VmmAction::UpdateFaascaleMemRateLimiter(serde_json::from_slice::<
+                    FaascaleMemRateLimiterConfig,
+                >(body.raw())?),
+            )),
+            "fence" => Ok(ParsedRequest::new_sync(VmmAction::UpdateFaascaleMemFence(
+                serde_json::from_slice::<FaascaleMemFenceConfig>(body.raw())?,
+            ))),
+            "budget" => Ok(ParsedRequest::new_sync(VmmAction::UpdateFaascaleMemBudget(
+                serde_json::from_slice::<FaascaleMemBudgetConfig>(body.raw())?,
+            ))),
+            "population" => Ok(ParsedRequest::new_sync(
+                VmmAction::UpdateFaascaleMemPopulation(serde_json::from_slice::<
+                    FaascaleMemPopulationConfig,
+                >(body.raw())?),
+            )),
             _ => Err(Error::Generic(
                 StatusCode::BadRequest,
                 format!("Unrecognized PATCH request path `{}`.", *config_path),
             )),
         },
-        None => Err(Error::Generic(
-            StatusCode::BadRequest,
-            format!("Unrecognized PATCH request path, We haven't support update size."),
-        )),
+        None => Ok(ParsedRequest::new_sync(VmmAction::UpdateFaascaleMem(
+            serde_json::from_slice::<FaascaleMemUpdateConfig>(body.raw())?,
+        ))),
     }
-}
\ No newline at end of file
+}
+
+/// Parses the faascale-mem PATCH requests which carry no body, the statistics refreshes.
+pub(crate) fn parse_patch_faascale_mem_without_body(
+    path_second_token: Option<&&str>,
+    path_third_token: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match (path_second_token, path_third_token) {
+        (Some(&"statistics"), Some(&"refresh")) => {
+            Ok(ParsedRequest::new_sync(VmmAction::RefreshFaascaleMemStats))
+        }
+        _ => method_to_error(Method::Patch),
+    }
+}
+
+/// Versions of the faascale-mem API, oldest first. The routes of a version are served under
+/// `/<version>/faascale-mem`. The unversioned `/faascale_mem` routes keep serving the first
+/// version, for the agents written before the API was versioned.
+pub(crate) const FAASCALE_MEM_API_VERSIONS: &[&str] = &["v1"];
+
+/// Maps the versioned faascale-mem routes, `/<version>/faascale-mem/...`, to the unversioned
+/// `/faascale_mem/...` ones the requests are parsed from. Returns the path tokens along with the
+/// version of the API serving the request, which is `None` outside of the faascale-mem routes.
+/// The versions this build does not serve are refused.
+pub(crate) fn faascale_mem_api_version(
+    mut path_tokens: Vec<&str>,
+) -> Result<(Vec<&str>, Option<&'static str>), Error> {
+    let is_version = |token: &str| {
+        token.strip_prefix('v').map_or(false, |n| {
+            !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())
+        })
+    };
+    let version = match path_tokens.as_slice() {
+        ["faascale_mem", ..] => return Ok((path_tokens, Some(FAASCALE_MEM_API_VERSIONS[0]))),
+        [version, "faascale-mem", ..] if is_version(version) => *version,
+        _ => return Ok((path_tokens, None)),
+    };
+    let version = FAASCALE_MEM_API_VERSIONS
+        .iter()
+        .find(|&&served| served == version)
+        .ok_or_else(|| {
+            Error::Generic(
+                StatusCode::BadRequest,
+                format!(
+                    "Unsupported faascale-mem API version `{}`, the supported versions are: {}.",
+                    version,
+                    FAASCALE_MEM_API_VERSIONS.join(", ")
+                ),
+            )
+        })?;
+    path_tokens.remove(0);
+    path_tokens[0] = "faascale_mem";
+    Ok((path_tokens, Some(version)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faascale_mem_api_version() {
+        // The unversioned routes are served as the first version.
+        assert_eq!(
+            faascale_mem_api_version(vec!["faascale_mem", "pin"]).unwrap(),
+            (vec!["faascale_mem", "pin"], Some("v1"))
+        );
+        assert_eq!(
+            faascale_mem_api_version(vec!["v1", "faascale-mem", "pin"]).unwrap(),
+            (vec!["faascale_mem", "pin"], Some("v1"))
+        );
+        assert_eq!(
+            faascale_mem_api_version(vec!["v1", "faascale-mem"]).unwrap(),
+            (vec!["faascale_mem"], Some("v1"))
+        );
+
+        // The other routes are left alone.
+        assert_eq!(
+            faascale_mem_api_version(vec!["balloon", "statistics"]).unwrap(),
+            (vec!["balloon", "statistics"], None)
+        );
+        assert_eq!(
+            faascale_mem_api_version(vec!["debug", "faascale-mem", "config-space"]).unwrap(),
+            (vec!["debug", "faascale-mem", "config-space"], None)
+        );
+        assert_eq!(faascale_mem_api_version(vec![]).unwrap(), (vec![], None));
+
+        assert!(matches!(
+            faascale_mem_api_version(vec!["v2", "faascale-mem", "pin"]),
+            Err(Error::Generic(StatusCode::BadRequest, msg)) if msg.contains("`v2`")
+        ));
+    }
+}
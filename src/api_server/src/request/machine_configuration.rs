@@ -47,6 +47,7 @@ pub(crate) fn parse_patch_machine_config(body: &Body) -> Result<ParsedRequest, E
 #[cfg(test)]
 mod tests {
     use vmm::cpu_config::templates::StaticCpuTemplate;
+    use vmm::vmm_config::machine_config::HugePageConfig;
 
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
@@ -85,6 +86,7 @@ mod tests {
             smt: Some(false),
             cpu_template: Some(StaticCpuTemplate::None),
             track_dirty_pages: Some(false),
+            huge_pages: Some(HugePageConfig::None),
         };
 
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -104,6 +106,7 @@ mod tests {
             smt: Some(false),
             cpu_template: Some(StaticCpuTemplate::None),
             track_dirty_pages: Some(true),
+            huge_pages: Some(HugePageConfig::None),
         };
 
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -111,6 +114,32 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
+        let body = r#"{
+                "vcpu_count": 8,
+                "mem_size_mib": 1024,
+                "huge_pages": "2M"
+            }"#;
+        let expected_config = MachineConfigUpdate {
+            vcpu_count: Some(8),
+            mem_size_mib: Some(1024),
+            smt: Some(false),
+            cpu_template: Some(StaticCpuTemplate::None),
+            track_dirty_pages: Some(false),
+            huge_pages: Some(HugePageConfig::Hugetlbfs2M),
+        };
+
+        match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
+            VmmAction::UpdateVmConfiguration(config) => assert_eq!(config, expected_config),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "vcpu_count": 8,
+                "mem_size_mib": 1024,
+                "huge_pages": "1G"
+            }"#;
+        assert!(parse_put_machine_config(&Body::new(body)).is_err());
+
         // 4. Test that applying a CPU template is successful on x86_64 while on aarch64, it is not.
         let body = r#"{
                 "vcpu_count": 8,
@@ -128,6 +157,7 @@ mod tests {
                 smt: Some(false),
                 cpu_template: Some(StaticCpuTemplate::T2),
                 track_dirty_pages: Some(true),
+                huge_pages: Some(HugePageConfig::None),
             };
 
             match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -157,6 +187,7 @@ mod tests {
                 smt: Some(true),
                 cpu_template: Some(StaticCpuTemplate::None),
                 track_dirty_pages: Some(true),
+                huge_pages: Some(HugePageConfig::None),
             };
 
             match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
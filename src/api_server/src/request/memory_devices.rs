@@ -0,0 +1,95 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use micro_http::StatusCode;
+use vmm::vmm_config::memory_devices::MemoryDevicesQuiesceToken;
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::Body;
+
+pub(crate) fn parse_get_memory_devices(
+    path_second_token: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match path_second_token {
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        Some(&"overlays") => Ok(ParsedRequest::new_sync(VmmAction::GetMemoryOverlays)),
+        Some(unrecognized) => Err(Error::Generic(
+            StatusCode::BadRequest,
+            format!("Unrecognized GET request path `{}`.", unrecognized),
+        )),
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing memory devices resource.".to_string(),
+        )),
+    }
+}
+
+pub(crate) fn parse_patch_memory_devices(
+    body: &Body,
+    path_second_token: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match path_second_token {
+        // The quiesce request takes no parameters, its body is ignored.
+        Some(&"quiesce") => Ok(ParsedRequest::new_sync(VmmAction::QuiesceMemoryDevices)),
+        Some(&"resume") => Ok(ParsedRequest::new_sync(VmmAction::ResumeMemoryDevices(
+            serde_json::from_slice::<MemoryDevicesQuiesceToken>(body.raw())?,
+        ))),
+        Some(unrecognized) => Err(Error::Generic(
+            StatusCode::BadRequest,
+            format!("Unrecognized PATCH request path `{}`.", unrecognized),
+        )),
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing memory devices operation.".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_get_memory_devices_request() {
+        assert!(parse_get_memory_devices(None).is_err());
+        assert!(parse_get_memory_devices(Some(&"quiesce")).is_err());
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        assert_eq!(
+            vmm_action_from_request(parse_get_memory_devices(Some(&"overlays")).unwrap()),
+            VmmAction::GetMemoryOverlays
+        );
+    }
+
+    #[test]
+    fn test_parse_patch_memory_devices_request() {
+        let body = r#"{}"#;
+        assert!(parse_patch_memory_devices(&Body::new(body), None).is_err());
+        assert!(parse_patch_memory_devices(&Body::new(body), Some(&"pause")).is_err());
+        assert_eq!(
+            vmm_action_from_request(
+                parse_patch_memory_devices(&Body::new(body), Some(&"quiesce")).unwrap()
+            ),
+            VmmAction::QuiesceMemoryDevices
+        );
+
+        // PATCH resume with invalid fields.
+        let body = r#"{
+            "token": 1,
+            "pinned": true
+        }"#;
+        assert!(parse_patch_memory_devices(&Body::new(body), Some(&"resume")).is_err());
+
+        // PATCH resume with valid fields.
+        let body = r#"{
+            "token": 1
+        }"#;
+        assert_eq!(
+            vmm_action_from_request(
+                parse_patch_memory_devices(&Body::new(body), Some(&"resume")).unwrap()
+            ),
+            VmmAction::ResumeMemoryDevices(MemoryDevicesQuiesceToken { token: 1 })
+        );
+    }
+}
@@ -33,6 +33,7 @@ mod tests {
 
         let expected_cfg = MetricsConfig {
             metrics_path: PathBuf::from("metrics"),
+            statsd: None,
         };
         match vmm_action_from_request(parse_put_metrics(&Body::new(body)).unwrap()) {
             VmmAction::ConfigureMetrics(cfg) => assert_eq!(cfg, expected_cfg),
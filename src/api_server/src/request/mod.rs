@@ -2,18 +2,24 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod actions;
+pub mod audit;
+#[cfg(feature = "balloon")]
 pub mod balloon;
+#[cfg(feature = "faascale-mem")]
 pub mod faascale_mem;
 pub mod boot_source;
 pub mod cpu_configuration;
+pub mod debug;
 pub mod drive;
 pub mod entropy;
 pub mod instance_info;
 pub mod logger;
 pub mod machine_configuration;
+pub mod memory_devices;
 pub mod metrics;
 pub mod mmds;
 pub mod net;
+pub mod routes;
 pub mod snapshot;
 pub mod version;
 pub mod vsock;
@@ -0,0 +1,281 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use logger::{IncMetric, METRICS};
+use serde::Serialize;
+
+use crate::parsed_request::{Error, ParsedRequest, RequestAction};
+#[cfg(feature = "faascale-mem")]
+use crate::request::faascale_mem::FAASCALE_MEM_API_VERSIONS;
+
+/// An API endpoint and the HTTP methods it accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct RouteInfo {
+    pub path: &'static str,
+    pub methods: &'static [&'static str],
+}
+
+/// Body of the `GET /routes` response.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Routes {
+    pub routes: Vec<RouteInfo>,
+    /// Versions of the faascale-mem API, each served under `/<version>/faascale-mem` on top of
+    /// the unversioned `/faascale_mem` routes listed above.
+    #[cfg(feature = "faascale-mem")]
+    pub faascale_mem_api_versions: &'static [&'static str],
+}
+
+/// Lists the memory device endpoints compiled into this build.
+pub(crate) fn memory_routes() -> Routes {
+    let mut routes = vec![
+        RouteInfo {
+            path: "/routes",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/audit/memory-devices",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/memory-devices/quiesce",
+            methods: &["PATCH"],
+        },
+        RouteInfo {
+            path: "/memory-devices/resume",
+            methods: &["PATCH"],
+        },
+        RouteInfo {
+            path: "/snapshot/memory-info",
+            methods: &["GET"],
+        },
+    ];
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    routes.push(RouteInfo {
+        path: "/memory-devices/overlays",
+        methods: &["GET"],
+    });
+    #[cfg(feature = "balloon")]
+    routes.extend([
+        RouteInfo {
+            path: "/balloon",
+            methods: &["GET", "PUT", "PATCH"],
+        },
+        RouteInfo {
+            path: "/balloon/statistics",
+            methods: &["GET", "PATCH"],
+        },
+        RouteInfo {
+            path: "/debug/balloon/config-space",
+            methods: &["GET"],
+        },
+    ]);
+    #[cfg(feature = "faascale-mem")]
+    routes.extend([
+        RouteInfo {
+            path: "/debug/faascale-mem/config-space",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/faascale_mem",
+            methods: &["GET", "PUT", "PATCH"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/blocks",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/budget",
+            methods: &["GET", "PATCH"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/depopulate",
+            methods: &["PUT"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/errors",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/estimate",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/fence",
+            methods: &["PATCH"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/footprint",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/health",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/heatmap",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/hotplug-slot",
+            methods: &["PUT"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/metadata",
+            methods: &["GET"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/mlock",
+            methods: &["PATCH"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/pin",
+            methods: &["PATCH"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/populate",
+            methods: &["PUT"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/population",
+            methods: &["PATCH"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/rate_limiter",
+            methods: &["PATCH"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/rehydrate",
+            methods: &["PUT"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/statistics",
+            methods: &["GET", "PATCH"],
+        },
+        RouteInfo {
+            path: "/faascale_mem/statistics/refresh",
+            methods: &["PATCH"],
+        },
+    ]);
+    Routes {
+        routes,
+        #[cfg(feature = "faascale-mem")]
+        faascale_mem_api_versions: FAASCALE_MEM_API_VERSIONS,
+    }
+}
+
+/// Lists the memory device endpoints served by the read-only API socket.
+pub(crate) fn read_only_routes() -> Routes {
+    let routes = memory_routes()
+        .routes
+        .into_iter()
+        .filter(|route| route.methods.contains(&"GET"))
+        .map(|route| RouteInfo {
+            path: route.path,
+            methods: &["GET"],
+        })
+        .collect();
+    Routes {
+        routes,
+        #[cfg(feature = "faascale-mem")]
+        faascale_mem_api_versions: FAASCALE_MEM_API_VERSIONS,
+    }
+}
+
+pub(crate) fn parse_get_routes() -> Result<ParsedRequest, Error> {
+    METRICS.get_api_requests.routes_count.inc();
+    Ok(ParsedRequest::new(RequestAction::GetRoutes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_get_routes_request() {
+        match parse_get_routes().unwrap().into_parts() {
+            (RequestAction::GetRoutes, _) => {}
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    fn test_memory_routes() {
+        let routes = memory_routes().routes;
+        let methods = |path| {
+            routes
+                .iter()
+                .find(|route| route.path == path)
+                .map(|route| route.methods)
+        };
+
+        assert_eq!(methods("/routes"), Some(&["GET"][..]));
+        assert_eq!(methods("/audit/memory-devices"), Some(&["GET"][..]));
+        assert_eq!(methods("/memory-devices/quiesce"), Some(&["PATCH"][..]));
+        assert_eq!(methods("/snapshot/memory-info"), Some(&["GET"][..]));
+        #[cfg(feature = "balloon")]
+        assert_eq!(methods("/balloon"), Some(&["GET", "PUT", "PATCH"][..]));
+        #[cfg(not(feature = "balloon"))]
+        assert_eq!(methods("/balloon"), None);
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem"), Some(&["GET", "PUT", "PATCH"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/pin"), Some(&["PATCH"][..]));
+        #[cfg(not(feature = "faascale-mem"))]
+        assert_eq!(methods("/faascale_mem/pin"), None);
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/population"), Some(&["PATCH"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/rate_limiter"), Some(&["PATCH"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/depopulate"), Some(&["PUT"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/blocks"), Some(&["GET"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/heatmap"), Some(&["GET"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/errors"), Some(&["GET"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/metadata"), Some(&["GET"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/mlock"), Some(&["PATCH"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/populate"), Some(&["PUT"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(
+            methods("/faascale_mem/statistics/refresh"),
+            Some(&["PATCH"][..])
+        );
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/estimate"), Some(&["GET"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/rehydrate"), Some(&["PUT"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(methods("/faascale_mem/hotplug-slot"), Some(&["PUT"][..]));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(
+            methods("/debug/faascale-mem/config-space"),
+            Some(&["GET"][..])
+        );
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(memory_routes().faascale_mem_api_versions, ["v1"]);
+    }
+
+    #[test]
+    fn test_read_only_routes() {
+        let routes = read_only_routes().routes;
+        assert!(routes.iter().all(|route| route.methods == ["GET"]));
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        assert!(routes
+            .iter()
+            .any(|route| route.path == "/memory-devices/overlays"));
+        assert!(!routes
+            .iter()
+            .any(|route| route.path == "/memory-devices/quiesce"));
+        #[cfg(feature = "faascale-mem")]
+        assert!(routes
+            .iter()
+            .any(|route| route.path == "/faascale_mem/budget"));
+        #[cfg(feature = "faascale-mem")]
+        assert_eq!(read_only_routes().faascale_mem_api_versions, ["v1"]);
+    }
+}
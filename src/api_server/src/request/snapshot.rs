@@ -22,6 +22,20 @@ pub const MISSING_FIELD: &str =
 pub const TOO_MANY_FIELDS: &str =
     "too many fields: either `mem_backend` or `mem_file_path` exclusively is required";
 
+pub(crate) fn parse_get_snapshot(path_second_token: Option<&&str>) -> Result<ParsedRequest, Error> {
+    match path_second_token {
+        Some(&"memory-info") => Ok(ParsedRequest::new_sync(VmmAction::GetSnapshotMemoryInfo)),
+        Some(&unrecognized) => Err(Error::InvalidPathMethod(
+            format!("/snapshot/{}", unrecognized),
+            Method::Get,
+        )),
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing snapshot resource.".to_string(),
+        )),
+    }
+}
+
 pub(crate) fn parse_put_snapshot(
     body: &Body,
     request_type_from_path: Option<&&str>,
@@ -124,7 +138,8 @@ mod tests {
                 "snapshot_type": "Diff",
                 "snapshot_path": "foo",
                 "mem_file_path": "bar",
-                "version": "0.23.0"
+                "version": "0.23.0",
+                "sparse": true
               }"#;
 
         let mut expected_cfg = CreateSnapshotParams {
@@ -132,6 +147,7 @@ mod tests {
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
             version: Some(String::from("0.23.0")),
+            sparse: true,
         };
 
         match vmm_action_from_request(
@@ -151,6 +167,7 @@ mod tests {
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
             version: None,
+            sparse: false,
         };
 
         match vmm_action_from_request(
@@ -381,4 +398,14 @@ mod tests {
 
         assert!(parse_patch_vm_state(&Body::new(invalid_body)).is_err());
     }
+
+    #[test]
+    fn test_parse_get_snapshot() {
+        assert_eq!(
+            vmm_action_from_request(parse_get_snapshot(Some(&"memory-info")).unwrap()),
+            VmmAction::GetSnapshotMemoryInfo
+        );
+        assert!(parse_get_snapshot(Some(&"create")).is_err());
+        assert!(parse_get_snapshot(None).is_err());
+    }
 }
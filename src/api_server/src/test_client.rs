@@ -0,0 +1,450 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Typed client of the memory device endpoints, for the tests of the API server.
+//!
+//! The requests are built from the `vmm_config` types the endpoints parse, rather than from raw
+//! JSON strings, and the responses are checked against the types the endpoints report. The
+//! module doubles as a reference for the clients written outside of Firecracker.
+
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+
+use micro_http::{HttpConnection, Request, Response, StatusCode};
+use serde::Serialize;
+use serde_json::Value;
+#[cfg(feature = "balloon")]
+use vmm::vmm_config::balloon::{
+    BalloonDeviceConfig, BalloonUpdateConfig, BalloonUpdateStatsConfig,
+};
+#[cfg(feature = "faascale-mem")]
+use vmm::vmm_config::faascale_mem::{
+    FaascaleMemBudgetConfig, FaascaleMemDepopulateConfig, FaascaleMemDeviceConfig,
+    FaascaleMemFenceConfig, FaascaleMemMlockConfig, FaascaleMemPinConfig,
+    FaascaleMemPollStatsConfig, FaascaleMemPopulateConfig, FaascaleMemPopulationConfig,
+    FaascaleMemRateLimiterConfig, FaascaleMemUpdateConfig, FaascaleMemUpdateStatsConfig,
+};
+use vmm::vmm_config::memory_devices::MemoryDevicesQuiesceToken;
+
+/// A request on one of the memory device endpoints, along with its typed body.
+#[derive(Clone, Debug)]
+pub(crate) enum MemoryDeviceRequest {
+    GetRoutes,
+    GetAuditLog,
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    GetMemoryOverlays,
+    QuiesceMemoryDevices,
+    ResumeMemoryDevices(MemoryDevicesQuiesceToken),
+    GetSnapshotMemoryInfo,
+    #[cfg(feature = "balloon")]
+    GetBalloon,
+    #[cfg(feature = "balloon")]
+    PutBalloon(BalloonDeviceConfig),
+    #[cfg(feature = "balloon")]
+    PatchBalloon(BalloonUpdateConfig),
+    #[cfg(feature = "balloon")]
+    GetBalloonStats,
+    #[cfg(feature = "balloon")]
+    PatchBalloonStats(BalloonUpdateStatsConfig),
+    #[cfg(feature = "balloon")]
+    GetBalloonConfigSpace,
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemConfigSpace,
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMem,
+    #[cfg(feature = "faascale-mem")]
+    PutFaascaleMem(FaascaleMemDeviceConfig),
+    #[cfg(feature = "faascale-mem")]
+    PatchFaascaleMem(FaascaleMemUpdateConfig),
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemBlocks,
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemBudget,
+    #[cfg(feature = "faascale-mem")]
+    PatchFaascaleMemBudget(FaascaleMemBudgetConfig),
+    #[cfg(feature = "faascale-mem")]
+    PutFaascaleMemDepopulate(FaascaleMemDepopulateConfig),
+    #[cfg(feature = "faascale-mem")]
+    PatchFaascaleMemFence(FaascaleMemFenceConfig),
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemFootprint,
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemHealth,
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemHeatmap,
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemErrors,
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemMetadata,
+    #[cfg(feature = "faascale-mem")]
+    PatchFaascaleMemMlock(FaascaleMemMlockConfig),
+    #[cfg(feature = "faascale-mem")]
+    PatchFaascaleMemPin(FaascaleMemPinConfig),
+    #[cfg(feature = "faascale-mem")]
+    PutFaascaleMemPopulate(FaascaleMemPopulateConfig),
+    #[cfg(feature = "faascale-mem")]
+    PatchFaascaleMemPopulation(FaascaleMemPopulationConfig),
+    #[cfg(feature = "faascale-mem")]
+    PatchFaascaleMemRateLimiter(FaascaleMemRateLimiterConfig),
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemStats,
+    #[cfg(feature = "faascale-mem")]
+    PatchFaascaleMemStats(FaascaleMemUpdateStatsConfig),
+    #[cfg(feature = "faascale-mem")]
+    PollFaascaleMemStats(FaascaleMemPollStatsConfig),
+    #[cfg(feature = "faascale-mem")]
+    RefreshFaascaleMemStats,
+}
+
+impl MemoryDeviceRequest {
+    /// HTTP method and path of the request.
+    pub fn endpoint(&self) -> (&'static str, String) {
+        use self::MemoryDeviceRequest::*;
+
+        let (method, path) = match self {
+            GetRoutes => ("GET", "/routes"),
+            GetAuditLog => ("GET", "/audit/memory-devices"),
+            #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+            GetMemoryOverlays => ("GET", "/memory-devices/overlays"),
+            QuiesceMemoryDevices => ("PATCH", "/memory-devices/quiesce"),
+            ResumeMemoryDevices(_) => ("PATCH", "/memory-devices/resume"),
+            GetSnapshotMemoryInfo => ("GET", "/snapshot/memory-info"),
+            #[cfg(feature = "balloon")]
+            GetBalloon => ("GET", "/balloon"),
+            #[cfg(feature = "balloon")]
+            PutBalloon(_) => ("PUT", "/balloon"),
+            #[cfg(feature = "balloon")]
+            PatchBalloon(_) => ("PATCH", "/balloon"),
+            #[cfg(feature = "balloon")]
+            GetBalloonStats => ("GET", "/balloon/statistics"),
+            #[cfg(feature = "balloon")]
+            PatchBalloonStats(_) => ("PATCH", "/balloon/statistics"),
+            #[cfg(feature = "balloon")]
+            GetBalloonConfigSpace => ("GET", "/debug/balloon/config-space"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemConfigSpace => ("GET", "/debug/faascale-mem/config-space"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMem => ("GET", "/faascale_mem"),
+            #[cfg(feature = "faascale-mem")]
+            PutFaascaleMem(_) => ("PUT", "/faascale_mem"),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMem(_) => ("PATCH", "/faascale_mem"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemBlocks => ("GET", "/faascale_mem/blocks"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemBudget => ("GET", "/faascale_mem/budget"),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemBudget(_) => ("PATCH", "/faascale_mem/budget"),
+            #[cfg(feature = "faascale-mem")]
+            PutFaascaleMemDepopulate(_) => ("PUT", "/faascale_mem/depopulate"),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemFence(_) => ("PATCH", "/faascale_mem/fence"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemFootprint => ("GET", "/faascale_mem/footprint"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemHealth => ("GET", "/faascale_mem/health"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemHeatmap => ("GET", "/faascale_mem/heatmap"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemErrors => ("GET", "/faascale_mem/errors"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemMetadata => ("GET", "/faascale_mem/metadata"),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemMlock(_) => ("PATCH", "/faascale_mem/mlock"),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemPin(_) => ("PATCH", "/faascale_mem/pin"),
+            #[cfg(feature = "faascale-mem")]
+            PutFaascaleMemPopulate(_) => ("PUT", "/faascale_mem/populate"),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemPopulation(_) => ("PATCH", "/faascale_mem/population"),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemRateLimiter(_) => ("PATCH", "/faascale_mem/rate_limiter"),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemStats => ("GET", "/faascale_mem/statistics"),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemStats(_) => ("PATCH", "/faascale_mem/statistics"),
+            #[cfg(feature = "faascale-mem")]
+            PollFaascaleMemStats(_) => ("PUT", "/faascale_mem/statistics/poll-now"),
+            #[cfg(feature = "faascale-mem")]
+            RefreshFaascaleMemStats => ("PATCH", "/faascale_mem/statistics/refresh"),
+        };
+        (method, path.to_string())
+    }
+
+    /// JSON body of the request, serialized from its typed configuration.
+    pub fn body(&self) -> Option<String> {
+        use self::MemoryDeviceRequest::*;
+
+        let body = match self {
+            // The quiesce request takes no parameters.
+            QuiesceMemoryDevices => serde_json::json!({}),
+            ResumeMemoryDevices(token) => to_value(token),
+            #[cfg(feature = "balloon")]
+            PutBalloon(config) => to_value(config),
+            #[cfg(feature = "balloon")]
+            PatchBalloon(config) => to_value(config),
+            #[cfg(feature = "balloon")]
+            PatchBalloonStats(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PutFaascaleMem(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMem(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemBudget(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PutFaascaleMemDepopulate(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemFence(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemMlock(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemPin(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PutFaascaleMemPopulate(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemPopulation(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemRateLimiter(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemStats(config) => to_value(config),
+            #[cfg(feature = "faascale-mem")]
+            PollFaascaleMemStats(config) => to_value(config),
+            _ => return None,
+        };
+        Some(body.to_string())
+    }
+
+    /// The request as sent on the API socket.
+    pub fn to_http(&self) -> String {
+        let (method, path) = self.endpoint();
+        match self.body() {
+            Some(body) => format!(
+                "{} {} HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                method,
+                path,
+                body.len(),
+                body
+            ),
+            None => format!("{} {} HTTP/1.1\r\n\r\n", method, path),
+        }
+    }
+}
+
+fn to_value<T: Serialize>(config: &T) -> Value {
+    serde_json::to_value(config).expect("Failed to serialize the request body")
+}
+
+/// Both ends of an API connection. The requests are written on the client end and read back
+/// on the server end, as the API server receives them.
+pub(crate) struct TestClient {
+    sender: UnixStream,
+    connection: HttpConnection<UnixStream>,
+}
+
+impl TestClient {
+    pub fn new() -> Self {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        TestClient {
+            sender,
+            connection: HttpConnection::new(receiver),
+        }
+    }
+
+    /// Sends the request, and returns it as received by the server.
+    pub fn send(&mut self, request: &MemoryDeviceRequest) -> Request {
+        self.send_raw(request.to_http().as_bytes())
+    }
+
+    /// Sends raw bytes, for the malformed requests the typed ones cannot express.
+    pub fn send_raw(&mut self, request: &[u8]) -> Request {
+        self.sender.write_all(request).unwrap();
+        assert!(self.connection.try_read().is_ok());
+        self.connection.pop_parsed_request().unwrap()
+    }
+}
+
+/// Status and JSON body of a response of the API server.
+#[derive(Debug)]
+pub(crate) struct ApiResponse {
+    pub status: StatusCode,
+    pub body: Option<Value>,
+}
+
+impl ApiResponse {
+    pub fn from_response(response: &Response) -> Self {
+        ApiResponse {
+            status: response.status(),
+            body: response
+                .body()
+                .map(|body| serde_json::from_slice(body.raw()).unwrap()),
+        }
+    }
+
+    /// Checks that the response succeeded and holds `expected`, serialized the way the API
+    /// server does.
+    pub fn assert_body<T: Serialize>(&self, expected: &T) {
+        assert_eq!(self.status, StatusCode::OK);
+        assert_eq!(self.body, Some(to_value(expected)));
+    }
+
+    /// Message of an error response.
+    pub fn fault_message(&self) -> Option<&str> {
+        self.body.as_ref()?.get("fault_message")?.as_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::ParsedRequest;
+    use crate::request::routes::memory_routes;
+
+    // One request of each kind, with the bodies the endpoints accept.
+    fn memory_device_requests() -> Vec<MemoryDeviceRequest> {
+        use self::MemoryDeviceRequest::*;
+
+        vec![
+            GetRoutes,
+            GetAuditLog,
+            #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+            GetMemoryOverlays,
+            QuiesceMemoryDevices,
+            ResumeMemoryDevices(MemoryDevicesQuiesceToken { token: 1 }),
+            GetSnapshotMemoryInfo,
+            #[cfg(feature = "balloon")]
+            GetBalloon,
+            #[cfg(feature = "balloon")]
+            PutBalloon(BalloonDeviceConfig::default()),
+            #[cfg(feature = "balloon")]
+            PatchBalloon(BalloonUpdateConfig {
+                amount_mib: 64,
+                if_match_epoch: None,
+            }),
+            #[cfg(feature = "balloon")]
+            GetBalloonStats,
+            #[cfg(feature = "balloon")]
+            PatchBalloonStats(BalloonUpdateStatsConfig {
+                stats_polling_interval_s: 1,
+                if_match_epoch: None,
+            }),
+            #[cfg(feature = "balloon")]
+            GetBalloonConfigSpace,
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemConfigSpace,
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMem,
+            #[cfg(feature = "faascale-mem")]
+            PutFaascaleMem(FaascaleMemDeviceConfig::default()),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMem(FaascaleMemUpdateConfig { target_mib: 256 }),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemBlocks,
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemBudget,
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemBudget(FaascaleMemBudgetConfig { budget_mib: 512 }),
+            #[cfg(feature = "faascale-mem")]
+            PutFaascaleMemDepopulate(FaascaleMemDepopulateConfig {
+                release_mib: Some(64),
+                ..Default::default()
+            }),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemFence(FaascaleMemFenceConfig { fenced: true }),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemFootprint,
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemHealth,
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemHeatmap,
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemErrors,
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemMetadata,
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemMlock(FaascaleMemMlockConfig {
+                start_pfn: 0x6000,
+                num_pages: 16,
+                locked: true,
+            }),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemPin(FaascaleMemPinConfig {
+                start_pfn: 0x6000,
+                num_pages: 256,
+                pinned: true,
+            }),
+            #[cfg(feature = "faascale-mem")]
+            PutFaascaleMemPopulate(FaascaleMemPopulateConfig {
+                amount_mib: Some(64),
+                ..Default::default()
+            }),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemPopulation(FaascaleMemPopulationConfig {
+                pre_alloc_mem: Some(true),
+                pre_tdp_fault: None,
+            }),
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemRateLimiter(FaascaleMemRateLimiterConfig::default()),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemStats,
+            #[cfg(feature = "faascale-mem")]
+            PatchFaascaleMemStats(FaascaleMemUpdateStatsConfig {
+                stats_polling_interval_s: 1,
+                if_match_epoch: None,
+            }),
+            #[cfg(feature = "faascale-mem")]
+            PollFaascaleMemStats(FaascaleMemPollStatsConfig::default()),
+            #[cfg(feature = "faascale-mem")]
+            RefreshFaascaleMemStats,
+        ]
+    }
+
+    #[test]
+    fn test_memory_device_requests() {
+        let requests = memory_device_requests();
+        let mut client = TestClient::new();
+        for request in &requests {
+            let req = client.send(request);
+            assert!(
+                ParsedRequest::try_from_request(&req).is_ok(),
+                "{:?}",
+                request
+            );
+        }
+
+        // Each memory device endpoint has a typed request.
+        for route in memory_routes().routes {
+            for method in route.methods {
+                assert!(
+                    requests.iter().any(|request| {
+                        let (request_method, path) = request.endpoint();
+                        request_method == *method && path.split('?').next() == Some(route.path)
+                    }),
+                    "{} {}",
+                    method,
+                    route.path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_api_response() {
+        let response = ParsedRequest::convert_to_response(&Ok(vmm::rpc_interface::VmmData::Empty));
+        let response = ApiResponse::from_response(&response);
+        assert_eq!(response.status, StatusCode::NoContent);
+        assert_eq!(response.body, None);
+
+        let token = MemoryDevicesQuiesceToken { token: 1 };
+        let response = ParsedRequest::convert_to_response(&Ok(
+            vmm::rpc_interface::VmmData::MemoryDevicesQuiesced(token),
+        ));
+        ApiResponse::from_response(&response).assert_body(&token);
+
+        let response = ParsedRequest::convert_to_response(&Err(
+            vmm::rpc_interface::VmmActionError::OperationNotSupportedPreBoot,
+        ));
+        let response = ApiResponse::from_response(&response);
+        assert_eq!(response.status, StatusCode::BadRequest);
+        assert!(response.fault_message().is_some());
+    }
+}
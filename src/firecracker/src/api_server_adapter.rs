@@ -8,61 +8,165 @@ use std::path::PathBuf;
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+#[cfg(feature = "faascale-mem")]
+use std::time::{Duration, Instant};
 
 use api_server::{ApiRequest, ApiResponse, ApiServer, ServerError};
 use event_manager::{EventOps, Events, MutEventSubscriber, SubscriberOps};
 use logger::{error, warn, ProcessTimeReporter};
 use seccompiler::BpfThreadMap;
+#[cfg(feature = "faascale-mem")]
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
 use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
 use vmm::resources::VmResources;
-use vmm::rpc_interface::{PrebootApiController, RuntimeApiController, VmmAction};
+use vmm::rpc_interface::{ActionResult, PrebootApiController, RuntimeApiController, VmmAction};
 use vmm::vmm_config::instance_info::InstanceInfo;
 use vmm::{EventManager, FcExitCode, Vmm};
 
+// How often the faascale-mem statistics queue is checked while the guest owes fresh
+// statistics.
+#[cfg(feature = "faascale-mem")]
+const STATS_POLL_CHECK_INTERVAL: Duration = Duration::from_millis(1);
+
+// Channel between the read-only API server and the VMM.
+struct ReadOnlyApiChannel {
+    event_fd: EventFd,
+    from_api: Receiver<ApiRequest>,
+    to_api: Sender<ApiResponse>,
+}
+
 struct ApiServerAdapter {
     api_event_fd: EventFd,
     from_api: Receiver<ApiRequest>,
     to_api: Sender<ApiResponse>,
+    read_only_api: Option<ReadOnlyApiChannel>,
     controller: RuntimeApiController,
+    // Deadline of the faascale-mem statistics poll left unanswered, if any. The API thread
+    // waits for its response, so there is at most one.
+    #[cfg(feature = "faascale-mem")]
+    stats_poll_deadline: Option<Instant>,
+    // Checks the statistics poll left unanswered, the guest answering on the event loop.
+    #[cfg(feature = "faascale-mem")]
+    stats_poll_timer: TimerFd,
 }
 
 impl ApiServerAdapter {
     /// Runs the vmm to completion, while any arising control events are deferred
     /// to a `RuntimeApiController`.
+    #[allow(clippy::too_many_arguments)]
     fn run_microvm(
         api_event_fd: EventFd,
         from_api: Receiver<ApiRequest>,
         to_api: Sender<ApiResponse>,
+        read_only_api: Option<ReadOnlyApiChannel>,
         vm_resources: VmResources,
         vmm: Arc<Mutex<Vmm>>,
         event_manager: &mut EventManager,
+        #[cfg(feature = "faascale-mem")] stats_poll_timer: TimerFd,
     ) -> FcExitCode {
         let api_adapter = Arc::new(Mutex::new(Self {
             api_event_fd,
             from_api,
             to_api,
+            read_only_api,
             controller: RuntimeApiController::new(vm_resources, vmm.clone()),
+            #[cfg(feature = "faascale-mem")]
+            stats_poll_deadline: None,
+            #[cfg(feature = "faascale-mem")]
+            stats_poll_timer,
         }));
         event_manager.add_subscriber(api_adapter);
         loop {
             event_manager
                 .run()
                 .expect("EventManager events driver fatal error");
-            if let Some(exit_code) = vmm.lock().unwrap().shutdown_exit_code() {
+            let mut locked_vmm = vmm.lock().unwrap();
+            if let Some(exit_code) = locked_vmm.shutdown_exit_code() {
                 return exit_code;
             }
+            // The devices hot-plugged by the API requests just handled.
+            for subscriber in locked_vmm.take_hotplugged_subscribers() {
+                event_manager.add_subscriber(subscriber);
+            }
         }
     }
 
     fn handle_request(&mut self, req_action: VmmAction) {
+        // The guest is not waited for on the VMM thread, the response to a statistics poll
+        // waits for the event loop to collect the guest answer.
+        #[cfg(feature = "faascale-mem")]
+        if let VmmAction::PollFaascaleMemStats(poll_cfg) = &req_action {
+            match self.controller.start_faascale_mem_stats_poll() {
+                Some(response) => self.send_response(response),
+                None => {
+                    self.stats_poll_deadline = Some(Instant::now() + poll_cfg.timeout());
+                    self.stats_poll_timer.set_state(
+                        TimerState::Periodic {
+                            current: STATS_POLL_CHECK_INTERVAL,
+                            interval: STATS_POLL_CHECK_INTERVAL,
+                        },
+                        SetTimeFlags::Default,
+                    );
+                }
+            }
+            return;
+        }
         let response = self.controller.handle_request(req_action);
+        self.send_response(response);
+    }
+
+    fn send_response(&self, response: ActionResult) {
         // Send back the result.
         self.to_api
             .send(Box::new(response))
             .map_err(|_| ())
             .expect("one-shot channel closed");
     }
+
+    // Answers the statistics poll left unanswered once the guest reported its statistics, or
+    // once the poll times out.
+    #[cfg(feature = "faascale-mem")]
+    fn check_stats_poll(&mut self) {
+        self.stats_poll_timer.read();
+        let deadline = match self.stats_poll_deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        let give_up = Instant::now() >= deadline;
+        if let Some(response) = self.controller.poll_faascale_mem_stats(give_up) {
+            self.stats_poll_deadline = None;
+            self.stats_poll_timer
+                .set_state(TimerState::Disarmed, SetTimeFlags::Default);
+            self.send_response(response);
+        }
+    }
+
+    // Serves the requests of the read-only API server. Its parser only lets the GET requests
+    // through.
+    fn handle_read_only_requests(&mut self) {
+        let read_only_api = match self.read_only_api.as_ref() {
+            Some(read_only_api) => read_only_api,
+            None => return,
+        };
+        let _ = read_only_api.event_fd.read();
+        loop {
+            match read_only_api.from_api.try_recv() {
+                Ok(api_request) => {
+                    let response = self.controller.handle_request(*api_request);
+                    read_only_api
+                        .to_api
+                        .send(Box::new(response))
+                        .map_err(|_| ())
+                        .expect("one-shot channel closed");
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    panic!("The channel's sending half was disconnected. Cannot receive data.");
+                }
+            }
+        }
+    }
 }
 impl MutEventSubscriber for ApiServerAdapter {
     /// Handle a read event (EPOLLIN).
@@ -102,7 +206,17 @@ impl MutEventSubscriber for ApiServerAdapter {
                     panic!("The channel's sending half was disconnected. Cannot receive data.");
                 }
             };
+        } else if self.read_only_api.as_ref().map_or(false, |read_only_api| {
+            source == read_only_api.event_fd.as_raw_fd()
+        }) && event_set == EventSet::IN
+        {
+            self.handle_read_only_requests();
         } else {
+            #[cfg(feature = "faascale-mem")]
+            if source == self.stats_poll_timer.as_raw_fd() && event_set == EventSet::IN {
+                self.check_stats_poll();
+                return;
+            }
             error!("Spurious EventManager event for handler: ApiServerAdapter");
         }
     }
@@ -111,6 +225,15 @@ impl MutEventSubscriber for ApiServerAdapter {
         if let Err(err) = ops.add(Events::new(&self.api_event_fd, EventSet::IN)) {
             error!("Failed to register activate event: {}", err);
         }
+        if let Some(read_only_api) = self.read_only_api.as_ref() {
+            if let Err(err) = ops.add(Events::new(&read_only_api.event_fd, EventSet::IN)) {
+                error!("Failed to register read-only API event: {}", err);
+            }
+        }
+        #[cfg(feature = "faascale-mem")]
+        if let Err(err) = ops.add(Events::new(&self.stats_poll_timer, EventSet::IN)) {
+            error!("Failed to register statistics poll timer event: {}", err);
+        }
     }
 }
 
@@ -125,6 +248,8 @@ pub(crate) fn run_with_api(
     api_payload_limit: usize,
     mmds_size_limit: usize,
     metadata_json: Option<&str>,
+    balloon_compat: bool,
+    read_only_bind_path: Option<PathBuf>,
 ) -> FcExitCode {
     // FD to notify of API events. This is a blocking eventfd by design.
     // It is used in the config/pre-boot loop which is a simple blocking loop
@@ -144,11 +269,61 @@ pub(crate) fn run_with_api(
         .remove("api")
         .expect("Missing seccomp filter for API thread.");
 
+    let mut api_server = ApiServer::new(to_vmm, from_vmm, to_vmm_event_fd);
+    api_server.set_balloon_compat(balloon_compat);
+
+    // Start the read-only API thread, which has a channel to the VMM of its own. The VMM only
+    // serves that channel once the microVM runs, so the thread waits until then to bind its
+    // socket, and the socket is removed when the microVM stops. The thread is spawned right
+    // away, before the VMM thread installs its seccomp filter.
+    let mut read_only_api = None;
+    let mut read_only_start = None;
+    if let Some(read_only_bind_path) = read_only_bind_path.clone() {
+        let event_fd =
+            EventFd::new(libc::EFD_NONBLOCK).expect("Cannot create read-only API Eventfd.");
+        let (to_vmm, from_api) = channel();
+        let (to_api, from_vmm) = channel();
+        let mut read_only_server = api_server.read_only_server(
+            to_vmm,
+            from_vmm,
+            event_fd
+                .try_clone()
+                .expect("Failed to clone read-only API event FD"),
+        );
+        read_only_api = Some(ReadOnlyApiChannel {
+            event_fd,
+            from_api,
+            to_api,
+        });
+
+        let (start_sender, start_receiver) = channel::<()>();
+        read_only_start = Some(start_sender);
+        let read_only_seccomp_filter = api_seccomp_filter.clone();
+        thread::Builder::new()
+            .name("fc_api_read_only".to_owned())
+            .spawn(move || {
+                // The microVM could not be started.
+                if start_receiver.recv().is_err() {
+                    return;
+                }
+                if let Err(api_server::Error::ServerCreation(err)) = read_only_server.bind_and_run(
+                    &read_only_bind_path,
+                    &read_only_seccomp_filter,
+                    api_payload_limit,
+                ) {
+                    let sock_path = read_only_bind_path.display().to_string();
+                    error!("Failed to run the read-only API server at {sock_path}: {err}");
+                    std::process::exit(vmm::FcExitCode::GenericError as i32);
+                }
+            })
+            .expect("Read-only API thread spawn failed.");
+    }
+
     // Start the separate API thread.
     let api_thread = thread::Builder::new()
         .name("fc_api".to_owned())
         .spawn(move || {
-            match ApiServer::new(to_vmm, from_vmm, to_vmm_event_fd).bind_and_run(
+            match api_server.bind_and_run(
                 &api_bind_path,
                 process_time_reporter,
                 &api_seccomp_filter,
@@ -178,6 +353,10 @@ pub(crate) fn run_with_api(
     // Create the firecracker metrics object responsible for periodically printing metrics.
     let firecracker_metrics = Arc::new(Mutex::new(super::metrics::PeriodicMetrics::new()));
     event_manager.add_subscriber(firecracker_metrics.clone());
+    // The VMM thread cannot create the timer once its seccomp filter is installed.
+    #[cfg(feature = "faascale-mem")]
+    let stats_poll_timer = TimerFd::new_custom(ClockId::Monotonic, true, true)
+        .expect("Cannot create the statistics poll timer fd.");
 
     // Configure, build and start the microVM.
     let build_result = match config_json {
@@ -224,14 +403,31 @@ pub(crate) fn run_with_api(
                 .expect("Poisoned lock")
                 .start(super::metrics::WRITE_METRICS_PERIOD_MS);
 
-            ApiServerAdapter::run_microvm(
+            if let Some(start_sender) = read_only_start {
+                let _ = start_sender.send(());
+            }
+            let exit_code = ApiServerAdapter::run_microvm(
                 api_event_fd,
                 from_api,
                 to_api,
+                read_only_api,
                 vm_resources,
                 vmm,
                 &mut event_manager,
-            )
+                #[cfg(feature = "faascale-mem")]
+                stats_poll_timer,
+            );
+            // The read-only API thread goes down with the process.
+            if let Some(read_only_bind_path) = read_only_bind_path {
+                if let Err(err) = std::fs::remove_file(&read_only_bind_path) {
+                    warn!(
+                        "Failed to remove the read-only API socket at {}: {}",
+                        read_only_bind_path.display(),
+                        err
+                    );
+                }
+            }
+            exit_code
         }
         Err(exit_code) => exit_code,
     };
@@ -117,6 +117,16 @@ fn main_exitable() -> FcExitCode {
                 .default_value(DEFAULT_API_SOCK_PATH)
                 .help("Path to unix domain socket used by the API."),
         )
+        .arg(
+            Argument::new("api-sock-read-only")
+                .takes_value(true)
+                .forbids(vec!["no-api"])
+                .help(
+                    "Path to a second unix domain socket only serving the GET API requests, \
+                     for monitoring agents. It is bound once the microVM is started, and removed \
+                     when it stops.",
+                ),
+        )
         .arg(
             Argument::new("id")
                 .takes_value(true)
@@ -227,6 +237,16 @@ fn main_exitable() -> FcExitCode {
             Argument::new("mmds-size-limit")
                 .takes_value(true)
                 .help("Mmds data store limit, in bytes."),
+        )
+        .arg(
+            Argument::new("balloon-compat")
+                .takes_value(false)
+                .forbids(vec!["no-api"])
+                .help(
+                    "Serve the /balloon API requests with the faascale-mem device, for \
+                     orchestrators only speaking the upstream balloon API. Requires a build with \
+                     both the balloon and faascale-mem features.",
+                ),
         );
 
     let arguments = match arg_parser.parse_from_cmdline() {
@@ -259,6 +279,16 @@ fn main_exitable() -> FcExitCode {
         }
     };
 
+    // The balloon requests can only be translated when both devices are built.
+    #[cfg(not(all(feature = "balloon", feature = "faascale-mem")))]
+    if arguments.flag_present("balloon-compat") {
+        error!(
+            "Arguments parsing error: --balloon-compat requires the balloon and faascale-mem \
+             features."
+        );
+        return vmm::FcExitCode::ArgParsing;
+    }
+
     // Display warnings for any used deprecated parameters.
     // Currently unused since there are no deprecated parameters. Uncomment the line when
     // deprecating one.
@@ -307,6 +337,7 @@ fn main_exitable() -> FcExitCode {
     if let Some(metrics_path) = arguments.single_value("metrics-path") {
         let metrics_config = MetricsConfig {
             metrics_path: PathBuf::from(metrics_path),
+            statsd: None,
         };
         if let Err(err) = init_metrics(metrics_config) {
             return generic_error_exit(&format!("Could not initialize metrics: {}", err));
@@ -391,6 +422,10 @@ fn main_exitable() -> FcExitCode {
             api_payload_limit,
             mmds_size_limit,
             metadata_json.as_deref(),
+            arguments.flag_present("balloon-compat"),
+            arguments
+                .single_value("api-sock-read-only")
+                .map(PathBuf::from),
         )
     } else {
         let seccomp_filters: BpfThreadMap = seccomp_filters
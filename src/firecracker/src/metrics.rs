@@ -46,7 +46,7 @@ impl PeriodicMetrics {
     }
 
     fn write_metrics(&mut self) {
-        if let Err(err) = METRICS.write() {
+        if let Err(err) = METRICS.write_and_push() {
             METRICS.logger.missed_metrics_count.inc();
             error!("Failed to write metrics: {}", err);
         }
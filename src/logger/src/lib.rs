@@ -9,6 +9,7 @@
 mod init;
 mod logger;
 mod metrics;
+mod statsd;
 
 use std::sync::LockResult;
 
@@ -16,12 +17,15 @@ pub use log::Level::*;
 pub use log::{warn, *};
 
 pub use crate::logger::{LoggerError, LOGGER};
+#[cfg(feature = "faascale-mem")]
+pub use crate::metrics::FaascaleMemExperimentMetrics;
 #[cfg(target_arch = "aarch64")]
 pub use crate::metrics::RTCDeviceMetrics;
 pub use crate::metrics::{
     IncMetric, MetricsError, ProcessTimeReporter, SerialDeviceMetrics, SharedIncMetric,
     SharedStoreMetric, StoreMetric, METRICS,
 };
+pub use crate::statsd::StatsdSink;
 
 /// Prefix to be used in log lines for functions/modules in Firecracker
 /// that are not generally available.
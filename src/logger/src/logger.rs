@@ -214,6 +214,11 @@ impl Logger {
         self
     }
 
+    /// Returns the ID of this logger session, empty if not set.
+    pub fn instance_id(&self) -> String {
+        extract_guard(self.instance_id.read()).clone()
+    }
+
     /// Explicitly sets the max log level for the Logger.
     /// The default level is WARN. So, ERROR and WARN statements will be shown (i.e. all that is
     /// bigger than the level code).
@@ -700,6 +705,7 @@ mod tests {
 
         // Test with a mock instance id.
         logger.set_instance_id(TEST_INSTANCE_ID.to_string());
+        assert_eq!(logger.instance_id(), TEST_INSTANCE_ID);
 
         // Check that the prefix contains only the instance id when all flags are false.
         logger
@@ -72,6 +72,7 @@ use serde::{Serialize, Serializer};
 use vm_superio::rtc_pl031::RtcEvents;
 
 use super::extract_guard;
+use crate::statsd::StatsdSink;
 #[cfg(target_arch = "aarch64")]
 use crate::warn;
 
@@ -87,6 +88,8 @@ pub struct Metrics<T: Serialize> {
     // Metrics will get flushed here.
     metrics_buf: Mutex<Option<Box<dyn Write + Send>>>,
     is_initialized: AtomicBool,
+    // The memory device metrics also get pushed here, by `write_and_push`.
+    statsd_sink: Mutex<Option<StatsdSink>>,
     pub app_metrics: T,
 }
 
@@ -98,6 +101,7 @@ impl<T: Serialize> Metrics<T> {
         Metrics {
             metrics_buf: Mutex::new(None),
             is_initialized: AtomicBool::new(false),
+            statsd_sink: Mutex::new(None),
             app_metrics,
         }
     }
@@ -127,6 +131,17 @@ impl<T: Serialize> Metrics<T> {
         Ok(())
     }
 
+    /// Sets the statsd server the memory device metrics are pushed to at each `write_and_push`,
+    /// besides being written to the metrics destination. It can only be set once.
+    pub fn init_statsd(&self, sink: StatsdSink) -> Result<(), MetricsError> {
+        let mut g = extract_guard(self.statsd_sink.lock());
+        if g.is_some() {
+            return Err(MetricsError::AlreadyInitialized);
+        }
+        *g = Some(sink);
+        Ok(())
+    }
+
     /// Writes metrics to the destination provided as argument upon initialization of the metrics.
     /// Upon failure, an error is returned if metrics system is initialized and metrics could not be
     /// written.
@@ -147,9 +162,24 @@ impl<T: Serialize> Metrics<T> {
     /// The alternative is to hold a Mutex over the entire function call, but this increases the
     /// known deadlock potential.
     pub fn write(&self) -> Result<bool, MetricsError> {
+        self.flush(false)
+    }
+
+    /// Writes the metrics, as `write` does, and pushes the memory device ones to the statsd
+    /// server, if one is set. Pushing sends UDP datagrams, which the seccomp filters only allow on
+    /// the VMM thread, so the other threads, e.g. the signal handlers, stick to `write`.
+    pub fn write_and_push(&self) -> Result<bool, MetricsError> {
+        self.flush(true)
+    }
+
+    fn flush(&self, push: bool) -> Result<bool, MetricsError> {
         if self.is_initialized.load(Ordering::Relaxed) {
             match serde_json::to_string(&self.app_metrics) {
                 Ok(msg) => {
+                    // Serializing resets the counters, so the pushed metrics are parsed back from
+                    // the written ones rather than serialized again. The statsd lock is not taken
+                    // when not pushing, so the signal handlers cannot deadlock on it.
+                    let pushed = if push { self.push(&msg) } else { Ok(()) };
                     if let Some(guard) = extract_guard(self.metrics_buf.lock()).as_mut() {
                         // No need to explicitly call flush because the underlying LineWriter
                         // flushes automatically whenever a newline is
@@ -158,6 +188,7 @@ impl<T: Serialize> Metrics<T> {
                         guard
                             .write_all(format!("{msg}\n",).as_bytes())
                             .map_err(MetricsError::Write)
+                            .and_then(|_| pushed)
                             .map(|_| true)
                     } else {
                         // We have not incremented `missed_metrics_count` as there is no way to push
@@ -176,6 +207,16 @@ impl<T: Serialize> Metrics<T> {
             Ok(false)
         }
     }
+
+    // Pushes the serialized metrics `msg` to the statsd server, if one is set.
+    fn push(&self, msg: &str) -> Result<(), MetricsError> {
+        match extract_guard(self.statsd_sink.lock()).as_ref() {
+            Some(sink) => serde_json::from_str::<serde_json::Value>(msg)
+                .map_err(|err| MetricsError::Serde(err.to_string()))
+                .and_then(|metrics| sink.push(&metrics).map_err(MetricsError::Statsd)),
+            None => Ok(()),
+        }
+    }
 }
 
 impl<T: Serialize> Deref for Metrics<T> {
@@ -201,6 +242,9 @@ pub enum MetricsError {
     /// Writing the specified buffer failed.
     #[error("Failed to write metrics: {0}")]
     Write(std::io::Error),
+    /// Pushing the metrics to the statsd server failed.
+    #[error("Failed to push metrics to statsd: {0}")]
+    Statsd(std::io::Error),
 }
 
 /// Used for defining new types of metrics that act as a counter (i.e they are continuously updated
@@ -283,9 +327,14 @@ impl Serialize for SharedIncMetric {
     }
 }
 
+/// Name the `SharedStoreMetric`s are serialized under, as newtype structs. JSON leaves it out,
+/// while it lets the statsd sink tell them apart from the counters.
+pub(crate) const STORE_METRIC_NAME: &str = "SharedStoreMetric";
+
 impl Serialize for SharedStoreMetric {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_u64(self.0.load(Ordering::Relaxed) as u64)
+        serializer
+            .serialize_newtype_struct(STORE_METRIC_NAME, &(self.0.load(Ordering::Relaxed) as u64))
     }
 }
 
@@ -367,6 +416,10 @@ pub struct GetRequestsMetrics {
     pub mmds_count: SharedIncMetric,
     /// Number of GETs for getting the VMM version.
     pub vmm_version_count: SharedIncMetric,
+    /// Number of GETs for listing the memory device routes.
+    pub routes_count: SharedIncMetric,
+    /// Number of GETs for the audit log of the memory device requests.
+    pub audit_count: SharedIncMetric,
 }
 
 /// Metrics specific to PUT API Requests for counting user triggered actions and/or failures.
@@ -444,7 +497,39 @@ pub struct DeprecatedApiMetrics {
     pub deprecated_cmd_line_api_calls: SharedIncMetric,
 }
 
+/// Failures of a memory management syscall, broken down by errno since the remediation
+/// depends on the cause.
+#[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+#[derive(Default, Serialize)]
+pub struct SyscallErrnoMetrics {
+    /// Failures with `ENOMEM`, usually the memory limit of the cgroup.
+    pub enomem: SharedIncMetric,
+    /// Failures with `EINVAL`, usually a bad range.
+    pub einval: SharedIncMetric,
+    /// Failures with `EAGAIN`, usually a racing unmap or a transient kernel shortage.
+    pub eagain: SharedIncMetric,
+    /// Failures with `EPERM`, usually the seccomp filters.
+    pub eperm: SharedIncMetric,
+    /// Failures with any other errno.
+    pub other: SharedIncMetric,
+}
+
+#[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+impl SyscallErrnoMetrics {
+    /// Counts a failure of the syscall with the errno of `err`.
+    pub fn record(&self, err: &std::io::Error) {
+        match err.raw_os_error() {
+            Some(libc::ENOMEM) => self.enomem.inc(),
+            Some(libc::EINVAL) => self.einval.inc(),
+            Some(libc::EAGAIN) => self.eagain.inc(),
+            Some(libc::EPERM) => self.eperm.inc(),
+            _ => self.other.inc(),
+        }
+    }
+}
+
 /// Balloon Device associated metrics.
+#[cfg(feature = "balloon")]
 #[derive(Default, Serialize)]
 pub struct BalloonDeviceMetrics {
     /// Number of times when activate failed on a balloon device.
@@ -455,13 +540,30 @@ pub struct BalloonDeviceMetrics {
     pub stats_updates_count: SharedIncMetric,
     // Number of balloon statistics update failures.
     pub stats_update_fails: SharedIncMetric,
+    /// Number of statistics with a tag unknown to the device, skipped.
+    pub stats_unknown_tags: SharedIncMetric,
     /// Number of balloon device deflations.
     pub deflate_count: SharedIncMetric,
+    /// Number of deflate notifications held back for the next ones to share.
+    pub irq_moderation_held: SharedIncMetric,
+    /// Number of held deflate notifications raised once the hold window elapsed.
+    pub irq_moderation_timeouts: SharedIncMetric,
+    /// Number of failures while prefetching deflated memory ranges.
+    pub deflate_prefetch_fails: SharedIncMetric,
+    /// Number of target size updates clamped to keep the guest memory floor.
+    pub floor_clamps: SharedIncMetric,
+    /// Failed `madvise` calls on the guest memory, by errno.
+    pub madvise_fails: SyscallErrnoMetrics,
+    /// Failed `mmap` calls over the guest memory of restored microVMs, by errno.
+    pub mmap_fails: SyscallErrnoMetrics,
     /// Number of times when handling events on a balloon device failed.
     pub event_fails: SharedIncMetric,
+    /// Number of queue events left unprocessed while the microVM was paused.
+    pub paused_deferred_events: SharedIncMetric,
 }
 
 /// FaascaleMem Device associated metrics.
+#[cfg(feature = "faascale-mem")]
 #[derive(Default, Serialize)]
 pub struct FaascaleMemMetrics {
     /// Number of times when activate failed on a balloon device.
@@ -472,10 +574,203 @@ pub struct FaascaleMemMetrics {
     pub stats_updates_count: SharedIncMetric,
     // Number of balloon statistics update failures.
     pub stats_update_fails: SharedIncMetric,
+    /// Number of statistics with a tag unknown to the device, skipped.
+    pub stats_unknown_tags: SharedIncMetric,
+    /// Number of changes of the statistics polling interval following the memory pressure of
+    /// the guest.
+    pub stats_polling_adaptations: SharedIncMetric,
+    /// Number of statistics collections requested by the host ahead of the polling interval.
+    pub stats_polls_now: SharedIncMetric,
+    /// Number of statistics collections requested by the host the guest did not answer in time.
+    pub stats_poll_timeouts: SharedIncMetric,
+    /// Number of statistics refreshes requested by the host without waiting for the guest.
+    pub stats_refreshes: SharedIncMetric,
     /// Number of balloon device deflations.
     pub depopulate_count: SharedIncMetric,
+    /// Number of depopulated ranges freed with `MADV_DONTNEED` because their backing refused the
+    /// configured depopulate mode.
+    pub depopulate_mode_fallbacks: SharedIncMetric,
+    /// Number of populated ranges pre-allocated by touching their pages because the host lacks
+    /// `MADV_POPULATE_WRITE`.
+    pub pre_alloc_touch_fallbacks: SharedIncMetric,
+    /// Failed `madvise` calls on the guest memory, by errno.
+    pub madvise_fails: SyscallErrnoMetrics,
+    /// Failed `mmap` calls over the guest memory of restored microVMs, by errno.
+    pub mmap_fails: SyscallErrnoMetrics,
     /// Number of times when handling events on a balloon device failed.
     pub event_fails: SharedIncMetric,
+    /// Number of queue events left unprocessed while the microVM was paused.
+    pub paused_deferred_events: SharedIncMetric,
+    /// Number of populated bytes zeroed explicitly.
+    pub scrubbed_bytes: SharedIncMetric,
+    /// Number of populated blocks not holding the canary once written, when the populate
+    /// verification is enabled.
+    pub populate_verification_failures: SharedIncMetric,
+    /// Number of populated bytes zeroed because they were recycled rather than freshly
+    /// faulted, once the guest negotiated zero-filled pages.
+    pub recycled_zeroed_bytes: SharedIncMetric,
+    /// Number of bytes of guest memory advised mergeable, for KSM to deduplicate.
+    pub ksm_advised_bytes: SharedIncMetric,
+    /// Number of bytes of guest memory advised unmergeable.
+    pub ksm_unadvised_bytes: SharedIncMetric,
+    /// Number of blocks whose KSM advice failed.
+    pub ksm_advise_fails: SharedIncMetric,
+    /// Number of populated bytes advised with `MADV_HUGEPAGE`. The memory actually backed by
+    /// transparent huge pages is reported in the footprint of the device.
+    pub thp_advised_bytes: SharedIncMetric,
+    /// Number of failed attempts to collapse populated blocks into huge pages.
+    pub thp_collapse_fails: SharedIncMetric,
+    /// Number of populated bytes pre-allocated interleaved across NUMA nodes.
+    pub interleaved_bytes: SharedIncMetric,
+    /// Number of failures to set or reset the interleaving policy of populated blocks.
+    pub interleave_fails: SharedIncMetric,
+    /// Number of populated bytes placed on the configured NUMA node.
+    pub numa_node_bytes: SharedIncMetric,
+    /// Number of failures to place populated blocks on the configured NUMA node.
+    pub numa_node_fails: SharedIncMetric,
+    /// Number of blocks refused because they do not cover whole hugetlb pages.
+    pub hugetlb_misaligned_blocks: SharedIncMetric,
+    /// Number of blocks split at the boundaries of the KVM memory slots they span.
+    pub memslot_splits: SharedIncMetric,
+    /// Number of re-submitted populate blocks completed without populating them again.
+    pub populate_dedup_hits: SharedIncMetric,
+    /// Number of ranges held by the populated-range tracker.
+    pub tracker_entries: SharedStoreMetric,
+    /// Number of populated ranges merged with an overlapping or adjacent tracked range.
+    pub tracker_merges: SharedIncMetric,
+    /// Number of tracked ranges dropped to keep the tracker within its size limit.
+    pub tracker_spills: SharedIncMetric,
+    /// Number of depopulate blocks refused because they overlap a pinned range.
+    pub depopulate_pinned_refusals: SharedIncMetric,
+    /// Number of guest pages pinned against depopulation.
+    pub pinned_pages: SharedStoreMetric,
+    /// Number of guest pages locked into host memory.
+    pub mlocked_pages: SharedStoreMetric,
+    /// Number of blocks left unlocked because the locking budget was exhausted.
+    pub mlock_budget_refusals: SharedIncMetric,
+    /// Number of failures to lock or unlock blocks into host memory.
+    pub mlock_fails: SharedIncMetric,
+    /// Number of events associated with the rate limiter of the populate and depopulate
+    /// requests.
+    pub rate_limiter_event_count: SharedIncMetric,
+    /// Number of populate and depopulate requests throttled by the rate limiter.
+    pub rate_limiter_throttled_events: SharedIncMetric,
+    /// Number of populate and depopulate requests whose status could not be written back.
+    pub request_status_fails: SharedIncMetric,
+    /// Number of failures to publish the memory state into MMDS.
+    pub mmds_publish_fails: SharedIncMetric,
+    /// Number of guest connections to the vsock observer port.
+    pub observer_connections: SharedIncMetric,
+    /// Number of failures to accept or answer the guest connections to the vsock observer port.
+    pub observer_fails: SharedIncMetric,
+    /// Number of descriptors popped from a queue and not returned to the guest by the pass
+    /// processing them.
+    pub descriptor_leaks: SharedIncMetric,
+    /// Number of depopulate notifications held back for the next ones to share.
+    pub irq_moderation_held: SharedIncMetric,
+    /// Number of held depopulate notifications raised once the hold window elapsed.
+    pub irq_moderation_timeouts: SharedIncMetric,
+    /// Number of used buffer notifications the guest suppressed through the event index.
+    pub event_idx_suppressed: SharedIncMetric,
+    /// Time between noticing the last populate queue kick and populating its first block,
+    /// in microseconds.
+    pub populate_latency_us: SharedStoreMetric,
+    /// Number of populate queue kicks handled by the latency mode poller thread.
+    pub populate_poller_wakeups: SharedIncMetric,
+    /// Number of scheduling knobs that failed to apply to the worker threads.
+    pub worker_scheduling_fails: SharedIncMetric,
+    /// Number of memory budgets acknowledged by the guest.
+    pub budget_acks: SharedIncMetric,
+    /// Number of populate blocks refused because they exceed the agreed memory budget.
+    pub budget_violations: SharedIncMetric,
+    /// Number of populate blocks refused because they exceed the populated memory cap.
+    pub max_populated_refusals: SharedIncMetric,
+    /// Number of populate blocks refused because the device is fenced.
+    pub populate_fenced_refusals: SharedIncMetric,
+    /// Number of guest pages depopulated at the request of the host.
+    pub host_depopulated_pages: SharedIncMetric,
+    /// Number of guest pages populated by the host ahead of the guest.
+    pub prepopulated_pages: SharedIncMetric,
+    /// Number of populated guest pages faulted in from the snapshot memory file after a restore.
+    pub rehydrated_pages: SharedIncMetric,
+    /// Number of populated blocks that failed to be faulted in after a restore.
+    pub rehydrate_fails: SharedIncMetric,
+    /// Number of requests asking the guest to release memory.
+    pub release_requests: SharedIncMetric,
+    /// Number of device resets by the guest driver, each starting a new driver session.
+    pub driver_resets: SharedIncMetric,
+    /// Number of guest pages depopulated when the guest driver reset the device.
+    pub reset_depopulated_pages: SharedIncMetric,
+    /// Number of config space changes the guest acknowledged after the timeout.
+    pub late_config_acks: SharedIncMetric,
+    /// Number of `KVM_PREALLOC_USER_MEMORY_REGION` ioctls issued to pre-handle TDP faults.
+    pub prefault_ioctls: SharedIncMetric,
+    /// Number of populated blocks whose TDP faults were pre-handled along with a contiguous
+    /// block, without an ioctl of their own.
+    pub prefault_coalesced_blocks: SharedIncMetric,
+    /// Number of huge pages depopulated in pieces and released with one aligned `madvise`
+    /// instead of being split.
+    pub thp_splits_avoided: SharedIncMetric,
+    /// Number of pieces of huge pages released on their own after waiting too long for the rest
+    /// of the huge page.
+    pub thp_batch_expired: SharedIncMetric,
+    /// Number of depopulated guest pages held back until their huge page is fully depopulated.
+    pub thp_batch_pending_pages: SharedStoreMetric,
+    /// Number of bytes of populated blocks taken from the reserved host memory pool.
+    pub pool_populated_bytes: SharedIncMetric,
+    /// Number of populate blocks the reserved host memory pool could not back.
+    pub pool_fallbacks: SharedIncMetric,
+    /// Number of bytes of the reserved host memory pool lent to the guest.
+    pub pool_used_bytes: SharedStoreMetric,
+    /// Number of populated blocks whose contents were restored from the block cache.
+    pub block_cache_hits: SharedIncMetric,
+    /// Number of populated blocks not found in the block cache.
+    pub block_cache_misses: SharedIncMetric,
+    /// Number of cached blocks evicted to make room for newer ones.
+    pub block_cache_evictions: SharedIncMetric,
+    /// Number of bytes of compressed block contents held by the block cache.
+    pub block_cache_bytes: SharedStoreMetric,
+    /// Number of bytes of depopulated block contents held by the spill file.
+    pub spilled_bytes: SharedStoreMetric,
+    /// Number of spilled pages read back into the blocks populated again.
+    pub spill_restored_pages: SharedIncMetric,
+    /// Number of blocks that could not be written to or read back from the spill file.
+    pub spill_fails: SharedIncMetric,
+    /// Number of populated pages filled from the template memory file.
+    pub template_filled_pages: SharedIncMetric,
+    /// Number of blocks that could not be filled from the template memory file.
+    pub template_fails: SharedIncMetric,
+    /// Number of bytes of populated blocks registered to be backed on their first touch.
+    pub lazy_registered_bytes: SharedIncMetric,
+    /// Number of pages of the lazily populated blocks backed on their first touch.
+    pub lazy_faults: SharedIncMetric,
+    /// Number of failures registering, unregistering or backing the lazily populated blocks.
+    pub lazy_populate_fails: SharedIncMetric,
+    /// Number of runs of the policy program.
+    pub policy_runs: SharedIncMetric,
+    /// Number of runs of the policy program that failed or went over their budget, and of
+    /// decisions that could not be applied.
+    pub policy_fails: SharedIncMetric,
+    /// Number of runs of the policy program stopped for going over their step or time budget.
+    pub policy_budget_exceeded: SharedIncMetric,
+    /// Populate blocks handled with variant A of the population policy experiment.
+    pub experiment_a: FaascaleMemExperimentMetrics,
+    /// Populate blocks handled with variant B of the population policy experiment.
+    pub experiment_b: FaascaleMemExperimentMetrics,
+}
+
+/// Metrics of one variant of a faascale-mem population policy experiment.
+#[cfg(feature = "faascale-mem")]
+#[derive(Default, Serialize)]
+pub struct FaascaleMemExperimentMetrics {
+    /// Number of populate blocks handled with the variant.
+    pub populate_count: SharedIncMetric,
+    /// Number of populate blocks the variant failed to populate.
+    pub populate_fails: SharedIncMetric,
+    /// Time spent populating the blocks, in microseconds.
+    pub populate_us: SharedIncMetric,
+    /// Number of host page faults taken while populating the blocks.
+    pub page_faults: SharedIncMetric,
 }
 
 
@@ -845,9 +1140,11 @@ pub struct FirecrackerMetrics {
     utc_timestamp_ms: SerializeToUtcTimestampMs,
     /// API Server related metrics.
     pub api_server: ApiServerMetrics,
+    #[cfg(feature = "balloon")]
     /// A balloon device's related metrics.
     pub balloon: BalloonDeviceMetrics,
-    /// A balloon device's related metrics.
+    #[cfg(feature = "faascale-mem")]
+    /// A faascale-mem device's related metrics.
     pub faascale_mem: FaascaleMemMetrics,
     /// A block device's related metrics.
     pub block: BlockDeviceMetrics,
@@ -917,6 +1214,34 @@ mod tests {
         assert!(m.init(Box::new(f.into_file()),).is_err());
     }
 
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_write_and_push() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sink = || StatsdSink::new(server.local_addr().unwrap(), "fc", &[]).unwrap();
+        let m = Metrics::new(FirecrackerMetrics::default());
+        let f = TempFile::new().expect("Failed to create temporary metrics file");
+        assert!(m.init(Box::new(f.into_file())).is_ok());
+        assert!(m.init_statsd(sink()).is_ok());
+        assert!(m.init_statsd(sink()).is_err());
+        server.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 4096];
+
+        m.faascale_mem.pinned_pages.store(5);
+        m.faascale_mem.populate_count.add(3);
+        // Only the VMM thread pushes the metrics.
+        assert!(m.write().unwrap());
+        assert!(server.recv(&mut buf).is_err());
+
+        m.faascale_mem.populate_count.add(2);
+        assert!(m.write_and_push().unwrap());
+        let len = server.recv(&mut buf).unwrap();
+        let datagram = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(datagram.contains("fc.faascale_mem.pinned_pages:5|g"));
+        // The counters are pushed with the increment since the previous flush.
+        assert!(datagram.contains("fc.faascale_mem.populate_count:2|c"));
+    }
+
     #[test]
     fn test_shared_inc_metric() {
         let metric = Arc::new(SharedIncMetric::default());
@@ -960,6 +1285,28 @@ mod tests {
         assert_eq!(1, m1.fetch());
     }
 
+    #[test]
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    fn test_syscall_errno_metrics() {
+        let metrics = SyscallErrnoMetrics::default();
+        for errno in [
+            libc::ENOMEM,
+            libc::ENOMEM,
+            libc::EINVAL,
+            libc::EPERM,
+            libc::EBADF,
+        ] {
+            metrics.record(&std::io::Error::from_raw_os_error(errno));
+        }
+        metrics.record(&std::io::Error::new(ErrorKind::Other, "no errno"));
+
+        assert_eq!(metrics.enomem.count(), 2);
+        assert_eq!(metrics.einval.count(), 1);
+        assert_eq!(metrics.eagain.count(), 0);
+        assert_eq!(metrics.eperm.count(), 1);
+        assert_eq!(metrics.other.count(), 2);
+    }
+
     #[test]
     fn test_serialize() {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
@@ -986,6 +1333,13 @@ mod tests {
             ),
             "Failed to write metrics: write"
         );
+        assert_eq!(
+            format!(
+                "{}",
+                MetricsError::Statsd(std::io::Error::new(ErrorKind::Interrupted, "send"))
+            ),
+            "Failed to push metrics to statsd: send"
+        );
         assert_eq!(
             format!(
                 "{}",
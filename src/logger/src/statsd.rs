@@ -0,0 +1,367 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pushes the memory device metrics to a statsd server.
+//!
+//! At each flush, the `balloon` and `faascale_mem` sections of the metrics are sent over UDP, one
+//! statsd line per metric, named after its path in the JSON metrics:
+//! ```text
+//! <prefix>.faascale_mem.populate_count:12|c|#vm_id:vm0
+//! <prefix>.faascale_mem.pinned_pages:4096|g|#vm_id:vm0
+//! ```
+//! The `SharedIncMetric`s are pushed as counters, with the increment since the previous flush,
+//! and skipped when they did not change. The `SharedStoreMetric`s are pushed as gauges. The tags
+//! use the DogStatsD extension of the protocol.
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use serde::ser::{self, Impossible, Serialize, SerializeStruct, Serializer};
+use serde_json::Value;
+
+use crate::metrics::{FirecrackerMetrics, STORE_METRIC_NAME};
+
+/// Sections of the metrics pushed to the statsd server.
+const STATSD_SECTIONS: &[&str] = &["balloon", "faascale_mem"];
+
+/// Largest datagram sent to the statsd server, which fits the MTU of most networks.
+const STATSD_MAX_DATAGRAM: usize = 1432;
+
+/// UDP client of a statsd server.
+#[derive(Debug)]
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+    // The tags attached to every line, as `|#key:value,...`, or empty.
+    tags: String,
+    // Paths of the metrics pushed as gauges.
+    gauges: HashSet<String>,
+}
+
+impl StatsdSink {
+    /// Creates a client of the statsd server at `address`. The metric names start with `prefix`
+    /// and the `tags` are attached to each of them.
+    pub fn new(address: SocketAddr, prefix: &str, tags: &[(&str, &str)]) -> io::Result<Self> {
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        let tags = tags
+            .iter()
+            .map(|(key, value)| format!("{}:{}", key, value))
+            .collect::<Vec<_>>()
+            .join(",");
+        Ok(StatsdSink {
+            socket,
+            prefix: prefix.trim_end_matches('.').to_owned(),
+            tags: if tags.is_empty() {
+                tags
+            } else {
+                format!("|#{}", tags)
+            },
+            gauges: gauge_paths(&FirecrackerMetrics::default())?,
+        })
+    }
+
+    /// Pushes the memory device sections of the serialized `metrics`.
+    pub(crate) fn push(&self, metrics: &Value) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for section in STATSD_SECTIONS {
+            if let Some(value) = metrics.get(section) {
+                self.lines(section, value, &mut lines);
+            }
+        }
+
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > STATSD_MAX_DATAGRAM {
+                self.socket.send(datagram.as_bytes())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(datagram.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Appends the statsd lines of the metric at `path`, and of the ones nested in it.
+    fn lines(&self, path: &str, value: &Value, lines: &mut Vec<String>) {
+        match value {
+            Value::Object(fields) => {
+                for (name, value) in fields {
+                    self.lines(&format!("{}.{}", path, name), value, lines);
+                }
+            }
+            Value::Number(number) => {
+                let number = match number.as_u64() {
+                    Some(number) => number,
+                    None => return,
+                };
+                let kind = if self.gauges.contains(path) {
+                    "g"
+                } else if number == 0 {
+                    return;
+                } else {
+                    "c"
+                };
+                lines.push(format!(
+                    "{}.{}:{}|{}{}",
+                    self.prefix, path, number, kind, self.tags
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the paths of the `SharedStoreMetric`s of `metrics`, which are serialized as newtype
+/// structs rather than plain numbers.
+fn gauge_paths<T: Serialize>(metrics: &T) -> Result<HashSet<String>, serde_json::Error> {
+    let mut collector = GaugeCollector::default();
+    metrics.serialize(&mut collector)?;
+    Ok(collector.gauges)
+}
+
+// Walks the metrics structs, keeping the paths of the `SharedStoreMetric`s.
+#[derive(Default)]
+struct GaugeCollector {
+    path: Vec<&'static str>,
+    gauges: HashSet<String>,
+}
+
+// The metrics are structs of numbers, the other values are skipped.
+macro_rules! skip_values {
+    ($($method:ident: $ty:ty),*) => {
+        $(fn $method(self, _: $ty) -> Result<(), serde_json::Error> {
+            Ok(())
+        })*
+    };
+}
+
+fn unsupported<T>() -> Result<T, serde_json::Error> {
+    Err(ser::Error::custom(
+        "the metrics only hold structs and numbers",
+    ))
+}
+
+impl<'a> Serializer for &'a mut GaugeCollector {
+    type Ok = ();
+    type Error = serde_json::Error;
+    type SerializeSeq = Impossible<(), serde_json::Error>;
+    type SerializeTuple = Impossible<(), serde_json::Error>;
+    type SerializeTupleStruct = Impossible<(), serde_json::Error>;
+    type SerializeTupleVariant = Impossible<(), serde_json::Error>;
+    type SerializeMap = Impossible<(), serde_json::Error>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), serde_json::Error>;
+
+    skip_values!(
+        serialize_bool: bool,
+        serialize_i8: i8,
+        serialize_i16: i16,
+        serialize_i32: i32,
+        serialize_i64: i64,
+        serialize_u8: u8,
+        serialize_u16: u16,
+        serialize_u32: u32,
+        serialize_u64: u64,
+        serialize_f32: f32,
+        serialize_f64: f64,
+        serialize_char: char,
+        serialize_str: &str,
+        serialize_bytes: &[u8],
+        serialize_unit_struct: &'static str
+    );
+
+    fn serialize_none(self) -> Result<(), serde_json::Error> {
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), serde_json::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), serde_json::Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<(), serde_json::Error> {
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), serde_json::Error> {
+        if name == STORE_METRIC_NAME {
+            self.gauges.insert(self.path.join("."));
+            return Ok(());
+        }
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<(), serde_json::Error> {
+        unsupported()
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, serde_json::Error> {
+        unsupported()
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, serde_json::Error> {
+        unsupported()
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, serde_json::Error> {
+        unsupported()
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, serde_json::Error> {
+        unsupported()
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, serde_json::Error> {
+        unsupported()
+    }
+
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStruct, serde_json::Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, serde_json::Error> {
+        unsupported()
+    }
+}
+
+impl<'a> SerializeStruct for &'a mut GaugeCollector {
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), serde_json::Error> {
+        self.path.push(key);
+        let res = value.serialize(&mut **self);
+        self.path.pop();
+        res
+    }
+
+    fn end(self) -> Result<(), serde_json::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pushed(sink: &StatsdSink, server: &UdpSocket, metrics: &Value) -> Vec<String> {
+        sink.push(metrics).unwrap();
+        let mut lines = Vec::new();
+        let mut buf = [0u8; STATSD_MAX_DATAGRAM];
+        server.set_nonblocking(true).unwrap();
+        while let Ok(len) = server.recv(&mut buf) {
+            assert!(len <= STATSD_MAX_DATAGRAM);
+            let datagram = std::str::from_utf8(&buf[..len]).unwrap();
+            lines.extend(datagram.lines().map(str::to_owned));
+        }
+        lines
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_push() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+
+        let sink = StatsdSink::new(address, "fc.", &[("vm_id", "vm0"), ("host", "h1")]).unwrap();
+        let metrics = serde_json::json!({
+            "utc_timestamp_ms": 1,
+            "block": { "read_count": 3 },
+            "balloon": { "inflate_count": 2, "activate_fails": 0 },
+            "faascale_mem": {
+                "pinned_pages": 0,
+                "madvise_fails": { "enomem": 1, "eperm": 0 },
+            },
+        });
+        let mut lines = pushed(&sink, &server, &metrics);
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "fc.balloon.inflate_count:2|c|#vm_id:vm0,host:h1",
+                "fc.faascale_mem.madvise_fails.enomem:1|c|#vm_id:vm0,host:h1",
+                "fc.faascale_mem.pinned_pages:0|g|#vm_id:vm0,host:h1",
+            ]
+        );
+
+        // Many metrics are split across datagrams.
+        let sink = StatsdSink::new(address, "fc", &[]).unwrap();
+        let fields = (0..200)
+            .map(|i| (format!("metric_{}", i), Value::from(1)))
+            .collect::<serde_json::Map<_, _>>();
+        let metrics = serde_json::json!({ "faascale_mem": fields });
+        let lines = pushed(&sink, &server, &metrics);
+        assert_eq!(lines.len(), 200);
+        assert!(lines.contains(&"fc.faascale_mem.metric_7:1|c".to_owned()));
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_gauges() {
+        // The gauges are told apart from the counters by the type of the metrics.
+        let gauges = gauge_paths(&FirecrackerMetrics::default()).unwrap();
+        assert!(gauges.contains("faascale_mem.pinned_pages"));
+        assert!(gauges.contains("faascale_mem.mlocked_pages"));
+        assert!(!gauges.contains("faascale_mem.populate_count"));
+        assert!(!gauges.contains("faascale_mem.experiment_a.populate_count"));
+
+        // The JSON metrics do not show the difference.
+        let metrics = crate::metrics::SharedStoreMetric::default();
+        crate::StoreMetric::store(&metrics, 5);
+        assert_eq!(serde_json::to_string(&metrics).unwrap(), "5");
+    }
+}
@@ -44,11 +44,13 @@
 //! trait and provides an *event-handler* as part of its API. This *event-handler*
 //! needs to be called by the user on every event on the rate limiter's `AsRawFd` FD.
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::{fmt, io};
 
 use logger::error;
-use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use timerfd::{SetTimeFlags, TimerState};
+use utils::clock::{Clock, MonotonicClock, Timer};
 
 pub mod persist;
 
@@ -157,10 +159,9 @@ impl TokenBucket {
     }
 
     // Replenishes token bucket based on elapsed time. Should only be called internally by `Self`.
-    fn auto_replenish(&mut self) {
+    fn auto_replenish(&mut self, now: Instant) {
         // Compute time passed since last refill/update.
-        let now = Instant::now();
-        let time_delta = (now - self.last_update).as_nanos();
+        let time_delta = now.saturating_duration_since(self.last_update).as_nanos();
 
         if time_delta >= u128::from(self.refill_time * NANOSEC_IN_ONE_MILLISEC) {
             self.budget = self.size;
@@ -210,13 +211,18 @@ impl TokenBucket {
     }
 
     /// Attempts to consume `tokens` from the bucket and returns whether the action succeeded.
-    pub fn reduce(&mut self, mut tokens: u64) -> BucketReduction {
+    pub fn reduce(&mut self, tokens: u64) -> BucketReduction {
+        self.reduce_at(tokens, Instant::now())
+    }
+
+    // Attempts to consume `tokens` from the bucket at time `now`.
+    fn reduce_at(&mut self, mut tokens: u64, now: Instant) -> BucketReduction {
         // First things first: consume the one-time-burst budget.
         if self.one_time_burst > 0 {
             // We still have burst budget for *all* tokens requests.
             if self.one_time_burst >= tokens {
                 self.one_time_burst -= tokens;
-                self.last_update = Instant::now();
+                self.last_update = now;
                 // No need to continue to the refill process, we still have burst budget to consume
                 // from.
                 return BucketReduction::Success;
@@ -230,7 +236,7 @@ impl TokenBucket {
 
         if tokens > self.budget {
             // Hit the bucket bottom, let's auto-replenish and try again.
-            self.auto_replenish();
+            self.auto_replenish(now);
 
             // This operation requests a bandwidth higher than the bucket size
             if tokens > self.size {
@@ -320,7 +326,8 @@ pub enum BucketUpdate {
 /// Bandwidth (bytes/s) and ops/s limiting can be used at the same time or individually.
 ///
 /// Implementation uses a single timer through TimerFd to refresh either or
-/// both token buckets.
+/// both token buckets. The timer, and the time the buckets replenish with, come from a
+/// `Clock`, the host one unless `set_clock` is called.
 ///
 /// Its internal buckets are 'passively' replenished as they're being used (as
 /// part of `consume()` operations).
@@ -334,7 +341,8 @@ pub struct RateLimiter {
     bandwidth: Option<TokenBucket>,
     ops: Option<TokenBucket>,
 
-    timer_fd: TimerFd,
+    clock: Arc<dyn Clock>,
+    timer: Box<dyn Timer>,
     // Internal flag that quickly determines timer state.
     timer_active: bool,
 }
@@ -400,20 +408,36 @@ impl RateLimiter {
         // We'll need a timer_fd, even if our current config effectively disables rate limiting,
         // because `Self::update_buckets()` might re-enable it later, and we might be
         // seccomp-blocked from creating the timer_fd at that time.
-        let timer_fd = TimerFd::new_custom(ClockId::Monotonic, true, true)?;
+        let clock = MonotonicClock::shared();
+        let timer = clock.timer()?;
 
         Ok(RateLimiter {
             bandwidth: bytes_token_bucket,
             ops: ops_token_bucket,
-            timer_fd,
+            clock,
+            timer,
             timer_active: false,
         })
     }
 
+    /// Runs the rate limiter on `clock`: the token buckets replenish, and the limiter
+    /// unblocks, as it moves. Must be called before the FD of the limiter is monitored, and
+    /// leaves the limiter unblocked.
+    ///
+    /// # Errors
+    ///
+    /// If the timer creation fails, an error is returned.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> io::Result<()> {
+        self.timer = clock.timer()?;
+        self.clock = clock;
+        self.timer_active = false;
+        Ok(())
+    }
+
     // Arm the timer of the rate limiter with the provided `TimerState`.
     fn activate_timer(&mut self, timer_state: TimerState) {
         // Register the timer; don't care about its previous state
-        self.timer_fd.set_state(timer_state, SetTimeFlags::Default);
+        self.timer.set_state(timer_state, SetTimeFlags::Default);
         self.timer_active = true;
     }
 
@@ -427,6 +451,7 @@ impl RateLimiter {
         }
 
         // Identify the required token bucket.
+        let now = self.clock.now();
         let token_bucket = match token_type {
             TokenType::Bytes => self.bandwidth.as_mut(),
             TokenType::Ops => self.ops.as_mut(),
@@ -434,7 +459,7 @@ impl RateLimiter {
         // Try to consume from the token bucket.
         if let Some(bucket) = token_bucket {
             let refill_time = bucket.refill_time_ms();
-            match bucket.reduce(tokens) {
+            match bucket.reduce_at(tokens, now) {
                 // When we report budget is over, there will be no further calls here,
                 // register a timer to replenish the bucket and resume processing;
                 // make sure there is only one running timer for this limiter.
@@ -501,7 +526,7 @@ impl RateLimiter {
     ///
     /// If the rate limiter is disabled or is not blocked, an error is returned.
     pub fn event_handler(&mut self) -> Result<(), Error> {
-        match self.timer_fd.read() {
+        match self.timer.read() {
             0 => Err(Error::SpuriousRateLimiterEvent(
                 "Rate limiter event handler called without a present timer",
             )),
@@ -546,7 +571,7 @@ impl AsRawFd for RateLimiter {
     /// Will return a negative value if rate limiting is disabled on both
     /// token types.
     fn as_raw_fd(&self) -> RawFd {
-        self.timer_fd.as_raw_fd()
+        self.timer.as_raw_fd()
     }
 }
 
@@ -613,28 +638,28 @@ pub(crate) mod tests {
 
         // Auto-replenishing after 10 milliseconds should not yield any tokens
         thread::sleep(Duration::from_millis(10));
-        tb.auto_replenish();
+        tb.auto_replenish(Instant::now());
         assert_eq!(tb.budget(), 0);
 
         // Neither after 20.
         thread::sleep(Duration::from_millis(10));
-        tb.auto_replenish();
+        tb.auto_replenish(Instant::now());
         assert_eq!(tb.budget(), 0);
 
         // We should get 1 token after 100 millis
         thread::sleep(Duration::from_millis(80));
-        tb.auto_replenish();
+        tb.auto_replenish(Instant::now());
         assert_eq!(tb.budget(), 1);
 
         // So, 5 after 500 millis
         thread::sleep(Duration::from_millis(400));
-        tb.auto_replenish();
+        tb.auto_replenish(Instant::now());
         assert_eq!(tb.budget(), 5);
 
         // And be fully replenished after 1 second.
         // Wait more here to make sure we do not overshoot
         thread::sleep(Duration::from_millis(1000));
-        tb.auto_replenish();
+        tb.auto_replenish(Instant::now());
         assert_eq!(tb.budget(), 10);
     }
 
@@ -650,9 +675,9 @@ pub(crate) mod tests {
 
         let now = Instant::now();
         while now.elapsed() < time {
-            tb.auto_replenish();
+            tb.auto_replenish(Instant::now());
         }
-        tb.auto_replenish();
+        tb.auto_replenish(Instant::now());
         assert_eq!(tb.budget(), SIZE);
     }
 
@@ -73,6 +73,7 @@ impl Persist<'_> for RateLimiter {
     }
 
     fn restore(_: Self::ConstructorArgs, state: &Self::State) -> Result<Self, Self::Error> {
+        let clock = MonotonicClock::shared();
         let rate_limiter = RateLimiter {
             ops: if let Some(ops) = state.ops.as_ref() {
                 Some(TokenBucket::restore((), ops)?)
@@ -84,7 +85,8 @@ impl Persist<'_> for RateLimiter {
             } else {
                 None
             },
-            timer_fd: TimerFd::new_custom(ClockId::Monotonic, true, true)?,
+            timer: clock.timer()?,
+            clock,
             timer_active: false,
         };
 
@@ -147,7 +149,7 @@ mod tests {
             .unwrap()
             .partial_eq(restored_rate_limiter.bandwidth().unwrap()));
         assert_eq!(
-            restored_rate_limiter.timer_fd.get_state(),
+            restored_rate_limiter.timer.get_state(),
             TimerState::Disarmed
         );
 
@@ -166,7 +168,7 @@ mod tests {
             .unwrap()
             .partial_eq(restored_rate_limiter.bandwidth().unwrap()));
         assert_eq!(
-            restored_rate_limiter.timer_fd.get_state(),
+            restored_rate_limiter.timer.get_state(),
             TimerState::Disarmed
         );
 
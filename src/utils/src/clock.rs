@@ -0,0 +1,281 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Time source of the memory devices and of their rate limiters.
+//!
+//! The balloon and faascale-mem devices read the time, and create their timers, through a
+//! `Clock`. Running, they use the `MonotonicClock`, whose timers are timerfds. The tests
+//! hand them a `ManualClock` instead, which only moves when advanced, so that the statistics
+//! timer, the interrupt moderation, the depopulation batching or the rate limiting can be
+//! driven deterministically, without sleeping.
+
+use std::fmt::Debug;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Instant;
+
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+
+/// Timer signalling its expirations on a file descriptor, which becomes readable once it
+/// expired.
+pub trait Timer: AsRawFd + Debug + Send {
+    /// Arms or disarms the timer.
+    fn set_state(&mut self, state: TimerState, flags: SetTimeFlags);
+    /// Returns the current setting of the timer.
+    fn get_state(&self) -> TimerState;
+    /// Returns the number of expirations since the last read, without blocking.
+    fn read(&mut self) -> u64;
+}
+
+impl Timer for TimerFd {
+    fn set_state(&mut self, state: TimerState, flags: SetTimeFlags) {
+        TimerFd::set_state(self, state, flags)
+    }
+
+    fn get_state(&self) -> TimerState {
+        TimerFd::get_state(self)
+    }
+
+    fn read(&mut self) -> u64 {
+        TimerFd::read(self)
+    }
+}
+
+/// Source of the time and of the timers of a device.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+    /// Creates a disarmed, non-blocking timer running on this clock.
+    fn timer(&self) -> io::Result<Box<dyn Timer>>;
+}
+
+/// Clock of the host, the monotonic clock of the kernel.
+#[derive(Debug, Default)]
+pub struct MonotonicClock;
+
+impl MonotonicClock {
+    /// Returns the host clock, shared by the devices.
+    pub fn shared() -> Arc<dyn Clock> {
+        Arc::new(MonotonicClock)
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn timer(&self) -> io::Result<Box<dyn Timer>> {
+        Ok(Box::new(TimerFd::new_custom(
+            ClockId::Monotonic,
+            true,
+            true,
+        )?))
+    }
+}
+
+#[doc(hidden)]
+pub use self::manual::ManualClock;
+
+mod manual {
+    use std::os::unix::io::RawFd;
+    use std::sync::{Mutex, Weak};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::eventfd::EventFd;
+
+    /// Clock standing still until advanced. Its timers expire as it moves past their deadline.
+    #[derive(Debug, Clone)]
+    pub struct ManualClock(Arc<ManualClockState>);
+
+    #[derive(Debug)]
+    struct ManualClockState {
+        start: Instant,
+        elapsed: Mutex<Duration>,
+        timers: Mutex<Vec<Weak<ManualTimerState>>>,
+    }
+
+    impl ManualClock {
+        pub fn new() -> Self {
+            ManualClock(Arc::new(ManualClockState {
+                start: Instant::now(),
+                elapsed: Mutex::new(Duration::ZERO),
+                timers: Mutex::new(Vec::new()),
+            }))
+        }
+
+        /// Moves the clock `duration` forward, expiring the timers whose deadline it passes.
+        pub fn advance(&self, duration: Duration) {
+            let elapsed = {
+                let mut elapsed = self.0.elapsed.lock().unwrap();
+                *elapsed += duration;
+                *elapsed
+            };
+            let mut timers = self.0.timers.lock().unwrap();
+            timers.retain(|timer| match timer.upgrade() {
+                Some(timer) => {
+                    timer.expire(elapsed);
+                    true
+                }
+                None => false,
+            });
+        }
+
+        fn elapsed(&self) -> Duration {
+            *self.0.elapsed.lock().unwrap()
+        }
+    }
+
+    impl Default for ManualClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.0.start + self.elapsed()
+        }
+
+        fn timer(&self) -> io::Result<Box<dyn Timer>> {
+            let state = Arc::new(ManualTimerState {
+                event: EventFd::new(libc::EFD_NONBLOCK)?,
+                setting: Mutex::new(ManualTimerSetting::default()),
+            });
+            self.0.timers.lock().unwrap().push(Arc::downgrade(&state));
+            Ok(Box::new(ManualTimer {
+                clock: self.clone(),
+                state,
+            }))
+        }
+    }
+
+    #[derive(Debug)]
+    struct ManualTimer {
+        clock: ManualClock,
+        state: Arc<ManualTimerState>,
+    }
+
+    #[derive(Debug)]
+    struct ManualTimerState {
+        // Readable while expirations are pending.
+        event: EventFd,
+        setting: Mutex<ManualTimerSetting>,
+    }
+
+    #[derive(Debug, Default)]
+    struct ManualTimerSetting {
+        // Elapsed time of the clock at the next expiration, if armed.
+        deadline: Option<Duration>,
+        interval: Option<Duration>,
+        expirations: u64,
+    }
+
+    impl ManualTimerState {
+        fn expire(&self, elapsed: Duration) {
+            let mut setting = self.setting.lock().unwrap();
+            let expirations = setting.expirations;
+            while let Some(deadline) = setting.deadline.filter(|&deadline| deadline <= elapsed) {
+                setting.expirations += 1;
+                setting.deadline = setting.interval.map(|interval| deadline + interval);
+            }
+            if setting.expirations > expirations {
+                self.event.write(1).unwrap();
+            }
+        }
+    }
+
+    impl Timer for ManualTimer {
+        fn set_state(&mut self, state: TimerState, _flags: SetTimeFlags) {
+            let elapsed = self.clock.elapsed();
+            let mut setting = self.state.setting.lock().unwrap();
+            // Like a timerfd, the expirations not read yet are dropped.
+            let _ = self.state.event.read();
+            setting.expirations = 0;
+            (setting.deadline, setting.interval) = match state {
+                TimerState::Disarmed => (None, None),
+                TimerState::Oneshot(current) => (Some(elapsed + current), None),
+                TimerState::Periodic { current, interval } => {
+                    (Some(elapsed + current), Some(interval))
+                }
+            };
+        }
+
+        fn get_state(&self) -> TimerState {
+            let elapsed = self.clock.elapsed();
+            let setting = self.state.setting.lock().unwrap();
+            match (setting.deadline, setting.interval) {
+                (None, _) => TimerState::Disarmed,
+                (Some(deadline), None) => TimerState::Oneshot(deadline.saturating_sub(elapsed)),
+                (Some(deadline), Some(interval)) => TimerState::Periodic {
+                    current: deadline.saturating_sub(elapsed),
+                    interval,
+                },
+            }
+        }
+
+        fn read(&mut self) -> u64 {
+            let _ = self.state.event.read();
+            std::mem::take(&mut self.state.setting.lock().unwrap().expirations)
+        }
+    }
+
+    impl AsRawFd for ManualTimer {
+        fn as_raw_fd(&self) -> RawFd {
+            self.state.event.as_raw_fd()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut timer = clock.timer().unwrap();
+        assert!(matches!(timer.get_state(), TimerState::Disarmed));
+
+        timer.set_state(
+            TimerState::Oneshot(Duration::from_millis(10)),
+            SetTimeFlags::Default,
+        );
+        clock.advance(Duration::from_millis(9));
+        assert_eq!(clock.now() - start, Duration::from_millis(9));
+        assert_eq!(timer.read(), 0);
+        assert!(matches!(
+            timer.get_state(),
+            TimerState::Oneshot(remaining) if remaining == Duration::from_millis(1)
+        ));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(timer.read(), 1);
+        assert!(matches!(timer.get_state(), TimerState::Disarmed));
+
+        // A periodic timer expires once per interval the clock moves past.
+        timer.set_state(
+            TimerState::Periodic {
+                current: Duration::from_secs(1),
+                interval: Duration::from_secs(1),
+            },
+            SetTimeFlags::Default,
+        );
+        clock.advance(Duration::from_millis(3500));
+        assert_eq!(timer.read(), 3);
+        assert_eq!(timer.read(), 0);
+        timer.set_state(TimerState::Disarmed, SetTimeFlags::Default);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(timer.read(), 0);
+
+        // The host clock moves on its own.
+        let clock = MonotonicClock::shared();
+        let mut timer = clock.timer().unwrap();
+        assert_eq!(timer.read(), 0);
+        assert!(clock.now() >= start);
+    }
+}
@@ -22,6 +22,21 @@ pub type GuestRegionMmap = vm_memory::GuestRegionMmap<Option<AtomicBitmap>>;
 pub type GuestMmapRegion = vm_memory::MmapRegion<Option<AtomicBitmap>>;
 
 const GUARD_PAGE_COUNT: usize = 1;
+// Huge page size of the hugetlb mappings that do not encode one, the default of the supported
+// hosts.
+const DEFAULT_HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Returns the size of the hugetlb pages of a mapping made with `flags`, or `None` if the
+/// mapping uses regular pages.
+pub fn hugetlb_page_size(flags: i32) -> Option<usize> {
+    if flags & libc::MAP_HUGETLB == 0 {
+        return None;
+    }
+    match (flags >> libc::MAP_HUGE_SHIFT) & libc::MAP_HUGE_MASK {
+        0 => Some(DEFAULT_HUGE_PAGE_SIZE),
+        shift => Some(1 << shift),
+    }
+}
 
 /// Build a `MmapRegion` surrounded by guard pages.
 ///
@@ -36,6 +51,9 @@ const GUARD_PAGE_COUNT: usize = 1;
 /// This results in a border of `GUARD_PAGE_COUNT` pages on either side of the region, which
 /// acts as a safety net for accessing out-of-bounds addresses that are not allocated for the
 /// guest's memory.
+///
+/// Hugetlb regions must start on a huge page boundary, so the guard region is grown by a huge
+/// page and the accessible region moved up to the next boundary.
 fn build_guarded_region(
     maybe_file_offset: Option<FileOffset>,
     size: usize,
@@ -44,9 +62,10 @@ fn build_guarded_region(
     track_dirty_pages: bool,
 ) -> Result<GuestMmapRegion, MmapRegionError> {
     let page_size = crate::get_page_size().expect("Cannot retrieve page size.");
+    let alignment = hugetlb_page_size(flags).unwrap_or(page_size);
     // Create the guarded range size (received size + X pages),
     // where X is defined as a constant GUARD_PAGE_COUNT.
-    let guarded_size = size + GUARD_PAGE_COUNT * 2 * page_size;
+    let guarded_size = size + GUARD_PAGE_COUNT * 2 * page_size + (alignment - page_size);
 
     // Map the guarded range to PROT_NONE
     // SAFETY: Safe because the parameters are valid.
@@ -73,7 +92,8 @@ fn build_guarded_region(
         None => (-1, 0),
     };
 
-    let region_start_addr = guard_addr as usize + page_size * GUARD_PAGE_COUNT;
+    let region_start_addr =
+        (guard_addr as usize + page_size * GUARD_PAGE_COUNT + alignment - 1) & !(alignment - 1);
 
     // Inside the protected range, starting with guard_addr + PAGE_SIZE,
     // map the requested range with received protection and flags
@@ -132,6 +152,31 @@ pub fn create_guest_memory(
     GuestMemoryMmap::from_regions(mmap_regions)
 }
 
+/// Helper for creating the guest memory out of anonymous hugetlb pages of `huge_page_size`
+/// bytes. The size of the regions must be a multiple of the huge page size.
+pub fn create_hugetlb_guest_memory(
+    regions: &[(GuestAddress, usize)],
+    track_dirty_pages: bool,
+    huge_page_size: usize,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = libc::MAP_NORESERVE
+        | libc::MAP_PRIVATE
+        | libc::MAP_ANONYMOUS
+        | libc::MAP_HUGETLB
+        | ((huge_page_size.trailing_zeros() as i32) << libc::MAP_HUGE_SHIFT);
+    let mut mmap_regions = Vec::with_capacity(regions.len());
+
+    for region in regions {
+        let mmap_region = build_guarded_region(None, region.1, prot, flags, track_dirty_pages)
+            .map_err(Error::MmapRegion)?;
+
+        mmap_regions.push(GuestRegionMmap::new(mmap_region, region.0)?);
+    }
+
+    GuestMemoryMmap::from_regions(mmap_regions)
+}
+
 pub fn mark_dirty_mem(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) {
     let _ = mem.try_access(len, addr, |_total, count, caddr, region| {
         if let Some(bitmap) = region.bitmap() {
@@ -649,6 +694,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_hugetlb_page_size() {
+        let flags = libc::MAP_ANONYMOUS | libc::MAP_PRIVATE;
+        assert_eq!(hugetlb_page_size(flags), None);
+        assert_eq!(
+            hugetlb_page_size(flags | libc::MAP_HUGETLB),
+            Some(DEFAULT_HUGE_PAGE_SIZE)
+        );
+        assert_eq!(
+            hugetlb_page_size(flags | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB),
+            Some(2 << 20)
+        );
+        assert_eq!(
+            hugetlb_page_size(flags | libc::MAP_HUGETLB | libc::MAP_HUGE_1GB),
+            Some(1 << 30)
+        );
+    }
+
     #[test]
     fn test_mark_dirty_mem() {
         let page_size = crate::get_page_size().unwrap();
@@ -0,0 +1,66 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Benchmarking cases:
+//   * Draining a full virtio queue one descriptor chain at a time
+//   * Draining a full virtio queue in batches
+
+use std::path::Path;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use utils::vm_memory::{GuestAddress, GuestMemoryMmap};
+use vmm::devices::virtio::test_utils::{single_region_mem, VirtQueue};
+use vmm::devices::virtio::POP_BATCH_SIZE;
+
+const QUEUE_SIZE: u16 = 256;
+
+// Makes every descriptor of the queue available as a single-descriptor chain.
+fn fill_queue(vq: &VirtQueue) {
+    for i in 0..QUEUE_SIZE {
+        vq.dtable[usize::from(i)].set(0x10_0000 + u64::from(i) * 0x1000, 0x1000, 0, 0);
+        vq.avail.ring[usize::from(i)].set(i);
+    }
+    vq.avail.idx.set(QUEUE_SIZE);
+}
+
+#[inline]
+pub fn bench_pop(vq: &VirtQueue, mem: &GuestMemoryMmap) {
+    let mut queue = vq.create_queue();
+    while let Some(head) = queue.pop(mem) {
+        black_box(head);
+    }
+}
+
+#[inline]
+pub fn bench_pop_batch(vq: &VirtQueue, mem: &GuestMemoryMmap, max_n: u16) {
+    let mut queue = vq.create_queue();
+    loop {
+        let heads = queue.pop_batch(mem, max_n);
+        if heads.is_empty() {
+            break;
+        }
+        black_box(heads);
+    }
+}
+
+pub fn queue_benchmark(c: &mut Criterion) {
+    let mem = single_region_mem(0x40_0000);
+    let vq = VirtQueue::new(GuestAddress(0), &mem, QUEUE_SIZE);
+    fill_queue(&vq);
+
+    c.bench_function("pop_full_queue", |b| b.iter(|| bench_pop(&vq, &mem)));
+
+    c.bench_function("pop_batch_full_queue", |b| {
+        b.iter(|| bench_pop_batch(&vq, &mem, black_box(POP_BATCH_SIZE)))
+    });
+}
+
+criterion_group! {
+    name = queue_benches;
+    config = Criterion::default().sample_size(200).output_directory(Path::new("../../build/vmm_benchmark/queue"));
+    targets = queue_benchmark
+}
+
+criterion_main! {
+    queue_benches
+}
@@ -6,8 +6,13 @@ use std::process::Command;
 use std::{env, fs};
 
 const ADVANCED_BINARY_FILTER_FILE_NAME: &str = "seccomp_filter.bpf";
+const ADVANCED_JSON_FILTER_FILE_NAME: &str = "seccomp_filter.json";
 
 const JSON_DIR: &str = "../../resources/seccomp";
+// Rules the filter of the VMM thread of a microVM with a faascale-mem device adds to the `vmm`
+// filter, per target.
+const FAASCALE_MEM_JSON_DIR: &str = "../../resources/seccomp/faascale_mem";
+const FAASCALE_MEM_VMM_CATEGORY: &str = "vmm_faascale_mem";
 const SECCOMPILER_BUILD_DIR: &str = "../../build/seccompiler";
 const SECCOMPILER_SRC_DIR: &str = "../seccompiler/src";
 
@@ -45,10 +50,57 @@ fn main() {
     // Also retrigger the build script on any seccompiler source code change.
     register_seccompiler_src_watchlist(Path::new(SECCOMPILER_SRC_DIR));
 
+    // Add the filter of the VMM thread of a microVM with a faascale-mem device.
+    let mut faascale_mem_json_path = PathBuf::from(FAASCALE_MEM_JSON_DIR);
+    faascale_mem_json_path.push(format!("{}.json", target));
+    let mut json_out_path = PathBuf::from(&out_dir);
+    json_out_path.push(ADVANCED_JSON_FILTER_FILE_NAME);
+    add_faascale_mem_filter(json_path, &faascale_mem_json_path, &json_out_path);
+
     // Run seccompiler-bin, getting the default, advanced filter.
     let mut bpf_out_path = PathBuf::from(&out_dir);
     bpf_out_path.push(ADVANCED_BINARY_FILTER_FILE_NAME);
-    run_seccompiler_bin(json_path, bpf_out_path.to_str().expect("Invalid bytes."));
+    run_seccompiler_bin(
+        json_out_path.to_str().expect("Invalid bytes."),
+        bpf_out_path.to_str().expect("Invalid bytes."),
+    );
+}
+
+// Writes the filters of `json_path` to `out_path`, along with the `vmm_faascale_mem` filter: the
+// `vmm` filter extended with the rules of `faascale_mem_json_path`, when the target has any.
+fn add_faascale_mem_filter(json_path: &str, faascale_mem_json_path: &Path, out_path: &Path) {
+    let read_json = |path: &Path| -> serde_json::Value {
+        let json = fs::read_to_string(path).expect("Unable to read seccomp policy.");
+        serde_json::from_str(&json).expect("Invalid seccomp policy.")
+    };
+
+    let mut filters = read_json(Path::new(json_path));
+    let mut filter = filters["vmm"].clone();
+    if faascale_mem_json_path.exists() {
+        // Retrigger the build script if the rules have changed.
+        println!(
+            "cargo:rerun-if-changed={}",
+            faascale_mem_json_path.to_str().expect("Invalid bytes")
+        );
+        let faascale_mem_filters = read_json(faascale_mem_json_path);
+        let rules = faascale_mem_filters[FAASCALE_MEM_VMM_CATEGORY]["filter"]
+            .as_array()
+            .expect("Invalid faascale-mem seccomp rules.");
+        filter["filter"]
+            .as_array_mut()
+            .expect("Invalid vmm seccomp filter.")
+            .extend(rules.iter().cloned());
+    }
+    filters
+        .as_object_mut()
+        .expect("Invalid seccomp policy.")
+        .insert(FAASCALE_MEM_VMM_CATEGORY.to_string(), filter);
+
+    fs::write(
+        out_path,
+        serde_json::to_string_pretty(&filters).expect("Invalid seccomp policy."),
+    )
+    .expect("Unable to write seccomp policy.");
 }
 
 // Run seccompiler with the given arguments.
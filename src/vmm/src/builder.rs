@@ -18,7 +18,7 @@ use linux_loader::loader::elf::Elf as Loader;
 use linux_loader::loader::pe::PE as Loader;
 use linux_loader::loader::KernelLoader;
 use logger::{error, warn, METRICS};
-use seccompiler::BpfThreadMap;
+use seccompiler::{BpfProgram, BpfThreadMap};
 use snapshot::Persist;
 use userfaultfd::Uffd;
 use utils::eventfd::EventFd;
@@ -44,35 +44,37 @@ use crate::devices::legacy::RTCDevice;
 use crate::devices::legacy::{
     EventFdTrigger, ReadableFd, SerialDevice, SerialEventsWrapper, SerialWrapper,
 };
-use crate::devices::virtio::{Balloon, Block, Entropy, FaascaleMem, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend};
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::faascale_mem::lazy::spawn_fault_handler;
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::faascale_mem::observer::start_activity_observer;
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::faascale_mem::poller::spawn_populate_poller;
+#[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+use crate::devices::virtio::mem_overlay::MmapOverlays;
+use crate::devices::virtio::pause_gate::VmPauseGate;
+#[cfg(feature = "balloon")]
+use crate::devices::virtio::Balloon;
+use crate::devices::virtio::{
+    Block, Entropy, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend,
+};
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::{
+    FaascaleMem, FaascaleMemCapabilities, HotplugSlot, FAASCALE_MEM_HOTPLUG_SLOT_ID,
+};
 use crate::persist::{MicrovmState, MicrovmStateError};
 use crate::resources::VmResources;
 use crate::vmm_config::boot_source::BootConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
-use crate::vmm_config::machine_config::{MachineConfigUpdate, VmConfig, VmConfigError};
+use crate::vmm_config::machine_config::{
+    HugePageConfig, MachineConfigUpdate, VmConfig, VmConfigError,
+};
+use crate::vmm_config::memory_devices::MemoryDevicesQuiesce;
 use crate::vstate::system::KvmContext;
 use crate::vstate::vcpu::{Vcpu, VcpuConfig};
 use crate::vstate::vm::Vm;
 use crate::{device_manager, Error, EventManager, RestoreVcpusError, Vmm, VmmEventsObserver};
 
-extern crate lazy_static;
-use lazy_static::lazy_static;
-lazy_static! {
-   static ref GLOBAL_VM_FD: Mutex<i32> = Mutex::new(0);
-}
-
-/// set global vm fd for faascale_mem to pre-allocate memory
-pub fn set_global_vm_fd(fd: i32) {
-    let mut global_vm_fd = GLOBAL_VM_FD.lock().unwrap();
-    *global_vm_fd = fd;
-}
-
-/// get global vm fd for faascale_mem to pre-allocate memory
-pub fn get_global_vm_fd() -> i32 {
-    let global_vm_fd = GLOBAL_VM_FD.lock().unwrap();
-    *global_vm_fd
-}
-
 /// Errors associated with starting the instance.
 #[derive(Debug, thiserror::Error)]
 pub enum StartMicrovmError {
@@ -148,6 +150,26 @@ pub enum StartMicrovmError {
     /// Failed to create an Entropy device
     #[error("Cannot create the entropy device: {0}")]
     CreateEntropyDevice(crate::devices::virtio::rng::Error),
+    /// Failed to start the faascale-mem populate queue poller.
+    #[cfg(feature = "faascale-mem")]
+    #[error("Cannot start the faascale-mem populate poller: {0:?}")]
+    StartFaascaleMemPoller(crate::devices::virtio::faascale_mem::Error),
+    /// Failed to start the handler of the lazily populated faascale-mem page faults.
+    #[cfg(feature = "faascale-mem")]
+    #[error("Cannot start the faascale-mem fault handler: {0:?}")]
+    StartFaascaleMemFaultHandler(crate::devices::virtio::faascale_mem::Error),
+    /// Failed to start the faascale-mem activity observer.
+    #[cfg(feature = "faascale-mem")]
+    #[error("Cannot start the faascale-mem activity observer: {0:?}")]
+    StartFaascaleMemObserver(crate::devices::virtio::faascale_mem::Error),
+    /// Failed to create the slot to hot-plug the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    #[error("Cannot create the faascale-mem hot-plug slot: {0:?}")]
+    CreateFaascaleMemHotplugSlot(crate::devices::virtio::faascale_mem::Error),
+    /// The faascale-mem device cannot back the guest memory.
+    #[cfg(feature = "faascale-mem")]
+    #[error("The faascale-mem device cannot back the guest memory: {0:?}")]
+    FaascaleMemGuestMemory(crate::devices::virtio::faascale_mem::Error),
 }
 
 /// It's convenient to automatically convert `linux_loader::cmdline::Error`s
@@ -285,6 +307,12 @@ fn create_vmm_and_vcpus(
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
+        memory_devices_quiesce: MemoryDevicesQuiesce::default(),
+        pause_gate: VmPauseGate::default(),
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        consolidation_overlays: MmapOverlays::default(),
+        snapshot_memory_info: None,
+        hotplugged_subscribers: Vec::new(),
     };
 
     Ok((vmm, vcpus))
@@ -311,7 +339,11 @@ pub fn build_microvm_for_boot(
         .ok_or(MissingKernelConfig)?;
 
     let track_dirty_pages = vm_resources.track_dirty_pages();
-    let guest_memory = create_guest_memory(vm_resources.vm_config.mem_size_mib, track_dirty_pages)?;
+    let guest_memory = create_guest_memory(
+        vm_resources.vm_config.mem_size_mib,
+        track_dirty_pages,
+        vm_resources.vm_config.huge_pages,
+    )?;
     let entry_addr = load_kernel(boot_config, &guest_memory)?;
     let initrd = load_initrd_from_config(boot_config, &guest_memory)?;
     // Clone the command-line so that a failed boot doesn't pollute the original.
@@ -334,12 +366,20 @@ pub fn build_microvm_for_boot(
         attach_boot_timer_device(&mut vmm, request_ts)?;
     }
 
+    #[cfg(feature = "balloon")]
     if let Some(balloon) = vm_resources.balloon.get() {
         attach_balloon_device(&mut vmm, &mut boot_cmdline, balloon, event_manager)?;
     }
 
+    #[cfg(feature = "faascale-mem")]
     if let Some(faascale) = vm_resources.faascale_mem.get() {
+        faascale
+            .lock()
+            .expect("Poisoned lock")
+            .set_mmds(vm_resources.mmds.clone());
         attach_faascale_device(&mut vmm, &mut boot_cmdline, faascale, event_manager)?;
+    } else if vm_resources.faascale_mem.hotplug_slot() {
+        attach_faascale_hotplug_slot(&mut vmm, &mut boot_cmdline)?;
     }
 
     attach_block_devices(
@@ -359,12 +399,24 @@ pub fn build_microvm_for_boot(
         attach_unixsock_vsock_device(&mut vmm, &mut boot_cmdline, unix_vsock, event_manager)?;
     }
 
+    // Guest connections to the observer port go through the Unix socket of the vsock device.
+    #[cfg(feature = "faascale-mem")]
+    if let Some(faascale) = vm_resources.faascale_mem.get() {
+        let vsock_uds_path = vm_resources.vsock.get().map(|vsock| {
+            let vsock = vsock.lock().expect("Poisoned lock");
+            vsock.backend().host_sock_path().to_owned()
+        });
+        start_activity_observer(faascale, vsock_uds_path.as_deref(), event_manager)
+            .map_err(StartFaascaleMemObserver)?;
+    }
+
     if let Some(entropy) = vm_resources.entropy.get() {
         attach_entropy_device(&mut vmm, &mut boot_cmdline, entropy, event_manager)?;
     }
 
     #[cfg(target_arch = "aarch64")]
     attach_legacy_devices_aarch64(event_manager, &mut vmm, &mut boot_cmdline).map_err(Internal)?;
+    vmm.share_pause_gate();
 
     configure_system_for_boot(
         &vmm,
@@ -386,15 +438,28 @@ pub fn build_microvm_for_boot(
     .map_err(Error::VcpuStart)
     .map_err(Internal)?;
 
+    #[cfg(feature = "faascale-mem")]
+    let faascale_mem =
+        vm_resources.faascale_mem.get().is_some() || vm_resources.faascale_mem.hotplug_slot();
+    #[cfg(not(feature = "faascale-mem"))]
+    let faascale_mem = false;
+    let vmm_filter = vmm_seccomp_filter(seccomp_filters, faascale_mem)
+        .ok_or_else(|| MissingSeccompFilters("vmm".to_string()))?;
+
+    // The populate poller and the fault handler run under the VMM thread filters.
+    #[cfg(feature = "faascale-mem")]
+    if let Some(faascale) = vm_resources.faascale_mem.get() {
+        if faascale.lock().expect("Poisoned lock").latency_mode() {
+            spawn_populate_poller(faascale, vmm_filter.clone()).map_err(StartFaascaleMemPoller)?;
+        }
+        spawn_fault_handler(faascale, vmm_filter.clone()).map_err(StartFaascaleMemFaultHandler)?;
+    }
+
     // Load seccomp filters for the VMM thread.
     // Execution panics if filters cannot be loaded, use --no-seccomp if skipping filters
     // altogether is the desired behaviour.
     // Keep this as the last step before resuming vcpus.
-    seccompiler::apply_filter(
-        seccomp_filters
-            .get("vmm")
-            .ok_or_else(|| MissingSeccompFilters("vmm".to_string()))?,
-    )
+    seccompiler::apply_filter(vmm_filter)
     .map_err(Error::SeccompFilters)
     .map_err(Internal)?;
 
@@ -540,15 +605,22 @@ pub fn build_microvm_from_snapshot(
         smt: Some(microvm_state.vm_info.smt),
         cpu_template: Some(microvm_state.vm_info.cpu_template),
         track_dirty_pages: Some(track_dirty_pages),
+        huge_pages: Some(microvm_state.vm_info.huge_pages),
     })?;
 
     // Restore the boot source config paths.
     vm_resources.set_boot_source_config(microvm_state.vm_info.boot_source);
 
+    let faascale_mem = microvm_state.device_states.faascale_mem_device.is_some()
+        || microvm_state
+            .device_states
+            .faascale_mem_hotplug_slot
+            .is_some();
+
     // Restore devices states.
     let mmio_ctor_args = MMIODevManagerConstructorArgs {
         mem: guest_memory,
-        vm: vmm.vm.fd(),
+        vm: vmm.vm.shared_fd(),
         event_manager,
         for_each_restored_device: VmResources::update_from_restored_device,
         vm_resources,
@@ -558,6 +630,7 @@ pub fn build_microvm_from_snapshot(
     vmm.mmio_device_manager =
         MMIODeviceManager::restore(mmio_ctor_args, &microvm_state.device_states)
             .map_err(MicrovmStateError::RestoreDevices)?;
+    vmm.share_pause_gate();
     vmm.emulate_serial_init()?;
 
     // Move vcpus to their own threads and start their state machine in the 'Paused' state.
@@ -578,22 +651,44 @@ pub fn build_microvm_from_snapshot(
     // Load seccomp filters for the VMM thread.
     // Keep this as the last step of the building process.
     seccompiler::apply_filter(
-        seccomp_filters
-            .get("vmm")
+        vmm_seccomp_filter(seccomp_filters, faascale_mem)
             .ok_or(BuildMicrovmFromSnapshotError::MissingVmmSeccompFilters)?,
     )?;
 
     Ok(vmm)
 }
 
-/// Creates GuestMemory of `mem_size_mib` MiB in size.
+// Filter of the VMM thread. A microVM with a faascale-mem device, or a slot to hot-plug one in,
+// gets the one also allowing the TDP pre-fault ioctl. Custom filters may not provide it, the
+// vmm one is used then.
+fn vmm_seccomp_filter(
+    seccomp_filters: &BpfThreadMap,
+    faascale_mem: bool,
+) -> Option<&Arc<BpfProgram>> {
+    faascale_mem
+        .then(|| seccomp_filters.get(crate::seccomp_filters::FAASCALE_MEM_VMM_CATEGORY))
+        .flatten()
+        .or_else(|| seccomp_filters.get("vmm"))
+}
+
+/// Creates GuestMemory of `mem_size_mib` MiB in size, backed by the `huge_pages` host pages.
 pub fn create_guest_memory(
     mem_size_mib: usize,
     track_dirty_pages: bool,
+    huge_pages: HugePageConfig,
 ) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
     let arch_mem_regions = crate::arch::arch_memory_regions(mem_size);
 
+    if let Some(huge_page_size) = huge_pages.page_size() {
+        return utils::vm_memory::create_hugetlb_guest_memory(
+            &arch_mem_regions,
+            track_dirty_pages,
+            huge_page_size,
+        )
+        .map_err(StartMicrovmError::GuestMemoryMmap);
+    }
+
     utils::vm_memory::create_guest_memory(
         &arch_mem_regions
             .iter()
@@ -706,8 +801,6 @@ pub(crate) fn setup_kvm_vm(
         .map_err(Error::KvmContext)
         .map_err(Internal)?;
     let mut vm = Vm::new(kvm.fd()).map_err(Error::Vm).map_err(Internal)?;
-    set_global_vm_fd(vm.fd().as_raw_fd());
-    log::info!("VM-fd: {}",get_global_vm_fd());
     vm.memory_init(guest_memory, kvm.max_memslots(), track_dirty_pages)
         .map_err(Error::Vm)
         .map_err(Internal)?;
@@ -1028,6 +1121,7 @@ fn attach_unixsock_vsock_device(
     attach_virtio_device(event_manager, vmm, id, unix_vsock.clone(), cmdline)
 }
 
+#[cfg(feature = "balloon")]
 fn attach_balloon_device(
     vmm: &mut Vmm,
     cmdline: &mut LoaderKernelCmdline,
@@ -1039,17 +1133,52 @@ fn attach_balloon_device(
     attach_virtio_device(event_manager, vmm, id, balloon.clone(), cmdline)
 }
 
+#[cfg(feature = "faascale-mem")]
 fn attach_faascale_device(
     vmm: &mut Vmm,
     cmdline: &mut LoaderKernelCmdline,
     faascale_mem: &Arc<Mutex<FaascaleMem>>,
     event_manager: &mut EventManager,
 ) -> std::result::Result<(), StartMicrovmError> {
-    let id = String::from(faascale_mem.lock().expect("Poisoned lock").id());
+    let id = {
+        let mut locked_faascale_mem = faascale_mem.lock().expect("Poisoned lock");
+        // The microVM boots, so no userfaultfd serves its memory.
+        locked_faascale_mem
+            .check_guest_memory(vmm.guest_memory(), false)
+            .map_err(StartMicrovmError::FaascaleMemGuestMemory)?;
+        // The VM is created by now, so that its KVM ioctls can be probed.
+        locked_faascale_mem.set_vm_fd(vmm.vm.shared_fd().clone());
+        locked_faascale_mem.probe_capabilities();
+        String::from(locked_faascale_mem.id())
+    };
     // The device mutex mustn't be locked here otherwise it will deadlock.
     attach_virtio_device(event_manager, vmm, id, faascale_mem.clone(), cmdline)
 }
 
+// Reserves the slot the faascale-mem device can be hot-plugged in after boot. The slot is
+// announced to the guest like any other virtio-mmio device.
+#[cfg(feature = "faascale-mem")]
+fn attach_faascale_hotplug_slot(
+    vmm: &mut Vmm,
+    cmdline: &mut LoaderKernelCmdline,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    // The host is probed now, the device is hot-plugged under the seccomp filters.
+    let capabilities = FaascaleMemCapabilities::probe(vmm.vm.fd().as_raw_fd());
+    let slot = HotplugSlot::new(capabilities).map_err(CreateFaascaleMemHotplugSlot)?;
+    let slot = MmioTransport::new(vmm.guest_memory().clone(), Arc::new(Mutex::new(slot)));
+    vmm.mmio_device_manager
+        .register_mmio_virtio_for_boot(
+            vmm.vm.fd(),
+            FAASCALE_MEM_HOTPLUG_SLOT_ID.to_string(),
+            slot,
+            cmdline,
+        )
+        .map_err(RegisterMmioDevice)
+        .map(|_| ())
+}
+
 // Adds `O_NONBLOCK` to the stdout flags.
 pub(crate) fn set_stdout_nonblocking() {
     // SAFETY: Call is safe since parameters are valid.
@@ -1078,8 +1207,13 @@ pub mod tests {
     use crate::arch::DeviceType;
     use crate::devices::virtio::rng::device::ENTROPY_DEV_ID;
     use crate::devices::virtio::vsock::VSOCK_DEV_ID;
-    use crate::devices::virtio::{TYPE_BALLOON, TYPE_BLOCK, TYPE_RNG, TYPE_VSOCK};
-    use crate::vmm_config::balloon::{BalloonBuilder, BalloonDeviceConfig, BALLOON_DEV_ID};
+    #[cfg(feature = "balloon")]
+    use crate::devices::virtio::TYPE_BALLOON;
+    use crate::devices::virtio::{TYPE_BLOCK, TYPE_RNG, TYPE_VSOCK};
+    #[cfg(feature = "balloon")]
+    use crate::vmm_config::balloon::{
+        BalloonBuilder, BalloonDeflatePrefetch, BalloonDeviceConfig, BALLOON_DEV_ID,
+    };
     use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
     use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType};
     use crate::vmm_config::entropy::{EntropyDeviceBuilder, EntropyDeviceConfig};
@@ -1169,7 +1303,7 @@ pub mod tests {
     }
 
     pub(crate) fn default_vmm() -> Vmm {
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, HugePageConfig::None).unwrap();
 
         let vcpus_exit_evt = EventFd::new(libc::EFD_NONBLOCK)
             .map_err(Error::EventFd)
@@ -1203,6 +1337,12 @@ pub mod tests {
             mmio_device_manager,
             #[cfg(target_arch = "x86_64")]
             pio_device_manager,
+            memory_devices_quiesce: MemoryDevicesQuiesce::default(),
+            pause_gate: VmPauseGate::default(),
+            #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+            consolidation_overlays: MmapOverlays::default(),
+            snapshot_memory_info: None,
+            hotplugged_subscribers: Vec::new(),
         }
     }
 
@@ -1307,6 +1447,7 @@ pub mod tests {
             .is_some());
     }
 
+    #[cfg(feature = "balloon")]
     pub(crate) fn insert_balloon_device(
         vmm: &mut Vmm,
         cmdline: &mut Cmdline,
@@ -1414,13 +1555,13 @@ pub mod tests {
 
         // Case 1: create guest memory without dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, false).unwrap();
+            let guest_memory = create_guest_memory(mem_size, false, HugePageConfig::None).unwrap();
             assert!(!is_dirty_tracking_enabled(&guest_memory));
         }
 
         // Case 2: create guest memory with dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, true).unwrap();
+            let guest_memory = create_guest_memory(mem_size, true, HugePageConfig::None).unwrap();
             assert!(is_dirty_tracking_enabled(&guest_memory));
         }
     }
@@ -1428,7 +1569,7 @@ pub mod tests {
     #[test]
     fn test_create_vcpus() {
         let vcpu_count = 2;
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, HugePageConfig::None).unwrap();
 
         #[allow(unused_mut)]
         let mut vm = setup_kvm_vm(&guest_memory, false).unwrap();
@@ -1659,6 +1800,7 @@ pub mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_attach_balloon_device() {
         let mut event_manager = EventManager::new().expect("Unable to create EventManager");
         let mut vmm = default_vmm();
@@ -1667,6 +1809,11 @@ pub mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            deflate_prefetch: BalloonDeflatePrefetch::None,
+            strict_stats: false,
+            interrupt_moderation: false,
+            min_guest_mib: 0,
+            config_epoch: 0,
         };
 
         let mut cmdline = default_kernel_cmdline();
@@ -26,10 +26,13 @@ use crate::devices::legacy::RTCDevice;
 #[cfg(target_arch = "aarch64")]
 use crate::devices::legacy::SerialDevice;
 use crate::devices::pseudo::BootTimer;
+#[cfg(feature = "balloon")]
+use crate::devices::virtio::{Balloon, TYPE_BALLOON};
 use crate::devices::virtio::{
-    Balloon, FaascaleMem, Block, Entropy, MmioTransport, Net, VirtioDevice, TYPE_BALLOON, TYPE_FAASCALE_MEM,TYPE_BLOCK, TYPE_NET,
-    TYPE_RNG, TYPE_VSOCK,
+    Block, Entropy, MmioTransport, Net, VirtioDevice, TYPE_BLOCK, TYPE_NET, TYPE_RNG, TYPE_VSOCK,
 };
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::{FaascaleMem, TYPE_FAASCALE_MEM};
 use crate::devices::BusDevice;
 
 /// Errors for MMIO device manager.
@@ -47,6 +50,9 @@ pub enum Error {
     /// The device couldn't be found on the bus.
     #[error("Failed to find the device on the bus.")]
     DeviceNotFound,
+    /// No slot is reserved to hot-plug the device.
+    #[error("No slot is reserved to hot-plug the device.")]
+    HotplugSlotNotFound,
     /// Incorrect device type.
     #[error("Invalid device type found on the MMIO bus.")]
     InvalidDeviceType,
@@ -62,6 +68,9 @@ pub enum Error {
     /// Registering an IRQ FD failed.
     #[error("Failed to register irqfd: {0}")]
     RegisterIrqFd(kvm_ioctls::Error),
+    /// Unregistering an IRQ FD failed.
+    #[error("Failed to unregister irqfd: {0}")]
+    UnregisterIrqFd(kvm_ioctls::Error),
 }
 
 type Result<T> = ::std::result::Result<T, Error>;
@@ -157,15 +166,7 @@ impl MMIODeviceManager {
         {
             let locked_device = mmio_device.locked_device();
             identifier = (DeviceType::Virtio(locked_device.device_type()), device_id);
-            for (i, queue_evt) in locked_device.queue_events().iter().enumerate() {
-                let io_addr = IoEventAddress::Mmio(
-                    device_info.addr + u64::from(crate::devices::virtio::NOTIFY_REG_OFFSET),
-                );
-                vm.register_ioevent(queue_evt, &io_addr, i as u32)
-                    .map_err(Error::RegisterIoEvent)?;
-            }
-            vm.register_irqfd(locked_device.interrupt_evt(), device_info.irqs[0])
-                .map_err(Error::RegisterIrqFd)?;
+            Self::register_virtio_events(vm, &*locked_device, device_info)?;
         }
 
         self.register_mmio_device(
@@ -175,6 +176,69 @@ impl MMIODeviceManager {
         )
     }
 
+    // Registers the queue and interrupt events of a virtio device at the slot `device_info`.
+    fn register_virtio_events(
+        vm: &VmFd,
+        device: &dyn VirtioDevice,
+        device_info: &MMIODeviceInfo,
+    ) -> Result<()> {
+        for (i, queue_evt) in device.queue_events().iter().enumerate() {
+            let io_addr = IoEventAddress::Mmio(
+                device_info.addr + u64::from(crate::devices::virtio::NOTIFY_REG_OFFSET),
+            );
+            vm.register_ioevent(queue_evt, &io_addr, i as u32)
+                .map_err(Error::RegisterIoEvent)?;
+        }
+        vm.register_irqfd(device.interrupt_evt(), device_info.irqs[0])
+            .map_err(Error::RegisterIrqFd)
+    }
+
+    /// Hot-plugs a virtio-over-MMIO device in the slot registered as `slot`, which holds a
+    /// placeholder virtio device. The device takes over the MMIO range and IRQ of the slot, and is
+    /// swapped in the transport already on the bus, which the vCPUs hold their own copy of.
+    pub fn hotplug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        slot: (DeviceType, String),
+        device_id: String,
+        mmio_device: MmioTransport,
+    ) -> Result<MMIODeviceInfo> {
+        let device_info = self
+            .id_to_dev_info
+            .get(&slot)
+            .cloned()
+            .ok_or(Error::HotplugSlotNotFound)?;
+        let identifier;
+        {
+            let (_, bus_device) = self
+                .bus
+                .get_device(device_info.addr)
+                .ok_or(Error::DeviceNotFound)?;
+            let mut locked_bus_device = bus_device.lock().expect("Poisoned lock");
+            let transport = locked_bus_device
+                .as_mut_any()
+                .downcast_mut::<MmioTransport>()
+                .ok_or(Error::InvalidDeviceType)?;
+            // The placeholder has no queues, only its IRQ is routed. It never interrupts the
+            // guest, so it can be left without one if the device fails to register.
+            vm.unregister_irqfd(
+                transport.locked_device().interrupt_evt(),
+                device_info.irqs[0],
+            )
+            .map_err(Error::UnregisterIrqFd)?;
+            {
+                let locked_device = mmio_device.locked_device();
+                identifier = (DeviceType::Virtio(locked_device.device_type()), device_id);
+                Self::register_virtio_events(vm, &*locked_device, &device_info)?;
+            }
+            *transport = mmio_device;
+        }
+
+        self.id_to_dev_info.remove(&slot);
+        self.id_to_dev_info.insert(identifier, device_info.clone());
+        Ok(device_info)
+    }
+
     /// Append a registered virtio-over-MMIO device to the kernel cmdline.
     #[cfg(target_arch = "x86_64")]
     pub fn add_virtio_device_to_cmdline(
@@ -395,6 +459,7 @@ impl MMIODeviceManager {
         let _: Result<()> = self.for_each_virtio_device(|virtio_type, id, _info, dev| {
             let mut virtio = dev.lock().expect("Poisoned lock");
             match virtio_type {
+                #[cfg(feature = "balloon")]
                 TYPE_BALLOON => {
                     let balloon = virtio.as_mut_any().downcast_mut::<Balloon>().unwrap();
                     // If device is activated, kick the balloon queue(s) to make up for any
@@ -405,6 +470,7 @@ impl MMIODeviceManager {
                         balloon.process_virtio_queues();
                     }
                 }
+                #[cfg(feature = "faascale-mem")]
                 TYPE_FAASCALE_MEM => {
                     let faascale = virtio.as_mut_any().downcast_mut::<FaascaleMem>().unwrap();
                     // If device is activated, kick the faascale-mem queue(s) to make up for any
@@ -607,6 +673,95 @@ mod tests {
             .is_ok());
     }
 
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_hotplug_virtio_device() {
+        use crate::devices::virtio::{
+            HotplugSlot, FAASCALE_MEM_HOTPLUG_SLOT_ID, TYPE_HOTPLUG_SLOT,
+        };
+
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let guest_mem = utils::vm_memory::test_utils::create_anon_guest_memory(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            false,
+        )
+        .unwrap();
+        let mut vm = builder::setup_kvm_vm(&guest_mem, false).unwrap();
+        let mut device_manager = MMIODeviceManager::new(
+            0xd000_0000,
+            crate::arch::MMIO_MEM_SIZE,
+            (crate::arch::IRQ_BASE, crate::arch::IRQ_MAX),
+        )
+        .unwrap();
+
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&mut vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        let slot = (
+            DeviceType::Virtio(TYPE_HOTPLUG_SLOT),
+            FAASCALE_MEM_HOTPLUG_SLOT_ID.to_string(),
+        );
+        let slot_addr = device_manager
+            .register_virtio_test_device(
+                vm.fd(),
+                guest_mem.clone(),
+                Arc::new(Mutex::new(HotplugSlot::new(Default::default()).unwrap())),
+                &mut cmdline,
+                FAASCALE_MEM_HOTPLUG_SLOT_ID,
+            )
+            .unwrap();
+        // The vCPUs hold their own copy of the bus.
+        let vcpu_bus = device_manager.bus.clone();
+        let mut queue_num_max = [0u8; 4];
+        vcpu_bus.read(slot_addr + 0x34, &mut queue_num_max);
+        assert_eq!(u32::from_le_bytes(queue_num_max), 0);
+
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        let wrong_slot = (DeviceType::Virtio(TYPE_HOTPLUG_SLOT), "dummy".to_string());
+        assert!(matches!(
+            device_manager.hotplug_mmio_virtio(
+                vm.fd(),
+                wrong_slot,
+                "dummy".to_string(),
+                MmioTransport::new(guest_mem.clone(), dummy.clone()),
+            ),
+            Err(Error::HotplugSlotNotFound)
+        ));
+
+        let device_info = device_manager
+            .hotplug_mmio_virtio(
+                vm.fd(),
+                slot.clone(),
+                "dummy".to_string(),
+                MmioTransport::new(guest_mem.clone(), dummy.clone()),
+            )
+            .unwrap();
+        assert_eq!(device_info.addr, slot_addr);
+        assert!(!device_manager.id_to_dev_info.contains_key(&slot));
+        assert_eq!(
+            device_manager.id_to_dev_info[&(DeviceType::Virtio(0), "dummy".to_string())],
+            device_info
+        );
+        // The device is reachable through the copy of the bus taken before plugging it.
+        vcpu_bus.read(slot_addr + 0x34, &mut queue_num_max);
+        assert_eq!(u32::from_le_bytes(queue_num_max), u32::from(QUEUE_SIZES[0]));
+
+        // The slot is used up.
+        assert!(matches!(
+            device_manager.hotplug_mmio_virtio(
+                vm.fd(),
+                slot,
+                "dummy".to_string(),
+                MmioTransport::new(guest_mem, dummy),
+            ),
+            Err(Error::HotplugSlotNotFound)
+        ));
+    }
+
     #[test]
     fn test_register_too_many_devices() {
         let start_addr1 = GuestAddress(0x0);
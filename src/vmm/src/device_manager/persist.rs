@@ -3,6 +3,8 @@
 
 //! Provides functionality for saving/restoring the MMIO device manager and its devices.
 
+#[cfg(feature = "faascale-mem")]
+use std::os::unix::io::AsRawFd;
 use std::result::Result;
 use std::sync::{Arc, Mutex};
 
@@ -19,10 +21,18 @@ use vm_allocator::AllocPolicy;
 use super::mmio::*;
 #[cfg(target_arch = "aarch64")]
 use crate::arch::DeviceType;
-use crate::devices::virtio::balloon::persist::{BalloonConstructorArgs, BalloonState};
-use crate::devices::virtio::balloon::{Balloon, Error as BalloonError};
-use crate::devices::virtio::faascale_mem::persist::{FaascaleMemConstructorArgs, FaascaleMemState};
-use crate::devices::virtio::faascale_mem::{FaascaleMem, Error as FaascaleMemError};
+#[cfg(feature = "balloon")]
+use crate::devices::virtio::balloon::persist::BalloonConstructorArgs;
+use crate::devices::virtio::balloon::persist::BalloonState;
+#[cfg(feature = "balloon")]
+use crate::devices::virtio::balloon::Balloon;
+use crate::devices::virtio::balloon::Error as BalloonError;
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::faascale_mem::persist::FaascaleMemConstructorArgs;
+use crate::devices::virtio::faascale_mem::persist::FaascaleMemState;
+use crate::devices::virtio::faascale_mem::Error as FaascaleMemError;
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::faascale_mem::FaascaleMem;
 use crate::devices::virtio::block::persist::{BlockConstructorArgs, BlockState};
 use crate::devices::virtio::block::{Block, Error as BlockError};
 use crate::devices::virtio::net::persist::{Error as NetError, NetConstructorArgs, NetState};
@@ -36,9 +46,14 @@ use crate::devices::virtio::vsock::persist::{
     VsockConstructorArgs, VsockState, VsockUdsConstructorArgs,
 };
 use crate::devices::virtio::vsock::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
+#[cfg(feature = "balloon")]
+use crate::devices::virtio::TYPE_BALLOON;
+#[cfg(feature = "faascale-mem")]
 use crate::devices::virtio::{
-    MmioTransport, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, TYPE_RNG, TYPE_VSOCK, TYPE_FAASCALE_MEM
+    FaascaleMemCapabilities, HotplugSlot, FAASCALE_MEM_HOTPLUG_SLOT_ID, TYPE_FAASCALE_MEM,
+    TYPE_HOTPLUG_SLOT,
 };
+use crate::devices::virtio::{MmioTransport, VirtioDevice, TYPE_BLOCK, TYPE_NET, TYPE_RNG, TYPE_VSOCK};
 use crate::resources::VmResources;
 use crate::vmm_config::mmds::MmdsConfigError;
 use crate::EventManager;
@@ -58,6 +73,9 @@ pub enum Error {
     VsockUnixBackend(VsockUnixBackendError),
     MmdsConfig(MmdsConfigError),
     Entropy(EntropyError),
+    /// The snapshot holds a device whose support is not compiled into this build.
+    #[from(ignore)]
+    MissingFeature(&'static str),
 }
 
 /// Holds the state of a balloon device connected to the MMIO space.
@@ -205,6 +223,9 @@ pub struct DeviceStates {
     /// Entropy device state.
     #[version(start = 4, ser_fn = "entropy_serialize")]
     pub entropy_device: Option<ConnectedEntropyState>,
+    /// Slot reserved to hot-plug the faascale-mem device, if still empty.
+    #[version(start = 5, ser_fn = "faascale_mem_hotplug_slot_serialize")]
+    pub faascale_mem_hotplug_slot: Option<MMIODeviceInfo>,
 }
 
 /// A type used to extract the concrete Arc<Mutex<T>> for each of the device types when restoring
@@ -212,7 +233,9 @@ pub struct DeviceStates {
 pub enum SharedDeviceType {
     Block(Arc<Mutex<Block>>),
     Network(Arc<Mutex<Net>>),
+    #[cfg(feature = "balloon")]
     Balloon(Arc<Mutex<Balloon>>),
+    #[cfg(feature = "faascale-mem")]
     FaascaleMem(Arc<Mutex<FaascaleMem>>),
     Vsock(Arc<Mutex<Vsock<VsockUnixBackend>>>),
     Entropy(Arc<Mutex<Entropy>>),
@@ -259,11 +282,22 @@ impl DeviceStates {
 
         Ok(())
     }
+
+    fn faascale_mem_hotplug_slot_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 5 && self.faascale_mem_hotplug_slot.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support persisting the faascale-mem hot-plug slot."
+                    .to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct MMIODevManagerConstructorArgs<'a> {
     pub mem: GuestMemoryMmap,
-    pub vm: &'a VmFd,
+    pub vm: &'a Arc<VmFd>,
     pub event_manager: &'a mut EventManager,
     pub for_each_restored_device: fn(&mut VmResources, SharedDeviceType),
     pub vm_resources: &'a mut VmResources,
@@ -286,6 +320,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             legacy_devices: Vec::new(),
             mmds_version: None,
             entropy_device: None,
+            faascale_mem_hotplug_slot: None,
         };
         let _: Result<(), ()> = self.for_each_device(|devtype, devid, device_info, bus_dev| {
             if *devtype == crate::arch::DeviceType::BootTimer {
@@ -315,6 +350,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
 
             let mut locked_device = mmio_transport.locked_device();
             match locked_device.device_type() {
+                #[cfg(feature = "balloon")]
                 TYPE_BALLOON => {
                     let balloon_state = locked_device
                         .as_any()
@@ -328,12 +364,14 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                         device_info: device_info.clone(),
                     });
                 }
+                #[cfg(feature = "faascale-mem")]
                 TYPE_FAASCALE_MEM => {
-                    let faascale_mem_state = locked_device
-                        .as_any()
-                        .downcast_ref::<FaascaleMem>()
-                        .unwrap()
-                        .save();
+                    let faascale_mem = locked_device
+                        .as_mut_any()
+                        .downcast_mut::<FaascaleMem>()
+                        .unwrap();
+                    faascale_mem.prepare_save();
+                    let faascale_mem_state = faascale_mem.save();
                     states.faascale_mem_device = Some(ConnectedFaascaleMemState {
                         device_id: devid.clone(),
                         device_state: faascale_mem_state,
@@ -407,6 +445,11 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                         device_info: device_info.clone(),
                     });
                 }
+                #[cfg(feature = "faascale-mem")]
+                TYPE_HOTPLUG_SLOT => {
+                    // The guest never drives the placeholder, only the slot is kept.
+                    states.faascale_mem_hotplug_slot = Some(device_info.clone());
+                }
                 _ => unreachable!(),
             };
 
@@ -507,6 +550,12 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             Ok(())
         };
 
+        #[cfg(not(feature = "balloon"))]
+        if state.balloon_device.is_some() {
+            return Err(Error::MissingFeature("balloon"));
+        }
+
+        #[cfg(feature = "balloon")]
         if let Some(balloon_state) = &state.balloon_device {
             let device = Arc::new(Mutex::new(Balloon::restore(
                 BalloonConstructorArgs { mem: mem.clone() },
@@ -528,9 +577,18 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             )?;
         }
 
+        #[cfg(not(feature = "faascale-mem"))]
+        if state.faascale_mem_device.is_some() {
+            return Err(Error::MissingFeature("faascale-mem"));
+        }
+
+        #[cfg(feature = "faascale-mem")]
         if let Some(faascale_mem_state) = &state.faascale_mem_device {
             let device = Arc::new(Mutex::new(FaascaleMem::restore(
-                FaascaleMemConstructorArgs { mem: mem.clone() },
+                FaascaleMemConstructorArgs {
+                    mem: mem.clone(),
+                    vm_fd: vm.clone(),
+                },
                 &faascale_mem_state.device_state,
             )?));
 
@@ -666,6 +724,32 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             )?;
         }
 
+        #[cfg(not(feature = "faascale-mem"))]
+        if state.faascale_mem_hotplug_slot.is_some() {
+            return Err(Error::MissingFeature("faascale-mem"));
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        if let Some(device_info) = &state.faascale_mem_hotplug_slot {
+            // The filters are not installed yet, the host can still be probed.
+            let slot = HotplugSlot::new(FaascaleMemCapabilities::probe(vm.as_raw_fd()))?;
+            let slot = MmioTransport::new(mem.clone(), Arc::new(Mutex::new(slot)));
+            dev_manager
+                .address_allocator
+                .allocate(
+                    MMIO_LEN,
+                    MMIO_LEN,
+                    AllocPolicy::ExactMatch(device_info.addr),
+                )
+                .map_err(|e| Error::DeviceManager(super::mmio::Error::Allocator(e)))?;
+            dev_manager.register_mmio_virtio(
+                vm,
+                FAASCALE_MEM_HOTPLUG_SLOT_ID.to_string(),
+                slot,
+                device_info,
+            )?;
+        }
+
         Ok(dev_manager)
     }
 }
@@ -679,7 +763,8 @@ mod tests {
     use crate::devices::virtio::block::CacheType;
     use crate::devices::virtio::net::persist::NetConfigSpaceState;
     use crate::resources::VmmConfig;
-    use crate::vmm_config::balloon::BalloonDeviceConfig;
+    #[cfg(feature = "balloon")]
+    use crate::vmm_config::balloon::{BalloonDeflatePrefetch, BalloonDeviceConfig};
     use crate::vmm_config::entropy::EntropyDeviceConfig;
     use crate::vmm_config::net::NetworkInterfaceConfig;
     use crate::vmm_config::vsock::VsockDeviceConfig;
@@ -812,6 +897,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_device_manager_persistence() {
         let mut buf = vec![0; 16384];
         let mut version_map = VersionMap::new();
@@ -830,6 +916,11 @@ mod tests {
                 amount_mib: 123,
                 deflate_on_oom: false,
                 stats_polling_interval_s: 1,
+                deflate_prefetch: BalloonDeflatePrefetch::None,
+                strict_stats: false,
+                interrupt_moderation: false,
+                min_guest_mib: 0,
+                config_epoch: 0,
             };
             insert_balloon_device(&mut vmm, &mut cmdline, &mut event_manager, balloon_cfg);
             // Add a block device.
@@ -948,7 +1039,7 @@ mod tests {
         let vm_resources = &mut VmResources::default();
         let restore_args = MMIODevManagerConstructorArgs {
             mem: vmm.guest_memory().clone(),
-            vm: vmm.vm.fd(),
+            vm: vmm.vm.shared_fd(),
             event_manager: &mut event_manager,
             for_each_restored_device: VmResources::update_from_restored_device,
             vm_resources,
@@ -962,7 +1053,12 @@ mod tests {
   "balloon": {{
     "amount_mib": 123,
     "deflate_on_oom": false,
-    "stats_polling_interval_s": 1
+    "stats_polling_interval_s": 1,
+    "deflate_prefetch": "None",
+    "strict_stats": false,
+    "interrupt_moderation": false,
+    "min_guest_mib": 0,
+    "config_epoch": 0
   }},
   "drives": [
     {{
@@ -23,11 +23,13 @@ pub(crate) fn report_net_event_fail(err: Error) {
     METRICS.net.event_fails.inc();
 }
 
+#[cfg(feature = "balloon")]
 pub(crate) fn report_balloon_event_fail(err: virtio::balloon::Error) {
     error!("{:?}", err);
     METRICS.balloon.event_fails.inc();
 }
 
+#[cfg(feature = "faascale-mem")]
 pub(crate) fn report_faascale_mem_event_fail(err: virtio::faascale_mem::Error) {
     error!("{:?}", err);
     METRICS.faascale_mem.event_fails.inc();
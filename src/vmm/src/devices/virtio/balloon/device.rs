@@ -6,17 +6,20 @@ use std::io::Write;
 use std::result::Result;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use logger::{error, IncMetric, METRICS};
-use serde::Serialize;
-use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use serde::{Deserialize, Serialize};
+use timerfd::{SetTimeFlags, TimerState};
+use utils::clock::{Clock, MonotonicClock, Timer};
 use utils::eventfd::EventFd;
 use utils::vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 use virtio_gen::virtio_blk::VIRTIO_F_VERSION_1;
 
-use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BALLOON};
-use super::util::{compact_page_frame_numbers, remove_range};
+use super::super::{
+    ActivateResult, DeviceState, Queue, VirtioDevice, POP_BATCH_SIZE, TYPE_BALLOON,
+};
+use super::util::{compact_page_frame_numbers, prefetch_range, remove_range};
 use super::{
     BALLOON_DEV_ID, DEFLATE_INDEX, INFLATE_INDEX, MAX_PAGES_IN_DESC, MAX_PAGE_COMPACT_BUFFER,
     MIB_TO_4K_PAGES, NUM_QUEUES, QUEUE_SIZES, STATS_INDEX, VIRTIO_BALLOON_F_DEFLATE_ON_OOM,
@@ -26,6 +29,10 @@ use super::{
     VIRTIO_BALLOON_S_MINFLT, VIRTIO_BALLOON_S_SWAP_IN, VIRTIO_BALLOON_S_SWAP_OUT,
 };
 use crate::devices::virtio::balloon::Error as BalloonError;
+use crate::devices::virtio::irq_moderation::InterruptModerator;
+use crate::devices::virtio::mem_overlay::MmapOverlays;
+use crate::devices::virtio::pause_gate::VmPauseGate;
+use crate::devices::virtio::stats_delta::CounterDelta;
 use crate::devices::virtio::{IrqTrigger, IrqType};
 
 /// SIZE_OF_U32和SIZE_OF_STAT，分别表示u32和BalloonStat类型的大小（以字节为单位）
@@ -109,6 +116,46 @@ pub struct BalloonConfig {
     pub deflate_on_oom: bool,
     // 在 Out Of Memory（OOM，内存不足）时是否启用"收紧气球"
     pub stats_polling_interval_s: u16, // 轮询统计信息的时间间隔（以秒为单位）
+    pub deflate_prefetch: BalloonDeflatePrefetch,
+    pub strict_stats: bool,
+    pub interrupt_moderation: bool,
+    pub min_guest_mib: u32,
+    pub config_epoch: u64,
+}
+
+/// Policy applied to the pages the guest takes back when the balloon deflates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BalloonDeflatePrefetch {
+    /// Leave the pages alone, they will be faulted in when the guest touches them.
+    #[default]
+    None,
+    /// Hint the host kernel with `MADV_WILLNEED`.
+    WillNeed,
+    /// Fault the pages in with `MADV_POPULATE_WRITE` before acknowledging the deflate.
+    Populate,
+}
+
+impl BalloonDeflatePrefetch {
+    /// Returns the `madvise` advice implementing the policy, if any.
+    fn advice(&self) -> Option<libc::c_int> {
+        match self {
+            BalloonDeflatePrefetch::None => None,
+            BalloonDeflatePrefetch::WillNeed => Some(libc::MADV_WILLNEED),
+            BalloonDeflatePrefetch::Populate => Some(libc::MADV_POPULATE_WRITE),
+        }
+    }
+}
+
+/// Balloon config space as the guest reads it: the target size set by the host next to the
+/// size the guest reports, to tell a balloon still inflating from a guest ignoring it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BalloonConfigSpace {
+    /// Bytes of the config space, in hexadecimal, in the order the guest reads them.
+    pub raw: String,
+    /// Target size of the balloon in 4K pages, written by the host.
+    pub num_pages: u32,
+    /// Size of the balloon in 4K pages, written by the guest.
+    pub actual_pages: u32,
 }
 
 // BalloonStats holds statistics returned from the stats_queue.
@@ -152,6 +199,24 @@ pub struct BalloonStats {
     pub hugetlb_allocations: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hugetlb_failures: Option<u64>,
+    /// Time elapsed between the last two samples of the guest statistics, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_interval_ms: Option<u64>,
+    /// Growth of the swap and page fault counters since the previous sample.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_in_delta: Option<CounterDelta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_out_delta: Option<CounterDelta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub major_faults_delta: Option<CounterDelta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minor_faults_delta: Option<CounterDelta>,
+    /// Host time spent prefetching the pages returned by the last deflate, in microseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deflate_prefetch_us: Option<u64>,
+    /// Number of pages prefetched during the last deflate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deflate_prefetch_pages: Option<u64>,
 }
 
 impl BalloonStats {
@@ -177,6 +242,18 @@ impl BalloonStats {
 
         Ok(())
     }
+
+    /// Computes the growth of the cumulative counters since the `previous` sample, taken
+    /// `interval` before this one.
+    fn update_deltas(&mut self, previous: &Self, interval: Duration) {
+        self.sample_interval_ms = Some(u64::try_from(interval.as_millis()).unwrap_or(u64::MAX));
+        self.swap_in_delta = CounterDelta::between(previous.swap_in, self.swap_in, interval);
+        self.swap_out_delta = CounterDelta::between(previous.swap_out, self.swap_out, interval);
+        self.major_faults_delta =
+            CounterDelta::between(previous.major_faults, self.major_faults, interval);
+        self.minor_faults_delta =
+            CounterDelta::between(previous.minor_faults, self.minor_faults, interval);
+    }
 }
 
 // Virtio balloon device.
@@ -204,7 +281,7 @@ pub struct Balloon {
     // 表示设备是否已经恢复过。
     pub(crate) stats_polling_interval_s: u16,
     // 表示统计信息轮询的时间间隔，单位为秒。
-    pub(crate) stats_timer: TimerFd,
+    pub(crate) stats_timer: Box<dyn Timer>,
     // 表示统计信息轮询定时器。
     // The index of the previous stats descriptor is saved because
     // it is acknowledged after the stats queue is processed.
@@ -212,8 +289,29 @@ pub struct Balloon {
     // 表示上一次处理的统计信息描述符的索引，这个索引在统计信息队列被处理后会被确认。
     pub(crate) latest_stats: BalloonStats,
     // 表示最新的设备统计信息。
+    // When the latest statistics sample was processed.
+    pub(crate) last_stats_sample: Option<Instant>,
     // A buffer used as pfn accumulator during descriptor processing.
     pub(crate) pfn_buffer: [u32; MAX_PAGE_COMPACT_BUFFER], // 表示在描述符处理过程中用作页面帧号累加器的缓冲区。
+    // Policy applied to the pages returned by the guest on deflate.
+    pub(crate) deflate_prefetch: BalloonDeflatePrefetch,
+    // Whether a statistics descriptor holding an unknown tag is rejected, instead of skipping
+    // the tag.
+    pub(crate) strict_stats: bool,
+    // Guest memory, in MiB, the balloon never reclaims. Zero leaves the guest unprotected.
+    pub(crate) min_guest_mib: u32,
+    // Number of successful runtime configuration updates.
+    pub(crate) config_epoch: u64,
+    // Whether the queues are left unprocessed until the device is resumed.
+    pub(crate) quiesced: bool,
+    // Anonymous mappings laid over the guest memory of a restored microVM.
+    pub(crate) mmap_overlays: MmapOverlays,
+    // Pause state of the microVM, the queues are left unprocessed while it is paused.
+    pub(crate) pause_gate: VmPauseGate,
+    // Moderation of the notifications of the deflate queue.
+    pub(crate) irq_moderator: InterruptModerator,
+    // Time source of the statistics timer, the interrupt moderation and the statistics deltas.
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
 impl Balloon {
@@ -236,6 +334,9 @@ impl Balloon {
         deflate_on_oom: bool,
         stats_polling_interval_s: u16,
         restored: bool,
+        deflate_prefetch: BalloonDeflatePrefetch,
+        strict_stats: bool,
+        min_guest_mib: u32,
     ) -> Result<Balloon, BalloonError> {
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
 
@@ -267,8 +368,10 @@ impl Balloon {
         }
 
         // TimerFD 时间轮询器
-        let stats_timer =
-            TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(BalloonError::Timer)?;
+        let clock = MonotonicClock::shared();
+        let stats_timer = clock.timer().map_err(BalloonError::Timer)?;
+        let irq_moderator =
+            InterruptModerator::new(false, clock.as_ref()).map_err(BalloonError::Timer)?;
 
         Ok(Balloon {
             avail_features,
@@ -289,7 +392,17 @@ impl Balloon {
             stats_timer,
             stats_desc_index: None,
             latest_stats: BalloonStats::default(),
+            last_stats_sample: None,
             pfn_buffer: [0u32; MAX_PAGE_COMPACT_BUFFER],
+            deflate_prefetch,
+            strict_stats,
+            min_guest_mib,
+            config_epoch: 0,
+            quiesced: false,
+            mmap_overlays: MmapOverlays::default(),
+            pause_gate: VmPauseGate::default(),
+            irq_moderator,
+            clock,
         })
     }
 
@@ -310,6 +423,9 @@ impl Balloon {
         self.queue_evts[INFLATE_INDEX]
             .read()
             .map_err(BalloonError::EventFd)?;
+        if self.events_deferred() {
+            return Ok(());
+        }
         self.process_inflate_queue()
     }
 
@@ -317,6 +433,10 @@ impl Balloon {
         self.queue_evts[DEFLATE_INDEX]
             .read()
             .map_err(BalloonError::EventFd)?;
+        self.irq_moderator.kicked(self.clock.now());
+        if self.events_deferred() {
+            return Ok(());
+        }
         self.process_deflate_queue()
     }
 
@@ -324,14 +444,30 @@ impl Balloon {
         self.queue_evts[STATS_INDEX]
             .read()
             .map_err(BalloonError::EventFd)?;
+        if self.events_deferred() {
+            return Ok(());
+        }
         self.process_stats_queue()
     }
 
     pub(crate) fn process_stats_timer_event(&mut self) -> Result<(), BalloonError> {
         self.stats_timer.read();
+        if self.events_deferred() {
+            return Ok(());
+        }
         self.trigger_stats_update()
     }
 
+    pub(crate) fn process_irq_moderation_event(&mut self) -> Result<(), BalloonError> {
+        // The held notification is raised even while the microVM is paused, its requests are
+        // already complete.
+        if !self.irq_moderator.timer_expired(self.clock.now()) {
+            return Ok(());
+        }
+        METRICS.balloon.irq_moderation_timeouts.inc();
+        self.signal_used_queue()
+    }
+
 
     /// 这段代码实现了 BalloonDevice 中的 process_inflate_queue 函数。当 BalloonDevice 接收到来自 VM 的膨胀请求时，process_inflate_queue 函数会被调用来处理这个请求。
     ///
@@ -360,6 +496,7 @@ impl Balloon {
         let mut pfn_buffer_idx = 0;
         let mut needs_interrupt = false;
         let mut valid_descs_found = true;
+        let mut result = Ok(());
 
         // Loop until there are no more valid DescriptorChains.
         while valid_descs_found {
@@ -373,71 +510,97 @@ impl Balloon {
             // head的内容。尽管如此，这段代码并不会出问题，因为Linux内核，会将每1MB的page，即256个PFN作为一次IO请求，写入到Queue中。因此每个IO请求
             // 的Descriptor链表，确实只有一个Descriptor，因此不需要对其进行遍历
             // （一个IO请求，对应了Linux内核中的一个散列表，Linux balloon使用了sg_init_one来初始化，所以其散列表中只有一个Descriptor）
-            while let Some(head) = queue.pop(mem) {
-                println!("{:?}", head);
-                let len = head.len as usize; // 获取该Descriptor的数据区的大小，数据区存放的是guest返回的PFN
-                /*
-                 * 需要知道，在Linux内核中，用于传输的PFN的数据结构为：
-                 * __virtio32 pfns[VIRTIO_BALLOON_ARRAY_PFNS_MAX];
-                 * 因此，这个数据的类型就是U32，而这个数组的大小是：
-                 * #define VIRTIO_BALLOON_ARRAY_PFNS_MAX 256
-                 */
-                let max_len = MAX_PAGES_IN_DESC * SIZE_OF_U32; // 每个Descriptor最多存放256个PFN，也即1MB
-                valid_descs_found = true;
-
-                // head的数据区就是内核传输过来的pfns数组，因此其数据区的长度一定是整除SIZE_OF_U32的
-                // is_write_only 为真表明，这个descriptors对于Device是write_only,而对于driver是read_only，显然在这里，应该对于firecracker应该是只读的
-                if !head.is_write_only() && len % SIZE_OF_U32 == 0 { //
-                    // Check descriptor pfn count.
-                    // head的长度肯定不能超过最大的长度限制，即其最多存放256个pfn
-                    if len > max_len {
-                        error!(
-                            "Inflate descriptor has bogus page count {} > {}, skipping.",
-                            len / SIZE_OF_U32,
-                            MAX_PAGES_IN_DESC
-                        );
-
-                        // Skip descriptor.
-                        continue;
-                    }
-                    // Break loop if `pfn_buffer` will be overrun by adding all pfns from current
-                    // desc.
-                    // firecracker会将所有要释放的pfn统一到一个pfn_buffer中，然后进行收缩处理，即尝试识别连续的pfn
-                    // pfn_buffer的大小是MAX_PAGE_COMPACT_BUFFER=2048，当个pfn_buffer的大小不足以装下本次循环的
-                    // Descriptor中的fpn时，将会退出循环，然后处理这一批的pfn，注意我们前面设置了valid_descs_found = true;
-                    // 因此当上一批的fpn处理完成后，循环将会继续
-                    if MAX_PAGE_COMPACT_BUFFER - pfn_buffer_idx < len / SIZE_OF_U32 {
-                        queue.undo_pop();
-                        break;
+            // Heads are popped in batches, reading the avail index once per batch.
+            let mut heads = queue.pop_batch(mem, POP_BATCH_SIZE);
+            'batches: while !heads.is_empty() {
+                let batch_len = heads.len();
+                for (popped, head) in heads.into_iter().enumerate() {
+                    let len = head.len as usize; // 获取该Descriptor的数据区的大小，数据区存放的是guest返回的PFN
+                    /*
+                     * 需要知道，在Linux内核中，用于传输的PFN的数据结构为：
+                     * __virtio32 pfns[VIRTIO_BALLOON_ARRAY_PFNS_MAX];
+                     * 因此，这个数据的类型就是U32，而这个数组的大小是：
+                     * #define VIRTIO_BALLOON_ARRAY_PFNS_MAX 256
+                     */
+                    let max_len = MAX_PAGES_IN_DESC * SIZE_OF_U32; // 每个Descriptor最多存放256个PFN，也即1MB
+                    valid_descs_found = true;
+
+                    // head的数据区就是内核传输过来的pfns数组，因此其数据区的长度一定是整除SIZE_OF_U32的
+                    // is_write_only 为真表明，这个descriptors对于Device是write_only,而对于driver是read_only，显然在这里，应该对于firecracker应该是只读的
+                    if !head.is_write_only() && len % SIZE_OF_U32 == 0 { //
+                        // Check descriptor pfn count.
+                        // head的长度肯定不能超过最大的长度限制，即其最多存放256个pfn
+                        if len > max_len {
+                            error!(
+                                "Inflate descriptor has bogus page count {} > {}, skipping.",
+                                len / SIZE_OF_U32,
+                                MAX_PAGES_IN_DESC
+                            );
+
+                            // Skip descriptor.
+                            continue;
+                        }
+                        // Break loop if `pfn_buffer` will be overrun by adding all pfns from current
+                        // desc.
+                        // firecracker会将所有要释放的pfn统一到一个pfn_buffer中，然后进行收缩处理，即尝试识别连续的pfn
+                        // pfn_buffer的大小是MAX_PAGE_COMPACT_BUFFER=2048，当个pfn_buffer的大小不足以装下本次循环的
+                        // Descriptor中的fpn时，将会退出循环，然后处理这一批的pfn，注意我们前面设置了valid_descs_found = true;
+                        // 因此当上一批的fpn处理完成后，循环将会继续
+                        if MAX_PAGE_COMPACT_BUFFER - pfn_buffer_idx < len / SIZE_OF_U32 {
+                            // Give back the descriptor, along with the rest of the batch.
+                            for _ in popped..batch_len {
+                                queue.undo_pop();
+                            }
+                            break 'batches;
+                        }
+
+                        // This is safe, `len` was validated above.
+                        // 循环的遍历出Descriptor的数据区中所有的pfn
+                        let desc_pfn_buffer_idx = pfn_buffer_idx;
+                        let read_pfns = (0..len).step_by(SIZE_OF_U32).try_for_each(|index| {
+                            // head.addr 是数据区的首地址，加上index后，就是每个fpn的地址，整个地址是虚拟机的物理地址
+                            let addr = head
+                                .addr
+                                .checked_add(index as u64)
+                                .ok_or(BalloonError::MalformedDescriptor)?;
+
+                            // 通过mem.read_obj，将pfn读出来
+                            let page_frame_number = mem
+                                .read_obj::<u32>(addr)
+                                .map_err(|_| BalloonError::MalformedDescriptor)?;
+
+                            // 将每个pfn加入到pfn_buffer中
+                            self.pfn_buffer[pfn_buffer_idx] = page_frame_number;
+                            pfn_buffer_idx += 1;
+                            Ok(())
+                        });
+                        if let Err(err) = read_pfns {
+                            // The malformed descriptor is completed and the rest of the batch
+                            // given back, while the pages of the previous descriptors are still
+                            // removed.
+                            pfn_buffer_idx = desc_pfn_buffer_idx;
+                            for _ in popped + 1..batch_len {
+                                queue.undo_pop();
+                            }
+                            queue
+                                .add_used(mem, head.index, 0)
+                                .map_err(BalloonError::Queue)?;
+                            needs_interrupt = true;
+                            result = Err(err);
+                            valid_descs_found = false;
+                            break 'batches;
+                        }
                     }
 
-                    // This is safe, `len` was validated above.
-                    // 循环的遍历出Descriptor的数据区中所有的pfn
-                    for index in (0..len).step_by(SIZE_OF_U32) {
-                        // head.addr 是数据区的首地址，加上index后，就是每个fpn的地址，整个地址是虚拟机的物理地址
-                        let addr = head
-                            .addr
-                            .checked_add(index as u64)
-                            .ok_or(BalloonError::MalformedDescriptor)?;
-
-                        // 通过mem.read_obj，将pfn读出来
-                        let page_frame_number = mem
-                            .read_obj::<u32>(addr)
-                            .map_err(|_| BalloonError::MalformedDescriptor)?;
-
-                        // 将每个pfn加入到pfn_buffer中
-                        self.pfn_buffer[pfn_buffer_idx] = page_frame_number;
-                        pfn_buffer_idx += 1;
-                    }
+                    // Acknowledge the receipt of the descriptor.
+                    // 0 is number of bytes the device has written to memory.
+                    // 告诉guest，我们已经读取完成了一个IO请求，其可以将指定的descriptor给释放掉。
+                    queue
+                        .add_used(mem, head.index, 0)
+                        .map_err(BalloonError::Queue)?;
+                    needs_interrupt = true;
                 }
-
-                // Acknowledge the receipt of the descriptor.
-                // 0 is number of bytes the device has written to memory.
-                // 告诉guest，我们已经读取完成了一个IO请求，其可以将指定的descriptor给释放掉。
-                queue
-                    .add_used(mem, head.index, 0)
-                    .map_err(BalloonError::Queue)?;
-                needs_interrupt = true;
+                heads = queue.pop_batch(mem, POP_BATCH_SIZE);
             }
 
             // Compact pages into ranges.
@@ -455,7 +618,7 @@ impl Balloon {
                 if let Err(err) = remove_range(
                     mem,
                     (guest_addr, u64::from(range_len) << VIRTIO_BALLOON_PFN_SHIFT),
-                    self.restored,
+                    self.restored.then_some(&mut self.mmap_overlays),
                 ) {
                     error!("Error removing memory range: {:?}", err);
                 }
@@ -466,7 +629,7 @@ impl Balloon {
             self.signal_used_queue()?;
         }
 
-        Ok(())
+        result
     }
 
     // 对于收缩气球，也就是扩展VM的内存，firecracker是没有进行任何操作的，也就是，完全靠pagefault来填充物理内存
@@ -477,18 +640,71 @@ impl Balloon {
         METRICS.balloon.deflate_count.inc();
 
         let queue = &mut self.queues[DEFLATE_INDEX];
+        let advice = self.deflate_prefetch.advice();
+        let mut pfn_buffer_idx = 0;
         let mut needs_interrupt = false;
 
         while let Some(head) = queue.pop(mem) {
+            let len = head.len as usize;
+            // The pfns are only needed when prefetching. Descriptors that do not fit
+            // in `pfn_buffer` are acknowledged without being prefetched.
+            if advice.is_some()
+                && !head.is_write_only()
+                && len % SIZE_OF_U32 == 0
+                && len <= MAX_PAGES_IN_DESC * SIZE_OF_U32
+                && len / SIZE_OF_U32 <= MAX_PAGE_COMPACT_BUFFER - pfn_buffer_idx
+            {
+                for index in (0..len).step_by(SIZE_OF_U32) {
+                    let addr = head
+                        .addr
+                        .checked_add(index as u64)
+                        .ok_or(BalloonError::MalformedDescriptor)?;
+                    self.pfn_buffer[pfn_buffer_idx] = mem
+                        .read_obj::<u32>(addr)
+                        .map_err(|_| BalloonError::MalformedDescriptor)?;
+                    pfn_buffer_idx += 1;
+                }
+            }
+
             queue
                 .add_used(mem, head.index, 0)
                 .map_err(BalloonError::Queue)?;
             needs_interrupt = true;
         }
 
-        if needs_interrupt {
+        // Prefetch before notifying the guest, so that the pages are ready by the time
+        // the driver hands them back to the guest allocator.
+        if let Some(advice) = advice {
+            if pfn_buffer_idx > 0 {
+                let start_time = Instant::now();
+                let page_ranges =
+                    compact_page_frame_numbers(&mut self.pfn_buffer[..pfn_buffer_idx]);
+                for (page_frame_number, range_len) in page_ranges {
+                    let guest_addr =
+                        GuestAddress(u64::from(page_frame_number) << VIRTIO_BALLOON_PFN_SHIFT);
+
+                    if let Err(err) = prefetch_range(
+                        mem,
+                        (guest_addr, u64::from(range_len) << VIRTIO_BALLOON_PFN_SHIFT),
+                        advice,
+                    ) {
+                        METRICS.balloon.deflate_prefetch_fails.inc();
+                        error!("Error prefetching memory range: {:?}", err);
+                    }
+                }
+                self.latest_stats.deflate_prefetch_us =
+                    Some(u64::try_from(start_time.elapsed().as_micros()).unwrap_or(u64::MAX));
+                self.latest_stats.deflate_prefetch_pages = Some(pfn_buffer_idx as u64);
+            }
+        }
+
+        if !needs_interrupt {
+            return Ok(());
+        }
+        if self.irq_moderator.pass_completed(self.clock.now()) {
             self.signal_used_queue()
         } else {
+            METRICS.balloon.irq_moderation_held.inc();
             Ok(())
         }
     }
@@ -507,6 +723,7 @@ impl Balloon {
                     .add_used(mem, prev_stats_desc, 0)
                     .map_err(BalloonError::Queue)?;
             }
+            let previous_stats = self.latest_stats.clone();
             for index in (0..head.len).step_by(SIZE_OF_STAT) {
                 // Read the address at position `index`. The only case
                 // in which this fails is if there is overflow,
@@ -519,10 +736,20 @@ impl Balloon {
                 let stat = mem
                     .read_obj::<BalloonStat>(addr)
                     .map_err(|_| BalloonError::MalformedDescriptor)?;
-                self.latest_stats.update_with_stat(&stat).map_err(|_| {
-                    METRICS.balloon.stats_update_fails.inc();
-                    BalloonError::MalformedPayload
-                })?;
+                match self.latest_stats.update_with_stat(&stat) {
+                    Ok(()) => {}
+                    // Newer guest drivers report statistics unknown to the device.
+                    Err(_) if !self.strict_stats => METRICS.balloon.stats_unknown_tags.inc(),
+                    Err(_) => {
+                        METRICS.balloon.stats_update_fails.inc();
+                        return Err(BalloonError::MalformedPayload);
+                    }
+                }
+            }
+            let now = self.clock.now();
+            if let Some(last_sample) = self.last_stats_sample.replace(now) {
+                self.latest_stats
+                    .update_deltas(&previous_stats, now.duration_since(last_sample));
             }
 
             self.stats_desc_index = Some(head.index);
@@ -570,10 +797,16 @@ impl Balloon {
         if self.is_activated() {
             // 这个指令非常的关键，vmm通过配置空间，向guest传达，我希望将气球调节至多大，因此需要写入config_space.num_pages
             // guest会读取此数值，然后根据当前气球的大小进行调整，并将最终的实际调节结果写入到config_space.actul_pages
-            self.config_space.num_pages = mib_to_pages(amount_mib)?;
+            let num_pages = mib_to_pages(amount_mib)?;
+            let changed = self.config_space.num_pages != num_pages;
+            self.config_space.num_pages = num_pages;
             self.irq_trigger
                 .trigger_irq(IrqType::Config)
-                .map_err(BalloonError::InterruptError)
+                .map_err(BalloonError::InterruptError)?;
+            if changed {
+                self.config_epoch += 1;
+            }
+            Ok(())
         } else {
             Err(BalloonError::DeviceNotActive)
         }
@@ -593,6 +826,7 @@ impl Balloon {
 
         self.stats_polling_interval_s = interval_s;
         self.update_timer_state();
+        self.config_epoch += 1;
         Ok(())
     }
 
@@ -609,6 +843,20 @@ impl Balloon {
         self.config_space.num_pages
     }
 
+    /// Dumps the config space as the guest sees it.
+    pub fn config_space_info(&self) -> BalloonConfigSpace {
+        BalloonConfigSpace {
+            raw: self
+                .config_space
+                .as_slice()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            num_pages: self.config_space.num_pages,
+            actual_pages: self.config_space.actual_pages,
+        }
+    }
+
     pub fn size_mb(&self) -> u32 {
         pages_to_mib(self.config_space.num_pages)
     }
@@ -621,6 +869,139 @@ impl Balloon {
         self.stats_polling_interval_s
     }
 
+    pub fn deflate_prefetch(&self) -> BalloonDeflatePrefetch {
+        self.deflate_prefetch
+    }
+
+    pub fn strict_stats(&self) -> bool {
+        self.strict_stats
+    }
+
+    pub fn config_epoch(&self) -> u64 {
+        self.config_epoch
+    }
+
+    pub fn interrupt_moderation(&self) -> bool {
+        self.irq_moderator.enabled()
+    }
+
+    /// Enables or disables the moderation of the deflate notifications.
+    pub fn set_interrupt_moderation(&mut self, enabled: bool) {
+        self.irq_moderator.set_enabled(enabled);
+    }
+
+    /// Runs the statistics timer, the interrupt moderation and the statistics deltas on `clock`.
+    /// Must be called before the device is activated, the statistics timer keeps its setting.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> Result<(), BalloonError> {
+        let mut stats_timer = clock.timer().map_err(BalloonError::Timer)?;
+        stats_timer.set_state(self.stats_timer.get_state(), SetTimeFlags::Default);
+        self.irq_moderator = InterruptModerator::new(self.irq_moderator.enabled(), clock.as_ref())
+            .map_err(BalloonError::Timer)?;
+        self.stats_timer = stats_timer;
+        self.clock = clock;
+        Ok(())
+    }
+
+    pub fn min_guest_mib(&self) -> u32 {
+        self.min_guest_mib
+    }
+
+    /// Returns the largest balloon target, not above `amount_mib`, that leaves at least
+    /// `min_guest_mib` of the `mem_size_mib` of guest memory to the guest.
+    pub fn floor_clamped_size(&self, amount_mib: u32, mem_size_mib: u64) -> u32 {
+        let ceiling = mem_size_mib.saturating_sub(u64::from(self.min_guest_mib));
+        u32::try_from(ceiling).map_or(amount_mib, |ceiling| amount_mib.min(ceiling))
+    }
+
+    /// Cross-checks the config space and the statistics reported by the guest against the
+    /// `mem_size_mib` MiB of guest memory. Returns the failed checks, as `(check, detail)` pairs.
+    pub fn verify(&self, mem_size_mib: u64) -> Vec<(&'static str, String)> {
+        let mut inconsistencies = Vec::new();
+        let mem_pages = mem_size_mib * u64::from(MIB_TO_4K_PAGES);
+
+        let target_pages = u64::from(self.config_space.num_pages);
+        let floor_pages = u64::from(self.min_guest_mib) * u64::from(MIB_TO_4K_PAGES);
+        if target_pages + floor_pages > mem_pages {
+            inconsistencies.push((
+                "target_within_guest_memory",
+                format!(
+                    "target of {} pages with a floor of {} MiB over {} MiB of guest memory",
+                    target_pages, self.min_guest_mib, mem_size_mib
+                ),
+            ));
+        }
+
+        let actual_pages = u64::from(self.config_space.actual_pages);
+        if actual_pages > mem_pages {
+            inconsistencies.push((
+                "actual_within_guest_memory",
+                format!(
+                    "the guest reports {} pages inflated over {} MiB of guest memory",
+                    actual_pages, mem_size_mib
+                ),
+            ));
+        }
+
+        if let Some(total_memory) = self.latest_stats.total_memory {
+            if total_memory > mem_size_mib << 20 {
+                inconsistencies.push((
+                    "guest_total_within_guest_memory",
+                    format!(
+                        "the guest reports {} bytes of total memory over {} MiB of guest memory",
+                        total_memory, mem_size_mib
+                    ),
+                ));
+            }
+        }
+
+        inconsistencies
+    }
+
+    /// Stops or restarts the processing of the device queues. The requests queued by the
+    /// guest while the device was quiesced are processed when it is resumed.
+    pub fn set_quiesced(&mut self, quiesced: bool) {
+        let resumed = self.quiesced && !quiesced;
+        self.quiesced = quiesced;
+        if resumed {
+            self.process_deferred_events();
+        }
+    }
+
+    /// Shares the pause state of the microVM with the device.
+    pub fn set_pause_gate(&mut self, pause_gate: VmPauseGate) {
+        self.pause_gate = pause_gate;
+    }
+
+    /// Processes the requests left in the queues while the device was quiesced or the microVM
+    /// paused, unless it still is.
+    pub fn process_deferred_events(&mut self) {
+        if self.quiesced || self.pause_gate.is_paused() || !self.is_activated() {
+            return;
+        }
+        self.process_virtio_queues();
+        if self.stats_enabled() {
+            let _ = self.process_stats_queue();
+        }
+    }
+
+    // Whether the queue events are left unprocessed, counting those deferred because the
+    // microVM is paused.
+    fn events_deferred(&self) -> bool {
+        if self.pause_gate.is_paused() {
+            METRICS.balloon.paused_deferred_events.inc();
+            return true;
+        }
+        self.quiesced
+    }
+
+    pub fn is_quiesced(&self) -> bool {
+        self.quiesced
+    }
+
+    pub fn mmap_overlays(&self) -> &MmapOverlays {
+        &self.mmap_overlays
+    }
+
     pub fn latest_stats(&mut self) -> Option<&BalloonStats> {
         if self.stats_enabled() {
             self.latest_stats.target_pages = self.config_space.num_pages;
@@ -638,6 +1019,11 @@ impl Balloon {
             amount_mib: self.size_mb(),
             deflate_on_oom: self.deflate_on_oom(),
             stats_polling_interval_s: self.stats_polling_interval_s(),
+            deflate_prefetch: self.deflate_prefetch(),
+            strict_stats: self.strict_stats(),
+            interrupt_moderation: self.interrupt_moderation(),
+            min_guest_mib: self.min_guest_mib(),
+            config_epoch: self.config_epoch(),
         }
     }
 
@@ -740,6 +1126,7 @@ impl VirtioDevice for Balloon {
 pub(crate) mod tests {
     use std::u32;
 
+    use utils::clock::ManualClock;
     use utils::vm_memory::GuestAddress;
 
     use super::super::CONFIG_SPACE_SIZE;
@@ -793,6 +1180,13 @@ pub(crate) mod tests {
             disk_caches: Some(0),
             hugetlb_allocations: Some(0),
             hugetlb_failures: Some(0),
+            sample_interval_ms: None,
+            swap_in_delta: None,
+            swap_out_delta: None,
+            major_faults_delta: None,
+            minor_faults_delta: None,
+            deflate_prefetch_us: None,
+            deflate_prefetch_pages: None,
         };
 
         let mut stat = BalloonStat {
@@ -829,6 +1223,22 @@ pub(crate) mod tests {
         stat.tag = VIRTIO_BALLOON_S_HTLB_PGFAIL;
         stats.update_with_stat(&stat).unwrap();
         assert_eq!(stats.hugetlb_failures, Some(1));
+
+        // The cumulative counters report their growth since the previous sample.
+        let previous = stats.clone();
+        stat.tag = VIRTIO_BALLOON_S_MAJFLT;
+        stat.val = 201;
+        stats.update_with_stat(&stat).unwrap();
+        stats.update_deltas(&previous, Duration::from_secs(2));
+        assert_eq!(stats.sample_interval_ms, Some(2000));
+        assert_eq!(
+            stats.major_faults_delta,
+            Some(CounterDelta {
+                delta: 200,
+                per_second: 100
+            })
+        );
+        assert_eq!(stats.swap_in_delta, Some(CounterDelta::default()));
     }
 
     #[test]
@@ -836,7 +1246,16 @@ pub(crate) mod tests {
         // Test all feature combinations.
         for deflate_on_oom in vec![true, false].iter() {
             for stats_interval in vec![0, 1].iter() {
-                let mut balloon = Balloon::new(0, *deflate_on_oom, *stats_interval, false).unwrap();
+                let mut balloon = Balloon::new(
+                    0,
+                    *deflate_on_oom,
+                    *stats_interval,
+                    false,
+                    BalloonDeflatePrefetch::None,
+                    false,
+                    0,
+                )
+                .unwrap();
                 assert_eq!(balloon.device_type(), TYPE_BALLOON);
 
                 let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
@@ -860,12 +1279,18 @@ pub(crate) mod tests {
 
     #[test]
     fn test_virtio_read_config() {
-        let balloon = Balloon::new(0x10, true, 0, false).unwrap();
+        let balloon =
+            Balloon::new(0x10, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
 
         let cfg = BalloonConfig {
             amount_mib: 16,
             deflate_on_oom: true,
             stats_polling_interval_s: 0,
+            deflate_prefetch: BalloonDeflatePrefetch::None,
+            strict_stats: false,
+            interrupt_moderation: false,
+            min_guest_mib: 0,
+            config_epoch: 0,
         };
         assert_eq!(balloon.config(), cfg);
 
@@ -891,7 +1316,8 @@ pub(crate) mod tests {
 
     #[test]
     fn test_virtio_write_config() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
 
         let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
             [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
@@ -911,7 +1337,8 @@ pub(crate) mod tests {
 
     #[test]
     fn test_invalid_request() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
         let mem = default_mem();
         // Only initialize the inflate queue to demonstrate invalid request handling.
         let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
@@ -970,7 +1397,8 @@ pub(crate) mod tests {
 
     #[test]
     fn test_inflate() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
         let mem = default_mem();
         let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(INFLATE_INDEX, infq.create_queue());
@@ -1026,9 +1454,46 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_inflate_malformed_descriptor() {
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
+        let mem = default_mem();
+        let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        balloon.set_queue(INFLATE_INDEX, infq.create_queue());
+        balloon.activate(mem.clone()).unwrap();
+
+        for i in 0..0x1000 {
+            mem.write_obj::<u8>(1, GuestAddress((1 << 12) + i)).unwrap();
+        }
+
+        // The second descriptor of the batch runs past the end of the guest memory.
+        mem.write_obj::<u32>(0x1, GuestAddress(0x10)).unwrap();
+        mem.write_obj::<u32>(0x2, GuestAddress(0x20)).unwrap();
+        set_request(&infq, 0, 0x10, SIZE_OF_U32 as u32, VIRTQ_DESC_F_NEXT);
+        set_request(&infq, 1, 0xfffc, 2 * SIZE_OF_U32 as u32, VIRTQ_DESC_F_NEXT);
+        set_request(&infq, 2, 0x20, SIZE_OF_U32 as u32, VIRTQ_DESC_F_NEXT);
+
+        assert!(matches!(
+            balloon.process_inflate_queue(),
+            Err(BalloonError::MalformedDescriptor)
+        ));
+        // The malformed descriptor is completed along with the one before it, whose page is
+        // removed.
+        assert_eq!(infq.used.idx.get(), 2);
+        assert_eq!(infq.used.ring[1].get().id, 1);
+        assert!(balloon.irq_trigger.has_pending_irq(IrqType::Vring));
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(1 << 12)).unwrap(), 0);
+
+        // The descriptor after it was given back.
+        balloon.process_inflate_queue().unwrap();
+        check_request_completion(&infq, 2);
+    }
+
     #[test]
     fn test_deflate() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
         let mem = default_mem();
         let defq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(DEFLATE_INDEX, defq.create_queue());
@@ -1062,9 +1527,168 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_deflate_prefetch() {
+        let mut balloon = Balloon::new(
+            0,
+            true,
+            0,
+            false,
+            BalloonDeflatePrefetch::WillNeed,
+            false,
+            0,
+        )
+        .unwrap();
+        let mem = default_mem();
+        let defq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        balloon.set_queue(DEFLATE_INDEX, defq.create_queue());
+        balloon.activate(mem.clone()).unwrap();
+        assert_eq!(balloon.config().deflate_prefetch, BalloonDeflatePrefetch::WillNeed);
+
+        let page_addr = 0x10;
+        // Deflate the pages starting at pfn 0x20.
+        mem.write_obj::<u32>(0x20, GuestAddress(page_addr)).unwrap();
+        mem.write_obj::<u32>(0x21, GuestAddress(page_addr + SIZE_OF_U32 as u64))
+            .unwrap();
+
+        set_request(
+            &defq,
+            0,
+            page_addr,
+            2 * SIZE_OF_U32 as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        check_metric_after_block!(
+            METRICS.balloon.deflate_prefetch_fails,
+            0,
+            invoke_handler_for_queue_event(&mut balloon, DEFLATE_INDEX)
+        );
+        check_request_completion(&defq, 0);
+        assert_eq!(balloon.latest_stats.deflate_prefetch_pages, Some(2));
+        assert!(balloon.latest_stats.deflate_prefetch_us.is_some());
+
+        // Write-only descriptors are acknowledged without being prefetched.
+        balloon.latest_stats.deflate_prefetch_pages = None;
+        set_request(&defq, 1, page_addr, SIZE_OF_U32 as u32, VIRTQ_DESC_F_WRITE);
+        invoke_handler_for_queue_event(&mut balloon, DEFLATE_INDEX);
+        check_request_completion(&defq, 1);
+        assert_eq!(balloon.latest_stats.deflate_prefetch_pages, None);
+    }
+
+    #[test]
+    fn test_deflate_interrupt_moderation() {
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
+        let mem = default_mem();
+        let defq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        balloon.set_queue(DEFLATE_INDEX, defq.create_queue());
+        balloon.activate(mem.clone()).unwrap();
+        balloon.set_interrupt_moderation(true);
+        assert!(balloon.config().interrupt_moderation);
+
+        let page_addr = 0x10;
+        // Nothing is known of the guest yet, the first deflation is notified right away.
+        set_request(&defq, 0, page_addr, SIZE_OF_U32 as u32, VIRTQ_DESC_F_NEXT);
+        invoke_handler_for_queue_event(&mut balloon, DEFLATE_INDEX);
+        check_request_completion(&defq, 0);
+
+        // The guest streams its requests, the notification of the next one is held back.
+        set_request(&defq, 1, page_addr, SIZE_OF_U32 as u32, VIRTQ_DESC_F_NEXT);
+        balloon.queue_evts[DEFLATE_INDEX].write(1).unwrap();
+        check_metric_after_block!(
+            METRICS.balloon.irq_moderation_held,
+            1,
+            balloon.process_deflate_queue_event().unwrap()
+        );
+        check_request_completion(&defq, 1);
+        assert!(!balloon.irq_trigger.has_pending_irq(IrqType::Vring));
+
+        // The timer raises the held notification.
+        check_metric_after_block!(
+            METRICS.balloon.irq_moderation_timeouts,
+            1,
+            balloon.process_irq_moderation_event().unwrap()
+        );
+        assert!(balloon.irq_trigger.has_pending_irq(IrqType::Vring));
+        assert!(!balloon.irq_moderator.held());
+    }
+
+    #[test]
+    fn test_quiesce() {
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
+        let mem = default_mem();
+        let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        balloon.set_queue(INFLATE_INDEX, infq.create_queue());
+        balloon.activate(mem.clone()).unwrap();
+
+        let page_addr = 0x10;
+        mem.write_obj::<u32>(0x1, GuestAddress(page_addr)).unwrap();
+        set_request(&infq, 0, page_addr, SIZE_OF_U32 as u32, VIRTQ_DESC_F_NEXT);
+
+        // The request is left in the queue while the device is quiesced.
+        balloon.set_quiesced(true);
+        assert!(balloon.is_quiesced());
+        balloon.queue_evts[INFLATE_INDEX].write(1).unwrap();
+        check_metric_after_block!(
+            METRICS.balloon.inflate_count,
+            0,
+            balloon.process_inflate_queue_event().unwrap()
+        );
+        assert_eq!(infq.used.idx.get(), 0);
+
+        // And processed once the device is resumed.
+        check_metric_after_block!(
+            METRICS.balloon.inflate_count,
+            1,
+            balloon.set_quiesced(false)
+        );
+        assert!(!balloon.is_quiesced());
+        check_request_completion(&infq, 0);
+    }
+
+    #[test]
+    fn test_pause_gate() {
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
+        let mem = default_mem();
+        let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        balloon.set_queue(INFLATE_INDEX, infq.create_queue());
+        balloon.activate(mem.clone()).unwrap();
+        let pause_gate = VmPauseGate::default();
+        balloon.set_pause_gate(pause_gate.clone());
+
+        let page_addr = 0x10;
+        mem.write_obj::<u32>(0x1, GuestAddress(page_addr)).unwrap();
+        set_request(&infq, 0, page_addr, SIZE_OF_U32 as u32, VIRTQ_DESC_F_NEXT);
+
+        // The request is deferred while the microVM is paused.
+        pause_gate.set_paused(true);
+        balloon.queue_evts[INFLATE_INDEX].write(1).unwrap();
+        check_metric_after_block!(
+            METRICS.balloon.paused_deferred_events,
+            1,
+            balloon.process_inflate_queue_event().unwrap()
+        );
+        balloon.process_deferred_events();
+        assert_eq!(infq.used.idx.get(), 0);
+
+        // And processed once it is resumed.
+        pause_gate.set_paused(false);
+        check_metric_after_block!(
+            METRICS.balloon.inflate_count,
+            1,
+            balloon.process_deferred_events()
+        );
+        check_request_completion(&infq, 0);
+    }
+
     #[test]
     fn test_stats() {
-        let mut balloon = Balloon::new(0, true, 1, false).unwrap();
+        let mut balloon =
+            Balloon::new(0, true, 1, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
+        let clock = ManualClock::new();
+        balloon.set_clock(Arc::new(clock.clone())).unwrap();
         let mem = default_mem();
         let statsq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(STATS_INDEX, statsq.create_queue());
@@ -1128,10 +1752,10 @@ pub(crate) mod tests {
             };
             assert_eq!(stats, &expected_stats);
 
-            // Wait for the timer to expire, although as it is non-blocking
-            // we could just process the timer event and it would not
-            // return an error.
-            std::thread::sleep(Duration::from_secs(1));
+            // The timer expires once the polling interval elapsed.
+            clock.advance(Duration::from_millis(999));
+            assert_eq!(balloon.stats_timer.read(), 0);
+            clock.advance(Duration::from_millis(1));
             check_metric_after_block!(METRICS.balloon.event_fails, 0, {
                 // Trigger the timer event, which consumes the stats
                 // descriptor index and signals the used queue.
@@ -1143,9 +1767,83 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_stats_unknown_tags() {
+        let mem = default_mem();
+        let page_addr = 0x100;
+        let stats = [
+            BalloonStat {
+                tag: VIRTIO_BALLOON_S_SWAP_OUT,
+                val: 0x1,
+            },
+            // Tag introduced by a guest driver newer than the device.
+            BalloonStat {
+                tag: 0x42,
+                val: 0x2,
+            },
+            BalloonStat {
+                tag: VIRTIO_BALLOON_S_MEMFREE,
+                val: 0x5678,
+            },
+        ];
+        for (i, stat) in stats.iter().enumerate() {
+            mem.write_obj::<BalloonStat>(
+                *stat,
+                GuestAddress(page_addr + (i * SIZE_OF_STAT) as u64),
+            )
+            .unwrap();
+        }
+
+        // The unknown statistic is skipped by default.
+        let mut balloon =
+            Balloon::new(0, true, 1, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
+        let statsq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        balloon.set_queue(STATS_INDEX, statsq.create_queue());
+        balloon.activate(mem.clone()).unwrap();
+        set_request(
+            &statsq,
+            0,
+            page_addr,
+            3 * SIZE_OF_STAT as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        check_metric_after_block!(METRICS.balloon.stats_unknown_tags, 1, {
+            balloon.process_stats_queue().unwrap();
+        });
+        let expected_stats = BalloonStats {
+            swap_out: Some(0x1),
+            free_memory: Some(0x5678),
+            ..BalloonStats::default()
+        };
+        assert_eq!(balloon.latest_stats().unwrap(), &expected_stats);
+        assert!(balloon.stats_desc_index.is_some());
+
+        // The strict device rejects the descriptor.
+        let mut balloon =
+            Balloon::new(0, true, 1, false, BalloonDeflatePrefetch::None, true, 0).unwrap();
+        let statsq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        balloon.set_queue(STATS_INDEX, statsq.create_queue());
+        balloon.activate(mem.clone()).unwrap();
+        set_request(
+            &statsq,
+            0,
+            page_addr,
+            3 * SIZE_OF_STAT as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        check_metric_after_block!(METRICS.balloon.stats_update_fails, 1, {
+            assert!(matches!(
+                balloon.process_stats_queue(),
+                Err(BalloonError::MalformedPayload)
+            ));
+        });
+        assert_eq!(balloon.latest_stats().unwrap().free_memory, None);
+    }
+
     #[test]
     fn test_process_balloon_queues() {
-        let mut balloon = Balloon::new(0x10, true, 0, false).unwrap();
+        let mut balloon =
+            Balloon::new(0x10, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
         let mem = default_mem();
         balloon.activate(mem).unwrap();
         balloon.process_virtio_queues()
@@ -1153,7 +1851,8 @@ pub(crate) mod tests {
 
     #[test]
     fn test_update_stats_interval() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
         let mem = default_mem();
         balloon.activate(mem).unwrap();
         assert_eq!(
@@ -1162,7 +1861,8 @@ pub(crate) mod tests {
         );
         assert!(balloon.update_stats_polling_interval(0).is_ok());
 
-        let mut balloon = Balloon::new(0, true, 1, false).unwrap();
+        let mut balloon =
+            Balloon::new(0, true, 1, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
         let mem = default_mem();
         balloon.activate(mem).unwrap();
         assert_eq!(
@@ -1171,13 +1871,60 @@ pub(crate) mod tests {
         );
         assert!(balloon.update_stats_polling_interval(1).is_ok());
         assert!(balloon.update_stats_polling_interval(2).is_ok());
+        // Only the successful updates changing the interval bump the configuration epoch.
+        assert_eq!(balloon.config_epoch(), 1);
+    }
+
+    #[test]
+    fn test_floor_clamped_size() {
+        let balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
+        assert_eq!(balloon.floor_clamped_size(128, 128), 128);
+
+        let balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 96).unwrap();
+        assert_eq!(balloon.config().min_guest_mib, 96);
+        assert_eq!(balloon.floor_clamped_size(16, 128), 16);
+        assert_eq!(balloon.floor_clamped_size(32, 128), 32);
+        assert_eq!(balloon.floor_clamped_size(100, 128), 32);
+        // A floor above the guest memory size leaves no room for the balloon.
+        assert_eq!(balloon.floor_clamped_size(64, 64), 0);
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut balloon =
+            Balloon::new(16, true, 0, false, BalloonDeflatePrefetch::None, false, 96).unwrap();
+        assert!(balloon.verify(128).is_empty());
+
+        // A target eating into the floor.
+        let checks = |balloon: &Balloon, mem_size_mib| -> Vec<&str> {
+            balloon
+                .verify(mem_size_mib)
+                .into_iter()
+                .map(|(check, _)| check)
+                .collect()
+        };
+        assert_eq!(checks(&balloon, 64), vec!["target_within_guest_memory"]);
+
+        balloon.config_space.actual_pages = 200 * MIB_TO_4K_PAGES;
+        balloon.latest_stats.total_memory = Some(256 << 20);
+        assert_eq!(
+            checks(&balloon, 128),
+            vec![
+                "actual_within_guest_memory",
+                "guest_total_within_guest_memory"
+            ]
+        );
     }
 
     #[test]
     fn test_num_pages() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon =
+            Balloon::new(0, true, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
         // Assert that we can't update an inactive device.
         assert!(balloon.update_size(1).is_err());
+        assert_eq!(balloon.config_epoch(), 0);
         // Switch the state to active.
         balloon.device_state = DeviceState::Activated(
             utils::vm_memory::test_utils::create_guest_memory_unguarded(
@@ -1195,6 +1942,10 @@ pub(crate) mod tests {
         balloon.update_num_pages(0x100);
         assert_eq!(balloon.num_pages(), 0x100);
         assert!(balloon.update_size(16).is_ok());
+        assert_eq!(balloon.config_epoch(), 1);
+        // Asking for the size already requested leaves the epoch alone.
+        assert!(balloon.update_size(16).is_ok());
+        assert_eq!(balloon.config_epoch(), 1);
 
         let mut actual_config = vec![0; CONFIG_SPACE_SIZE];
         balloon.read_config(0, &mut actual_config);
@@ -1208,5 +1959,10 @@ pub(crate) mod tests {
         balloon.write_config(0, &expected_config);
         assert_eq!(balloon.num_pages(), 0x1122_3344);
         assert_eq!(balloon.actual_pages(), 0x1234_5678);
+
+        let config_space = balloon.config_space_info();
+        assert_eq!(config_space.raw, "4433221178563412");
+        assert_eq!(config_space.num_pages, 0x1122_3344);
+        assert_eq!(config_space.actual_pages, 0x1234_5678);
     }
 }
@@ -19,11 +19,16 @@ impl Balloon {
         if let Err(err) = ops.add(Events::new(&self.queue_evts[DEFLATE_INDEX], EventSet::IN)) {
             error!("Failed to register deflate queue event: {}", err);
         }
+        // The moderation can be enabled after activation, its timer is registered even while it
+        // is disabled.
+        if let Err(err) = ops.add(Events::new(&self.irq_moderator, EventSet::IN)) {
+            error!("Failed to register moderation timerfd event: {}", err);
+        }
         if self.stats_enabled() {
             if let Err(err) = ops.add(Events::new(&self.queue_evts[STATS_INDEX], EventSet::IN)) {
                 error!("Failed to register stats queue event: {}", err);
             }
-            if let Err(err) = ops.add(Events::new(&self.stats_timer, EventSet::IN)) {
+            if let Err(err) = ops.add(Events::new_raw(self.stats_timer.as_raw_fd(), EventSet::IN)) {
                 error!("Failed to register stats timerfd event: {}", err);
             }
         }
@@ -66,6 +71,7 @@ impl MutEventSubscriber for Balloon {
             let virtq_deflate_ev_fd = self.queue_evts[DEFLATE_INDEX].as_raw_fd();
             let virtq_stats_ev_fd = self.queue_evts[STATS_INDEX].as_raw_fd();
             let stats_timer_fd = self.stats_timer.as_raw_fd();
+            let irq_moderation_fd = self.irq_moderator.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
 
             // Looks better than C style if/else if/else.
@@ -82,6 +88,9 @@ impl MutEventSubscriber for Balloon {
                 _ if source == stats_timer_fd => self
                     .process_stats_timer_event()
                     .unwrap_or_else(report_balloon_event_fail),
+                _ if source == irq_moderation_fd => self
+                    .process_irq_moderation_event()
+                    .unwrap_or_else(report_balloon_event_fail),
                 _ if activate_fd == source => self.process_activate_event(ops),
                 _ => {
                     warn!("Balloon: Spurious event received: {:?}", source);
@@ -117,12 +126,14 @@ pub mod tests {
 
     use super::*;
     use crate::devices::virtio::balloon::test_utils::set_request;
+    use crate::devices::virtio::balloon::BalloonDeflatePrefetch;
     use crate::devices::virtio::test_utils::{default_mem, VirtQueue};
 
     #[test]
     fn test_event_handler() {
         let mut event_manager = EventManager::new().unwrap();
-        let mut balloon = Balloon::new(0, true, 10, false).unwrap();
+        let mut balloon =
+            Balloon::new(0, true, 10, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
         let mem = default_mem();
         let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(INFLATE_INDEX, infq.create_queue());
@@ -1,15 +1,27 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+// Only the snapshot state is built without the device, which leaves the
+// feature bits and statistics tags unused.
+#![cfg_attr(not(feature = "balloon"), allow(dead_code))]
+
+#[cfg(feature = "balloon")]
 pub mod device;
+#[cfg(feature = "balloon")]
 pub mod event_handler;
 pub mod persist;
+#[cfg(feature = "balloon")]
 pub mod test_utils;
+#[cfg(feature = "balloon")]
 mod util;
 
 use utils::vm_memory::GuestMemoryError;
 
-pub use self::device::{Balloon, BalloonConfig, BalloonStats};
+#[cfg(feature = "balloon")]
+pub use self::device::{
+    Balloon, BalloonConfig, BalloonConfigSpace, BalloonDeflatePrefetch, BalloonStats,
+};
+#[cfg(feature = "balloon")]
 pub use self::event_handler::*;
 
 /// Device ID used in MMIO device identification.
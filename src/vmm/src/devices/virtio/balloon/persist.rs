@@ -3,19 +3,27 @@
 
 //! Defines the structures needed for saving/restoring balloon devices.
 
+#[cfg(feature = "balloon")]
 use std::sync::atomic::AtomicUsize;
+#[cfg(feature = "balloon")]
 use std::sync::Arc;
+#[cfg(feature = "balloon")]
 use std::time::Duration;
 
+#[cfg(feature = "balloon")]
 use snapshot::Persist;
+#[cfg(feature = "balloon")]
 use timerfd::{SetTimeFlags, TimerState};
 use utils::vm_memory::GuestMemoryMmap;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 
+#[cfg(feature = "balloon")]
 use super::*;
+#[cfg(feature = "balloon")]
 use crate::devices::virtio::balloon::device::{BalloonStats, ConfigSpace};
 use crate::devices::virtio::persist::VirtioDeviceState;
+#[cfg(feature = "balloon")]
 use crate::devices::virtio::{DeviceState, TYPE_BALLOON};
 
 #[derive(Clone, Versionize)]
@@ -40,6 +48,7 @@ pub struct BalloonStatsState {
     hugetlb_failures: Option<u64>,
 }
 
+#[cfg(feature = "balloon")]
 impl BalloonStatsState {
     fn from_stats(stats: &BalloonStats) -> Self {
         Self {
@@ -72,6 +81,13 @@ impl BalloonStatsState {
             disk_caches: self.disk_caches,
             hugetlb_allocations: self.hugetlb_allocations,
             hugetlb_failures: self.hugetlb_failures,
+            sample_interval_ms: None,
+            swap_in_delta: None,
+            swap_out_delta: None,
+            major_faults_delta: None,
+            minor_faults_delta: None,
+            deflate_prefetch_us: None,
+            deflate_prefetch_pages: None,
         }
     }
 }
@@ -84,12 +100,15 @@ pub struct BalloonState {
     latest_stats: BalloonStatsState,
     config_space: BalloonConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    #[version(start = 2)]
+    config_epoch: u64,
 }
 
 pub struct BalloonConstructorArgs {
     pub mem: GuestMemoryMmap,
 }
 
+#[cfg(feature = "balloon")]
 impl Persist<'_> for Balloon {
     type State = BalloonState;
     type ConstructorArgs = BalloonConstructorArgs;
@@ -105,6 +124,7 @@ impl Persist<'_> for Balloon {
                 actual_pages: self.config_space.actual_pages,
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            config_epoch: self.config_epoch,
         }
     }
 
@@ -113,8 +133,18 @@ impl Persist<'_> for Balloon {
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
         // We can safely create the balloon with arbitrary flags and
-        // num_pages because we will overwrite them after.
-        let mut balloon = Balloon::new(0, false, state.stats_polling_interval_s, true)?;
+        // num_pages because we will overwrite them after. The deflate prefetch
+        // policy, the statistics strictness and the guest memory floor are not
+        // part of the snapshot, so they fall back to the defaults.
+        let mut balloon = Balloon::new(
+            0,
+            false,
+            state.stats_polling_interval_s,
+            true,
+            BalloonDeflatePrefetch::default(),
+            false,
+            0,
+        )?;
 
         let mut num_queues = NUM_QUEUES;
         // As per the virtio 1.1 specification, the statistics queue
@@ -135,6 +165,7 @@ impl Persist<'_> for Balloon {
             num_pages: state.config_space.num_pages,
             actual_pages: state.config_space.actual_pages,
         };
+        balloon.config_epoch = state.config_epoch;
 
         if state.virtio_state.activated {
             balloon.device_state = DeviceState::Activated(constructor_args.mem);
@@ -158,7 +189,7 @@ impl Persist<'_> for Balloon {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "balloon"))]
 mod tests {
     use std::sync::atomic::Ordering;
 
@@ -171,19 +202,32 @@ mod tests {
     fn test_persistence() {
         let guest_mem = default_mem();
         let mut mem = vec![0; 4096];
-        let version_map = VersionMap::new();
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BalloonState::type_id(), 2);
 
         // Create and save the balloon device.
-        let balloon = Balloon::new(0x42, false, 2, false).unwrap();
+        let mut balloon = Balloon::new(
+            0x42,
+            false,
+            2,
+            false,
+            BalloonDeflatePrefetch::None,
+            false,
+            0,
+        )
+        .unwrap();
+        balloon.config_epoch = 3;
 
         <Balloon as Persist>::save(&balloon)
-            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
             .unwrap();
 
         // Deserialize and restore the balloon device.
         let restored_balloon = Balloon::restore(
             BalloonConstructorArgs { mem: guest_mem },
-            &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+            &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
         )
         .unwrap();
 
@@ -206,5 +250,6 @@ mod tests {
         );
         assert_eq!(restored_balloon.stats_desc_index, balloon.stats_desc_index);
         assert_eq!(restored_balloon.latest_stats, balloon.latest_stats);
+        assert_eq!(restored_balloon.config_epoch(), 3);
     }
 }
@@ -3,10 +3,11 @@
 
 use std::io;
 
-use logger::error;
+use logger::{error, METRICS};
 use utils::vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 use super::{RemoveRegionError, MAX_PAGE_COMPACT_BUFFER};
+use crate::devices::virtio::mem_overlay::MmapOverlays;
 
 /// This takes a vector of page frame numbers, and compacts them
 /// into ranges of consecutive pages. The result is a vector
@@ -65,10 +66,24 @@ pub(crate) fn compact_page_frame_numbers(v: &mut [u32]) -> Vec<(u32, u32)> {
     result
 }
 
+// Builds the error of a failed `madvise`, counting its errno.
+fn madvise_fail() -> RemoveRegionError {
+    let err = io::Error::last_os_error();
+    METRICS.balloon.madvise_fails.record(&err);
+    RemoveRegionError::MadviseFail(err)
+}
+
+// Builds the error of a failed `mmap`, counting its errno.
+fn mmap_fail() -> RemoveRegionError {
+    let err = io::Error::last_os_error();
+    METRICS.balloon.mmap_fails.record(&err);
+    RemoveRegionError::MmapFail(err)
+}
+
 pub(crate) fn remove_range(
     guest_memory: &GuestMemoryMmap,
     range: (GuestAddress, u64),
-    restored: bool,
+    overlays: Option<&mut MmapOverlays>,
 ) -> std::result::Result<(), RemoveRegionError> {
     let (guest_address, range_len) = range;
 
@@ -83,7 +98,7 @@ pub(crate) fn remove_range(
         // Mmap a new anonymous region over the present one in order to create a hole.
         // This workaround is (only) needed after resuming from a snapshot because the guest memory
         // is mmaped from file as private and there is no `madvise` flag that works for this case.
-        if restored {
+        if let Some(overlays) = overlays {
             // SAFETY: The address and length are known to be valid.
             let ret = unsafe {
                 libc::mmap(
@@ -96,8 +111,9 @@ pub(crate) fn remove_range(
                 )
             };
             if ret == libc::MAP_FAILED {
-                return Err(RemoveRegionError::MmapFail(io::Error::last_os_error()));
+                return Err(mmap_fail());
             }
+            overlays.insert(guest_address, range_len);
         };
 
         // Madvise the region in order to mark it as not used.
@@ -107,7 +123,36 @@ pub(crate) fn remove_range(
             libc::madvise(phys_address.cast(), range_len, libc::MADV_DONTNEED)
         };
         if ret < 0 {
-            return Err(RemoveRegionError::MadviseFail(io::Error::last_os_error()));
+            return Err(madvise_fail());
+        }
+
+        Ok(())
+    } else {
+        Err(RemoveRegionError::RegionNotFound)
+    }
+}
+
+/// Applies `advice` to a range of guest memory that the guest has just taken back,
+/// so that the host can fault the pages in ahead of the guest touching them.
+pub(crate) fn prefetch_range(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+    advice: libc::c_int,
+) -> std::result::Result<(), RemoveRegionError> {
+    let (guest_address, range_len) = range;
+
+    if let Some(region) = guest_memory.find_region(guest_address) {
+        if guest_address.0 + range_len > region.start_addr().0 + region.len() {
+            return Err(RemoveRegionError::MalformedRange);
+        }
+        let phys_address = guest_memory
+            .get_host_address(guest_address)
+            .map_err(|_| RemoveRegionError::AddressTranslation)?;
+
+        // SAFETY: The address and length are known to be valid.
+        let ret = unsafe { libc::madvise(phys_address.cast(), range_len as usize, advice) };
+        if ret < 0 {
+            return Err(madvise_fail());
         }
 
         Ok(())
@@ -118,6 +163,7 @@ pub(crate) fn remove_range(
 
 #[cfg(test)]
 mod tests {
+    use logger::IncMetric;
     use utils::vm_memory::Bytes;
 
     use super::*;
@@ -185,7 +231,7 @@ mod tests {
         mem.write(&ones[..], GuestAddress(0)).unwrap();
 
         // Remove the first page.
-        assert!(remove_range(&mem, (GuestAddress(0), page_size as u64), false).is_ok());
+        assert!(remove_range(&mem, (GuestAddress(0), page_size as u64), None).is_ok());
 
         // Check that the first page is zeroed.
         let mut actual_page = vec![0u8; page_size];
@@ -199,21 +245,55 @@ mod tests {
 
         // Malformed range: the len is too big.
         assert_match!(
-            remove_range(&mem, (GuestAddress(0), 0x10000), false).unwrap_err(),
+            remove_range(&mem, (GuestAddress(0), 0x10000), None).unwrap_err(),
             RemoveRegionError::MalformedRange
         );
 
         // Region not mapped.
         assert_match!(
-            remove_range(&mem, (GuestAddress(0x10000), 0x10), false).unwrap_err(),
+            remove_range(&mem, (GuestAddress(0x10000), 0x10), None).unwrap_err(),
             RemoveRegionError::RegionNotFound
         );
 
         // Madvise fail: the guest address is not aligned to the page size.
+        let einval_fails = METRICS.balloon.madvise_fails.einval.count();
         assert_match!(
-            remove_range(&mem, (GuestAddress(0x20), page_size as u64), false).unwrap_err(),
+            remove_range(&mem, (GuestAddress(0x20), page_size as u64), None).unwrap_err(),
             RemoveRegionError::MadviseFail(_)
         );
+        assert!(METRICS.balloon.madvise_fails.einval.count() > einval_fails);
+    }
+
+    #[test]
+    fn test_prefetch_range() {
+        let page_size: usize = 0x1000;
+        let mem = single_region_mem(2 * page_size);
+
+        // Fill the memory with ones.
+        let ones = vec![1u8; 2 * page_size];
+        mem.write(&ones[..], GuestAddress(0)).unwrap();
+
+        // Prefetching must not alter the contents of the range.
+        assert!(
+            prefetch_range(&mem, (GuestAddress(0), page_size as u64), libc::MADV_WILLNEED).is_ok()
+        );
+        let mut actual_page = vec![0u8; page_size];
+        mem.read(actual_page.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(vec![1u8; page_size], actual_page);
+
+        // Malformed range: the len is too big.
+        assert_match!(
+            prefetch_range(&mem, (GuestAddress(0), 0x10000), libc::MADV_WILLNEED).unwrap_err(),
+            RemoveRegionError::MalformedRange
+        );
+
+        // Region not mapped.
+        assert_match!(
+            prefetch_range(&mem, (GuestAddress(0x10000), 0x10), libc::MADV_WILLNEED)
+                .unwrap_err(),
+            RemoveRegionError::RegionNotFound
+        );
     }
 
     #[test]
@@ -226,7 +306,17 @@ mod tests {
         mem.write(&ones[..], GuestAddress(0)).unwrap();
 
         // Remove the first page.
-        assert!(remove_range(&mem, (GuestAddress(0), page_size as u64), true).is_ok());
+        let mut overlays = MmapOverlays::default();
+        assert!(remove_range(
+            &mem,
+            (GuestAddress(0), page_size as u64),
+            Some(&mut overlays)
+        )
+        .is_ok());
+        assert_eq!(
+            overlays.iter().collect::<Vec<_>>(),
+            vec![(GuestAddress(0), page_size as u64)]
+        );
 
         // Check that the first page is zeroed.
         let mut actual_page = vec![0u8; page_size];
@@ -240,21 +330,28 @@ mod tests {
 
         // Malformed range: the len is too big.
         assert_match!(
-            remove_range(&mem, (GuestAddress(0), 0x10000), true).unwrap_err(),
+            remove_range(&mem, (GuestAddress(0), 0x10000), Some(&mut overlays)).unwrap_err(),
             RemoveRegionError::MalformedRange
         );
 
         // Region not mapped.
         assert_match!(
-            remove_range(&mem, (GuestAddress(0x10000), 0x10), true).unwrap_err(),
+            remove_range(&mem, (GuestAddress(0x10000), 0x10), Some(&mut overlays)).unwrap_err(),
             RemoveRegionError::RegionNotFound
         );
 
         // Mmap fail: the guest address is not aligned to the page size.
         assert_match!(
-            remove_range(&mem, (GuestAddress(0x20), page_size as u64), true).unwrap_err(),
+            remove_range(
+                &mem,
+                (GuestAddress(0x20), page_size as u64),
+                Some(&mut overlays)
+            )
+            .unwrap_err(),
             RemoveRegionError::MmapFail(_)
         );
+        // Failed removals leave no overlay behind.
+        assert_eq!(overlays.count(), 1);
     }
 
     /// -------------------------------------
@@ -0,0 +1,233 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache of the contents of the blocks depopulated by the guest.
+//!
+//! Before a block is given back to the host, its contents are compressed into a cache of bounded
+//! size. When the guest populates the same block again during the same driver session, they are
+//! decompressed into it instead of leaving it zero-filled, which keeps warm state cheaply. The
+//! oldest blocks are evicted to make room for newer ones, and a cached block is dropped as soon
+//! as the guest populates a block overlapping it, since its contents are out of date from then
+//! on.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use logger::{error, IncMetric, StoreMetric, METRICS};
+use utils::vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::{lz4, VIRTIO_FAASCALE_MEM_PFN_SHIFT};
+
+const MIB: usize = 1 << 20;
+
+#[derive(Debug)]
+struct CachedBlock {
+    // Driver session the block was depopulated in.
+    generation: u64,
+    num_pages: u64,
+    // LZ4 block holding the contents.
+    contents: Vec<u8>,
+}
+
+/// Compressed contents of the blocks depopulated by the guest, keyed by their start pfn and
+/// by the driver session they were depopulated in.
+#[derive(Debug)]
+pub(crate) struct BlockCache {
+    max_bytes: usize,
+    used_bytes: usize,
+    // Disjoint blocks keyed by their start pfn.
+    blocks: BTreeMap<u64, CachedBlock>,
+    // Start pfns of the blocks, oldest first.
+    order: VecDeque<u64>,
+}
+
+impl BlockCache {
+    pub fn new(max_mib: u32) -> Self {
+        BlockCache {
+            max_bytes: max_mib as usize * MIB,
+            used_bytes: 0,
+            blocks: BTreeMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Size of the cache, in MiB.
+    pub fn max_mib(&self) -> u32 {
+        u32::try_from(self.max_bytes / MIB).unwrap_or(u32::MAX)
+    }
+
+    /// Caches the contents of the `(start pfn, number of pages)` block the guest is about to
+    /// depopulate in the driver session `generation`. Blocks larger than the cache are left out.
+    pub fn save(&mut self, mem: &GuestMemoryMmap, block: (u64, u64), generation: u64) {
+        self.invalidate(block);
+        let len = match usize::try_from(block.1 << VIRTIO_FAASCALE_MEM_PFN_SHIFT) {
+            Ok(len) if len <= self.max_bytes => len,
+            _ => return,
+        };
+        let mut contents = vec![0u8; len];
+        let addr = GuestAddress(block.0 << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        if let Err(err) = mem.read_slice(&mut contents, addr) {
+            error!("faascale-mem: error caching block contents: {:?}", err);
+            return;
+        }
+        let contents = lz4::compress(&contents);
+        if contents.len() > self.max_bytes {
+            return;
+        }
+
+        while self.used_bytes + contents.len() > self.max_bytes {
+            match self.order.front().copied() {
+                Some(oldest) => {
+                    self.remove(oldest);
+                    METRICS.faascale_mem.block_cache_evictions.inc();
+                }
+                None => break,
+            }
+        }
+        self.used_bytes += contents.len();
+        self.order.push_back(block.0);
+        self.blocks.insert(
+            block.0,
+            CachedBlock {
+                generation,
+                num_pages: block.1,
+                contents,
+            },
+        );
+        METRICS
+            .faascale_mem
+            .block_cache_bytes
+            .store(self.used_bytes);
+    }
+
+    /// Writes the cached contents of the `(start pfn, number of pages)` block the guest just
+    /// populated in the driver session `generation` back into the guest memory. Returns whether
+    /// the block was found in the cache.
+    pub fn restore(&mut self, mem: &GuestMemoryMmap, block: (u64, u64), generation: u64) -> bool {
+        let cached = match self.blocks.get(&block.0) {
+            Some(cached) if cached.generation == generation && cached.num_pages == block.1 => {
+                self.remove(block.0)
+            }
+            _ => None,
+        };
+        self.invalidate(block);
+
+        let contents = cached.and_then(|cached| {
+            let len = usize::try_from(block.1 << VIRTIO_FAASCALE_MEM_PFN_SHIFT).ok()?;
+            lz4::decompress(&cached.contents, len)
+        });
+        let addr = GuestAddress(block.0 << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        match contents.map(|contents| mem.write_slice(&contents, addr)) {
+            Some(Ok(())) => {
+                METRICS.faascale_mem.block_cache_hits.inc();
+                true
+            }
+            Some(Err(err)) => {
+                error!("faascale-mem: error restoring block contents: {:?}", err);
+                METRICS.faascale_mem.block_cache_misses.inc();
+                false
+            }
+            None => {
+                METRICS.faascale_mem.block_cache_misses.inc();
+                false
+            }
+        }
+    }
+
+    /// Drops all the cached blocks.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.order.clear();
+        self.used_bytes = 0;
+        METRICS.faascale_mem.block_cache_bytes.store(0);
+    }
+
+    // Drops the cached blocks overlapping `block`.
+    fn invalidate(&mut self, block: (u64, u64)) {
+        let end = block.0.saturating_add(block.1);
+        // The blocks are disjoint, so once one ends before `block` starts, the ones before it do
+        // too.
+        let overlapping = self
+            .blocks
+            .range(..end)
+            .rev()
+            .take_while(|(&pfn, cached)| pfn + cached.num_pages > block.0)
+            .map(|(&pfn, _)| pfn)
+            .collect::<Vec<_>>();
+        for pfn in overlapping {
+            self.remove(pfn);
+        }
+    }
+
+    fn remove(&mut self, pfn: u64) -> Option<CachedBlock> {
+        let cached = self.blocks.remove(&pfn)?;
+        self.order.retain(|&other| other != pfn);
+        self.used_bytes -= cached.contents.len();
+        METRICS
+            .faascale_mem
+            .block_cache_bytes
+            .store(self.used_bytes);
+        Some(cached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+
+    #[test]
+    fn test_block_cache() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x20_0000)], false).unwrap();
+        let mut cache = BlockCache::new(1);
+        assert_eq!(cache.max_mib(), 1);
+        let page = |pfn: u64| GuestAddress(pfn << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+
+        mem.write_obj(0xdead_beef_u64, page(0x10)).unwrap();
+        cache.save(&mem, (0x10, 0x10), 1);
+        mem.write_obj(0u64, page(0x10)).unwrap();
+        // The block of another driver session, or of another size, is not restored.
+        assert!(!cache.restore(&mem, (0x10, 0x10), 2));
+        cache.save(&mem, (0x10, 0x10), 1);
+        assert!(!cache.restore(&mem, (0x10, 0x8), 1));
+        assert!(cache.blocks.is_empty());
+
+        mem.write_obj(0xdead_beef_u64, page(0x10)).unwrap();
+        cache.save(&mem, (0x10, 0x10), 1);
+        mem.write_obj(0u64, page(0x10)).unwrap();
+        assert!(cache.restore(&mem, (0x10, 0x10), 1));
+        assert_eq!(mem.read_obj::<u64>(page(0x10)).unwrap(), 0xdead_beef);
+        assert_eq!(cache.used_bytes, 0);
+
+        // Populating an overlapping block drops the cached one.
+        cache.save(&mem, (0x10, 0x10), 1);
+        cache.save(&mem, (0x40, 0x10), 1);
+        assert!(!cache.restore(&mem, (0x18, 0x10), 1));
+        assert_eq!(cache.blocks.keys().copied().collect::<Vec<_>>(), vec![0x40]);
+
+        // Blocks larger than the cache are left out, the oldest blocks make room for others.
+        cache.save(&mem, (0x100, 0x200), 1);
+        assert_eq!(cache.order, [0x40]);
+        let mut state = 0x1234_5678u32;
+        let noise = (0..0x10_0000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        mem.write_slice(&noise, page(0x80)).unwrap();
+        cache.save(&mem, (0x80, 0x80), 1);
+        assert_eq!(cache.order, [0x40, 0x80]);
+        let evictions = METRICS.faascale_mem.block_cache_evictions.count();
+        cache.save(&mem, (0x100, 0x80), 1);
+        assert_eq!(cache.order, [0x100]);
+        assert!(cache.used_bytes <= cache.max_bytes);
+        assert!(METRICS.faascale_mem.block_cache_evictions.count() >= evictions + 2);
+
+        cache.clear();
+        assert!(cache.blocks.is_empty());
+        assert_eq!(cache.used_bytes, 0);
+    }
+}
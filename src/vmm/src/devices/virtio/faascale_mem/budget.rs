@@ -0,0 +1,209 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Negotiation of the memory budget of the microVM between the host and the guest driver.
+//!
+//! The host offers a budget through the config space, tagged with a new epoch. The guest
+//! acknowledges it on the control queue with the epoch and the number of pages it agrees to
+//! keep populated, which may be lower than the offer. From then on, populate requests that
+//! would take the guest over the agreed budget are refused and counted as violations. A new
+//! offer does not lift the agreed budget until the guest acknowledges it.
+
+use serde::Serialize;
+use utils::vm_memory::ByteValued;
+
+use super::MIB_TO_4K_PAGES;
+
+/// Stage reached by the budget negotiation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetNegotiationState {
+    /// The host did not offer a budget yet.
+    #[default]
+    Unoffered,
+    /// The latest offer was not acknowledged by the guest yet.
+    Offered,
+    /// The guest acknowledged the latest offer.
+    Agreed,
+}
+
+/// Memory budget negotiated with the guest, as reported by the API.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemBudget {
+    /// Stage reached by the negotiation.
+    pub state: BudgetNegotiationState,
+    /// Epoch of the latest offer.
+    pub offered_epoch: u32,
+    /// Budget of the latest offer, in MiB.
+    pub offered_mib: u32,
+    /// Budget the guest acknowledged, in MiB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agreed_mib: Option<u32>,
+    /// Memory populated by the guest, in MiB.
+    pub populated_mib: u64,
+    /// Number of populate blocks refused because they exceed the agreed budget.
+    pub violations: u64,
+}
+
+/// Acknowledgement of a budget offer written by the guest on the control queue.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct BudgetAck {
+    /// Epoch of the acknowledged offer.
+    pub epoch: u32,
+    /// Number of 4K pages the guest agrees to keep populated.
+    pub num_pages: u32,
+}
+
+// SAFETY: Safe because BudgetAck only contains plain data.
+unsafe impl ByteValued for BudgetAck {}
+
+/// Host side of the budget negotiation.
+#[derive(Debug, Default)]
+pub(crate) struct BudgetNegotiation {
+    pub(crate) state: BudgetNegotiationState,
+    pub(crate) offered_epoch: u32,
+    pub(crate) offered_pages: u32,
+    pub(crate) agreed_pages: Option<u32>,
+    pub(crate) violations: u64,
+}
+
+impl BudgetNegotiation {
+    /// Offers a budget of `num_pages` and returns the epoch tagging the offer.
+    pub fn offer(&mut self, num_pages: u32) -> u32 {
+        self.offered_epoch = self.offered_epoch.wrapping_add(1);
+        self.offered_pages = num_pages;
+        self.state = BudgetNegotiationState::Offered;
+        self.offered_epoch
+    }
+
+    /// Applies an acknowledgement from the guest and returns whether it was accepted. Only the
+    /// latest offer can be acknowledged, with at most the offered number of pages.
+    pub fn ack(&mut self, ack: &BudgetAck) -> bool {
+        if self.state == BudgetNegotiationState::Unoffered
+            || ack.epoch != self.offered_epoch
+            || ack.num_pages > self.offered_pages
+        {
+            return false;
+        }
+
+        self.agreed_pages = Some(ack.num_pages);
+        self.state = BudgetNegotiationState::Agreed;
+        true
+    }
+
+    /// Returns whether the guest, holding `populated_pages`, may populate `new_pages` more.
+    /// Refusals are counted as violations.
+    pub fn admit(&mut self, populated_pages: u64, new_pages: u64) -> bool {
+        match self.agreed_pages {
+            Some(agreed_pages) if populated_pages + new_pages > u64::from(agreed_pages) => {
+                self.violations += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Offers the latest budget again to a new guest driver, which has to acknowledge it before
+    /// a budget is enforced again. Returns the epoch tagging the offer.
+    pub fn restart(&mut self) -> u32 {
+        self.agreed_pages = None;
+        self.offer(self.offered_pages)
+    }
+
+    /// Number of pages of the latest offer.
+    pub fn offered_pages(&self) -> u32 {
+        self.offered_pages
+    }
+
+    /// Number of pages the guest agreed to keep populated, enforced until a new driver starts.
+    pub fn agreed_pages(&self) -> Option<u32> {
+        self.agreed_pages
+    }
+
+    /// Reports the negotiation for a guest holding `populated_pages`.
+    pub fn info(&self, populated_pages: u64) -> FaascaleMemBudget {
+        FaascaleMemBudget {
+            state: self.state,
+            offered_epoch: self.offered_epoch,
+            offered_mib: self.offered_pages / MIB_TO_4K_PAGES,
+            agreed_mib: self.agreed_pages.map(|pages| pages / MIB_TO_4K_PAGES),
+            populated_mib: populated_pages / u64::from(MIB_TO_4K_PAGES),
+            violations: self.violations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_negotiation() {
+        let mut budget = BudgetNegotiation::default();
+        // Nothing is enforced nor can be acknowledged before an offer.
+        assert!(budget.admit(1 << 20, 1 << 20));
+        assert!(!budget.ack(&BudgetAck {
+            epoch: 0,
+            num_pages: 0
+        }));
+        assert_eq!(budget.info(0), FaascaleMemBudget::default());
+
+        let epoch = budget.offer(512 * MIB_TO_4K_PAGES);
+        assert_eq!(epoch, 1);
+        assert_eq!(budget.info(0).state, BudgetNegotiationState::Offered);
+        // Nothing is enforced until the guest acknowledges the offer.
+        assert!(budget.admit(1 << 20, 1 << 20));
+
+        // The guest cannot take more than offered.
+        assert!(!budget.ack(&BudgetAck {
+            epoch,
+            num_pages: 512 * MIB_TO_4K_PAGES + 1
+        }));
+        assert!(budget.ack(&BudgetAck {
+            epoch,
+            num_pages: 256 * MIB_TO_4K_PAGES
+        }));
+        assert!(budget.admit(0, u64::from(256 * MIB_TO_4K_PAGES)));
+        assert!(!budget.admit(u64::from(255 * MIB_TO_4K_PAGES), 2 * 256));
+        assert_eq!(
+            budget.info(u64::from(100 * MIB_TO_4K_PAGES)),
+            FaascaleMemBudget {
+                state: BudgetNegotiationState::Agreed,
+                offered_epoch: 1,
+                offered_mib: 512,
+                agreed_mib: Some(256),
+                populated_mib: 100,
+                violations: 1,
+            }
+        );
+
+        // The agreed budget holds until the guest acknowledges the new offer, and stale
+        // acknowledgements are ignored.
+        let epoch = budget.offer(1024 * MIB_TO_4K_PAGES);
+        assert!(!budget.admit(u64::from(256 * MIB_TO_4K_PAGES), 1));
+        assert!(!budget.ack(&BudgetAck {
+            epoch: epoch - 1,
+            num_pages: 0
+        }));
+        assert!(budget.ack(&BudgetAck {
+            epoch,
+            num_pages: 1024 * MIB_TO_4K_PAGES
+        }));
+        assert!(budget.admit(u64::from(256 * MIB_TO_4K_PAGES), 1));
+        let info = budget.info(0);
+        assert_eq!(info.state, BudgetNegotiationState::Agreed);
+        assert_eq!(info.agreed_mib, Some(1024));
+        assert_eq!(info.violations, 2);
+
+        // A new guest driver has to acknowledge the budget again.
+        let epoch = budget.restart();
+        assert!(budget.admit(u64::from(2048 * MIB_TO_4K_PAGES), 1));
+        let info = budget.info(0);
+        assert_eq!(info.state, BudgetNegotiationState::Offered);
+        assert_eq!(info.offered_epoch, epoch);
+        assert_eq!(info.offered_mib, 1024);
+        assert_eq!(info.agreed_mib, None);
+        assert_eq!(info.violations, 2);
+    }
+}
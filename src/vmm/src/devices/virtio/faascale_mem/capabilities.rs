@@ -0,0 +1,119 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host support for the system calls the population policies rely on.
+//!
+//! The policies of the device depend on host kernel features that may be missing: a
+//! `pre_alloc_mem` device relies on `MADV_POPULATE_WRITE`, falling back to touching the pages
+//! one at a time without it, and a `pre_tdp_fault` one needs the
+//! `KVM_PREALLOC_USER_MEMORY_REGION` ioctl of the patched host KVM. They are probed once the
+//! microVM is created, so that the API reports which of the flags actually work.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+use serde::{Deserialize, Serialize};
+
+use super::util::{kvm_userspace_prealloc_memory_region, KVM_PREALLOC_USER_MEMORY_REGION};
+
+/// Support of the host for the system calls used by the device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemCapabilities {
+    /// `madvise(MADV_POPULATE_WRITE)`, used by `pre_alloc_mem` (Linux 5.14+).
+    pub madv_populate_write: bool,
+    /// `process_madvise` (Linux 5.10+).
+    pub process_madvise: bool,
+    /// The `KVM_PREALLOC_USER_MEMORY_REGION` ioctl of the patched host KVM, needed by
+    /// `pre_tdp_fault`.
+    pub kvm_prealloc_user_memory_region: bool,
+}
+
+impl FaascaleMemCapabilities {
+    /// Probes the host, with `vm_fd` the file descriptor of the KVM VM of the microVM.
+    pub fn probe(vm_fd: RawFd) -> Self {
+        FaascaleMemCapabilities {
+            madv_populate_write: probe_madv_populate_write(),
+            process_madvise: probe_process_madvise(),
+            kvm_prealloc_user_memory_region: probe_kvm_prealloc(vm_fd),
+        }
+    }
+}
+
+// Populates a scratch page, older kernels refuse the advice with `EINVAL`.
+fn probe_madv_populate_write() -> bool {
+    // SAFETY: The mapping is private to this function and unmapped before returning.
+    unsafe {
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let addr = libc::mmap(
+            std::ptr::null_mut(),
+            page_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            return false;
+        }
+        let ret = libc::madvise(addr, page_size, libc::MADV_POPULATE_WRITE);
+        libc::munmap(addr, page_size);
+        ret == 0
+    }
+}
+
+// Calls `process_madvise` on an invalid pidfd, which only fails with `ENOSYS` when the system
+// call does not exist.
+fn probe_process_madvise() -> bool {
+    // SAFETY: No memory is passed to the system call.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_process_madvise,
+            -1,
+            std::ptr::null::<libc::iovec>(),
+            0,
+            libc::MADV_COLD,
+            0,
+        )
+    };
+    ret == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS)
+}
+
+// Preallocates an empty range, KVM fails unknown ioctls with `ENOTTY`.
+fn probe_kvm_prealloc(vm_fd: RawFd) -> bool {
+    if vm_fd <= 0 {
+        return false;
+    }
+    let region = kvm_userspace_prealloc_memory_region::default();
+    // SAFETY: The region outlives the call and the ioctl does not keep it.
+    let ret = unsafe {
+        libc::ioctl(
+            vm_fd,
+            KVM_PREALLOC_USER_MEMORY_REGION() as libc::c_int,
+            &region,
+        )
+    };
+    if ret == 0 {
+        return true;
+    }
+    !matches!(
+        io::Error::last_os_error().raw_os_error(),
+        Some(libc::ENOTTY) | Some(libc::EBADF)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_capabilities() {
+        // Without a VM, the KVM ioctl is never reported as supported.
+        let capabilities = FaascaleMemCapabilities::probe(-1);
+        assert!(!capabilities.kvm_prealloc_user_memory_region);
+        assert_eq!(
+            capabilities.madv_populate_write,
+            probe_madv_populate_write()
+        );
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracking of the config space changes the guest has not acknowledged.
+//!
+//! The host changes the config space, e.g. the memory budget or the release request, and
+//! notifies the guest with a config interrupt. The driver acknowledges the change by writing to
+//! the config space or by sending requests on any of the queues. A change still unacknowledged
+//! after `CONFIG_ACK_TIMEOUT` hints at a driver that is stuck or ignores the notifications.
+
+use std::time::{Duration, Instant};
+
+/// How long the guest has to acknowledge a config space change.
+pub(crate) const CONFIG_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Config space changes notified to the guest and not acknowledged yet.
+#[derive(Debug, Default)]
+pub(crate) struct ConfigAckTracker {
+    // The changed fields, along with when the guest was first notified of an unacknowledged
+    // change to them.
+    pending: Vec<(&'static str, Instant)>,
+}
+
+impl ConfigAckTracker {
+    /// Records that the guest was notified of a change to `field`. A field changed again before
+    /// being acknowledged keeps the time of its first change.
+    pub fn notify(&mut self, field: &'static str, now: Instant) {
+        if !self.pending.iter().any(|(pending, _)| *pending == field) {
+            self.pending.push((field, now));
+        }
+    }
+
+    /// Records that the guest responded, acknowledging all the changes notified so far. Returns
+    /// the fields acknowledged after `timeout`, along with how long the guest took.
+    pub fn acknowledge(
+        &mut self,
+        now: Instant,
+        timeout: Duration,
+    ) -> Vec<(&'static str, Duration)> {
+        let late = self.overdue(now, timeout);
+        self.pending.clear();
+        late
+    }
+
+    /// Returns the fields left unacknowledged for longer than `timeout`, along with for how long.
+    pub fn overdue(&self, now: Instant, timeout: Duration) -> Vec<(&'static str, Duration)> {
+        self.pending
+            .iter()
+            .map(|(field, since)| (*field, now.saturating_duration_since(*since)))
+            .filter(|(_, waited)| *waited > timeout)
+            .collect()
+    }
+
+    /// Drops the pending changes, the next driver reads the whole config space anyway.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_ack_tracker() {
+        let mut tracker = ConfigAckTracker::default();
+        let now = Instant::now();
+        let timeout = Duration::from_secs(10);
+        assert!(tracker.overdue(now + timeout * 2, timeout).is_empty());
+
+        tracker.notify("budget_pages", now);
+        tracker.notify("release_pages", now + Duration::from_secs(5));
+        // A change made again keeps the time of the first one.
+        tracker.notify("budget_pages", now + Duration::from_secs(8));
+        assert!(tracker.overdue(now + timeout, timeout).is_empty());
+        assert_eq!(
+            tracker.overdue(now + Duration::from_secs(12), timeout),
+            vec![("budget_pages", Duration::from_secs(12))]
+        );
+        assert_eq!(
+            tracker
+                .overdue(now + Duration::from_secs(16), timeout)
+                .len(),
+            2
+        );
+
+        // Acknowledging reports the late fields and drops them all.
+        assert_eq!(
+            tracker.acknowledge(now + Duration::from_secs(12), timeout),
+            vec![("budget_pages", Duration::from_secs(12))]
+        );
+        assert!(tracker.overdue(now + timeout * 10, timeout).is_empty());
+
+        tracker.notify("release_pages", now);
+        assert!(tracker
+            .acknowledge(now + Duration::from_secs(1), timeout)
+            .is_empty());
+        tracker.notify("release_pages", now);
+        tracker.clear();
+        assert!(tracker.overdue(now + timeout * 10, timeout).is_empty());
+    }
+}
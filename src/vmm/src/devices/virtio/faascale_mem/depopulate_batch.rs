@@ -0,0 +1,236 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Batching of the depopulations of transparent huge pages.
+//!
+//! Releasing part of a huge page with `MADV_DONTNEED` splits it into base pages. When the guest
+//! depopulates a huge page in pieces, the pieces are held back until the whole huge page is
+//! depopulated, and then released with one aligned `madvise`. Pieces held back for too long are
+//! released on their own, when the timer of the device expires.
+//!
+//! Huge pages are aligned in the host address space, which is not necessarily aligned with the
+//! guest physical address space, so the blocks are split at the huge page boundaries of their
+//! host mapping.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use logger::{IncMetric, StoreMetric, METRICS};
+
+use super::util::{PfnRanges, THP_SIZE};
+use super::VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+
+/// How long the pieces of a huge page are held back waiting for the rest of it.
+pub(crate) const DEPOPULATE_BATCH_TIMEOUT: Duration = Duration::from_secs(1);
+// Number of 4K pages in a transparent huge page.
+const THP_PAGES: u64 = THP_SIZE >> VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+
+// Pieces of a huge page depopulated by the guest, waiting for the rest of it.
+#[derive(Debug)]
+struct PendingHugePage {
+    pieces: PfnRanges,
+    // When the first piece was depopulated.
+    since: Instant,
+}
+
+/// Holds back the depopulated pieces of huge pages until the whole huge page is depopulated.
+#[derive(Debug, Default)]
+pub(crate) struct DepopulateBatcher {
+    // Keyed by the index of the huge page in the host address space.
+    pending: BTreeMap<u64, PendingHugePage>,
+}
+
+impl DepopulateBatcher {
+    /// Takes the `(start pfn, number of pages)` block depopulated by the guest, the first page
+    /// of which is mapped at the host frame `host_pfn`. Returns the blocks to release right
+    /// away: the huge pages the block covers, and those it completes along with the pieces
+    /// held back before.
+    pub fn depopulate(
+        &mut self,
+        block: (u64, u64),
+        host_pfn: u64,
+        now: Instant,
+    ) -> Vec<(u64, u64)> {
+        let mut release = Vec::new();
+        let (start, end) = (block.0, block.0 + block.1);
+        let mut pfn = start;
+        while pfn < end {
+            let host = host_pfn + (pfn - start);
+            let huge_page = host / THP_PAGES;
+            let piece_end = cmp::min(end, pfn + THP_PAGES - host % THP_PAGES);
+            let piece = (pfn, piece_end - pfn);
+            pfn = piece_end;
+
+            if piece.1 == THP_PAGES {
+                // The pieces held back are released along with the rest of the huge page.
+                self.pending.remove(&huge_page);
+                release.push(piece);
+                continue;
+            }
+
+            let pending = self
+                .pending
+                .entry(huge_page)
+                .or_insert_with(|| PendingHugePage {
+                    pieces: PfnRanges::default(),
+                    since: now,
+                });
+            pending.pieces.insert(piece);
+            if pending.pieces.num_pages() == THP_PAGES {
+                // The pieces add up to the whole huge page, a single range.
+                release.extend(pending.pieces.ranges().map(range_to_block));
+                self.pending.remove(&huge_page);
+                METRICS.faascale_mem.thp_splits_avoided.inc();
+            }
+        }
+        self.update_pending_pages();
+        release
+    }
+
+    /// Forgets the pieces held back that overlap the `(start pfn, number of pages)` block,
+    /// which the guest populated again.
+    pub fn populate(&mut self, block: (u64, u64)) {
+        let mut emptied = Vec::new();
+        for (&huge_page, pending) in self.pending.iter_mut() {
+            if pending.pieces.overlaps(block) {
+                pending.pieces.remove(block);
+                if pending.pieces.num_pages() == 0 {
+                    emptied.push(huge_page);
+                }
+            }
+        }
+        for huge_page in emptied {
+            self.pending.remove(&huge_page);
+        }
+        self.update_pending_pages();
+    }
+
+    /// The pieces held back that overlap the `(start pfn, number of pages)` block, whose pages
+    /// still hold what the guest left in them.
+    pub fn held_back(&self, block: (u64, u64)) -> Vec<(u64, u64)> {
+        self.pending
+            .values()
+            .flat_map(|pending| pending.pieces.intersection(block))
+            .collect()
+    }
+
+    /// Returns the pieces held back for longer than `timeout`, which are no longer held back.
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> Vec<(u64, u64)> {
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.saturating_duration_since(pending.since) >= timeout)
+            .map(|(&huge_page, _)| huge_page)
+            .collect();
+        if expired.is_empty() {
+            return Vec::new();
+        }
+
+        let mut release = Vec::new();
+        for huge_page in expired {
+            if let Some(pending) = self.pending.remove(&huge_page) {
+                release.extend(pending.pieces.ranges().map(range_to_block));
+            }
+        }
+        METRICS.faascale_mem.thp_batch_expired.add(release.len());
+        self.update_pending_pages();
+        release
+    }
+
+    /// When the oldest pieces held back are held back for `timeout`, if any are.
+    pub fn next_expiry(&self, timeout: Duration) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|pending| pending.since + timeout)
+            .min()
+    }
+
+    /// Number of guest pages held back.
+    pub fn pending_pages(&self) -> u64 {
+        self.pending
+            .values()
+            .map(|pending| pending.pieces.num_pages())
+            .sum()
+    }
+
+    fn update_pending_pages(&self) {
+        METRICS
+            .faascale_mem
+            .thp_batch_pending_pages
+            .store(self.pending_pages() as usize);
+    }
+}
+
+// Converts a `[start, end)` pfn range within a huge page to a `(start pfn, number of pages)`
+// block.
+fn range_to_block((start, end): (u64, u64)) -> (u64, u64) {
+    (start, end - start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depopulate_batcher() {
+        let mut batcher = DepopulateBatcher::default();
+        let now = Instant::now();
+        let huge_page = THP_PAGES;
+        let splits_avoided = METRICS.faascale_mem.thp_splits_avoided.count();
+
+        // Guest pfn 0x1000 is mapped half way through a huge page of the host. The block is
+        // split at the boundary, the second piece is held back.
+        let host_pfn = 3 * THP_PAGES + THP_PAGES / 2;
+        assert!(batcher
+            .depopulate((0x1000, huge_page / 2 + 16), host_pfn, now)
+            .is_empty());
+        assert_eq!(batcher.pending_pages(), huge_page / 2 + 16);
+
+        // The rest of the second huge page completes it. The whole huge page is released at
+        // once, the rest of the block covers the third huge page.
+        let start = 0x1000 + huge_page / 2 + 16;
+        let released = batcher.depopulate(
+            (start, 2 * huge_page - 16),
+            host_pfn + huge_page / 2 + 16,
+            now,
+        );
+        assert_eq!(
+            released,
+            vec![
+                (0x1000 + huge_page / 2, huge_page),
+                (0x1000 + huge_page / 2 + huge_page, huge_page)
+            ]
+        );
+        assert_eq!(batcher.pending_pages(), huge_page / 2);
+        assert_eq!(
+            METRICS.faascale_mem.thp_splits_avoided.count(),
+            splits_avoided + 1
+        );
+
+        // Pieces populated again are not released anymore.
+        assert_eq!(batcher.held_back((0x1000, 16)), vec![(0x1000, 16)]);
+        batcher.populate((0x1000, 16));
+        assert_eq!(batcher.pending_pages(), huge_page / 2 - 16);
+        assert_eq!(batcher.held_back((0x1000, 32)), vec![(0x1010, 16)]);
+        batcher.populate((0x1000, huge_page));
+        assert_eq!(batcher.pending_pages(), 0);
+
+        // Pieces held back for too long are released on their own.
+        assert!(batcher.depopulate((0x1000, 8), host_pfn, now).is_empty());
+        assert!(batcher
+            .depopulate((0x1010, 8), host_pfn + 0x10, now)
+            .is_empty());
+        assert_eq!(
+            batcher.next_expiry(DEPOPULATE_BATCH_TIMEOUT),
+            Some(now + DEPOPULATE_BATCH_TIMEOUT)
+        );
+        assert!(batcher.expire(now, DEPOPULATE_BATCH_TIMEOUT).is_empty());
+        assert_eq!(
+            batcher.expire(now + DEPOPULATE_BATCH_TIMEOUT, DEPOPULATE_BATCH_TIMEOUT),
+            vec![(0x1000, 8), (0x1010, 8)]
+        );
+        assert_eq!(batcher.pending_pages(), 0);
+        assert_eq!(batcher.next_expiry(DEPOPULATE_BATCH_TIMEOUT), None);
+    }
+}
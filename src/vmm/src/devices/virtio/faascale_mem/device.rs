@@ -3,42 +3,368 @@
 
 use std::cmp;
 use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::result::Result;
-use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use log::debug;
 
-use logger::{error, IncMetric, METRICS};
-use serde::Serialize;
-use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use kvm_ioctls::VmFd;
+use logger::{error, info, warn, IncMetric, StoreMetric, METRICS};
+use mmds::data_store::Mmds;
+use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
+use serde::{Deserialize, Serialize};
+use timerfd::{SetTimeFlags, TimerState};
+use utils::clock::{Clock, MonotonicClock, Timer};
 use utils::eventfd::EventFd;
-use utils::vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
+use utils::vm_memory::{
+    hugetlb_page_size, Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap,
+    GuestMemoryRegion,
+};
 use virtio_gen::virtio_blk::VIRTIO_F_VERSION_1;
+use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 
-use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_FAASCALE_MEM};
-use super::util::{populate_range, remove_range};
+use super::super::{
+    ActivateResult, DescriptorChain, DeviceState, Queue, VirtioDevice, POP_BATCH_SIZE,
+    TYPE_FAASCALE_MEM,
+};
+use super::block_cache::BlockCache;
+use super::budget::{BudgetAck, BudgetNegotiation, FaascaleMemBudget};
+use super::capabilities::FaascaleMemCapabilities;
+use super::config_ack::{ConfigAckTracker, CONFIG_ACK_TIMEOUT};
+use super::depopulate_batch::{DepopulateBatcher, DEPOPULATE_BATCH_TIMEOUT};
+use super::encryption::{EncryptedMemoryBackend, MemoryEncryptionKind};
+use super::error_log::{ErrorLog, FaascaleMemErrors, FaascaleMemOperation};
+use super::estimate::{
+    estimate, EstimateInputs, FaascaleMemEstimate, HostMemInfo, PopulateThroughput,
+};
+use super::experiment::{
+    ExperimentSample, ExperimentSplitter, FaascaleMemExperiment, FaascaleMemExperimentResults,
+};
+use super::latency::{FaascaleMemPopulateLatency, PopulateTimings};
+use super::heatmap::{ActivityHeatmap, FaascaleMemHeatmap};
+use super::interleave::{check_numa_node, BlockInterleave, FaascaleMemInterleaveConfig};
+use super::lazy::{FaascaleMemPopulateMode, LazyPopulate};
+use super::leak::DescriptorLeakTracker;
+use super::memory_template::MemoryTemplate;
+use super::mlock::{BlockMlock, FaascaleMemMlockUsage};
+use super::mmds_publish::{FaascaleMemMmdsSummary, MmdsPublisher};
+use super::observer::{FaascaleMemObservedActivity, RequestActivity};
+use super::perf::PrefaultSampler;
+use super::policy::{FaascaleMemPolicyConfig, FaascaleMemPolicyDecision, Policy, PolicyInputs};
+use super::polling::{FaascaleMemPollingAdaptation, PollingAdaptation};
+use super::pool::{FaascaleMemPoolConfig, FaascaleMemPoolUsage, HostMemoryPool};
+use super::prefault_batch::PrefaultBatch;
+use super::scheduling::{FaascaleMemWorkerScheduling, FaascaleMemWorkerSchedulingConfig};
+use super::spill::SpillFile;
+use super::template::FaascaleMemWarmReport;
+use super::util::{
+    advise_huge_pages, advise_mergeable, host_pfn, populate_range, prefault_slot_range,
+    queue_prefault, rehydrate_range, remove_range, thp_backed_bytes, write_populate_canary,
+    zero_range, PfnRanges, PopulateTracker, PreAllocMethod, TraceId, MADV_COLLAPSE,
+};
+use super::warmup::{BootWarmupTracker, FaascaleMemBootWarmup};
 use super::{
-    FAASCALE_MEM_DEV_ID, POPULATE_INDEX, DEPOPULATE_INDEX,
+    FAASCALE_MEM_DEV_ID, POPULATE_INDEX, DEPOPULATE_INDEX, CONTROL_INDEX,
     MIB_TO_4K_PAGES, NUM_QUEUES, QUEUE_SIZES, FAASCALE_STATS_INDEX,
-    VIRTIO_FAASCALE_MEM_F_STATS_VQ, VIRTIO_FAASCALE_MEM_S_AVAIL, VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+    VIRTIO_FAASCALE_MEM_BLOCK_F_MLOCKED, VIRTIO_FAASCALE_MEM_BLOCK_F_PINNED,
+    VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_1G, VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_2M,
+    VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_4K, VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_MASK,
+    VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_SHIFT, VIRTIO_FAASCALE_MEM_F_BLOCK_GRANULARITY,
+    VIRTIO_FAASCALE_MEM_F_BUDGET, VIRTIO_FAASCALE_MEM_F_MLOCK, VIRTIO_FAASCALE_MEM_F_PIN,
+    VIRTIO_FAASCALE_MEM_F_STATS_VQ, VIRTIO_FAASCALE_MEM_F_STATUS, VIRTIO_FAASCALE_MEM_F_TRACE_IDS,
+    VIRTIO_FAASCALE_MEM_F_WIDE_BLOCKS, VIRTIO_FAASCALE_MEM_F_ZEROED,
+    VIRTIO_FAASCALE_MEM_STATUS_EINVAL, VIRTIO_FAASCALE_MEM_STATUS_ENOMEM,
+    VIRTIO_FAASCALE_MEM_STATUS_OK, VIRTIO_FAASCALE_MEM_STATUS_OVER_BUDGET,
+    VIRTIO_FAASCALE_MEM_S_AVAIL, VIRTIO_FAASCALE_MEM_PFN_SHIFT,
     VIRTIO_FAASCALE_MEM_S_CACHES, VIRTIO_FAASCALE_MEM_S_HTLB_PGALLOC, VIRTIO_FAASCALE_MEM_S_HTLB_PGFAIL,
     VIRTIO_FAASCALE_MEM_S_MAJFLT, VIRTIO_FAASCALE_MEM_S_MEMFREE, VIRTIO_FAASCALE_MEM_S_MEMTOT,
-    VIRTIO_FAASCALE_MEM_S_MINFLT, VIRTIO_FAASCALE_MEM_S_SWAP_IN, VIRTIO_FAASCALE_MEM_S_SWAP_OUT,
+    VIRTIO_FAASCALE_MEM_S_MINFLT, VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG10,
+    VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG60, VIRTIO_FAASCALE_MEM_S_SWAP_IN,
+    VIRTIO_FAASCALE_MEM_S_SWAP_OUT,
+};
+use crate::devices::virtio::faascale_mem::{
+    Error as FaascaleMemError, RemoveRegionError, MAX_BLOCKS_IN_DESC, POPULATE_TRACKER_MAX_ENTRIES,
 };
-use crate::devices::virtio::faascale_mem::{Error as FaascaleMemError, MAX_BLOCKS_IN_DESC};
+use crate::devices::virtio::irq_moderation::InterruptModerator;
+use crate::devices::virtio::mem_overlay::MmapOverlays;
+use crate::devices::virtio::pause_gate::VmPauseGate;
+use crate::devices::virtio::stats_delta::CounterDelta;
 use crate::devices::virtio::{IrqTrigger, IrqType};
+use crate::vmm_config::faascale_mem::FaascaleMemDeviceConfig;
+use crate::vmm_config::RateLimiterConfig;
 
 /// SIZE_OF_U32和SIZE_OF_STAT，分别表示u32和FaascaleMemStat类型的大小（以字节为单位）
 const SIZE_OF_BLOCK_INFO: usize = std::mem::size_of::<(u32, u32)>();
+// Blocks are made of a 64-bit pfn and page count once VIRTIO_FAASCALE_MEM_F_WIDE_BLOCKS is
+// negotiated.
+const SIZE_OF_WIDE_BLOCK_INFO: usize = std::mem::size_of::<(u64, u64)>();
+// Populate blocks carry a trace ID after the page count once VIRTIO_FAASCALE_MEM_F_TRACE_IDS is
+// negotiated.
+const SIZE_OF_TRACE_ID: usize = std::mem::size_of::<u64>();
+// The status of a request once VIRTIO_FAASCALE_MEM_F_STATUS is negotiated.
+const SIZE_OF_STATUS: usize = std::mem::size_of::<u32>();
 /// std::mem::size_of函数来获取类型的大小
 const SIZE_OF_STAT: usize = std::mem::size_of::<FaascaleMemStat>();
+const SIZE_OF_BUDGET_ACK: usize = std::mem::size_of::<BudgetAck>();
 
 /// 将以4KB页面为单位的数量转换为以MB为单位的数量
 fn pages_to_mib(amount_pages: u32) -> u32 {
     amount_pages / MIB_TO_4K_PAGES
 }
 
+// Guest memory range of a `(start pfn, number of pages)` block.
+pub(crate) fn block_range(block: (u64, u64)) -> (GuestAddress, u64) {
+    (
+        GuestAddress(block.0 << VIRTIO_FAASCALE_MEM_PFN_SHIFT),
+        block.1 << VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+    )
+}
+
+// Whether the guest memory range of a `(start pfn, number of pages)` block can be addressed,
+// which wide blocks do not guarantee.
+fn block_addressable(block: (u64, u64)) -> bool {
+    block.0.checked_add(block.1).map_or(false, |end| {
+        end <= u64::MAX >> VIRTIO_FAASCALE_MEM_PFN_SHIFT
+    })
+}
+
+// Reads the `(start pfn, number of pages, flags)` block at `addr`, keeping only the
+// `block_flags` of the guest. The flags of a wide block are in the upper half of its page
+// count, at the same positions as in the page count of a narrow block.
+fn read_block(
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    wide_blocks: bool,
+    block_flags: u32,
+) -> Result<(u64, u64, u32), FaascaleMemError> {
+    if wide_blocks {
+        let block = mem
+            .read_obj::<[u64; 2]>(addr)
+            .map_err(|_| FaascaleMemError::MalformedDescriptor)?;
+        let flags = (block[1] >> 32) as u32 & block_flags;
+        Ok((block[0], block[1] & !(u64::from(flags) << 32), flags))
+    } else {
+        let block = mem
+            .read_obj::<[u32; 2]>(addr)
+            .map_err(|_| FaascaleMemError::MalformedDescriptor)?;
+        let flags = block[1] & block_flags;
+        Ok((u64::from(block[0]), u64::from(block[1] & !flags), flags))
+    }
+}
+
+// Reads the block of a request at `addr`, followed by its trace ID when `block_size` leaves room
+// for one. A zero trace ID leaves the block untraced.
+fn read_request_block(
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    block_size: usize,
+    wide_blocks: bool,
+    block_flags: u32,
+) -> Result<(u64, u64, u32, TraceId), FaascaleMemError> {
+    let (pfn, page_count, flags) = read_block(mem, addr, wide_blocks, block_flags)?;
+    let block_info_size = if wide_blocks {
+        SIZE_OF_WIDE_BLOCK_INFO
+    } else {
+        SIZE_OF_BLOCK_INFO
+    };
+    if block_size == block_info_size {
+        return Ok((pfn, page_count, flags, TraceId::default()));
+    }
+    let trace_addr = addr
+        .checked_add(block_info_size as u64)
+        .ok_or(FaascaleMemError::MalformedDescriptor)?;
+    let trace_id = mem
+        .read_obj::<u64>(trace_addr)
+        .map_err(|_| FaascaleMemError::MalformedDescriptor)?;
+    let trace_id = TraceId((trace_id != 0).then_some(trace_id));
+    Ok((pfn, page_count, flags, trace_id))
+}
+
+// Keeps the first failure of a request, which is the status the guest is told.
+fn fail_request(status: &mut u32, failure: u32) {
+    if *status == VIRTIO_FAASCALE_MEM_STATUS_OK {
+        *status = failure;
+    }
+}
+
+// Status of a request whose block could not be populated or depopulated.
+fn region_error_status(err: &RemoveRegionError) -> u32 {
+    match err {
+        RemoveRegionError::AddressTranslation
+        | RemoveRegionError::MalformedRange
+        | RemoveRegionError::MisalignedHugePage
+        | RemoveRegionError::OutsideMemslot(_)
+        | RemoveRegionError::RegionNotFound => VIRTIO_FAASCALE_MEM_STATUS_EINVAL,
+        RemoveRegionError::EncryptionBackend(_)
+        | RemoveRegionError::MadviseFail(_)
+        | RemoveRegionError::MmapFail(_)
+        | RemoveRegionError::Userfaultfd(_) => VIRTIO_FAASCALE_MEM_STATUS_ENOMEM,
+    }
+}
+
+// Writes the status of a request into the writable descriptor chained after its blocks, and
+// returns the number of bytes written. The guest is told nothing without such a descriptor.
+fn write_request_status(mem: &GuestMemoryMmap, head: &DescriptorChain, status: u32) -> u32 {
+    match head.next_descriptor() {
+        Some(desc) if desc.is_write_only() && desc.len as usize >= SIZE_OF_STATUS => {
+            match mem.write_obj(status, desc.addr) {
+                Ok(()) => SIZE_OF_STATUS as u32,
+                Err(err) => {
+                    METRICS.faascale_mem.request_status_fails.inc();
+                    error!("faascale-mem: error writing request status: {:?}", err);
+                    0
+                }
+            }
+        }
+        _ => {
+            METRICS.faascale_mem.request_status_fails.inc();
+            error!(
+                "faascale-mem: request without a status descriptor, status {} dropped",
+                status
+            );
+            0
+        }
+    }
+}
+
+// Takes the tokens of a request of `len` bytes of blocks at `addr`: an operation per block and
+// the guest memory the blocks span. Nothing is taken when either runs out.
+fn rate_limit_request(
+    rate_limiter: &mut RateLimiter,
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: usize,
+    block_size: usize,
+    wide_blocks: bool,
+    block_flags: u32,
+) -> Result<bool, FaascaleMemError> {
+    let num_blocks = (len / block_size) as u64;
+    if !rate_limiter.consume(num_blocks, TokenType::Ops) {
+        return Ok(false);
+    }
+    // The blocks are only read twice when the bandwidth is limited.
+    if rate_limiter.bandwidth().is_none() {
+        return Ok(true);
+    }
+    let mut bytes = 0u64;
+    for index in (0..len).step_by(block_size) {
+        let block_addr = addr
+            .checked_add(index as u64)
+            .ok_or(FaascaleMemError::MalformedDescriptor)?;
+        let (_, page_count, _) = read_block(mem, block_addr, wide_blocks, block_flags)?;
+        bytes = bytes.saturating_add(page_count << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    }
+    if !rate_limiter.consume(bytes, TokenType::Bytes) {
+        rate_limiter.manual_replenish(num_blocks, TokenType::Ops);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+// Zero-fills the recycled `(start pfn, number of pages)` blocks of a populated block.
+fn zero_recycled(mem: &GuestMemoryMmap, recycled: &[(u64, u64)], trace_id: TraceId) {
+    for &block in recycled {
+        if let Err(err) = zero_range(mem, block_range(block)) {
+            error!(
+                "Error zeroing recycled block: start_pfn={}, size={}: {:?}{}",
+                block.0, block.1, err, trace_id
+            );
+        }
+    }
+}
+
+// Releases a block with the advice of `mode`, once the encryption backend of an encrypted guest
+// gave it back. The block is unlocked first if it was locked, the chunks of the reserved pool
+// backing the block are reserved again, the block is unregistered from the lazy population, and
+// it is given the KSM advice of the depopulated blocks.
+#[allow(clippy::too_many_arguments)]
+fn release_block(
+    mem: &GuestMemoryMmap,
+    block: (u64, u64),
+    encryption_backend: Option<&mut (dyn EncryptedMemoryBackend + 'static)>,
+    pool: Option<&mut HostMemoryPool>,
+    overlays: Option<&mut MmapOverlays>,
+    mode: FaascaleMemDepopulateMode,
+    ksm: KsmAdvice,
+    mlock: Option<&mut BlockMlock>,
+    lazy_populate: Option<&LazyPopulate>,
+) -> Result<(), RemoveRegionError> {
+    // Locked pages cannot be dropped.
+    if let Some(mlock) = mlock {
+        mlock.unlock(mem, block);
+    }
+    if let Some(backend) = encryption_backend {
+        backend
+            .depopulate(mem, block_range(block))
+            .map_err(RemoveRegionError::EncryptionBackend)?;
+    }
+    if let Some(pool) = pool {
+        pool.depopulate(mem, block_range(block));
+    }
+    remove_range(mem, block_range(block), overlays, mode)?;
+    // The block is no longer backed on its next touch, whether it was populated lazily or not.
+    if let Some(lazy_populate) = lazy_populate {
+        lazy_populate.unregister(mem, block_range(block));
+    }
+    // The pages are gone already, or only kept for the host to reclaim, so nothing is unmerged.
+    if let Some(mergeable) = ksm.depopulated() {
+        advise_ksm(mem, block, mergeable);
+    }
+    Ok(())
+}
+
+// KSM advice of the guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum KsmAdvice {
+    // KSM leaves the guest memory alone.
+    None,
+    // The populated blocks are mergeable.
+    Populated,
+    // The guest memory is mergeable but for the populated blocks, which the guest uses.
+    Idle,
+}
+
+impl KsmAdvice {
+    fn new(ksm_mergeable: bool, ksm_idle: bool) -> Result<Self, FaascaleMemError> {
+        match (ksm_mergeable, ksm_idle) {
+            (false, false) => Ok(KsmAdvice::None),
+            (true, false) => Ok(KsmAdvice::Populated),
+            (false, true) => Ok(KsmAdvice::Idle),
+            (true, true) => Err(FaascaleMemError::KsmMergeableWithIdle),
+        }
+    }
+
+    // Whether the blocks are advised mergeable once populated, if advised at all.
+    fn populated(self) -> Option<bool> {
+        match self {
+            KsmAdvice::None => None,
+            KsmAdvice::Populated => Some(true),
+            KsmAdvice::Idle => Some(false),
+        }
+    }
+
+    // Whether the blocks are advised mergeable once depopulated, if advised at all.
+    fn depopulated(self) -> Option<bool> {
+        self.populated().map(|mergeable| !mergeable)
+    }
+}
+
+// Advises the `(start pfn, number of pages)` block mergeable or unmergeable for KSM. A failed
+// advice only costs the deduplication of the block.
+fn advise_ksm(mem: &GuestMemoryMmap, block: (u64, u64), mergeable: bool) {
+    if let Err(err) = advise_mergeable(mem, block_range(block), mergeable) {
+        METRICS.faascale_mem.ksm_advise_fails.inc();
+        error!(
+            "Error advising block for KSM: start_pfn={}, size={}, mergeable={}: {:?}",
+            block.0, block.1, mergeable, err
+        );
+    }
+}
+
 #[repr(C)] /// #[repr(C)] 表示按照 C 语言的内存布局方式对结构体进行排列
 /// 这是 Rust 中的一个派生宏（derive macro）的示例，这个宏会自动为一个结构体或者枚举类型实现一些常用的 trait 方法。
 /// 具体来说，这个宏实现了 Clone、Copy、Debug、Default 和 PartialEq 这几个 trait。其中：
@@ -55,6 +381,11 @@ pub(crate) struct ConfigSpace {
     /// pub(crate) 表示这个结构体只能在当前 crate 中被公开访问，对于外部 crate 不可见
     pub num_pages: u32,
     pub actual_pages: u32,
+    // The budget offered to the guest, in 4K pages, and the epoch tagging the offer.
+    pub budget_pages: u32,
+    pub budget_epoch: u32,
+    // Memory the host asks the guest to release, in 4K pages.
+    pub release_pages: u32,
 }
 
 // SAFETY: Safe because ConfigSpace only contains plain data.
@@ -84,6 +415,8 @@ unsafe impl ByteValued for ConfigSpace {}
 /// const VIRTIO_FAASCALE_MEM_S_CACHES: u16 = 7;
 /// const VIRTIO_FAASCALE_MEM_S_HTLB_PGALLOC: u16 = 8;
 /// const VIRTIO_FAASCALE_MEM_S_HTLB_PGFAIL: u16 = 9;
+/// const VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG10: u16 = 10;
+/// const VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG60: u16 = 11;
 struct FaascaleMemStat {
     pub tag: u16,
     pub val: u64,
@@ -101,6 +434,94 @@ pub struct FaascaleMemConfig {
     pub stats_polling_interval_s: u16, // 轮询统计信息的时间间隔（以秒为单位）
     pub pre_alloc_mem: bool,
     pub pre_tdp_fault: bool,
+    pub thp_policy: FaascaleMemThpPolicy,
+    pub populate_tracker_max_entries: u32,
+    pub latency_mode: bool,
+    pub perf_sampling: bool,
+    pub budget_mib: Option<u32>,
+    pub experiment: Option<FaascaleMemExperiment>,
+    pub pool: Option<FaascaleMemPoolConfig>,
+    pub strict_stats: bool,
+    pub stats_polling_min_interval_ms: Option<u32>,
+    pub max_populated_mib: Option<u32>,
+    pub block_cache_mib: Option<u32>,
+    pub interleave: Option<FaascaleMemInterleaveConfig>,
+    pub numa_node: Option<u32>,
+    pub depopulate_mode: FaascaleMemDepopulateMode,
+    pub mlock_budget_mib: Option<u32>,
+    pub rate_limiter: Option<RateLimiterConfig>,
+    pub mmds_publish: bool,
+    pub spill_path: Option<PathBuf>,
+    pub scrub_on_populate: bool,
+    pub complete_leaked_descriptors: bool,
+    pub interrupt_moderation: bool,
+    pub policy: Option<FaascaleMemPolicyConfig>,
+    pub populate_verification: bool,
+    pub ksm_mergeable: bool,
+    pub ksm_idle: bool,
+    pub worker_scheduling: Option<FaascaleMemWorkerSchedulingConfig>,
+    pub vsock_observer_port: Option<u32>,
+    pub template_path: Option<PathBuf>,
+    pub populate_mode: FaascaleMemPopulateMode,
+    pub depopulate_on_reset: bool,
+    pub config_epoch: u64,
+    pub populated_mib: u64,
+    pub target_mib: u32,
+    pub boot_warmup: Option<FaascaleMemBootWarmup>,
+    pub stats_polling_adaptation: Option<FaascaleMemPollingAdaptation>,
+    pub capabilities: Option<FaascaleMemCapabilities>,
+    pub policy_decision: Option<FaascaleMemPolicyDecision>,
+    pub applied_worker_scheduling: Option<FaascaleMemWorkerScheduling>,
+}
+
+/// Transparent huge page advice given to every populated block, instead of relying on the
+/// host defaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FaascaleMemThpPolicy {
+    /// Leave the backing of populated blocks to the host defaults.
+    #[default]
+    System,
+    /// Advise the huge page aligned part of every block with `MADV_HUGEPAGE`, so that it is
+    /// backed by huge pages when faulted in.
+    Always,
+    /// Advise every block with `MADV_NOHUGEPAGE`.
+    Never,
+    /// Advise the blocks covering at least one huge page like `Always`, and the smaller ones
+    /// like `Never`.
+    Threshold,
+    /// Like `Always`, and also ask the kernel to synchronously collapse the block into huge
+    /// pages with `MADV_COLLAPSE` once it is populated.
+    Collapse,
+}
+
+/// Advice releasing the host memory of the blocks the guest depopulates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FaascaleMemDepopulateMode {
+    /// Free the pages right away with `MADV_DONTNEED`, the guest finds them zero-filled when
+    /// populated again.
+    #[default]
+    DontNeed,
+    /// Free the pages lazily with `MADV_FREE`, once the host is short of memory. The pages
+    /// reused before then keep their contents, sparing the faults of a quick repopulation.
+    Free,
+    /// Only deactivate the pages with `MADV_COLD`, making them the first to be reclaimed.
+    Cold,
+    /// Reclaim the pages right away with `MADV_PAGEOUT`, writing them to swap.
+    Pageout,
+}
+
+/// How fresh the faascale-mem statistics served by the host are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaascaleMemStatsFreshness {
+    /// Reported by the guest since the device was created or restored.
+    #[default]
+    Live,
+    /// Reported by the guest before the snapshot the microVM was restored from.
+    RestoredStale,
+    /// Estimated by the host from the memory populated by the guest, as the snapshot held no
+    /// report of the guest.
+    Simulated,
 }
 
 // FaascaleMemStats holds statistics returned from the stats_queue.
@@ -137,6 +558,48 @@ pub struct FaascaleMemStats {
     pub hugetlb_allocations: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hugetlb_failures: Option<u64>,
+    /// Share of time some task of the guest stalled on memory over the last 10 and 60 seconds,
+    /// in hundredths of a percent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_pressure_some_avg10: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_pressure_some_avg60: Option<u64>,
+    /// Time elapsed between the last two samples of the guest statistics, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_interval_ms: Option<u64>,
+    /// Growth of the swap and page fault counters since the previous sample.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_in_delta: Option<CounterDelta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_out_delta: Option<CounterDelta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub major_faults_delta: Option<CounterDelta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minor_faults_delta: Option<CounterDelta>,
+    /// Number of populated blocks the host advised the 4 KiB or the 2 MiB granularity the guest
+    /// asked for. The advice does not guarantee how the block ends up backed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub advised_4k_blocks: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub advised_2m_blocks: Option<u64>,
+    /// Number of populated blocks that could not be advised the granularity the guest asked for,
+    /// which includes every 1 GiB hint.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub granularity_fallbacks: Option<u64>,
+    /// Latency histograms of the blocks populated on the host side.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub populate_latency: Option<FaascaleMemPopulateLatency>,
+    /// Whether the guest reported the statistics since the microVM was restored.
+    pub freshness: FaascaleMemStatsFreshness,
+    /// Number of populated blocks holding the canary once written, and of the ones that did
+    /// not, when the populate verification is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub populate_verified_blocks: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub populate_verification_failures: Option<u64>,
+    /// Measurements of the population policy experiment, per variant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<FaascaleMemExperimentResults>,
 }
 
 impl FaascaleMemStats {
@@ -155,6 +618,8 @@ impl FaascaleMemStats {
             VIRTIO_FAASCALE_MEM_S_CACHES => self.disk_caches = val,
             VIRTIO_FAASCALE_MEM_S_HTLB_PGALLOC => self.hugetlb_allocations = val,
             VIRTIO_FAASCALE_MEM_S_HTLB_PGFAIL => self.hugetlb_failures = val,
+            VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG10 => self.memory_pressure_some_avg10 = val,
+            VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG60 => self.memory_pressure_some_avg60 = val,
             _ => {
                 return Err(FaascaleMemError::MalformedPayload);
             }
@@ -162,6 +627,202 @@ impl FaascaleMemStats {
 
         Ok(())
     }
+
+    /// Computes the growth of the cumulative counters since the `previous` sample, taken
+    /// `interval` before this one.
+    fn update_deltas(&mut self, previous: &Self, interval: Duration) {
+        self.sample_interval_ms = Some(u64::try_from(interval.as_millis()).unwrap_or(u64::MAX));
+        self.swap_in_delta = CounterDelta::between(previous.swap_in, self.swap_in, interval);
+        self.swap_out_delta = CounterDelta::between(previous.swap_out, self.swap_out, interval);
+        self.major_faults_delta =
+            CounterDelta::between(previous.major_faults, self.major_faults, interval);
+        self.minor_faults_delta =
+            CounterDelta::between(previous.minor_faults, self.minor_faults, interval);
+    }
+
+    // Whether a block was populated following a granularity hint of the guest.
+    fn has_granularity_counts(&self) -> bool {
+        self.advised_4k_blocks.is_some()
+            || self.advised_2m_blocks.is_some()
+            || self.granularity_fallbacks.is_some()
+    }
+
+    fn record_granularity(&mut self, granularity: BlockGranularity, advised: bool) {
+        let count = match (granularity, advised) {
+            (BlockGranularity::Page4K, true) => &mut self.advised_4k_blocks,
+            (BlockGranularity::Huge2M, true) => &mut self.advised_2m_blocks,
+            (BlockGranularity::Huge1G, _) | (_, false) => &mut self.granularity_fallbacks,
+        };
+        *count.get_or_insert(0) += 1;
+    }
+
+    fn record_populate_latency(&mut self, total: Duration, timings: PopulateTimings) {
+        self.populate_latency
+            .get_or_insert_with(FaascaleMemPopulateLatency::default)
+            .record(total, timings);
+    }
+
+    fn record_pre_tdp_fault_latency(&mut self, duration: Duration) {
+        self.populate_latency
+            .get_or_insert_with(FaascaleMemPopulateLatency::default)
+            .record_pre_tdp_fault(duration);
+    }
+
+    fn record_populate_verification(&mut self, verified: bool) {
+        let count = if verified {
+            &mut self.populate_verified_blocks
+        } else {
+            &mut self.populate_verification_failures
+        };
+        *count.get_or_insert(0) += 1;
+    }
+}
+
+/// Backing granularity the guest asks for a populated block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BlockGranularity {
+    Page4K,
+    Huge2M,
+    Huge1G,
+}
+
+impl BlockGranularity {
+    // Reads the hint carried by the page count of a block.
+    fn from_page_count(page_count: u32) -> Option<Self> {
+        match (page_count & VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_MASK)
+            >> VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_SHIFT
+        {
+            VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_4K => Some(BlockGranularity::Page4K),
+            VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_2M => Some(BlockGranularity::Huge2M),
+            VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_1G => Some(BlockGranularity::Huge1G),
+            _ => None,
+        }
+    }
+}
+
+// A block to populate, asked for by the guest or populated by the host ahead of it.
+#[derive(Clone, Copy, Debug)]
+struct PopulateRequest {
+    block: (u64, u64),
+    trace_id: TraceId,
+    // Flags the guest set on the block.
+    pin: bool,
+    lock: bool,
+    granularity: Option<BlockGranularity>,
+    // Only the guest is held to the budget and counts towards the boot warmup.
+    from_guest: bool,
+}
+
+impl PopulateRequest {
+    // A block the host populates ahead of the guest.
+    fn host(block: (u64, u64)) -> Self {
+        PopulateRequest {
+            block,
+            trace_id: TraceId::default(),
+            pin: false,
+            lock: false,
+            granularity: None,
+            from_guest: false,
+        }
+    }
+}
+
+/// faascale-mem config space as the guest reads it. Besides the target and actual sizes, it
+/// holds the budget offer and the release request written by the host, to check which of them
+/// the guest saw.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemConfigSpace {
+    /// Bytes of the config space, in hexadecimal, in the order the guest reads them.
+    pub raw: String,
+    /// Target number of 4K pages, written by the host.
+    pub num_pages: u32,
+    /// Number of 4K pages, written by the guest.
+    pub actual_pages: u32,
+    /// Budget offered to the guest in 4K pages, written by the host.
+    pub budget_pages: u32,
+    /// Epoch tagging the budget offer, written by the host.
+    pub budget_epoch: u32,
+    /// Number of 4K pages the guest is asked to release, written by the host.
+    pub release_pages: u32,
+}
+
+/// Outcome of a single internal consistency check of the device.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemHealthCheck {
+    /// Name of the check.
+    pub name: String,
+    /// Whether the check passed.
+    pub passed: bool,
+    /// Details about a failed check.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Host memory used by the guest through the device, as reported by the API.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemFootprint {
+    /// Memory populated by the guest, in MiB.
+    pub populated_mib: u64,
+    /// Memory pinned against depopulation, in MiB.
+    pub pinned_mib: u64,
+    /// Whether the device is fenced, refusing to populate more memory.
+    pub fenced: bool,
+    /// Usage of the reserved host memory pool.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool: Option<FaascaleMemPoolUsage>,
+    /// Populated memory pre-allocated interleaved across NUMA nodes, in MiB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interleaved_mib: Option<u64>,
+    /// Host memory locked on behalf of the microVM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mlock: Option<FaascaleMemMlockUsage>,
+    /// Contents of the depopulated memory held by the spill file, in MiB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spilled_mib: Option<u64>,
+    /// Guest memory the host backs with transparent huge pages, in MiB, as sampled on the
+    /// statistics polling interval. Absent until the statistics are first polled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thp_backed_mib: Option<u64>,
+}
+
+/// Guest memory populated through the device, as reported by the API.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemBlocks {
+    /// Memory populated by the guest, in MiB.
+    pub populated_mib: u64,
+    /// Populated blocks in ascending order, adjacent blocks are merged.
+    pub blocks: Vec<FaascaleMemBlock>,
+}
+
+/// A block of guest memory populated through the device.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemBlock {
+    /// First guest page frame of the block.
+    pub start_pfn: u64,
+    /// Number of pages in the block.
+    pub num_pages: u64,
+}
+
+/// Health report built from the device internal consistency checks.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemHealth {
+    /// Whether all the checks passed.
+    pub healthy: bool,
+    pub checks: Vec<FaascaleMemHealthCheck>,
+    /// Conditions worth a look which do not make the device unhealthy, e.g. a guest slow to
+    /// acknowledge a config space change.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl FaascaleMemHealth {
+    fn add_check(&mut self, name: &str, passed: bool, detail: impl FnOnce() -> String) {
+        self.checks.push(FaascaleMemHealthCheck {
+            name: name.to_string(),
+            passed,
+            detail: if passed { None } else { Some(detail()) },
+        });
+    }
 }
 
 // Virtio FaascaleMem device.
@@ -189,32 +850,272 @@ pub struct FaascaleMem {
     pub(crate) restored: bool,
     pub(crate) pre_alloc_mem: bool,
     pub(crate) pre_tdp_fault: bool,
+    pub(crate) thp_policy: FaascaleMemThpPolicy,
     pub(crate) stats_polling_interval_s: u16,
-    pub(crate) stats_timer: TimerFd,
+    // Shortens the polling interval while the available memory of the guest drops quickly.
+    pub(crate) polling_adaptation: Option<PollingAdaptation>,
+    pub(crate) stats_timer: Box<dyn Timer>,
     // The index of the previous stats descriptor is saved because
     // it is acknowledged after the stats queue is processed.
     pub(crate) stats_desc_index: Option<u16>,
     pub(crate) latest_stats: FaascaleMemStats,
+    // When the latest statistics sample was processed.
+    pub(crate) last_stats_sample: Option<Instant>,
+    // Guest memory the host backs with transparent huge pages, sampled on the stats timer
+    // rather than on every footprint request as it walks every mapping of the process.
+    pub(crate) thp_backed_bytes: Option<u64>,
+    // Number of successful runtime configuration updates.
+    pub(crate) config_epoch: u64,
+    // Blocks populated recently, used to drop populate requests re-submitted by the guest.
+    pub(crate) populate_tracker: PopulateTracker,
+    // Blocks that must stay populated, depopulating them is refused.
+    pub(crate) pinned_ranges: PfnRanges,
+    // Blocks pinned through the API, which outlive the sessions of the guest driver.
+    pub(crate) host_pinned_ranges: PfnRanges,
+    // Blocks populated by the guest and not depopulated since, counted against the budget.
+    pub(crate) populated_ranges: PfnRanges,
+    // Populate and depopulate activity over the guest physical address space.
+    heatmap: ActivityHeatmap,
+    // Recent populate and depopulate failures.
+    error_log: ErrorLog,
+    // Memory budget negotiated with the guest.
+    pub(crate) budget: BudgetNegotiation,
+    // Cap set by the host on the populated pages, enforced whatever the guest agreed on.
+    pub(crate) max_populated_pages: Option<u64>,
+    // Whether the populate queue is handled by a dedicated thread instead of the event loop.
+    pub(crate) latency_mode: bool,
+    // When the populate queue kick being handled was noticed.
+    pub(crate) populate_kicked_at: Option<Instant>,
+    // Whether the queues are left unprocessed until the device is resumed.
+    pub(crate) quiesced: bool,
+    // Whether the populate requests are refused, the guest memory can only shrink.
+    pub(crate) fenced: bool,
+    // Populate activity of the guest right after boot.
+    pub(crate) boot_warmup: BootWarmupTracker,
+    // Number of times the guest driver reset the device to start a new session.
+    pub(crate) driver_resets: u64,
+    // Whether a statistics descriptor holding an unknown tag is rejected, instead of skipping
+    // the tag.
+    pub(crate) strict_stats: bool,
+    // Anonymous mappings laid over the guest memory of a restored microVM.
+    pub(crate) mmap_overlays: MmapOverlays,
+    // Pause state of the microVM, the queues are left unprocessed while it is paused.
+    pub(crate) pause_gate: VmPauseGate,
+    // Whether the performance counters are sampled around the TDP pre-fault.
+    pub(crate) perf_sampling: bool,
+    // KVM VM the TDP pre-faults are issued on, handed over when the device is attached.
+    pub(crate) vm_fd: Option<Arc<VmFd>>,
+    // Opened once the vCPU threads are started, before the seccomp filters are loaded.
+    pub(crate) prefault_sampler: Option<PrefaultSampler>,
+    // Populated blocks whose TDP faults are pre-handled once the queue is drained.
+    pub(crate) prefault_batch: PrefaultBatch,
+    // Population policy experiment overriding `pre_alloc_mem` and `pre_tdp_fault`.
+    pub(crate) experiment: Option<ExperimentSplitter>,
+    // Pieces of huge pages depopulated by the guest, held back until the whole huge page is.
+    // Only used with a THP placement policy.
+    pub(crate) depopulate_batcher: Option<DepopulateBatcher>,
+    // Expires when the oldest pieces held back are held back for too long.
+    pub(crate) depopulate_batch_timer: Box<dyn Timer>,
+    // Population path of the memory of an encrypted guest, replacing the plain one.
+    pub(crate) encryption_backend: Option<Box<dyn EncryptedMemoryBackend>>,
+    // Host memory reserved to back the populated blocks first.
+    pub(crate) pool: Option<HostMemoryPool>,
+    // Compressed contents of the blocks depopulated by the guest, restored when it populates
+    // them again.
+    pub(crate) block_cache: Option<BlockCache>,
+    // NUMA interleaving of the large blocks pre-allocated on population.
+    pub(crate) interleave: Option<BlockInterleave>,
+    // Host NUMA node the populated blocks are placed on, the one the vCPUs are pinned to.
+    pub(crate) numa_node: Option<u32>,
+    // Advice releasing the host memory of the depopulated blocks.
+    pub(crate) depopulate_mode: FaascaleMemDepopulateMode,
+    // Blocks locked into host memory, within the locking budget.
+    pub(crate) mlock: Option<BlockMlock>,
+    // Throughput of the populate and depopulate requests of the guest. The throttled requests
+    // are left in their queue until the rate limiter replenishes.
+    pub(crate) rate_limiter: RateLimiter,
+    // Whether the memory state is published into MMDS, once the data store is set.
+    pub(crate) mmds_publish: bool,
+    // Publishes the memory state into the MMDS data store of the microVM.
+    pub(crate) mmds_publisher: Option<MmdsPublisher>,
+    // Contents of the blocks depopulated by the guest, written to a file and read back when it
+    // populates them again.
+    pub(crate) spill_file: Option<SpillFile>,
+    // Whether the populated blocks are zeroed explicitly.
+    pub(crate) scrub_on_populate: bool,
+    // Descriptors popped from the populate, depopulate and control queues and not returned to
+    // the guest yet.
+    pub(crate) leak_tracker: DescriptorLeakTracker,
+    // Whether the leaked descriptors are returned to the guest, with an error status.
+    pub(crate) complete_leaked_descriptors: bool,
+    // Whether a canary is written at the head of the populated blocks and read back, a
+    // debugging aid altering the guest memory.
+    pub(crate) populate_verification: bool,
+    // Which of the guest memory is advised mergeable, for KSM to deduplicate its pages with the
+    // identical pages of other microVMs.
+    pub(crate) ksm: KsmAdvice,
+    // Whether the memory populated by a guest driver is depopulated when the driver resets the
+    // device, for guests whose next driver starts without any populated memory.
+    pub(crate) depopulate_on_reset: bool,
+    // Host support for the system calls used by the device, once probed.
+    pub(crate) capabilities: Option<FaascaleMemCapabilities>,
+    // Moderation of the notifications of the depopulate queue.
+    pub(crate) irq_moderator: InterruptModerator,
+    // Program run on every statistics update, deciding the populate cap, the release requests
+    // and the budget.
+    pub(crate) policy: Option<Policy>,
+    // Decisions of the latest successful run of the policy program.
+    pub(crate) policy_decision: Option<FaascaleMemPolicyDecision>,
+    // Memory populated by the device and the time it took, the rate capacity estimates are
+    // made at.
+    pub(crate) populate_throughput: PopulateThroughput,
+    // Time source of the statistics timer, the interrupt moderation, the depopulation batching,
+    // the boot warmup and the statistics deltas.
+    pub(crate) clock: Arc<dyn Clock>,
+    // Scheduling knobs of the populate poller, and their values once the poller applied them.
+    pub(crate) worker_scheduling: Option<FaascaleMemWorkerSchedulingConfig>,
+    pub(crate) applied_worker_scheduling: Option<FaascaleMemWorkerScheduling>,
+    // Threads serving the device outside the VMM event loop, by name, joined on drop.
+    pub(crate) worker_threads: Vec<(&'static str, thread::JoinHandle<()>)>,
+    // Written on drop to wake the worker threads up, so that they notice the device is gone.
+    pub(crate) worker_stop_evt: EventFd,
+    // Host vsock port the guest connects to for the memory activity, if served.
+    pub(crate) vsock_observer_port: Option<u32>,
+    // Populate and depopulate requests of the guest, as answered on the observer port.
+    pub(crate) request_activity: RequestActivity,
+    // Memory file of a template microVM the populated blocks are filled from.
+    pub(crate) memory_template: Option<MemoryTemplate>,
+    // Userfaultfd the blocks are registered with in the lazy populate mode, backed on their
+    // first touch.
+    pub(crate) lazy_populate: Option<LazyPopulate>,
+    // Config space changes the guest was notified of and has not acknowledged yet.
+    pub(crate) config_acks: ConfigAckTracker,
 }
 
 impl FaascaleMem {
+    /// Creates the device configured by `cfg`, whose guest requests are limited by
+    /// `rate_limiter`. A `restored` device gets the rest of its state from a snapshot.
     pub fn new(
-        stats_polling_interval_s: u16,
+        cfg: &FaascaleMemDeviceConfig,
         restored: bool,
-        pre_alloc_mem: bool,
-        pre_tdp_fault: bool
+        rate_limiter: RateLimiter,
     ) -> Result<FaascaleMem, FaascaleMemError> {
-        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let FaascaleMemDeviceConfig {
+            stats_polling_interval_s,
+            pre_alloc_mem,
+            pre_tdp_fault,
+            thp_policy,
+            populate_tracker_max_entries,
+            latency_mode,
+            perf_sampling,
+            budget_mib,
+            experiment,
+            pool,
+            strict_stats,
+            stats_polling_min_interval_ms,
+            max_populated_mib,
+            block_cache_mib,
+            interleave,
+            depopulate_mode,
+            mlock_budget_mib,
+            mmds_publish,
+            scrub_on_populate,
+            complete_leaked_descriptors,
+            interrupt_moderation,
+            policy,
+            populate_verification,
+            numa_node,
+            worker_scheduling,
+            ksm_idle,
+            spill_path,
+            vsock_observer_port,
+            template_path,
+            ksm_mergeable,
+            populate_mode,
+            depopulate_on_reset,
+            ..
+        } = cfg.clone();
+        let populate_tracker_max_entries = populate_tracker_max_entries
+            .map_or(POPULATE_TRACKER_MAX_ENTRIES, |max_entries| {
+                max_entries as usize
+            });
+        if block_cache_mib.is_some() && spill_path.is_some() {
+            return Err(FaascaleMemError::SpillWithBlockCache);
+        }
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1
+            | 1u64 << VIRTIO_RING_F_EVENT_IDX
+            | 1u64 << VIRTIO_FAASCALE_MEM_F_BLOCK_GRANULARITY
+            | 1u64 << VIRTIO_FAASCALE_MEM_F_TRACE_IDS
+            | 1u64 << VIRTIO_FAASCALE_MEM_F_WIDE_BLOCKS
+            | 1u64 << VIRTIO_FAASCALE_MEM_F_STATUS
+            | 1u64 << VIRTIO_FAASCALE_MEM_F_PIN
+            // The statistics queue is always offered, so that the statistics can be enabled
+            // after boot. It stays inert while the polling interval is 0.
+            | 1u64 << VIRTIO_FAASCALE_MEM_F_STATS_VQ;
+        // The cached, spilled or template contents replace the zero-filled pages, which cannot be
+        // promised then. Neither can they when the depopulate mode keeps the old page contents.
+        if block_cache_mib.is_none()
+            && spill_path.is_none()
+            && template_path.is_none()
+            && depopulate_mode == FaascaleMemDepopulateMode::DontNeed
+        {
+            avail_features |= 1u64 << VIRTIO_FAASCALE_MEM_F_ZEROED;
+        }
+
+        // The budget configured at boot is the first offer made to the guest.
+        let mut budget = BudgetNegotiation::default();
+        let mut config_space = ConfigSpace::default();
+        if let Some(budget_mib) = budget_mib {
+            avail_features |= 1u64 << VIRTIO_FAASCALE_MEM_F_BUDGET;
+            config_space.budget_pages = budget_mib
+                .checked_mul(MIB_TO_4K_PAGES)
+                .ok_or(FaascaleMemError::TooManyPagesRequested)?;
+            config_space.budget_epoch = budget.offer(config_space.budget_pages);
+        }
+        // The guest may only flag the blocks it wants locked within a budget.
+        if mlock_budget_mib.is_some() {
+            avail_features |= 1u64 << VIRTIO_FAASCALE_MEM_F_MLOCK;
+        }
 
-        if stats_polling_interval_s > 0 {
-            avail_features |= 1u64 << VIRTIO_FAASCALE_MEM_F_STATS_VQ;
+        let experiment = experiment.map(ExperimentSplitter::new).transpose()?;
+        if let Some(node) = numa_node {
+            check_numa_node(node)?;
         }
+        if let Some(scheduling) = worker_scheduling.as_ref() {
+            scheduling.validate()?;
+        }
+        let polling_adaptation = stats_polling_min_interval_ms.map(|min_ms| {
+                PollingAdaptation::new(
+                    Duration::from_secs(u64::from(stats_polling_interval_s)),
+                    Duration::from_millis(u64::from(min_ms)),
+                )
+            });
+        let interleave = interleave.map(BlockInterleave::new).transpose()?;
+        let pool = pool.map(HostMemoryPool::new).transpose()?;
+        let spill_file = spill_path
+            .map(SpillFile::new)
+            .transpose()
+            .map_err(FaascaleMemError::SpillFile)?;
+        let memory_template = template_path
+            .map(MemoryTemplate::new)
+            .transpose()
+            .map_err(FaascaleMemError::TemplateFile)?;
+        let ksm = KsmAdvice::new(ksm_mergeable, ksm_idle)?;
+        let lazy_populate = match populate_mode {
+            FaascaleMemPopulateMode::Eager => None,
+            FaascaleMemPopulateMode::Lazy => Some(LazyPopulate::new()?),
+        };
+        let policy = policy
+            .map(Policy::new)
+            .transpose()
+            .map_err(FaascaleMemError::InvalidPolicy)?;
 
         // 给每个队列挂上一个eventFD，和pistache中的队列设计完全一样
         let queue_evts = [
             EventFd::new(libc::EFD_NONBLOCK).map_err(FaascaleMemError::EventFd)?,
             EventFd::new(libc::EFD_NONBLOCK).map_err(FaascaleMemError::EventFd)?,
             EventFd::new(libc::EFD_NONBLOCK).map_err(FaascaleMemError::EventFd)?,
+            EventFd::new(libc::EFD_NONBLOCK).map_err(FaascaleMemError::EventFd)?,
         ];
 
         // QUEUE_SIZES中记录了每个队列的大小
@@ -223,23 +1124,22 @@ impl FaascaleMem {
         // 最后通过 collect() 方法将转换后的所有实例收集到一个 Vec 容器中。
         let mut queues: Vec<Queue> = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
 
-        // The VirtIO specification states that the statistics queue should
-        // not be present at all if the statistics are not enabled.
-        if stats_polling_interval_s == 0 {
-            let _ = queues.remove(FAASCALE_STATS_INDEX);
+        // The control queue is only present when the budget negotiation is enabled.
+        if budget_mib.is_none() {
+            let _ = queues.remove(CONTROL_INDEX);
         }
 
         // TimerFD 时间轮询器
-        let stats_timer =
-            TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(FaascaleMemError::Timer)?;
+        let clock = MonotonicClock::shared();
+        let stats_timer = clock.timer().map_err(FaascaleMemError::Timer)?;
+        let depopulate_batch_timer = clock.timer().map_err(FaascaleMemError::Timer)?;
+        let irq_moderator = InterruptModerator::new(interrupt_moderation, clock.as_ref())
+            .map_err(FaascaleMemError::Timer)?;
 
         Ok(FaascaleMem {
             avail_features,
             acked_features: 0u64,
-            config_space: ConfigSpace {
-                num_pages: 0, // 气球设备的页面数
-                actual_pages: 0, // 气球设备的实际页面数
-            },
+            config_space,
             queue_evts,
             queues,
             irq_trigger: IrqTrigger::new().map_err(FaascaleMemError::EventFd)?,
@@ -250,15 +1150,86 @@ impl FaascaleMem {
             restored,
             pre_alloc_mem,
             pre_tdp_fault,
+            thp_policy,
             stats_polling_interval_s,
+            polling_adaptation,
             stats_timer,
             stats_desc_index: None,
             latest_stats: FaascaleMemStats::default(),
+            last_stats_sample: None,
+            thp_backed_bytes: None,
+            config_epoch: 0,
+            populate_tracker: PopulateTracker::new(populate_tracker_max_entries),
+            pinned_ranges: PfnRanges::default(),
+            host_pinned_ranges: PfnRanges::default(),
+            populated_ranges: PfnRanges::default(),
+            heatmap: ActivityHeatmap::default(),
+            error_log: ErrorLog::default(),
+            budget,
+            max_populated_pages: max_populated_mib
+                .map(|max_mib| u64::from(max_mib) * u64::from(MIB_TO_4K_PAGES)),
+            latency_mode,
+            populate_kicked_at: None,
+            quiesced: false,
+            fenced: false,
+            boot_warmup: BootWarmupTracker::default(),
+            driver_resets: 0,
+            strict_stats,
+            mmap_overlays: MmapOverlays::default(),
+            pause_gate: VmPauseGate::default(),
+            perf_sampling,
+            vm_fd: None,
+            prefault_sampler: None,
+            prefault_batch: PrefaultBatch::default(),
+            experiment,
+            depopulate_batcher: matches!(
+                thp_policy,
+                FaascaleMemThpPolicy::Always
+                    | FaascaleMemThpPolicy::Threshold
+                    | FaascaleMemThpPolicy::Collapse
+            )
+            .then(DepopulateBatcher::default),
+            depopulate_batch_timer,
+            encryption_backend: None,
+            pool,
+            block_cache: block_cache_mib.map(BlockCache::new),
+            interleave,
+            numa_node,
+            depopulate_mode,
+            mlock: mlock_budget_mib.map(BlockMlock::new),
+            rate_limiter,
+            mmds_publish,
+            mmds_publisher: None,
+            spill_file,
+            scrub_on_populate,
+            leak_tracker: DescriptorLeakTracker::default(),
+            complete_leaked_descriptors,
+            populate_verification,
+            ksm,
+            depopulate_on_reset,
+            capabilities: None,
+            irq_moderator,
+            clock,
+            policy,
+            policy_decision: None,
+            populate_throughput: PopulateThroughput::default(),
+            worker_scheduling,
+            applied_worker_scheduling: None,
+            worker_threads: Vec::new(),
+            worker_stop_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(FaascaleMemError::EventFd)?,
+            vsock_observer_port,
+            request_activity: RequestActivity::default(),
+            memory_template,
+            lazy_populate,
+            config_acks: ConfigAckTracker::default(),
         })
     }
 
 
-    pub(crate) fn process_populate_queue_event(&mut self) -> Result<(), FaascaleMemError> {
+    pub(crate) fn process_populate_queue_event(
+        &mut self,
+        kicked_at: Instant,
+    ) -> Result<(), FaascaleMemError> {
         // FaascaleMemError::EventFd 是一个自定义的错误类型，表示 EventFd 的创建和操作失败。
         // map_err(FaascaleMemError::EventFd) 的作用是将可能在 EventFd 创建和操作过程中出现的错误转换为 FaascaleMemError::EventFd 类型的错误。
         // ? 运算符用于在错误出现时快速返回并传播错误，它的作用类似于 try catch 语句。如果结果是 Ok，则该运算符将返回 Ok 中的值，否则将立即返回错误。
@@ -266,6 +1237,11 @@ impl FaascaleMem {
         self.queue_evts[POPULATE_INDEX]
             .read()
             .map_err(FaascaleMemError::EventFd)?;
+        self.acknowledge_config_changes();
+        if self.events_deferred() {
+            return Ok(());
+        }
+        self.populate_kicked_at = Some(kicked_at);
         self.process_populate_queue(POPULATE_INDEX)
     }
 
@@ -273,6 +1249,11 @@ impl FaascaleMem {
         self.queue_evts[DEPOPULATE_INDEX]
             .read()
             .map_err(FaascaleMemError::EventFd)?;
+        self.irq_moderator.kicked(self.clock.now());
+        self.acknowledge_config_changes();
+        if self.events_deferred() {
+            return Ok(());
+        }
         self.process_populate_queue(DEPOPULATE_INDEX)
     }
 
@@ -280,27 +1261,387 @@ impl FaascaleMem {
         self.queue_evts[FAASCALE_STATS_INDEX]
             .read()
             .map_err(FaascaleMemError::EventFd)?;
+        self.acknowledge_config_changes();
+        if self.events_deferred() {
+            return Ok(());
+        }
         self.process_stats_queue()
     }
 
     pub(crate) fn process_stats_timer_event(&mut self) -> Result<(), FaascaleMemError> {
         self.stats_timer.read();
+        if self.events_deferred() {
+            return Ok(());
+        }
+        self.release_expired_depopulations(DEPOPULATE_BATCH_TIMEOUT);
+        self.thp_backed_bytes = self
+            .device_state
+            .mem()
+            .and_then(|mem| thp_backed_bytes(mem).ok());
         self.trigger_stats_update()
     }
 
+    pub(crate) fn process_depopulate_batch_timer_event(&mut self) {
+        self.depopulate_batch_timer.read();
+        // The pieces held back are released even while the microVM is paused, like the guest
+        // asked.
+        self.release_expired_depopulations(DEPOPULATE_BATCH_TIMEOUT);
+    }
+
+    pub(crate) fn process_irq_moderation_event(&mut self) -> Result<(), FaascaleMemError> {
+        // The held notification is raised even while the microVM is paused, its requests are
+        // already complete.
+        if !self.irq_moderator.timer_expired(self.clock.now()) {
+            return Ok(());
+        }
+        METRICS.faascale_mem.irq_moderation_timeouts.inc();
+        self.signal_used_queue()
+    }
+
+    pub(crate) fn process_rate_limiter_event(&mut self) -> Result<(), FaascaleMemError> {
+        METRICS.faascale_mem.rate_limiter_event_count.inc();
+        self.rate_limiter
+            .event_handler()
+            .map_err(FaascaleMemError::RateLimiter)?;
+        if self.events_deferred() {
+            return Ok(());
+        }
+        // There might be enough budget now for the throttled requests. In latency mode the
+        // populate queue is drained by its own thread.
+        if !self.latency_mode {
+            self.process_populate_queue(POPULATE_INDEX)?;
+        }
+        self.process_populate_queue(DEPOPULATE_INDEX)
+    }
+
+    pub(crate) fn process_control_queue_event(&mut self) -> Result<(), FaascaleMemError> {
+        self.queue_evts[self.control_index()]
+            .read()
+            .map_err(FaascaleMemError::EventFd)?;
+        self.acknowledge_config_changes();
+        if self.events_deferred() {
+            return Ok(());
+        }
+        self.process_control_queue()
+    }
+
+    pub(crate) fn process_populate_queue(
+        &mut self,
+        queue_index: usize,
+    ) -> Result<(), FaascaleMemError> {
+        let result = self.drain_populate_queue(queue_index);
+        // A drain bailing out leaves the blocks it populated to pre-fault.
+        self.flush_prefault_batch();
+        self.update_depopulate_batch_timer();
+        self.check_descriptor_leaks(queue_index, self.status_enabled())?;
+        result
+    }
+
+    // Pre-handles the TDP faults of the blocks populated since the last flush, one ioctl per run
+    // of contiguous blocks.
+    fn flush_prefault_batch(&mut self) {
+        if self.prefault_batch.is_empty() {
+            return;
+        }
+        let (pieces, merges) = self.prefault_batch.take();
+        METRICS.faascale_mem.prefault_coalesced_blocks.add(merges);
+        let vm_fd = match self.vm_fd.as_ref() {
+            Some(vm_fd) => vm_fd,
+            None => {
+                warn!("faascale-mem: no KVM VM attached, the TDP faults are left to the guest");
+                return;
+            }
+        };
+        for piece in pieces {
+            METRICS.faascale_mem.prefault_ioctls.inc();
+            let elapsed = prefault_slot_range(vm_fd, piece, self.prefault_sampler.as_mut());
+            self.latest_stats.record_pre_tdp_fault_latency(elapsed);
+        }
+    }
+
+    // Populates the block of `request`, with everything that comes along: the accounting, the
+    // backing the guest hinted at, the experiment and the contents read back into the block.
+    // Returns the status the guest is told.
+    fn populate_block(&mut self, mem: &GuestMemoryMmap, request: PopulateRequest) -> u32 {
+        let PopulateRequest {
+            block,
+            trace_id,
+            pin,
+            lock,
+            granularity,
+            from_guest,
+        } = request;
+        let range = block_range(block);
+        // Without STATUS the guest is not told, the fence is advisory.
+        if self.fenced {
+            METRICS.faascale_mem.populate_fenced_refusals.inc();
+            warn!(
+                "Refusing to populate block on a fenced device: start_pfn={}, size={}{}",
+                block.0, block.1, trace_id
+            );
+            return VIRTIO_FAASCALE_MEM_STATUS_ENOMEM;
+        }
+        // Only the pages not populated yet count against the budget, which only the guest
+        // agreed on.
+        let new_pages = block.1 - self.populated_ranges.overlap_pages(block);
+        if from_guest
+            && !self
+                .budget
+                .admit(self.populated_ranges.num_pages(), new_pages)
+        {
+            METRICS.faascale_mem.budget_violations.inc();
+            warn!(
+                "Refusing to populate block over the memory budget: start_pfn={}, size={}{}",
+                block.0, block.1, trace_id
+            );
+            return VIRTIO_FAASCALE_MEM_STATUS_OVER_BUDGET;
+        }
+        if !self.admit_populated_pages(new_pages) {
+            METRICS.faascale_mem.max_populated_refusals.inc();
+            warn!(
+                "Refusing to populate block over the populated memory cap: start_pfn={}, size={}{}",
+                block.0, block.1, trace_id
+            );
+            return VIRTIO_FAASCALE_MEM_STATUS_OVER_BUDGET;
+        }
+        // Must be known before the block is populated and the pieces
+        // held back are forgotten.
+        let recycled = if self.zeroed_enabled() {
+            self.recycled_blocks(block)
+        } else {
+            Vec::new()
+        };
+        // The pieces of huge pages held back are in use again.
+        if let Some(ref mut batcher) = self.depopulate_batcher {
+            batcher.populate(block);
+        }
+        if pin {
+            self.pinned_ranges.insert(block);
+            METRICS
+                .faascale_mem
+                .pinned_pages
+                .store(self.pinned_ranges.num_pages() as usize);
+        }
+        // The block was populated moments ago, the memory is already in place.
+        if self
+            .populate_tracker
+            .check_and_record(block, self.clock.now())
+        {
+            METRICS.faascale_mem.populate_dedup_hits.inc();
+            zero_recycled(mem, &recycled, trace_id);
+            return VIRTIO_FAASCALE_MEM_STATUS_OK;
+        }
+        // The guest hint takes precedence over the THP policy.
+        let advised = granularity.map(|granularity| match granularity {
+            BlockGranularity::Page4K => {
+                advise_huge_pages(mem, range, libc::MADV_NOHUGEPAGE).is_ok()
+            }
+            BlockGranularity::Huge2M => matches!(
+                advise_huge_pages(mem, range, libc::MADV_HUGEPAGE),
+                Ok(len) if len > 0
+            ),
+            // Anonymous guest memory is never backed by gigantic pages,
+            // fall back to the largest transparent huge pages.
+            BlockGranularity::Huge1G => {
+                let _ = advise_huge_pages(mem, range, libc::MADV_HUGEPAGE);
+                false
+            }
+        });
+        let thp_policy = match granularity {
+            Some(_) => FaascaleMemThpPolicy::System,
+            None => self.thp_policy,
+        };
+        // The experiment, if any, picks the population policy of the block.
+        let (variant, pre_alloc_mem, pre_tdp_fault) = match self.experiment {
+            Some(ref mut experiment) => {
+                let (variant, policy) = experiment.assign();
+                (Some(variant), policy.pre_alloc_mem, policy.pre_tdp_fault)
+            }
+            None => (None, self.pre_alloc_mem, self.pre_tdp_fault),
+        };
+        let sample = variant.map(ExperimentSample::start);
+        let pre_alloc_method = pre_alloc_mem.then(|| self.pre_alloc_method());
+        // Only the pre-allocated blocks are placed by the device.
+        let interleave_nodes = self
+            .interleave
+            .as_ref()
+            .filter(|_| pre_alloc_mem)
+            .and_then(|interleave| interleave.node_mask(block));
+        let lazy = self
+            .lazy_populate
+            .as_ref()
+            .filter(|lazy| lazy.covers(mem, range));
+        // Only the wait for the first block of the kick is measured, not
+        // the populating itself.
+        if let Some(kicked_at) = self.populate_kicked_at.take() {
+            METRICS
+                .faascale_mem
+                .populate_latency_us
+                .store(kicked_at.elapsed().as_micros() as usize);
+        }
+        let result = match (self.encryption_backend.as_mut(), lazy) {
+            // The memory of encrypted guests is registered with the
+            // hypervisor instead.
+            (Some(backend), _) => backend
+                .populate(mem, range)
+                .map(|()| false)
+                .map_err(RemoveRegionError::EncryptionBackend),
+            // The pages are backed when the guest first touches them.
+            (None, Some(lazy)) => lazy.register(mem, range).map(|()| false),
+            (None, None) => {
+                // The reserved pool backs the block first, if it can,
+                // with memory already in place.
+                let pooled = self
+                    .pool
+                    .as_mut()
+                    .map_or(false, |pool| pool.populate(mem, range));
+                let populate_start = Instant::now();
+                let prefault_batch = pre_tdp_fault.then_some(&mut self.prefault_batch);
+                let populated = if pooled {
+                    queue_prefault(mem, range, prefault_batch)
+                } else {
+                    populate_range(
+                        mem,
+                        range,
+                        self.restored.then_some(&mut self.mmap_overlays),
+                        thp_policy,
+                        pre_alloc_method,
+                        interleave_nodes,
+                        self.numa_node,
+                        self.scrub_on_populate,
+                        prefault_batch,
+                        trace_id,
+                    )
+                };
+                populated.map(|timings| {
+                    let elapsed = populate_start.elapsed();
+                    self.populate_throughput.record(range.1, elapsed);
+                    self.latest_stats.record_populate_latency(elapsed, timings);
+                    if self.populate_verification {
+                        self.latest_stats
+                            .record_populate_verification(write_populate_canary(
+                                mem, range, trace_id,
+                            ));
+                    }
+                    timings.interleaved_pages == block.1
+                })
+            }
+        };
+        if let Some(sample) = sample {
+            let results = self
+                .latest_stats
+                .experiment
+                .get_or_insert_with(FaascaleMemExperimentResults::default);
+            sample.finish(result.is_ok(), results);
+        }
+        let status = match result {
+            Ok(interleaved) => {
+                // Restored microVMs lay fresh memory over the block,
+                // and scrubbing zeroed all of it already.
+                if !self.restored && !self.scrub_on_populate {
+                    zero_recycled(mem, &recycled, trace_id);
+                }
+                self.populated_ranges.insert(block);
+                self.heatmap.populated(block);
+                if let Some(interleave) = self.interleave.as_mut() {
+                    interleave.populated(block, interleaved);
+                }
+                if from_guest {
+                    self.boot_warmup.populated(block.1);
+                }
+                // Unless the populated blocks are deduplicated, the
+                // pages KSM merged are copied back, sparing the guest
+                // the copy-on-write faults.
+                if let Some(mergeable) = self.ksm.populated() {
+                    advise_ksm(mem, block, mergeable);
+                }
+                // The template of the runtime replaces the zero-filled
+                // pages, and the contents the block had when the guest
+                // gave it back replace both. Encrypted guests are never
+                // filled.
+                if let (Some(template), None) = (
+                    self.memory_template.as_ref(),
+                    self.encryption_backend.as_ref(),
+                ) {
+                    template.fill(mem, block);
+                }
+                if let Some(cache) = self.block_cache.as_mut() {
+                    cache.restore(mem, block, self.driver_resets);
+                }
+                // Over the budget, the block is only left unlocked.
+                if let (true, Some(mlock)) = (lock, self.mlock.as_mut()) {
+                    if let Err(err) = mlock.lock(mem, block) {
+                        warn!(
+                            "Leaving populated block unlocked: start_pfn={}, size={}: {:?}{}",
+                            block.0, block.1, err, trace_id
+                        );
+                    }
+                }
+                if let Some(spill) = self.spill_file.as_mut() {
+                    spill.restore(mem, block);
+                }
+                VIRTIO_FAASCALE_MEM_STATUS_OK
+            }
+            Err(err) => {
+                self.error_log
+                    .record(FaascaleMemOperation::Populate, block, &err);
+                error!("Error populating memory range: {:?}{}", err, trace_id);
+                region_error_status(&err)
+            }
+        };
+        if let (Some(granularity), Some(advised)) = (granularity, advised) {
+            self.latest_stats.record_granularity(granularity, advised);
+        }
+        if self.thp_policy == FaascaleMemThpPolicy::Collapse
+            && granularity != Some(BlockGranularity::Page4K)
+        {
+            if let Err(err) = advise_huge_pages(mem, range, MADV_COLLAPSE) {
+                METRICS.faascale_mem.thp_collapse_fails.inc();
+                error!("Error collapsing huge pages: {:?}{}", err, trace_id);
+            }
+        }
+        status
+    }
+
     // 对于收缩气球，也就是扩展VM的内存，firecracker是没有进行任何操作的，也就是，完全靠pagefault来填充物理内存
     // 因为对于使用MADV_DONTNEED的私有匿名页而言，下一次读会重新的分配物理内存，并按零填充
-    pub(crate) fn process_populate_queue(&mut self, queue_index: usize) -> Result<(), FaascaleMemError> {
+    fn drain_populate_queue(&mut self, queue_index: usize) -> Result<(), FaascaleMemError> {
+        self.release_expired_depopulations(DEPOPULATE_BATCH_TIMEOUT);
+
         // This is safe since we checked in the event handler that the device is activated.
         // device_state，指示FaascaleMem 设备是否被激活，激活时需要提供用于表示设备所附加的内存区域的GuestMemoryMmap 的参数，这里的.mem()就是返回这个
         // self.device_state.mem() 返回了一个 Option 类型的值，表示可能存在一个内存区域。但在这里，我们通过 unwrap() 方法解包了这个值，也就是说，
         // 如果 self.device_state.mem() 返回了 None，那么程序会崩溃并抛出一个 panic。但是，由于前面的事件处理程序已经检查了该设备是否已经激活，所以这里使用 unwrap() 方法是安全的。
-        let mem = self.device_state.mem().unwrap();
+        let mem = &self.device_state.mem().unwrap().clone();
         METRICS.faascale_mem.depopulate_count.inc();
 
-        let queue = &mut self.queues[queue_index];
-
         let mut needs_interrupt = false;
+        let granularity_hints = self.granularity_hints_enabled();
+        // Flags the guest sets in the upper bits of the page count of a block.
+        let mut block_flags = if granularity_hints {
+            VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_MASK
+        } else {
+            0
+        };
+        if self.pin_enabled() {
+            block_flags |= VIRTIO_FAASCALE_MEM_BLOCK_F_PINNED;
+        }
+        if self.mlock_enabled() {
+            block_flags |= VIRTIO_FAASCALE_MEM_BLOCK_F_MLOCKED;
+        }
+        let wide_blocks = self.wide_blocks_enabled();
+        let status_enabled = self.status_enabled();
+        let block_info_size = if wide_blocks {
+            SIZE_OF_WIDE_BLOCK_INFO
+        } else {
+            SIZE_OF_BLOCK_INFO
+        };
+        // Only the populate requests are traced.
+        let block_size = if queue_index == POPULATE_INDEX && self.trace_ids_enabled() {
+            block_info_size + SIZE_OF_TRACE_ID
+        } else {
+            block_info_size
+        };
 
         // Internal loop processes descriptors and acummulates the pfns in `pfn_buffer`.
         // Breaks out when there is not enough space in `pfn_buffer` to completely process
@@ -311,95 +1652,319 @@ impl FaascaleMem {
         // head的内容。尽管如此，这段代码并不会出问题，因为Linux内核，会将每1MB的page，即256个PFN作为一次IO请求，写入到Queue中。因此每个IO请求
         // 的Descriptor链表，确实只有一个Descriptor，因此不需要对其进行遍历
         // （一个IO请求，对应了Linux内核中的一个散列表，Linux faascale使用了sg_init_one来初始化，所以其散列表中只有一个Descriptor）
-        while let Some(head) = queue.pop(mem) {
-            let len = head.len as usize; // 获取该Descriptor的数据区的大小，数据区存放的是guest返回的PFN
-            let max_len = MAX_BLOCKS_IN_DESC * SIZE_OF_BLOCK_INFO; // 每个Descriptor最多存放256个PFN，也即1MB
+        // Heads are popped in batches, reading the avail index once per batch.
+        // The queue is indexed at each use, leaving `self` free for the checks of the blocks.
+        let mut heads =
+            self.queues[queue_index].pop_batch_or_enable_notification(mem, POP_BATCH_SIZE);
+        'queue: while !heads.is_empty() {
+            let indices: Vec<u16> = heads.iter().map(|head| head.index).collect();
+            self.leak_tracker
+                .popped(queue_index, indices.iter().copied());
+            for (position, head) in heads.into_iter().enumerate() {
+                let mut status = VIRTIO_FAASCALE_MEM_STATUS_OK;
+                let len = head.len as usize; // 获取该Descriptor的数据区的大小，数据区存放的是guest返回的PFN
+                let max_len = MAX_BLOCKS_IN_DESC * block_size; // 每个Descriptor最多存放256个PFN，也即1MB
 
-            // head的数据区就是内核传输过来的pfns数组，因此其数据区的长度一定是整除SIZE_OF_U32的
-            // is_write_only 为真表明，这个descriptors对于Device是write_only,而对于driver是read_only，显然在这里，应该对于firecracker应该是只读的
-            if !head.is_write_only() && len % SIZE_OF_BLOCK_INFO == 0 { //
+                // head的数据区就是内核传输过来的pfns数组，因此其数据区的长度一定是整除SIZE_OF_U32的
+                // is_write_only 为真表明，这个descriptors对于Device是write_only,而对于driver是read_only，显然在这里，应该对于firecracker应该是只读的
                 // Check descriptor pfn count.
                 // head的长度肯定不能超过最大的长度限制，即其最多存放256个pfn
-                if len > max_len {
+                if !head.is_write_only() && len > max_len {
                     error!(
-                            "populate descriptor has bogus page count {} > {}, skipping.",
-                            len / SIZE_OF_BLOCK_INFO,
-                            MAX_BLOCKS_IN_DESC
-                        );
+                        "populate descriptor has bogus page count {} > {}, skipping.",
+                        len / block_size,
+                        MAX_BLOCKS_IN_DESC
+                    );
+                    // The request is still returned to the guest, with its failure.
+                    fail_request(&mut status, VIRTIO_FAASCALE_MEM_STATUS_EINVAL);
+                } else if !head.is_write_only() && len % block_size == 0 {
+                    // The throttled request and the ones popped after it are left in the queue
+                    // until the rate limiter replenishes. A request whose blocks cannot be read
+                    // is returned to the guest with its failure.
+                    let allowed = rate_limit_request(
+                        &mut self.rate_limiter,
+                        mem,
+                        head.addr,
+                        len,
+                        block_size,
+                        wide_blocks,
+                        block_flags,
+                    )
+                    .unwrap_or_else(|err| {
+                        error!("faascale-mem: error reading request blocks: {:?}", err);
+                        fail_request(&mut status, VIRTIO_FAASCALE_MEM_STATUS_EINVAL);
+                        true
+                    });
+                    if !allowed {
+                        METRICS.faascale_mem.rate_limiter_throttled_events.inc();
+                        for &index in &indices[position..] {
+                            self.queues[queue_index].undo_pop();
+                            self.leak_tracker.returned(queue_index, index);
+                        }
+                        break 'queue;
+                    }
 
-                    // Skip descriptor.
-                    continue;
-                }
+                    let request_start = Instant::now();
+                    // This is safe, `len` was validated above.
+                    // 循环的遍历出Descriptor的数据区中所有的pfn
+                    // Nothing more of a request is read once one of its blocks cannot be.
+                    let readable_len = if status == VIRTIO_FAASCALE_MEM_STATUS_OK {
+                        len
+                    } else {
+                        0
+                    };
+                    for index in (0..readable_len).step_by(block_size) {
+                        // head.addr 是数据区的首地址，加上index后，就是每个fpn的地址，整个地址是虚拟机的物理地址
+                        // 通过mem.read_obj，将pfn读出来
+                        let block = head.addr.checked_add(index as u64).map_or(
+                            Err(FaascaleMemError::MalformedDescriptor),
+                            |addr| {
+                                read_request_block(mem, addr, block_size, wide_blocks, block_flags)
+                            },
+                        );
+                        let (pfn, page_count, flags, trace_id) = match block {
+                            Ok(block) => block,
+                            Err(err) => {
+                                error!("faascale-mem: error reading request block: {:?}", err);
+                                fail_request(&mut status, VIRTIO_FAASCALE_MEM_STATUS_EINVAL);
+                                break;
+                            }
+                        };
+                        // The guest flags the blocks it wants pinned in the top bit of the page count,
+                        // the backing granularity it asks for in the bits below, and the blocks it
+                        // wants locked in the bit below these.
+                        let pin = flags & VIRTIO_FAASCALE_MEM_BLOCK_F_PINNED != 0;
+                        let lock = flags & VIRTIO_FAASCALE_MEM_BLOCK_F_MLOCKED != 0;
+                        let granularity = if granularity_hints {
+                            BlockGranularity::from_page_count(flags)
+                        } else {
+                            None
+                        };
+                        let block = (pfn, page_count);
+                        if !block_addressable(block) {
+                            error!(
+                                "faascale-mem: skipping block beyond the guest physical address \
+                                 space: start_pfn={}, size={}{}",
+                                block.0, block.1, trace_id
+                            );
+                            fail_request(&mut status, VIRTIO_FAASCALE_MEM_STATUS_EINVAL);
+                            continue;
+                        }
+                        let range = block_range(block);
 
-                // This is safe, `len` was validated above.
-                // 循环的遍历出Descriptor的数据区中所有的pfn
-                for index in (0..len).step_by(SIZE_OF_BLOCK_INFO) {
-                    // head.addr 是数据区的首地址，加上index后，就是每个fpn的地址，整个地址是虚拟机的物理地址
-                    let addr = head
-                        .addr
-                        .checked_add(index as u64)
-                        .ok_or(FaascaleMemError::MalformedDescriptor)?;
-
-                    // 通过mem.read_obj，将pfn读出来
-                    let block = mem
-                        .read_obj::<[u32; 2]>(addr)
-                        .map_err(|_| FaascaleMemError::MalformedDescriptor)?;
-
-                    let guest_addr =
-                        GuestAddress(u64::from(block[0]) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
-                    let range = (guest_addr, u64::from(block[1]) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
-
-                    match queue_index {
-                        POPULATE_INDEX =>{
-                            debug!("KINGDO: Populate Block: start_pfn={}, size={}",block[0],block[1]);
-                            if let Err(err) = populate_range(
-                                mem,
-                                range,
-                                self.restored,
-                                self.pre_alloc_mem,
-                                self.pre_tdp_fault,
-                            ) {
-                                error!("Error populating memory range: {:?}", err);
+                        match queue_index {
+                            POPULATE_INDEX => {
+                                debug!(
+                                    "faascale-mem: populating block: start_pfn={}, size={}{}",
+                                    block.0, block.1, trace_id
+                                );
+                                self.boot_warmup.request(self.clock.now());
+                                let request = PopulateRequest {
+                                    block,
+                                    trace_id,
+                                    pin,
+                                    lock,
+                                    granularity,
+                                    from_guest: true,
+                                };
+                                fail_request(&mut status, self.populate_block(mem, request));
                             }
-                        },
-                        DEPOPULATE_INDEX =>{
-                            debug!("KINGDO: Remove Block: start_pfn={}, size={}",block[0],block[1]);
-                            if let Err(err) = remove_range(
-                                mem,
-                                range,
-                                self.restored,
-                            ) {
-                                error!("Error removing memory range: {:?}", err);
+                            DEPOPULATE_INDEX => {
+                                debug!(
+                                    "faascale-mem: depopulating block: start_pfn={}, size={}",
+                                    block.0, block.1
+                                );
+                                if self.pinned_ranges.overlaps(block) {
+                                    METRICS.faascale_mem.depopulate_pinned_refusals.inc();
+                                    warn!(
+                                        "Refusing to depopulate pinned block: start_pfn={}, size={}",
+                                        block.0, block.1
+                                    );
+                                    fail_request(&mut status, VIRTIO_FAASCALE_MEM_STATUS_EINVAL);
+                                    continue;
+                                }
+                                // The contents of encrypted guests cannot be read back.
+                                if self.encryption_backend.is_none() {
+                                    if let Some(cache) = self.block_cache.as_mut() {
+                                        cache.save(mem, block, self.driver_resets);
+                                    }
+                                    // The block stays populated rather than losing contents
+                                    // the guest expects back, and the guest is told.
+                                    if let Some(spill) = self.spill_file.as_mut() {
+                                        if let Err(err) = spill.save(mem, block) {
+                                            METRICS.faascale_mem.spill_fails.inc();
+                                            error!(
+                                                "Error spilling block, keeping it populated: \
+                                                 start_pfn={}, size={}: {:?}",
+                                                block.0, block.1, err
+                                            );
+                                            fail_request(
+                                                &mut status,
+                                                VIRTIO_FAASCALE_MEM_STATUS_ENOMEM,
+                                            );
+                                            continue;
+                                        }
+                                    }
+                                }
+                                self.populate_tracker
+                                    .forget_overlapping(block, self.clock.now());
+                                self.heatmap.depopulated(block);
+                                // The pieces of huge pages are held back, and no longer count as
+                                // populated.
+                                let blocks = match (
+                                    self.depopulate_batcher.as_mut(),
+                                    host_pfn(mem, range),
+                                ) {
+                                    (Some(batcher), Some(host_pfn)) => {
+                                        self.populated_ranges.remove(block);
+                                        batcher.depopulate(block, host_pfn, self.clock.now())
+                                    }
+                                    _ => vec![block],
+                                };
+                                for block in blocks {
+                                    match release_block(
+                                        mem,
+                                        block,
+                                        self.encryption_backend.as_deref_mut(),
+                                        self.pool.as_mut(),
+                                        self.restored.then_some(&mut self.mmap_overlays),
+                                        self.depopulate_mode,
+                                        self.ksm,
+                                        self.mlock.as_mut(),
+                                        self.lazy_populate.as_ref(),
+                                    ) {
+                                        Ok(()) => self.populated_ranges.remove(block),
+                                        Err(err) => {
+                                            fail_request(&mut status, region_error_status(&err));
+                                            self.error_log.record(
+                                                FaascaleMemOperation::Depopulate,
+                                                block,
+                                                &err,
+                                            );
+                                            error!("Error removing memory range: {:?}", err)
+                                        }
+                                    }
+                                }
                             }
+                            _ => {}
                         }
-                        _ => {}
                     }
+                    self.request_activity
+                        .record(populate, request_start.elapsed());
+                } else if !head.is_write_only() {
+                    // The size of the blocks depends on the negotiated features.
+                    error!(
+                        "faascale-mem: descriptor length {} is not a multiple of the {} bytes \
+                         of a block, skipping.",
+                        len, block_size
+                    );
+                    fail_request(&mut status, VIRTIO_FAASCALE_MEM_STATUS_EINVAL);
                 }
-            }
 
-            // Acknowledge the receipt of the descriptor.
-            // 0 is number of bytes the device has written to memory.
-            // 告诉guest，我们已经读取完成了一个IO请求，其可以将指定的descriptor给释放掉。
-            queue
-                .add_used(mem, head.index, 0)
-                .map_err(FaascaleMemError::Queue)?;
-            needs_interrupt = true;
+                // Acknowledge the receipt of the descriptor.
+                // The length is the number of bytes the device has written to memory: the status
+                // of the request, if negotiated.
+                // 告诉guest，我们已经读取完成了一个IO请求，其可以将指定的descriptor给释放掉。
+                let used_len = if status_enabled && !head.is_write_only() {
+                    write_request_status(mem, &head, status)
+                } else {
+                    0
+                };
+                // The requests popped after a failing one are left in the queue.
+                if let Err(err) = self.queues[queue_index].add_used(mem, head.index, used_len) {
+                    for &index in &indices[position + 1..] {
+                        self.queues[queue_index].undo_pop();
+                        self.leak_tracker.returned(queue_index, index);
+                    }
+                    return Err(FaascaleMemError::Queue(err));
+                }
+                self.leak_tracker.returned(queue_index, head.index);
+                needs_interrupt = true;
+            }
+            heads = self.queues[queue_index].pop_batch_or_enable_notification(mem, POP_BATCH_SIZE);
         }
 
+        // The guest finds the populated blocks mapped once notified.
+        self.flush_prefault_batch();
         // 告诉虚拟机，我们已经完成了对一次IO请求，执行该函数后会触发Linux内核中vqueue的callbacks，
+        // Only the depopulate notifications are moderated, the guest waits on the populated
+        // memory.
         if needs_interrupt {
-            self.signal_used_queue()?;
+            if queue_index == POPULATE_INDEX
+                || self.irq_moderator.pass_completed(self.clock.now())
+            {
+                self.notify_used_queue(queue_index)?;
+            } else {
+                METRICS.faascale_mem.irq_moderation_held.inc();
+            }
         }
+        self.publish_mmds();
 
         Ok(())
     }
 
+    // Releases the pieces of huge pages held back for longer than `timeout`, splitting the huge
+    // pages.
+    pub(crate) fn release_expired_depopulations(&mut self, timeout: Duration) {
+        let expired = match self.depopulate_batcher.as_mut() {
+            Some(batcher) => batcher.expire(self.clock.now(), timeout),
+            None => return,
+        };
+        self.update_depopulate_batch_timer();
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return,
+        };
+        for block in expired {
+            if let Err(err) = release_block(
+                mem,
+                block,
+                self.encryption_backend.as_deref_mut(),
+                self.pool.as_mut(),
+                self.restored.then_some(&mut self.mmap_overlays),
+                self.depopulate_mode,
+                self.ksm,
+                self.mlock.as_mut(),
+                self.lazy_populate.as_ref(),
+            ) {
+                self.error_log
+                    .record(FaascaleMemOperation::Depopulate, block, &err);
+                error!("Error removing memory range: {:?}", err);
+            }
+        }
+    }
+
+    // Arms the batching timer to expire with the oldest pieces held back, or disarms it once
+    // none are.
+    fn update_depopulate_batch_timer(&mut self) {
+        let next_expiry = self
+            .depopulate_batcher
+            .as_ref()
+            .and_then(|batcher| batcher.next_expiry(DEPOPULATE_BATCH_TIMEOUT));
+        let timer_state = match next_expiry {
+            // A zero duration would disarm the timer.
+            Some(expiry) => TimerState::Oneshot(cmp::max(
+                expiry.saturating_duration_since(self.clock.now()),
+                Duration::from_millis(1),
+            )),
+            None => TimerState::Disarmed,
+        };
+        self.depopulate_batch_timer
+            .set_state(timer_state, SetTimeFlags::Default);
+    }
+
+    /// Releases the pieces of huge pages held back before the device is saved, the snapshot
+    /// does not keep them.
+    pub fn prepare_save(&mut self) {
+        self.release_expired_depopulations(Duration::ZERO);
+    }
+
     pub(crate) fn process_stats_queue(&mut self) -> Result<(), FaascaleMemError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
         METRICS.faascale_mem.stats_updates_count.inc();
 
-        while let Some(head) = self.queues[FAASCALE_STATS_INDEX].pop(mem) {
+        while let Some(head) = self.queues[FAASCALE_STATS_INDEX].pop_or_enable_notification(mem) {
             if let Some(prev_stats_desc) = self.stats_desc_index {
                 // We shouldn't ever have an extra buffer if the driver follows
                 // the protocol, but return it if we find one.
@@ -408,6 +1973,14 @@ impl FaascaleMem {
                     .add_used(mem, prev_stats_desc, 0)
                     .map_err(FaascaleMemError::Queue)?;
             }
+            self.refresh_restored_stats();
+            // While the statistics are disabled, the buffer is only held until they are enabled
+            // again.
+            if !self.stats_enabled() {
+                self.stats_desc_index = Some(head.index);
+                continue;
+            }
+            let previous_stats = self.latest_stats.clone();
             for index in (0..head.len).step_by(SIZE_OF_STAT) {
                 // Read the address at position `index`. The only case
                 // in which this fails is if there is overflow,
@@ -420,11 +1993,17 @@ impl FaascaleMem {
                 let stat = mem
                     .read_obj::<FaascaleMemStat>(addr)
                     .map_err(|_| FaascaleMemError::MalformedDescriptor)?;
-                self.latest_stats.update_with_stat(&stat).map_err(|_| {
-                    METRICS.faascale_mem.stats_update_fails.inc();
-                    FaascaleMemError::MalformedPayload
-                })?;
-            }
+                match self.latest_stats.update_with_stat(&stat) {
+                    Ok(()) => {}
+                    // Newer guest drivers report statistics unknown to the device.
+                    Err(_) if !self.strict_stats => METRICS.faascale_mem.stats_unknown_tags.inc(),
+                    Err(_) => {
+                        METRICS.faascale_mem.stats_update_fails.inc();
+                        return Err(FaascaleMemError::MalformedPayload);
+                    }
+                }
+            }
+            self.update_stats_deltas(&previous_stats);
 
             self.stats_desc_index = Some(head.index);
         }
@@ -432,6 +2011,97 @@ impl FaascaleMem {
         Ok(())
     }
 
+    pub(crate) fn process_control_queue(&mut self) -> Result<(), FaascaleMemError> {
+        let result = self.drain_control_queue();
+        // The budget acknowledgements carry no status.
+        self.check_descriptor_leaks(self.control_index(), false)?;
+        result
+    }
+
+    fn drain_control_queue(&mut self) -> Result<(), FaascaleMemError> {
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+        let control_index = self.control_index();
+        let mut needs_interrupt = false;
+
+        while let Some(head) = self.queues[control_index].pop_or_enable_notification(mem) {
+            self.leak_tracker
+                .popped(control_index, std::iter::once(head.index));
+            let ack = if !head.is_write_only() && head.len as usize == SIZE_OF_BUDGET_ACK {
+                mem.read_obj::<BudgetAck>(head.addr).ok()
+            } else {
+                None
+            };
+            if let Some(ack) = ack {
+                if self.budget.ack(&ack) {
+                    METRICS.faascale_mem.budget_acks.inc();
+                } else {
+                    warn!(
+                        "Ignoring budget acknowledgement: epoch={}, num_pages={}",
+                        ack.epoch, ack.num_pages
+                    );
+                }
+            } else {
+                error!("faascale-mem: malformed budget acknowledgement, skipping.");
+            }
+
+            self.queues[control_index]
+                .add_used(mem, head.index, 0)
+                .map_err(FaascaleMemError::Queue)?;
+            self.leak_tracker.returned(control_index, head.index);
+            needs_interrupt = true;
+        }
+
+        if needs_interrupt {
+            self.notify_used_queue(control_index)?;
+        }
+        self.publish_mmds();
+
+        Ok(())
+    }
+
+    // Counts the descriptors of the `queue_index` queue the last processing pass did not return
+    // to the guest and, if configured, returns them with an error status when `with_status`.
+    fn check_descriptor_leaks(
+        &mut self,
+        queue_index: usize,
+        with_status: bool,
+    ) -> Result<(), FaascaleMemError> {
+        let leaks = self.leak_tracker.take_leaks(queue_index);
+        if leaks.is_empty() {
+            return Ok(());
+        }
+        METRICS.faascale_mem.descriptor_leaks.add(leaks.len());
+        error!(
+            "faascale-mem: {} descriptors of queue {} were not returned to the guest{}",
+            leaks.len(),
+            queue_index,
+            if self.complete_leaked_descriptors {
+                ", completing them."
+            } else {
+                "."
+            }
+        );
+        if !self.complete_leaked_descriptors {
+            return Ok(());
+        }
+
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+        for index in leaks {
+            let used_len = match self.queues[queue_index].descriptor_chain(mem, index) {
+                Some(head) if with_status && !head.is_write_only() => {
+                    write_request_status(mem, &head, VIRTIO_FAASCALE_MEM_STATUS_EINVAL)
+                }
+                _ => 0,
+            };
+            self.queues[queue_index]
+                .add_used(mem, index, used_len)
+                .map_err(FaascaleMemError::Queue)?;
+        }
+        self.notify_used_queue(queue_index)
+    }
+
     // 周期性的告诉guest，获取的states信息
     fn trigger_stats_update(&mut self) -> Result<(), FaascaleMemError> {
         // This is safe since we checked in the event handler that the device is activated.
@@ -443,13 +2113,26 @@ impl FaascaleMem {
             self.queues[FAASCALE_STATS_INDEX]
                 .add_used(mem, index, 0)
                 .map_err(FaascaleMemError::Queue)?;
-            self.signal_used_queue()
+            self.notify_used_queue(FAASCALE_STATS_INDEX)
         } else {
             error!("Failed to update faascale_mem stats, missing descriptor.");
             Ok(())
         }
     }
 
+    // Notifies the guest of the buffers used on the `queue_index` queue, unless it asked not to be
+    // through the event index of the queue.
+    fn notify_used_queue(&mut self, queue_index: usize) -> Result<(), FaascaleMemError> {
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+        if self.queues[queue_index].prepare_kick(mem) {
+            self.signal_used_queue()
+        } else {
+            METRICS.faascale_mem.event_idx_suppressed.inc();
+            Ok(())
+        }
+    }
+
     pub(crate) fn signal_used_queue(&self) -> Result<(), FaascaleMemError> {
         self.irq_trigger.trigger_irq(IrqType::Vring).map_err(|err| {
             METRICS.faascale_mem.event_fails.inc();
@@ -473,21 +2156,37 @@ impl FaascaleMem {
             return Ok(());
         }
 
-        if self.stats_polling_interval_s == 0 || interval_s == 0 {
+        // Devices restored from snapshots taken without the statistics queue cannot get it
+        // back.
+        if !self.stats_queue_present() && (self.stats_polling_interval_s == 0 || interval_s == 0) {
             return Err(FaascaleMemError::StatisticsStateChange);
         }
 
-        self.trigger_stats_update()?;
-
         self.stats_polling_interval_s = interval_s;
-        self.update_timer_state();
+        if let Some(adaptation) = self.polling_adaptation.as_mut() {
+            adaptation.set_baseline(Duration::from_secs(u64::from(interval_s)));
+        }
+        self.config_epoch += 1;
+
+        if interval_s == 0 {
+            // The statistics queue keeps the buffer of the guest until they are enabled again.
+            self.stats_timer
+                .set_state(TimerState::Disarmed, SetTimeFlags::Default);
+            return Ok(());
+        }
+        // Before activation, the timer is armed by the activation itself.
+        if self.is_activated() {
+            self.update_timer_state();
+            self.trigger_stats_update()?;
+        }
         Ok(())
     }
 
     pub fn update_timer_state(&mut self) {
+        let interval = self.effective_stats_polling_interval();
         let timer_state = TimerState::Periodic {
-            current: Duration::from_secs(u64::from(self.stats_polling_interval_s)),
-            interval: Duration::from_secs(u64::from(self.stats_polling_interval_s)),
+            current: interval,
+            interval,
         };
         self.stats_timer
             .set_state(timer_state, SetTimeFlags::Default);
@@ -497,6 +2196,23 @@ impl FaascaleMem {
         self.config_space.num_pages
     }
 
+    /// Dumps the config space as the guest sees it.
+    pub fn config_space_info(&self) -> FaascaleMemConfigSpace {
+        FaascaleMemConfigSpace {
+            raw: self
+                .config_space
+                .as_slice()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            num_pages: self.config_space.num_pages,
+            actual_pages: self.config_space.actual_pages,
+            budget_pages: self.config_space.budget_pages,
+            budget_epoch: self.config_space.budget_epoch,
+            release_pages: self.config_space.release_pages,
+        }
+    }
+
     pub fn size_mb(&self) -> u32 {
         pages_to_mib(self.config_space.num_pages)
     }
@@ -505,6 +2221,15 @@ impl FaascaleMem {
         self.stats_polling_interval_s
     }
 
+    /// Interval the statistics are polled at, the configured one unless it is being adapted to
+    /// the memory pressure of the guest.
+    pub fn effective_stats_polling_interval(&self) -> Duration {
+        self.polling_adaptation.as_ref().map_or(
+            Duration::from_secs(u64::from(self.stats_polling_interval_s)),
+            PollingAdaptation::interval,
+        )
+    }
+
     pub fn pre_alloc_mem(&self) -> bool {
         self.pre_alloc_mem
     }
@@ -513,9 +2238,312 @@ impl FaascaleMem {
         self.pre_tdp_fault
     }
 
+    /// Turns the pre-allocation of the host memory of the populated blocks on or off. The blocks
+    /// already populated are left as they are, and an experiment overrides the setting.
+    pub fn set_pre_alloc_mem(&mut self, pre_alloc_mem: bool) {
+        if self.pre_alloc_mem != pre_alloc_mem {
+            self.pre_alloc_mem = pre_alloc_mem;
+            self.config_epoch += 1;
+        }
+    }
+
+    /// Turns the pre-handling of the TDP faults of the populated blocks on or off. The blocks
+    /// already populated are left as they are, and an experiment overrides the setting.
+    pub fn set_pre_tdp_fault(&mut self, pre_tdp_fault: bool) {
+        if self.pre_tdp_fault != pre_tdp_fault {
+            self.pre_tdp_fault = pre_tdp_fault;
+            self.config_epoch += 1;
+        }
+    }
+
+    /// Checks that the reserved host memory pool, if any, can back `guest_memory`, served by a
+    /// userfaultfd if `uffd` is set.
+    pub fn check_guest_memory(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        uffd: bool,
+    ) -> Result<(), FaascaleMemError> {
+        match self.pool {
+            Some(_) => HostMemoryPool::check_guest_memory(guest_memory, uffd),
+            None => Ok(()),
+        }
+    }
+
+    /// Hands the device the KVM VM it issues the TDP pre-faults on. Until then, the populated
+    /// blocks are not pre-faulted.
+    pub fn set_vm_fd(&mut self, vm_fd: Arc<VmFd>) {
+        self.vm_fd = Some(vm_fd);
+    }
+
+    /// Hands the device the threads of the vCPUs, whose dTLB misses are sampled around the TDP
+    /// pre-faults. The counters are opened right away, since the seccomp filters loaded after
+    /// the vCPUs are started forbid opening them.
+    pub fn set_vcpu_tids(&mut self, vcpu_tids: &[libc::pid_t]) {
+        self.prefault_sampler = self
+            .vm_fd
+            .as_ref()
+            .filter(|_| self.perf_sampling)
+            .map(|vm_fd| PrefaultSampler::new(vm_fd.as_raw_fd(), vcpu_tids));
+    }
+
+    /// Runs the statistics timer, the interrupt moderation, the depopulation batching, the boot
+    /// warmup, the statistics deltas and the rate limiter on `clock`. Must be called before the
+    /// device is activated, the statistics timer keeps its setting.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> Result<(), FaascaleMemError> {
+        let mut stats_timer = clock.timer().map_err(FaascaleMemError::Timer)?;
+        stats_timer.set_state(self.stats_timer.get_state(), SetTimeFlags::Default);
+        let mut depopulate_batch_timer = clock.timer().map_err(FaascaleMemError::Timer)?;
+        depopulate_batch_timer.set_state(
+            self.depopulate_batch_timer.get_state(),
+            SetTimeFlags::Default,
+        );
+        self.irq_moderator = InterruptModerator::new(self.irq_moderator.enabled(), clock.as_ref())
+            .map_err(FaascaleMemError::Timer)?;
+        self.rate_limiter
+            .set_clock(clock.clone())
+            .map_err(FaascaleMemError::Timer)?;
+        self.stats_timer = stats_timer;
+        self.depopulate_batch_timer = depopulate_batch_timer;
+        self.clock = clock;
+        Ok(())
+    }
+
+    /// Probes the host support for the system calls used by the device, on the KVM VM handed
+    /// over by `set_vm_fd`. Warns about the population policies the host cannot honour.
+    pub fn probe_capabilities(&mut self) -> FaascaleMemCapabilities {
+        let vm_fd = self.vm_fd.as_ref().map_or(-1, |vm_fd| vm_fd.as_raw_fd());
+        let capabilities = FaascaleMemCapabilities::probe(vm_fd);
+        self.set_capabilities(capabilities);
+        capabilities
+    }
+
+    /// Records the host support probed beforehand, e.g. when the device is hot-plugged under the
+    /// seccomp filters, which forbid the probes. Warns like `probe_capabilities`.
+    pub fn set_capabilities(&mut self, capabilities: FaascaleMemCapabilities) {
+        if self.pre_alloc_mem && !capabilities.madv_populate_write {
+            warn!(
+                "faascale-mem: the host lacks MADV_POPULATE_WRITE, pre_alloc_mem falls back to \
+                 touching the pages"
+            );
+        }
+        if self.pre_tdp_fault && !capabilities.kvm_prealloc_user_memory_region {
+            warn!(
+                "faascale-mem: pre_tdp_fault is enabled but the host lacks the \
+                 KVM_PREALLOC_USER_MEMORY_REGION ioctl"
+            );
+        }
+        self.capabilities = Some(capabilities);
+    }
+
+    pub fn capabilities(&self) -> Option<FaascaleMemCapabilities> {
+        self.capabilities
+    }
+
+    // Pre-allocation method of the host, `MADV_POPULATE_WRITE` until the host is probed.
+    fn pre_alloc_method(&self) -> PreAllocMethod {
+        match self.capabilities {
+            Some(capabilities) if !capabilities.madv_populate_write => PreAllocMethod::Touch,
+            _ => PreAllocMethod::PopulateWrite,
+        }
+    }
+
+    pub fn thp_policy(&self) -> FaascaleMemThpPolicy {
+        self.thp_policy
+    }
+
+    pub fn populate_tracker_max_entries(&self) -> usize {
+        self.populate_tracker.max_entries()
+    }
+
+    pub fn latency_mode(&self) -> bool {
+        self.latency_mode
+    }
+
+    pub fn perf_sampling(&self) -> bool {
+        self.perf_sampling
+    }
+
+    pub fn config_epoch(&self) -> u64 {
+        self.config_epoch
+    }
+
+    /// Asks the guest driver to grow or shrink the memory it keeps populated to `target_mib`.
+    /// The guest reads the target from the config space.
+    pub fn update_size(&mut self, target_mib: u32) -> Result<(), FaascaleMemError> {
+        if !self.is_activated() {
+            return Err(FaascaleMemError::DeviceNotActive);
+        }
+
+        let num_pages = target_mib
+            .checked_mul(MIB_TO_4K_PAGES)
+            .ok_or(FaascaleMemError::TooManyPagesRequested)?;
+        let changed = self.config_space.num_pages != num_pages;
+        self.config_space.num_pages = num_pages;
+        self.irq_trigger
+            .trigger_irq(IrqType::Config)
+            .map_err(FaascaleMemError::InterruptError)?;
+        if changed {
+            self.config_acks.notify("num_pages", self.clock.now());
+            self.config_epoch += 1;
+        }
+        Ok(())
+    }
+
+    /// Offers a new memory budget to the guest. The budget agreed on before stays enforced
+    /// until the guest acknowledges the new one.
+    pub fn update_budget(&mut self, budget_mib: u32) -> Result<(), FaascaleMemError> {
+        if !self.budget_enabled() {
+            return Err(FaascaleMemError::BudgetDisabled);
+        }
+        if !self.is_activated() {
+            return Err(FaascaleMemError::DeviceNotActive);
+        }
+
+        let budget_pages = budget_mib
+            .checked_mul(MIB_TO_4K_PAGES)
+            .ok_or(FaascaleMemError::TooManyPagesRequested)?;
+        if self.config_space.budget_pages == budget_pages {
+            return Ok(());
+        }
+        self.config_space.budget_pages = budget_pages;
+        self.config_space.budget_epoch = self.budget.offer(budget_pages);
+        self.irq_trigger
+            .trigger_irq(IrqType::Config)
+            .map_err(FaascaleMemError::InterruptError)?;
+        self.config_acks.notify("budget_pages", self.clock.now());
+        self.config_epoch += 1;
+        Ok(())
+    }
+
+    /// Reports the memory budget negotiated with the guest.
+    pub fn budget(&self) -> Result<FaascaleMemBudget, FaascaleMemError> {
+        if !self.budget_enabled() {
+            return Err(FaascaleMemError::BudgetDisabled);
+        }
+        Ok(self.budget.info(self.populated_ranges.num_pages()))
+    }
+
+    /// Stops or restarts the processing of the device queues. The requests queued by the
+    /// guest while the device was quiesced are processed when it is resumed.
+    pub fn set_quiesced(&mut self, quiesced: bool) {
+        let resumed = self.quiesced && !quiesced;
+        self.quiesced = quiesced;
+        if resumed {
+            self.process_deferred_events();
+        }
+    }
+
+    /// Shares the pause state of the microVM with the device.
+    pub fn set_pause_gate(&mut self, pause_gate: VmPauseGate) {
+        self.pause_gate = pause_gate;
+    }
+
+    /// Processes the requests left in the queues while the device was quiesced or the microVM
+    /// paused, unless it still is.
+    pub fn process_deferred_events(&mut self) {
+        if self.quiesced || self.pause_gate.is_paused() || !self.is_activated() {
+            return;
+        }
+        self.process_virtio_queues();
+        if self.stats_queue_present() {
+            let _ = self.process_stats_queue();
+        }
+        if self.budget_enabled() {
+            let _ = self.process_control_queue();
+        }
+    }
+
+    // Whether the queue events are left unprocessed, counting those deferred because the
+    // microVM is paused.
+    fn events_deferred(&self) -> bool {
+        if self.pause_gate.is_paused() {
+            METRICS.faascale_mem.paused_deferred_events.inc();
+            return true;
+        }
+        self.quiesced
+    }
+
+    pub fn is_quiesced(&self) -> bool {
+        self.quiesced
+    }
+
+    pub fn mmap_overlays(&self) -> &MmapOverlays {
+        &self.mmap_overlays
+    }
+
+
+    /// Hands the statistics buffer held by the device back to the guest right away, instead of
+    /// at the next tick of the statistics timer, for `take_polled_stats` to collect the fresh
+    /// statistics. Without a buffer held, the guest is already preparing a report. Fails at once
+    /// when the guest cannot answer.
+    pub fn start_stats_poll(&mut self) -> Result<(), FaascaleMemError> {
+        if !self.stats_enabled() {
+            return Err(FaascaleMemError::StatisticsDisabled);
+        }
+        if !self.is_activated() {
+            return Err(FaascaleMemError::DeviceNotActive);
+        }
+        METRICS.faascale_mem.stats_polls_now.inc();
+
+        // The guest cannot answer while the microVM is paused, and the queues are left alone
+        // while the device is quiesced.
+        if self.quiesced || self.pause_gate.is_paused() {
+            METRICS.faascale_mem.stats_poll_timeouts.inc();
+            return Err(FaascaleMemError::StatisticsPollTimeout);
+        }
+        if self.stats_desc_index.is_some() {
+            self.trigger_stats_update()?;
+        }
+        Ok(())
+    }
+
+    /// The statistics the guest reported since `start_stats_poll`, or `None` while it has not
+    /// answered yet. With `give_up`, an unanswered poll fails instead. Never waits for the
+    /// guest.
+    pub fn take_polled_stats(
+        &mut self,
+        give_up: bool,
+    ) -> Result<Option<FaascaleMemStats>, FaascaleMemError> {
+        // The device may have been reset meanwhile.
+        if !self.is_activated() {
+            return Err(FaascaleMemError::DeviceNotActive);
+        }
+        // The queue event left behind finds the queue empty.
+        self.process_stats_queue()?;
+        if self.stats_desc_index.is_some() {
+            return Ok(Some(self.latest_stats.clone()));
+        }
+        if give_up {
+            METRICS.faascale_mem.stats_poll_timeouts.inc();
+            return Err(FaascaleMemError::StatisticsPollTimeout);
+        }
+        Ok(None)
+    }
+
+    /// Asks the guest to report fresh statistics right away, without waiting for them. The
+    /// polling interval starts over. Nothing is asked while the guest still owes a report, nor
+    /// while it cannot answer.
+    pub fn refresh_stats(&mut self) -> Result<(), FaascaleMemError> {
+        if !self.stats_enabled() {
+            return Err(FaascaleMemError::StatisticsDisabled);
+        }
+        if !self.is_activated() {
+            return Err(FaascaleMemError::DeviceNotActive);
+        }
+        METRICS.faascale_mem.stats_refreshes.inc();
+        if self.quiesced || self.pause_gate.is_paused() || self.stats_desc_index.is_none() {
+            return Ok(());
+        }
+        self.update_timer_state();
+        self.trigger_stats_update()
+    }
 
     pub fn latest_stats(&mut self) -> Option<&FaascaleMemStats> {
-        if self.stats_enabled() {
+        if self.stats_enabled()
+            || self.latest_stats.has_granularity_counts()
+            || self.latest_stats.populate_latency.is_some()
+            || self.latest_stats.experiment.is_some()
+        {
             Some(&self.latest_stats)
         } else {
             None
@@ -527,100 +2555,2781 @@ impl FaascaleMem {
             stats_polling_interval_s: self.stats_polling_interval_s(),
             pre_alloc_mem: self.pre_alloc_mem(),
             pre_tdp_fault: self.pre_tdp_fault(),
+            thp_policy: self.thp_policy(),
+            populate_tracker_max_entries: u32::try_from(self.populate_tracker_max_entries())
+                .unwrap_or(u32::MAX),
+            latency_mode: self.latency_mode(),
+            perf_sampling: self.perf_sampling(),
+            budget_mib: self
+                .budget_enabled()
+                .then(|| self.budget.offered_pages() / MIB_TO_4K_PAGES),
+            experiment: self.experiment.as_ref().map(ExperimentSplitter::experiment),
+            pool: self.pool.as_ref().map(HostMemoryPool::config),
+            strict_stats: self.strict_stats,
+            stats_polling_min_interval_ms: self
+                .polling_adaptation
+                .as_ref()
+                .map(|adaptation| u32::try_from(adaptation.report().min_ms).unwrap_or(u32::MAX)),
+            max_populated_mib: self.max_populated_pages.map(|max_pages| {
+                u32::try_from(max_pages / u64::from(MIB_TO_4K_PAGES)).unwrap_or(u32::MAX)
+            }),
+            block_cache_mib: self.block_cache.as_ref().map(BlockCache::max_mib),
+            interleave: self
+                .interleave
+                .as_ref()
+                .map(|interleave| interleave.config().clone()),
+            numa_node: self.numa_node,
+            depopulate_mode: self.depopulate_mode,
+            mlock_budget_mib: self.mlock.as_ref().map(BlockMlock::budget_mib),
+            rate_limiter: RateLimiterConfig::from(&self.rate_limiter).into_option(),
+            mmds_publish: self.mmds_publish,
+            spill_path: self
+                .spill_file
+                .as_ref()
+                .map(|spill| spill.path().to_path_buf()),
+            scrub_on_populate: self.scrub_on_populate,
+            complete_leaked_descriptors: self.complete_leaked_descriptors,
+            interrupt_moderation: self.irq_moderator.enabled(),
+            policy: self.policy.as_ref().map(|policy| policy.config().clone()),
+            populate_verification: self.populate_verification,
+            ksm_mergeable: self.ksm == KsmAdvice::Populated,
+            ksm_idle: self.ksm == KsmAdvice::Idle,
+            worker_scheduling: self.worker_scheduling.clone(),
+            vsock_observer_port: self.vsock_observer_port,
+            template_path: self
+                .memory_template
+                .as_ref()
+                .map(|template| template.path().to_path_buf()),
+            populate_mode: self.populate_mode(),
+            depopulate_on_reset: self.depopulate_on_reset,
+            config_epoch: self.config_epoch(),
+            populated_mib: self.populated_ranges.num_pages() / u64::from(MIB_TO_4K_PAGES),
+            target_mib: self.size_mb(),
+            boot_warmup: self.boot_warmup.report(self.clock.now()),
+            stats_polling_adaptation: self
+                .polling_adaptation
+                .as_ref()
+                .map(PollingAdaptation::report),
+            capabilities: self.capabilities,
+            policy_decision: self.policy_decision,
+            applied_worker_scheduling: self.applied_worker_scheduling.clone(),
         }
     }
 
-    pub(crate) fn stats_enabled(&self) -> bool {
-        self.stats_polling_interval_s > 0
+    // Advises the guest memory mergeable for KSM, but for the blocks populated already, e.g. for
+    // a previous driver, before a reset.
+    fn advise_idle_mergeable(&self, mem: &GuestMemoryMmap) {
+        for region in mem.iter() {
+            let region_block = (
+                region.start_addr().raw_value() >> VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+                region.len() >> VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+            );
+            advise_ksm(mem, region_block, true);
+        }
+        for (start, end) in self.populated_ranges.ranges() {
+            advise_ksm(mem, (start, end - start), false);
+        }
     }
 
-    pub(crate) fn set_stats_desc_index(&mut self, stats_desc_index: Option<u16>) {
-        self.stats_desc_index = stats_desc_index;
+    /// Reports the host memory used by the guest through the device.
+    pub fn footprint(&self) -> FaascaleMemFootprint {
+        let pages_to_mib = |pages: u64| pages / u64::from(MIB_TO_4K_PAGES);
+        FaascaleMemFootprint {
+            populated_mib: pages_to_mib(self.populated_ranges.num_pages()),
+            pinned_mib: pages_to_mib(self.pinned_pages()),
+            fenced: self.fenced,
+            pool: self.pool.as_ref().map(HostMemoryPool::usage),
+            interleaved_mib: self.interleave.as_ref().map(|interleave| {
+                pages_to_mib(interleave.interleaved_pages(&self.populated_ranges))
+            }),
+            mlock: self.mlock.as_ref().map(BlockMlock::usage),
+            spilled_mib: self
+                .spill_file
+                .as_ref()
+                .map(|spill| pages_to_mib(spill.spilled_pages())),
+            thp_backed_mib: self.thp_backed_bytes.map(|bytes| bytes >> 20),
+        }
     }
-}
 
-impl VirtioDevice for FaascaleMem {
-    fn avail_features(&self) -> u64 {
-        self.avail_features
+    /// Estimates whether and how fast the guest could populate up to `target_mib`, given the
+    /// limits of the device, the memory of the host and the population throughput so far.
+    pub fn estimate(&self, target_mib: u32) -> FaascaleMemEstimate {
+        let pages_to_mib = |pages: u64| pages / u64::from(MIB_TO_4K_PAGES);
+        // The free huge pages only matter when they back the guest memory.
+        let hugetlb = self.device_state.mem().map_or(false, |mem| {
+            mem.iter()
+                .any(|region| hugetlb_page_size(region.flags()).is_some())
+        });
+        estimate(
+            target_mib,
+            &EstimateInputs {
+                populated_mib: pages_to_mib(self.populated_ranges.num_pages()),
+                fenced: self.fenced,
+                max_populated_mib: self.max_populated_pages.map(pages_to_mib),
+                budget_mib: self
+                    .budget
+                    .agreed_pages()
+                    .filter(|_| self.budget_enabled())
+                    .map(|pages| pages_to_mib(u64::from(pages))),
+                host: HostMemInfo::read(),
+                hugetlb,
+                throughput: self.populate_throughput,
+            },
+        )
     }
 
-    fn acked_features(&self) -> u64 {
-        self.acked_features
+    /// Reports the guest memory populated through the device.
+    pub fn blocks_info(&self) -> FaascaleMemBlocks {
+        FaascaleMemBlocks {
+            populated_mib: self.populated_ranges.num_pages() / u64::from(MIB_TO_4K_PAGES),
+            blocks: self
+                .populated_blocks()
+                .into_iter()
+                .map(|(start_pfn, num_pages)| FaascaleMemBlock {
+                    start_pfn,
+                    num_pages,
+                })
+                .collect(),
+        }
     }
 
-    fn set_acked_features(&mut self, acked_features: u64) {
-        self.acked_features = acked_features;
+    /// Reports the populate and depopulate activity over the guest physical address space.
+    pub fn heatmap(&self) -> FaascaleMemHeatmap {
+        self.heatmap.report()
     }
 
-    fn device_type(&self) -> u32 {
-        TYPE_FAASCALE_MEM
+    /// Reports the recent populate and depopulate failures.
+    pub fn errors(&self) -> FaascaleMemErrors {
+        self.error_log.report()
     }
 
-    fn queues(&self) -> &[Queue] {
-        &self.queues
+    /// Whether the memory state is to be published into MMDS.
+    pub fn mmds_publish(&self) -> bool {
+        self.mmds_publish
     }
 
-    fn queues_mut(&mut self) -> &mut [Queue] {
-        &mut self.queues
+    /// Sets the MMDS data store the memory state is published into, and publishes it. Left
+    /// unset unless the publishing is enabled.
+    pub fn set_mmds(&mut self, mmds: Option<Arc<Mutex<Mmds>>>) {
+        if !self.mmds_publish {
+            return;
+        }
+        match mmds {
+            Some(mmds) => {
+                self.mmds_publisher = Some(MmdsPublisher::new(mmds));
+                self.publish_mmds();
+            }
+            None => {
+                warn!("faascale-mem: MMDS is not configured, the memory state is not published.")
+            }
+        }
     }
 
-    fn queue_events(&self) -> &[EventFd] {
-        &self.queue_evts
+    /// Host vsock port the guest connects to for the memory activity, if served.
+    pub fn vsock_observer_port(&self) -> Option<u32> {
+        self.vsock_observer_port
     }
 
-    fn interrupt_evt(&self) -> &EventFd {
-        &self.irq_trigger.irq_evt
+    /// How the blocks populated by the guest are backed.
+    pub fn populate_mode(&self) -> FaascaleMemPopulateMode {
+        match self.lazy_populate {
+            Some(_) => FaascaleMemPopulateMode::Lazy,
+            None => FaascaleMemPopulateMode::Eager,
+        }
     }
 
-    fn interrupt_status(&self) -> Arc<AtomicUsize> {
-        self.irq_trigger.irq_status.clone()
+    /// Reports the memory activity, as answered on the observer port.
+    pub fn observed_activity(&self) -> FaascaleMemObservedActivity {
+        self.request_activity.report(
+            self.populated_ranges.num_pages() / u64::from(MIB_TO_4K_PAGES),
+            self.size_mb(),
+        )
     }
 
-    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_space_bytes = self.config_space.as_slice();
-        let config_len = config_space_bytes.len() as u64;
-        if offset >= config_len {
-            error!("Failed to read config space");
-            return;
+    /// Summarizes the memory state, as published into MMDS.
+    pub fn mmds_summary(&self) -> FaascaleMemMmdsSummary {
+        let pages_to_mib = |pages: u32| pages / MIB_TO_4K_PAGES;
+        FaascaleMemMmdsSummary {
+            populated_mib: self.populated_ranges.num_pages() / u64::from(MIB_TO_4K_PAGES),
+            target_mib: self.size_mb(),
+            max_populated_mib: self.max_populated_pages.map(|max_pages| {
+                u32::try_from(max_pages / u64::from(MIB_TO_4K_PAGES)).unwrap_or(u32::MAX)
+            }),
+            budget_mib: self
+                .budget
+                .agreed_pages()
+                .filter(|_| self.budget_enabled())
+                .map(pages_to_mib),
+            pressure_some_avg10: self.latest_stats.memory_pressure_some_avg10,
         }
+    }
 
-        if let Some(end) = offset.checked_add(data.len() as u64) {
-            // This write can't fail, offset and end are checked against config_len.
-            data.write_all(
-                &config_space_bytes[offset as usize..cmp::min(end, config_len) as usize],
-            )
-                .unwrap();
+    /// Publishes the memory state into MMDS if it changed since it was last published.
+    pub(crate) fn publish_mmds(&mut self) {
+        if self.mmds_publisher.is_none() {
+            return;
+        }
+        let summary = self.mmds_summary();
+        if let Some(publisher) = self.mmds_publisher.as_mut() {
+            publisher.publish(summary);
         }
     }
 
-    fn write_config(&mut self, offset: u64, data: &[u8]) {
-        let data_len = data.len() as u64;
-        let config_space_bytes = self.config_space.as_mut_slice();
-        let config_len = config_space_bytes.len() as u64;
-        if offset + data_len > config_len {
-            error!("Failed to write config space");
-            return;
+    /// Runs the internal consistency checks of the device.
+    pub fn health(&self) -> FaascaleMemHealth {
+        let mut health = FaascaleMemHealth::default();
+
+        health.add_check("device_active", self.is_activated(), || {
+            "the guest driver has not activated the device".to_string()
+        });
+
+        if let Some(mem) = self.device_state.mem() {
+            for (index, queue) in self.queues.iter().enumerate() {
+                health.add_check(&format!("queue_{}_valid", index), queue.is_valid(mem), || {
+                    format!(
+                        "size {}, next_avail {}, next_used {}",
+                        queue.size, queue.next_avail, queue.next_used
+                    )
+                });
+            }
+
+            if self.stats_enabled() {
+                let timer_armed = !matches!(self.stats_timer.get_state(), TimerState::Disarmed);
+                health.add_check("stats_timer_armed", timer_armed, || {
+                    format!(
+                        "statistics are polled every {}s but the timer is disarmed",
+                        self.stats_polling_interval_s
+                    )
+                });
+            }
         }
-        config_space_bytes[offset as usize..(offset + data_len) as usize].copy_from_slice(data);
+
+        // The driver only writes the actual pages once it populated memory.
+        let actual_pages = u64::from(self.config_space.actual_pages);
+        let populated_pages = self.populated_ranges.num_pages();
+        health.add_check(
+            "tracker_matches_config_space",
+            actual_pages == 0 || actual_pages == populated_pages,
+            || {
+                format!(
+                    "the guest reports {} actual pages, {} pages are tracked as populated",
+                    actual_pages, populated_pages
+                )
+            },
+        );
+
+        for (name, handle) in &self.worker_threads {
+            health.add_check(&format!("{}_alive", name), !handle.is_finished(), || {
+                format!("the {} thread exited", name)
+            });
+        }
+
+        health.healthy = health.checks.iter().all(|check| check.passed);
+        for (field, waited) in self
+            .config_acks
+            .overdue(self.clock.now(), CONFIG_ACK_TIMEOUT)
+        {
+            health.warnings.push(format!(
+                "the guest has not acknowledged the change of `{}` for {}s",
+                field,
+                waited.as_secs()
+            ));
+        }
+        health
     }
 
-    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
-        self.device_state = DeviceState::Activated(mem);
-        if self.activate_evt.write(1).is_err() {
-            error!("FaascaleMem: Cannot write to activate_evt");
-            METRICS.faascale_mem.activate_fails.inc();
-            self.device_state = DeviceState::Inactive;
-            return Err(super::super::ActivateError::BadActivate);
+    /// Cross-checks the config space, the range trackers and the statistics reported by the
+    /// guest against each other and against the `mem_size_mib` MiB of guest memory. Returns the
+    /// failed checks, as `(check, detail)` pairs.
+    pub fn verify(&self, mem_size_mib: u64) -> Vec<(&'static str, String)> {
+        let mut inconsistencies = Vec::new();
+        let mem_pages = mem_size_mib * u64::from(MIB_TO_4K_PAGES);
+        let populated_pages = self.populated_ranges.num_pages();
+
+        let target_pages = u64::from(self.config_space.num_pages);
+        if target_pages > mem_pages {
+            inconsistencies.push((
+                "target_within_guest_memory",
+                format!(
+                    "target of {} pages over {} MiB of guest memory",
+                    target_pages, mem_size_mib
+                ),
+            ));
         }
 
-        if self.stats_enabled() {
-            self.update_timer_state();
+        if let Some(mem) = self.device_state.mem() {
+            let page_addr = |pfn: u64| GuestAddress(pfn << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+            let outside: Vec<_> = self
+                .populated_ranges
+                .ranges()
+                .filter(|&(start, end)| {
+                    !mem.address_in_range(page_addr(start))
+                        || !mem.address_in_range(page_addr(end).unchecked_sub(1))
+                })
+                .collect();
+            if let Some((start, _)) = outside.first() {
+                inconsistencies.push((
+                    "populated_within_guest_memory",
+                    format!(
+                        "{} populated ranges lie outside the guest memory, the first at pfn {:#x}",
+                        outside.len(),
+                        start
+                    ),
+                ));
+            }
         }
 
-        Ok(())
+        // The driver only writes the actual pages once it populated memory.
+        let actual_pages = u64::from(self.config_space.actual_pages);
+        if actual_pages != 0 && actual_pages != populated_pages {
+            inconsistencies.push((
+                "actual_pages_match_populated",
+                format!(
+                    "the guest reports {} actual pages, {} pages are tracked as populated",
+                    actual_pages, populated_pages
+                ),
+            ));
+        }
+
+        let unpinned: u64 = self
+            .host_pinned_ranges
+            .ranges()
+            .map(|(start, end)| {
+                (end - start) - self.pinned_ranges.overlap_pages((start, end - start))
+            })
+            .sum();
+        if unpinned != 0 {
+            inconsistencies.push((
+                "host_pins_applied",
+                format!("{} pages pinned through the API are not pinned", unpinned),
+            ));
+        }
+
+        // The contents of the populated pages live in the guest memory only.
+        if let Some(spill) = self.spill_file.as_ref() {
+            let spilled: u64 = self
+                .populated_ranges
+                .ranges()
+                .map(|(start, end)| spill.overlap_pages((start, end - start)))
+                .sum();
+            if spilled != 0 {
+                inconsistencies.push((
+                    "spilled_not_populated",
+                    format!("{} populated pages are also in the spill file", spilled),
+                ));
+            }
+        }
+        if let Some(batcher) = self.depopulate_batcher.as_ref() {
+            let held_back: u64 = self
+                .populated_ranges
+                .ranges()
+                .flat_map(|(start, end)| batcher.held_back((start, end - start)))
+                .map(|(_, num_pages)| num_pages)
+                .sum();
+            if held_back != 0 {
+                inconsistencies.push((
+                    "held_back_not_populated",
+                    format!("{} populated pages are also held back", held_back),
+                ));
+            }
+        }
+
+        if let Some(total_memory) = self.latest_stats.total_memory {
+            if total_memory > mem_size_mib << 20 {
+                inconsistencies.push((
+                    "guest_total_within_guest_memory",
+                    format!(
+                        "the guest reports {} bytes of total memory over {} MiB of guest memory",
+                        total_memory, mem_size_mib
+                    ),
+                ));
+            }
+        }
+
+        inconsistencies
     }
 
-    fn is_activated(&self) -> bool {
-        self.device_state.is_activated()
+    /// Marks the statistics carried over from a snapshot as stale until the guest reports
+    /// again. Without any memory figures of the guest in the snapshot, conservative ones are
+    /// estimated from the populated memory, all of it taken as in use.
+    pub(crate) fn mark_restored_stats(&mut self) {
+        let populated_bytes = self.populated_bytes();
+        let stats = &mut self.latest_stats;
+        if stats.total_memory.is_some()
+            || stats.free_memory.is_some()
+            || stats.available_memory.is_some()
+        {
+            stats.freshness = FaascaleMemStatsFreshness::RestoredStale;
+        } else if populated_bytes > 0 {
+            stats.total_memory = Some(populated_bytes);
+            stats.free_memory = Some(0);
+            stats.available_memory = Some(0);
+            stats.freshness = FaascaleMemStatsFreshness::Simulated;
+        }
     }
-}
\ No newline at end of file
+
+    // The first report of the guest after a restore replaces the statistics of the snapshot, and
+    // drops the estimates of the host.
+    fn refresh_restored_stats(&mut self) {
+        let stats = &mut self.latest_stats;
+        if stats.freshness == FaascaleMemStatsFreshness::Simulated {
+            stats.total_memory = None;
+            stats.free_memory = None;
+            stats.available_memory = None;
+        }
+        stats.freshness = FaascaleMemStatsFreshness::Live;
+    }
+
+    // Records the time of the latest statistics sample, and the growth of the counters since
+    // the `previous` one. Adapts the polling interval to the change of the available memory.
+    fn update_stats_deltas(&mut self, previous: &FaascaleMemStats) {
+        let now = self.clock.now();
+        if let Some(last_sample) = self.last_stats_sample.replace(now) {
+            self.latest_stats
+                .update_deltas(previous, now.duration_since(last_sample));
+        }
+
+        let available_memory = self.latest_stats.available_memory;
+        let adapted = self
+            .polling_adaptation
+            .as_mut()
+            .map_or(false, |adaptation| {
+                adaptation.sample(previous.available_memory, available_memory)
+            });
+        if adapted {
+            METRICS.faascale_mem.stats_polling_adaptations.inc();
+            debug!(
+                "faascale-mem: statistics polled every {}ms",
+                self.effective_stats_polling_interval().as_millis()
+            );
+            if !matches!(self.stats_timer.get_state(), TimerState::Disarmed) {
+                self.update_timer_state();
+            }
+        }
+
+        self.run_policy();
+        self.publish_mmds();
+    }
+
+    // Runs the policy program, if any, on the latest statistics and applies its decisions.
+    fn run_policy(&mut self) {
+        let policy = match self.policy.as_ref() {
+            Some(policy) => policy,
+            None => return,
+        };
+        let bytes_to_mib = |bytes: Option<u64>| bytes.map(|bytes| bytes >> 20);
+        let pages_to_mib = |pages: u64| pages / u64::from(MIB_TO_4K_PAGES);
+        let inputs = PolicyInputs {
+            available_mib: bytes_to_mib(self.latest_stats.available_memory),
+            free_mib: bytes_to_mib(self.latest_stats.free_memory),
+            total_mib: bytes_to_mib(self.latest_stats.total_memory),
+            caches_mib: bytes_to_mib(self.latest_stats.disk_caches),
+            swap_in: self.latest_stats.swap_in,
+            swap_out: self.latest_stats.swap_out,
+            major_faults: self.latest_stats.major_faults,
+            minor_faults: self.latest_stats.minor_faults,
+            populated_mib: pages_to_mib(self.populated_ranges.num_pages()),
+            pinned_mib: pages_to_mib(self.pinned_ranges.num_pages()),
+        };
+
+        METRICS.faascale_mem.policy_runs.inc();
+        let decision = match policy.run(&inputs) {
+            Ok(decision) => decision,
+            Err(err) => {
+                METRICS.faascale_mem.policy_fails.inc();
+                if err.is_budget_exceeded() {
+                    METRICS.faascale_mem.policy_budget_exceeded.inc();
+                }
+                error!("faascale-mem: policy run failed: {}", err);
+                return;
+            }
+        };
+        // Policies mostly decide the same on consecutive updates, only the changes are logged at
+        // the info level.
+        if self.policy_decision == Some(decision) {
+            debug!("faascale-mem: policy decided {:?}", decision);
+        } else {
+            info!("faascale-mem: policy decided {:?}", decision);
+        }
+        self.policy_decision = Some(decision);
+        if let Err(err) = self.apply_policy_decision(decision) {
+            METRICS.faascale_mem.policy_fails.inc();
+            error!(
+                "faascale-mem: failed to apply the policy decision: {:?}",
+                err
+            );
+        }
+    }
+
+    // Applies the decisions of the policy program that differ from the current state of the
+    // device.
+    fn apply_policy_decision(
+        &mut self,
+        decision: FaascaleMemPolicyDecision,
+    ) -> Result<(), FaascaleMemError> {
+        if let Some(cap_mib) = decision.populate_cap_mib {
+            let max_pages = Some(u64::from(cap_mib) * u64::from(MIB_TO_4K_PAGES));
+            if self.max_populated_pages != max_pages {
+                self.max_populated_pages = max_pages;
+                self.config_epoch += 1;
+            }
+        }
+
+        // The budget and the release requests go to the guest driver.
+        if !self.is_activated() {
+            return Ok(());
+        }
+        if let Some(budget_mib) = decision.budget_mib {
+            if self.budget_enabled()
+                && budget_mib.checked_mul(MIB_TO_4K_PAGES) != Some(self.budget.offered_pages())
+            {
+                self.update_budget(budget_mib)?;
+            }
+        }
+        if let Some(release_mib) = decision.release_mib {
+            if release_mib != 0
+                && release_mib.checked_mul(MIB_TO_4K_PAGES) != Some(self.config_space.release_pages)
+            {
+                self.request_release(release_mib)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pins or unpins the `(start pfn, number of pages)` block. Depopulate requests overlapping
+    /// a pinned block are refused until it is unpinned.
+    pub fn update_pinned_range(&mut self, block: (u32, u32), pinned: bool) {
+        let block = (u64::from(block.0), u64::from(block.1));
+        if pinned {
+            self.pinned_ranges.insert(block);
+            self.host_pinned_ranges.insert(block);
+        } else {
+            self.pinned_ranges.remove(block);
+            self.host_pinned_ranges.remove(block);
+        }
+        METRICS
+            .faascale_mem
+            .pinned_pages
+            .store(self.pinned_ranges.num_pages() as usize);
+    }
+
+    /// Number of guest pages pinned against depopulation.
+    pub fn pinned_pages(&self) -> u64 {
+        self.pinned_ranges.num_pages()
+    }
+
+    /// Locks the populated `(start pfn, number of pages)` block into host memory, within the
+    /// locking budget, or unlocks it. The block stays locked until unlocked or depopulated.
+    pub fn update_mlocked_range(
+        &mut self,
+        block: (u32, u32),
+        locked: bool,
+    ) -> Result<(), FaascaleMemError> {
+        let block = (u64::from(block.0), u64::from(block.1));
+        let mem = self
+            .device_state
+            .mem()
+            .ok_or(FaascaleMemError::DeviceNotActive)?;
+        let mlock = self.mlock.as_mut().ok_or(FaascaleMemError::MlockDisabled)?;
+        if !locked {
+            mlock.unlock(mem, block);
+            return Ok(());
+        }
+        // Locking would fault in the pages the guest did not populate.
+        if self.populated_ranges.overlap_pages(block) != block.1 {
+            return Err(FaascaleMemError::MlockNotPopulated);
+        }
+        mlock.lock(mem, block)
+    }
+
+    /// Guest ranges populated through the device and not depopulated since, as
+    /// `(start pfn, number of pages)` blocks in ascending order. Adjacent blocks are merged.
+    pub fn populated_blocks(&self) -> Vec<(u64, u64)> {
+        self.populated_ranges
+            .ranges()
+            .map(|(start, end)| (start, end - start))
+            .collect()
+    }
+
+    /// Guest memory populated through the device and not depopulated since, in bytes.
+    pub fn populated_bytes(&self) -> u64 {
+        self.populated_ranges.num_pages() << VIRTIO_FAASCALE_MEM_PFN_SHIFT
+    }
+
+    /// Removes the `(start pfn, number of pages)` block from the guest memory without waiting
+    /// for the guest to depopulate it. The guest is not told: the content of the block is lost
+    /// and the guest reads zeroes the next time it touches it. Blocks overlapping a pinned range
+    /// are refused.
+    pub fn depopulate_range(&mut self, block: (u32, u32)) -> Result<(), FaascaleMemError> {
+        let block = (u64::from(block.0), u64::from(block.1));
+        let mem = self
+            .device_state
+            .mem()
+            .ok_or(FaascaleMemError::DeviceNotActive)?;
+        if self.pinned_ranges.overlaps(block) {
+            METRICS.faascale_mem.depopulate_pinned_refusals.inc();
+            return Err(FaascaleMemError::DepopulatePinned);
+        }
+        self.populate_tracker
+            .forget_overlapping(block, self.clock.now());
+        release_block(
+            mem,
+            block,
+            self.encryption_backend.as_deref_mut(),
+            self.pool.as_mut(),
+            self.restored.then_some(&mut self.mmap_overlays),
+            self.depopulate_mode,
+            self.ksm,
+            self.mlock.as_mut(),
+            self.lazy_populate.as_ref(),
+        )
+        .map_err(FaascaleMemError::RemoveMemoryRegion)?;
+        self.populated_ranges.remove(block);
+        self.heatmap.depopulated(block);
+        METRICS
+            .faascale_mem
+            .host_depopulated_pages
+            .add(block.1 as usize);
+        Ok(())
+    }
+
+    /// Faults in the guest memory populated through the device from the snapshot memory file,
+    /// as done on the clones of a warm pool template before they are resumed. Only the populated
+    /// memory within the `(start pfn, number of pages)` blocks is faulted in, or all of it
+    /// without blocks. Blocks failing to fault in are reported and left to the guest.
+    pub fn rehydrate(
+        &self,
+        blocks: &[(u32, u32)],
+    ) -> Result<FaascaleMemWarmReport, FaascaleMemError> {
+        let mem = self
+            .device_state
+            .mem()
+            .ok_or(FaascaleMemError::DeviceNotActive)?;
+
+        let mut wanted = PfnRanges::default();
+        for &(start_pfn, num_pages) in blocks {
+            wanted.insert((u64::from(start_pfn), u64::from(num_pages)));
+        }
+        let start = Instant::now();
+        let mut report = FaascaleMemWarmReport::default();
+        for populated in self.populated_blocks() {
+            let pieces = if blocks.is_empty() {
+                vec![populated]
+            } else {
+                wanted.intersection(populated)
+            };
+            for block in pieces {
+                match rehydrate_range(mem, block_range(block)) {
+                    Ok(()) => {
+                        METRICS.faascale_mem.rehydrated_pages.add(block.1 as usize);
+                        report.record(block, true);
+                    }
+                    Err(err) => {
+                        METRICS.faascale_mem.rehydrate_fails.inc();
+                        error!(
+                            "Error rehydrating block: start_pfn={}, size={}: {:?}",
+                            block.0, block.1, err
+                        );
+                        report.record(block, false);
+                    }
+                }
+            }
+        }
+        report.elapsed_us = start.elapsed().as_micros() as u64;
+        Ok(report)
+    }
+
+    /// Asks the guest to release `release_mib` of the memory it populated. The guest driver
+    /// reads the amount from the config space and depopulates it through the depopulate queue.
+    pub fn request_release(&mut self, release_mib: u32) -> Result<(), FaascaleMemError> {
+        if !self.is_activated() {
+            return Err(FaascaleMemError::DeviceNotActive);
+        }
+
+        self.config_space.release_pages = release_mib
+            .checked_mul(MIB_TO_4K_PAGES)
+            .ok_or(FaascaleMemError::TooManyPagesRequested)?;
+        self.irq_trigger
+            .trigger_irq(IrqType::Config)
+            .map_err(FaascaleMemError::InterruptError)?;
+        self.config_acks.notify("release_pages", self.clock.now());
+        METRICS.faascale_mem.release_requests.inc();
+        self.config_epoch += 1;
+        Ok(())
+    }
+
+    // The guest responded to the config interrupts, by writing to the config space or by
+    // sending requests on a queue. The changes it took too long to acknowledge are reported.
+    fn acknowledge_config_changes(&mut self) {
+        for (field, waited) in self
+            .config_acks
+            .acknowledge(self.clock.now(), CONFIG_ACK_TIMEOUT)
+        {
+            METRICS.faascale_mem.late_config_acks.inc();
+            warn!(
+                "faascale-mem: the guest acknowledged the change of `{}` after {}ms",
+                field,
+                waited.as_millis()
+            );
+        }
+    }
+
+    /// Number of times the guest driver reset the device, as it does to negotiate the features
+    /// again after a kexec or when the driver is reloaded.
+    pub fn driver_resets(&self) -> u64 {
+        self.driver_resets
+    }
+
+    // Ends the session of the guest driver. What the driver negotiated or asked for is dropped,
+    // while the host policies and the memory populated so far are kept: the next driver finds
+    // the guest memory as the previous one left it.
+    fn reset_driver_session(&mut self) {
+        // The pieces held back belong to blocks the previous driver gave back.
+        self.release_expired_depopulations(Duration::ZERO);
+        if self.depopulate_on_reset {
+            self.depopulate_driver_blocks();
+        }
+        self.device_state = DeviceState::Inactive;
+        self.acked_features = 0;
+        // The interrupts raised for the previous driver are not pending for the next one.
+        self.irq_trigger.irq_status.store(0, Ordering::SeqCst);
+        self.stats_timer
+            .set_state(TimerState::Disarmed, SetTimeFlags::Default);
+        self.stats_desc_index = None;
+        self.latest_stats = FaascaleMemStats::default();
+        self.last_stats_sample = None;
+        if let Some(adaptation) = self.polling_adaptation.as_mut() {
+            adaptation.reset();
+        }
+        self.populate_kicked_at = None;
+        self.config_acks.clear();
+        // A block the next driver populates right away is not a request re-submitted by the
+        // previous one.
+        self.populate_tracker = PopulateTracker::new(self.populate_tracker.max_entries());
+        METRICS.faascale_mem.tracker_entries.store(0);
+
+        // Only the blocks pinned through the API stay pinned.
+        let mut pinned_ranges = PfnRanges::default();
+        for (start, end) in self.host_pinned_ranges.ranges() {
+            pinned_ranges.insert_range(start, end);
+        }
+        self.pinned_ranges = pinned_ranges;
+        METRICS
+            .faascale_mem
+            .pinned_pages
+            .store(self.pinned_ranges.num_pages() as usize);
+
+        // The next driver has to acknowledge the budget again before it is enforced.
+        if self.budget_enabled() {
+            self.config_space.budget_epoch = self.budget.restart();
+        }
+        self.config_space.actual_pages = 0;
+        self.config_space.release_pages = 0;
+        self.boot_warmup = BootWarmupTracker::default();
+        // The next driver must not see the contents the previous one gave back.
+        if let Some(cache) = self.block_cache.as_mut() {
+            cache.clear();
+        }
+        if let Some(spill) = self.spill_file.as_mut() {
+            spill.clear();
+        }
+
+        self.driver_resets += 1;
+        METRICS.faascale_mem.driver_resets.inc();
+        info!(
+            "faascale-mem: guest driver reset the device, starting driver session {} with {} MiB \
+             populated and {} MiB pinned",
+            self.driver_resets + 1,
+            self.populated_ranges.num_pages() / u64::from(MIB_TO_4K_PAGES),
+            self.pinned_ranges.num_pages() / u64::from(MIB_TO_4K_PAGES)
+        );
+    }
+
+    // Depopulates the memory populated by the guest driver ending its session, but for the blocks
+    // pinned through the API. The blocks failing to depopulate stay tracked as populated.
+    fn depopulate_driver_blocks(&mut self) {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return,
+        };
+        let mut blocks = PfnRanges::default();
+        for (start, end) in self.populated_ranges.ranges() {
+            blocks.insert_range(start, end);
+        }
+        for (start, end) in self.host_pinned_ranges.ranges() {
+            blocks.remove((start, end - start));
+        }
+
+        for (start, end) in blocks.ranges() {
+            let block = (start, end - start);
+            match release_block(
+                mem,
+                block,
+                self.encryption_backend.as_deref_mut(),
+                self.pool.as_mut(),
+                self.restored.then_some(&mut self.mmap_overlays),
+                self.depopulate_mode,
+                self.ksm,
+                self.mlock.as_mut(),
+                self.lazy_populate.as_ref(),
+            ) {
+                Ok(()) => {
+                    self.populated_ranges.remove(block);
+                    self.heatmap.depopulated(block);
+                    METRICS
+                        .faascale_mem
+                        .reset_depopulated_pages
+                        .add(block.1 as usize);
+                }
+                Err(err) => {
+                    self.error_log
+                        .record(FaascaleMemOperation::Depopulate, block, &err);
+                    error!(
+                        "faascale-mem: failed to depopulate pfns {:#x}..{:#x} on reset: {:?}",
+                        start, end, err
+                    )
+                }
+            }
+        }
+    }
+
+    /// Populates the `(start pfn, number of pages)` blocks ahead of the guest, to warm up the
+    /// microVM before an invocation arrives, or the template of a warm pool before it is
+    /// snapshotted. The blocks are populated as if the guest asked for them, short of the budget
+    /// the guest agreed on, which does not bind the host. Blocks over the populated memory cap or
+    /// failing to populate are reported and skipped.
+    pub fn prepopulate_blocks(
+        &mut self,
+        blocks: &[(u32, u32)],
+    ) -> Result<FaascaleMemWarmReport, FaascaleMemError> {
+        let mem = self
+            .device_state
+            .mem()
+            .ok_or(FaascaleMemError::DeviceNotActive)?
+            .clone();
+        if self.fenced {
+            return Err(FaascaleMemError::DeviceFenced);
+        }
+
+        let start = Instant::now();
+        let mut report = FaascaleMemWarmReport::default();
+        for &(start_pfn, num_pages) in blocks {
+            let block = (u64::from(start_pfn), u64::from(num_pages));
+            // The guest asking for the block right away finds it in place, with the template
+            // and spilled contents read back in.
+            let populated = self.populate_block(&mem, PopulateRequest::host(block))
+                == VIRTIO_FAASCALE_MEM_STATUS_OK;
+            if populated {
+                METRICS
+                    .faascale_mem
+                    .prepopulated_pages
+                    .add(block.1 as usize);
+            }
+            report.record(block, populated);
+        }
+        self.flush_prefault_batch();
+        report.elapsed_us = start.elapsed().as_micros() as u64;
+        Ok(report)
+    }
+
+    /// Populates `amount_mib` of the guest memory not populated yet ahead of the guest, from the
+    /// lowest guest addresses up. The memory is populated in blocks of at most 1 GiB, reported
+    /// like `prepopulate_blocks`.
+    pub fn prepopulate_amount(
+        &mut self,
+        amount_mib: u32,
+    ) -> Result<FaascaleMemWarmReport, FaascaleMemError> {
+        let mem = self
+            .device_state
+            .mem()
+            .ok_or(FaascaleMemError::DeviceNotActive)?;
+
+        let max_block_pages = u64::from(MIB_TO_4K_PAGES) * 1024;
+        let mut remaining = u64::from(amount_mib) * u64::from(MIB_TO_4K_PAGES);
+        let mut blocks = Vec::new();
+        for region in mem.iter() {
+            let region_start = region.start_addr().0 >> VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+            let region_end = region_start + (region.len() >> VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+            // The gaps between the populated ranges within the region.
+            let mut gaps = Vec::new();
+            let mut next = region_start;
+            for (start, end) in self.populated_ranges.ranges() {
+                if end <= next || start >= region_end {
+                    continue;
+                }
+                if start > next {
+                    gaps.push((next, start));
+                }
+                next = end;
+            }
+            if next < region_end {
+                gaps.push((next, region_end));
+            }
+            for (mut start, end) in gaps {
+                while start < end && remaining > 0 {
+                    let num_pages = cmp::min(cmp::min(end - start, max_block_pages), remaining);
+                    // Guest frames beyond 32 bits cannot be given in a block.
+                    let block = match u32::try_from(start) {
+                        Ok(start_pfn) => (start_pfn, num_pages as u32),
+                        Err(_) => break,
+                    };
+                    blocks.push(block);
+                    start += num_pages;
+                    remaining -= num_pages;
+                }
+            }
+        }
+        self.prepopulate_blocks(&blocks)
+    }
+
+    /// Rate limiter of the populate and depopulate requests of the guest.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Updates the buckets of the rate limiter of the populate and depopulate requests.
+    pub fn update_rate_limiter(&mut self, bytes: BucketUpdate, ops: BucketUpdate) {
+        if matches!(bytes, BucketUpdate::None) && matches!(ops, BucketUpdate::None) {
+            return;
+        }
+        self.rate_limiter.update_buckets(bytes, ops);
+        self.config_epoch += 1;
+    }
+
+    /// Fences or unfences the device. The populate requests of a fenced device are
+    /// acknowledged but refused, while the depopulate requests and statistics are still handled.
+    ///
+    /// The fence is advisory: it stops the device from allocating host memory ahead of the
+    /// guest, not the guest from using its memory. Only a guest which negotiated the STATUS
+    /// feature learns that its request was refused. Any other guest takes the block as
+    /// populated, and its pages are faulted in as it touches them.
+    pub fn set_fenced(&mut self, fenced: bool) {
+        self.fenced = fenced;
+    }
+
+    /// Whether the populate requests are refused.
+    pub fn fenced(&self) -> bool {
+        self.fenced
+    }
+
+    /// Routes the population of the guest memory through the backend of an encrypted guest.
+    /// Fails if the host does not support the memory encryption of the backend.
+    pub fn set_encryption_backend(
+        &mut self,
+        backend: Box<dyn EncryptedMemoryBackend>,
+    ) -> Result<(), FaascaleMemError> {
+        if !backend.is_supported() {
+            return Err(FaascaleMemError::MemoryEncryptionUnsupported);
+        }
+        // What the registered pages hold is up to the backend, so they are not promised to be
+        // zero-filled.
+        self.avail_features &= !(1u64 << VIRTIO_FAASCALE_MEM_F_ZEROED);
+        self.encryption_backend = Some(backend);
+        Ok(())
+    }
+
+    /// Memory encryption of the guest, if an encryption backend is set.
+    pub fn memory_encryption(&self) -> Option<MemoryEncryptionKind> {
+        self.encryption_backend
+            .as_ref()
+            .map(|backend| backend.kind())
+    }
+
+    // Whether the guest tells the backing granularity of the blocks it populates.
+    pub(crate) fn granularity_hints_enabled(&self) -> bool {
+        self.acked_features & (1u64 << VIRTIO_FAASCALE_MEM_F_BLOCK_GRANULARITY) != 0
+    }
+
+    // Whether the guest attaches a trace ID to the blocks it populates.
+    pub(crate) fn trace_ids_enabled(&self) -> bool {
+        self.acked_features & (1u64 << VIRTIO_FAASCALE_MEM_F_TRACE_IDS) != 0
+    }
+
+    // Whether the guest sends blocks made of a 64-bit pfn and page count.
+    pub(crate) fn wide_blocks_enabled(&self) -> bool {
+        self.acked_features & (1u64 << VIRTIO_FAASCALE_MEM_F_WIDE_BLOCKS) != 0
+    }
+
+    // Whether the guest flags the populated blocks it wants locked.
+    pub(crate) fn mlock_enabled(&self) -> bool {
+        self.acked_features & (1u64 << VIRTIO_FAASCALE_MEM_F_MLOCK) != 0
+    }
+
+    // Whether the guest may pin the blocks it populates.
+    pub(crate) fn pin_enabled(&self) -> bool {
+        self.acked_features & (1u64 << VIRTIO_FAASCALE_MEM_F_PIN) != 0
+    }
+
+    // Whether the status of every populate and depopulate request is written back to the guest.
+    pub(crate) fn status_enabled(&self) -> bool {
+        self.acked_features & (1u64 << VIRTIO_FAASCALE_MEM_F_STATUS) != 0
+    }
+
+    // Whether the guest relies on the populated pages being zero-filled.
+    pub(crate) fn zeroed_enabled(&self) -> bool {
+        self.acked_features & (1u64 << VIRTIO_FAASCALE_MEM_F_ZEROED) != 0
+    }
+
+    // Parts of the `(start pfn, number of pages)` block whose pages may hold data once
+    // populated: the ones populated already, and the pieces of huge pages held back since the
+    // guest depopulated them.
+    fn recycled_blocks(&self, block: (u64, u64)) -> Vec<(u64, u64)> {
+        let mut recycled = PfnRanges::default();
+        for piece in self.populated_ranges.intersection(block) {
+            recycled.insert(piece);
+        }
+        if let Some(ref batcher) = self.depopulate_batcher {
+            for piece in batcher.held_back(block) {
+                recycled.insert(piece);
+            }
+        }
+        recycled
+            .ranges()
+            .map(|(start, end)| (start, end - start))
+            .collect()
+    }
+
+    pub(crate) fn stats_enabled(&self) -> bool {
+        self.stats_polling_interval_s > 0
+    }
+
+    // Whether the statistics queue exists, even if the statistics are disabled. Only devices
+    // restored from older snapshots lack it when the statistics are disabled.
+    pub(crate) fn stats_queue_present(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_FAASCALE_MEM_F_STATS_VQ) != 0
+    }
+
+    pub(crate) fn budget_enabled(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_FAASCALE_MEM_F_BUDGET) != 0
+    }
+
+    // Whether the guest may populate `new_pages` more without going over the cap set by the
+    // host.
+    fn admit_populated_pages(&self, new_pages: u64) -> bool {
+        self.max_populated_pages.map_or(true, |max_pages| {
+            self.populated_ranges.num_pages() + new_pages <= max_pages
+        })
+    }
+
+    // The control queue follows the last queue present, so it moves down without the stats
+    // queue.
+    pub(crate) fn control_index(&self) -> usize {
+        if self.stats_queue_present() {
+            CONTROL_INDEX
+        } else {
+            CONTROL_INDEX - 1
+        }
+    }
+
+    pub(crate) fn set_stats_desc_index(&mut self, stats_desc_index: Option<u16>) {
+        self.stats_desc_index = stats_desc_index;
+    }
+}
+
+impl Drop for FaascaleMem {
+    fn drop(&mut self) {
+        if let Err(err) = self.worker_stop_evt.write(1) {
+            error!("Failed to stop the faascale-mem worker threads: {:?}", err);
+        }
+        for (name, handle) in self.worker_threads.drain(..) {
+            // The last reference to the device may be dropped by one of its worker threads.
+            if handle.thread().id() == thread::current().id() {
+                continue;
+            }
+            if handle.join().is_err() {
+                error!("The faascale-mem {} thread panicked.", name);
+            }
+        }
+    }
+}
+
+impl VirtioDevice for FaascaleMem {
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_FAASCALE_MEM
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_space_bytes = self.config_space.as_slice();
+        let config_len = config_space_bytes.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(
+                &config_space_bytes[offset as usize..cmp::min(end, config_len) as usize],
+            )
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let data_len = data.len() as u64;
+        let config_space_bytes = self.config_space.as_mut_slice();
+        let config_len = config_space_bytes.len() as u64;
+        if offset + data_len > config_len {
+            error!("Failed to write config space");
+            return;
+        }
+        config_space_bytes[offset as usize..(offset + data_len) as usize].copy_from_slice(data);
+        self.acknowledge_config_changes();
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if self.has_feature(u64::from(VIRTIO_RING_F_EVENT_IDX)) {
+            for queue in &mut self.queues {
+                queue.enable_notif_suppression();
+            }
+        }
+
+        self.device_state = DeviceState::Activated(mem);
+        if self.activate_evt.write(1).is_err() {
+            error!("FaascaleMem: Cannot write to activate_evt");
+            METRICS.faascale_mem.activate_fails.inc();
+            self.device_state = DeviceState::Inactive;
+            return Err(super::super::ActivateError::BadActivate);
+        }
+
+        if self.stats_enabled() {
+            self.update_timer_state();
+        }
+        if let (KsmAdvice::Idle, Some(mem)) = (self.ksm, self.device_state.mem()) {
+            self.advise_idle_mergeable(mem);
+        }
+        // A restored guest is already past its boot, unless it rebooted since.
+        if !self.restored || self.driver_resets > 0 {
+            self.boot_warmup.activate(self.clock.now());
+        }
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn reset(&mut self) -> Option<(EventFd, Vec<EventFd>)> {
+        // The event manager keeps polling the events of the device, the transport only gets
+        // copies of them.
+        let interrupt_evt = self.irq_trigger.irq_evt.try_clone().ok()?;
+        let queue_evts = self
+            .queue_evts
+            .iter()
+            .map(EventFd::try_clone)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        self.reset_driver_session();
+        Some((interrupt_evt, queue_evts))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use utils::clock::ManualClock;
+    use utils::tempfile::TempFile;
+    use utils::vm_memory::test_utils::create_anon_guest_memory;
+    use utils::vm_memory::GuestAddress;
+
+    use super::super::CONFIG_SPACE_SIZE;
+    use super::*;
+    use crate::check_metric_after_block;
+    use crate::devices::report_faascale_mem_event_fail;
+    use crate::devices::virtio::balloon::test_utils::{check_request_completion, set_request};
+    use crate::devices::virtio::faascale_mem::test_utils::{
+        default_faascale_mem, invoke_handler_for_queue_event, populated_ranges,
+    };
+    use crate::devices::virtio::faascale_mem::POPULATE_CANARY;
+    use crate::devices::virtio::test_utils::{default_mem, VirtQueue};
+    use crate::devices::virtio::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use crate::vstate::vm::tests::setup_vm;
+
+    impl FaascaleMem {
+        pub(crate) fn set_queue(&mut self, idx: usize, q: Queue) {
+            self.queues[idx] = q;
+        }
+
+        pub(crate) fn actual_pages(&self) -> u32 {
+            self.config_space.actual_pages
+        }
+
+        pub fn update_num_pages(&mut self, num_pages: u32) {
+            self.config_space.num_pages = num_pages;
+        }
+
+        pub fn update_actual_pages(&mut self, actual_pages: u32) {
+            self.config_space.actual_pages = actual_pages;
+        }
+    }
+
+    // Guest address of the payloads of the requests, past the rings of the queues.
+    const DATA_ADDR: u64 = 0x1000;
+    // Block populated and depopulated by the tests, past the payloads of the requests.
+    const BLOCK: (u32, u32) = (8, 2);
+
+    fn block_head(mem: &GuestMemoryMmap) -> [u8; 8] {
+        mem.read_obj::<[u8; 8]>(GuestAddress(
+            u64::from(BLOCK.0) << VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_faascale_mem_stat_size() {
+        assert_eq!(SIZE_OF_STAT, 10);
+        assert_eq!(SIZE_OF_BLOCK_INFO, 8);
+    }
+
+    #[test]
+    fn test_update_faascale_mem_stats() {
+        let mut stats = FaascaleMemStats::default();
+        let mut stat = FaascaleMemStat { tag: 0, val: 1 };
+        let tags = [
+            VIRTIO_FAASCALE_MEM_S_SWAP_IN,
+            VIRTIO_FAASCALE_MEM_S_SWAP_OUT,
+            VIRTIO_FAASCALE_MEM_S_MAJFLT,
+            VIRTIO_FAASCALE_MEM_S_MINFLT,
+            VIRTIO_FAASCALE_MEM_S_MEMFREE,
+            VIRTIO_FAASCALE_MEM_S_MEMTOT,
+            VIRTIO_FAASCALE_MEM_S_AVAIL,
+            VIRTIO_FAASCALE_MEM_S_CACHES,
+            VIRTIO_FAASCALE_MEM_S_HTLB_PGALLOC,
+            VIRTIO_FAASCALE_MEM_S_HTLB_PGFAIL,
+            VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG10,
+            VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG60,
+        ];
+        for tag in tags {
+            stat.tag = tag;
+            stats.update_with_stat(&stat).unwrap();
+        }
+        assert_eq!(stats.swap_in, Some(1));
+        assert_eq!(stats.swap_out, Some(1));
+        assert_eq!(stats.major_faults, Some(1));
+        assert_eq!(stats.minor_faults, Some(1));
+        assert_eq!(stats.free_memory, Some(1));
+        assert_eq!(stats.total_memory, Some(1));
+        assert_eq!(stats.available_memory, Some(1));
+        assert_eq!(stats.disk_caches, Some(1));
+        assert_eq!(stats.hugetlb_allocations, Some(1));
+        assert_eq!(stats.hugetlb_failures, Some(1));
+        assert_eq!(stats.memory_pressure_some_avg10, Some(1));
+        assert_eq!(stats.memory_pressure_some_avg60, Some(1));
+
+        // Unknown tags are rejected.
+        stat.tag = VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG60 + 1;
+        assert!(matches!(
+            stats.update_with_stat(&stat),
+            Err(FaascaleMemError::MalformedPayload)
+        ));
+
+        // The cumulative counters report their growth since the previous sample.
+        let previous = stats.clone();
+        stat.tag = VIRTIO_FAASCALE_MEM_S_MAJFLT;
+        stat.val = 201;
+        stats.update_with_stat(&stat).unwrap();
+        stats.update_deltas(&previous, Duration::from_secs(2));
+        assert_eq!(stats.sample_interval_ms, Some(2000));
+        assert_eq!(
+            stats.major_faults_delta,
+            Some(CounterDelta {
+                delta: 200,
+                per_second: 100
+            })
+        );
+        assert_eq!(stats.swap_in_delta, Some(CounterDelta::default()));
+    }
+
+    #[test]
+    fn test_virtio_features() {
+        // Test all feature combinations.
+        for stats_interval in [0, 1] {
+            for (budget_mib, block_cache_mib, mlock_budget_mib, spill) in [
+                (None, None, None, false),
+                (Some(1), None, None, false),
+                (None, Some(1), None, false),
+                (None, None, Some(1), false),
+                (None, None, None, true),
+            ] {
+                let spill_file = TempFile::new().unwrap();
+                let mut faascale_mem = FaascaleMem::new(
+                    &FaascaleMemDeviceConfig {
+                        stats_polling_interval_s: stats_interval,
+                        budget_mib,
+                        block_cache_mib,
+                        mlock_budget_mib,
+                        spill_path: spill.then(|| spill_file.as_path().to_path_buf()),
+                        ..Default::default()
+                    },
+                    false,
+                    RateLimiter::default(),
+                )
+                .unwrap();
+                assert_eq!(faascale_mem.device_type(), TYPE_FAASCALE_MEM);
+                // The statistics queue is always present, the control queue only with a budget.
+                assert_eq!(
+                    faascale_mem.queues().len(),
+                    NUM_QUEUES - usize::from(budget_mib.is_none())
+                );
+
+                let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+                    | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+                    | (1u64 << VIRTIO_FAASCALE_MEM_F_STATS_VQ)
+                    | (1u64 << VIRTIO_FAASCALE_MEM_F_BLOCK_GRANULARITY)
+                    | (u64::from(budget_mib.is_some()) << VIRTIO_FAASCALE_MEM_F_BUDGET)
+                    | (1u64 << VIRTIO_FAASCALE_MEM_F_TRACE_IDS)
+                    | (1u64 << VIRTIO_FAASCALE_MEM_F_WIDE_BLOCKS)
+                    | (u64::from(mlock_budget_mib.is_some()) << VIRTIO_FAASCALE_MEM_F_MLOCK)
+                    | (1u64 << VIRTIO_FAASCALE_MEM_F_STATUS)
+                    | (1u64 << VIRTIO_FAASCALE_MEM_F_PIN)
+                    // The cached or spilled contents are not zero-filled.
+                    | (u64::from(block_cache_mib.is_none() && !spill)
+                        << VIRTIO_FAASCALE_MEM_F_ZEROED);
+
+                assert_eq!(faascale_mem.avail_features_by_page(0), features as u32);
+                assert_eq!(
+                    faascale_mem.avail_features_by_page(1),
+                    (features >> 32) as u32
+                );
+                for i in 2..10 {
+                    assert_eq!(faascale_mem.avail_features_by_page(i), 0u32);
+                }
+
+                for i in 0..10 {
+                    faascale_mem.ack_features_by_page(i, u32::MAX);
+                }
+                // Only present features should be acknowledged.
+                assert_eq!(faascale_mem.acked_features, features);
+            }
+        }
+
+        // The spill file and the block cache exclude each other.
+        let spill_file = TempFile::new().unwrap();
+        assert!(matches!(
+            FaascaleMem::new(
+                &FaascaleMemDeviceConfig {
+                    block_cache_mib: Some(1),
+                    spill_path: Some(spill_file.as_path().to_path_buf()),
+                    ..Default::default()
+                },
+                false,
+                RateLimiter::default(),
+            ),
+            Err(FaascaleMemError::SpillWithBlockCache)
+        ));
+        // So do the two KSM advices.
+        assert!(matches!(
+            KsmAdvice::new(true, true),
+            Err(FaascaleMemError::KsmMergeableWithIdle)
+        ));
+    }
+
+    #[test]
+    fn test_zeroed_feature_depopulate_modes() {
+        // Only `DontNeed` drops the old page contents, the other modes cannot promise zero-filled
+        // pages.
+        for (depopulate_mode, zeroed) in [
+            (FaascaleMemDepopulateMode::DontNeed, true),
+            (FaascaleMemDepopulateMode::Free, false),
+            (FaascaleMemDepopulateMode::Cold, false),
+            (FaascaleMemDepopulateMode::Pageout, false),
+        ] {
+            let faascale_mem = FaascaleMem::new(
+                &FaascaleMemDeviceConfig {
+                    depopulate_mode,
+                    ..Default::default()
+                },
+                false,
+                RateLimiter::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                faascale_mem.avail_features() & (1u64 << VIRTIO_FAASCALE_MEM_F_ZEROED) != 0,
+                zeroed
+            );
+        }
+    }
+
+    #[test]
+    fn test_virtio_read_config() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.update_num_pages(0x1000);
+
+        let cfg = faascale_mem.config();
+        assert_eq!(cfg.stats_polling_interval_s, 0);
+        assert_eq!(cfg.budget_mib, None);
+        assert_eq!(cfg.config_epoch, 0);
+        assert_eq!(cfg.populated_mib, 0);
+
+        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        faascale_mem.read_config(0, &mut actual_config_space);
+        // The config space is little endian, starting with num_pages and actual_pages.
+        let mut expected_config_space = [0u8; CONFIG_SPACE_SIZE];
+        expected_config_space[1] = 0x10;
+        assert_eq!(actual_config_space, expected_config_space);
+
+        // Invalid read.
+        let expected_config_space = [0xd; CONFIG_SPACE_SIZE];
+        actual_config_space = expected_config_space;
+        faascale_mem.read_config(CONFIG_SPACE_SIZE as u64 + 1, &mut actual_config_space);
+
+        // Validate read failed (the config space was not updated).
+        assert_eq!(actual_config_space, expected_config_space);
+    }
+
+    #[test]
+    fn test_virtio_write_config() {
+        let mut faascale_mem = default_faascale_mem(0);
+
+        let mut expected_config_space = [0u8; CONFIG_SPACE_SIZE];
+        expected_config_space[1] = 0x50;
+        faascale_mem.write_config(0, &expected_config_space);
+
+        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        faascale_mem.read_config(0, &mut actual_config_space);
+        assert_eq!(actual_config_space, expected_config_space);
+
+        // Invalid write.
+        let new_config_space = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
+        faascale_mem.write_config(CONFIG_SPACE_SIZE as u64 - 4, &new_config_space);
+        // Make sure nothing got written.
+        faascale_mem.read_config(0, &mut actual_config_space);
+        assert_eq!(actual_config_space, expected_config_space);
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.populate_verification = true;
+        let mem = default_mem();
+        // Only initialize the populate queue to demonstrate invalid request handling.
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        mem.write_obj::<[u32; 2]>([BLOCK.0, BLOCK.1], GuestAddress(DATA_ADDR))
+            .unwrap();
+
+        // Invalid case: the descriptor is write-only.
+        {
+            set_request(
+                &popq,
+                0,
+                DATA_ADDR,
+                SIZE_OF_BLOCK_INFO as u32,
+                VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            );
+
+            invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+            check_request_completion(&popq, 0);
+
+            // Check that the block was not populated.
+            assert_eq!(block_head(&mem), [0; 8]);
+            assert!(populated_ranges(&faascale_mem).is_empty());
+        }
+
+        // Invalid case: descriptor len is not a multiple of 'SIZE_OF_BLOCK_INFO'.
+        {
+            set_request(
+                &popq,
+                1,
+                DATA_ADDR,
+                SIZE_OF_BLOCK_INFO as u32 + 1,
+                VIRTQ_DESC_F_NEXT,
+            );
+
+            invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+            check_request_completion(&popq, 1);
+
+            // Check that the block was not populated.
+            assert_eq!(block_head(&mem), [0; 8]);
+            assert!(populated_ranges(&faascale_mem).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_populate() {
+        let mut faascale_mem = default_faascale_mem(0);
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        mem.write_obj::<[u32; 2]>([BLOCK.0, BLOCK.1], GuestAddress(DATA_ADDR))
+            .unwrap();
+
+        // Error case: the request is well-formed, but we forgot
+        // to trigger the populate event queue.
+        {
+            set_request(
+                &popq,
+                0,
+                DATA_ADDR,
+                SIZE_OF_BLOCK_INFO as u32,
+                VIRTQ_DESC_F_NEXT,
+            );
+
+            check_metric_after_block!(
+                METRICS.faascale_mem.event_fails,
+                1,
+                faascale_mem
+                    .process_populate_queue_event(Instant::now())
+                    .unwrap_or_else(report_faascale_mem_event_fail)
+            );
+            // Verify that nothing got processed.
+            assert_eq!(popq.used.idx.get(), 0);
+            assert!(populated_ranges(&faascale_mem).is_empty());
+        }
+
+        // Test the happy case.
+        {
+            invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+            check_request_completion(&popq, 0);
+
+            // Check that the block was populated on the host side, leaving its memory
+            // untouched.
+            assert_eq!(block_head(&mem), [0; 8]);
+            assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10)]);
+            assert_eq!(faascale_mem.config().populated_mib, 0);
+            let latency = faascale_mem
+                .latest_stats()
+                .unwrap()
+                .populate_latency
+                .clone()
+                .unwrap();
+            assert_eq!(latency.total.count, 1);
+            assert_eq!(faascale_mem.latest_stats.populate_verified_blocks, None);
+        }
+    }
+
+    #[test]
+    fn test_populate_status() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.acked_features = 1u64 << VIRTIO_FAASCALE_MEM_F_STATUS;
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        // Every request chains a writable status after its blocks, the second one lying
+        // outside the guest memory.
+        let status_addr = |idx: u64| DATA_ADDR + 0x100 * (2 * idx + 1);
+        for (idx, block) in [BLOCK, (0x100, 1)].into_iter().enumerate() {
+            let blocks_addr = DATA_ADDR + 0x200 * idx as u64;
+            mem.write_obj::<[u32; 2]>([block.0, block.1], GuestAddress(blocks_addr))
+                .unwrap();
+            mem.write_obj::<u32>(u32::MAX, GuestAddress(status_addr(idx as u64)))
+                .unwrap();
+            popq.avail.ring[idx].set(2 * idx as u16);
+            popq.dtable[2 * idx].set(
+                blocks_addr,
+                SIZE_OF_BLOCK_INFO as u32,
+                VIRTQ_DESC_F_NEXT,
+                2 * idx as u16 + 1,
+            );
+            popq.dtable[2 * idx + 1].set(
+                status_addr(idx as u64),
+                SIZE_OF_STATUS as u32,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+        }
+        popq.avail.idx.set(2);
+
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        assert_eq!(popq.used.idx.get(), 2);
+        for idx in 0..2 {
+            assert_eq!(popq.used.ring[idx].get().len, SIZE_OF_STATUS as u32);
+        }
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(status_addr(0))).unwrap(),
+            VIRTIO_FAASCALE_MEM_STATUS_OK
+        );
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(status_addr(1))).unwrap(),
+            VIRTIO_FAASCALE_MEM_STATUS_EINVAL
+        );
+        assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10)]);
+
+        // The first failure of a request is kept.
+        let mut status = VIRTIO_FAASCALE_MEM_STATUS_OK;
+        fail_request(&mut status, VIRTIO_FAASCALE_MEM_STATUS_OVER_BUDGET);
+        fail_request(&mut status, VIRTIO_FAASCALE_MEM_STATUS_ENOMEM);
+        assert_eq!(status, VIRTIO_FAASCALE_MEM_STATUS_OVER_BUDGET);
+    }
+
+    #[test]
+    fn test_populate_bogus_block_count() {
+        let mut faascale_mem = default_faascale_mem(0);
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        // Requests with more blocks than allowed, chaining a writable status.
+        let status_addr = DATA_ADDR + 0x1000;
+        let bogus_request = |idx: usize| {
+            mem.write_obj::<u32>(u32::MAX, GuestAddress(status_addr))
+                .unwrap();
+            popq.avail.ring[idx].set(2 * idx as u16);
+            popq.dtable[2 * idx].set(
+                DATA_ADDR,
+                ((MAX_BLOCKS_IN_DESC + 1) * SIZE_OF_BLOCK_INFO) as u32,
+                VIRTQ_DESC_F_NEXT,
+                2 * idx as u16 + 1,
+            );
+            popq.dtable[2 * idx + 1].set(status_addr, SIZE_OF_STATUS as u32, VIRTQ_DESC_F_WRITE, 0);
+            popq.avail.idx.set(idx as u16 + 1);
+        };
+
+        // Without the status, the request is returned with nothing written.
+        bogus_request(0);
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        assert_eq!(popq.used.idx.get(), 1);
+        assert_eq!(popq.used.ring[0].get().id, 0);
+        assert_eq!(popq.used.ring[0].get().len, 0);
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(status_addr)).unwrap(),
+            u32::MAX
+        );
+
+        // With the status, the guest is told the request is invalid.
+        faascale_mem.acked_features = 1u64 << VIRTIO_FAASCALE_MEM_F_STATUS;
+        bogus_request(1);
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        assert_eq!(popq.used.idx.get(), 2);
+        assert_eq!(popq.used.ring[1].get().id, 2);
+        assert_eq!(popq.used.ring[1].get().len, SIZE_OF_STATUS as u32);
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(status_addr)).unwrap(),
+            VIRTIO_FAASCALE_MEM_STATUS_EINVAL
+        );
+        assert!(populated_ranges(&faascale_mem).is_empty());
+    }
+
+    #[test]
+    fn test_populate_unreadable_block() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.acked_features = 1u64 << VIRTIO_FAASCALE_MEM_F_STATUS;
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+        mem.write_obj::<[u32; 2]>([BLOCK.0, BLOCK.1], GuestAddress(DATA_ADDR))
+            .unwrap();
+
+        // A request whose block straddles the end of the guest memory, then a valid one, in
+        // the same batch.
+        let status_addr = DATA_ADDR + 0x1000;
+        let end_of_mem = mem.last_addr().raw_value() + 1;
+        for (idx, addr) in [end_of_mem - 4, DATA_ADDR].into_iter().enumerate() {
+            popq.avail.ring[idx].set(2 * idx as u16);
+            popq.dtable[2 * idx].set(
+                addr,
+                SIZE_OF_BLOCK_INFO as u32,
+                VIRTQ_DESC_F_NEXT,
+                2 * idx as u16 + 1,
+            );
+            popq.dtable[2 * idx + 1].set(
+                status_addr + (idx * SIZE_OF_STATUS) as u64,
+                SIZE_OF_STATUS as u32,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+        }
+        popq.avail.idx.set(2);
+
+        // The malformed request fails alone, and the rest of the batch is served.
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        assert_eq!(popq.used.idx.get(), 2);
+        for idx in 0..2 {
+            assert_eq!(popq.used.ring[idx].get().id, 2 * idx as u32);
+            assert_eq!(popq.used.ring[idx].get().len, SIZE_OF_STATUS as u32);
+        }
+        assert_eq!(
+            mem.read_obj::<[u32; 2]>(GuestAddress(status_addr)).unwrap(),
+            [
+                VIRTIO_FAASCALE_MEM_STATUS_EINVAL,
+                VIRTIO_FAASCALE_MEM_STATUS_OK
+            ]
+        );
+        assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10)]);
+        assert!(faascale_mem
+            .leak_tracker
+            .take_leaks(POPULATE_INDEX)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_depopulate_spill_failure() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.acked_features = 1u64 << VIRTIO_FAASCALE_MEM_F_STATUS;
+        // Writing to `/dev/full` fails, as when the disk of the spill file is full.
+        faascale_mem.spill_file = Some(SpillFile::new(PathBuf::from("/dev/full")).unwrap());
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let depq = VirtQueue::new(GuestAddress(0x400), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.set_queue(DEPOPULATE_INDEX, depq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+        mem.write_obj::<[u32; 2]>([BLOCK.0, BLOCK.1], GuestAddress(DATA_ADDR))
+            .unwrap();
+
+        // A request for the block, chaining a writable status.
+        let status_addr = DATA_ADDR + 0x1000;
+        let request = |queue: &VirtQueue| {
+            queue.avail.ring[0].set(0);
+            queue.dtable[0].set(DATA_ADDR, SIZE_OF_BLOCK_INFO as u32, VIRTQ_DESC_F_NEXT, 1);
+            queue.dtable[1].set(status_addr, SIZE_OF_STATUS as u32, VIRTQ_DESC_F_WRITE, 0);
+            queue.avail.idx.set(1);
+        };
+        request(&popq);
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10)]);
+
+        // The block the spill file cannot hold stays populated, and the guest is told.
+        let spill_fails = METRICS.faascale_mem.spill_fails.count();
+        request(&depq);
+        invoke_handler_for_queue_event(&mut faascale_mem, DEPOPULATE_INDEX);
+        assert_eq!(depq.used.idx.get(), 1);
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(status_addr)).unwrap(),
+            VIRTIO_FAASCALE_MEM_STATUS_ENOMEM
+        );
+        assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10)]);
+        assert!(METRICS.faascale_mem.spill_fails.count() > spill_fails);
+        // Populating it again is still deduplicated.
+        let now = faascale_mem.clock.now();
+        assert!(faascale_mem.populate_tracker.check_and_record((8, 2), now));
+    }
+
+    #[test]
+    fn test_pin_feature() {
+        let mut faascale_mem = default_faascale_mem(0);
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+        mem.write_obj::<[u32; 2]>(
+            [BLOCK.0, BLOCK.1 | VIRTIO_FAASCALE_MEM_BLOCK_F_PINNED],
+            GuestAddress(DATA_ADDR),
+        )
+        .unwrap();
+
+        // Without the pin feature, the top bit belongs to the page count and the block does
+        // not fit in the guest memory.
+        set_request(
+            &popq,
+            0,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        check_request_completion(&popq, 0);
+        assert_eq!(faascale_mem.pinned_pages(), 0);
+        assert!(populated_ranges(&faascale_mem).is_empty());
+
+        faascale_mem.acked_features = 1u64 << VIRTIO_FAASCALE_MEM_F_PIN;
+        set_request(
+            &popq,
+            1,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        check_request_completion(&popq, 1);
+        assert_eq!(faascale_mem.pinned_pages(), u64::from(BLOCK.1));
+        assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10)]);
+    }
+
+    #[test]
+    fn test_descriptor_leaks() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.acked_features = 1u64 << VIRTIO_FAASCALE_MEM_F_STATUS;
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        // Requests popped and never returned to the guest are leaked.
+        let status_addr = DATA_ADDR + 0x1000;
+        let leak_request = |faascale_mem: &mut FaascaleMem, idx: usize| {
+            popq.avail.ring[idx].set(2 * idx as u16);
+            popq.dtable[2 * idx].set(
+                DATA_ADDR,
+                SIZE_OF_BLOCK_INFO as u32,
+                VIRTQ_DESC_F_NEXT,
+                2 * idx as u16 + 1,
+            );
+            popq.dtable[2 * idx + 1].set(status_addr, SIZE_OF_STATUS as u32, VIRTQ_DESC_F_WRITE, 0);
+            popq.avail.idx.set(idx as u16 + 1);
+            let head = faascale_mem.queues[POPULATE_INDEX].pop(&mem).unwrap();
+            faascale_mem
+                .leak_tracker
+                .popped(POPULATE_INDEX, std::iter::once(head.index));
+        };
+
+        // The leaked request is only counted by default.
+        leak_request(&mut faascale_mem, 0);
+        let leaks = METRICS.faascale_mem.descriptor_leaks.count();
+        faascale_mem
+            .check_descriptor_leaks(POPULATE_INDEX, true)
+            .unwrap();
+        assert_eq!(popq.used.idx.get(), 0);
+        assert!(METRICS.faascale_mem.descriptor_leaks.count() > leaks);
+        assert!(faascale_mem
+            .leak_tracker
+            .take_leaks(POPULATE_INDEX)
+            .is_empty());
+
+        // Or returned to the guest with an error status.
+        faascale_mem.complete_leaked_descriptors = true;
+        leak_request(&mut faascale_mem, 1);
+        faascale_mem
+            .check_descriptor_leaks(POPULATE_INDEX, true)
+            .unwrap();
+        assert_eq!(popq.used.idx.get(), 1);
+        assert_eq!(popq.used.ring[0].get().id, 2);
+        assert_eq!(popq.used.ring[0].get().len, SIZE_OF_STATUS as u32);
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(status_addr)).unwrap(),
+            VIRTIO_FAASCALE_MEM_STATUS_EINVAL
+        );
+    }
+
+    #[test]
+    fn test_depopulate_interrupt_moderation() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.irq_moderator.set_enabled(true);
+        assert!(faascale_mem.config().interrupt_moderation);
+        let mem = default_mem();
+        let depq = VirtQueue::new(GuestAddress(0x400), &mem, 16);
+        faascale_mem.set_queue(DEPOPULATE_INDEX, depq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+        mem.write_obj::<[u32; 2]>([BLOCK.0, BLOCK.1], GuestAddress(DATA_ADDR))
+            .unwrap();
+
+        // Nothing is known of the guest yet, the first depopulation is notified right away.
+        set_request(
+            &depq,
+            0,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, DEPOPULATE_INDEX);
+
+        // The guest streams its requests, the notification of the next one is held back.
+        set_request(
+            &depq,
+            1,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        faascale_mem.queue_evts[DEPOPULATE_INDEX].write(1).unwrap();
+        check_metric_after_block!(
+            METRICS.faascale_mem.irq_moderation_held,
+            1,
+            faascale_mem.process_depopulate_queue_event().unwrap()
+        );
+        assert_eq!(depq.used.idx.get(), 2);
+        assert!(!faascale_mem.irq_trigger.has_pending_irq(IrqType::Vring));
+
+        // The timer raises the held notification.
+        check_metric_after_block!(
+            METRICS.faascale_mem.irq_moderation_timeouts,
+            1,
+            faascale_mem.process_irq_moderation_event().unwrap()
+        );
+        assert!(faascale_mem.irq_trigger.has_pending_irq(IrqType::Vring));
+    }
+
+    #[test]
+    fn test_event_idx() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.acked_features = 1u64 << VIRTIO_RING_F_EVENT_IDX;
+        let mem = default_mem();
+        let depq = VirtQueue::new(GuestAddress(0x400), &mem, 16);
+        faascale_mem.set_queue(DEPOPULATE_INDEX, depq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+        mem.write_obj::<[u32; 2]>([BLOCK.0, BLOCK.1], GuestAddress(DATA_ADDR))
+            .unwrap();
+
+        // The guest is notified of the first used buffer, and asked to kick the device for the
+        // next request once the queue is drained.
+        set_request(
+            &depq,
+            0,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, DEPOPULATE_INDEX);
+        assert_eq!(depq.used.event.get(), 1);
+
+        // The guest did not move its used event index, the next used buffer is not notified.
+        set_request(
+            &depq,
+            1,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        faascale_mem.queue_evts[DEPOPULATE_INDEX].write(1).unwrap();
+        check_metric_after_block!(
+            METRICS.faascale_mem.event_idx_suppressed,
+            1,
+            faascale_mem.process_depopulate_queue_event().unwrap()
+        );
+        assert_eq!(depq.used.idx.get(), 2);
+        assert_eq!(depq.used.event.get(), 2);
+        assert!(!faascale_mem.irq_trigger.has_pending_irq(IrqType::Vring));
+
+        // Once it does, the used buffers are notified again.
+        depq.avail.event.set(2);
+        set_request(
+            &depq,
+            2,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, DEPOPULATE_INDEX);
+        assert_eq!(depq.used.event.get(), 3);
+    }
+
+    #[test]
+    fn test_prefault_batching() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.set_pre_tdp_fault(true);
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        // Without a KVM VM, the populated blocks are not pre-faulted.
+        mem.write_obj::<[u32; 4]>([8, 2, 10, 2], GuestAddress(DATA_ADDR))
+            .unwrap();
+        set_request(
+            &popq,
+            0,
+            DATA_ADDR,
+            2 * SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        check_request_completion(&popq, 0);
+        assert!(faascale_mem.prefault_batch.is_empty());
+        let latency = faascale_mem.latest_stats.populate_latency.as_ref().unwrap();
+        assert_eq!(latency.total.count, 2);
+        assert_eq!(latency.pre_tdp_fault.count, 0);
+
+        // Two contiguous blocks populated by the same drain are pre-faulted together.
+        let (vm, _) = setup_vm(0x1000);
+        faascale_mem.set_vm_fd(vm.shared_fd().clone());
+        mem.write_obj::<[u32; 4]>([12, 2, 14, 2], GuestAddress(DATA_ADDR))
+            .unwrap();
+        set_request(
+            &popq,
+            1,
+            DATA_ADDR,
+            2 * SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        let ioctls = METRICS.faascale_mem.prefault_ioctls.count();
+        let coalesced = METRICS.faascale_mem.prefault_coalesced_blocks.count();
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        check_request_completion(&popq, 1);
+        assert!(METRICS.faascale_mem.prefault_ioctls.count() > ioctls);
+        assert!(METRICS.faascale_mem.prefault_coalesced_blocks.count() > coalesced);
+        assert!(faascale_mem.prefault_batch.is_empty());
+        let latency = faascale_mem.latest_stats.populate_latency.as_ref().unwrap();
+        assert_eq!(latency.total.count, 4);
+        assert_eq!(latency.pre_tdp_fault.count, 1);
+    }
+
+    #[test]
+    fn test_populate_verification() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.populate_verification = true;
+        assert!(faascale_mem.config().populate_verification);
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        mem.write_obj::<[u32; 2]>([BLOCK.0, BLOCK.1], GuestAddress(DATA_ADDR))
+            .unwrap();
+        set_request(
+            &popq,
+            0,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        check_request_completion(&popq, 0);
+
+        // The canary is left at the head of the block for the guest to check.
+        assert_eq!(block_head(&mem), POPULATE_CANARY);
+        let stats = faascale_mem.latest_stats().unwrap();
+        assert_eq!(stats.populate_verified_blocks, Some(1));
+        assert_eq!(stats.populate_verification_failures, None);
+
+        // The canary is checked in every memory slot a block spans.
+        let mem = create_anon_guest_memory(
+            &[(GuestAddress(0), 0x10000), (GuestAddress(0x10000), 0x10000)],
+            false,
+        )
+        .unwrap();
+        assert!(write_populate_canary(
+            &mem,
+            (GuestAddress(0xf000), 0x2000),
+            TraceId::default()
+        ));
+        assert_eq!(
+            mem.read_obj::<[u8; 8]>(GuestAddress(0x10000)).unwrap(),
+            POPULATE_CANARY
+        );
+        // Ranges outside the memory slots fail the verification.
+        check_metric_after_block!(
+            METRICS.faascale_mem.populate_verification_failures,
+            1,
+            assert!(!write_populate_canary(
+                &mem,
+                (GuestAddress(0x20000), 0x1000),
+                TraceId::default()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_depopulate() {
+        let mut faascale_mem = default_faascale_mem(0);
+        // The canary shows whether the memory of the block went back to the host.
+        faascale_mem.populate_verification = true;
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let depq = VirtQueue::new(GuestAddress(0x400), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.set_queue(DEPOPULATE_INDEX, depq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        mem.write_obj::<[u32; 2]>([BLOCK.0, BLOCK.1], GuestAddress(DATA_ADDR))
+            .unwrap();
+        set_request(
+            &popq,
+            0,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        assert_eq!(block_head(&mem), POPULATE_CANARY);
+
+        // Error case: forgot to trigger depopulate event queue.
+        {
+            set_request(
+                &depq,
+                0,
+                DATA_ADDR,
+                SIZE_OF_BLOCK_INFO as u32,
+                VIRTQ_DESC_F_NEXT,
+            );
+            check_metric_after_block!(
+                METRICS.faascale_mem.event_fails,
+                1,
+                faascale_mem
+                    .process_depopulate_queue_event()
+                    .unwrap_or_else(report_faascale_mem_event_fail)
+            );
+            // Verify that nothing got processed.
+            assert_eq!(depq.used.idx.get(), 0);
+            assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10)]);
+        }
+
+        // Happy case.
+        {
+            invoke_handler_for_queue_event(&mut faascale_mem, DEPOPULATE_INDEX);
+            check_request_completion(&depq, 0);
+
+            // Check that the memory of the block went back to the host.
+            assert_eq!(block_head(&mem), [0; 8]);
+            assert!(populated_ranges(&faascale_mem).is_empty());
+        }
+
+        // Pinned blocks are not depopulated.
+        {
+            set_request(
+                &popq,
+                1,
+                DATA_ADDR,
+                SIZE_OF_BLOCK_INFO as u32,
+                VIRTQ_DESC_F_NEXT,
+            );
+            invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+            faascale_mem.update_pinned_range(BLOCK, true);
+
+            set_request(
+                &depq,
+                1,
+                DATA_ADDR,
+                SIZE_OF_BLOCK_INFO as u32,
+                VIRTQ_DESC_F_NEXT,
+            );
+            check_metric_after_block!(
+                METRICS.faascale_mem.depopulate_pinned_refusals,
+                1,
+                invoke_handler_for_queue_event(&mut faascale_mem, DEPOPULATE_INDEX)
+            );
+            check_request_completion(&depq, 1);
+            assert_eq!(block_head(&mem), POPULATE_CANARY);
+            assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10)]);
+        }
+    }
+
+    #[test]
+    fn test_4gib_boundary_blocks() {
+        // Two slots meeting at 4GiB, and a narrow block crossing it.
+        let mem = create_anon_guest_memory(
+            &[
+                (GuestAddress(0xffff_0000), 0x10000),
+                (GuestAddress(0x1_0000_0000), 0x10000),
+            ],
+            false,
+        )
+        .unwrap();
+        let block: (u32, u32) = (0xffff8, 0x10);
+        let data_addr = 0xffff_1000;
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.populate_verification = true;
+        let popq = VirtQueue::new(GuestAddress(0xffff_0000), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        mem.write_obj::<[u32; 2]>([block.0, block.1], GuestAddress(data_addr))
+            .unwrap();
+        set_request(
+            &popq,
+            0,
+            data_addr,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        check_request_completion(&popq, 0);
+
+        // The block was split at 4GiB and both slots were populated, and the block is tracked past 32 bits of address.
+        let head = |addr| mem.read_obj::<[u8; 8]>(GuestAddress(addr)).unwrap();
+        assert_eq!(head(0xffff_8000), POPULATE_CANARY);
+        assert_eq!(head(0x1_0000_0000), POPULATE_CANARY);
+        assert_eq!(populated_ranges(&faascale_mem), vec![(0xffff8, 0x100008)]);
+        assert_eq!(faascale_mem.populated_bytes(), 0x10000);
+
+        faascale_mem.depopulate_range(block).unwrap();
+        assert_eq!(head(0xffff_8000), [0; 8]);
+        assert_eq!(head(0x1_0000_0000), [0; 8]);
+        assert!(populated_ranges(&faascale_mem).is_empty());
+
+        // The end of the largest narrow block is past 32 bits of pfn, it must not wrap around
+        // to the memory of the guest.
+        let block = (u32::MAX, u32::MAX);
+        faascale_mem.update_pinned_range(block, true);
+        assert_eq!(faascale_mem.pinned_pages(), u64::from(u32::MAX));
+        faascale_mem.update_pinned_range(block, false);
+        assert_eq!(faascale_mem.pinned_pages(), 0);
+        match faascale_mem.depopulate_range(block) {
+            Err(FaascaleMemError::RemoveMemoryRegion(RemoveRegionError::OutsideMemslot(
+                address,
+            ))) => assert_eq!(address, GuestAddress(0xfff_ffff_f000)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_update_mlocked_range() {
+        let mut faascale_mem = default_faascale_mem(0);
+        assert!(matches!(
+            faascale_mem.update_mlocked_range(BLOCK, true),
+            Err(FaascaleMemError::DeviceNotActive)
+        ));
+
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+        // Locking is disabled without a budget.
+        assert!(matches!(
+            faascale_mem.update_mlocked_range(BLOCK, true),
+            Err(FaascaleMemError::MlockDisabled)
+        ));
+
+        faascale_mem.mlock = Some(BlockMlock::new(1));
+        // Only populated blocks may be locked.
+        assert!(matches!(
+            faascale_mem.update_mlocked_range(BLOCK, true),
+            Err(FaascaleMemError::MlockNotPopulated)
+        ));
+
+        mem.write_obj::<[u32; 2]>([BLOCK.0, BLOCK.1], GuestAddress(DATA_ADDR))
+            .unwrap();
+        set_request(
+            &popq,
+            0,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        faascale_mem.update_mlocked_range(BLOCK, true).unwrap();
+        assert_eq!(faascale_mem.mlock.as_ref().unwrap().locked_pages(), 2);
+
+        // Depopulating the block unlocks it.
+        faascale_mem.depopulate_range(BLOCK).unwrap();
+        assert_eq!(faascale_mem.mlock.as_ref().unwrap().locked_pages(), 0);
+        faascale_mem.update_mlocked_range(BLOCK, false).unwrap();
+    }
+
+    #[test]
+    fn test_populate_rate_limiter() {
+        use rate_limiter::TokenBucket;
+
+        let mut faascale_mem = default_faascale_mem(0);
+        let clock = ManualClock::new();
+        faascale_mem.set_clock(Arc::new(clock.clone())).unwrap();
+        assert_eq!(faascale_mem.config().rate_limiter, None);
+        // A single block every 100ms.
+        faascale_mem.update_rate_limiter(
+            BucketUpdate::None,
+            BucketUpdate::Update(TokenBucket::new(1, 0, 100).unwrap()),
+        );
+        assert!(faascale_mem.config().rate_limiter.unwrap().ops.is_some());
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        mem.write_obj::<[u32; 4]>([BLOCK.0, BLOCK.1, 16, 2], GuestAddress(DATA_ADDR))
+            .unwrap();
+        set_request(
+            &popq,
+            0,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        set_request(
+            &popq,
+            1,
+            DATA_ADDR + SIZE_OF_BLOCK_INFO as u64,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        // The second request is left in the queue.
+        check_metric_after_block!(
+            METRICS.faascale_mem.rate_limiter_throttled_events,
+            1,
+            invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX)
+        );
+        check_request_completion(&popq, 0);
+        assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10)]);
+        assert!(faascale_mem.rate_limiter().is_blocked());
+
+        // Until the rate limiter replenishes.
+        clock.advance(Duration::from_millis(150));
+        faascale_mem.process_rate_limiter_event().unwrap();
+        check_request_completion(&popq, 1);
+        assert_eq!(populated_ranges(&faascale_mem), vec![(8, 10), (16, 18)]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut faascale_mem = default_faascale_mem(1);
+        let clock = ManualClock::new();
+        faascale_mem.set_clock(Arc::new(clock.clone())).unwrap();
+        let mem = default_mem();
+        let statsq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(FAASCALE_STATS_INDEX, statsq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        // Error case: forgot to trigger stats event queue.
+        {
+            set_request(
+                &statsq,
+                0,
+                DATA_ADDR,
+                SIZE_OF_STAT as u32,
+                VIRTQ_DESC_F_NEXT,
+            );
+            check_metric_after_block!(
+                METRICS.faascale_mem.event_fails,
+                1,
+                faascale_mem
+                    .process_stats_queue_event()
+                    .unwrap_or_else(report_faascale_mem_event_fail)
+            );
+            // Verify that nothing got processed.
+            assert_eq!(statsq.used.idx.get(), 0);
+        }
+
+        // Happy case.
+        {
+            let swap_out_stat = FaascaleMemStat {
+                tag: VIRTIO_FAASCALE_MEM_S_SWAP_OUT,
+                val: 0x1,
+            };
+            let mem_free_stat = FaascaleMemStat {
+                tag: VIRTIO_FAASCALE_MEM_S_MEMFREE,
+                val: 0x5678,
+            };
+
+            // Write the stats in memory.
+            mem.write_obj::<FaascaleMemStat>(swap_out_stat, GuestAddress(DATA_ADDR))
+                .unwrap();
+            mem.write_obj::<FaascaleMemStat>(
+                mem_free_stat,
+                GuestAddress(DATA_ADDR + SIZE_OF_STAT as u64),
+            )
+            .unwrap();
+
+            set_request(
+                &statsq,
+                0,
+                DATA_ADDR,
+                2 * SIZE_OF_STAT as u32,
+                VIRTQ_DESC_F_NEXT,
+            );
+            check_metric_after_block!(METRICS.faascale_mem.stats_updates_count, 1, {
+                // Trigger the queue event.
+                faascale_mem.queue_events()[FAASCALE_STATS_INDEX]
+                    .write(1)
+                    .unwrap();
+                faascale_mem.process_stats_queue_event().unwrap();
+                // Don't check for completion yet.
+            });
+
+            let stats = faascale_mem.latest_stats().unwrap();
+            let expected_stats = FaascaleMemStats {
+                swap_out: Some(0x1),
+                free_memory: Some(0x5678),
+                ..FaascaleMemStats::default()
+            };
+            assert_eq!(stats, &expected_stats);
+
+            // The timer expires once the polling interval elapsed.
+            clock.advance(Duration::from_millis(999));
+            assert_eq!(faascale_mem.stats_timer.read(), 0);
+            clock.advance(Duration::from_millis(1));
+            check_metric_after_block!(METRICS.faascale_mem.event_fails, 0, {
+                // Trigger the timer event, which consumes the stats
+                // descriptor index and signals the used queue.
+                assert!(faascale_mem.stats_desc_index.is_some());
+                assert!(faascale_mem.process_stats_timer_event().is_ok());
+                assert!(faascale_mem.stats_desc_index.is_none());
+                assert!(faascale_mem.irq_trigger.has_pending_irq(IrqType::Vring));
+            });
+            // The THP-backed memory is sampled along.
+            assert!(faascale_mem.footprint().thp_backed_mib.is_some());
+        }
+    }
+
+    #[test]
+    fn test_restored_stats() {
+        let mut faascale_mem = default_faascale_mem(1);
+        let mem = default_mem();
+        let statsq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(FAASCALE_STATS_INDEX, statsq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+
+        // Without any report in the snapshot, the host estimates the memory of the guest.
+        faascale_mem.populated_ranges.insert((0, 0x10));
+        faascale_mem.mark_restored_stats();
+        let expected_stats = FaascaleMemStats {
+            total_memory: Some(0x10000),
+            free_memory: Some(0),
+            available_memory: Some(0),
+            freshness: FaascaleMemStatsFreshness::Simulated,
+            ..FaascaleMemStats::default()
+        };
+        assert_eq!(faascale_mem.latest_stats().unwrap(), &expected_stats);
+
+        // The first report of the guest drops the estimates.
+        let swap_out_stat = FaascaleMemStat {
+            tag: VIRTIO_FAASCALE_MEM_S_SWAP_OUT,
+            val: 0x1,
+        };
+        mem.write_obj::<FaascaleMemStat>(swap_out_stat, GuestAddress(DATA_ADDR))
+            .unwrap();
+        set_request(
+            &statsq,
+            0,
+            DATA_ADDR,
+            SIZE_OF_STAT as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        faascale_mem.queue_events()[FAASCALE_STATS_INDEX]
+            .write(1)
+            .unwrap();
+        faascale_mem.process_stats_queue_event().unwrap();
+        let expected_stats = FaascaleMemStats {
+            swap_out: Some(0x1),
+            ..FaascaleMemStats::default()
+        };
+        assert_eq!(faascale_mem.latest_stats().unwrap(), &expected_stats);
+
+        // Reported figures are kept, and only marked stale.
+        faascale_mem.latest_stats.free_memory = Some(0x5678);
+        faascale_mem.mark_restored_stats();
+        let stats = faascale_mem.latest_stats().unwrap();
+        assert_eq!(stats.free_memory, Some(0x5678));
+        assert_eq!(stats.total_memory, None);
+        assert_eq!(stats.freshness, FaascaleMemStatsFreshness::RestoredStale);
+    }
+
+    #[test]
+    fn test_mmds_publish() {
+        let mmds = Arc::new(Mutex::new(Mmds::default()));
+
+        // Nothing is published unless enabled.
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.set_mmds(Some(mmds.clone()));
+        assert!(faascale_mem.mmds_publisher.is_none());
+
+        faascale_mem.mmds_publish = true;
+        faascale_mem.set_mmds(Some(mmds.clone()));
+        faascale_mem
+            .populated_ranges
+            .insert((0, 2 * u64::from(MIB_TO_4K_PAGES)));
+        faascale_mem.latest_stats.memory_pressure_some_avg10 = Some(125);
+        let summary = faascale_mem.mmds_summary();
+        assert_eq!(summary.populated_mib, 2);
+        assert_eq!(summary.budget_mib, None);
+        assert_eq!(summary.pressure_some_avg10, Some(125));
+
+        faascale_mem.publish_mmds();
+        let value = mmds.lock().unwrap().data_store_value();
+        assert_eq!(value["faascale_mem"]["populated_mib"], 2);
+        assert_eq!(value["faascale_mem"]["pressure_some_avg10"], 125);
+    }
+
+    #[test]
+    fn test_process_faascale_mem_queues() {
+        let mut faascale_mem = default_faascale_mem(0);
+        let mem = default_mem();
+        faascale_mem.activate(mem).unwrap();
+        faascale_mem.process_virtio_queues()
+    }
+
+    #[test]
+    fn test_update_stats_interval() {
+        let mut faascale_mem = default_faascale_mem(0);
+        let mem = default_mem();
+        faascale_mem.activate(mem).unwrap();
+        assert!(faascale_mem.update_stats_polling_interval(0).is_ok());
+        // The statistics can be enabled and disabled after activation.
+        assert!(faascale_mem.update_stats_polling_interval(1).is_ok());
+        assert!(faascale_mem.stats_enabled());
+        assert!(faascale_mem.update_stats_polling_interval(2).is_ok());
+        assert!(faascale_mem.update_stats_polling_interval(0).is_ok());
+        assert!(!faascale_mem.stats_enabled());
+        assert!(matches!(
+            faascale_mem.stats_timer.get_state(),
+            TimerState::Disarmed
+        ));
+        // Setting the interval it already has does not count as an update.
+        assert_eq!(faascale_mem.config_epoch(), 3);
+
+        // Unless the device was restored without a statistics queue.
+        faascale_mem.avail_features &= !(1u64 << VIRTIO_FAASCALE_MEM_F_STATS_VQ);
+        assert_eq!(
+            format!("{:?}", faascale_mem.update_stats_polling_interval(1)),
+            "Err(StatisticsStateChange)"
+        );
+        assert_eq!(faascale_mem.config_epoch(), 3);
+    }
+
+    #[test]
+    fn test_num_pages() {
+        let mut faascale_mem = default_faascale_mem(0);
+        assert_eq!(faascale_mem.num_pages(), 0);
+        assert_eq!(faascale_mem.actual_pages(), 0);
+
+        // Update fields through the setters.
+        faascale_mem.update_actual_pages(0x1234);
+        faascale_mem.update_num_pages(0x1000);
+        assert_eq!(faascale_mem.num_pages(), 0x1000);
+        assert_eq!(faascale_mem.size_mb(), 16);
+
+        let mut actual_config = vec![0; 8];
+        faascale_mem.read_config(0, &mut actual_config);
+        assert_eq!(actual_config, vec![0x0, 0x10, 0x0, 0x0, 0x34, 0x12, 0, 0]);
+
+        // Update fields through the config space.
+        let expected_config = vec![0x44, 0x33, 0x22, 0x11, 0x78, 0x56, 0x34, 0x12];
+        faascale_mem.write_config(0, &expected_config);
+        assert_eq!(faascale_mem.num_pages(), 0x1122_3344);
+        assert_eq!(faascale_mem.actual_pages(), 0x1234_5678);
+
+        let config_space = faascale_mem.config_space_info();
+        assert!(config_space.raw.starts_with("4433221178563412"));
+        assert_eq!(config_space.num_pages, 0x1122_3344);
+        assert_eq!(config_space.actual_pages, 0x1234_5678);
+    }
+
+    #[test]
+    fn test_update_size() {
+        let mut faascale_mem = default_faascale_mem(0);
+        assert!(matches!(
+            faascale_mem.update_size(16),
+            Err(FaascaleMemError::DeviceNotActive)
+        ));
+
+        faascale_mem.activate(default_mem()).unwrap();
+        let epoch = faascale_mem.config_epoch();
+        faascale_mem.update_size(16).unwrap();
+        assert_eq!(faascale_mem.num_pages(), 16 * MIB_TO_4K_PAGES);
+        assert_eq!(faascale_mem.config().target_mib, 16);
+        assert_eq!(faascale_mem.config_epoch(), epoch + 1);
+        // Asking for the size already requested leaves the epoch alone.
+        faascale_mem.update_size(16).unwrap();
+        assert_eq!(faascale_mem.config_epoch(), epoch + 1);
+        assert!(matches!(
+            faascale_mem.update_size(u32::MAX),
+            Err(FaascaleMemError::TooManyPagesRequested)
+        ));
+    }
+
+    #[test]
+    fn test_set_population_policy() {
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.set_pre_alloc_mem(true);
+        faascale_mem.set_pre_tdp_fault(true);
+        assert!(faascale_mem.config().pre_alloc_mem);
+        assert!(faascale_mem.config().pre_tdp_fault);
+        assert_eq!(faascale_mem.config_epoch(), 2);
+        // Setting the policy it already has does not count as an update.
+        faascale_mem.set_pre_tdp_fault(true);
+        assert_eq!(faascale_mem.config_epoch(), 2);
+
+        faascale_mem.set_pre_alloc_mem(false);
+        assert!(!faascale_mem.pre_alloc_mem());
+        assert!(faascale_mem.pre_tdp_fault());
+        assert_eq!(faascale_mem.config_epoch(), 3);
+        faascale_mem.set_pre_tdp_fault(false);
+
+        // The blocks populated after a toggle follow the new policy.
+        let mem = default_mem();
+        let popq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        faascale_mem.set_queue(POPULATE_INDEX, popq.create_queue());
+        faascale_mem.activate(mem.clone()).unwrap();
+        mem.write_obj::<[u32; 2]>([8, 2], GuestAddress(DATA_ADDR))
+            .unwrap();
+        set_request(
+            &popq,
+            0,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        check_request_completion(&popq, 0);
+        let latency = faascale_mem.latest_stats.populate_latency.as_ref().unwrap();
+        assert_eq!(latency.pre_alloc_mem.count, 0);
+
+        faascale_mem.set_pre_alloc_mem(true);
+        mem.write_obj::<[u32; 2]>([12, 2], GuestAddress(DATA_ADDR))
+            .unwrap();
+        set_request(
+            &popq,
+            1,
+            DATA_ADDR,
+            SIZE_OF_BLOCK_INFO as u32,
+            VIRTQ_DESC_F_NEXT,
+        );
+        invoke_handler_for_queue_event(&mut faascale_mem, POPULATE_INDEX);
+        check_request_completion(&popq, 1);
+        let latency = faascale_mem.latest_stats.populate_latency.as_ref().unwrap();
+        assert_eq!(latency.total.count, 2);
+        assert_eq!(latency.pre_alloc_mem.count, 1);
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut faascale_mem = default_faascale_mem(0);
+        let mem = default_mem();
+        faascale_mem.activate(mem.clone()).unwrap();
+        let checks = |faascale_mem: &FaascaleMem| -> Vec<&str> {
+            faascale_mem
+                .verify(1)
+                .into_iter()
+                .map(|(check, _)| check)
+                .collect()
+        };
+
+        faascale_mem.populated_ranges.insert((2, 4));
+        faascale_mem.config_space.actual_pages = 4;
+        assert!(checks(&faascale_mem).is_empty());
+
+        // The guest memory ends at pfn 0x10.
+        faascale_mem.populated_ranges.insert((0xe, 4));
+        faascale_mem.config_space.num_pages = 0x200;
+        assert_eq!(
+            checks(&faascale_mem),
+            vec![
+                "target_within_guest_memory",
+                "populated_within_guest_memory",
+                "actual_pages_match_populated"
+            ]
+        );
+        faascale_mem.populated_ranges.remove((0xe, 4));
+        faascale_mem.config_space.num_pages = 0;
+
+        faascale_mem.host_pinned_ranges.insert((0, 2));
+        let tmp = TempFile::new().unwrap();
+        let mut spill = SpillFile::new(tmp.as_path().to_path_buf()).unwrap();
+        spill.save(&mem, (4, 4)).unwrap();
+        faascale_mem.spill_file = Some(spill);
+        faascale_mem.latest_stats.total_memory = Some(2 << 20);
+        assert_eq!(
+            checks(&faascale_mem),
+            vec![
+                "host_pins_applied",
+                "spilled_not_populated",
+                "guest_total_within_guest_memory"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_health() {
+        let passed = |faascale_mem: &FaascaleMem, name: &str| {
+            faascale_mem
+                .health()
+                .checks
+                .iter()
+                .find(|check| check.name == name)
+                .map(|check| check.passed)
+        };
+        let mut faascale_mem = default_faascale_mem(0);
+        faascale_mem.activate(default_mem()).unwrap();
+
+        // The actual pages written by the guest must match the tracked ranges.
+        faascale_mem.populated_ranges.insert_range(0x100, 0x200);
+        assert_eq!(
+            passed(&faascale_mem, "tracker_matches_config_space"),
+            Some(true)
+        );
+        faascale_mem.update_actual_pages(0x80);
+        assert_eq!(
+            passed(&faascale_mem, "tracker_matches_config_space"),
+            Some(false)
+        );
+        faascale_mem.update_actual_pages(0x100);
+        assert_eq!(
+            passed(&faascale_mem, "tracker_matches_config_space"),
+            Some(true)
+        );
+
+        // Every worker thread of the device must be running.
+        assert_eq!(passed(&faascale_mem, "populate_poller_alive"), None);
+        let worker = thread::spawn(|| ());
+        while !worker.is_finished() {
+            thread::yield_now();
+        }
+        faascale_mem
+            .worker_threads
+            .push(("populate_poller", worker));
+        let health = faascale_mem.health();
+        assert!(!health.healthy);
+        let check = health
+            .checks
+            .iter()
+            .find(|check| check.name == "populate_poller_alive")
+            .unwrap();
+        assert!(!check.passed);
+        assert_eq!(
+            check.detail.as_deref(),
+            Some("the populate_poller thread exited")
+        );
+    }
+
+    #[test]
+    fn test_config_change_acknowledgement() {
+        let mut faascale_mem = default_faascale_mem(0);
+        let clock = ManualClock::new();
+        faascale_mem.set_clock(Arc::new(clock.clone())).unwrap();
+        faascale_mem.activate(default_mem()).unwrap();
+
+        // A change acknowledged in time is not reported.
+        faascale_mem.request_release(1).unwrap();
+        assert!(faascale_mem.health().warnings.is_empty());
+        check_metric_after_block!(
+            METRICS.faascale_mem.late_config_acks,
+            0,
+            faascale_mem.write_config(0, &[0u8; 4])
+        );
+
+        // An overdue change is a warning, which leaves the device healthy.
+        faascale_mem.update_size(1).unwrap();
+        clock.advance(CONFIG_ACK_TIMEOUT * 2);
+        let health = faascale_mem.health();
+        assert!(health.healthy);
+        assert_eq!(health.warnings.len(), 1);
+        assert!(health.warnings[0].contains("`num_pages`"));
+
+        // Queue traffic acknowledges the change, late.
+        faascale_mem.queue_evts[DEPOPULATE_INDEX].write(1).unwrap();
+        check_metric_after_block!(
+            METRICS.faascale_mem.late_config_acks,
+            1,
+            faascale_mem.process_depopulate_queue_event().unwrap()
+        );
+        assert!(faascale_mem.health().warnings.is_empty());
+
+        // Setting the same target again changes nothing to acknowledge.
+        faascale_mem.update_size(1).unwrap();
+        clock.advance(CONFIG_ACK_TIMEOUT * 2);
+        assert!(faascale_mem.health().warnings.is_empty());
+
+        // The pending changes do not outlive the driver session.
+        faascale_mem.request_release(1).unwrap();
+        clock.advance(CONFIG_ACK_TIMEOUT * 2);
+        faascale_mem.reset_driver_session();
+        assert!(faascale_mem.health().warnings.is_empty());
+    }
+}
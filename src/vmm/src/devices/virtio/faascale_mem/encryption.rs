@@ -0,0 +1,77 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hooks for populating the memory of encrypted guests.
+//!
+//! The memory of an encrypted guest (SEV, TDX, ...) cannot be pre-allocated nor pre-faulted by
+//! the host: the pages have to be registered and pinned with the hypervisor before the guest can
+//! use them, and given back before they are released. An [`EncryptedMemoryBackend`] set on the
+//! device takes over these steps for every block, in place of the plain population path.
+
+use std::fs;
+use std::io;
+
+use serde::Serialize;
+use utils::vm_memory::{GuestAddress, GuestMemoryMmap};
+
+/// Memory encryption technology of a guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryEncryptionKind {
+    /// AMD Secure Encrypted Virtualization.
+    Sev,
+    /// AMD SEV with Encrypted State.
+    SevEs,
+    /// AMD SEV with Secure Nested Paging.
+    SevSnp,
+    /// Intel Trust Domain Extensions.
+    Tdx,
+}
+
+impl MemoryEncryptionKind {
+    // KVM module parameter enabling the technology on the host.
+    fn kvm_parameter(self) -> &'static str {
+        match self {
+            MemoryEncryptionKind::Sev => "/sys/module/kvm_amd/parameters/sev",
+            MemoryEncryptionKind::SevEs => "/sys/module/kvm_amd/parameters/sev_es",
+            MemoryEncryptionKind::SevSnp => "/sys/module/kvm_amd/parameters/sev_snp",
+            MemoryEncryptionKind::Tdx => "/sys/module/kvm_intel/parameters/tdx",
+        }
+    }
+}
+
+/// Returns the memory encryption technologies KVM has enabled on the host.
+pub fn detect_memory_encryption() -> Vec<MemoryEncryptionKind> {
+    [
+        MemoryEncryptionKind::Sev,
+        MemoryEncryptionKind::SevEs,
+        MemoryEncryptionKind::SevSnp,
+        MemoryEncryptionKind::Tdx,
+    ]
+    .into_iter()
+    .filter(|kind| {
+        fs::read_to_string(kind.kvm_parameter())
+            .map(|value| matches!(value.trim(), "Y" | "1"))
+            .unwrap_or(false)
+    })
+    .collect()
+}
+
+/// Population path of the memory of an encrypted guest.
+pub trait EncryptedMemoryBackend: Send {
+    /// Memory encryption technology the backend handles.
+    fn kind(&self) -> MemoryEncryptionKind;
+
+    /// Whether the host can run guests encrypted with the technology of the backend.
+    fn is_supported(&self) -> bool {
+        detect_memory_encryption().contains(&self.kind())
+    }
+
+    /// Makes the range usable by the guest, e.g. by registering and pinning it with the
+    /// hypervisor. Replaces the pre-allocation and the TDP pre-fault of plain guests.
+    fn populate(&mut self, mem: &GuestMemoryMmap, range: (GuestAddress, u64)) -> io::Result<()>;
+
+    /// Gives the range back, e.g. by unpinning and unregistering it. The range is only
+    /// released once this succeeds.
+    fn depopulate(&mut self, mem: &GuestMemoryMmap, range: (GuestAddress, u64)) -> io::Result<()>;
+}
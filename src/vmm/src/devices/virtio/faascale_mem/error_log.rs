@@ -0,0 +1,155 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! History of the recent populate and depopulate failures.
+//!
+//! The failures of the madvise, mmap and KVM calls behind the guest requests are otherwise only
+//! logged. The last `ERROR_HISTORY_LEN` of them are kept, oldest first, for operators to debug a
+//! microVM through the API.
+
+use std::collections::VecDeque;
+
+use serde::Serialize;
+use utils::time::{get_time_us, ClockType};
+
+use super::RemoveRegionError;
+
+/// Number of failures kept in the history.
+pub const ERROR_HISTORY_LEN: usize = 64;
+
+/// Operation of the device which failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaascaleMemOperation {
+    /// Backing a block with host memory.
+    Populate,
+    /// Releasing the host memory of a block.
+    Depopulate,
+}
+
+/// A populate or depopulate failure.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemErrorRecord {
+    /// Wall clock time of the failure, in microseconds since the epoch.
+    pub timestamp_us: u64,
+    /// The operation which failed.
+    pub operation: FaascaleMemOperation,
+    /// First guest page frame of the block.
+    pub start_pfn: u64,
+    /// Number of guest pages of the block.
+    pub num_pages: u64,
+    /// Error number returned by the host, if a system call failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errno: Option<i32>,
+    /// Description of the error.
+    pub error: String,
+}
+
+/// Recent populate and depopulate failures, as reported by the API.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemErrors {
+    /// Number of failures since the device was activated, including the ones no longer kept.
+    pub total: u64,
+    /// The last failures, oldest first.
+    pub errors: Vec<FaascaleMemErrorRecord>,
+}
+
+/// Ring of the last `ERROR_HISTORY_LEN` failures.
+#[derive(Debug, Default)]
+pub(crate) struct ErrorLog {
+    total: u64,
+    records: VecDeque<FaascaleMemErrorRecord>,
+}
+
+impl ErrorLog {
+    /// Records the failure of `operation` on the `(start pfn, number of pages)` block, dropping
+    /// the oldest failure once the history is full.
+    pub fn record(
+        &mut self,
+        operation: FaascaleMemOperation,
+        block: (u64, u64),
+        err: &RemoveRegionError,
+    ) {
+        if self.records.len() == ERROR_HISTORY_LEN {
+            self.records.pop_front();
+        }
+        self.records.push_back(FaascaleMemErrorRecord {
+            timestamp_us: get_time_us(ClockType::Real),
+            operation,
+            start_pfn: block.0,
+            num_pages: block.1,
+            errno: region_errno(err),
+            error: format!("{:?}", err),
+        });
+        self.total += 1;
+    }
+
+    pub fn report(&self) -> FaascaleMemErrors {
+        FaascaleMemErrors {
+            total: self.total,
+            errors: self.records.iter().cloned().collect(),
+        }
+    }
+}
+
+// Error number of the system call behind a region error, if any.
+fn region_errno(err: &RemoveRegionError) -> Option<i32> {
+    match err {
+        RemoveRegionError::EncryptionBackend(err)
+        | RemoveRegionError::MadviseFail(err)
+        | RemoveRegionError::MmapFail(err) => err.raw_os_error(),
+        RemoveRegionError::AddressTranslation
+        | RemoveRegionError::MalformedRange
+        | RemoveRegionError::MisalignedHugePage
+        | RemoveRegionError::OutsideMemslot(_)
+        | RemoveRegionError::RegionNotFound
+        | RemoveRegionError::Userfaultfd(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use utils::vm_memory::GuestAddress;
+
+    use super::*;
+
+    #[test]
+    fn test_error_log() {
+        let mut log = ErrorLog::default();
+        assert_eq!(log.report(), FaascaleMemErrors::default());
+
+        log.record(
+            FaascaleMemOperation::Populate,
+            (0x10, 0x8),
+            &RemoveRegionError::MadviseFail(io::Error::from_raw_os_error(libc::ENOMEM)),
+        );
+        let report = log.report();
+        assert_eq!(report.total, 1);
+        assert_eq!(report.errors[0].operation, FaascaleMemOperation::Populate);
+        assert_eq!(report.errors[0].start_pfn, 0x10);
+        assert_eq!(report.errors[0].num_pages, 0x8);
+        assert_eq!(report.errors[0].errno, Some(libc::ENOMEM));
+        assert_ne!(report.errors[0].timestamp_us, 0);
+
+        // Only the last failures are kept, and counted all the same.
+        for pfn in 0..ERROR_HISTORY_LEN as u64 {
+            log.record(
+                FaascaleMemOperation::Depopulate,
+                (pfn, 1),
+                &RemoveRegionError::OutsideMemslot(GuestAddress(pfn)),
+            );
+        }
+        let report = log.report();
+        assert_eq!(report.total, ERROR_HISTORY_LEN as u64 + 1);
+        assert_eq!(report.errors.len(), ERROR_HISTORY_LEN);
+        assert_eq!(report.errors[0].operation, FaascaleMemOperation::Depopulate);
+        assert_eq!(report.errors[0].start_pfn, 0);
+        assert_eq!(report.errors[0].errno, None);
+        assert_eq!(
+            report.errors[ERROR_HISTORY_LEN - 1].start_pfn,
+            ERROR_HISTORY_LEN as u64 - 1
+        );
+    }
+}
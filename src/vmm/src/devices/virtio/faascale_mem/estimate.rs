@@ -0,0 +1,331 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Capacity estimates of the scale-ups of the guest memory.
+//!
+//! Schedulers ask whether the guest could populate up to a target amount of memory before
+//! routing work to the microVM. The target is checked against the limits of the device, the
+//! memory left on the host and, for guests backed by hugetlb pages, the free huge pages of the
+//! host. The time the scale-up takes is extrapolated from the population throughput so far.
+
+use std::fs;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// File the memory of the host is read from.
+const HOST_MEMINFO_PATH: &str = "/proc/meminfo";
+
+/// Limit keeping a scale-up from being satisfied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaascaleMemEstimateLimit {
+    /// The device is fenced and refuses the populate requests.
+    Fenced,
+    /// The cap set by the host on the populated memory.
+    MaxPopulated,
+    /// The memory budget the guest agreed on.
+    Budget,
+    /// The memory available on the host.
+    HostMemory,
+    /// The free huge pages of the host, for guests backed by hugetlb pages.
+    HugePages,
+}
+
+/// Capacity estimate of a scale-up of the memory populated by the guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemEstimate {
+    /// Memory the guest would hold populated after the scale-up, in MiB.
+    pub target_mib: u32,
+    /// Memory populated by the guest, in MiB.
+    pub populated_mib: u64,
+    /// Memory left to populate to reach the target, in MiB.
+    pub additional_mib: u64,
+    /// Whether the guest could populate up to the target.
+    pub satisfiable: bool,
+    /// Limits the target goes over.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub limited_by: Vec<FaascaleMemEstimateLimit>,
+    /// Most memory the guest could hold populated, in MiB, when any limit applies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_mib: Option<u64>,
+    /// Memory available on the host, in MiB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_available_mib: Option<u64>,
+    /// Free huge pages of the host, in MiB, for guests backed by hugetlb pages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugepages_free_mib: Option<u64>,
+    /// Population throughput so far, in MiB per second.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub populate_mib_per_s: Option<u64>,
+    /// Time populating the additional memory would take at that throughput, in milliseconds.
+    /// Unknown until the guest populated memory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_ms: Option<u64>,
+}
+
+/// Memory of the host, as read from `/proc/meminfo`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct HostMemInfo {
+    /// Memory available for new allocations, in MiB.
+    pub available_mib: Option<u64>,
+    /// Free huge pages of the default size, in MiB.
+    pub hugepages_free_mib: Option<u64>,
+}
+
+impl HostMemInfo {
+    /// Reads the memory of the host. The fields that cannot be read are left unknown.
+    pub fn read() -> Self {
+        fs::read_to_string(HOST_MEMINFO_PATH)
+            .map(|meminfo| Self::parse(&meminfo))
+            .unwrap_or_default()
+    }
+
+    fn parse(meminfo: &str) -> Self {
+        let field = |name: &str| {
+            meminfo.lines().find_map(|line| {
+                line.strip_prefix(name)?
+                    .strip_prefix(':')?
+                    .split_whitespace()
+                    .next()?
+                    .parse::<u64>()
+                    .ok()
+            })
+        };
+        // The sizes are in KiB, the huge pages are counted.
+        HostMemInfo {
+            available_mib: field("MemAvailable").map(|kib| kib >> 10),
+            hugepages_free_mib: field("HugePages_Free")
+                .zip(field("Hugepagesize"))
+                .map(|(free, size_kib)| free.saturating_mul(size_kib) >> 10),
+        }
+    }
+}
+
+/// Memory populated by the device and the time it took.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PopulateThroughput {
+    bytes: u64,
+    busy: Duration,
+}
+
+impl PopulateThroughput {
+    /// Records `bytes` populated in `duration`.
+    pub fn record(&mut self, bytes: u64, duration: Duration) {
+        self.bytes = self.bytes.saturating_add(bytes);
+        self.busy = self.busy.saturating_add(duration);
+    }
+
+    /// Populated MiB per second, once memory was populated.
+    pub fn mib_per_s(&self) -> Option<u64> {
+        let (bytes, busy_us) = self.sample()?;
+        Some(u64::try_from((bytes * 1_000_000 / busy_us) >> 20).unwrap_or(u64::MAX))
+    }
+
+    /// Time populating `mib` takes at the throughput so far, once memory was populated.
+    pub fn time_to_populate(&self, mib: u64) -> Option<Duration> {
+        let (bytes, busy_us) = self.sample()?;
+        let us = (u128::from(mib) << 20) * busy_us / bytes;
+        Some(Duration::from_micros(u64::try_from(us).unwrap_or(u64::MAX)))
+    }
+
+    fn sample(&self) -> Option<(u128, u128)> {
+        let busy_us = self.busy.as_micros();
+        (self.bytes > 0 && busy_us > 0).then_some((u128::from(self.bytes), busy_us))
+    }
+}
+
+/// State of the device a scale-up is estimated from.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct EstimateInputs {
+    /// Memory populated by the guest, in MiB.
+    pub populated_mib: u64,
+    /// Whether the device refuses the populate requests.
+    pub fenced: bool,
+    /// Cap set by the host on the populated memory, in MiB.
+    pub max_populated_mib: Option<u64>,
+    /// Budget the guest agreed on, in MiB.
+    pub budget_mib: Option<u64>,
+    /// Memory of the host.
+    pub host: HostMemInfo,
+    /// Whether the guest memory is backed by hugetlb pages.
+    pub hugetlb: bool,
+    /// Population throughput so far.
+    pub throughput: PopulateThroughput,
+}
+
+/// Estimates whether and how fast the guest could populate up to `target_mib`.
+pub(crate) fn estimate(target_mib: u32, inputs: &EstimateInputs) -> FaascaleMemEstimate {
+    let target = u64::from(target_mib);
+    let additional_mib = target.saturating_sub(inputs.populated_mib);
+    let hugepages_free_mib = inputs.host.hugepages_free_mib.filter(|_| inputs.hugetlb);
+
+    // The limits of the device cap the populated memory, the ones of the host the memory
+    // populated from now on.
+    let limits = [
+        (
+            FaascaleMemEstimateLimit::MaxPopulated,
+            inputs.max_populated_mib,
+        ),
+        (FaascaleMemEstimateLimit::Budget, inputs.budget_mib),
+        (
+            FaascaleMemEstimateLimit::HostMemory,
+            inputs
+                .host
+                .available_mib
+                .map(|mib| inputs.populated_mib.saturating_add(mib)),
+        ),
+        (
+            FaascaleMemEstimateLimit::HugePages,
+            hugepages_free_mib.map(|mib| inputs.populated_mib.saturating_add(mib)),
+        ),
+    ];
+    let max_mib = limits.iter().filter_map(|(_, max_mib)| *max_mib).min();
+
+    let mut limited_by = Vec::new();
+    if additional_mib > 0 {
+        if inputs.fenced {
+            limited_by.push(FaascaleMemEstimateLimit::Fenced);
+        }
+        limited_by.extend(
+            limits
+                .iter()
+                .filter(|(_, max_mib)| max_mib.map_or(false, |max_mib| target > max_mib))
+                .map(|(limit, _)| *limit),
+        );
+    }
+
+    FaascaleMemEstimate {
+        target_mib,
+        populated_mib: inputs.populated_mib,
+        additional_mib,
+        satisfiable: limited_by.is_empty(),
+        limited_by,
+        max_mib,
+        host_available_mib: inputs.host.available_mib,
+        hugepages_free_mib,
+        populate_mib_per_s: inputs.throughput.mib_per_s(),
+        estimated_ms: inputs
+            .throughput
+            .time_to_populate(additional_mib)
+            .map(|time| u64::try_from(time.as_millis()).unwrap_or(u64::MAX)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEMINFO: &str = "MemTotal:       16316412 kB\n\
+                           MemFree:          812244 kB\n\
+                           MemAvailable:    2097152 kB\n\
+                           HugePages_Total:     512\n\
+                           HugePages_Free:      256\n\
+                           Hugepagesize:       2048 kB\n";
+
+    #[test]
+    fn test_parse_meminfo() {
+        assert_eq!(
+            HostMemInfo::parse(MEMINFO),
+            HostMemInfo {
+                available_mib: Some(2048),
+                hugepages_free_mib: Some(512),
+            }
+        );
+        // Fields that are missing or malformed are left unknown.
+        assert_eq!(
+            HostMemInfo::parse("MemAvailableX: 10 kB\nHugePages_Free: 4\n"),
+            HostMemInfo::default()
+        );
+        assert_eq!(
+            HostMemInfo::parse("MemAvailable: lots kB\n"),
+            HostMemInfo::default()
+        );
+    }
+
+    #[test]
+    fn test_throughput() {
+        let mut throughput = PopulateThroughput::default();
+        assert_eq!(throughput.mib_per_s(), None);
+        assert_eq!(throughput.time_to_populate(64), None);
+
+        throughput.record(64 << 20, Duration::from_millis(100));
+        throughput.record(64 << 20, Duration::from_millis(100));
+        assert_eq!(throughput.mib_per_s(), Some(640));
+        assert_eq!(
+            throughput.time_to_populate(64),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(throughput.time_to_populate(0), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_estimate() {
+        let mut throughput = PopulateThroughput::default();
+        throughput.record(100 << 20, Duration::from_millis(100));
+        let mut inputs = EstimateInputs {
+            populated_mib: 256,
+            host: HostMemInfo {
+                available_mib: Some(1024),
+                hugepages_free_mib: Some(128),
+            },
+            throughput,
+            ..Default::default()
+        };
+
+        // Only the memory of the host limits the scale-up, the huge pages do not back the
+        // guest memory.
+        let estimate_512 = estimate(512, &inputs);
+        assert_eq!(
+            estimate_512,
+            FaascaleMemEstimate {
+                target_mib: 512,
+                populated_mib: 256,
+                additional_mib: 256,
+                satisfiable: true,
+                limited_by: vec![],
+                max_mib: Some(1280),
+                host_available_mib: Some(1024),
+                hugepages_free_mib: None,
+                populate_mib_per_s: Some(1000),
+                estimated_ms: Some(256),
+            }
+        );
+        let estimate_2048 = estimate(2048, &inputs);
+        assert!(!estimate_2048.satisfiable);
+        assert_eq!(
+            estimate_2048.limited_by,
+            vec![FaascaleMemEstimateLimit::HostMemory]
+        );
+
+        inputs.hugetlb = true;
+        inputs.max_populated_mib = Some(300);
+        inputs.budget_mib = Some(1024);
+        inputs.fenced = true;
+        let estimate_512 = estimate(512, &inputs);
+        assert!(!estimate_512.satisfiable);
+        assert_eq!(
+            estimate_512.limited_by,
+            vec![
+                FaascaleMemEstimateLimit::Fenced,
+                FaascaleMemEstimateLimit::MaxPopulated,
+                FaascaleMemEstimateLimit::HugePages,
+            ]
+        );
+        assert_eq!(estimate_512.max_mib, Some(300));
+        assert_eq!(estimate_512.hugepages_free_mib, Some(128));
+
+        // Targets already reached are satisfied whatever the limits.
+        let estimate_128 = estimate(128, &inputs);
+        assert!(estimate_128.satisfiable);
+        assert!(estimate_128.limited_by.is_empty());
+        assert_eq!(estimate_128.additional_mib, 0);
+        assert_eq!(estimate_128.estimated_ms, Some(0));
+
+        // Without any population, the time is unknown.
+        inputs.throughput = PopulateThroughput::default();
+        let estimate_256 = estimate(256, &inputs);
+        assert_eq!(estimate_256.populate_mib_per_s, None);
+        assert_eq!(estimate_256.estimated_ms, None);
+    }
+}
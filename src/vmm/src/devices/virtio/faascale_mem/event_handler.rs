@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::os::unix::io::AsRawFd;
+use std::time::Instant;
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
 use logger::{debug, error, warn};
@@ -13,20 +14,45 @@ use crate::devices::virtio::{VirtioDevice, DEPOPULATE_INDEX, POPULATE_INDEX, FAA
 
 impl FaascaleMem {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[POPULATE_INDEX], EventSet::IN)) {
-            error!("Failed to register populate queue event: {}", err);
+        // In latency mode the populate queue is handled by its own thread.
+        if !self.latency_mode {
+            if let Err(err) = ops.add(Events::new(&self.queue_evts[POPULATE_INDEX], EventSet::IN)) {
+                error!("Failed to register populate queue event: {}", err);
+            }
         }
         if let Err(err) = ops.add(Events::new(&self.queue_evts[DEPOPULATE_INDEX], EventSet::IN)) {
             error!("Failed to register depopulate queue event: {}", err);
         }
-        if self.stats_enabled() {
+        if let Err(err) = ops.add(Events::new(&self.irq_moderator, EventSet::IN)) {
+            error!("Failed to register moderation timerfd event: {}", err);
+        }
+        // The rate limiter can be set up after activation, its event is registered even while
+        // it is unlimited.
+        if let Err(err) = ops.add(Events::new(&self.rate_limiter, EventSet::IN)) {
+            error!("Failed to register rate limiter event: {}", err);
+        }
+        if self.depopulate_batcher.is_some() {
+            let timer_fd = self.depopulate_batch_timer.as_raw_fd();
+            if let Err(err) = ops.add(Events::new_raw(timer_fd, EventSet::IN)) {
+                error!("Failed to register depopulate batch timerfd event: {}", err);
+            }
+        }
+        // The statistics can be enabled after activation, so their events are registered even
+        // while they are disabled. The timer is only armed once they are enabled.
+        if self.stats_queue_present() {
             if let Err(err) = ops.add(Events::new(&self.queue_evts[FAASCALE_STATS_INDEX], EventSet::IN)) {
                 error!("Failed to register stats queue event: {}", err);
             }
-            if let Err(err) = ops.add(Events::new(&self.stats_timer, EventSet::IN)) {
+            if let Err(err) = ops.add(Events::new_raw(self.stats_timer.as_raw_fd(), EventSet::IN)) {
                 error!("Failed to register stats timerfd event: {}", err);
             }
         }
+        if self.budget_enabled() {
+            let control_evt = &self.queue_evts[self.control_index()];
+            if let Err(err) = ops.add(Events::new(control_evt, EventSet::IN)) {
+                error!("Failed to register control queue event: {}", err);
+            }
+        }
     }
 
     fn register_activate_event(&self, ops: &mut EventOps) {
@@ -65,23 +91,40 @@ impl MutEventSubscriber for FaascaleMem {
             let virtq_populate_ev_fd = self.queue_evts[POPULATE_INDEX].as_raw_fd();
             let virtq_depopulate_ev_fd = self.queue_evts[DEPOPULATE_INDEX].as_raw_fd();
             let virtq_stats_ev_fd = self.queue_evts[FAASCALE_STATS_INDEX].as_raw_fd();
+            let virtq_control_ev_fd = self.queue_evts[self.control_index()].as_raw_fd();
             let stats_timer_fd = self.stats_timer.as_raw_fd();
+            let depopulate_batch_timer_fd = self.depopulate_batch_timer.as_raw_fd();
+            let rate_limiter_fd = self.rate_limiter.as_raw_fd();
+            let irq_moderation_fd = self.irq_moderator.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
 
             // Looks better than C style if/else if/else.
             match source {
                 _ if source == virtq_populate_ev_fd => self
-                    .process_populate_queue_event()
+                    .process_populate_queue_event(Instant::now())
                     .unwrap_or_else(report_faascale_mem_event_fail),
                 _ if source == virtq_depopulate_ev_fd => self
                     .process_depopulate_queue_event()
                     .unwrap_or_else(report_faascale_mem_event_fail),
+                // Without the stats queue, the control queue takes its event.
+                _ if self.budget_enabled() && source == virtq_control_ev_fd => self
+                    .process_control_queue_event()
+                    .unwrap_or_else(report_faascale_mem_event_fail),
                 _ if source == virtq_stats_ev_fd => self
                     .process_stats_queue_event()
                     .unwrap_or_else(report_faascale_mem_event_fail),
                 _ if source == stats_timer_fd => self
                     .process_stats_timer_event()
                     .unwrap_or_else(report_faascale_mem_event_fail),
+                _ if source == depopulate_batch_timer_fd => {
+                    self.process_depopulate_batch_timer_event()
+                }
+                _ if source == rate_limiter_fd => self
+                    .process_rate_limiter_event()
+                    .unwrap_or_else(report_faascale_mem_event_fail),
+                _ if source == irq_moderation_fd => self
+                    .process_irq_moderation_event()
+                    .unwrap_or_else(report_faascale_mem_event_fail),
                 _ if activate_fd == source => self.process_activate_event(ops),
                 _ => {
                     warn!("FaascaleMem: Spurious event received: {:?}", source);
@@ -0,0 +1,233 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A/B experiment between two population policies.
+//!
+//! Each populate block is handled with variant B with the configured probability, and with
+//! variant A otherwise. The number of blocks, the failures, the latency of the population and the
+//! host page faults taken along the way are recorded per variant in the statistics of the
+//! device, and counted in the metrics, so that the policies can be compared on live traffic.
+
+use std::time::Instant;
+
+use logger::{FaascaleMemExperimentMetrics, IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
+use utils::time::{get_time_ns, ClockType};
+
+use super::latency::LatencyHistogram;
+use super::Error;
+
+/// Population policy applied to the populate blocks of an experiment variant.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemPopulatePolicy {
+    /// Pre-allocate the memory of the blocks.
+    #[serde(default)]
+    pub pre_alloc_mem: bool,
+    /// Pre-handle the TDP faults of the blocks.
+    #[serde(default)]
+    pub pre_tdp_fault: bool,
+}
+
+/// Experiment splitting the populate blocks between two population policies. Overrides the
+/// `pre_alloc_mem` and `pre_tdp_fault` settings of the device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemExperiment {
+    /// Policy of variant A.
+    pub variant_a: FaascaleMemPopulatePolicy,
+    /// Policy of variant B.
+    pub variant_b: FaascaleMemPopulatePolicy,
+    /// Percentage of the populate blocks handled with variant B, from 0 to 100.
+    pub variant_b_percent: u8,
+}
+
+/// Measurements of the populate blocks handled with one variant of an experiment.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemExperimentVariantResults {
+    /// Number of populate blocks handled with the variant.
+    pub populate_count: u64,
+    /// Number of populate blocks the variant failed to populate.
+    pub populate_fails: u64,
+    /// Number of host page faults taken while populating the blocks.
+    pub page_faults: u64,
+    /// Latency histogram of the population of the blocks.
+    pub latency: LatencyHistogram,
+}
+
+/// Measurements of an experiment, per variant.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemExperimentResults {
+    /// Blocks handled with variant A.
+    pub variant_a: FaascaleMemExperimentVariantResults,
+    /// Blocks handled with variant B.
+    pub variant_b: FaascaleMemExperimentVariantResults,
+}
+
+/// Variant of the experiment a populate block is assigned to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExperimentVariant {
+    A,
+    B,
+}
+
+impl ExperimentVariant {
+    fn metrics(self) -> &'static FaascaleMemExperimentMetrics {
+        match self {
+            ExperimentVariant::A => &METRICS.faascale_mem.experiment_a,
+            ExperimentVariant::B => &METRICS.faascale_mem.experiment_b,
+        }
+    }
+}
+
+/// Assigns the populate blocks to the variants of an experiment.
+#[derive(Debug)]
+pub(crate) struct ExperimentSplitter {
+    experiment: FaascaleMemExperiment,
+    // State of the xorshift generator drawing the variants, never 0.
+    rng_state: u64,
+}
+
+impl ExperimentSplitter {
+    pub fn new(experiment: FaascaleMemExperiment) -> Result<Self, Error> {
+        if experiment.variant_b_percent > 100 {
+            return Err(Error::InvalidExperimentSplit);
+        }
+
+        Ok(ExperimentSplitter {
+            experiment,
+            rng_state: get_time_ns(ClockType::Monotonic) | 1,
+        })
+    }
+
+    pub fn experiment(&self) -> FaascaleMemExperiment {
+        self.experiment
+    }
+
+    /// Draws the variant of the next populate block and returns it along with its policy.
+    pub fn assign(&mut self) -> (ExperimentVariant, FaascaleMemPopulatePolicy) {
+        if self.next_percentile() < self.experiment.variant_b_percent {
+            (ExperimentVariant::B, self.experiment.variant_b)
+        } else {
+            (ExperimentVariant::A, self.experiment.variant_a)
+        }
+    }
+
+    // Returns a pseudo-random number in [0, 100).
+    fn next_percentile(&mut self) -> u8 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        (self.rng_state % 100) as u8
+    }
+}
+
+/// Measures the population of a block assigned to a variant.
+#[derive(Debug)]
+pub(crate) struct ExperimentSample {
+    variant: ExperimentVariant,
+    started_at: Instant,
+    page_faults_before: u64,
+}
+
+impl ExperimentSample {
+    pub fn start(variant: ExperimentVariant) -> Self {
+        ExperimentSample {
+            variant,
+            page_faults_before: thread_page_faults(),
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Adds the measurements to the results and to the metrics of the variant.
+    pub fn finish(self, populated: bool, results: &mut FaascaleMemExperimentResults) {
+        let elapsed = self.started_at.elapsed();
+        let page_faults = thread_page_faults().saturating_sub(self.page_faults_before);
+        let variant_results = match self.variant {
+            ExperimentVariant::A => &mut results.variant_a,
+            ExperimentVariant::B => &mut results.variant_b,
+        };
+        variant_results.populate_count += 1;
+        variant_results.populate_fails += u64::from(!populated);
+        variant_results.page_faults += page_faults;
+        variant_results.latency.record(elapsed);
+
+        let metrics = self.variant.metrics();
+        metrics.populate_count.inc();
+        if !populated {
+            metrics.populate_fails.inc();
+        }
+        metrics.populate_us.add(elapsed.as_micros() as usize);
+        metrics.page_faults.add(page_faults as usize);
+    }
+}
+
+// Number of page faults taken by the calling thread.
+fn thread_page_faults() -> u64 {
+    // SAFETY: Safe because the struct is plain data.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: `usage` is valid for writes.
+    if unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) } < 0 {
+        return 0;
+    }
+    u64::try_from(usage.ru_minflt + usage.ru_majflt).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_experiment_splitter() {
+        let experiment = |variant_b_percent| FaascaleMemExperiment {
+            variant_a: FaascaleMemPopulatePolicy::default(),
+            variant_b: FaascaleMemPopulatePolicy {
+                pre_alloc_mem: true,
+                pre_tdp_fault: false,
+            },
+            variant_b_percent,
+        };
+        assert!(matches!(
+            ExperimentSplitter::new(experiment(101)),
+            Err(Error::InvalidExperimentSplit)
+        ));
+
+        // The bounds of the split send every block to one of the variants.
+        let mut splitter = ExperimentSplitter::new(experiment(0)).unwrap();
+        assert!((0..1000).all(|_| splitter.assign().0 == ExperimentVariant::A));
+        let mut splitter = ExperimentSplitter::new(experiment(100)).unwrap();
+        let variant_b = (ExperimentVariant::B, experiment(100).variant_b);
+        assert!((0..1000).all(|_| splitter.assign() == variant_b));
+
+        // Both variants get their share of the blocks.
+        let mut splitter = ExperimentSplitter::new(experiment(25)).unwrap();
+        let variant_b_count = (0..10_000)
+            .filter(|_| splitter.assign().0 == ExperimentVariant::B)
+            .count();
+        assert!((2000..3000).contains(&variant_b_count));
+        assert_eq!(splitter.experiment(), experiment(25));
+    }
+
+    #[test]
+    fn test_experiment_sample() {
+        let mut results = FaascaleMemExperimentResults::default();
+        let sample = ExperimentSample::start(ExperimentVariant::B);
+        // Touching fresh anonymous memory takes page faults.
+        let buffer = vec![1u8; 1 << 20];
+        assert_eq!(
+            buffer.iter().map(|&b| usize::from(b)).sum::<usize>(),
+            1 << 20
+        );
+        sample.finish(false, &mut results);
+        ExperimentSample::start(ExperimentVariant::B).finish(true, &mut results);
+
+        assert_eq!(
+            results.variant_a,
+            FaascaleMemExperimentVariantResults::default()
+        );
+        assert_eq!(results.variant_b.populate_count, 2);
+        assert_eq!(results.variant_b.populate_fails, 1);
+        assert!(results.variant_b.page_faults > 0);
+        assert_eq!(results.variant_b.latency.count, 2);
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Activity heatmap of the guest physical address space.
+//!
+//! The blocks populated and depopulated through the device are aggregated into buckets of
+//! `HEATMAP_BUCKET_MIB` of guest memory, so that operators can tell which parts of the address
+//! space churn the most. A block straddling buckets is split between them, and only the buckets
+//! which saw activity are kept.
+
+use std::cmp;
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::MIB_TO_4K_PAGES;
+
+/// Size of the buckets of the heatmap, in MiB.
+pub const HEATMAP_BUCKET_MIB: u32 = 64;
+
+/// Activity within a bucket of guest memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemHeatmapBucket {
+    /// First guest page frame of the bucket.
+    pub start_pfn: u64,
+    /// Number of blocks populated within the bucket.
+    pub populate_count: u64,
+    /// Number of guest pages populated within the bucket.
+    pub populated_pages: u64,
+    /// Number of blocks depopulated within the bucket.
+    pub depopulate_count: u64,
+    /// Number of guest pages depopulated within the bucket.
+    pub depopulated_pages: u64,
+}
+
+/// Activity heatmap of the guest memory, as reported by the API.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemHeatmap {
+    /// Size of the buckets, in MiB.
+    pub bucket_mib: u32,
+    /// The buckets which saw activity, in ascending order.
+    pub buckets: Vec<FaascaleMemHeatmapBucket>,
+}
+
+/// Counters of the buckets of guest memory, keyed by their first page frame.
+#[derive(Debug, Default)]
+pub(crate) struct ActivityHeatmap {
+    buckets: BTreeMap<u64, FaascaleMemHeatmapBucket>,
+}
+
+impl ActivityHeatmap {
+    /// Records the population of the `(start pfn, number of pages)` block.
+    pub fn populated(&mut self, block: (u64, u64)) {
+        self.record(block, |bucket, pages| {
+            bucket.populate_count += 1;
+            bucket.populated_pages += pages;
+        });
+    }
+
+    /// Records the depopulation of the `(start pfn, number of pages)` block.
+    pub fn depopulated(&mut self, block: (u64, u64)) {
+        self.record(block, |bucket, pages| {
+            bucket.depopulate_count += 1;
+            bucket.depopulated_pages += pages;
+        });
+    }
+
+    /// Reports the buckets which saw activity.
+    pub fn report(&self) -> FaascaleMemHeatmap {
+        FaascaleMemHeatmap {
+            bucket_mib: HEATMAP_BUCKET_MIB,
+            buckets: self.buckets.values().copied().collect(),
+        }
+    }
+
+    // Applies `update` to each bucket the block falls into, with the number of its pages there.
+    fn record<F>(&mut self, block: (u64, u64), update: F)
+    where
+        F: Fn(&mut FaascaleMemHeatmapBucket, u64),
+    {
+        let bucket_pages = u64::from(HEATMAP_BUCKET_MIB) * u64::from(MIB_TO_4K_PAGES);
+        let (mut start, end) = (block.0, block.0.saturating_add(block.1));
+        while start < end {
+            let bucket_start = start - start % bucket_pages;
+            let piece_end = cmp::min(end, bucket_start.saturating_add(bucket_pages));
+            let bucket = self
+                .buckets
+                .entry(bucket_start)
+                .or_insert(FaascaleMemHeatmapBucket {
+                    start_pfn: bucket_start,
+                    ..Default::default()
+                });
+            update(bucket, piece_end - start);
+            start = piece_end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activity_heatmap() {
+        let bucket_pages = u64::from(HEATMAP_BUCKET_MIB) * u64::from(MIB_TO_4K_PAGES);
+        let mut heatmap = ActivityHeatmap::default();
+        assert_eq!(
+            heatmap.report(),
+            FaascaleMemHeatmap {
+                bucket_mib: HEATMAP_BUCKET_MIB,
+                buckets: vec![],
+            }
+        );
+
+        heatmap.populated((0x10, 0x10));
+        heatmap.populated((0x20, 0x10));
+        heatmap.depopulated((0x10, 0x8));
+        // A block straddling two buckets counts in both.
+        heatmap.populated((2 * bucket_pages - 4, 8));
+        heatmap.depopulated((0, 0));
+
+        let report = heatmap.report();
+        assert_eq!(report.buckets.len(), 3);
+        assert_eq!(
+            report.buckets[0],
+            FaascaleMemHeatmapBucket {
+                start_pfn: 0,
+                populate_count: 2,
+                populated_pages: 0x20,
+                depopulate_count: 1,
+                depopulated_pages: 0x8,
+            }
+        );
+        assert_eq!(report.buckets[1].start_pfn, bucket_pages);
+        assert_eq!(report.buckets[1].populated_pages, 4);
+        assert_eq!(report.buckets[2].start_pfn, 2 * bucket_pages);
+        assert_eq!(report.buckets[2].populate_count, 1);
+        assert_eq!(report.buckets[2].populated_pages, 4);
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Slot reserved at boot for hot-plugging the faascale-mem device.
+//!
+//! The guest discovers the virtio-mmio devices at boot only, from the kernel command line or the
+//! FDT, and the microVM has no ACPI to announce new ones. A microVM booted with a hot-plug slot
+//! gets a virtio-mmio device of ID 0 there, which Linux probes as a placeholder without function
+//! and leaves unbound. Hot-plugging the faascale-mem device swaps it in at the slot, after which
+//! the guest binds the virtio-mmio driver to the slot again to probe it:
+//! ```text
+//! echo virtio-mmio.<N> > /sys/bus/platform/drivers/virtio-mmio/bind
+//! ```
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use utils::eventfd::EventFd;
+use utils::vm_memory::GuestMemoryMmap;
+
+use super::{Error as FaascaleMemError, FaascaleMemCapabilities};
+use crate::devices::virtio::{ActivateError, ActivateResult, Queue, VirtioDevice};
+
+/// Device ID of the hot-plug slot in MMIO device identification.
+pub const FAASCALE_MEM_HOTPLUG_SLOT_ID: &str = "faascale_mem_hotplug_slot";
+/// Virtio device ID of the hot-plug slot, which virtio reserves for placeholders.
+pub const TYPE_HOTPLUG_SLOT: u32 = 0;
+
+/// Placeholder virtio device holding the slot of the hot-pluggable faascale-mem device.
+#[derive(Debug)]
+pub struct HotplugSlot {
+    // The IRQ of the slot is registered before the faascale-mem device is plugged.
+    interrupt_evt: EventFd,
+    interrupt_status: Arc<AtomicUsize>,
+    // The host support for the device, probed when reserving the slot since the probes are not
+    // allowed by the seccomp filters the device is hot-plugged under.
+    capabilities: FaascaleMemCapabilities,
+}
+
+impl HotplugSlot {
+    /// Creates the placeholder of an empty slot, for a host with the given `capabilities`.
+    pub fn new(capabilities: FaascaleMemCapabilities) -> Result<Self, FaascaleMemError> {
+        Ok(HotplugSlot {
+            interrupt_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(FaascaleMemError::EventFd)?,
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            capabilities,
+        })
+    }
+
+    /// Returns the host support for the device plugged in the slot.
+    pub fn capabilities(&self) -> FaascaleMemCapabilities {
+        self.capabilities
+    }
+}
+
+impl VirtioDevice for HotplugSlot {
+    fn avail_features(&self) -> u64 {
+        0
+    }
+
+    fn acked_features(&self) -> u64 {
+        0
+    }
+
+    fn set_acked_features(&mut self, _: u64) {}
+
+    fn device_type(&self) -> u32 {
+        TYPE_HOTPLUG_SLOT
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &[]
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut []
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &[]
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.interrupt_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.interrupt_status.clone()
+    }
+
+    fn read_config(&self, _: u64, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn write_config(&mut self, _: u64, _: &[u8]) {}
+
+    fn activate(&mut self, _: GuestMemoryMmap) -> ActivateResult {
+        // The guest does not drive a device of ID 0.
+        Err(ActivateError::BadActivate)
+    }
+
+    fn is_activated(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hotplug_slot() {
+        let capabilities = FaascaleMemCapabilities {
+            madv_populate_write: true,
+            ..Default::default()
+        };
+        let mut slot = HotplugSlot::new(capabilities).unwrap();
+        assert_eq!(slot.device_type(), TYPE_HOTPLUG_SLOT);
+        assert_eq!(slot.capabilities(), capabilities);
+        assert_eq!(slot.avail_features(), 0);
+        assert!(slot.queues().is_empty());
+        assert!(slot.queue_events().is_empty());
+
+        let mut data = [0xffu8; 8];
+        slot.read_config(0, &mut data);
+        assert_eq!(data, [0u8; 8]);
+
+        let mem = utils::vm_memory::test_utils::create_anon_guest_memory(
+            &[(utils::vm_memory::GuestAddress(0), 0x1000)],
+            false,
+        )
+        .unwrap();
+        assert!(slot.activate(mem).is_err());
+        assert!(!slot.is_activated());
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! NUMA placement of the populated blocks.
+//!
+//! The host memory of a block pre-allocated with `MADV_POPULATE_WRITE` comes from the node the
+//! VMM thread runs on, which very large function heaps can exhaust. Blocks of at least
+//! `min_block_mib` are instead pre-allocated under an `MPOL_INTERLEAVE` policy spread over the
+//! configured nodes. The default policy is restored once the block is pre-allocated, so the
+//! pages faulted in later by the guest are placed as usual.
+//!
+//! Alternatively, the populated blocks are given an `MPOL_PREFERRED` policy for the node the
+//! vCPUs are pinned to, which the pre-allocation and the later guest faults both honour. The
+//! policy is kept on the blocks, and restored on the large blocks once interleaved.
+
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use super::util::PfnRanges;
+use super::{Error, MIB_TO_4K_PAGES};
+
+// Memory policies of `mbind`, from `linux/mempolicy.h`.
+const MPOL_DEFAULT: libc::c_int = 0;
+const MPOL_PREFERRED: libc::c_int = 1;
+const MPOL_INTERLEAVE: libc::c_int = 3;
+
+/// Interleaving of the large populated blocks across NUMA nodes of the host.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemInterleaveConfig {
+    /// Size from which the populated blocks are interleaved, in MiB.
+    pub min_block_mib: u32,
+    /// Host NUMA nodes to interleave the blocks across, below 64.
+    pub nodes: Vec<u32>,
+}
+
+/// Interleaving policy of the device, and the blocks it applied to.
+#[derive(Debug)]
+pub(crate) struct BlockInterleave {
+    config: FaascaleMemInterleaveConfig,
+    min_pages: u64,
+    node_mask: u64,
+    // Blocks populated interleaved, some of which may have been depopulated since.
+    interleaved_ranges: PfnRanges,
+}
+
+impl BlockInterleave {
+    pub fn new(config: FaascaleMemInterleaveConfig) -> Result<Self, Error> {
+        if config.nodes.is_empty() {
+            return Err(Error::InvalidInterleaveNodes);
+        }
+        let mut node_mask = 0u64;
+        for &node in &config.nodes {
+            node_mask |= 1u64
+                .checked_shl(node)
+                .ok_or(Error::InvalidInterleaveNodes)?;
+        }
+        Ok(BlockInterleave {
+            min_pages: u64::from(config.min_block_mib) * u64::from(MIB_TO_4K_PAGES),
+            config,
+            node_mask,
+            interleaved_ranges: PfnRanges::default(),
+        })
+    }
+
+    pub fn config(&self) -> &FaascaleMemInterleaveConfig {
+        &self.config
+    }
+
+    /// Nodes to interleave the `(start pfn, number of pages)` block across, if it is large
+    /// enough.
+    pub fn node_mask(&self, block: (u64, u64)) -> Option<u64> {
+        (block.1 >= self.min_pages).then_some(self.node_mask)
+    }
+
+    /// Records the population of the block, interleaved or not.
+    pub fn populated(&mut self, block: (u64, u64), interleaved: bool) {
+        if interleaved {
+            self.interleaved_ranges.insert(block);
+        } else {
+            self.interleaved_ranges.remove(block);
+        }
+    }
+
+    /// Number of pages populated interleaved and not depopulated since.
+    pub fn interleaved_pages(&self, populated_ranges: &PfnRanges) -> u64 {
+        self.interleaved_ranges
+            .ranges()
+            .map(|(start, end)| populated_ranges.overlap_pages((start, end - start)))
+            .sum()
+    }
+}
+
+// Sets the memory policy of the host range, with the nodes of `node_mask`. The mask is an
+// `unsigned long` of the 64-bit hosts supported.
+fn mbind(host_addr: *mut u8, len: usize, mode: libc::c_int, node_mask: u64) -> io::Result<()> {
+    // SAFETY: The node mask outlives the call, and the kernel reads at most `maxnode` bits of it.
+    // Changing the policy of the range does not touch its contents.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            host_addr,
+            len,
+            mode,
+            &node_mask as *const u64,
+            // The kernel ignores the last bit of `maxnode`.
+            u64::from(u64::BITS + 1),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Interleaves the pages allocated next in the host range across the nodes of `node_mask`.
+pub(crate) fn interleave_range(host_addr: *mut u8, len: usize, node_mask: u64) -> io::Result<()> {
+    mbind(host_addr, len, MPOL_INTERLEAVE, node_mask)
+}
+
+/// Checks that the NUMA node to place the populated blocks on fits in the node mask.
+pub(crate) fn check_numa_node(node: u32) -> Result<(), Error> {
+    if node >= u64::BITS {
+        return Err(Error::InvalidNumaNode);
+    }
+    Ok(())
+}
+
+/// Places the pages allocated next in the host range on `node`, falling back to the other nodes
+/// once it runs out of memory.
+pub(crate) fn prefer_node(host_addr: *mut u8, len: usize, node: u32) -> io::Result<()> {
+    mbind(host_addr, len, MPOL_PREFERRED, 1u64 << node)
+}
+
+/// Restores the default memory policy of the host range. The pages already allocated stay on
+/// their nodes.
+pub(crate) fn reset_range_policy(host_addr: *mut u8, len: usize) -> io::Result<()> {
+    mbind(host_addr, len, MPOL_DEFAULT, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_interleave() {
+        assert!(matches!(
+            BlockInterleave::new(FaascaleMemInterleaveConfig {
+                min_block_mib: 64,
+                nodes: vec![],
+            }),
+            Err(Error::InvalidInterleaveNodes)
+        ));
+        assert!(matches!(
+            BlockInterleave::new(FaascaleMemInterleaveConfig {
+                min_block_mib: 64,
+                nodes: vec![0, 64],
+            }),
+            Err(Error::InvalidInterleaveNodes)
+        ));
+        assert!(matches!(check_numa_node(64), Err(Error::InvalidNumaNode)));
+        check_numa_node(63).unwrap();
+
+        let mut interleave = BlockInterleave::new(FaascaleMemInterleaveConfig {
+            min_block_mib: 1,
+            nodes: vec![0, 2],
+        })
+        .unwrap();
+        let pages = u64::from(MIB_TO_4K_PAGES);
+        assert_eq!(interleave.node_mask((0, pages - 1)), None);
+        assert_eq!(interleave.node_mask((0, pages)), Some(0b101));
+
+        let mut populated_ranges = PfnRanges::default();
+        populated_ranges.insert((0, 2 * pages));
+        interleave.populated((0, 2 * pages), true);
+        assert_eq!(interleave.interleaved_pages(&populated_ranges), 2 * pages);
+        // Depopulated pages no longer count, nor do those populated again without interleaving.
+        populated_ranges.remove((0, 0x10));
+        interleave.populated((pages, 0x10), false);
+        assert_eq!(
+            interleave.interleaved_pages(&populated_ranges),
+            2 * pages - 0x20
+        );
+    }
+
+    #[test]
+    fn test_interleave_range() {
+        let len = 0x10000;
+        // SAFETY: Anonymous mapping, unmapped below.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        // Node 0 exists on every host, NUMA or not.
+        interleave_range(addr.cast(), len, 1).unwrap();
+        reset_range_policy(addr.cast(), len).unwrap();
+        prefer_node(addr.cast(), len, 0).unwrap();
+        // SAFETY: The mapping is writable and `len` bytes long.
+        unsafe { libc::memset(addr, 1, len) };
+        reset_range_policy(addr.cast(), len).unwrap();
+        // SAFETY: The mapping was created above.
+        unsafe { libc::munmap(addr, len) };
+    }
+}
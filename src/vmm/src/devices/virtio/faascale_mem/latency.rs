@@ -0,0 +1,147 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Latency histograms of the populate requests.
+//!
+//! Each block populated on the host side is counted in a histogram of the whole population,
+//! and in the histograms of the pre-allocation, scrubbing and pre-fault phases when they run, so
+//! that the cost of the population policies shows up in the statistics of the device.
+
+use std::cmp;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Upper bounds of the buckets of the latency histograms, in microseconds. A last bucket counts
+/// the longer durations.
+pub const LATENCY_BUCKET_BOUNDS_US: [u64; 8] =
+    [10, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000];
+
+/// Histogram of durations.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LatencyHistogram {
+    /// Number of durations recorded.
+    pub count: u64,
+    /// Sum of the durations, in microseconds.
+    pub sum_us: u64,
+    /// Longest duration, in microseconds.
+    pub max_us: u64,
+    /// Number of durations up to each bound of `LATENCY_BUCKET_BOUNDS_US` and above the last
+    /// one, buckets are not cumulative.
+    pub buckets: Vec<u64>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            count: 0,
+            sum_us: 0,
+            max_us: 0,
+            buckets: vec![0; LATENCY_BUCKET_BOUNDS_US.len() + 1],
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, duration: Duration) {
+        let us = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKET_BOUNDS_US
+            .iter()
+            .position(|&bound| us <= bound)
+            .unwrap_or(LATENCY_BUCKET_BOUNDS_US.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+        self.max_us = cmp::max(self.max_us, us);
+    }
+}
+
+/// Time spent in the phases of the population of a block, summed over the memory slots it
+/// spans.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PopulateTimings {
+    pub pre_alloc_mem: Option<Duration>,
+    pub scrub: Option<Duration>,
+    // Pages pre-allocated interleaved across NUMA nodes.
+    pub interleaved_pages: u64,
+}
+
+impl PopulateTimings {
+    pub fn add(&mut self, other: PopulateTimings) {
+        fn sum(total: &mut Option<Duration>, other: Option<Duration>) {
+            if let Some(other) = other {
+                *total = Some(total.unwrap_or_default() + other);
+            }
+        }
+        sum(&mut self.pre_alloc_mem, other.pre_alloc_mem);
+        sum(&mut self.scrub, other.scrub);
+        self.interleaved_pages += other.interleaved_pages;
+    }
+}
+
+/// Latency histograms of the blocks populated on the host side.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemPopulateLatency {
+    /// Whole population of the blocks.
+    pub total: LatencyHistogram,
+    /// Pre-allocation of the host memory, for the blocks populated with `pre_alloc_mem`.
+    pub pre_alloc_mem: LatencyHistogram,
+    /// Zeroing of the blocks, for the blocks populated with `scrub_on_populate`.
+    pub scrub: LatencyHistogram,
+    /// Pre-handling of the TDP faults, for the blocks populated with `pre_tdp_fault`. The
+    /// contiguous blocks populated together are pre-handled at once, and recorded once.
+    pub pre_tdp_fault: LatencyHistogram,
+}
+
+impl FaascaleMemPopulateLatency {
+    /// Records a block populated in `total`, with the time spent in its phases.
+    pub(crate) fn record(&mut self, total: Duration, timings: PopulateTimings) {
+        self.total.record(total);
+        if let Some(duration) = timings.pre_alloc_mem {
+            self.pre_alloc_mem.record(duration);
+        }
+        if let Some(duration) = timings.scrub {
+            self.scrub.record(duration);
+        }
+    }
+
+    /// Records the pre-handling of the TDP faults of a run of contiguous blocks.
+    pub(crate) fn record_pre_tdp_fault(&mut self, duration: Duration) {
+        self.pre_tdp_fault.record(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_populate_latency() {
+        let mut timings = PopulateTimings {
+            pre_alloc_mem: Some(Duration::from_micros(300)),
+            ..Default::default()
+        };
+        // The phases of the pieces in other memory slots add up.
+        timings.add(PopulateTimings {
+            pre_alloc_mem: Some(Duration::from_micros(300)),
+            ..Default::default()
+        });
+        assert_eq!(timings.pre_alloc_mem, Some(Duration::from_micros(600)));
+
+        let mut latency = FaascaleMemPopulateLatency::default();
+        latency.record(Duration::from_micros(700), timings);
+        latency.record(Duration::from_micros(10), PopulateTimings::default());
+        latency.record(Duration::from_secs(1), PopulateTimings::default());
+        assert_eq!(latency.total.count, 3);
+        assert_eq!(latency.total.sum_us, 1_000_710);
+        assert_eq!(latency.total.max_us, 1_000_000);
+        assert_eq!(latency.total.buckets, [1, 0, 0, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(latency.pre_alloc_mem.count, 1);
+        assert_eq!(latency.pre_alloc_mem.buckets, [0, 0, 0, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(latency.scrub, LatencyHistogram::default());
+        assert_eq!(latency.pre_tdp_fault, LatencyHistogram::default());
+        latency.record_pre_tdp_fault(Duration::from_micros(700));
+        assert_eq!(latency.pre_tdp_fault.count, 1);
+        assert_eq!(latency.total.count, 3);
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Lazy population of the blocks through userfaultfd.
+//!
+//! In the lazy populate mode, a populate request only registers the blocks with a userfaultfd
+//! owned by the device, and is acknowledged right away. Nothing is allocated until the guest
+//! first touches a page of the blocks: the fault is handed to a thread of the VMM, which maps a
+//! zero-filled page at the faulting address and wakes the faulting thread up. Depopulated blocks
+//! are unregistered once released. The fault handler never takes the device lock, so that the
+//! VMM thread touching the blocks is served as well.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+
+use logger::{error, IncMetric, METRICS};
+use seccompiler::BpfProgram;
+use serde::{Deserialize, Serialize};
+use userfaultfd::{Event, Uffd, UffdBuilder};
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::vm_memory::{
+    hugetlb_page_size, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+use super::device::FaascaleMem;
+use super::util::split_at_memslots;
+use super::{Error as FaascaleMemError, RemoveRegionError};
+
+// Longest the fault handler sleeps before checking whether the device is still alive.
+const FAULT_HANDLER_IDLE_TIMEOUT_MS: i32 = 100;
+
+/// How the blocks populated by the guest are backed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FaascaleMemPopulateMode {
+    /// Back the blocks before acknowledging the request, as the population policy configures.
+    #[default]
+    Eager,
+    /// Acknowledge the request right away, and back the pages when the guest first touches
+    /// them, through userfaultfd.
+    Lazy,
+}
+
+/// Userfaultfd the blocks populated lazily are registered with.
+#[derive(Debug)]
+pub(crate) struct LazyPopulate {
+    uffd: Arc<Uffd>,
+}
+
+impl LazyPopulate {
+    /// Creates the userfaultfd, which has to be done before the seccomp filters are loaded.
+    pub fn new() -> Result<Self, FaascaleMemError> {
+        // The vCPUs fault on the blocks from the kernel, through KVM, which takes a userfaultfd
+        // not limited to the user mode faults.
+        let uffd = UffdBuilder::new()
+            .close_on_exec(true)
+            .non_blocking(true)
+            .user_mode_only(false)
+            .create()
+            .map_err(FaascaleMemError::Userfaultfd)?;
+        Ok(LazyPopulate {
+            uffd: Arc::new(uffd),
+        })
+    }
+
+    /// Whether `range` can be populated lazily. The hugetlb backed regions are not, as zero
+    /// pages cannot be mapped into them.
+    pub fn covers(&self, guest_memory: &GuestMemoryMmap, range: (GuestAddress, u64)) -> bool {
+        guest_memory
+            .find_region(range.0)
+            .map_or(false, |region| hugetlb_page_size(region.flags()).is_none())
+    }
+
+    /// Registers `range` for its pages to be backed on their first touch, one KVM memory slot
+    /// at a time.
+    pub fn register(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        range: (GuestAddress, u64),
+    ) -> Result<(), RemoveRegionError> {
+        for (_, (guest_address, len)) in split_at_memslots(guest_memory, range)? {
+            let host_address = guest_memory
+                .get_host_address(guest_address)
+                .map_err(|_| RemoveRegionError::AddressTranslation)?;
+            self.uffd
+                .register(host_address.cast(), len as usize)
+                .map_err(RemoveRegionError::Userfaultfd)?;
+            METRICS.faascale_mem.lazy_registered_bytes.add(len as usize);
+        }
+        Ok(())
+    }
+
+    /// Unregisters `range`, released already, so that its pages are no longer handed to the
+    /// fault handler.
+    pub fn unregister(&self, guest_memory: &GuestMemoryMmap, range: (GuestAddress, u64)) {
+        let result = split_at_memslots(guest_memory, range).and_then(|pieces| {
+            pieces
+                .into_iter()
+                .try_for_each(|(_, (guest_address, len))| {
+                    let host_address = guest_memory
+                        .get_host_address(guest_address)
+                        .map_err(|_| RemoveRegionError::AddressTranslation)?;
+                    self.uffd
+                        .unregister(host_address.cast(), len as usize)
+                        .map_err(RemoveRegionError::Userfaultfd)
+                })
+        });
+        if let Err(err) = result {
+            METRICS.faascale_mem.lazy_populate_fails.inc();
+            error!("Error unregistering the lazily populated range: {:?}", err);
+        }
+    }
+}
+
+/// Starts the thread backing the pages of the blocks populated lazily by `faascale_mem`,
+/// running under `seccomp_filter`. The thread exits once the device is dropped, which joins it.
+pub(crate) fn spawn_fault_handler(
+    faascale_mem: &Arc<Mutex<FaascaleMem>>,
+    seccomp_filter: Arc<BpfProgram>,
+) -> Result<(), FaascaleMemError> {
+    let (uffd, stop_evt) = {
+        let faascale_mem = faascale_mem.lock().expect("Poisoned lock");
+        let uffd = match faascale_mem.lazy_populate.as_ref() {
+            Some(lazy_populate) => lazy_populate.uffd.clone(),
+            None => return Ok(()),
+        };
+        let stop_evt = faascale_mem
+            .worker_stop_evt
+            .try_clone()
+            .map_err(FaascaleMemError::EventFd)?;
+        (uffd, stop_evt)
+    };
+    // SAFETY: The call has no side effect.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let epoll = Epoll::new().map_err(FaascaleMemError::FaultHandler)?;
+    epoll
+        .ctl(
+            ControlOperation::Add,
+            uffd.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, 0),
+        )
+        .map_err(FaascaleMemError::FaultHandler)?;
+    epoll
+        .ctl(
+            ControlOperation::Add,
+            stop_evt.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, 1),
+        )
+        .map_err(FaascaleMemError::FaultHandler)?;
+
+    let device = Arc::downgrade(faascale_mem);
+    let handle = thread::Builder::new()
+        .name("fc_faascale_uffd".to_string())
+        .spawn(move || {
+            // Keeps the stop event registered for as long as the thread runs.
+            let _stop_evt = stop_evt;
+            // Execution panics if filters cannot be loaded, use --no-seccomp if skipping
+            // filters altogether is the desired behaviour.
+            if let Err(err) = seccompiler::apply_filter(&seccomp_filter) {
+                panic!(
+                    "Failed to set the requested seccomp filters on the fault handler: {}",
+                    err
+                );
+            }
+            run_fault_handler(&device, &epoll, &uffd, page_size)
+        })
+        .map_err(FaascaleMemError::FaultHandler)?;
+    faascale_mem
+        .lock()
+        .expect("Poisoned lock")
+        .worker_threads
+        .push(("fault_handler", handle));
+    Ok(())
+}
+
+fn run_fault_handler(
+    device: &Weak<Mutex<FaascaleMem>>,
+    epoll: &Epoll,
+    uffd: &Uffd,
+    page_size: usize,
+) {
+    let mut events = vec![EpollEvent::default(); 2];
+    loop {
+        match epoll.wait(FAULT_HANDLER_IDLE_TIMEOUT_MS, &mut events) {
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                error!(
+                    "Failed to wait for the lazily populated page faults: {}",
+                    err
+                );
+                return;
+            }
+        }
+        if device.strong_count() == 0 {
+            return;
+        }
+
+        loop {
+            match uffd.read_event() {
+                Ok(Some(Event::Pagefault { addr, .. })) => {
+                    serve_page_fault(uffd, addr as usize & !(page_size - 1), page_size)
+                }
+                // The other events are not requested.
+                Ok(Some(_)) => (),
+                Ok(None) => break,
+                Err(err) => {
+                    METRICS.faascale_mem.lazy_populate_fails.inc();
+                    error!("Failed to read the lazily populated page faults: {:?}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Maps a zero-filled page at `addr` and wakes the faulting thread up.
+fn serve_page_fault(uffd: &Uffd, addr: usize, page_size: usize) {
+    // SAFETY: The page lies in a range registered by the device, which is missing its page.
+    match unsafe { uffd.zeropage(addr as *mut libc::c_void, page_size, true) } {
+        Ok(_) => METRICS.faascale_mem.lazy_faults.inc(),
+        Err(err) => {
+            // The page may have been backed by a concurrent fault, or released by the guest,
+            // in which case the faulting thread only has to retry.
+            if let Err(err) = uffd.wake(addr as *mut libc::c_void, page_size) {
+                error!(
+                    "Failed to wake the lazily populated page fault up: {:?}",
+                    err
+                );
+            }
+            METRICS.faascale_mem.lazy_populate_fails.inc();
+            error!("Failed to back the lazily populated page: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::vm_memory::test_utils::create_anon_guest_memory;
+    use utils::vm_memory::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_lazy_populate() {
+        // Creating a userfaultfd handling the kernel faults may be refused to unprivileged users.
+        let lazy_populate = match LazyPopulate::new() {
+            Ok(lazy_populate) => lazy_populate,
+            Err(_) => return,
+        };
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x40_0000)], false).unwrap();
+        let range = (GuestAddress(0x10_0000), 0x10_0000);
+        assert!(lazy_populate.covers(&mem, range));
+        assert!(!lazy_populate.covers(&mem, (GuestAddress(0x40_0000), 0x1000)));
+        assert!(matches!(
+            lazy_populate.register(&mem, (GuestAddress(0x30_0000), 0x20_0000)),
+            Err(RemoveRegionError::OutsideMemslot(_))
+        ));
+        lazy_populate.register(&mem, range).unwrap();
+
+        // The first touch of a page is served by the handler thread with a zero-filled page.
+        let uffd = lazy_populate.uffd.clone();
+        // SAFETY: The call has no side effect.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let handler = std::thread::spawn(move || {
+            let epoll = Epoll::new().unwrap();
+            epoll
+                .ctl(
+                    ControlOperation::Add,
+                    uffd.as_raw_fd(),
+                    EpollEvent::new(EventSet::IN, 0),
+                )
+                .unwrap();
+            let mut events = vec![EpollEvent::default(); 1];
+            assert_eq!(epoll.wait(5000, &mut events).unwrap(), 1);
+            match uffd.read_event().unwrap() {
+                Some(Event::Pagefault { addr, .. }) => {
+                    serve_page_fault(&uffd, addr as usize & !(page_size - 1), page_size)
+                }
+                _ => panic!("Expected a page fault"),
+            }
+        });
+        let faults = METRICS.faascale_mem.lazy_faults.count();
+        assert_eq!(mem.read_obj::<u64>(GuestAddress(0x10_0008)).unwrap(), 0);
+        handler.join().unwrap();
+        assert_eq!(METRICS.faascale_mem.lazy_faults.count(), faults + 1);
+        mem.write_obj(0xdead_beef_u64, GuestAddress(0x10_0008))
+            .unwrap();
+
+        // Unregistered ranges fault as usual.
+        lazy_populate.unregister(&mem, range);
+        assert_eq!(mem.read_obj::<u64>(GuestAddress(0x18_0000)).unwrap(), 0);
+        assert_eq!(
+            mem.read_obj::<u64>(GuestAddress(0x10_0008)).unwrap(),
+            0xdead_beef
+        );
+    }
+}
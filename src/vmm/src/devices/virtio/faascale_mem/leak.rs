@@ -0,0 +1,67 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of the descriptors popped from a queue and never returned to the guest.
+//!
+//! A descriptor the device pops is owned by the device until it adds it to the used ring. One
+//! left behind, e.g. by an error path bailing out of a processing pass, is lost to the guest
+//! for good, and enough of them exhaust the queue. Every request is completed within the pass
+//! processing it, so the descriptors still in flight once a pass is over have leaked.
+
+use std::collections::BTreeMap;
+
+/// Descriptors popped from each queue and not returned to the guest yet.
+#[derive(Debug, Default)]
+pub(crate) struct DescriptorLeakTracker {
+    in_flight: BTreeMap<usize, Vec<u16>>,
+}
+
+impl DescriptorLeakTracker {
+    /// Records the descriptors popped from the `queue_index` queue.
+    pub fn popped(&mut self, queue_index: usize, indices: impl Iterator<Item = u16>) {
+        self.in_flight
+            .entry(queue_index)
+            .or_default()
+            .extend(indices);
+    }
+
+    /// Records a descriptor returned to the guest, or put back in the queue.
+    pub fn returned(&mut self, queue_index: usize, index: u16) {
+        if let Some(in_flight) = self.in_flight.get_mut(&queue_index) {
+            if let Some(position) = in_flight.iter().position(|&popped| popped == index) {
+                in_flight.swap_remove(position);
+            }
+        }
+    }
+
+    /// Takes the descriptors of the `queue_index` queue still in flight, which have leaked
+    /// once a processing pass is over.
+    pub fn take_leaks(&mut self, queue_index: usize) -> Vec<u16> {
+        self.in_flight.remove(&queue_index).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptor_leak_tracker() {
+        let mut tracker = DescriptorLeakTracker::default();
+        assert!(tracker.take_leaks(0).is_empty());
+
+        tracker.popped(0, [1, 2, 3].into_iter());
+        tracker.popped(1, [1].into_iter());
+        tracker.returned(0, 2);
+        // Descriptors not in flight are ignored.
+        tracker.returned(0, 7);
+        tracker.returned(2, 1);
+
+        let mut leaks = tracker.take_leaks(0);
+        leaks.sort_unstable();
+        assert_eq!(leaks, vec![1, 3]);
+        assert!(tracker.take_leaks(0).is_empty());
+        tracker.returned(1, 1);
+        assert!(tracker.take_leaks(1).is_empty());
+    }
+}
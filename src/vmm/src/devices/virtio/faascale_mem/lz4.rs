@@ -0,0 +1,210 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compression in the LZ4 block format.
+//!
+//! A block is a series of sequences, each made of a token, literals copied as they are and a
+//! match copied from the output already produced. The high nibble of the token holds the number
+//! of literals and the low nibble the length of the match minus 4, both continued by bytes of
+//! 255 and a final byte when they reach 15. The match is located by a 2-byte little endian offset
+//! back from the end of the output. The last sequence only holds literals, at least the last 5
+//! bytes of the input.
+//!
+//! The compressor greedily takes the first match found through a table of the positions of the
+//! 4-byte sequences met so far, which is fast and works well on memory pages.
+
+use std::cmp;
+
+// Shortest match, which the low nibble of the token counts from.
+const MIN_MATCH: usize = 4;
+// The last bytes of the input are always literals.
+const LAST_LITERALS: usize = 5;
+// No match starts within the last bytes of the input.
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65535;
+// Number of bits of the hash of a 4-byte sequence.
+const HASH_LOG: u32 = 12;
+// Value of a nibble continued by the following bytes.
+const NIBBLE_MAX: usize = 15;
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn write_length(output: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        output.push(255);
+        len -= 255;
+    }
+    output.push(len as u8);
+}
+
+// Writes the `literals` followed by the `(offset, length)` match, if any.
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    let token = (cmp::min(literals.len(), NIBBLE_MAX) << 4) | cmp::min(match_len, NIBBLE_MAX);
+    output.push(token as u8);
+    if literals.len() >= NIBBLE_MAX {
+        write_length(output, literals.len() - NIBBLE_MAX);
+    }
+    output.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= NIBBLE_MAX {
+            write_length(output, match_len - NIBBLE_MAX);
+        }
+    }
+}
+
+/// Compresses `input` into an LZ4 block.
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut table = vec![usize::MAX; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    if input.len() > MF_LIMIT {
+        let match_start_limit = input.len() - MF_LIMIT;
+        let match_end_limit = input.len() - LAST_LITERALS;
+        while pos < match_start_limit {
+            let sequence = read_u32(input, pos);
+            let slot = hash(sequence);
+            let candidate = table[slot];
+            table[slot] = pos;
+            if candidate == usize::MAX
+                || pos - candidate > MAX_OFFSET
+                || read_u32(input, candidate) != sequence
+            {
+                pos += 1;
+                continue;
+            }
+
+            let mut len = MIN_MATCH;
+            while pos + len < match_end_limit && input[candidate + len] == input[pos + len] {
+                len += 1;
+            }
+            write_sequence(
+                &mut output,
+                &input[anchor..pos],
+                Some((pos - candidate, len)),
+            );
+            pos += len;
+            anchor = pos;
+        }
+    }
+    write_sequence(&mut output, &input[anchor..], None);
+    output
+}
+
+// Reads a length whose token nibble is `nibble`, continued by the following bytes if needed.
+fn read_length(input: &[u8], pos: &mut usize, nibble: u8) -> Option<usize> {
+    let mut len = usize::from(nibble);
+    if len == NIBBLE_MAX {
+        loop {
+            let byte = *input.get(*pos)?;
+            *pos += 1;
+            len = len.checked_add(usize::from(byte))?;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+/// Decompresses the LZ4 block `input`, which must decompress to exactly `output_len` bytes.
+/// Returns `None` if the block is malformed.
+pub(crate) fn decompress(input: &[u8], output_len: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(output_len);
+    let mut pos = 0;
+
+    loop {
+        let token = *input.get(pos)?;
+        pos += 1;
+        let literals_len = read_length(input, &mut pos, token >> 4)?;
+        let literals = input.get(pos..pos.checked_add(literals_len)?)?;
+        if output.len() + literals_len > output_len {
+            return None;
+        }
+        output.extend_from_slice(literals);
+        pos += literals_len;
+        if pos == input.len() {
+            break;
+        }
+
+        let offset = usize::from(u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]));
+        pos += 2;
+        let match_len = read_length(input, &mut pos, token & 0xf)?.checked_add(MIN_MATCH)?;
+        if offset == 0 || offset > output.len() || output.len() + match_len > output_len {
+            return None;
+        }
+        let start = output.len() - offset;
+        if offset >= match_len {
+            output.extend_from_within(start..start + match_len);
+        } else {
+            // The match overlaps the bytes it produces.
+            for _ in 0..match_len {
+                let byte = output[output.len() - offset];
+                output.push(byte);
+            }
+        }
+    }
+
+    (output.len() == output_len).then_some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> usize {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        compressed.len()
+    }
+
+    #[test]
+    fn test_lz4_format() {
+        // A literal, a match overlapping its output, and the last literals.
+        let block = [0x1a, b'a', 0x01, 0x00, 0x50, b'a', b'a', b'a', b'a', b'a'];
+        assert_eq!(compress(&[b'a'; 20]), block);
+        assert_eq!(decompress(&block, 20).unwrap(), [b'a'; 20]);
+
+        // Malformed blocks and blocks of the wrong size are refused.
+        assert_eq!(decompress(&block, 19), None);
+        assert_eq!(decompress(&block, 21), None);
+        assert_eq!(decompress(&block[..3], 20), None);
+        assert_eq!(decompress(&[0x1a, b'a', 0x02, 0x00, 0x50], 20), None);
+        assert_eq!(decompress(&[0x1a, b'a', 0x00, 0x00, 0x50], 20), None);
+        assert_eq!(decompress(&[], 0), None);
+    }
+
+    #[test]
+    fn test_lz4_round_trip() {
+        assert_eq!(round_trip(&[]), 1);
+        round_trip(b"short");
+        // Long literal runs and matches spill over the token.
+        let pattern = (0..4096u32).flat_map(u32::to_le_bytes).collect::<Vec<_>>();
+        round_trip(&pattern);
+        assert!(round_trip(&vec![0u8; 1 << 20]) < 8192);
+        let mut page = vec![0u8; 4096];
+        page[100..106].copy_from_slice(b"KINGDO");
+        assert!(round_trip(&page.repeat(16)) < 4096);
+
+        // Incompressible data grows only slightly.
+        let mut state = 0x1234_5678u32;
+        let noise = (0..65536)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        assert!(round_trip(&noise) < 65536 + 512);
+    }
+}
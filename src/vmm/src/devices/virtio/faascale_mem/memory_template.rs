@@ -0,0 +1,176 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Template memory file the populated blocks are filled from.
+//!
+//! Language runtimes snapshotted once warm leave a memory file holding their heap, code caches
+//! and the like. With such a file as the template of the device, the blocks the guest populates
+//! get the contents of the template instead of zero-filled pages, so that a freshly booted
+//! runtime finds its warm state in place. The file is laid out as the snapshot memory files
+//! are, the guest memory regions one after the other, and is only ever read. The parts of the
+//! blocks past the end of the file are left zero-filled.
+//!
+//! The contents are copied rather than mapped: a private mapping of the file would bring them
+//! back whenever the guest depopulates the block, instead of zero-filled pages.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use logger::{error, IncMetric, METRICS};
+use utils::vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use super::VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+use crate::memory_snapshot::SnapshotMemory;
+
+// Size of the buffer the contents are copied through.
+const COPY_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Memory file of a template microVM, filling the blocks populated by the guest.
+#[derive(Debug)]
+pub(crate) struct MemoryTemplate {
+    path: PathBuf,
+    file: File,
+    // Length of the file, in whole pages.
+    len: u64,
+}
+
+impl MemoryTemplate {
+    /// Opens the template memory file at `path`.
+    pub fn new(path: PathBuf) -> io::Result<Self> {
+        let file = File::open(&path)?;
+        let page_mask = (1 << VIRTIO_FAASCALE_MEM_PFN_SHIFT) - 1;
+        let len = file.metadata()?.len() & !page_mask;
+        Ok(MemoryTemplate { path, file, len })
+    }
+
+    /// Path of the template memory file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Copies the contents of the template into the `(start pfn, number of pages)` block the
+    /// guest just populated. Returns the number of pages filled.
+    pub fn fill(&self, mem: &GuestMemoryMmap, block: (u64, u64)) -> u64 {
+        let start = block.0 << VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+        let end = start + (block.1 << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        let mut filled_pages = 0;
+        for region in mem.describe().regions {
+            // The part of the region held by the file.
+            let region_len = cmp::min(region.size as u64, self.len.saturating_sub(region.offset));
+            let piece_start = cmp::max(start, region.base_address);
+            let piece_end = cmp::min(end, region.base_address + region_len);
+            if piece_start >= piece_end {
+                continue;
+            }
+            let file_offset = region.offset + (piece_start - region.base_address);
+            match self.copy(mem, piece_start, piece_end - piece_start, file_offset) {
+                Ok(()) => {
+                    filled_pages += (piece_end - piece_start) >> VIRTIO_FAASCALE_MEM_PFN_SHIFT
+                }
+                Err(err) => {
+                    METRICS.faascale_mem.template_fails.inc();
+                    error!(
+                        "faascale-mem: error filling block from the template: start_pfn={}, \
+                         size={}: {:?}",
+                        block.0, block.1, err
+                    );
+                }
+            }
+        }
+        METRICS
+            .faascale_mem
+            .template_filled_pages
+            .add(filled_pages as usize);
+        filled_pages
+    }
+
+    // Copies `len` bytes of the file from `file_offset` to the guest physical address `addr`.
+    fn copy(&self, mem: &GuestMemoryMmap, addr: u64, len: u64, file_offset: u64) -> io::Result<()> {
+        let mut buf = vec![0u8; cmp::min(COPY_CHUNK_SIZE, len) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = cmp::min(COPY_CHUNK_SIZE, len - done) as usize;
+            self.file
+                .read_exact_at(&mut buf[..chunk], file_offset + done)?;
+            mem.write_slice(&buf[..chunk], GuestAddress(addr + done))
+                .map_err(guest_memory_error)?;
+            done += chunk as u64;
+        }
+        Ok(())
+    }
+}
+
+fn guest_memory_error(err: GuestMemoryError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::thread;
+
+    use utils::tempfile::TempFile;
+    use utils::vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+    use crate::seccomp_filters::{get_filters, SeccompConfig, FAASCALE_MEM_VMM_CATEGORY};
+
+    #[test]
+    fn test_memory_template() {
+        // Two regions, the second one laid out in the file right after the first.
+        let mem = create_anon_guest_memory(
+            &[
+                (GuestAddress(0), 0x10_0000),
+                (GuestAddress(0x100_0000), 0x10_0000),
+            ],
+            false,
+        )
+        .unwrap();
+        let page = |pfn: u64| pfn << VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+        let tmp = TempFile::new().unwrap();
+        let mut contents = vec![0u8; 0x10_0000 + 0x2_0000 + 0x800];
+        contents[page(0x10) as usize] = 0xaa;
+        contents[0x10_0000 + page(0x1) as usize] = 0xbb;
+        tmp.as_file().write_all(&contents).unwrap();
+        let template = MemoryTemplate::new(tmp.as_path().to_path_buf()).unwrap();
+        assert_eq!(template.path(), tmp.as_path());
+
+        assert_eq!(template.fill(&mem, (0x10, 0x8)), 0x8);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(page(0x10))).unwrap(), 0xaa);
+        // The blocks of the second region are read from its offset in the file, up to the
+        // last whole page of the file.
+        assert_eq!(template.fill(&mem, (0x1000, 0x40)), 0x20);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x100_0000 + page(0x1)))
+                .unwrap(),
+            0xbb
+        );
+        // Blocks past the end of the file are left alone.
+        assert_eq!(template.fill(&mem, (0x1030, 0x10)), 0);
+    }
+
+    #[test]
+    fn test_memory_template_seccomp() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x10_0000)], false).unwrap();
+        let tmp = TempFile::new().unwrap();
+        tmp.as_file().write_all(&[0xaa; 0x1_0000]).unwrap();
+        let template = MemoryTemplate::new(tmp.as_path().to_path_buf()).unwrap();
+        let filter = get_filters(SeccompConfig::Advanced)
+            .unwrap()
+            .remove(FAASCALE_MEM_VMM_CATEGORY)
+            .unwrap();
+
+        // The template is read from the VMM thread, under the filter of a microVM with a
+        // faascale-mem device.
+        thread::spawn(move || {
+            seccompiler::apply_filter(&filter).unwrap();
+            assert_eq!(template.fill(&mem, (0x0, 0x10)), 0x10);
+            assert_eq!(mem.read_obj::<u8>(GuestAddress(0xf000)).unwrap(), 0xaa);
+        })
+        .join()
+        .unwrap();
+    }
+}
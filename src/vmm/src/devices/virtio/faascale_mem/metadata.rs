@@ -0,0 +1,641 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Machine-readable descriptions of the statistics and configuration fields reported by the
+//! faascale-mem API, for dashboards to label them without hard-coding their meaning.
+//!
+//! The tables below are kept next to the structures they describe, and the tests fail as soon
+//! as a field is reported without a description, or a description outlives its field.
+
+use serde::Serialize;
+use FaascaleMemFieldCadence::*;
+use FaascaleMemFieldSource::*;
+
+/// Where the value of a field comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaascaleMemFieldSource {
+    /// Reported by the guest driver.
+    Guest,
+    /// Measured or decided by the host.
+    Host,
+    /// Set through the API.
+    Api,
+}
+
+/// When the value of a field changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaascaleMemFieldCadence {
+    /// On every statistics sample of the guest.
+    StatsPoll,
+    /// On every populate or depopulate request of the guest.
+    Populate,
+    /// Whenever the device is configured or updated through the API.
+    ConfigUpdate,
+    /// Once, then left alone.
+    Once,
+}
+
+/// Description of a field reported by the faascale-mem API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemFieldMetadata {
+    /// Name of the field in the API responses.
+    pub name: &'static str,
+    /// Meaning of the field.
+    pub description: &'static str,
+    /// Unit of the field, if it is a quantity.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<&'static str>,
+    /// Where the value of the field comes from.
+    pub source: FaascaleMemFieldSource,
+    /// When the value of the field changes.
+    pub cadence: FaascaleMemFieldCadence,
+}
+
+/// Descriptions of the fields of `GET /faascale_mem/statistics` and `GET /faascale_mem`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemMetadata {
+    /// Fields of the statistics.
+    pub statistics: &'static [FaascaleMemFieldMetadata],
+    /// Fields of the device configuration.
+    pub config: &'static [FaascaleMemFieldMetadata],
+}
+
+impl Default for FaascaleMemMetadata {
+    fn default() -> Self {
+        FaascaleMemMetadata {
+            statistics: STATISTICS,
+            config: CONFIG,
+        }
+    }
+}
+
+const fn field(
+    name: &'static str,
+    description: &'static str,
+    unit: Option<&'static str>,
+    source: FaascaleMemFieldSource,
+    cadence: FaascaleMemFieldCadence,
+) -> FaascaleMemFieldMetadata {
+    FaascaleMemFieldMetadata {
+        name,
+        description,
+        unit,
+        source,
+        cadence,
+    }
+}
+
+const STATISTICS: &[FaascaleMemFieldMetadata] = &[
+    field(
+        "swap_in",
+        "Memory swapped in by the guest.",
+        Some("bytes"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "swap_out",
+        "Memory swapped out by the guest.",
+        Some("bytes"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "major_faults",
+        "Major page faults of the guest.",
+        Some("count"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "minor_faults",
+        "Minor page faults of the guest.",
+        Some("count"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "free_memory",
+        "Memory left unused by the guest.",
+        Some("bytes"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "total_memory",
+        "Memory available to the guest.",
+        Some("bytes"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "available_memory",
+        "Memory the guest could allocate without swapping.",
+        Some("bytes"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "disk_caches",
+        "Memory caching guest files.",
+        Some("bytes"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "hugetlb_allocations",
+        "Huge pages allocated by the guest.",
+        Some("count"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "hugetlb_failures",
+        "Huge page allocations failed in the guest.",
+        Some("count"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "memory_pressure_some_avg10",
+        "Share of the last 10 seconds some task of the guest stalled on memory, in hundredths \
+         of a percent.",
+        Some("basis_points"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "memory_pressure_some_avg60",
+        "Share of the last 60 seconds some task of the guest stalled on memory, in hundredths \
+         of a percent.",
+        Some("basis_points"),
+        Guest,
+        StatsPoll,
+    ),
+    field(
+        "sample_interval_ms",
+        "Time between the last two statistics samples.",
+        Some("ms"),
+        Host,
+        StatsPoll,
+    ),
+    field(
+        "swap_in_delta",
+        "Growth of `swap_in` between the last two samples, and its rate per second.",
+        Some("bytes"),
+        Host,
+        StatsPoll,
+    ),
+    field(
+        "swap_out_delta",
+        "Growth of `swap_out` between the last two samples, and its rate per second.",
+        Some("bytes"),
+        Host,
+        StatsPoll,
+    ),
+    field(
+        "major_faults_delta",
+        "Growth of `major_faults` between the last two samples, and its rate per second.",
+        Some("count"),
+        Host,
+        StatsPoll,
+    ),
+    field(
+        "minor_faults_delta",
+        "Growth of `minor_faults` between the last two samples, and its rate per second.",
+        Some("count"),
+        Host,
+        StatsPoll,
+    ),
+    field(
+        "advised_4k_blocks",
+        "Blocks advised the 4 KiB granularity the guest asked for.",
+        Some("count"),
+        Host,
+        Populate,
+    ),
+    field(
+        "advised_2m_blocks",
+        "Blocks advised the 2 MiB granularity the guest asked for.",
+        Some("count"),
+        Host,
+        Populate,
+    ),
+    field(
+        "granularity_fallbacks",
+        "Blocks populated without advising the granularity the guest asked for, 1 GiB included.",
+        Some("count"),
+        Host,
+        Populate,
+    ),
+    field(
+        "populate_latency",
+        "Latency histograms of the host side population of the blocks, per phase.",
+        Some("us"),
+        Host,
+        Populate,
+    ),
+    field(
+        "populate_verified_blocks",
+        "Populated blocks holding the verification canary once written.",
+        Some("count"),
+        Host,
+        Populate,
+    ),
+    field(
+        "populate_verification_failures",
+        "Populated blocks not holding the verification canary once written.",
+        Some("count"),
+        Host,
+        Populate,
+    ),
+    field(
+        "experiment",
+        "Blocks, failures, host page faults and latency histogram of each variant of the \
+         population policy experiment.",
+        None,
+        Host,
+        Populate,
+    ),
+    field(
+        "freshness",
+        "Whether the guest reported the statistics since the microVM was restored: `live`, \
+         `restored_stale`, or `simulated` from the populated memory.",
+        None,
+        Host,
+        StatsPoll,
+    ),
+];
+
+const CONFIG: &[FaascaleMemFieldMetadata] = &[
+    field(
+        "stats_polling_interval_s",
+        "Interval between the statistics samples, 0 when disabled.",
+        Some("s"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "pre_alloc_mem",
+        "Whether the host memory of the populated blocks is pre-allocated.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "pre_tdp_fault",
+        "Whether the TDP faults of the populated blocks are pre-handled.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "thp_policy",
+        "Transparent huge page advice given to the populated blocks.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "populate_tracker_max_entries",
+        "Most ranges remembered to deduplicate the populate requests.",
+        Some("count"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "latency_mode",
+        "Whether the populate queue is handled on a dedicated thread.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "perf_sampling",
+        "Whether the dTLB misses and TDP mappings of the populated blocks are logged.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "budget_mib",
+        "Memory budget offered to the guest.",
+        Some("MiB"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "experiment",
+        "Split of the populate blocks between two population policies.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "pool",
+        "Host memory reserved to back the populated blocks first.",
+        Some("MiB"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "strict_stats",
+        "Whether statistics holding unknown tags are rejected.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "stats_polling_min_interval_ms",
+        "Shortest interval the statistics are polled at under memory pressure.",
+        Some("ms"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "max_populated_mib",
+        "Most memory the guest may have populated at once.",
+        Some("MiB"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "block_cache_mib",
+        "Size of the cache of the compressed depopulated blocks.",
+        Some("MiB"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "interleave",
+        "Interleaving of the large pre-allocated blocks across host NUMA nodes.",
+        Some("MiB"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "numa_node",
+        "Host NUMA node the populated blocks are placed on.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "depopulate_mode",
+        "Advice releasing the host memory of the depopulated blocks.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "mlock_budget_mib",
+        "Most memory that may be locked into host memory at once.",
+        Some("MiB"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "rate_limiter",
+        "Limits on the blocks and bytes of the populate and depopulate requests.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "mmds_publish",
+        "Whether the memory state is published into the MMDS data store.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "scrub_on_populate",
+        "Whether the populated blocks are zeroed explicitly.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "spill_path",
+        "File the depopulated blocks are written to.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "complete_leaked_descriptors",
+        "Whether the requests the device failed to complete are returned to the guest.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "interrupt_moderation",
+        "Whether the depopulate notifications are held back while the guest streams requests.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "policy",
+        "Program deciding the populated memory cap, the release and the budget.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "populate_verification",
+        "Whether a canary is written to the populated blocks and read back.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "ksm_mergeable",
+        "Whether the populated blocks are advised mergeable for KSM.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "ksm_idle",
+        "Whether the guest memory but for the populated blocks is advised mergeable for KSM.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "worker_scheduling",
+        "Nice value, I/O priority and cgroup requested for the populate poller.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "vsock_observer_port",
+        "Host vsock port answering the guest with the memory activity of the microVM.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "template_path",
+        "Memory file of a template microVM the populated blocks are filled from.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "populate_mode",
+        "Whether the populated blocks are backed right away or on their first touch.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "depopulate_on_reset",
+        "Whether the memory populated by a guest driver is depopulated when it resets the device.",
+        None,
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "config_epoch",
+        "Number of successful updates that changed the configuration.",
+        Some("count"),
+        Host,
+        ConfigUpdate,
+    ),
+    field(
+        "populated_mib",
+        "Memory populated by the guest.",
+        Some("MiB"),
+        Guest,
+        Populate,
+    ),
+    field(
+        "target_mib",
+        "Memory the guest driver is asked to keep populated.",
+        Some("MiB"),
+        Api,
+        ConfigUpdate,
+    ),
+    field(
+        "boot_warmup",
+        "Populate activity of the guest from boot until it first quiesced.",
+        None,
+        Guest,
+        Once,
+    ),
+    field(
+        "stats_polling_adaptation",
+        "Statistics polling interval in use under memory pressure.",
+        Some("ms"),
+        Host,
+        StatsPoll,
+    ),
+    field(
+        "capabilities",
+        "Support of the host for the pre-allocation and TDP pre-fault system calls.",
+        None,
+        Host,
+        Once,
+    ),
+    field(
+        "policy_decision",
+        "Decisions of the latest successful run of the policy program.",
+        None,
+        Host,
+        StatsPoll,
+    ),
+    field(
+        "applied_worker_scheduling",
+        "Scheduling of the populate poller, as applied by the host.",
+        None,
+        Host,
+        Once,
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::devices::virtio::faascale_mem::experiment::FaascaleMemExperimentResults;
+    use crate::devices::virtio::faascale_mem::latency::FaascaleMemPopulateLatency;
+    use crate::devices::virtio::faascale_mem::FaascaleMemStats;
+    use crate::devices::virtio::stats_delta::CounterDelta;
+    use crate::vmm_config::faascale_mem::FaascaleMemDeviceConfig;
+
+    // Names of the fields of the serialized value, which must have none left out.
+    fn keys<T: Serialize>(value: &T) -> BTreeSet<String> {
+        match serde_json::to_value(value).unwrap() {
+            serde_json::Value::Object(map) => map.keys().cloned().collect(),
+            _ => panic!("Not a structure."),
+        }
+    }
+
+    fn names(fields: &[FaascaleMemFieldMetadata]) -> BTreeSet<String> {
+        fields.iter().map(|field| field.name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_statistics_metadata() {
+        let stats = FaascaleMemStats {
+            swap_in: Some(1),
+            swap_out: Some(1),
+            major_faults: Some(1),
+            minor_faults: Some(1),
+            free_memory: Some(1),
+            total_memory: Some(1),
+            available_memory: Some(1),
+            disk_caches: Some(1),
+            hugetlb_allocations: Some(1),
+            hugetlb_failures: Some(1),
+            memory_pressure_some_avg10: Some(1),
+            memory_pressure_some_avg60: Some(1),
+            sample_interval_ms: Some(1),
+            swap_in_delta: Some(CounterDelta::default()),
+            swap_out_delta: Some(CounterDelta::default()),
+            major_faults_delta: Some(CounterDelta::default()),
+            minor_faults_delta: Some(CounterDelta::default()),
+            advised_4k_blocks: Some(1),
+            advised_2m_blocks: Some(1),
+            granularity_fallbacks: Some(1),
+            populate_latency: Some(FaascaleMemPopulateLatency::default()),
+            populate_verified_blocks: Some(1),
+            populate_verification_failures: Some(1),
+            experiment: Some(FaascaleMemExperimentResults::default()),
+            freshness: Default::default(),
+        };
+        assert_eq!(keys(&stats), names(STATISTICS));
+        assert_eq!(names(STATISTICS).len(), STATISTICS.len());
+    }
+
+    #[test]
+    fn test_config_metadata() {
+        // Every field is set, a field added without a description fails the test.
+        let config = FaascaleMemDeviceConfig::fully_populated();
+        assert_eq!(keys(&config), names(CONFIG));
+        assert_eq!(names(CONFIG).len(), CONFIG.len());
+    }
+
+    #[test]
+    fn test_serialize_metadata() {
+        let value = serde_json::to_value(FaascaleMemMetadata::default()).unwrap();
+        assert_eq!(
+            value["statistics"][0],
+            serde_json::json!({
+                "name": "swap_in",
+                "description": "Memory swapped in by the guest.",
+                "unit": "bytes",
+                "source": "guest",
+                "cadence": "stats_poll"
+            })
+        );
+        assert!(value["config"][1].get("unit").is_none());
+    }
+}
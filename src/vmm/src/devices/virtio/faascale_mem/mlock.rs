@@ -0,0 +1,174 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Locking of small critical populated blocks into host memory.
+//!
+//! The host may swap out the memory of an idle microVM, and the function state it holds then
+//! takes major faults to come back on the next invocation. The guest, by flagging its populate
+//! blocks, or the host, through the API, can lock the latency-critical blocks once populated so
+//! that they are never swapped out. The locked memory is bounded by a budget per microVM, and
+//! the blocks are unlocked when depopulated.
+
+use std::io;
+
+use logger::{error, IncMetric, StoreMetric, METRICS};
+use serde::Serialize;
+use utils::vm_memory::GuestMemoryMmap;
+
+use super::device::block_range;
+use super::util::{split_at_memslots, PfnRanges};
+use super::{Error, RemoveRegionError, MIB_TO_4K_PAGES};
+
+/// Host memory locked on behalf of the microVM, as reported by the API.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemMlockUsage {
+    /// Most memory that may be locked at once, in MiB.
+    pub budget_mib: u64,
+    /// Memory locked, in MiB.
+    pub locked_mib: u64,
+}
+
+/// Blocks locked into host memory, within the budget of the microVM.
+#[derive(Debug)]
+pub(crate) struct BlockMlock {
+    budget_mib: u32,
+    budget_pages: u64,
+    locked_ranges: PfnRanges,
+}
+
+impl BlockMlock {
+    pub fn new(budget_mib: u32) -> Self {
+        BlockMlock {
+            budget_mib,
+            budget_pages: u64::from(budget_mib) * u64::from(MIB_TO_4K_PAGES),
+            locked_ranges: PfnRanges::default(),
+        }
+    }
+
+    pub fn budget_mib(&self) -> u32 {
+        self.budget_mib
+    }
+
+    /// Number of guest pages locked.
+    pub fn locked_pages(&self) -> u64 {
+        self.locked_ranges.num_pages()
+    }
+
+    pub fn usage(&self) -> FaascaleMemMlockUsage {
+        FaascaleMemMlockUsage {
+            budget_mib: u64::from(self.budget_mib),
+            locked_mib: self.locked_pages() / u64::from(MIB_TO_4K_PAGES),
+        }
+    }
+
+    /// Locks the populated `(start pfn, number of pages)` block into host memory, unless it
+    /// would take the locked memory over the budget.
+    pub fn lock(&mut self, mem: &GuestMemoryMmap, block: (u64, u64)) -> Result<(), Error> {
+        let new_pages = block.1 - self.locked_ranges.overlap_pages(block);
+        if self.locked_pages() + new_pages > self.budget_pages {
+            METRICS.faascale_mem.mlock_budget_refusals.inc();
+            return Err(Error::MlockBudgetExceeded);
+        }
+        if let Err(err) = mlock_range(mem, block, true) {
+            METRICS.faascale_mem.mlock_fails.inc();
+            return Err(err);
+        }
+        self.locked_ranges.insert(block);
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// Unlocks the parts of the `(start pfn, number of pages)` block which are locked. A failed
+    /// unlock leaves the pages resident, the block is forgotten all the same.
+    pub fn unlock(&mut self, mem: &GuestMemoryMmap, block: (u64, u64)) {
+        if !self.locked_ranges.overlaps(block) {
+            return;
+        }
+        if let Err(err) = mlock_range(mem, block, false) {
+            METRICS.faascale_mem.mlock_fails.inc();
+            error!(
+                "Error unlocking block: start_pfn={}, size={}: {:?}",
+                block.0, block.1, err
+            );
+        }
+        self.locked_ranges.remove(block);
+        self.update_metrics();
+    }
+
+    fn update_metrics(&self) {
+        METRICS
+            .faascale_mem
+            .mlocked_pages
+            .store(self.locked_pages() as usize);
+    }
+}
+
+// Locks or unlocks the host memory of the `(start pfn, number of pages)` block, one KVM memory
+// slot at a time. Locking faults in the pages not resident yet.
+fn mlock_range(mem: &GuestMemoryMmap, block: (u64, u64), lock: bool) -> Result<(), Error> {
+    let pieces = split_at_memslots(mem, block_range(block)).map_err(Error::RemoveMemoryRegion)?;
+    for (_, (guest_address, range_len)) in pieces {
+        let host_addr = mem
+            .get_host_address(guest_address)
+            .map_err(|_| Error::RemoveMemoryRegion(RemoveRegionError::AddressTranslation))?;
+        // SAFETY: The piece lies within a memory slot of the guest, and locking only changes
+        // whether the host may swap its pages out.
+        let ret = unsafe {
+            if lock {
+                libc::mlock(host_addr.cast(), range_len as usize)
+            } else {
+                libc::munlock(host_addr.cast(), range_len as usize)
+            }
+        };
+        if ret < 0 {
+            return Err(Error::Mlock(io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::vm_memory::test_utils::create_anon_guest_memory;
+    use utils::vm_memory::GuestAddress;
+
+    use super::*;
+
+    #[test]
+    fn test_block_mlock() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x40_0000)], false).unwrap();
+        // Budget of a single MiB.
+        let mut mlock = BlockMlock::new(1);
+        let pages = u64::from(MIB_TO_4K_PAGES);
+
+        mlock.lock(&mem, (0, 0x10)).unwrap();
+        mlock.lock(&mem, (0x8, 0x10)).unwrap();
+        assert_eq!(mlock.locked_pages(), 0x18);
+        // Only the pages not locked yet count against the budget.
+        assert!(matches!(
+            mlock.lock(&mem, (0x100, pages)),
+            Err(Error::MlockBudgetExceeded)
+        ));
+        mlock.lock(&mem, (0, pages)).unwrap();
+        assert_eq!(
+            mlock.usage(),
+            FaascaleMemMlockUsage {
+                budget_mib: 1,
+                locked_mib: 1,
+            }
+        );
+
+        mlock.unlock(&mem, (0x10, pages - 0x10));
+        assert_eq!(mlock.locked_pages(), 0x10);
+        // Unlocking pages which are not locked is a no-op.
+        mlock.unlock(&mem, (0x200, 0x10));
+        assert_eq!(mlock.locked_pages(), 0x10);
+
+        assert!(matches!(
+            mlock.lock(&mem, (0x1000, 0x10)),
+            Err(Error::RemoveMemoryRegion(
+                RemoveRegionError::OutsideMemslot(_)
+            ))
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Publishing of the memory state of the microVM into the MMDS data store.
+//!
+//! Guest agents read their memory budget through the MMDS path they already use, without a
+//! feature of the guest driver. A summary of the memory state is published under the
+//! `MMDS_KEY` key of the data store whenever it changes, the rest of the data store is left
+//! alone. The figures the device does not know are published as `null`, which drops them from
+//! the data store.
+
+use std::sync::{Arc, Mutex};
+
+use logger::{error, IncMetric, METRICS};
+use mmds::data_store::{self, Mmds};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Key of the data store the summary is published under.
+pub const MMDS_KEY: &str = "faascale_mem";
+
+/// Memory state of the microVM, as published into the MMDS data store.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemMmdsSummary {
+    /// Memory populated by the guest, in MiB.
+    pub populated_mib: u64,
+    /// Memory the guest driver is asked to keep populated, in MiB.
+    pub target_mib: u32,
+    /// Cap on the memory populated by the guest, in MiB.
+    pub max_populated_mib: Option<u32>,
+    /// Memory budget agreed on with the guest, in MiB.
+    pub budget_mib: Option<u32>,
+    /// Share of the last 10 seconds some task of the guest stalled on memory, in hundredths of
+    /// a percent, as last reported by the guest.
+    pub pressure_some_avg10: Option<u64>,
+}
+
+/// Publishes the summaries which differ from the last one published.
+#[derive(Debug)]
+pub(crate) struct MmdsPublisher {
+    mmds: Arc<Mutex<Mmds>>,
+    published: Option<FaascaleMemMmdsSummary>,
+}
+
+impl MmdsPublisher {
+    pub fn new(mmds: Arc<Mutex<Mmds>>) -> Self {
+        MmdsPublisher {
+            mmds,
+            published: None,
+        }
+    }
+
+    /// Publishes `summary`, unless it was the last one published. A failed publication is
+    /// retried with the next summary.
+    pub fn publish(&mut self, summary: FaascaleMemMmdsSummary) {
+        if self.published.as_ref() == Some(&summary) {
+            return;
+        }
+        let value = match serde_json::to_value(&summary) {
+            Ok(value) => value,
+            Err(err) => {
+                error!("faascale-mem: error serializing the MMDS summary: {}", err);
+                return;
+            }
+        };
+        let mut data = Map::new();
+        data.insert(MMDS_KEY.to_string(), value);
+        let data = Value::Object(data);
+
+        let mut mmds = self.mmds.lock().expect("Poisoned lock");
+        // The data store is created by the first publication if nothing was put in it yet.
+        let result = match mmds.patch_data(data.clone()) {
+            Err(data_store::Error::NotInitialized) => mmds.put_data(data),
+            result => result,
+        };
+        match result {
+            Ok(()) => self.published = Some(summary),
+            Err(err) => {
+                METRICS.faascale_mem.mmds_publish_fails.inc();
+                error!("faascale-mem: error publishing into MMDS: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mmds::data_store::OutputFormat;
+
+    use super::*;
+
+    #[test]
+    fn test_mmds_publisher() {
+        let mmds = Arc::new(Mutex::new(Mmds::default()));
+        let mut publisher = MmdsPublisher::new(mmds.clone());
+        let summary = FaascaleMemMmdsSummary {
+            populated_mib: 256,
+            target_mib: 512,
+            budget_mib: Some(1024),
+            ..Default::default()
+        };
+
+        // The first publication creates the data store.
+        publisher.publish(summary.clone());
+        let path = format!("/{}/populated_mib", MMDS_KEY);
+        let populated_mib = mmds.lock().unwrap().get_value(path, OutputFormat::Json);
+        assert_eq!(populated_mib.unwrap(), "256");
+
+        // The other keys of the data store are left alone, and the unknown figures dropped.
+        mmds.lock()
+            .unwrap()
+            .patch_data(serde_json::json!({"latest": {"id": "i-1"}}))
+            .unwrap();
+        publisher.publish(FaascaleMemMmdsSummary {
+            populated_mib: 512,
+            budget_mib: None,
+            ..summary
+        });
+        let value = mmds.lock().unwrap().data_store_value();
+        assert_eq!(value["latest"]["id"], "i-1");
+        assert_eq!(value[MMDS_KEY]["populated_mib"], 512);
+        assert!(value[MMDS_KEY].get("budget_mib").is_none());
+    }
+}
@@ -1,23 +1,150 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+// Only the snapshot state is built without the device, which leaves the
+// feature bits and statistics tags unused.
+#![cfg_attr(not(feature = "faascale-mem"), allow(dead_code))]
+
+#[cfg(feature = "faascale-mem")]
+mod block_cache;
+#[cfg(feature = "faascale-mem")]
+pub mod budget;
+#[cfg(feature = "faascale-mem")]
+pub mod capabilities;
+#[cfg(feature = "faascale-mem")]
+mod config_ack;
+#[cfg(feature = "faascale-mem")]
+mod depopulate_batch;
+#[cfg(feature = "faascale-mem")]
 pub mod device;
+#[cfg(feature = "faascale-mem")]
+pub mod encryption;
+#[cfg(feature = "faascale-mem")]
+pub mod error_log;
+#[cfg(feature = "faascale-mem")]
+pub mod estimate;
+#[cfg(feature = "faascale-mem")]
 pub mod event_handler;
+#[cfg(feature = "faascale-mem")]
+pub mod experiment;
+#[cfg(feature = "faascale-mem")]
+pub mod hotplug;
+#[cfg(feature = "faascale-mem")]
+pub mod latency;
+#[cfg(feature = "faascale-mem")]
+pub mod heatmap;
+#[cfg(feature = "faascale-mem")]
+pub mod interleave;
+#[cfg(feature = "faascale-mem")]
+pub mod lazy;
+#[cfg(feature = "faascale-mem")]
+mod leak;
+#[cfg(feature = "faascale-mem")]
+mod lz4;
+#[cfg(feature = "faascale-mem")]
+mod memory_template;
+#[cfg(feature = "faascale-mem")]
+pub mod metadata;
+#[cfg(feature = "faascale-mem")]
+pub mod mlock;
+#[cfg(feature = "faascale-mem")]
+pub mod mmds_publish;
+#[cfg(feature = "faascale-mem")]
+pub mod observer;
+#[cfg(feature = "faascale-mem")]
+pub(crate) mod perf;
 pub mod persist;
+#[cfg(feature = "faascale-mem")]
+pub mod policy;
+#[cfg(feature = "faascale-mem")]
+pub(crate) mod poller;
+#[cfg(feature = "faascale-mem")]
+pub mod polling;
+#[cfg(feature = "faascale-mem")]
+pub mod pool;
+#[cfg(feature = "faascale-mem")]
+mod prefault_batch;
+#[cfg(feature = "faascale-mem")]
+pub mod scheduling;
+#[cfg(feature = "faascale-mem")]
+mod spill;
+#[cfg(feature = "faascale-mem")]
+pub mod template;
+#[cfg(feature = "faascale-mem")]
+pub mod test_utils;
+#[cfg(feature = "faascale-mem")]
 mod util;
+#[cfg(feature = "faascale-mem")]
+pub mod warmup;
 
-use utils::vm_memory::GuestMemoryError;
+use utils::vm_memory::{GuestAddress, GuestMemoryError};
 
-pub use self::device::{FaascaleMem, FaascaleMemConfig,FaascaleMemStats};
+#[cfg(feature = "faascale-mem")]
+pub use self::budget::{BudgetNegotiationState, FaascaleMemBudget};
+#[cfg(feature = "faascale-mem")]
+pub use self::capabilities::FaascaleMemCapabilities;
+#[cfg(feature = "faascale-mem")]
+pub use self::device::{
+    FaascaleMem, FaascaleMemBlock, FaascaleMemBlocks, FaascaleMemConfig, FaascaleMemConfigSpace,
+    FaascaleMemDepopulateMode, FaascaleMemFootprint, FaascaleMemHealth, FaascaleMemStats,
+    FaascaleMemStatsFreshness, FaascaleMemThpPolicy,
+};
+#[cfg(feature = "faascale-mem")]
+pub use self::encryption::{
+    detect_memory_encryption, EncryptedMemoryBackend, MemoryEncryptionKind,
+};
+#[cfg(feature = "faascale-mem")]
+pub use self::error_log::{
+    FaascaleMemErrorRecord, FaascaleMemErrors, FaascaleMemOperation, ERROR_HISTORY_LEN,
+};
+#[cfg(feature = "faascale-mem")]
+pub use self::estimate::{FaascaleMemEstimate, FaascaleMemEstimateLimit};
+#[cfg(feature = "faascale-mem")]
 pub use self::event_handler::*;
+#[cfg(feature = "faascale-mem")]
+pub use self::hotplug::{HotplugSlot, FAASCALE_MEM_HOTPLUG_SLOT_ID, TYPE_HOTPLUG_SLOT};
+#[cfg(feature = "faascale-mem")]
+pub use self::latency::{FaascaleMemPopulateLatency, LatencyHistogram, LATENCY_BUCKET_BOUNDS_US};
+#[cfg(feature = "faascale-mem")]
+pub use self::heatmap::{FaascaleMemHeatmap, FaascaleMemHeatmapBucket, HEATMAP_BUCKET_MIB};
+#[cfg(feature = "faascale-mem")]
+pub use self::interleave::FaascaleMemInterleaveConfig;
+#[cfg(feature = "faascale-mem")]
+pub use self::lazy::FaascaleMemPopulateMode;
+#[cfg(feature = "faascale-mem")]
+pub use self::metadata::{
+    FaascaleMemFieldCadence, FaascaleMemFieldMetadata, FaascaleMemFieldSource, FaascaleMemMetadata,
+};
+#[cfg(feature = "faascale-mem")]
+pub use self::mlock::FaascaleMemMlockUsage;
+#[cfg(feature = "faascale-mem")]
+pub use self::mmds_publish::{FaascaleMemMmdsSummary, MMDS_KEY};
+#[cfg(feature = "faascale-mem")]
+pub use self::observer::FaascaleMemObservedActivity;
+#[cfg(feature = "faascale-mem")]
+pub use self::policy::{FaascaleMemPolicyConfig, FaascaleMemPolicyDecision, PolicyError};
+#[cfg(feature = "faascale-mem")]
+pub use self::polling::FaascaleMemPollingAdaptation;
+#[cfg(feature = "faascale-mem")]
+pub use self::pool::{FaascaleMemPoolConfig, FaascaleMemPoolKind, FaascaleMemPoolUsage};
+#[cfg(feature = "faascale-mem")]
+pub use self::scheduling::{
+    FaascaleMemIoniceClass, FaascaleMemWorkerScheduling, FaascaleMemWorkerSchedulingConfig,
+};
+#[cfg(feature = "faascale-mem")]
+pub use self::template::FaascaleMemWarmReport;
+#[cfg(feature = "faascale-mem")]
+pub use self::util::POPULATE_DEDUP_WINDOW;
+#[cfg(feature = "faascale-mem")]
+pub use self::warmup::{FaascaleMemBootWarmup, BOOT_WARMUP_QUIET_PERIOD};
 
 /// Device ID used in MMIO device identification.
 /// Because FAASCALE_MEM is unique per-vm, this ID can be hardcoded.
 pub const FAASCALE_MEM_DEV_ID: &str = "faascale_mem";
-pub const CONFIG_SPACE_SIZE: usize = 8;
+pub const CONFIG_SPACE_SIZE: usize = 20;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 3;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+pub const NUM_QUEUES: usize = 4;
+pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
 // Number of 4K pages in a MiB.
 pub const MIB_TO_4K_PAGES: u32 = 256;
 // The maximum number of pages that can be received in a single descriptor.
@@ -30,9 +157,50 @@ pub const POPULATE_INDEX: usize = 0;
 pub const DEPOPULATE_INDEX: usize = 1;
 // The index of the stats queue from Faascale-Mem device queues/queues_evts vector.
 pub const FAASCALE_STATS_INDEX: usize = 2;
+// The index of the budget control queue from Faascale-Mem device queues/queues_evts vector.
+// The guest sees it right after the last queue present, at index 2 without the stats queue.
+pub const CONTROL_INDEX: usize = 3;
+// Default upper bound on the number of ranges held by the populated-range tracker.
+pub const POPULATE_TRACKER_MAX_ENTRIES: usize = 4096;
+// Written at the head of every memory slot piece of the populated blocks when the populate
+// verification is enabled.
+pub const POPULATE_CANARY: [u8; 8] = *b"FAASCALE";
+
+// Set by the guest in the page count of a populate block to pin the block, when
+// VIRTIO_FAASCALE_MEM_F_PIN is negotiated.
+pub const VIRTIO_FAASCALE_MEM_BLOCK_F_PINNED: u32 = 1 << 31;
+// Set by the guest in the page count of a populate block to lock the block into host memory
+// once populated, when VIRTIO_FAASCALE_MEM_F_MLOCK is negotiated.
+pub const VIRTIO_FAASCALE_MEM_BLOCK_F_MLOCKED: u32 = 1 << 28;
+// Bits of the page count of a populate block holding the backing granularity the guest asks
+// for, once VIRTIO_FAASCALE_MEM_F_BLOCK_GRANULARITY is negotiated.
+pub const VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_SHIFT: u32 = 29;
+pub const VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_MASK: u32 = 0x3 << 29;
+// The backing granularity hints.
+pub const VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_4K: u32 = 1;
+pub const VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_2M: u32 = 2;
+pub const VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_1G: u32 = 3;
+
+// The statuses of the populate and depopulate requests, written back by the device when
+// VIRTIO_FAASCALE_MEM_F_STATUS is negotiated. A request reports the first of its blocks to fail.
+pub const VIRTIO_FAASCALE_MEM_STATUS_OK: u32 = 0;
+// The host could not back or release the memory of a block.
+pub const VIRTIO_FAASCALE_MEM_STATUS_ENOMEM: u32 = 1;
+// A block lies outside the guest memory, or cannot be depopulated.
+pub const VIRTIO_FAASCALE_MEM_STATUS_EINVAL: u32 = 2;
+// Populating a block would exceed the memory budget or the populated memory cap.
+pub const VIRTIO_FAASCALE_MEM_STATUS_OVER_BUDGET: u32 = 3;
 
 // The feature bitmap for virtio faascale-mem.
 const VIRTIO_FAASCALE_MEM_F_STATS_VQ: u32 = 1; // Enable statistics.
+const VIRTIO_FAASCALE_MEM_F_BLOCK_GRANULARITY: u32 = 2; // Backing granularity hints.
+const VIRTIO_FAASCALE_MEM_F_BUDGET: u32 = 3; // Memory budget negotiation.
+const VIRTIO_FAASCALE_MEM_F_TRACE_IDS: u32 = 4; // Trace IDs in populate blocks.
+const VIRTIO_FAASCALE_MEM_F_WIDE_BLOCKS: u32 = 5; // 64-bit pfn and page count in blocks.
+const VIRTIO_FAASCALE_MEM_F_ZEROED: u32 = 6; // Populated pages are zero-filled.
+const VIRTIO_FAASCALE_MEM_F_MLOCK: u32 = 8; // Locking of populated blocks.
+const VIRTIO_FAASCALE_MEM_F_STATUS: u32 = 9; // Status of the requests written back.
+const VIRTIO_FAASCALE_MEM_F_PIN: u32 = 10; // Pinning of populated blocks.
 
 // The statistics tags.
 const VIRTIO_FAASCALE_MEM_S_SWAP_IN: u16 = 0;
@@ -45,30 +213,96 @@ const VIRTIO_FAASCALE_MEM_S_AVAIL: u16 = 6;
 const VIRTIO_FAASCALE_MEM_S_CACHES: u16 = 7;
 const VIRTIO_FAASCALE_MEM_S_HTLB_PGALLOC: u16 = 8;
 const VIRTIO_FAASCALE_MEM_S_HTLB_PGFAIL: u16 = 9;
+// The share of time some task of the guest stalled on memory over the last 10 and 60 seconds,
+// from /proc/pressure/memory, in hundredths of a percent.
+const VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG10: u16 = 10;
+const VIRTIO_FAASCALE_MEM_S_PSI_SOME_AVG60: u16 = 11;
 
 #[derive(Debug)]
 pub enum Error {
     /// Activation error.
     Activate(super::ActivateError),
+    /// Error listening for the guest connections to the vsock observer port.
+    ActivityObserver(std::io::Error),
+    /// Received a budget request when the budget negotiation is disabled.
+    BudgetDisabled,
+    /// The device is fenced and refuses to populate guest memory.
+    DeviceFenced,
     /// No faascale-mem device found.
     DeviceNotFound,
     /// Device not activated yet.
     DeviceNotActive,
+    /// The host asked to depopulate a range overlapping a pinned range.
+    DepopulatePinned,
     /// EventFd error.
     EventFd(std::io::Error),
+    /// Error starting the thread backing the pages of the lazily populated blocks.
+    FaultHandler(std::io::Error),
     /// Guest gave us bad memory addresses.
     GuestMemory(GuestMemoryError),
+    /// Error reserving the host memory pool.
+    HostMemoryPool(std::io::Error),
     /// Received error while sending an interrupt.
     InterruptError(std::io::Error),
+    /// A depopulate request of the host gives neither a range nor an amount to release, both,
+    /// or only half of a range.
+    InvalidDepopulateRequest,
+    /// A populate request of the host gives none or several of a range, blocks and an amount
+    /// to populate, or only half of a range.
+    InvalidPopulateRequest,
+    /// The population policy experiment sends more than 100% of the blocks to a variant.
+    InvalidExperimentSplit,
+    /// The interleaving of the large blocks names no NUMA node, or one above 63.
+    InvalidInterleaveNodes,
+    /// The NUMA node to place the populated blocks on is above 63.
+    InvalidNumaNode,
+    /// The scheduling of the worker threads is out of the bounds of the host, or sets an I/O
+    /// priority level without a class or a CPU weight without a cgroup.
+    InvalidWorkerScheduling,
+    /// The policy program is invalid.
+    #[cfg(feature = "faascale-mem")]
+    InvalidPolicy(PolicyError),
+    /// The host memory pool is empty or not a multiple of its chunk size.
+    InvalidPoolSize,
+    /// KSM is advised either the populated blocks or the idle memory, not both.
+    KsmMergeableWithIdle,
     /// Guest gave us a malformed descriptor.
     MalformedDescriptor,
     /// Guest gave us a malformed payload.
     MalformedPayload,
+    /// Error locking or unlocking guest memory into host memory.
+    Mlock(std::io::Error),
+    /// Locking the range would take the locked memory over the budget of the microVM.
+    MlockBudgetExceeded,
+    /// The host asked to lock a range while no locking budget is configured.
+    MlockDisabled,
+    /// The host asked to lock a range which is not entirely populated.
+    MlockNotPopulated,
+    /// The host asked to pin a range lying outside the guest memory.
+    PinOutsideMemory,
+    /// The host memory pool only backs anonymous private guest memory of 4K pages, which no
+    /// userfaultfd serves.
+    PoolUnsupportedMemory,
+    /// The host does not support the memory encryption of the guest.
+    MemoryEncryptionUnsupported,
+    /// Error starting the thread polling the populate queue.
+    PopulatePoller(std::io::Error),
     /// Error restoring the faascale-mem device queues.
     QueueRestoreError,
+    /// Error handling the event of the rate limiter of the populate and depopulate requests.
+    RateLimiter(rate_limiter::Error),
+    /// Error creating the spill file.
+    SpillFile(std::io::Error),
+    /// The spill file and the block cache both restore the depopulated contents, only one can
+    /// be used.
+    SpillWithBlockCache,
+    /// Error opening the template memory file.
+    TemplateFile(std::io::Error),
     /// Received stats querry when stats are disabled.
     StatisticsDisabled,
-    /// Statistics cannot be enabled/disabled after activation.
+    /// The guest did not report fresh statistics in time.
+    StatisticsPollTimeout,
+    /// Statistics cannot be enabled/disabled on a device restored without a statistics queue.
     StatisticsStateChange,
     /// Amount of pages requested cannot fit in `u32`.
     TooManyPagesRequested,
@@ -78,15 +312,21 @@ pub enum Error {
     RemoveMemoryRegion(RemoveRegionError),
     /// Error creating the statistics timer.
     Timer(std::io::Error),
+    /// Error creating the userfaultfd of the lazy populate mode.
+    Userfaultfd(userfaultfd::Error),
 }
 
 #[derive(Debug)]
 pub enum RemoveRegionError {
     AddressTranslation,
+    EncryptionBackend(std::io::Error),
     MalformedRange,
+    MisalignedHugePage,
+    OutsideMemslot(GuestAddress),
     MadviseFail(std::io::Error),
     MmapFail(std::io::Error),
     RegionNotFound,
+    Userfaultfd(userfaultfd::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
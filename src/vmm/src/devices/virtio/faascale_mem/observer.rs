@@ -0,0 +1,258 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Vsock service reporting the memory activity of the microVM to the guest.
+//!
+//! In-guest runtimes adapting their allocations to the host-side cost of the memory they ask
+//! for connect to the `vsock_observer_port` of the host through the vsock device of the microVM.
+//! Like any connection the guest initiates, it lands on the Unix socket `<uds_path>_<port>` of
+//! the host, which the service listens on from the VMM event loop. Each connection is answered
+//! with a line of JSON giving the host view of the memory activity, then closed.
+
+use std::io::{self, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use event_manager::{EventOps, Events, MutEventSubscriber, SubscriberOps};
+use logger::{error, warn, IncMetric, METRICS};
+use serde::Serialize;
+use utils::epoll::EventSet;
+
+use super::device::FaascaleMem;
+use super::Error as FaascaleMemError;
+use crate::EventManager;
+
+/// Memory activity of the microVM, as answered to the guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemObservedActivity {
+    /// Memory populated by the guest, in MiB.
+    pub populated_mib: u64,
+    /// Memory the guest driver is asked to keep populated, in MiB.
+    pub target_mib: u32,
+    /// Number of populate requests of the guest completed.
+    pub populate_requests: u64,
+    /// Number of depopulate requests of the guest completed.
+    pub depopulate_requests: u64,
+    /// Time the host took to complete the last populate request, in microseconds.
+    pub last_populate_us: Option<u64>,
+    /// Time the host took to complete the last depopulate request, in microseconds.
+    pub last_depopulate_us: Option<u64>,
+}
+
+/// Counts of the populate and depopulate requests of the guest, and the time the last ones took.
+#[derive(Debug, Default)]
+pub(crate) struct RequestActivity {
+    populate_requests: u64,
+    depopulate_requests: u64,
+    last_populate: Option<Duration>,
+    last_depopulate: Option<Duration>,
+}
+
+impl RequestActivity {
+    /// Records the completion of a populate or depopulate request, which took `latency`.
+    pub fn record(&mut self, populate: bool, latency: Duration) {
+        if populate {
+            self.populate_requests += 1;
+            self.last_populate = Some(latency);
+        } else {
+            self.depopulate_requests += 1;
+            self.last_depopulate = Some(latency);
+        }
+    }
+
+    /// Reports the activity, along with the populated and target memory.
+    pub fn report(&self, populated_mib: u64, target_mib: u32) -> FaascaleMemObservedActivity {
+        let us = |latency: Duration| u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        FaascaleMemObservedActivity {
+            populated_mib,
+            target_mib,
+            populate_requests: self.populate_requests,
+            depopulate_requests: self.depopulate_requests,
+            last_populate_us: self.last_populate.map(us),
+            last_depopulate_us: self.last_depopulate.map(us),
+        }
+    }
+}
+
+/// Answers the guest connections to the observer port with the memory activity of the device.
+#[derive(Debug)]
+pub(crate) struct ActivityObserver {
+    listener: UnixListener,
+    device: Weak<Mutex<FaascaleMem>>,
+}
+
+impl ActivityObserver {
+    /// Listens for the guest connections to `port`, through the vsock device whose Unix socket
+    /// is at `vsock_uds_path`.
+    pub fn new(
+        faascale_mem: &Arc<Mutex<FaascaleMem>>,
+        vsock_uds_path: &str,
+        port: u32,
+    ) -> io::Result<Self> {
+        let listener = UnixListener::bind(format!("{}_{}", vsock_uds_path, port))?;
+        listener.set_nonblocking(true)?;
+        Ok(ActivityObserver {
+            listener,
+            device: Arc::downgrade(faascale_mem),
+        })
+    }
+
+    // Writes the activity of the device to the guest connection, which is closed once dropped.
+    fn answer(&self, mut stream: UnixStream) -> io::Result<()> {
+        let device = match self.device.upgrade() {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        let activity = device.lock().expect("Poisoned lock").observed_activity();
+        let mut answer = serde_json::to_vec(&activity)?;
+        answer.push(b'\n');
+        // The answer fits in the buffer of the socket, the guest is never waited for.
+        stream.set_nonblocking(true)?;
+        stream.write_all(&answer)
+    }
+}
+
+impl MutEventSubscriber for ActivityObserver {
+    fn process(&mut self, event: Events, _: &mut EventOps) {
+        let event_set = event.event_set();
+        if !EventSet::IN.contains(event_set) {
+            warn!(
+                "Received unknown event: {:?} from source: {:?}",
+                event_set,
+                event.fd()
+            );
+            return;
+        }
+
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    METRICS.faascale_mem.observer_connections.inc();
+                    if let Err(err) = self.answer(stream) {
+                        METRICS.faascale_mem.observer_fails.inc();
+                        error!(
+                            "faascale-mem: error answering the activity observer: {}",
+                            err
+                        );
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    METRICS.faascale_mem.observer_fails.inc();
+                    error!(
+                        "faascale-mem: error accepting an observer connection: {}",
+                        err
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(&self.listener, EventSet::IN)) {
+            error!(
+                "Failed to register the faascale-mem observer event: {}",
+                err
+            );
+        }
+    }
+}
+
+/// Starts answering the guest connections to the observer port of `faascale_mem`, if it has
+/// one, through the vsock device whose Unix socket is at `vsock_uds_path`.
+pub(crate) fn start_activity_observer(
+    faascale_mem: &Arc<Mutex<FaascaleMem>>,
+    vsock_uds_path: Option<&str>,
+    event_manager: &mut EventManager,
+) -> Result<(), FaascaleMemError> {
+    let port = faascale_mem
+        .lock()
+        .expect("Poisoned lock")
+        .vsock_observer_port();
+    match (port, vsock_uds_path) {
+        (Some(port), Some(vsock_uds_path)) => {
+            let observer = ActivityObserver::new(faascale_mem, vsock_uds_path, port)
+                .map_err(FaascaleMemError::ActivityObserver)?;
+            event_manager.add_subscriber(Arc::new(Mutex::new(observer)));
+        }
+        (Some(_), None) => {
+            warn!("faascale-mem: vsock is not configured, the activity is not observable.")
+        }
+        (None, _) => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::devices::virtio::faascale_mem::test_utils::default_faascale_mem;
+
+    #[test]
+    fn test_request_activity() {
+        let mut activity = RequestActivity::default();
+        assert_eq!(
+            activity.report(0, 0),
+            FaascaleMemObservedActivity::default()
+        );
+        activity.record(true, Duration::from_micros(10));
+        activity.record(true, Duration::from_micros(20));
+        activity.record(false, Duration::from_micros(5));
+        assert_eq!(
+            activity.report(64, 128),
+            FaascaleMemObservedActivity {
+                populated_mib: 64,
+                target_mib: 128,
+                populate_requests: 2,
+                depopulate_requests: 1,
+                last_populate_us: Some(20),
+                last_depopulate_us: Some(5),
+            }
+        );
+    }
+
+    #[test]
+    fn test_activity_observer() {
+        let mut event_manager = EventManager::new().unwrap();
+        let faascale_mem = Arc::new(Mutex::new(default_faascale_mem(0)));
+        let uds_file = TempFile::new().unwrap();
+        let uds_path = uds_file.as_path().to_str().unwrap().to_string();
+
+        // Without an observer port, or without vsock, nothing listens.
+        start_activity_observer(&faascale_mem, Some(&uds_path), &mut event_manager).unwrap();
+        faascale_mem.lock().unwrap().vsock_observer_port = Some(52);
+        start_activity_observer(&faascale_mem, None, &mut event_manager).unwrap();
+        let port_path = format!("{}_52", uds_path);
+        assert!(UnixStream::connect(&port_path).is_err());
+
+        start_activity_observer(&faascale_mem, Some(&uds_path), &mut event_manager).unwrap();
+        faascale_mem
+            .lock()
+            .unwrap()
+            .request_activity
+            .record(true, Duration::from_micros(30));
+        let connections = METRICS.faascale_mem.observer_connections.count();
+        let mut stream = UnixStream::connect(&port_path).unwrap();
+        event_manager.run_with_timeout(100).unwrap();
+        assert_eq!(
+            METRICS.faascale_mem.observer_connections.count(),
+            connections + 1
+        );
+
+        // The answer is a line of JSON, and the connection is closed after it.
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).unwrap();
+        assert_eq!(
+            answer,
+            "{\"populated_mib\":0,\"target_mib\":0,\"populate_requests\":1,\
+             \"depopulate_requests\":0,\"last_populate_us\":30,\"last_depopulate_us\":null}\n"
+        );
+        std::fs::remove_file(port_path).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Optional sampling of performance counters around the TDP pre-fault of populated blocks.
+//!
+//! The dTLB misses are counted for the vCPU threads, which the pre-fault spares the TDP faults
+//! of, and the TDP mappings of each page size are read from the binary statistics of the VM. The
+//! counters cannot be opened under the VMM seccomp filters, so they are opened before.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use logger::warn;
+use utils::{ioctl_io_nr, ioctl_ioc_nr};
+
+ioctl_io_nr!(KVM_GET_STATS_FD, kvm_bindings::KVMIO, 0xce);
+
+const PERF_TYPE_HW_CACHE: u32 = 3;
+const PERF_COUNT_HW_CACHE_DTLB: u64 = 3;
+const PERF_COUNT_HW_CACHE_OP_READ: u64 = 0;
+const PERF_COUNT_HW_CACHE_RESULT_MISS: u64 = 1;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+const PERF_ATTR_SIZE_VER0: u32 = 64;
+
+// VM statistics holding the number of TDP mappings of each page size.
+const KVM_STATS_TDP_PAGES: [&str; 3] = ["pages_4k", "pages_2m", "pages_1g"];
+// Size of `struct kvm_stats_header`.
+const KVM_STATS_HEADER_SIZE: usize = 24;
+// Size of `struct kvm_stats_desc`, without the name.
+const KVM_STATS_DESC_SIZE: usize = 16;
+
+// `struct perf_event_attr`, up to `PERF_ATTR_SIZE_VER0`. Only the kernel reads the fields.
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// Counter values sampled around a TDP pre-fault, or their difference. The counters that could
+/// not be opened are `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PrefaultCounters {
+    /// dTLB read misses of the vCPU threads.
+    pub dtlb_misses: Option<i64>,
+    /// TDP mappings of the VM with 4K, 2M and 1G pages.
+    pub tdp_pages: [Option<i64>; 3],
+}
+
+impl PrefaultCounters {
+    /// Returns the change of the counters since `before`.
+    pub fn delta(&self, before: &PrefaultCounters) -> PrefaultCounters {
+        let sub = |after: Option<i64>, before: Option<i64>| Some(after? - before?);
+        PrefaultCounters {
+            dtlb_misses: sub(self.dtlb_misses, before.dtlb_misses),
+            tdp_pages: [
+                sub(self.tdp_pages[0], before.tdp_pages[0]),
+                sub(self.tdp_pages[1], before.tdp_pages[1]),
+                sub(self.tdp_pages[2], before.tdp_pages[2]),
+            ],
+        }
+    }
+}
+
+impl fmt::Display for PrefaultCounters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let counters = std::iter::once(("dtlb_misses", self.dtlb_misses)).chain(
+            KVM_STATS_TDP_PAGES
+                .iter()
+                .copied()
+                .zip(self.tdp_pages.iter().copied()),
+        );
+        let mut separator = "";
+        for (name, value) in counters {
+            if let Some(value) = value {
+                write!(f, "{}{}:{}", separator, name, value)?;
+                separator = " ";
+            }
+        }
+        Ok(())
+    }
+}
+
+// The VM statistics read through the KVM binary statistics file.
+#[derive(Debug)]
+struct KvmStats {
+    file: File,
+    // Offsets in `file` of the `KVM_STATS_TDP_PAGES` values.
+    tdp_pages: [Option<u64>; 3],
+}
+
+impl KvmStats {
+    fn new(file: File) -> io::Result<KvmStats> {
+        let read_u32 = |buf: &[u8], offset: usize| {
+            u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+        };
+
+        let mut header = [0u8; KVM_STATS_HEADER_SIZE];
+        file.read_exact_at(&mut header, 0)?;
+        let name_size = read_u32(&header, 4) as usize;
+        let num_desc = read_u32(&header, 8) as usize;
+        let desc_offset = u64::from(read_u32(&header, 16));
+        let data_offset = u64::from(read_u32(&header, 20));
+
+        let mut descs = vec![0u8; num_desc * (KVM_STATS_DESC_SIZE + name_size)];
+        file.read_exact_at(&mut descs, desc_offset)?;
+
+        let mut tdp_pages = [None; 3];
+        for desc in descs.chunks_exact(KVM_STATS_DESC_SIZE + name_size) {
+            let name = &desc[KVM_STATS_DESC_SIZE..];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            if let Some(index) = KVM_STATS_TDP_PAGES
+                .iter()
+                .position(|stat| stat.as_bytes() == name)
+            {
+                tdp_pages[index] = Some(data_offset + u64::from(read_u32(desc, 8)));
+            }
+        }
+        Ok(KvmStats { file, tdp_pages })
+    }
+
+    fn read(&self, offset: Option<u64>) -> Option<i64> {
+        let mut value = [0u8; 8];
+        self.file.read_exact_at(&mut value, offset?).ok()?;
+        Some(u64::from_ne_bytes(value) as i64)
+    }
+}
+
+/// Samples the counters measuring the effect of the TDP pre-fault.
+#[derive(Debug)]
+pub(crate) struct PrefaultSampler {
+    // One counter per vCPU thread, summed up in the samples.
+    dtlb_misses: Vec<File>,
+    kvm_stats: Option<KvmStats>,
+}
+
+impl PrefaultSampler {
+    /// Opens the counters of the vCPU threads `vcpu_tids` and of the VM behind `vm_fd`. The
+    /// counters that cannot be opened are left out of the samples.
+    pub fn new(vm_fd: RawFd, vcpu_tids: &[libc::pid_t]) -> PrefaultSampler {
+        let dtlb_misses = vcpu_tids
+            .iter()
+            .filter_map(|&tid| {
+                open_dtlb_misses_counter(tid)
+                    .map_err(|err| {
+                        warn!(
+                            "Cannot count the dTLB misses of vCPU thread {}: {}",
+                            tid, err
+                        )
+                    })
+                    .ok()
+            })
+            .collect();
+        let kvm_stats = open_kvm_stats(vm_fd)
+            .map_err(|err| warn!("Cannot read the KVM statistics of the VM: {}", err))
+            .ok();
+        PrefaultSampler {
+            dtlb_misses,
+            kvm_stats,
+        }
+    }
+
+    /// Reads the current values of the counters.
+    pub fn sample(&mut self) -> PrefaultCounters {
+        let dtlb_misses = if self.dtlb_misses.is_empty() {
+            None
+        } else {
+            self.dtlb_misses
+                .iter_mut()
+                .try_fold(0i64, |total, counter| {
+                    let mut value = [0u8; 8];
+                    counter.read_exact(&mut value).ok()?;
+                    Some(total + u64::from_ne_bytes(value) as i64)
+                })
+        };
+        let tdp_pages = match self.kvm_stats.as_ref() {
+            Some(stats) => [
+                stats.read(stats.tdp_pages[0]),
+                stats.read(stats.tdp_pages[1]),
+                stats.read(stats.tdp_pages[2]),
+            ],
+            None => [None; 3],
+        };
+        PrefaultCounters {
+            dtlb_misses,
+            tdp_pages,
+        }
+    }
+}
+
+fn open_dtlb_misses_counter(tid: libc::pid_t) -> io::Result<File> {
+    let attr = PerfEventAttr {
+        type_: PERF_TYPE_HW_CACHE,
+        size: PERF_ATTR_SIZE_VER0,
+        config: PERF_COUNT_HW_CACHE_DTLB
+            | (PERF_COUNT_HW_CACHE_OP_READ << 8)
+            | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16),
+        ..Default::default()
+    };
+    // SAFETY: `attr` is a valid `perf_event_attr` of `PERF_ATTR_SIZE_VER0` bytes. The counter
+    // follows the thread `tid` on any CPU.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            tid,
+            -1,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: The file descriptor was just opened and is owned by nobody else.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+fn open_kvm_stats(vm_fd: RawFd) -> io::Result<KvmStats> {
+    // SAFETY: The ioctl takes no argument and returns a new file descriptor.
+    let fd = unsafe { libc::ioctl(vm_fd, KVM_GET_STATS_FD() as _) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: The file descriptor was just opened and is owned by nobody else.
+    KvmStats::new(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_prefault_counters_delta() {
+        let before = PrefaultCounters {
+            dtlb_misses: Some(100),
+            tdp_pages: [Some(512), Some(3), None],
+        };
+        let after = PrefaultCounters {
+            dtlb_misses: Some(140),
+            tdp_pages: [Some(500), Some(4), None],
+        };
+        let delta = after.delta(&before);
+        assert_eq!(
+            delta,
+            PrefaultCounters {
+                dtlb_misses: Some(40),
+                tdp_pages: [Some(-12), Some(1), None],
+            }
+        );
+        assert_eq!(delta.to_string(), "dtlb_misses:40 pages_4k:-12 pages_2m:1");
+        assert_eq!(PrefaultCounters::default().to_string(), "");
+    }
+
+    #[test]
+    fn test_kvm_stats() {
+        // A header, then two descriptors with 8-byte names, then the data.
+        let name_size = 8usize;
+        let desc_offset = KVM_STATS_HEADER_SIZE;
+        let data_offset = desc_offset + 2 * (KVM_STATS_DESC_SIZE + name_size);
+        let mut blob = Vec::new();
+        for field in [0, name_size, 2, 0, desc_offset, data_offset] {
+            blob.extend_from_slice(&(field as u32).to_ne_bytes());
+        }
+        for (name, offset) in [("pages_2m", 8u32), ("flooded", 0u32)] {
+            blob.extend_from_slice(&[0u8; 8]);
+            blob.extend_from_slice(&offset.to_ne_bytes());
+            blob.extend_from_slice(&[0u8; 4]);
+            let mut name = name.as_bytes().to_vec();
+            name.resize(name_size, 0);
+            blob.extend_from_slice(&name);
+        }
+        blob.extend_from_slice(&7u64.to_ne_bytes());
+        blob.extend_from_slice(&42u64.to_ne_bytes());
+
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(&blob).unwrap();
+        let stats = KvmStats::new(file).unwrap();
+        assert_eq!(stats.tdp_pages, [None, Some(data_offset as u64 + 8), None]);
+        assert_eq!(stats.read(stats.tdp_pages[1]), Some(42));
+        assert_eq!(stats.read(stats.tdp_pages[0]), None);
+
+        // A truncated file is not a statistics file.
+        let file = TempFile::new().unwrap().into_file();
+        assert!(KvmStats::new(file).is_err());
+    }
+}
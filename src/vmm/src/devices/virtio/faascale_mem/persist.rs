@@ -3,20 +3,37 @@
 
 //! Defines the structures needed for saving/restoring faascale-mem devices.
 
+#[cfg(feature = "faascale-mem")]
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
+#[cfg(feature = "faascale-mem")]
 use std::time::Duration;
 
+use kvm_ioctls::VmFd;
+use logger::warn;
+#[cfg(feature = "faascale-mem")]
+use logger::{StoreMetric, METRICS};
+#[cfg(feature = "faascale-mem")]
+use rate_limiter::RateLimiter;
+#[cfg(feature = "faascale-mem")]
 use snapshot::Persist;
+#[cfg(feature = "faascale-mem")]
 use timerfd::{SetTimeFlags, TimerState};
 use utils::vm_memory::GuestMemoryMmap;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 
+#[cfg(feature = "faascale-mem")]
 use super::*;
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::faascale_mem::budget::BudgetNegotiation;
+#[cfg(feature = "faascale-mem")]
 use crate::devices::virtio::faascale_mem::device::{FaascaleMemStats, ConfigSpace, FaascaleMem};
 use crate::devices::virtio::persist::VirtioDeviceState;
+#[cfg(feature = "faascale-mem")]
 use crate::devices::virtio::{DeviceState, TYPE_FAASCALE_MEM};
+#[cfg(feature = "faascale-mem")]
+use crate::vmm_config::faascale_mem::FaascaleMemDeviceConfig;
 
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
@@ -40,6 +57,7 @@ pub struct FaascaleMemStatsState {
     hugetlb_failures: Option<u64>,
 }
 
+#[cfg(feature = "faascale-mem")]
 impl FaascaleMemStatsState {
     fn from_stats(stats: &FaascaleMemStats) -> Self {
         Self {
@@ -68,6 +86,80 @@ impl FaascaleMemStatsState {
             disk_caches: self.disk_caches,
             hugetlb_allocations: self.hugetlb_allocations,
             hugetlb_failures: self.hugetlb_failures,
+            // The granularity counters and the memory pressure start over on restore.
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct FaascaleMemRangeState {
+    start_pfn: u64,
+    num_pages: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum FaascaleMemBudgetStageState {
+    // Snapshots taken before the negotiation was saved carry no offer.
+    #[default]
+    Unoffered,
+    Offered,
+    Agreed,
+}
+
+#[cfg(feature = "faascale-mem")]
+impl From<BudgetNegotiationState> for FaascaleMemBudgetStageState {
+    fn from(state: BudgetNegotiationState) -> Self {
+        match state {
+            BudgetNegotiationState::Unoffered => FaascaleMemBudgetStageState::Unoffered,
+            BudgetNegotiationState::Offered => FaascaleMemBudgetStageState::Offered,
+            BudgetNegotiationState::Agreed => FaascaleMemBudgetStageState::Agreed,
+        }
+    }
+}
+
+#[cfg(feature = "faascale-mem")]
+impl From<FaascaleMemBudgetStageState> for BudgetNegotiationState {
+    fn from(stage: FaascaleMemBudgetStageState) -> Self {
+        match stage {
+            FaascaleMemBudgetStageState::Unoffered => BudgetNegotiationState::Unoffered,
+            FaascaleMemBudgetStageState::Offered => BudgetNegotiationState::Offered,
+            FaascaleMemBudgetStageState::Agreed => BudgetNegotiationState::Agreed,
+        }
+    }
+}
+
+#[derive(Clone, Default, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct FaascaleMemBudgetState {
+    stage: FaascaleMemBudgetStageState,
+    offered_epoch: u32,
+    offered_pages: u32,
+    agreed_pages: Option<u32>,
+    violations: u64,
+}
+
+#[cfg(feature = "faascale-mem")]
+impl FaascaleMemBudgetState {
+    fn from_budget(budget: &BudgetNegotiation) -> Self {
+        Self {
+            stage: budget.state.into(),
+            offered_epoch: budget.offered_epoch,
+            offered_pages: budget.offered_pages,
+            agreed_pages: budget.agreed_pages,
+            violations: budget.violations,
+        }
+    }
+
+    fn create_budget(&self) -> BudgetNegotiation {
+        BudgetNegotiation {
+            state: self.stage.into(),
+            offered_epoch: self.offered_epoch,
+            offered_pages: self.offered_pages,
+            agreed_pages: self.agreed_pages,
+            violations: self.violations,
         }
     }
 }
@@ -80,18 +172,91 @@ pub struct FaascaleMemState {
     latest_stats: FaascaleMemStatsState,
     config_space: FaascaleMemConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    #[version(start = 2, ser_fn = "populated_ranges_ser")]
+    populated_ranges: Vec<FaascaleMemRangeState>,
+    #[version(start = 2)]
+    config_epoch: u64,
+    #[version(start = 2, ser_fn = "pinned_ranges_ser")]
+    pinned_ranges: Vec<FaascaleMemRangeState>,
+    #[version(start = 2)]
+    host_pinned_ranges: Vec<FaascaleMemRangeState>,
+    #[version(start = 2, ser_fn = "budget_ser")]
+    budget: FaascaleMemBudgetState,
+}
+
+#[cfg(feature = "faascale-mem")]
+fn range_states(ranges: impl Iterator<Item = (u64, u64)>) -> Vec<FaascaleMemRangeState> {
+    ranges
+        .map(|(start, end)| FaascaleMemRangeState {
+            start_pfn: start,
+            num_pages: end - start,
+        })
+        .collect()
+}
+
+impl FaascaleMemState {
+    fn populated_ranges_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && !self.populated_ranges.is_empty() {
+            warn!(
+                "Target version does not support persisting the faascale-mem populated ranges, \
+                 they will not be saved."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn pinned_ranges_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && !self.pinned_ranges.is_empty() {
+            warn!(
+                "Target version does not support persisting the faascale-mem pinned ranges, they \
+                 will not be saved."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn budget_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.budget.stage != FaascaleMemBudgetStageState::Unoffered {
+            warn!(
+                "Target version does not support persisting the faascale-mem budget negotiation, \
+                 the budget will not be enforced after restore."
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The `[start, end)` pfn ranges populated by the guest when the state was saved.
+    pub fn populated_ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.populated_ranges
+            .iter()
+            .map(|range| (range.start_pfn, range.start_pfn + range.num_pages))
+    }
 }
 
 pub struct FaascaleMemConstructorArgs {
     pub mem: GuestMemoryMmap,
+    pub vm_fd: Arc<VmFd>,
 }
 
+#[cfg(feature = "faascale-mem")]
 impl Persist<'_> for FaascaleMem {
     type State = FaascaleMemState;
     type ConstructorArgs = FaascaleMemConstructorArgs;
     type Error = super::Error;
 
     fn save(&self) -> Self::State {
+        if let Some(spill) = self.spill_file.as_ref() {
+            if spill.spilled_pages() > 0 {
+                warn!(
+                    "The contents of the {} pages in the faascale-mem spill file are not part of \
+                     the snapshot, the guest will find them zero-filled.",
+                    spill.spilled_pages()
+                );
+            }
+        }
         FaascaleMemState {
             stats_polling_interval_s: self.stats_polling_interval_s,
             stats_desc_index: self.stats_desc_index,
@@ -101,6 +266,11 @@ impl Persist<'_> for FaascaleMem {
                 actual_pages: self.config_space.actual_pages,
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            populated_ranges: range_states(self.populated_ranges.ranges()),
+            config_epoch: self.config_epoch,
+            pinned_ranges: range_states(self.pinned_ranges.ranges()),
+            host_pinned_ranges: range_states(self.host_pinned_ranges.ranges()),
+            budget: FaascaleMemBudgetState::from_budget(&self.budget),
         }
     }
 
@@ -108,16 +278,38 @@ impl Persist<'_> for FaascaleMem {
         constructor_args: Self::ConstructorArgs,
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
-        // We can safely create the faascale-mem with arbitrary flags and
-        // num_pages because we will overwrite them after.
-        let mut faascale_mem = FaascaleMem::new(state.stats_polling_interval_s, true,true,true)?;
-
         let mut num_queues = NUM_QUEUES;
-        // As per the virtio 1.1 specification, the statistics queue
-        // should not exist if the statistics are not enabled.
-        if state.stats_polling_interval_s == 0 {
+        // Snapshots of older devices have no statistics queue when the statistics are disabled.
+        if state.virtio_state.avail_features & (1u64 << VIRTIO_FAASCALE_MEM_F_STATS_VQ) == 0 {
+            num_queues -= 1;
+        }
+        // The control queue only exists if the budget negotiation was offered.
+        if state.virtio_state.avail_features & (1u64 << VIRTIO_FAASCALE_MEM_F_BUDGET) == 0 {
             num_queues -= 1;
         }
+        // We can safely create the faascale-mem with arbitrary flags and
+        // num_pages because we will overwrite them after. The statistics
+        // strictness, the THP policy, the polling adaptation, the cap on the
+        // populated memory, the block cache, the scrubbing, the NUMA
+        // interleaving and placement, the depopulate mode, the locking
+        // budget, the rate limiter, the MMDS publishing, the completion of the
+        // leaked descriptors, the interrupt moderation, the policy program, the
+        // populate verification, the scheduling of the worker threads, the
+        // KSM advice, the spill file, the vsock observer port, the template
+        // memory file, the populate mode and the depopulation on reset are not
+        // part of the snapshot, so they fall back to the default. The locked
+        // blocks are left unlocked.
+        let mut faascale_mem = FaascaleMem::new(
+            &FaascaleMemDeviceConfig {
+                stats_polling_interval_s: state.stats_polling_interval_s,
+                pre_alloc_mem: true,
+                pre_tdp_fault: true,
+                ..Default::default()
+            },
+            true,
+            RateLimiter::default(),
+        )?;
+
         faascale_mem.queues = state
             .virtio_state
             .build_queues_checked(&constructor_args.mem, TYPE_FAASCALE_MEM, num_queues, QUEUE_SIZE)
@@ -130,15 +322,43 @@ impl Persist<'_> for FaascaleMem {
         faascale_mem.config_space = ConfigSpace {
             num_pages: state.config_space.num_pages,
             actual_pages: state.config_space.actual_pages,
+            budget_pages: state.budget.offered_pages,
+            budget_epoch: state.budget.offered_epoch,
+            ..Default::default()
         };
+        // The budget agreed on before the snapshot stays enforced, and the pending offer can
+        // still be acknowledged.
+        faascale_mem.budget = state.budget.create_budget();
+        for (start, end) in state.populated_ranges() {
+            faascale_mem.populated_ranges.insert_range(start, end);
+        }
+        for range in &state.pinned_ranges {
+            faascale_mem
+                .pinned_ranges
+                .insert((range.start_pfn, range.num_pages));
+        }
+        for range in &state.host_pinned_ranges {
+            faascale_mem
+                .host_pinned_ranges
+                .insert((range.start_pfn, range.num_pages));
+        }
+        METRICS
+            .faascale_mem
+            .pinned_pages
+            .store(faascale_mem.pinned_ranges.num_pages() as usize);
+        faascale_mem.config_epoch = state.config_epoch;
+        faascale_mem.mark_restored_stats();
 
         if state.virtio_state.activated {
             faascale_mem.device_state = DeviceState::Activated(constructor_args.mem);
 
-            if faascale_mem.stats_enabled() {
-                // Restore the stats descriptor.
+            // Restore the stats descriptor, which is held even while the statistics are
+            // disabled.
+            if faascale_mem.stats_queue_present() {
                 faascale_mem.set_stats_desc_index(state.stats_desc_index);
+            }
 
+            if faascale_mem.stats_enabled() {
                 // Restart timer if needed.
                 let timer_state = TimerState::Periodic {
                     current: Duration::from_secs(u64::from(state.stats_polling_interval_s)),
@@ -150,6 +370,10 @@ impl Persist<'_> for FaascaleMem {
             }
         }
 
+        // The host restoring the snapshot may not be the one that took it.
+        faascale_mem.set_vm_fd(constructor_args.vm_fd);
+        faascale_mem.probe_capabilities();
+
         Ok(faascale_mem)
     }
 }
@@ -0,0 +1,796 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Policy programs steering the device from the statistics of the guest.
+//!
+//! A policy is a small program run on every statistics update. It reads the statistics and
+//! decides the cap on the populated memory, how much memory the guest is asked to release, and
+//! the memory budget offered to the guest, so that operators can try out policies without a new
+//! VMM build.
+//!
+//! A program is a list of `name = expression` statements, separated by `;` or new lines, with
+//! `#` starting a comment. Expressions work on signed 64-bit integers: literals, variables, the
+//! `+`, `-`, `*`, `/` and `%` operators, the comparisons and `&&`, `||` and `!`, which give 0 or
+//! 1, and the `min(a, b)`, `max(a, b)` and `if(condition, then, else)` functions. The inputs
+//! listed in `POLICY_INPUTS` are bound before the first statement, the ones the guest did not
+//! report are left unbound and fail the run when read. The decisions are the values last
+//! assigned to `populate_cap_mib`, which replaces `max_populated_mib`, to `release_mib`, asked
+//! from the guest when it is not 0, and to `budget_mib`, offered to the guest when the budget
+//! negotiation is enabled. The release and the budget are only sent again when they change:
+//!
+//! ```text
+//! # Keep 256 MiB of headroom and give back half of the free memory past 1 GiB.
+//! populate_cap_mib = populated_mib + max(available_mib - 256, 0)
+//! release_mib = if(free_mib > 1024, (free_mib - 1024) / 2, 0)
+//! ```
+//!
+//! Programs run inside the VMM, so they are sandboxed: they cannot loop, call out or allocate,
+//! their size, nesting and number of variables are bounded when they are configured, and every
+//! run is stopped once it goes over its step or time budget.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Longest policy program accepted, in bytes.
+pub const POLICY_MAX_PROGRAM_LEN: usize = 4096;
+/// Deepest nesting of the expressions of a policy program.
+pub const POLICY_MAX_DEPTH: usize = 32;
+/// Most variables a policy program may use, inputs included.
+pub const POLICY_MAX_VARIABLES: usize = 64;
+/// Evaluation steps a run of a policy program may take by default.
+pub const POLICY_DEFAULT_MAX_STEPS: u32 = 10_000;
+/// Time a run of a policy program may take by default, in microseconds.
+pub const POLICY_DEFAULT_MAX_TIME_US: u32 = 1000;
+/// Longest time budget of a run of a policy program, in microseconds. The VMM event loop does
+/// nothing else meanwhile.
+pub const POLICY_MAX_TIME_US: u32 = 10_000;
+/// Variables bound before a policy program runs. The memory sizes are in MiB, the counters are
+/// the totals reported by the guest.
+pub const POLICY_INPUTS: [&str; 10] = [
+    "available_mib",
+    "free_mib",
+    "total_mib",
+    "caches_mib",
+    "swap_in",
+    "swap_out",
+    "major_faults",
+    "minor_faults",
+    "populated_mib",
+    "pinned_mib",
+];
+
+// Variables holding the decisions of a policy program.
+const POPULATE_CAP_MIB: &str = "populate_cap_mib";
+const RELEASE_MIB: &str = "release_mib";
+const BUDGET_MIB: &str = "budget_mib";
+// How many steps are taken between two checks of the time budget.
+const STEPS_PER_CLOCK_CHECK: u64 = 16;
+
+/// Policy program of the device and the budget of its runs.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemPolicyConfig {
+    /// Source of the program.
+    pub program: String,
+    /// Evaluation steps a run may take. Defaults to `POLICY_DEFAULT_MAX_STEPS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_steps: Option<u32>,
+    /// Time a run may take, in microseconds. Defaults to `POLICY_DEFAULT_MAX_TIME_US` and may
+    /// not exceed `POLICY_MAX_TIME_US`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_time_us: Option<u32>,
+}
+
+/// Decisions of the latest run of a policy program. The decisions the program did not make are
+/// left out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemPolicyDecision {
+    /// Cap on the memory populated by the guest, in MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub populate_cap_mib: Option<u32>,
+    /// Memory the guest is asked to release, in MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_mib: Option<u32>,
+    /// Memory budget offered to the guest, in MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_mib: Option<u32>,
+}
+
+/// Errors of the policy programs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PolicyError {
+    /// The program is longer than `POLICY_MAX_PROGRAM_LEN`.
+    ProgramTooLong,
+    /// The time budget exceeds `POLICY_MAX_TIME_US`.
+    TimeBudgetTooLong,
+    /// The program does not parse, at the given byte offset.
+    Syntax(usize, &'static str),
+    /// The expressions of the program are nested deeper than `POLICY_MAX_DEPTH`.
+    TooDeep,
+    /// The program uses more than `POLICY_MAX_VARIABLES` variables.
+    TooManyVariables,
+    /// The program reads a variable it never assigned.
+    UnknownVariable(String),
+    /// The program calls an unknown function, or with the wrong number of arguments.
+    UnknownFunction(String),
+    /// The program reads an input the guest did not report.
+    MissingInput(&'static str),
+    /// The program divided by zero.
+    DivisionByZero,
+    /// The program computed a value that does not fit in 64 bits.
+    Overflow,
+    /// The run went over its step budget.
+    StepBudgetExceeded,
+    /// The run went over its time budget.
+    TimeBudgetExceeded,
+    /// The program decided a negative value, or one too large for the decision.
+    InvalidDecision(&'static str, i64),
+}
+
+impl PolicyError {
+    /// Whether the run was stopped for going over its budget.
+    pub fn is_budget_exceeded(&self) -> bool {
+        matches!(
+            self,
+            PolicyError::StepBudgetExceeded | PolicyError::TimeBudgetExceeded
+        )
+    }
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PolicyError::*;
+        match self {
+            ProgramTooLong => write!(
+                f,
+                "The policy program is longer than {} bytes.",
+                POLICY_MAX_PROGRAM_LEN
+            ),
+            TimeBudgetTooLong => write!(
+                f,
+                "The policy time budget exceeds {}us.",
+                POLICY_MAX_TIME_US
+            ),
+            Syntax(offset, reason) => write!(f, "Syntax error at byte {}: {}.", offset, reason),
+            TooDeep => write!(
+                f,
+                "The policy expressions are nested deeper than {}.",
+                POLICY_MAX_DEPTH
+            ),
+            TooManyVariables => write!(
+                f,
+                "The policy program uses more than {} variables.",
+                POLICY_MAX_VARIABLES
+            ),
+            UnknownVariable(name) => write!(f, "Unknown variable `{}`.", name),
+            UnknownFunction(name) => write!(f, "Unknown function `{}`.", name),
+            MissingInput(name) => write!(f, "The guest did not report `{}`.", name),
+            DivisionByZero => write!(f, "Division by zero."),
+            Overflow => write!(f, "Arithmetic overflow."),
+            StepBudgetExceeded => write!(f, "The policy run went over its step budget."),
+            TimeBudgetExceeded => write!(f, "The policy run went over its time budget."),
+            InvalidDecision(name, value) => write!(f, "Invalid value {} for `{}`.", value, name),
+        }
+    }
+}
+
+/// Values of the inputs of a policy program, in the order of `POLICY_INPUTS`.
+#[derive(Debug, Default)]
+pub(crate) struct PolicyInputs {
+    pub available_mib: Option<u64>,
+    pub free_mib: Option<u64>,
+    pub total_mib: Option<u64>,
+    pub caches_mib: Option<u64>,
+    pub swap_in: Option<u64>,
+    pub swap_out: Option<u64>,
+    pub major_faults: Option<u64>,
+    pub minor_faults: Option<u64>,
+    pub populated_mib: u64,
+    pub pinned_mib: u64,
+}
+
+impl PolicyInputs {
+    fn values(&self) -> [Option<u64>; POLICY_INPUTS.len()] {
+        [
+            self.available_mib,
+            self.free_mib,
+            self.total_mib,
+            self.caches_mib,
+            self.swap_in,
+            self.swap_out,
+            self.major_faults,
+            self.minor_faults,
+            Some(self.populated_mib),
+            Some(self.pinned_mib),
+        ]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Function {
+    Min,
+    Max,
+    If,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Const(i64),
+    Var(usize),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+/// A policy program ready to run.
+#[derive(Debug)]
+pub(crate) struct Policy {
+    config: FaascaleMemPolicyConfig,
+    // Names of the variables, the inputs first.
+    variables: Vec<String>,
+    // Variable assigned by each statement, and its expression.
+    statements: Vec<(usize, Expr)>,
+    max_steps: u64,
+    max_time: Duration,
+}
+
+impl Policy {
+    /// Parses the program of `config`.
+    pub fn new(config: FaascaleMemPolicyConfig) -> Result<Self, PolicyError> {
+        if config.program.len() > POLICY_MAX_PROGRAM_LEN {
+            return Err(PolicyError::ProgramTooLong);
+        }
+        let max_time_us = config.max_time_us.unwrap_or(POLICY_DEFAULT_MAX_TIME_US);
+        if max_time_us > POLICY_MAX_TIME_US {
+            return Err(PolicyError::TimeBudgetTooLong);
+        }
+
+        let mut parser = Parser {
+            source: config.program.as_bytes(),
+            pos: 0,
+            depth: 0,
+            variables: POLICY_INPUTS.iter().map(|name| name.to_string()).collect(),
+        };
+        let statements = parser.program()?;
+        Ok(Policy {
+            variables: parser.variables,
+            statements,
+            max_steps: u64::from(config.max_steps.unwrap_or(POLICY_DEFAULT_MAX_STEPS)),
+            max_time: Duration::from_micros(u64::from(max_time_us)),
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &FaascaleMemPolicyConfig {
+        &self.config
+    }
+
+    /// Runs the program on `inputs` and returns its decisions.
+    pub fn run(&self, inputs: &PolicyInputs) -> Result<FaascaleMemPolicyDecision, PolicyError> {
+        let mut values = vec![None; self.variables.len()];
+        for (value, input) in values.iter_mut().zip(inputs.values()) {
+            *value = input.map(|input| i64::try_from(input).unwrap_or(i64::MAX));
+        }
+
+        let mut run = Run {
+            values,
+            steps: 0,
+            max_steps: self.max_steps,
+            deadline: Instant::now() + self.max_time,
+        };
+        for (variable, expr) in &self.statements {
+            run.values[*variable] = Some(run.eval(expr)?);
+        }
+
+        let decision = |name: &'static str| -> Result<Option<u32>, PolicyError> {
+            let value = self
+                .variables
+                .iter()
+                .position(|variable| variable == name)
+                .and_then(|variable| run.values[variable]);
+            value
+                .map(|value| {
+                    u32::try_from(value).map_err(|_| PolicyError::InvalidDecision(name, value))
+                })
+                .transpose()
+        };
+        Ok(FaascaleMemPolicyDecision {
+            populate_cap_mib: decision(POPULATE_CAP_MIB)?,
+            release_mib: decision(RELEASE_MIB)?,
+            budget_mib: decision(BUDGET_MIB)?,
+        })
+    }
+}
+
+// State of a run of a policy program.
+struct Run {
+    values: Vec<Option<i64>>,
+    steps: u64,
+    max_steps: u64,
+    deadline: Instant,
+}
+
+impl Run {
+    fn eval(&mut self, expr: &Expr) -> Result<i64, PolicyError> {
+        self.steps += 1;
+        if self.steps > self.max_steps {
+            return Err(PolicyError::StepBudgetExceeded);
+        }
+        if self.steps % STEPS_PER_CLOCK_CHECK == 0 && Instant::now() > self.deadline {
+            return Err(PolicyError::TimeBudgetExceeded);
+        }
+
+        Ok(match expr {
+            Expr::Const(value) => *value,
+            Expr::Var(variable) => self.values[*variable].ok_or_else(|| {
+                // Only the inputs can be read unbound, the parser checks the other variables
+                // are assigned first.
+                PolicyError::MissingInput(POLICY_INPUTS[*variable])
+            })?,
+            Expr::Neg(operand) => self
+                .eval(operand)?
+                .checked_neg()
+                .ok_or(PolicyError::Overflow)?,
+            Expr::Not(operand) => i64::from(self.eval(operand)? == 0),
+            Expr::Binary(BinaryOp::And, lhs, rhs) => {
+                i64::from(self.eval(lhs)? != 0 && self.eval(rhs)? != 0)
+            }
+            Expr::Binary(BinaryOp::Or, lhs, rhs) => {
+                i64::from(self.eval(lhs)? != 0 || self.eval(rhs)? != 0)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (self.eval(lhs)?, self.eval(rhs)?);
+                binary(*op, lhs, rhs)?
+            }
+            Expr::Call(Function::If, args) => {
+                if self.eval(&args[0])? != 0 {
+                    self.eval(&args[1])?
+                } else {
+                    self.eval(&args[2])?
+                }
+            }
+            Expr::Call(Function::Min, args) => {
+                let (a, b) = (self.eval(&args[0])?, self.eval(&args[1])?);
+                a.min(b)
+            }
+            Expr::Call(Function::Max, args) => {
+                let (a, b) = (self.eval(&args[0])?, self.eval(&args[1])?);
+                a.max(b)
+            }
+        })
+    }
+}
+
+fn binary(op: BinaryOp, lhs: i64, rhs: i64) -> Result<i64, PolicyError> {
+    let value = match op {
+        BinaryOp::Add => lhs.checked_add(rhs),
+        BinaryOp::Sub => lhs.checked_sub(rhs),
+        BinaryOp::Mul => lhs.checked_mul(rhs),
+        BinaryOp::Div | BinaryOp::Rem if rhs == 0 => return Err(PolicyError::DivisionByZero),
+        BinaryOp::Div => lhs.checked_div(rhs),
+        BinaryOp::Rem => lhs.checked_rem(rhs),
+        BinaryOp::Lt => Some(i64::from(lhs < rhs)),
+        BinaryOp::Le => Some(i64::from(lhs <= rhs)),
+        BinaryOp::Gt => Some(i64::from(lhs > rhs)),
+        BinaryOp::Ge => Some(i64::from(lhs >= rhs)),
+        BinaryOp::Eq => Some(i64::from(lhs == rhs)),
+        BinaryOp::Ne => Some(i64::from(lhs != rhs)),
+        BinaryOp::And | BinaryOp::Or => unreachable!("short-circuited by the caller"),
+    };
+    value.ok_or(PolicyError::Overflow)
+}
+
+// Recursive descent parser of the policy programs.
+struct Parser<'a> {
+    source: &'a [u8],
+    pos: usize,
+    depth: usize,
+    variables: Vec<String>,
+}
+
+impl Parser<'_> {
+    fn program(&mut self) -> Result<Vec<(usize, Expr)>, PolicyError> {
+        let mut statements = Vec::new();
+        loop {
+            self.skip_blanks(true);
+            if self.pos == self.source.len() {
+                return Ok(statements);
+            }
+            if self.eat(b";") {
+                continue;
+            }
+
+            let name = self
+                .ident()
+                .ok_or(PolicyError::Syntax(self.pos, "expected a variable"))?;
+            self.skip_blanks(false);
+            if !self.eat(b"=") || self.peek() == Some(b'=') {
+                return Err(PolicyError::Syntax(self.pos, "expected `=`"));
+            }
+            let expr = self.or()?;
+            // Inputs can be overridden, the new value is only seen by the next statements.
+            let variable = self.define(name)?;
+            statements.push((variable, expr));
+
+            self.skip_blanks(false);
+            match self.peek() {
+                None | Some(b';') | Some(b'\n') | Some(b'#') => {}
+                Some(_) => {
+                    return Err(PolicyError::Syntax(
+                        self.pos,
+                        "expected the end of the statement",
+                    ))
+                }
+            }
+        }
+    }
+
+    fn define(&mut self, name: String) -> Result<usize, PolicyError> {
+        if let Some(variable) = self.variables.iter().position(|variable| *variable == name) {
+            return Ok(variable);
+        }
+        if self.variables.len() == POLICY_MAX_VARIABLES {
+            return Err(PolicyError::TooManyVariables);
+        }
+        self.variables.push(name);
+        Ok(self.variables.len() - 1)
+    }
+
+    fn or(&mut self) -> Result<Expr, PolicyError> {
+        let mut lhs = self.and()?;
+        while self.op(b"||") {
+            lhs = Expr::Binary(BinaryOp::Or, Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, PolicyError> {
+        let mut lhs = self.comparison()?;
+        while self.op(b"&&") {
+            lhs = Expr::Binary(BinaryOp::And, Box::new(lhs), Box::new(self.comparison()?));
+        }
+        Ok(lhs)
+    }
+
+    // Comparisons do not chain.
+    fn comparison(&mut self) -> Result<Expr, PolicyError> {
+        let lhs = self.sum()?;
+        let op = if self.op(b"<=") {
+            BinaryOp::Le
+        } else if self.op(b">=") {
+            BinaryOp::Ge
+        } else if self.op(b"==") {
+            BinaryOp::Eq
+        } else if self.op(b"!=") {
+            BinaryOp::Ne
+        } else if self.op(b"<") {
+            BinaryOp::Lt
+        } else if self.op(b">") {
+            BinaryOp::Gt
+        } else {
+            return Ok(lhs);
+        };
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, PolicyError> {
+        let mut lhs = self.product()?;
+        loop {
+            let op = if self.op(b"+") {
+                BinaryOp::Add
+            } else if self.op(b"-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, PolicyError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.op(b"*") {
+                BinaryOp::Mul
+            } else if self.op(b"/") {
+                BinaryOp::Div
+            } else if self.op(b"%") {
+                BinaryOp::Rem
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, PolicyError> {
+        self.depth += 1;
+        if self.depth > POLICY_MAX_DEPTH {
+            return Err(PolicyError::TooDeep);
+        }
+        let expr = if self.op(b"-") {
+            Expr::Neg(Box::new(self.unary()?))
+        } else if self.op(b"!") {
+            Expr::Not(Box::new(self.unary()?))
+        } else {
+            self.primary()?
+        };
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, PolicyError> {
+        self.skip_blanks(false);
+        if self.eat(b"(") {
+            let expr = self.or()?;
+            if !self.op(b")") {
+                return Err(PolicyError::Syntax(self.pos, "expected `)`"));
+            }
+            return Ok(expr);
+        }
+        if let Some(digits) = self.take_while(|byte| byte.is_ascii_digit()) {
+            return digits
+                .parse()
+                .map(Expr::Const)
+                .map_err(|_| PolicyError::Overflow);
+        }
+
+        let name = self
+            .ident()
+            .ok_or(PolicyError::Syntax(self.pos, "expected an expression"))?;
+        if self.op(b"(") {
+            let mut args = vec![self.or()?];
+            while self.op(b",") {
+                args.push(self.or()?);
+            }
+            if !self.op(b")") {
+                return Err(PolicyError::Syntax(self.pos, "expected `)`"));
+            }
+            let function = match (name.as_str(), args.len()) {
+                ("min", 2) => Function::Min,
+                ("max", 2) => Function::Max,
+                ("if", 3) => Function::If,
+                _ => return Err(PolicyError::UnknownFunction(name)),
+            };
+            return Ok(Expr::Call(function, args));
+        }
+        self.variables
+            .iter()
+            .position(|variable| *variable == name)
+            .map(Expr::Var)
+            .ok_or(PolicyError::UnknownVariable(name))
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        if !self.peek()?.is_ascii_alphabetic() && self.peek()? != b'_' {
+            return None;
+        }
+        self.take_while(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+    }
+
+    fn take_while(&mut self, pred: impl Fn(u8) -> bool) -> Option<String> {
+        let start = self.pos;
+        while self.peek().map_or(false, &pred) {
+            self.pos += 1;
+        }
+        (self.pos > start)
+            .then(|| String::from_utf8_lossy(&self.source[start..self.pos]).into_owned())
+    }
+
+    // Skips the blanks and comments, and the new lines if `newlines` is set. Expressions do
+    // not span lines.
+    fn skip_blanks(&mut self, newlines: bool) {
+        while let Some(byte) = self.peek() {
+            match byte {
+                b'#' => {
+                    while self.peek().map_or(false, |byte| byte != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                b'\n' if !newlines => return,
+                _ if byte.is_ascii_whitespace() => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    // Consumes the operator `token`, skipping the blanks before it.
+    fn op(&mut self, token: &[u8]) -> bool {
+        self.skip_blanks(false);
+        self.eat(token)
+    }
+
+    fn eat(&mut self, token: &[u8]) -> bool {
+        if self.source[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.get(self.pos).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(program: &str) -> Result<Policy, PolicyError> {
+        Policy::new(FaascaleMemPolicyConfig {
+            program: program.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn inputs() -> PolicyInputs {
+        PolicyInputs {
+            available_mib: Some(300),
+            free_mib: Some(2048),
+            total_mib: Some(4096),
+            populated_mib: 1024,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_policy_decisions() {
+        let program = "
+            # Keep 256 MiB of headroom and give back half of the free memory past 1 GiB.
+            populate_cap_mib = populated_mib + max(available_mib - 256, 0)
+            release_mib = if(free_mib > 1024, (free_mib - 1024) / 2, 0); budget_mib = 3 * 1024
+        ";
+        assert_eq!(
+            policy(program).unwrap().run(&inputs()).unwrap(),
+            FaascaleMemPolicyDecision {
+                populate_cap_mib: Some(1068),
+                release_mib: Some(512),
+                budget_mib: Some(3072),
+            }
+        );
+
+        // Decisions left out are not made, and locals can be reassigned.
+        let policy = policy("x = 7 % 4 * -2\nx = x + 10\nbudget_mib = x").unwrap();
+        assert_eq!(
+            policy.run(&inputs()).unwrap(),
+            FaascaleMemPolicyDecision {
+                budget_mib: Some(4),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_policy_operators() {
+        let eval = |expr: &str| {
+            policy(&format!("budget_mib = {}", expr))
+                .unwrap()
+                .run(&inputs())
+                .map(|decision| decision.budget_mib.unwrap())
+        };
+        assert_eq!(eval("1 + 2 * 3 - 4 / 2"), Ok(5));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("10 - 2 - 3"), Ok(5));
+        assert_eq!(
+            eval("1 < 2 && 2 <= 2 && 3 > 2 && 3 >= 3 && 1 == 1 && 1 != 2"),
+            Ok(1)
+        );
+        assert_eq!(eval("0 || !0"), Ok(1));
+        assert_eq!(eval("min(3, 5) + max(3, 5)"), Ok(8));
+        // The branch not taken and the right side of a decided `||` are not evaluated.
+        assert_eq!(eval("if(1, 2, 1 / 0)"), Ok(2));
+        assert_eq!(eval("1 || 1 / 0"), Ok(1));
+        assert_eq!(eval("1 / 0"), Err(PolicyError::DivisionByZero));
+        assert_eq!(eval("9223372036854775807 + 1"), Err(PolicyError::Overflow));
+        assert_eq!(
+            eval("-1"),
+            Err(PolicyError::InvalidDecision(BUDGET_MIB, -1))
+        );
+        assert_eq!(
+            eval("4294967296"),
+            Err(PolicyError::InvalidDecision(BUDGET_MIB, 1 << 32))
+        );
+        // Inputs the guest did not report fail the run.
+        assert_eq!(
+            eval("caches_mib"),
+            Err(PolicyError::MissingInput("caches_mib"))
+        );
+    }
+
+    #[test]
+    fn test_policy_parse_errors() {
+        assert!(matches!(
+            policy("budget_mib 1"),
+            Err(PolicyError::Syntax(11, _))
+        ));
+        assert!(matches!(
+            policy("budget_mib == 1"),
+            Err(PolicyError::Syntax(..))
+        ));
+        assert!(matches!(
+            policy("budget_mib = (1"),
+            Err(PolicyError::Syntax(..))
+        ));
+        assert!(matches!(
+            policy("budget_mib = 1 1"),
+            Err(PolicyError::Syntax(..))
+        ));
+        assert!(matches!(
+            policy("budget_mib = 1 +\n 1"),
+            Err(PolicyError::Syntax(..))
+        ));
+        assert!(matches!(policy("= 1"), Err(PolicyError::Syntax(0, _))));
+        assert_eq!(
+            policy("budget_mib = x").unwrap_err(),
+            PolicyError::UnknownVariable("x".to_string())
+        );
+        assert_eq!(
+            policy("budget_mib = min(1)").unwrap_err(),
+            PolicyError::UnknownFunction("min".to_string())
+        );
+        assert_eq!(
+            policy("budget_mib = 99999999999999999999").unwrap_err(),
+            PolicyError::Overflow
+        );
+    }
+
+    #[test]
+    fn test_policy_limits() {
+        assert_eq!(
+            policy(&"#".repeat(POLICY_MAX_PROGRAM_LEN + 1)).unwrap_err(),
+            PolicyError::ProgramTooLong
+        );
+        assert_eq!(
+            Policy::new(FaascaleMemPolicyConfig {
+                program: String::new(),
+                max_time_us: Some(POLICY_MAX_TIME_US + 1),
+                ..Default::default()
+            })
+            .unwrap_err(),
+            PolicyError::TimeBudgetTooLong
+        );
+        let nested = format!("x = {}1{}", "(".repeat(40), ")".repeat(40));
+        assert_eq!(policy(&nested).unwrap_err(), PolicyError::TooDeep);
+        let variables: String = (0..POLICY_MAX_VARIABLES)
+            .map(|i| format!("v{} = 0\n", i))
+            .collect();
+        assert_eq!(
+            policy(&variables).unwrap_err(),
+            PolicyError::TooManyVariables
+        );
+
+        // Runs are stopped once they go over their step budget.
+        let policy = Policy::new(FaascaleMemPolicyConfig {
+            program: "x = 1 + 1 + 1\nx = x + x + x".to_string(),
+            max_steps: Some(8),
+            ..Default::default()
+        })
+        .unwrap();
+        let err = policy.run(&inputs()).unwrap_err();
+        assert_eq!(err, PolicyError::StepBudgetExceeded);
+        assert!(err.is_budget_exceeded());
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dedicated thread handling the populate queue kicks when the faascale-mem device runs in
+//! latency mode, so that populate requests are not queued behind the other events of the
+//! VMM event loop.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use logger::{error, IncMetric, METRICS};
+use seccompiler::BpfProgram;
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::eventfd::EventFd;
+
+use super::device::FaascaleMem;
+use super::{Error as FaascaleMemError, POPULATE_INDEX};
+use crate::devices::report_faascale_mem_event_fail;
+use crate::devices::virtio::VirtioDevice;
+
+/// How long the poller keeps spinning on the populate queue after a kick, to catch the
+/// follow-up kicks of a burst without going back to sleep.
+pub(crate) const POPULATE_BUSY_POLL_WINDOW: Duration = Duration::from_micros(200);
+// Longest the poller sleeps before checking whether the device is still alive.
+const POPULATE_POLLER_IDLE_TIMEOUT_MS: i32 = 100;
+// Data of the event waking the poller up when the device is dropped, the kicks carry the index
+// of the populate queue.
+const STOP_EVENT_DATA: u64 = u64::MAX;
+
+/// Starts the thread handling the populate queue kicks of `faascale_mem`, running under
+/// `seccomp_filter` with the scheduling of the worker threads of the device. The thread exits
+/// once the device is dropped, which joins it.
+pub(crate) fn spawn_populate_poller(
+    faascale_mem: &Arc<Mutex<FaascaleMem>>,
+    seccomp_filter: Arc<BpfProgram>,
+) -> Result<(), FaascaleMemError> {
+    let (populate_evt, stop_evt, worker_scheduling) = {
+        let faascale_mem = faascale_mem.lock().expect("Poisoned lock");
+        let populate_evt = faascale_mem.queue_evts[POPULATE_INDEX]
+            .try_clone()
+            .map_err(FaascaleMemError::EventFd)?;
+        let stop_evt = faascale_mem
+            .worker_stop_evt
+            .try_clone()
+            .map_err(FaascaleMemError::EventFd)?;
+        (
+            populate_evt,
+            stop_evt,
+            faascale_mem.worker_scheduling.clone(),
+        )
+    };
+    let epoll = Epoll::new().map_err(FaascaleMemError::PopulatePoller)?;
+    epoll
+        .ctl(
+            ControlOperation::Add,
+            populate_evt.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, POPULATE_INDEX as u64),
+        )
+        .map_err(FaascaleMemError::PopulatePoller)?;
+    epoll
+        .ctl(
+            ControlOperation::Add,
+            stop_evt.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, STOP_EVENT_DATA),
+        )
+        .map_err(FaascaleMemError::PopulatePoller)?;
+
+    let device = Arc::downgrade(faascale_mem);
+    let handle = thread::Builder::new()
+        .name("fc_faascale_populate".to_string())
+        .spawn(move || {
+            // Keeps the stop event registered for as long as the thread runs.
+            let _stop_evt = stop_evt;
+            // The system calls setting the scheduling are left out of the filters.
+            if let Some(worker_scheduling) = worker_scheduling {
+                let applied = worker_scheduling.apply_to_current_thread();
+                if let Some(device) = device.upgrade() {
+                    let mut faascale_mem = device.lock().expect("Poisoned lock");
+                    faascale_mem.applied_worker_scheduling = Some(applied);
+                }
+            }
+            // Execution panics if filters cannot be loaded, use --no-seccomp if skipping
+            // filters altogether is the desired behaviour.
+            if let Err(err) = seccompiler::apply_filter(&seccomp_filter) {
+                panic!(
+                    "Failed to set the requested seccomp filters on the populate poller: {}",
+                    err
+                );
+            }
+            run_populate_poller(&device, &epoll, &populate_evt)
+        })
+        .map_err(FaascaleMemError::PopulatePoller)?;
+    faascale_mem
+        .lock()
+        .expect("Poisoned lock")
+        .worker_threads
+        .push(("populate_poller", handle));
+    Ok(())
+}
+
+fn run_populate_poller(device: &Weak<Mutex<FaascaleMem>>, epoll: &Epoll, populate_evt: &EventFd) {
+    let mut events = [EpollEvent::default(); 2];
+    let mut last_kick: Option<Instant> = None;
+
+    loop {
+        // Spin right after a kick, then fall back to a blocking wait.
+        let timeout = match last_kick {
+            Some(kicked_at) if kicked_at.elapsed() < POPULATE_BUSY_POLL_WINDOW => 0,
+            _ => POPULATE_POLLER_IDLE_TIMEOUT_MS,
+        };
+        let ready = match epoll.wait(timeout, &mut events) {
+            Ok(ready) => ready,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                error!("Failed to wait for populate queue events: {}", err);
+                return;
+            }
+        };
+        let device = match device.upgrade() {
+            Some(device) => device,
+            None => return,
+        };
+        if ready == 0 {
+            continue;
+        }
+
+        let kicked_at = Instant::now();
+        last_kick = Some(kicked_at);
+        METRICS.faascale_mem.populate_poller_wakeups.inc();
+
+        if events[..ready]
+            .iter()
+            .all(|event| event.data() == STOP_EVENT_DATA)
+        {
+            continue;
+        }
+        let mut faascale_mem = device.lock().expect("Poisoned lock");
+        if faascale_mem.is_activated() {
+            faascale_mem
+                .process_populate_queue_event(kicked_at)
+                .unwrap_or_else(report_faascale_mem_event_fail);
+        } else if let Err(err) = populate_evt.read() {
+            // Drain the kick, the queue cannot be processed before activation.
+            error!("Failed to consume populate queue event: {:?}", err);
+        }
+    }
+}
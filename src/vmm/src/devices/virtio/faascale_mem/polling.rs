@@ -0,0 +1,148 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Adaptation of the statistics polling interval to the memory pressure of the guest.
+//!
+//! The configured polling interval is the baseline. Every sample in which the available memory
+//! of the guest dropped quickly halves the interval, down to the minimum set by the host, so
+//! that the pressure is noticed sooner. Every other sample doubles it back, up to the baseline.
+
+use std::cmp;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Drop of the available memory between two samples, in percent of the previous sample, above
+/// which the polling interval is shortened.
+pub const POLLING_ADAPTATION_DROP_PERCENT: u64 = 10;
+/// Shortest polling interval ever used, whatever the minimum set by the host.
+pub const POLLING_ADAPTATION_FLOOR: Duration = Duration::from_millis(100);
+
+/// State of the adaptation of the statistics polling interval.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemPollingAdaptation {
+    /// Configured polling interval, in milliseconds.
+    pub baseline_ms: u64,
+    /// Polling interval in use, in milliseconds.
+    pub effective_ms: u64,
+    /// Shortest polling interval allowed, in milliseconds.
+    pub min_ms: u64,
+}
+
+/// Shortens and lengthens the polling interval following the available memory samples.
+#[derive(Debug)]
+pub(crate) struct PollingAdaptation {
+    baseline: Duration,
+    min: Duration,
+    effective: Duration,
+}
+
+impl PollingAdaptation {
+    pub fn new(baseline: Duration, min: Duration) -> Self {
+        PollingAdaptation {
+            baseline,
+            min: cmp::max(min, POLLING_ADAPTATION_FLOOR),
+            effective: baseline,
+        }
+    }
+
+    /// Polling interval in use.
+    pub fn interval(&self) -> Duration {
+        self.effective
+    }
+
+    /// Replaces the baseline, which is also the interval in use until the next sample.
+    pub fn set_baseline(&mut self, baseline: Duration) {
+        self.baseline = baseline;
+        self.reset();
+    }
+
+    /// Goes back to the baseline.
+    pub fn reset(&mut self) {
+        self.effective = self.baseline;
+    }
+
+    /// Adapts the interval to the change of the available memory from its `previous` to its
+    /// `current` sample, and returns whether the interval changed.
+    pub fn sample(&mut self, previous: Option<u64>, current: Option<u64>) -> bool {
+        let (previous, current) = match (previous, current) {
+            (Some(previous), Some(current)) => (previous, current),
+            _ => return false,
+        };
+        let dropping = u128::from(previous.saturating_sub(current)) * 100
+            > u128::from(previous) * u128::from(POLLING_ADAPTATION_DROP_PERCENT);
+        // The baseline wins over the minimum when it is the shorter one.
+        let effective = if dropping {
+            cmp::min(cmp::max(self.effective / 2, self.min), self.baseline)
+        } else {
+            cmp::min(self.effective * 2, self.baseline)
+        };
+        let changed = effective != self.effective;
+        self.effective = effective;
+        changed
+    }
+
+    pub fn report(&self) -> FaascaleMemPollingAdaptation {
+        let millis = |duration: Duration| u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        FaascaleMemPollingAdaptation {
+            baseline_ms: millis(self.baseline),
+            effective_ms: millis(self.effective),
+            min_ms: millis(self.min),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_polling_adaptation() {
+        let mut adaptation =
+            PollingAdaptation::new(Duration::from_secs(4), Duration::from_millis(1500));
+        assert_eq!(adaptation.interval(), Duration::from_secs(4));
+
+        // Missing samples leave the interval alone.
+        assert!(!adaptation.sample(None, Some(1000)));
+        assert!(!adaptation.sample(Some(1000), None));
+
+        // A quick drop halves the interval, down to the minimum.
+        assert!(adaptation.sample(Some(1000), Some(850)));
+        assert_eq!(adaptation.interval(), Duration::from_secs(2));
+        assert!(adaptation.sample(Some(850), Some(700)));
+        assert_eq!(adaptation.interval(), Duration::from_millis(1500));
+        assert!(!adaptation.sample(Some(700), Some(500)));
+        assert_eq!(
+            adaptation.report(),
+            FaascaleMemPollingAdaptation {
+                baseline_ms: 4000,
+                effective_ms: 1500,
+                min_ms: 1500,
+            }
+        );
+
+        // A slow drop or growth doubles it back, up to the baseline.
+        assert!(adaptation.sample(Some(500), Some(460)));
+        assert_eq!(adaptation.interval(), Duration::from_secs(3));
+        assert!(adaptation.sample(Some(460), Some(900)));
+        assert_eq!(adaptation.interval(), Duration::from_secs(4));
+        assert!(!adaptation.sample(Some(900), Some(900)));
+
+        adaptation.sample(Some(900), Some(100));
+        adaptation.set_baseline(Duration::from_secs(1));
+        assert_eq!(adaptation.interval(), Duration::from_secs(1));
+        // The baseline is never exceeded, even when it is below the minimum.
+        assert!(!adaptation.sample(Some(1000), Some(100)));
+        assert_eq!(adaptation.interval(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_polling_adaptation_floor() {
+        let mut adaptation = PollingAdaptation::new(Duration::from_secs(1), Duration::ZERO);
+        for _ in 0..10 {
+            adaptation.sample(Some(1000), Some(100));
+        }
+        assert_eq!(adaptation.interval(), POLLING_ADAPTATION_FLOOR);
+    }
+}
@@ -0,0 +1,449 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host memory pool reserved for the populate requests of a microVM.
+//!
+//! The pool is a mapping of host memory faulted in, and locked or taken from the hugetlb pool,
+//! when the device is created. Populated blocks are backed first by moving chunks of the pool
+//! into the guest memory with `mremap`, which cannot fail for lack of host memory. Blocks the
+//! pool cannot back, because it is exhausted or because they are not aligned on its 2 MiB
+//! chunks, fall back to the normal population path. Chunks depopulated by the guest are
+//! reserved again.
+//!
+//! Each chunk moved into the guest memory is a mapping of its own, so the chunks are large
+//! enough to keep their number well under the `vm.max_map_count` of the host. The chunks
+//! replace the mapping of the guest memory, which is only possible for anonymous private
+//! memory of 4K pages that no userfaultfd serves.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use logger::{error, IncMetric, StoreMetric, METRICS};
+use serde::{Deserialize, Serialize};
+use utils::vm_memory::{
+    hugetlb_page_size, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+use super::util::range_in_region;
+use super::Error;
+
+// Size of a chunk of the pool, a huge page of the hugetlb pools.
+const CHUNK_SIZE: u64 = 2 << 20;
+const MIB: u64 = 1 << 20;
+
+/// How the memory of the pool is reserved on the host.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaascaleMemPoolKind {
+    /// Anonymous memory faulted in and locked with `mlock`.
+    #[default]
+    Mlock,
+    /// 2 MiB pages taken from the hugetlb pool of the host.
+    Hugetlb,
+}
+
+/// Host memory reserved for the populate requests of the microVM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemPoolConfig {
+    /// Size of the pool, in MiB.
+    pub size_mib: u32,
+    /// How the memory of the pool is reserved.
+    #[serde(default)]
+    pub kind: FaascaleMemPoolKind,
+}
+
+/// Usage of the reserved host memory pool, as reported by the API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemPoolUsage {
+    /// How the memory of the pool is reserved.
+    pub kind: FaascaleMemPoolKind,
+    /// Size of the pool, in MiB.
+    pub size_mib: u32,
+    /// Memory of the pool lent to the guest, in MiB.
+    pub used_mib: u64,
+    /// Memory of the pool that could not be reserved again after the guest gave it back, in
+    /// MiB.
+    pub lost_mib: u64,
+    /// Number of populate blocks the pool could not back.
+    pub fallbacks: u64,
+}
+
+/// Reserved host memory pool backing the populated blocks.
+#[derive(Debug)]
+pub(crate) struct HostMemoryPool {
+    config: FaascaleMemPoolConfig,
+    // Start of the pool mapping. Chunks lent to the guest leave holes in it.
+    host_addr: usize,
+    // Offsets in the pool of the chunks still reserved.
+    free_chunks: Vec<u64>,
+    // Offsets in the pool of the chunks lent to the guest, by guest physical address.
+    lent_chunks: BTreeMap<u64, u64>,
+    // Number of chunks that could not be reserved again.
+    lost_chunks: u64,
+    fallbacks: u64,
+}
+
+impl HostMemoryPool {
+    /// Reserves the pool on the host.
+    pub fn new(config: FaascaleMemPoolConfig) -> Result<Self, Error> {
+        let size = u64::from(config.size_mib) * MIB;
+        if size == 0 || size % CHUNK_SIZE != 0 {
+            return Err(Error::InvalidPoolSize);
+        }
+
+        let mut pool = HostMemoryPool {
+            config,
+            host_addr: 0,
+            free_chunks: Vec::new(),
+            lent_chunks: BTreeMap::new(),
+            lost_chunks: 0,
+            fallbacks: 0,
+        };
+        // SAFETY: A new mapping is created, no memory is aliased.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                pool.map_flags(),
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::HostMemoryPool(io::Error::last_os_error()));
+        }
+        pool.host_addr = addr as usize;
+        // The chunks at the start of the pool are lent first.
+        pool.free_chunks = (0..size / CHUNK_SIZE)
+            .rev()
+            .map(|chunk| chunk * CHUNK_SIZE)
+            .collect();
+        Ok(pool)
+    }
+
+    /// Checks that the pool can back `guest_memory`, served by a userfaultfd if `uffd` is set:
+    /// the chunks of the pool only replace anonymous private memory of 4K pages.
+    pub fn check_guest_memory(guest_memory: &GuestMemoryMmap, uffd: bool) -> Result<(), Error> {
+        let supported = !uffd
+            && guest_memory.iter().all(|region| {
+                region.file_offset().is_none()
+                    && region.flags() & libc::MAP_PRIVATE != 0
+                    && hugetlb_page_size(region.flags()).is_none()
+            });
+        if supported {
+            Ok(())
+        } else {
+            Err(Error::PoolUnsupportedMemory)
+        }
+    }
+
+    fn map_flags(&self) -> libc::c_int {
+        let reserve = match self.config.kind {
+            FaascaleMemPoolKind::Mlock => libc::MAP_LOCKED,
+            FaascaleMemPoolKind::Hugetlb => libc::MAP_HUGETLB,
+        };
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE | reserve
+    }
+
+    /// Backs the guest memory range with chunks of the pool. Returns whether the whole range
+    /// is backed by the pool, otherwise it must go through the normal population path.
+    pub fn populate(&mut self, guest_memory: &GuestMemoryMmap, range: (GuestAddress, u64)) -> bool {
+        let (guest_address, range_len) = range;
+        let num_chunks = range_len / CHUNK_SIZE;
+        let host_address = match guest_memory.get_host_address(guest_address) {
+            Ok(host_address) => host_address as u64,
+            Err(_) => return false,
+        };
+        if range_len == 0
+            || range_len % CHUNK_SIZE != 0
+            || host_address % CHUNK_SIZE != 0
+            || num_chunks > self.free_chunks.len() as u64
+            || guest_memory
+                .find_region(guest_address)
+                .map_or(true, |region| !range_in_region(region, range))
+        {
+            self.fallback();
+            return false;
+        }
+
+        for chunk in 0..num_chunks {
+            let chunk_address = guest_address.0 + chunk * CHUNK_SIZE;
+            if self.lent_chunks.contains_key(&chunk_address) {
+                continue;
+            }
+            // Checked above.
+            let offset = self.free_chunks.pop().unwrap();
+            // SAFETY: The chunk is part of the pool mapping and the target is part of the
+            // guest memory, whose content is replaced by the content of the chunk.
+            let ret = unsafe {
+                libc::mremap(
+                    (self.host_addr as u64 + offset) as *mut libc::c_void,
+                    CHUNK_SIZE as usize,
+                    CHUNK_SIZE as usize,
+                    libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+                    (host_address + chunk * CHUNK_SIZE) as *mut libc::c_void,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                error!(
+                    "Error lending a chunk of the host memory pool: {}",
+                    io::Error::last_os_error()
+                );
+                self.free_chunks.push(offset);
+                self.fallback();
+                return false;
+            }
+            self.lent_chunks.insert(chunk_address, offset);
+        }
+        METRICS
+            .faascale_mem
+            .pool_populated_bytes
+            .add(range_len as usize);
+        self.update_used_bytes();
+        true
+    }
+
+    /// Takes back the chunks of the pool lent to the guest memory range, which the guest
+    /// depopulated, and reserves them again.
+    pub fn depopulate(&mut self, guest_memory: &GuestMemoryMmap, range: (GuestAddress, u64)) {
+        let (guest_address, range_len) = range;
+        let returned: Vec<(u64, u64)> = self
+            .lent_chunks
+            .range(guest_address.0..guest_address.0 + range_len)
+            .filter(|&(&chunk_address, _)| {
+                chunk_address + CHUNK_SIZE <= guest_address.0 + range_len
+            })
+            .map(|(&chunk_address, &offset)| (chunk_address, offset))
+            .collect();
+        if returned.is_empty() {
+            return;
+        }
+
+        for (chunk_address, offset) in returned {
+            self.lent_chunks.remove(&chunk_address);
+            if let Err(err) = self.reclaim(guest_memory, chunk_address, offset) {
+                error!(
+                    "Error reserving a chunk of the host memory pool again: {}",
+                    err
+                );
+                self.lost_chunks += 1;
+                continue;
+            }
+            self.free_chunks.push(offset);
+        }
+        self.update_used_bytes();
+    }
+
+    // Gives the guest memory chunk its plain anonymous mapping back, which releases the memory
+    // of the pool, and reserves a new chunk in the hole left in the pool.
+    fn reclaim(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        chunk_address: u64,
+        offset: u64,
+    ) -> io::Result<()> {
+        let host_address = guest_memory
+            .get_host_address(GuestAddress(chunk_address))
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // SAFETY: The chunk is part of the guest memory, which the guest gave back.
+        let ret = unsafe {
+            libc::mmap(
+                host_address.cast(),
+                CHUNK_SIZE as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_FIXED | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: The hole is part of the pool mapping, and not used since its chunk was lent.
+        let ret = unsafe {
+            libc::mmap(
+                (self.host_addr as u64 + offset) as *mut libc::c_void,
+                CHUNK_SIZE as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_FIXED | self.map_flags(),
+                -1,
+                0,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn fallback(&mut self) {
+        self.fallbacks += 1;
+        METRICS.faascale_mem.pool_fallbacks.inc();
+    }
+
+    fn update_used_bytes(&self) {
+        METRICS
+            .faascale_mem
+            .pool_used_bytes
+            .store(self.lent_chunks.len() * CHUNK_SIZE as usize);
+    }
+
+    /// Reports the usage of the pool.
+    pub fn usage(&self) -> FaascaleMemPoolUsage {
+        FaascaleMemPoolUsage {
+            kind: self.config.kind,
+            size_mib: self.config.size_mib,
+            used_mib: self.lent_chunks.len() as u64 * CHUNK_SIZE / MIB,
+            lost_mib: self.lost_chunks * CHUNK_SIZE / MIB,
+            fallbacks: self.fallbacks,
+        }
+    }
+
+    pub fn config(&self) -> FaascaleMemPoolConfig {
+        self.config
+    }
+}
+
+impl Drop for HostMemoryPool {
+    fn drop(&mut self) {
+        // SAFETY: The pool mapping is not used anymore. The holes left by the lent chunks are
+        // skipped by `munmap`, the lent chunks stay mapped in the guest memory.
+        unsafe {
+            libc::munmap(
+                self.host_addr as *mut libc::c_void,
+                (u64::from(self.config.size_mib) * MIB) as usize,
+            )
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use utils::tempfile::TempFile;
+    use utils::vm_memory::test_utils::create_anon_guest_memory;
+    use utils::vm_memory::{create_guest_memory, Bytes, FileOffset};
+
+    use super::*;
+    use crate::seccomp_filters::{get_filters, SeccompConfig, FAASCALE_MEM_VMM_CATEGORY};
+
+    #[test]
+    fn test_host_memory_pool() {
+        assert!(matches!(
+            HostMemoryPool::new(FaascaleMemPoolConfig {
+                size_mib: 1,
+                kind: FaascaleMemPoolKind::Mlock,
+            }),
+            Err(Error::InvalidPoolSize)
+        ));
+
+        // The locked memory limit may be too low for the pool.
+        let mut pool = match HostMemoryPool::new(FaascaleMemPoolConfig {
+            size_mib: 2,
+            kind: FaascaleMemPoolKind::Mlock,
+        }) {
+            Ok(pool) => pool,
+            Err(Error::HostMemoryPool(_)) => return,
+            Err(err) => panic!("Unexpected error: {:?}", err),
+        };
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x80_0000)], false).unwrap();
+        // The chunks are aligned in the host address space.
+        let start = (0..CHUNK_SIZE)
+            .step_by(0x1000)
+            .map(GuestAddress)
+            .find(|&addr| mem.get_host_address(addr).unwrap() as u64 % CHUNK_SIZE == 0)
+            .unwrap();
+        let next = GuestAddress(start.0 + CHUNK_SIZE);
+        mem.write_obj(0xdead_u32, start).unwrap();
+
+        // The chunks of the pool replace the content of the guest memory.
+        assert!(pool.populate(&mem, (start, CHUNK_SIZE)));
+        assert_eq!(mem.read_obj::<u32>(start).unwrap(), 0);
+        mem.write_obj(0xbeef_u32, start).unwrap();
+        assert_eq!(pool.lent_chunks.len(), 1);
+
+        // Blocks the pool cannot hold fall back to the normal population path.
+        assert!(!pool.populate(&mem, (next, CHUNK_SIZE)));
+        assert!(!pool.populate(&mem, (GuestAddress(start.0 + 0x1000), 0x2000)));
+        assert_eq!(pool.usage().fallbacks, 2);
+
+        // Chunks given back are reserved again, and the guest memory reads back as zero.
+        pool.depopulate(&mem, (start, CHUNK_SIZE));
+        assert_eq!(mem.read_obj::<u32>(start).unwrap(), 0);
+        assert!(pool.lent_chunks.is_empty());
+        assert!(pool.populate(&mem, (next, CHUNK_SIZE)));
+        assert_eq!(
+            pool.usage(),
+            FaascaleMemPoolUsage {
+                kind: FaascaleMemPoolKind::Mlock,
+                size_mib: 2,
+                used_mib: 2,
+                lost_mib: 0,
+                fallbacks: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_host_memory_pool_seccomp() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x80_0000)], false).unwrap();
+        let start = (0..CHUNK_SIZE)
+            .step_by(0x1000)
+            .map(GuestAddress)
+            .find(|&addr| mem.get_host_address(addr).unwrap() as u64 % CHUNK_SIZE == 0)
+            .unwrap();
+        let filter = get_filters(SeccompConfig::Advanced)
+            .unwrap()
+            .remove(FAASCALE_MEM_VMM_CATEGORY)
+            .unwrap();
+
+        for kind in [FaascaleMemPoolKind::Mlock, FaascaleMemPoolKind::Hugetlb] {
+            // The host may lack the locked memory or the huge pages for the pool.
+            let mut pool = match HostMemoryPool::new(FaascaleMemPoolConfig { size_mib: 2, kind }) {
+                Ok(pool) => pool,
+                Err(Error::HostMemoryPool(_)) => continue,
+                Err(err) => panic!("Unexpected error: {:?}", err),
+            };
+            let mem = mem.clone();
+            let filter = filter.clone();
+            // The pool is created before the filters are loaded, and used from the VMM thread
+            // under the filter of a microVM with a faascale-mem device.
+            thread::spawn(move || {
+                seccompiler::apply_filter(&filter).unwrap();
+                assert!(pool.populate(&mem, (start, CHUNK_SIZE)));
+                pool.depopulate(&mem, (start, CHUNK_SIZE));
+                assert_eq!(pool.usage().lost_mib, 0);
+                assert!(pool.populate(&mem, (start, CHUNK_SIZE)));
+            })
+            .join()
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_check_guest_memory() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x10_0000)], false).unwrap();
+        HostMemoryPool::check_guest_memory(&mem, false).unwrap();
+        // Memory served by a userfaultfd cannot be replaced.
+        assert!(matches!(
+            HostMemoryPool::check_guest_memory(&mem, true),
+            Err(Error::PoolUnsupportedMemory)
+        ));
+
+        // Neither can memory backed by a file.
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x10_0000).unwrap();
+        let mem = create_guest_memory(
+            &[(Some(FileOffset::new(file, 0)), GuestAddress(0), 0x10_0000)],
+            false,
+        )
+        .unwrap();
+        assert!(matches!(
+            HostMemoryPool::check_guest_memory(&mem, false),
+            Err(Error::PoolUnsupportedMemory)
+        ));
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Coalescing of the TDP fault pre-handling of the populated blocks.
+//!
+//! Pre-handling the TDP faults of a block costs a `KVM_PREALLOC_USER_MEMORY_REGION` ioctl, and
+//! a large scale-up populates thousands of blocks. The memory slot pieces of the blocks are
+//! queued instead while a queue is drained, and the contiguous ones of a memory slot are
+//! pre-handled by a single ioctl once the drain is over, before the guest is notified.
+
+use utils::vm_memory::GuestAddress;
+
+/// Memory slot pieces of the populated blocks waiting for their TDP faults to be pre-handled.
+#[derive(Debug, Default)]
+pub(crate) struct PrefaultBatch {
+    pieces: Vec<(u32, (GuestAddress, u64))>,
+}
+
+impl PrefaultBatch {
+    /// Queues `range`, lying within the KVM memory slot `slot`.
+    pub fn add(&mut self, slot: u32, range: (GuestAddress, u64)) {
+        self.pieces.push((slot, range));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    /// Takes the queued pieces, the contiguous or overlapping ones of a memory slot merged,
+    /// along with the number of pieces merged into another.
+    pub fn take(&mut self) -> (Vec<(u32, (GuestAddress, u64))>, usize) {
+        let mut pieces = std::mem::take(&mut self.pieces);
+        pieces.sort_unstable_by_key(|&(slot, (addr, _))| (slot, addr.0));
+
+        let mut merged: Vec<(u32, (GuestAddress, u64))> = Vec::with_capacity(pieces.len());
+        let mut merges = 0;
+        for (slot, (addr, len)) in pieces {
+            match merged.last_mut() {
+                Some((last_slot, (last_addr, last_len)))
+                    if *last_slot == slot && addr.0 <= last_addr.0 + *last_len =>
+                {
+                    *last_len = (*last_len).max(addr.0 + len - last_addr.0);
+                    merges += 1;
+                }
+                _ => merged.push((slot, (addr, len))),
+            }
+        }
+        (merged, merges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefault_batch() {
+        let mut batch = PrefaultBatch::default();
+        assert!(batch.is_empty());
+        assert_eq!(batch.take(), (vec![], 0));
+
+        batch.add(0, (GuestAddress(0x1000), 0x1000));
+        batch.add(0, (GuestAddress(0x0), 0x1000));
+        batch.add(0, (GuestAddress(0x3000), 0x1000));
+        batch.add(0, (GuestAddress(0x3800), 0x1000));
+        // Pieces of different memory slots are never merged.
+        batch.add(1, (GuestAddress(0x4800), 0x1000));
+        assert!(!batch.is_empty());
+
+        assert_eq!(
+            batch.take(),
+            (
+                vec![
+                    (0, (GuestAddress(0x0), 0x2000)),
+                    (0, (GuestAddress(0x3000), 0x1800)),
+                    (1, (GuestAddress(0x4800), 0x1000)),
+                ],
+                2
+            )
+        );
+        assert!(batch.is_empty());
+    }
+}
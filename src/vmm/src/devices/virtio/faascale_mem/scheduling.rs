@@ -0,0 +1,293 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Scheduling of the worker threads of the device.
+//!
+//! The populate poller of a device in latency mode pre-allocates and releases guest memory in
+//! the background of the vCPUs, and heavy memory churn on it can slow them down. Its nice value
+//! and I/O priority can be lowered, and it can be moved to a cgroup v2 of its own, whose CPU
+//! weight is set along. The thread applies the settings to itself before loading its seccomp
+//! filter, and reads them back for the API to report what the host actually applied.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use logger::{error, IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+
+// Bounds of the nice values.
+const MIN_NICE: i32 = -20;
+const MAX_NICE: i32 = 19;
+// Lowest priority within an I/O scheduling class.
+const MAX_IONICE_LEVEL: u8 = 7;
+// Priority within the I/O scheduling class when none is configured, the kernel default.
+const DEFAULT_IONICE_LEVEL: u8 = 4;
+// Bounds of the `cpu.weight` of a cgroup v2.
+const MIN_CPU_WEIGHT: u16 = 1;
+const MAX_CPU_WEIGHT: u16 = 10000;
+
+// I/O priorities of `ioprio_set`, from `linux/ioprio.h`.
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const IOPRIO_PRIO_MASK: libc::c_int = (1 << IOPRIO_CLASS_SHIFT) - 1;
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// I/O scheduling class of the worker threads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FaascaleMemIoniceClass {
+    /// Served first, whatever the other threads of the host do.
+    Realtime,
+    /// Served along the other threads, by priority.
+    BestEffort,
+    /// Only served once no other thread needs the disk.
+    Idle,
+}
+
+impl FaascaleMemIoniceClass {
+    fn from_raw(class: libc::c_int) -> Option<Self> {
+        match class {
+            1 => Some(FaascaleMemIoniceClass::Realtime),
+            2 => Some(FaascaleMemIoniceClass::BestEffort),
+            3 => Some(FaascaleMemIoniceClass::Idle),
+            _ => None,
+        }
+    }
+
+    fn raw(self) -> libc::c_int {
+        match self {
+            FaascaleMemIoniceClass::Realtime => 1,
+            FaascaleMemIoniceClass::BestEffort => 2,
+            FaascaleMemIoniceClass::Idle => 3,
+        }
+    }
+}
+
+/// Scheduling knobs of the worker threads of the device.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemWorkerSchedulingConfig {
+    /// Nice value of the worker threads, from -20 to 19.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+    /// I/O scheduling class of the worker threads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ionice_class: Option<FaascaleMemIoniceClass>,
+    /// Priority within the I/O scheduling class, from 0, the highest, to 7. Defaults to 4.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ionice_level: Option<u8>,
+    /// Directory of a threaded cgroup v2 the worker threads are moved to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cgroup_path: Option<PathBuf>,
+    /// `cpu.weight` set on the cgroup of `cgroup_path`, from 1 to 10000.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_weight: Option<u16>,
+}
+
+/// Scheduling of the worker threads, as read back from the host once applied.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemWorkerScheduling {
+    /// Nice value of the worker threads.
+    pub nice: i32,
+    /// I/O scheduling class of the worker threads, none when derived from their nice value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ionice_class: Option<FaascaleMemIoniceClass>,
+    /// Priority within the I/O scheduling class.
+    pub ionice_level: u8,
+    /// Cgroup the worker threads were moved to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cgroup_path: Option<PathBuf>,
+    /// `cpu.weight` of that cgroup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_weight: Option<u16>,
+}
+
+impl FaascaleMemWorkerSchedulingConfig {
+    /// Checks the knobs are within the bounds of the host.
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let nice_valid = self
+            .nice
+            .map_or(true, |nice| (MIN_NICE..=MAX_NICE).contains(&nice));
+        // A level needs a class to apply to, and a weight a cgroup.
+        let ionice_valid = match self.ionice_level {
+            Some(level) => level <= MAX_IONICE_LEVEL && self.ionice_class.is_some(),
+            None => true,
+        };
+        let cpu_weight_valid = match self.cpu_weight {
+            Some(weight) => {
+                (MIN_CPU_WEIGHT..=MAX_CPU_WEIGHT).contains(&weight) && self.cgroup_path.is_some()
+            }
+            None => true,
+        };
+        if !(nice_valid && ionice_valid && cpu_weight_valid) {
+            return Err(Error::InvalidWorkerScheduling);
+        }
+        Ok(())
+    }
+
+    /// Applies the knobs to the calling thread, and reads them back. The knobs failing to apply
+    /// are logged and left to the host defaults.
+    pub(crate) fn apply_to_current_thread(&self) -> FaascaleMemWorkerScheduling {
+        // SAFETY: The call has no side effect.
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+
+        if let Some(cgroup_path) = self.cgroup_path.as_deref() {
+            if let Err(err) = join_cgroup(cgroup_path, tid, self.cpu_weight) {
+                scheduling_fail("joining the cgroup", &err);
+            }
+        }
+        if let Some(nice) = self.nice {
+            // SAFETY: The call only changes the priority of the thread.
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } < 0 {
+                scheduling_fail("setting the nice value", &io::Error::last_os_error());
+            }
+        }
+        if let Some(class) = self.ionice_class {
+            let level = self.ionice_level.unwrap_or(DEFAULT_IONICE_LEVEL);
+            let ioprio = (class.raw() << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level);
+            // SAFETY: The call only changes the I/O priority of the thread.
+            let ret =
+                unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, ioprio) };
+            if ret < 0 {
+                scheduling_fail("setting the I/O priority", &io::Error::last_os_error());
+            }
+        }
+
+        current_scheduling(tid, self.cgroup_path.as_deref())
+    }
+}
+
+fn scheduling_fail(operation: &str, err: &io::Error) {
+    METRICS.faascale_mem.worker_scheduling_fails.inc();
+    error!("Error {} of the faascale-mem worker: {}", operation, err);
+}
+
+// Moves the thread `tid` to the threaded cgroup v2 of `cgroup_path`, setting its CPU weight.
+fn join_cgroup(cgroup_path: &Path, tid: libc::pid_t, cpu_weight: Option<u16>) -> io::Result<()> {
+    if let Some(weight) = cpu_weight {
+        fs::write(cgroup_path.join("cpu.weight"), weight.to_string())?;
+    }
+    fs::write(cgroup_path.join("cgroup.threads"), tid.to_string())
+}
+
+// Reads back the scheduling of the thread `tid`, which joined `cgroup_path` if any.
+fn current_scheduling(tid: libc::pid_t, cgroup_path: Option<&Path>) -> FaascaleMemWorkerScheduling {
+    // SAFETY: The calls only read the priorities of the thread. A nice value of -1 cannot be
+    // told from an error but through `errno`, which is cleared first.
+    let nice = unsafe {
+        *libc::__errno_location() = 0;
+        libc::getpriority(libc::PRIO_PROCESS, tid as libc::id_t)
+    };
+    let nice = match io::Error::last_os_error().raw_os_error() {
+        Some(0) | None => nice,
+        Some(_) => 0,
+    };
+    // SAFETY: See above.
+    let ioprio =
+        unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, tid) } as libc::c_int;
+    let (ionice_class, ionice_level) = match ioprio {
+        ioprio if ioprio < 0 => (None, DEFAULT_IONICE_LEVEL),
+        ioprio => (
+            FaascaleMemIoniceClass::from_raw(ioprio >> IOPRIO_CLASS_SHIFT),
+            (ioprio & IOPRIO_PRIO_MASK) as u8,
+        ),
+    };
+
+    let tid = tid.to_string();
+    let joined = cgroup_path.filter(|path| {
+        fs::read_to_string(path.join("cgroup.threads"))
+            .map_or(false, |threads| threads.lines().any(|thread| thread == tid))
+    });
+    let cpu_weight = joined.and_then(|path| {
+        fs::read_to_string(path.join("cpu.weight"))
+            .ok()
+            .and_then(|weight| weight.trim().parse().ok())
+    });
+
+    FaascaleMemWorkerScheduling {
+        nice,
+        ionice_class,
+        ionice_level,
+        cgroup_path: joined.map(Path::to_path_buf),
+        cpu_weight,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        FaascaleMemWorkerSchedulingConfig::default()
+            .validate()
+            .unwrap();
+        let invalid = [
+            FaascaleMemWorkerSchedulingConfig {
+                nice: Some(20),
+                ..Default::default()
+            },
+            FaascaleMemWorkerSchedulingConfig {
+                ionice_class: Some(FaascaleMemIoniceClass::BestEffort),
+                ionice_level: Some(8),
+                ..Default::default()
+            },
+            // A level without a class.
+            FaascaleMemWorkerSchedulingConfig {
+                ionice_level: Some(7),
+                ..Default::default()
+            },
+            FaascaleMemWorkerSchedulingConfig {
+                cgroup_path: Some(PathBuf::from("/sys/fs/cgroup/fc")),
+                cpu_weight: Some(0),
+                ..Default::default()
+            },
+            // A weight without a cgroup.
+            FaascaleMemWorkerSchedulingConfig {
+                cpu_weight: Some(100),
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(matches!(
+                config.validate(),
+                Err(Error::InvalidWorkerScheduling)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_apply_to_current_thread() {
+        // A thread of its own, whose priorities can only be lowered without privileges.
+        std::thread::spawn(|| {
+            let cgroup = TempDir::new().unwrap();
+            let config = FaascaleMemWorkerSchedulingConfig {
+                nice: Some(MAX_NICE),
+                ionice_class: Some(FaascaleMemIoniceClass::BestEffort),
+                ionice_level: Some(MAX_IONICE_LEVEL),
+                cgroup_path: Some(cgroup.as_path().to_path_buf()),
+                cpu_weight: Some(50),
+            };
+            config.validate().unwrap();
+            let fails = METRICS.faascale_mem.worker_scheduling_fails.count();
+            let applied = config.apply_to_current_thread();
+            assert_eq!(applied.nice, MAX_NICE);
+            assert_eq!(
+                applied.ionice_class,
+                Some(FaascaleMemIoniceClass::BestEffort)
+            );
+            assert_eq!(applied.ionice_level, MAX_IONICE_LEVEL);
+            // A plain directory stands for the cgroup, the thread is reported in it once
+            // listed in its threads.
+            assert_eq!(applied.cgroup_path, config.cgroup_path);
+            assert_eq!(applied.cpu_weight, Some(50));
+            assert_eq!(METRICS.faascale_mem.worker_scheduling_fails.count(), fails);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Spill file of the blocks depopulated by the guest.
+//!
+//! Before a block is given back to the host, its contents are written to a file of the microVM.
+//! When the guest populates the same pages again during the same driver session, they are read
+//! back into them, so that the guest can shrink memory holding state without losing it. Unlike
+//! the block cache, nothing is evicted from the file. It is laid out as the guest physical
+//! address space, the contents of a page sitting at the offset of its guest physical address,
+//! which keeps it sparse. The pages read back are punched out of the file.
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use logger::{error, IncMetric, StoreMetric, METRICS};
+use utils::vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use super::util::PfnRanges;
+use super::VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+
+// Size of the buffer the contents are copied through.
+const COPY_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Contents of the pages depopulated by the guest, kept in a file.
+#[derive(Debug)]
+pub(crate) struct SpillFile {
+    path: PathBuf,
+    file: File,
+    // Pages whose contents are in the file.
+    spilled: PfnRanges,
+}
+
+impl SpillFile {
+    /// Creates the spill file at `path`, truncating it if it exists.
+    pub fn new(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(SpillFile {
+            path,
+            file,
+            spilled: PfnRanges::default(),
+        })
+    }
+
+    /// Path of the spill file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of pages whose contents are in the file.
+    pub fn spilled_pages(&self) -> u64 {
+        self.spilled.num_pages()
+    }
+
+    /// Number of pages of the `(start pfn, number of pages)` block whose contents are in the
+    /// file.
+    pub fn overlap_pages(&self, block: (u64, u64)) -> u64 {
+        self.spilled.overlap_pages(block)
+    }
+
+    /// Writes the contents of the `(start pfn, number of pages)` block the guest is about to
+    /// depopulate to the file.
+    pub fn save(&mut self, mem: &GuestMemoryMmap, block: (u64, u64)) -> io::Result<()> {
+        let file = &self.file;
+        copy_block(block, |offset, buf| {
+            mem.read_slice(buf, GuestAddress(offset))
+                .map_err(guest_memory_error)?;
+            file.write_all_at(buf, offset)
+        })?;
+        self.spilled.insert(block);
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// Reads the spilled pages of the `(start pfn, number of pages)` block the guest just
+    /// populated back into the guest memory, and drops them from the file. Returns the number of
+    /// pages read back.
+    pub fn restore(&mut self, mem: &GuestMemoryMmap, block: (u64, u64)) -> u64 {
+        let mut restored_pages = 0;
+        for piece in self.spilled.intersection(block) {
+            let file = &self.file;
+            let result = copy_block(piece, |offset, buf| {
+                file.read_exact_at(buf, offset)?;
+                mem.write_slice(buf, GuestAddress(offset))
+                    .map_err(guest_memory_error)
+            });
+            match result {
+                Ok(()) => restored_pages += piece.1,
+                Err(err) => {
+                    METRICS.faascale_mem.spill_fails.inc();
+                    error!(
+                        "faascale-mem: error reading back spilled block: start_pfn={}, size={}: \
+                         {:?}",
+                        piece.0, piece.1, err
+                    );
+                }
+            }
+            // Read back or not, the contents are out of date once the guest uses the pages.
+            self.spilled.remove(piece);
+            self.punch_hole(piece);
+        }
+        METRICS
+            .faascale_mem
+            .spill_restored_pages
+            .add(restored_pages as usize);
+        self.update_metrics();
+        restored_pages
+    }
+
+    /// Drops all the spilled contents.
+    pub fn clear(&mut self) {
+        if let Err(err) = self.file.set_len(0) {
+            error!("faascale-mem: error truncating the spill file: {:?}", err);
+        }
+        self.spilled = PfnRanges::default();
+        self.update_metrics();
+    }
+
+    // Gives the disk space holding the `(start pfn, number of pages)` block back.
+    fn punch_hole(&self, block: (u64, u64)) {
+        let offset = block.0 << VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+        let len = block.1 << VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+        // SAFETY: The file descriptor is owned by `self.file`, and fallocate only changes the
+        // allocation of the file.
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret < 0 {
+            error!(
+                "faascale-mem: error punching the spill file: {:?}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    fn update_metrics(&self) {
+        METRICS
+            .faascale_mem
+            .spilled_bytes
+            .store((self.spilled.num_pages() << VIRTIO_FAASCALE_MEM_PFN_SHIFT) as usize);
+    }
+}
+
+// Runs `copy` on the consecutive chunks of the `(start pfn, number of pages)` block, given the
+// guest physical address of each chunk, which is also its offset in the file.
+fn copy_block<F>(block: (u64, u64), mut copy: F) -> io::Result<()>
+where
+    F: FnMut(u64, &mut [u8]) -> io::Result<()>,
+{
+    let start = block.0 << VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+    let end = start + (block.1 << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    let mut buf = vec![0u8; cmp::min(COPY_CHUNK_SIZE, end - start) as usize];
+    let mut offset = start;
+    while offset < end {
+        let len = cmp::min(COPY_CHUNK_SIZE, end - offset) as usize;
+        copy(offset, &mut buf[..len])?;
+        offset += len as u64;
+    }
+    Ok(())
+}
+
+fn guest_memory_error(err: GuestMemoryError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use utils::tempfile::TempFile;
+    use utils::vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+    use crate::seccomp_filters::{get_filters, SeccompConfig, FAASCALE_MEM_VMM_CATEGORY};
+
+    #[test]
+    fn test_spill_file() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x40_0000)], false).unwrap();
+        let tmp = TempFile::new().unwrap();
+        let mut spill = SpillFile::new(tmp.as_path().to_path_buf()).unwrap();
+        assert_eq!(spill.path(), tmp.as_path());
+        let page = |pfn: u64| GuestAddress(pfn << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+
+        // Blocks larger than the copy buffer are spilled whole.
+        mem.write_obj(0xdead_beef_u64, page(0x10)).unwrap();
+        mem.write_obj(0xcafe_f00d_u64, page(0x10 + 0x17f)).unwrap();
+        spill.save(&mem, (0x10, 0x180)).unwrap();
+        assert_eq!(spill.spilled_pages(), 0x180);
+        mem.write_obj(0u64, page(0x10)).unwrap();
+        mem.write_obj(0u64, page(0x10 + 0x17f)).unwrap();
+
+        // Populating a block reads back its spilled pages only.
+        assert_eq!(spill.restore(&mem, (0x0, 0x20)), 0x10);
+        assert_eq!(mem.read_obj::<u64>(page(0x10)).unwrap(), 0xdead_beef);
+        assert_eq!(mem.read_obj::<u64>(page(0x10 + 0x17f)).unwrap(), 0);
+        assert_eq!(spill.spilled_pages(), 0x170);
+        // Pages read back are no longer spilled.
+        assert_eq!(spill.restore(&mem, (0x10, 0x10)), 0);
+
+        assert_eq!(spill.restore(&mem, (0x20, 0x200)), 0x170);
+        assert_eq!(
+            mem.read_obj::<u64>(page(0x10 + 0x17f)).unwrap(),
+            0xcafe_f00d
+        );
+        assert_eq!(spill.spilled_pages(), 0);
+
+        spill.save(&mem, (0x100, 0x10)).unwrap();
+        spill.clear();
+        assert_eq!(spill.spilled_pages(), 0);
+        assert_eq!(spill.restore(&mem, (0x100, 0x10)), 0);
+    }
+
+    #[test]
+    fn test_spill_file_seccomp() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x40_0000)], false).unwrap();
+        let tmp = TempFile::new().unwrap();
+        let mut spill = SpillFile::new(tmp.as_path().to_path_buf()).unwrap();
+        let filter = get_filters(SeccompConfig::Advanced)
+            .unwrap()
+            .remove(FAASCALE_MEM_VMM_CATEGORY)
+            .unwrap();
+
+        // The spill file is used from the VMM thread, under the filter of a microVM with a
+        // faascale-mem device.
+        thread::spawn(move || {
+            seccompiler::apply_filter(&filter).unwrap();
+            let page = GuestAddress(0x10 << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+            mem.write_obj(0xdead_beef_u64, page).unwrap();
+            spill.save(&mem, (0x10, 0x10)).unwrap();
+            mem.write_obj(0u64, page).unwrap();
+            assert_eq!(spill.restore(&mem, (0x0, 0x20)), 0x10);
+            assert_eq!(mem.read_obj::<u64>(page).unwrap(), 0xdead_beef);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Warm templates of scaled microVMs.
+//!
+//! A warm pool keeps snapshots of microVMs already scaled up, so that the clones restored from
+//! them start with their memory in place. The template microVM is booted and has a set of
+//! blocks populated by the host ahead of the guest before it is paused and snapshotted. Each
+//! clone restored from the snapshot then faults in the blocks populated in the template from
+//! the snapshot memory file before it is resumed, instead of taking the faults once the guest
+//! runs. Booting the templates and restoring the clones is up to the orchestrator of the pool.
+
+use serde::Serialize;
+
+/// Outcome of populating the blocks of a template or faulting in the blocks of a clone.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FaascaleMemWarmReport {
+    /// Number of blocks done.
+    pub blocks: u64,
+    /// Number of guest pages in the blocks done.
+    pub pages: u64,
+    /// Number of blocks that could not be done.
+    pub failed_blocks: u64,
+    /// Time spent on the blocks, in microseconds.
+    pub elapsed_us: u64,
+}
+
+impl FaascaleMemWarmReport {
+    /// Records a `(start pfn, number of pages)` block, done or not.
+    pub(crate) fn record(&mut self, block: (u64, u64), done: bool) {
+        if done {
+            self.blocks += 1;
+            self.pages += block.1;
+        } else {
+            self.failed_blocks += 1;
+        }
+    }
+}
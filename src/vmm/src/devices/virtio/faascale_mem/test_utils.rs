@@ -0,0 +1,344 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![doc(hidden)]
+
+use std::sync::{Arc, Mutex};
+
+use rate_limiter::RateLimiter;
+use utils::clock::ManualClock;
+use utils::vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+
+use crate::arch::DeviceType;
+use crate::devices::virtio::faascale_mem::{
+    FaascaleMem, FAASCALE_MEM_DEV_ID, MAX_BLOCKS_IN_DESC, NUM_QUEUES,
+    VIRTIO_FAASCALE_MEM_F_TRACE_IDS, VIRTIO_FAASCALE_MEM_F_WIDE_BLOCKS,
+    VIRTIO_FAASCALE_MEM_F_ZEROED,
+};
+use crate::devices::virtio::test_utils::VirtQueue;
+use crate::devices::virtio::{
+    ActivateResult, MmioTransport, VirtioDevice, DEPOPULATE_INDEX, FAASCALE_STATS_INDEX,
+    POPULATE_INDEX, TYPE_FAASCALE_MEM,
+};
+#[cfg(test)]
+use crate::devices::virtio::{IrqType, CONTROL_INDEX};
+use crate::vmm_config::faascale_mem::FaascaleMemDeviceConfig;
+use crate::Vmm;
+
+// Size in bytes of a block descriptor entry: start pfn followed by the number of pages.
+const BLOCK_INFO_SIZE: u64 = 8;
+// Size in bytes of a wide block descriptor entry: 64-bit start pfn and number of pages.
+const WIDE_BLOCK_INFO_SIZE: u64 = 16;
+// Size in bytes of a trace ID following a block descriptor entry.
+const TRACE_ID_SIZE: u64 = 8;
+// Size in bytes of a packed statistics entry: 16-bit tag followed by a 64-bit value.
+const STAT_SIZE: u64 = 10;
+// Size in bytes of a budget acknowledgement: offer epoch followed by the number of pages.
+const BUDGET_ACK_SIZE: u64 = 8;
+// Guest memory reserved for the rings of one queue.
+const QUEUE_AREA_SIZE: u64 = 0x2000;
+// Guest memory reserved for the payload of one descriptor.
+const DESC_DATA_SIZE: u64 = MAX_BLOCKS_IN_DESC as u64 * (WIDE_BLOCK_INFO_SIZE + TRACE_ID_SIZE);
+
+/// Returns the faascale-mem device attached to a built microVM.
+pub fn faascale_mem_device(vmm: &Vmm) -> Arc<Mutex<dyn VirtioDevice>> {
+    let busdev = vmm
+        .get_bus_device(DeviceType::Virtio(TYPE_FAASCALE_MEM), FAASCALE_MEM_DEV_ID)
+        .expect("No faascale-mem device attached");
+    let device = busdev
+        .lock()
+        .expect("Poisoned lock")
+        .as_any()
+        .downcast_ref::<MmioTransport>()
+        .expect("Unexpected BusDevice type")
+        .device();
+    device
+}
+
+/// Runs the faascale-mem `device` on a `ManualClock`, returned to drive it. Must be called
+/// before the guest driver activates the device.
+pub fn use_manual_clock(device: &Mutex<dyn VirtioDevice>) -> ManualClock {
+    let clock = ManualClock::new();
+    device
+        .lock()
+        .expect("Poisoned lock")
+        .as_mut_any()
+        .downcast_mut::<FaascaleMem>()
+        .expect("Not a faascale-mem device")
+        .set_clock(Arc::new(clock.clone()))
+        .expect("Failed to create the timers of the manual clock");
+    clock
+}
+
+/// Returns the `[start, end)` pfn ranges the faascale-mem device counts as populated.
+pub fn populated_ranges(faascale_mem: &FaascaleMem) -> Vec<(u64, u64)> {
+    faascale_mem.populated_ranges.ranges().collect()
+}
+
+/// Creates a faascale-mem device with the defaults of the API but for the polling interval of
+/// the statistics.
+pub fn default_faascale_mem(stats_polling_interval_s: u16) -> FaascaleMem {
+    FaascaleMem::new(
+        &FaascaleMemDeviceConfig {
+            stats_polling_interval_s,
+            ..Default::default()
+        },
+        false,
+        RateLimiter::default(),
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+pub fn invoke_handler_for_queue_event(f: &mut FaascaleMem, queue_index: usize) {
+    assert!(queue_index < f.queue_evts.len());
+    // Trigger the queue event.
+    f.queue_evts[queue_index].write(1).unwrap();
+    // Handle event.
+    match queue_index {
+        POPULATE_INDEX => f
+            .process_populate_queue_event(std::time::Instant::now())
+            .unwrap(),
+        DEPOPULATE_INDEX => f.process_depopulate_queue_event().unwrap(),
+        FAASCALE_STATS_INDEX => f.process_stats_queue_event().unwrap(),
+        CONTROL_INDEX => f.process_control_queue_event().unwrap(),
+        _ => unreachable!(),
+    };
+    // Validate the queue operation finished successfully.
+    assert!(f.irq_trigger.has_pending_irq(IrqType::Vring));
+}
+
+/// Host-side stand-in for the guest faascale-mem driver.
+///
+/// Lays out the virtqueues and the descriptor payloads in guest memory starting at a given
+/// address and feeds populate, depopulate and statistics requests to the device the same way
+/// the guest kernel driver would.
+pub struct StubGuestDriver<'a> {
+    mem: &'a GuestMemoryMmap,
+    queues: Vec<VirtQueue<'a>>,
+    data_start: GuestAddress,
+    // Number of requests made available on each queue.
+    avail_count: [u16; NUM_QUEUES],
+    // Whether the populate blocks carry trace IDs.
+    trace_ids: bool,
+    // Whether the blocks are made of a 64-bit pfn and page count.
+    wide_blocks: bool,
+    // Whether the populated pages are promised to be zero-filled.
+    zeroed: bool,
+}
+
+impl<'a> StubGuestDriver<'a> {
+    pub fn new(mem: &'a GuestMemoryMmap, start: GuestAddress, queue_size: u16) -> Self {
+        let queues = (0..NUM_QUEUES as u64)
+            .map(|i| VirtQueue::new(start.unchecked_add(i * QUEUE_AREA_SIZE), mem, queue_size))
+            .collect::<Vec<_>>();
+        let data_start = start.unchecked_add(NUM_QUEUES as u64 * QUEUE_AREA_SIZE);
+
+        StubGuestDriver {
+            mem,
+            queues,
+            data_start,
+            avail_count: [0; NUM_QUEUES],
+            trace_ids: false,
+            wide_blocks: false,
+            zeroed: false,
+        }
+    }
+
+    /// Makes the driver negotiate the trace IDs of the populate blocks when activating the
+    /// device.
+    pub fn with_trace_ids(mut self) -> Self {
+        self.trace_ids = true;
+        self
+    }
+
+    /// Makes the driver negotiate the blocks made of a 64-bit pfn and page count when
+    /// activating the device.
+    pub fn with_wide_blocks(mut self) -> Self {
+        self.wide_blocks = true;
+        self
+    }
+
+    /// Makes the driver negotiate the zero-filled populated pages when activating the device.
+    pub fn with_zeroed(mut self) -> Self {
+        self.zeroed = true;
+        self
+    }
+
+    /// Guest memory range used by the driver, which must not be handed out as blocks.
+    pub fn footprint(&self) -> (GuestAddress, u64) {
+        let start = self.queues[0].start();
+        let end = self.data_address(NUM_QUEUES - 1, self.queues[0].size() - 1);
+        (start, end.unchecked_add(DESC_DATA_SIZE).0 - start.0)
+    }
+
+    /// Negotiates all offered features, but the trace IDs, the wide blocks and the zero-filled
+    /// pages unless asked to, hands the queues over and activates the device. The event index
+    /// is never negotiated, the driver does not maintain it.
+    pub fn activate(&self, device: &mut dyn VirtioDevice) -> ActivateResult {
+        let mut features = device.avail_features() & !(1u64 << VIRTIO_RING_F_EVENT_IDX);
+        if !self.trace_ids {
+            features &= !(1u64 << VIRTIO_FAASCALE_MEM_F_TRACE_IDS);
+        }
+        if !self.wide_blocks {
+            features &= !(1u64 << VIRTIO_FAASCALE_MEM_F_WIDE_BLOCKS);
+        }
+        if !self.zeroed {
+            features &= !(1u64 << VIRTIO_FAASCALE_MEM_F_ZEROED);
+        }
+        device.set_acked_features(features);
+        for (queue, virt_queue) in device.queues_mut().iter_mut().zip(self.queues.iter()) {
+            *queue = virt_queue.create_queue();
+        }
+        device.activate(self.mem.clone())
+    }
+
+    /// Asks the device to populate the given `(start pfn, number of pages)` blocks.
+    pub fn populate(&mut self, device: &dyn VirtioDevice, blocks: &[(u32, u32)]) {
+        let blocks = blocks
+            .iter()
+            .map(|&(pfn, npages)| (u64::from(pfn), u64::from(npages)))
+            .collect::<Vec<_>>();
+        self.populate_wide(device, &blocks);
+    }
+
+    /// Asks the device to populate the given `(start pfn, number of pages)` blocks. Blocks that
+    /// do not fit in 32 bits need the wide blocks to have been negotiated.
+    pub fn populate_wide(&mut self, device: &dyn VirtioDevice, blocks: &[(u64, u64)]) {
+        let blocks = blocks
+            .iter()
+            .map(|&(pfn, npages)| (pfn, npages, 0))
+            .collect::<Vec<_>>();
+        self.send_blocks(device, POPULATE_INDEX, &blocks);
+    }
+
+    /// Asks the device to populate the given `(start pfn, number of pages, trace ID)` blocks.
+    /// The trace IDs must have been negotiated.
+    pub fn populate_traced(&mut self, device: &dyn VirtioDevice, blocks: &[(u32, u32, u64)]) {
+        assert!(self.trace_ids);
+        let blocks = blocks
+            .iter()
+            .map(|&(pfn, npages, trace_id)| (u64::from(pfn), u64::from(npages), trace_id))
+            .collect::<Vec<_>>();
+        self.send_blocks(device, POPULATE_INDEX, &blocks);
+    }
+
+    /// Asks the device to depopulate the given `(start pfn, number of pages)` blocks.
+    pub fn depopulate(&mut self, device: &dyn VirtioDevice, blocks: &[(u32, u32)]) {
+        let blocks = blocks
+            .iter()
+            .map(|&(pfn, npages)| (u64::from(pfn), u64::from(npages), 0))
+            .collect::<Vec<_>>();
+        self.send_blocks(device, DEPOPULATE_INDEX, &blocks);
+    }
+
+    /// Hands a statistics buffer holding the given `(tag, value)` pairs to the device.
+    pub fn provide_stats(&mut self, device: &dyn VirtioDevice, stats: &[(u16, u64)]) {
+        let addr = self.next_data_address(FAASCALE_STATS_INDEX);
+        for (i, (tag, val)) in stats.iter().enumerate() {
+            let stat_addr = addr.unchecked_add(i as u64 * STAT_SIZE);
+            self.mem.write_obj(*tag, stat_addr).unwrap();
+            self.mem
+                .write_obj(*val, stat_addr.unchecked_add(2))
+                .unwrap();
+        }
+        self.push_request(FAASCALE_STATS_INDEX, addr, stats.len() as u64 * STAT_SIZE);
+        self.kick(device, FAASCALE_STATS_INDEX);
+    }
+
+    /// Acknowledges the budget offer of `epoch` with `num_pages` on the control queue, which
+    /// is the last queue of the device.
+    pub fn ack_budget(&mut self, device: &dyn VirtioDevice, epoch: u32, num_pages: u32) {
+        let queue_index = device.queues().len() - 1;
+        let addr = self.next_data_address(queue_index);
+        self.mem.write_obj([epoch, num_pages], addr).unwrap();
+        self.push_request(queue_index, addr, BUDGET_ACK_SIZE);
+        self.kick(device, queue_index);
+    }
+
+    /// Number of requests made available on the queue.
+    pub fn avail_count(&self, queue_index: usize) -> u16 {
+        self.avail_count[queue_index]
+    }
+
+    /// Number of requests the device has returned on the queue.
+    pub fn used_count(&self, queue_index: usize) -> u16 {
+        self.queues[queue_index].used.idx.get()
+    }
+
+    /// Checks that the device returned every request made available on the queue, in order and
+    /// without writing anything back.
+    pub fn check_all_used(&self, queue_index: usize) {
+        let queue = &self.queues[queue_index];
+        let count = self.avail_count[queue_index];
+        assert_eq!(self.used_count(queue_index), count);
+        for idx in 0..count {
+            let slot = idx % queue.size();
+            queue.check_used_elem(slot, slot, 0);
+        }
+    }
+
+    // Sends `(start pfn, number of pages, trace ID)` blocks in the negotiated format. Only the
+    // populate blocks carry their trace ID.
+    fn send_blocks(
+        &mut self,
+        device: &dyn VirtioDevice,
+        queue_index: usize,
+        blocks: &[(u64, u64, u64)],
+    ) {
+        assert!(blocks.len() <= MAX_BLOCKS_IN_DESC);
+        let info_size = if self.wide_blocks {
+            WIDE_BLOCK_INFO_SIZE
+        } else {
+            BLOCK_INFO_SIZE
+        };
+        let traced = queue_index == POPULATE_INDEX && self.trace_ids;
+        let entry_size = if traced {
+            info_size + TRACE_ID_SIZE
+        } else {
+            info_size
+        };
+        let addr = self.next_data_address(queue_index);
+        for (i, &(pfn, npages, trace_id)) in blocks.iter().enumerate() {
+            let block_addr = addr.unchecked_add(i as u64 * entry_size);
+            if self.wide_blocks {
+                self.mem.write_obj([pfn, npages], block_addr).unwrap();
+            } else {
+                let block = [u32::try_from(pfn).unwrap(), u32::try_from(npages).unwrap()];
+                self.mem.write_obj(block, block_addr).unwrap();
+            }
+            if traced {
+                self.mem
+                    .write_obj(trace_id, block_addr.unchecked_add(info_size))
+                    .unwrap();
+            }
+        }
+        self.push_request(queue_index, addr, blocks.len() as u64 * entry_size);
+        self.kick(device, queue_index);
+    }
+
+    fn data_address(&self, queue_index: usize, desc_index: u16) -> GuestAddress {
+        let slot = queue_index as u64 * u64::from(self.queues[0].size()) + u64::from(desc_index);
+        self.data_start.unchecked_add(slot * DESC_DATA_SIZE)
+    }
+
+    fn next_data_address(&self, queue_index: usize) -> GuestAddress {
+        let desc_index = self.avail_count[queue_index] % self.queues[queue_index].size();
+        self.data_address(queue_index, desc_index)
+    }
+
+    fn push_request(&mut self, queue_index: usize, addr: GuestAddress, len: u64) {
+        let queue = &self.queues[queue_index];
+        let desc_index = self.avail_count[queue_index] % queue.size();
+        // Device readable, single descriptor chain.
+        queue.dtable[desc_index as usize].set(addr.0, len as u32, 0, 0);
+        queue.avail.ring[desc_index as usize].set(desc_index);
+        self.avail_count[queue_index] = self.avail_count[queue_index].wrapping_add(1);
+        queue.avail.idx.set(self.avail_count[queue_index]);
+    }
+
+    fn kick(&self, device: &dyn VirtioDevice, queue_index: usize) {
+        device.queue_events()[queue_index].write(1).unwrap();
+    }
+}
@@ -1,14 +1,33 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
 use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, Instant};
 
-use utils::vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use kvm_ioctls::VmFd;
+use logger::{IncMetric, StoreMetric, METRICS};
+use utils::vm_memory::{
+    hugetlb_page_size, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+    GuestRegionMmap,
+};
 
-use super::{RemoveRegionError};
+use super::device::{FaascaleMemDepopulateMode, FaascaleMemThpPolicy};
+use super::interleave::{interleave_range, prefer_node, reset_range_policy};
+use super::latency::PopulateTimings;
+use super::perf::PrefaultSampler;
+use super::prefault_batch::PrefaultBatch;
+use super::{
+    RemoveRegionError, POPULATE_CANARY, POPULATE_TRACKER_MAX_ENTRIES, VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+};
+use crate::devices::virtio::mem_overlay::MmapOverlays;
 
 use utils::{ioctl_iow_nr, ioctl_ioc_nr};
-use crate::builder::get_global_vm_fd;
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
@@ -21,126 +40,1315 @@ ioctl_iow_nr!(KVM_PREALLOC_USER_MEMORY_REGION,
     0x49,
     kvm_userspace_prealloc_memory_region);
 
+/// Size of a transparent huge page with 4K base pages.
+pub(crate) const THP_SIZE: u64 = 0x20_0000;
+/// `MADV_COLLAPSE` (Linux 6.1+) is not exported by the libc crate.
+pub(crate) const MADV_COLLAPSE: libc::c_int = 25;
+
+/// How the host memory of the populated blocks is pre-allocated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PreAllocMethod {
+    /// `madvise(MADV_POPULATE_WRITE)` (Linux 5.14+).
+    PopulateWrite,
+    /// Write-faulting the pages in one at a time, on the hosts lacking `MADV_POPULATE_WRITE`.
+    Touch,
+}
+
+/// `MADV_POPULATE_READ` (Linux 5.14+) is not exported by the libc crate.
+pub(crate) const MADV_POPULATE_READ: libc::c_int = 22;
+/// How long a populated range is remembered to catch the guest re-submitting it.
+pub const POPULATE_DEDUP_WINDOW: Duration = Duration::from_millis(500);
+
+/// Keeps track of the guest frame ranges populated recently, so that a block the guest
+/// re-submits before depopulating it is not populated a second time.
+///
+/// Overlapping and adjacent ranges are merged, taking the time of the latest populate, and at
+/// most `max_entries` ranges are kept. A `max_entries` of 0 disables the tracking.
+#[derive(Debug)]
+pub(crate) struct PopulateTracker {
+    // Disjoint, non-adjacent `[start, end)` pfn ranges keyed by their start, along with the
+    // time they were last populated.
+    ranges: BTreeMap<u64, (u64, Instant)>,
+    max_entries: usize,
+}
+
+impl Default for PopulateTracker {
+    fn default() -> Self {
+        Self::new(POPULATE_TRACKER_MAX_ENTRIES)
+    }
+}
+
+impl PopulateTracker {
+    pub(crate) fn new(max_entries: usize) -> Self {
+        PopulateTracker {
+            ranges: BTreeMap::new(),
+            max_entries,
+        }
+    }
+
+    /// Upper bound on the number of ranges tracked at once.
+    pub(crate) fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    /// Records a populate request for the `(start pfn, number of pages)` block and returns
+    /// whether the block lies within a range populated within the deduplication window.
+    pub(crate) fn check_and_record(&mut self, block: (u64, u64), now: Instant) -> bool {
+        let (start, end) = block_bounds(block);
+        if self.max_entries == 0 || start == end {
+            return false;
+        }
+
+        if let Some((_, &(range_end, populated_at))) = self.ranges.range(..=start).next_back() {
+            if end <= range_end
+                && now.saturating_duration_since(populated_at) < POPULATE_DEDUP_WINDOW
+            {
+                return true;
+            }
+        }
+
+        self.insert(start, end, now);
+        false
+    }
+
+    /// Forgets the part of every tracked range overlapping the depopulated block, so that
+    /// populating it again is honored.
+    pub(crate) fn forget_overlapping(&mut self, block: (u64, u64), now: Instant) {
+        let (start, end) = block_bounds(block);
+        // Ranges are disjoint and sorted, so the overlapping ones are the last to start
+        // before `end`.
+        let overlapping: Vec<_> = self
+            .ranges
+            .range(..end)
+            .rev()
+            .take_while(|&(_, &(range_end, _))| range_end > start)
+            .map(|(&range_start, &range)| (range_start, range))
+            .collect();
+        if overlapping.is_empty() {
+            return;
+        }
+
+        for (range_start, (range_end, populated_at)) in overlapping {
+            self.ranges.remove(&range_start);
+            if range_start < start {
+                self.ranges.insert(range_start, (start, populated_at));
+            }
+            if range_end > end {
+                self.ranges.insert(end, (range_end, populated_at));
+            }
+        }
+        // Splitting a range may leave one range too many.
+        self.spill(now);
+        METRICS
+            .faascale_mem
+            .tracker_entries
+            .store(self.ranges.len());
+    }
+
+    fn insert(&mut self, mut start: u64, mut end: u64, now: Instant) {
+        // Merge with the range overlapping or touching the new one from below.
+        if let Some((&prev_start, &(prev_end, _))) = self.ranges.range(..=start).next_back() {
+            if prev_end >= start {
+                self.ranges.remove(&prev_start);
+                start = prev_start;
+                end = cmp::max(end, prev_end);
+                METRICS.faascale_mem.tracker_merges.inc();
+            }
+        }
+        // Merge with the ranges starting inside or right after the new one.
+        while let Some((&next_start, &(next_end, _))) = self.ranges.range(start..=end).next() {
+            self.ranges.remove(&next_start);
+            end = cmp::max(end, next_end);
+            METRICS.faascale_mem.tracker_merges.inc();
+        }
+
+        self.ranges.insert(start, (end, now));
+        self.spill(now);
+        METRICS
+            .faascale_mem
+            .tracker_entries
+            .store(self.ranges.len());
+    }
+
+    // Brings the tracker back within `max_entries`, dropping the expired ranges first and
+    // then the least recently populated ones.
+    fn spill(&mut self, now: Instant) {
+        if self.ranges.len() <= self.max_entries {
+            return;
+        }
+
+        let tracked = self.ranges.len();
+        self.ranges
+            .retain(|_, (_, at)| now.saturating_duration_since(*at) < POPULATE_DEDUP_WINDOW);
+        while self.ranges.len() > self.max_entries {
+            let oldest = self
+                .ranges
+                .iter()
+                .min_by_key(|(_, (_, at))| *at)
+                .map(|(&range_start, _)| range_start);
+            match oldest {
+                Some(range_start) => self.ranges.remove(&range_start),
+                None => break,
+            };
+        }
+        METRICS
+            .faascale_mem
+            .tracker_spills
+            .add(tracked - self.ranges.len());
+    }
+}
+
+/// A set of guest frames, such as the ranges the host or the guest asked to keep populated,
+/// or the ranges populated by the guest.
+#[derive(Debug, Default)]
+pub(crate) struct PfnRanges {
+    // Disjoint, non-adjacent `[start, end)` pfn ranges keyed by their start.
+    ranges: BTreeMap<u64, u64>,
+    // Number of pages in the ranges, kept up to date as they are inserted and removed.
+    num_pages: u64,
+}
+
+impl PfnRanges {
+    /// Adds the `(start pfn, number of pages)` block, merging it with the ranges it overlaps
+    /// or touches.
+    pub(crate) fn insert(&mut self, block: (u64, u64)) {
+        let (start, end) = block_bounds(block);
+        self.insert_range(start, end);
+    }
+
+    /// Adds the `[start, end)` pfn range, merging it with the ranges it overlaps or touches.
+    pub(crate) fn insert_range(&mut self, mut start: u64, mut end: u64) {
+        if start >= end {
+            return;
+        }
+
+        if let Some((&prev_start, &prev_end)) = self.ranges.range(..=start).next_back() {
+            if prev_end >= start {
+                self.ranges.remove(&prev_start);
+                self.num_pages -= prev_end - prev_start;
+                start = prev_start;
+                end = cmp::max(end, prev_end);
+            }
+        }
+        while let Some((&next_start, &next_end)) = self.ranges.range(start..=end).next() {
+            self.ranges.remove(&next_start);
+            self.num_pages -= next_end - next_start;
+            end = cmp::max(end, next_end);
+        }
+        self.ranges.insert(start, end);
+        self.num_pages += end - start;
+    }
+
+    /// Removes the `(start pfn, number of pages)` block, splitting the ranges it partially
+    /// covers.
+    pub(crate) fn remove(&mut self, block: (u64, u64)) {
+        let (start, end) = block_bounds(block);
+        let overlapping: Vec<_> = self
+            .ranges
+            .range(..end)
+            .rev()
+            .take_while(|&(_, &range_end)| range_end > start)
+            .map(|(&range_start, &range_end)| (range_start, range_end))
+            .collect();
+
+        for (range_start, range_end) in overlapping {
+            self.ranges.remove(&range_start);
+            self.num_pages -= cmp::min(range_end, end) - cmp::max(range_start, start);
+            if range_start < start {
+                self.ranges.insert(range_start, start);
+            }
+            if range_end > end {
+                self.ranges.insert(end, range_end);
+            }
+        }
+    }
+
+    /// Whether any page of the `(start pfn, number of pages)` block is in the set.
+    pub(crate) fn overlaps(&self, block: (u64, u64)) -> bool {
+        let (start, end) = block_bounds(block);
+        self.ranges
+            .range(..end)
+            .next_back()
+            .map_or(false, |(_, &range_end)| range_end > start)
+    }
+
+    /// Number of pages of the `(start pfn, number of pages)` block in the set.
+    pub(crate) fn overlap_pages(&self, block: (u64, u64)) -> u64 {
+        let (start, end) = block_bounds(block);
+        self.ranges
+            .range(..end)
+            .rev()
+            .take_while(|&(_, &range_end)| range_end > start)
+            .map(|(&range_start, &range_end)| {
+                cmp::min(range_end, end) - cmp::max(range_start, start)
+            })
+            .sum()
+    }
+
+    /// The parts of the `(start pfn, number of pages)` block in the set, as blocks in ascending
+    /// order.
+    pub(crate) fn intersection(&self, block: (u64, u64)) -> Vec<(u64, u64)> {
+        let (start, end) = block_bounds(block);
+        let mut blocks: Vec<_> = self
+            .ranges
+            .range(..end)
+            .rev()
+            .take_while(|&(_, &range_end)| range_end > start)
+            .map(|(&range_start, &range_end)| {
+                let piece_start = cmp::max(range_start, start);
+                (piece_start, cmp::min(range_end, end) - piece_start)
+            })
+            .collect();
+        blocks.reverse();
+        blocks
+    }
+
+    /// Number of pages in the set.
+    pub(crate) fn num_pages(&self) -> u64 {
+        self.num_pages
+    }
+
+    /// The `[start, end)` pfn ranges of the set, in ascending order.
+    pub(crate) fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(&start, &end)| (start, end))
+    }
+}
+
+/// Host frame number of the first page of the range, if the range lies within one guest memory
+/// region.
+pub(crate) fn host_pfn(guest_memory: &GuestMemoryMmap, range: (GuestAddress, u64)) -> Option<u64> {
+    let guest_address = range.0;
+    let region = guest_memory.find_region(guest_address)?;
+    if !range_in_region(region, range) {
+        return None;
+    }
+    let host_address = guest_memory.get_host_address(guest_address).ok()?;
+    Some(host_address as u64 >> VIRTIO_FAASCALE_MEM_PFN_SHIFT)
+}
+
+// Converts a `(start pfn, number of pages)` block to a `[start, end)` pfn range.
+fn block_bounds(block: (u64, u64)) -> (u64, u64) {
+    (block.0, block.0.saturating_add(block.1))
+}
+
+/// Whether `range` lies within the guest memory `region`. The end of the range is computed on
+/// 64 bits and checked, so that ranges ending past 4GiB, or wrapping around the address space,
+/// are compared correctly.
+pub(crate) fn range_in_region(region: &GuestRegionMmap, range: (GuestAddress, u64)) -> bool {
+    let (guest_address, range_len) = range;
+    guest_address.0 >= region.start_addr().0
+        && guest_address
+            .0
+            .checked_add(range_len)
+            .map_or(false, |end| end <= region.start_addr().0 + region.len())
+}
+
+/// Correlation ID the guest attached to a populate block, appended to the log entries about
+/// the block so that they can be joined with the traces of the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TraceId(pub Option<u64>);
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(trace_id) => write!(f, ", trace_id={:#018x}", trace_id),
+            None => Ok(()),
+        }
+    }
+}
+
+// Builds the error of a failed `madvise`, counting its errno.
+fn madvise_fail() -> RemoveRegionError {
+    let err = io::Error::last_os_error();
+    METRICS.faascale_mem.madvise_fails.record(&err);
+    RemoveRegionError::MadviseFail(err)
+}
+
+// Write-faults every page of the `len` bytes at `addr` in, without changing their content.
+// Unlike `MADV_POPULATE_WRITE`, running out of host memory is not reported but handled by the
+// kernel as for any page fault.
+fn touch_range(addr: *mut u8, len: usize) {
+    // SAFETY: `sysconf` has no preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    for offset in (0..len).step_by(page_size) {
+        // SAFETY: The byte lies within the range, which is mapped read-write. Atomic accesses
+        // keep the concurrent writes of the guest.
+        let byte = unsafe { &*(addr.add(offset) as *const AtomicU8) };
+        let value = byte.load(Ordering::Relaxed);
+        // The exchange write-faults the page in whatever its outcome, and fails only if the
+        // guest wrote the byte meanwhile, faulting the page in itself.
+        let _ = byte.compare_exchange(value, value, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+// Builds the error of a failed `mmap`, counting its errno.
+fn mmap_fail() -> RemoveRegionError {
+    let err = io::Error::last_os_error();
+    METRICS.faascale_mem.mmap_fails.record(&err);
+    RemoveRegionError::MmapFail(err)
+}
+
+// Refuses the ranges of hugetlb backed regions that do not cover whole huge pages, as these
+// pages can only be populated and released as a whole.
+fn check_hugetlb_alignment(
+    region: &GuestRegionMmap,
+    host_address: *mut u8,
+    range_len: u64,
+) -> std::result::Result<(), RemoveRegionError> {
+    if let Some(page_size) = hugetlb_page_size(region.flags()) {
+        if (host_address as u64 | range_len) & (page_size as u64 - 1) != 0 {
+            METRICS.faascale_mem.hugetlb_misaligned_blocks.inc();
+            return Err(RemoveRegionError::MisalignedHugePage);
+        }
+    }
+    Ok(())
+}
+
+// Flags of the anonymous mapping punching a hole in `region`, which has to be backed by the
+// same kind of pages.
+fn overlay_flags(region: &GuestRegionMmap) -> libc::c_int {
+    let hugetlb_flags = libc::MAP_HUGETLB | (libc::MAP_HUGE_MASK << libc::MAP_HUGE_SHIFT);
+    libc::MAP_FIXED | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | (region.flags() & hugetlb_flags)
+}
+
+/// Applies `advice` to the part of `range` made of whole, host aligned, transparent huge
+/// pages and returns its length. Ranges that do not cover a whole huge page are left alone,
+/// and so are the hugetlb backed regions, which are made of huge pages already.
+pub(crate) fn advise_huge_pages(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+    advice: libc::c_int,
+) -> std::result::Result<u64, RemoveRegionError> {
+    let (guest_address, range_len) = range;
+
+    if let Some(region) = guest_memory.find_region(guest_address) {
+        if !range_in_region(region, range) {
+            return Err(RemoveRegionError::MalformedRange);
+        }
+        if hugetlb_page_size(region.flags()).is_some() {
+            return Ok(0);
+        }
+        let phys_address = guest_memory
+            .get_host_address(guest_address)
+            .map_err(|_| RemoveRegionError::AddressTranslation)?;
+
+        let start = phys_address as u64;
+        let aligned_start = (start + THP_SIZE - 1) & !(THP_SIZE - 1);
+        let aligned_end = (start + range_len) & !(THP_SIZE - 1);
+        if aligned_end <= aligned_start {
+            return Ok(0);
+        }
+
+        let aligned_len = aligned_end - aligned_start;
+        // SAFETY: The aligned range is contained in the validated guest range.
+        let ret = unsafe {
+            libc::madvise(
+                aligned_start as *mut libc::c_void,
+                aligned_len as usize,
+                advice,
+            )
+        };
+        if ret < 0 {
+            return Err(madvise_fail());
+        }
+
+        Ok(aligned_len)
+    } else {
+        Err(RemoveRegionError::RegionNotFound)
+    }
+}
+
+/// Gives the advice of the THP `policy` to the `range` about to be populated and returns the
+/// length advised with `MADV_HUGEPAGE`.
+pub(crate) fn apply_thp_policy(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+    policy: FaascaleMemThpPolicy,
+) -> std::result::Result<u64, RemoveRegionError> {
+    let (guest_address, range_len) = range;
+    let huge = match policy {
+        FaascaleMemThpPolicy::System => return Ok(0),
+        FaascaleMemThpPolicy::Always | FaascaleMemThpPolicy::Collapse => true,
+        FaascaleMemThpPolicy::Never => false,
+        FaascaleMemThpPolicy::Threshold => range_len >= THP_SIZE,
+    };
+    if huge {
+        return advise_huge_pages(guest_memory, range, libc::MADV_HUGEPAGE);
+    }
+
+    let region = guest_memory
+        .find_region(guest_address)
+        .ok_or(RemoveRegionError::RegionNotFound)?;
+    if !range_in_region(region, range) {
+        return Err(RemoveRegionError::MalformedRange);
+    }
+    if hugetlb_page_size(region.flags()).is_some() {
+        return Ok(0);
+    }
+    let phys_address = guest_memory
+        .get_host_address(guest_address)
+        .map_err(|_| RemoveRegionError::AddressTranslation)?;
+
+    // Unlike the huge page advice, this one covers the whole block: khugepaged could otherwise
+    // collapse its unaligned ends along with the neighbouring pages.
+    // SAFETY: The range is contained in the validated guest range.
+    let ret = unsafe {
+        libc::madvise(
+            phys_address.cast(),
+            range_len as usize,
+            libc::MADV_NOHUGEPAGE,
+        )
+    };
+    if ret < 0 {
+        return Err(madvise_fail());
+    }
+
+    Ok(0)
+}
+
+const SELF_SMAPS_PATH: &str = "/proc/self/smaps";
+
+/// Returns the number of bytes of guest memory the host backs with transparent huge pages,
+/// from the `AnonHugePages` of the mappings holding the guest memory.
+pub(crate) fn thp_backed_bytes(guest_memory: &GuestMemoryMmap) -> io::Result<u64> {
+    let regions = guest_memory
+        .iter()
+        .filter_map(|region| {
+            let start = guest_memory.get_host_address(region.start_addr()).ok()? as u64;
+            Some((start, start + region.len()))
+        })
+        .collect::<Vec<_>>();
+    fs::read_to_string(SELF_SMAPS_PATH).map(|smaps| parse_thp_backed_bytes(&smaps, &regions))
+}
+
+// Sums the `AnonHugePages` of the mappings of `smaps` overlapping the host `regions`.
+fn parse_thp_backed_bytes(smaps: &str, regions: &[(u64, u64)]) -> u64 {
+    let mapping_bounds = |line: &str| {
+        let (start, end) = line.split_whitespace().next()?.split_once('-')?;
+        Some((
+            u64::from_str_radix(start, 16).ok()?,
+            u64::from_str_radix(end, 16).ok()?,
+        ))
+    };
+    let mut in_guest_memory = false;
+    let mut backed_kib = 0u64;
+    for line in smaps.lines() {
+        if let Some((start, end)) = mapping_bounds(line) {
+            in_guest_memory = regions
+                .iter()
+                .any(|&(region_start, region_end)| start < region_end && region_start < end);
+        } else if let Some(kib) = line
+            .strip_prefix("AnonHugePages:")
+            .filter(|_| in_guest_memory)
+        {
+            backed_kib += kib
+                .split_whitespace()
+                .next()
+                .and_then(|kib| kib.parse::<u64>().ok())
+                .unwrap_or(0);
+        }
+    }
+    backed_kib << 10
+}
+
+/// Splits `range` at the boundaries of the KVM memory slots it spans, which are the guest
+/// memory regions, and returns the slot of each piece along with it. Fails with the first
+/// address of the range outside of any slot, before anything is done to the range.
+pub(crate) fn split_at_memslots(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+) -> std::result::Result<Vec<(u32, (GuestAddress, u64))>, RemoveRegionError> {
+    let (guest_address, range_len) = range;
+    let end = guest_address
+        .0
+        .checked_add(range_len)
+        .ok_or(RemoveRegionError::MalformedRange)?;
+
+    let mut pieces = Vec::new();
+    let mut start = guest_address.0;
+    while start < end {
+        let (slot, region) = guest_memory
+            .iter()
+            .enumerate()
+            .find(|(_, region)| {
+                start >= region.start_addr().0 && start < region.start_addr().0 + region.len()
+            })
+            .ok_or(RemoveRegionError::OutsideMemslot(GuestAddress(start)))?;
+        let piece_end = cmp::min(end, region.start_addr().0 + region.len());
+        pieces.push((slot as u32, (GuestAddress(start), piece_end - start)));
+        start = piece_end;
+    }
+    if pieces.len() > 1 {
+        METRICS.faascale_mem.memslot_splits.inc();
+    }
+    Ok(pieces)
+}
+
+/// Populates `range` one KVM memory slot at a time, once the whole range is known to lie
+/// within the slots. The range is placed on the NUMA node `numa_node`, if any, and its
+/// pre-allocation interleaved across the nodes of `interleave_nodes`, if any. The memory slot
+/// pieces of the range are queued in `prefault_batch`, if any, for their TDP faults to be
+/// pre-handled. Returns the time spent pre-allocating and scrubbing the range, along with the
+/// pages pre-allocated interleaved.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn populate_range(
     guest_memory: &GuestMemoryMmap,
     range: (GuestAddress, u64),
-    restored: bool,
-    pre_mem_alloc:bool,
-    pre_tdp_alloc:bool
+    mut overlays: Option<&mut MmapOverlays>,
+    thp_policy: FaascaleMemThpPolicy,
+    pre_mem_alloc: Option<PreAllocMethod>,
+    interleave_nodes: Option<u64>,
+    numa_node: Option<u32>,
+    scrub: bool,
+    mut prefault_batch: Option<&mut PrefaultBatch>,
+    trace_id: TraceId,
+) -> std::result::Result<PopulateTimings, RemoveRegionError> {
+    let mut timings = PopulateTimings::default();
+    for (slot, piece) in split_at_memslots(guest_memory, range)? {
+        timings.add(populate_slot_range(
+            guest_memory,
+            (slot, piece),
+            overlays.as_deref_mut(),
+            thp_policy,
+            pre_mem_alloc,
+            interleave_nodes,
+            numa_node,
+            scrub,
+            prefault_batch.as_deref_mut(),
+            trace_id,
+        )?);
+    }
+    Ok(timings)
+}
+
+/// Queues the memory slot pieces of `range`, whose memory is already in place, in
+/// `prefault_batch`, if any, for their TDP faults to be pre-handled. Nothing is pre-allocated.
+pub(crate) fn queue_prefault(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+    prefault_batch: Option<&mut PrefaultBatch>,
+) -> std::result::Result<PopulateTimings, RemoveRegionError> {
+    let pieces = split_at_memslots(guest_memory, range)?;
+    if let Some(prefault_batch) = prefault_batch {
+        for (slot, piece) in pieces {
+            prefault_batch.add(slot, piece);
+        }
+    }
+    Ok(PopulateTimings::default())
+}
+
+/// Pre-handles the TDP faults of `range`, lying within the KVM memory slot `slot`, through the
+/// `KVM_PREALLOC_USER_MEMORY_REGION` ioctl of the patched host KVM on `vm_fd`. Returns the time
+/// it took.
+pub(crate) fn prefault_slot_range(
+    vm_fd: &VmFd,
+    (slot, range): (u32, (GuestAddress, u64)),
+    mut prefault_sampler: Option<&mut PrefaultSampler>,
+) -> Duration {
+    let (guest_address, range_len) = range;
+    let counters_before = prefault_sampler.as_deref_mut().map(PrefaultSampler::sample);
+    let start_time = Instant::now();
+    // The default seccomp filters of a microVM with the device allow the ioctl, see the rules
+    // of the `vmm_faascale_mem` filter in `resources/seccomp/faascale_mem`.
+    // SAFETY: The ioctl only reads the region descriptor, which outlives the call.
+    let ret = unsafe {
+        libc::ioctl(
+            vm_fd.as_raw_fd(),
+            KVM_PREALLOC_USER_MEMORY_REGION() as libc::c_int,
+            &kvm_userspace_prealloc_memory_region {
+                guest_phys_addr: guest_address.0,
+                memory_size: range_len,
+            },
+        )
+    };
+    if ret < 0 {
+        log::error!(
+            "pre-tdp-fault failed in memslot {} at guest_phys_addr:{} with memory_size:{}: {}",
+            slot,
+            guest_address.0,
+            range_len,
+            io::Error::last_os_error()
+        );
+    }
+    let elapsed = start_time.elapsed();
+    // Attach the change of the counters to the trace of the pre-fault.
+    let counters = match (prefault_sampler, counters_before) {
+        (Some(sampler), Some(before)) => format!(", {}", sampler.sample().delta(&before)),
+        _ => String::new(),
+    };
+    log::info!(
+        "pre-tdp-fault use vmfd({}), at guest_phys_addr:{} with memory_size:{}, took {}ms{}",
+        vm_fd.as_raw_fd(),
+        guest_address.0,
+        range_len,
+        elapsed.as_millis(),
+        counters
+    );
+    elapsed
+}
+
+/// Writes the populate canary at the head of every memory slot piece of the populated `range`
+/// and reads it back. Returns whether all the pieces hold it, the canary is left in place for
+/// the guest to check.
+pub(crate) fn write_populate_canary(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+    trace_id: TraceId,
+) -> bool {
+    let verified = split_at_memslots(guest_memory, range).map_or(false, |pieces| {
+        pieces.into_iter().all(|(_, (guest_address, _))| {
+            guest_memory
+                .write_obj(POPULATE_CANARY, guest_address)
+                .is_ok()
+                && guest_memory
+                    .read_obj::<[u8; 8]>(guest_address)
+                    .map_or(false, |canary| canary == POPULATE_CANARY)
+        })
+    });
+    if !verified {
+        METRICS.faascale_mem.populate_verification_failures.inc();
+        log::error!(
+            "Populate verification failed at guest_phys_addr:{} with memory_size:{}{}",
+            range.0 .0,
+            range.1,
+            trace_id
+        );
+    }
+    verified
+}
+
+/// Zero-fills `range`, one KVM memory slot at a time, for the guest to find its pages
+/// zero-filled although they may still hold data.
+pub(crate) fn zero_range(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
 ) -> std::result::Result<(), RemoveRegionError> {
+    for (_, (guest_address, range_len)) in split_at_memslots(guest_memory, range)? {
+        let phys_address = guest_memory
+            .get_host_address(guest_address)
+            .map_err(|_| RemoveRegionError::AddressTranslation)?;
+        // SAFETY: The piece lies within a memory slot of the guest.
+        unsafe { libc::memset(phys_address.cast(), 0, range_len as usize) };
+        METRICS
+            .faascale_mem
+            .recycled_zeroed_bytes
+            .add(range_len as usize);
+    }
+    Ok(())
+}
+
+/// Advises `range` mergeable, for KSM to deduplicate its pages with the identical pages of other
+/// processes, or unmergeable, one KVM memory slot at a time. The hugetlb backed regions, which
+/// KSM does not scan, are left alone.
+pub(crate) fn advise_mergeable(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+    mergeable: bool,
+) -> std::result::Result<(), RemoveRegionError> {
+    let (advice, advised_bytes) = if mergeable {
+        (
+            libc::MADV_MERGEABLE,
+            &METRICS.faascale_mem.ksm_advised_bytes,
+        )
+    } else {
+        (
+            libc::MADV_UNMERGEABLE,
+            &METRICS.faascale_mem.ksm_unadvised_bytes,
+        )
+    };
+    for (_, (guest_address, range_len)) in split_at_memslots(guest_memory, range)? {
+        let hugetlb = guest_memory
+            .find_region(guest_address)
+            .map_or(false, |region| hugetlb_page_size(region.flags()).is_some());
+        if hugetlb {
+            continue;
+        }
+        let phys_address = guest_memory
+            .get_host_address(guest_address)
+            .map_err(|_| RemoveRegionError::AddressTranslation)?;
+        // SAFETY: The piece lies within a memory slot of the guest, and the advice only changes
+        // how the host shares its pages.
+        let ret = unsafe { libc::madvise(phys_address.cast(), range_len as usize, advice) };
+        if ret < 0 {
+            return Err(madvise_fail());
+        }
+        advised_bytes.add(range_len as usize);
+    }
+    Ok(())
+}
+
+/// Faults in the pages of `range` for reading, one KVM memory slot at a time. The guest memory
+/// of a microVM restored from a snapshot is mapped privately from the memory file, so the pages
+/// come from the page cache shared by the clones of the snapshot until the guest writes to them.
+pub(crate) fn rehydrate_range(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+) -> std::result::Result<(), RemoveRegionError> {
+    for (_, (guest_address, range_len)) in split_at_memslots(guest_memory, range)? {
+        let phys_address = guest_memory
+            .get_host_address(guest_address)
+            .map_err(|_| RemoveRegionError::AddressTranslation)?;
+        // SAFETY: The piece lies within a memory slot of the guest.
+        let ret =
+            unsafe { libc::madvise(phys_address.cast(), range_len as usize, MADV_POPULATE_READ) };
+        if ret < 0 {
+            return Err(madvise_fail());
+        }
+    }
+    Ok(())
+}
+
+// Populates the `range` of the KVM memory `slot`, and returns the time spent in its phases.
+#[allow(clippy::too_many_arguments)]
+fn populate_slot_range(
+    guest_memory: &GuestMemoryMmap,
+    (slot, range): (u32, (GuestAddress, u64)),
+    overlays: Option<&mut MmapOverlays>,
+    thp_policy: FaascaleMemThpPolicy,
+    pre_mem_alloc: Option<PreAllocMethod>,
+    interleave_nodes: Option<u64>,
+    numa_node: Option<u32>,
+    scrub: bool,
+    prefault_batch: Option<&mut PrefaultBatch>,
+    trace_id: TraceId,
+) -> std::result::Result<PopulateTimings, RemoveRegionError> {
     let (guest_address, range_len) = range;
+    let mut timings = PopulateTimings::default();
 
     if let Some(region) = guest_memory.find_region(guest_address) {
-        if guest_address.0 + range_len > region.start_addr().0 + region.len() {
+        if !range_in_region(region, range) {
             return Err(RemoveRegionError::MalformedRange);
         }
         let phys_address = guest_memory
             .get_host_address(guest_address)
             .map_err(|_| RemoveRegionError::AddressTranslation)?;
+        check_hugetlb_alignment(region, phys_address, range_len)?;
 
         // Mmap a new anonymous region over the present one in order to create a hole.
         // This workaround is (only) needed after resuming from a snapshot because the guest memory
         // is mmaped from file as private and there is no `madvise` flag that works for this case.
-        if restored {
+        if let Some(overlays) = overlays {
             // SAFETY: The address and length are known to be valid.
             let ret = unsafe {
                 libc::mmap(
                     phys_address.cast(),
                     range_len as usize,
                     libc::PROT_READ | libc::PROT_WRITE,
-                    libc::MAP_FIXED | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                    overlay_flags(region),
                     -1,
                     0,
                 )
             };
             if ret == libc::MAP_FAILED {
-                return Err(RemoveRegionError::MmapFail(io::Error::last_os_error()));
+                return Err(mmap_fail());
             }
+            overlays.insert(guest_address, range_len);
         };
 
+        // The advice has to be given before the pages are touched.
+        match apply_thp_policy(guest_memory, range, thp_policy) {
+            Ok(len) => METRICS.faascale_mem.thp_advised_bytes.add(len as usize),
+            Err(err) => log::error!("Error applying the THP policy: {:?}{}", err, trace_id),
+        }
+        // The policy is kept, for the pages faulted in later by the guest to land on the node
+        // as well.
+        if let Some(node) = numa_node {
+            match prefer_node(phys_address, range_len as usize, node) {
+                Ok(()) => METRICS.faascale_mem.numa_node_bytes.add(range_len as usize),
+                Err(err) => {
+                    METRICS.faascale_mem.numa_node_fails.inc();
+                    log::error!("Error placing the populated block: {}{}", err, trace_id);
+                }
+            }
+        }
+
         unsafe {
             let range_len = range_len as usize;
             //#################  touch every page in the range #################
-            if pre_mem_alloc{
+            if let Some(method) = pre_mem_alloc {
                 let start_time = std::time::Instant::now();
-                let ret = libc::madvise(phys_address.cast(), range_len, libc::MADV_POPULATE_WRITE);
-                if ret < 0 {
-                    return Err(RemoveRegionError::MadviseFail(io::Error::last_os_error()));
+                // Large blocks are spread across NUMA nodes rather than exhausting the local one.
+                let interleaved = match interleave_nodes
+                    .map(|node_mask| interleave_range(phys_address, range_len, node_mask))
+                {
+                    Some(Ok(())) => true,
+                    Some(Err(err)) => {
+                        METRICS.faascale_mem.interleave_fails.inc();
+                        log::error!(
+                            "Error interleaving the populated block: {}{}",
+                            err,
+                            trace_id
+                        );
+                        false
+                    }
+                    None => false,
+                };
+                let result = match method {
+                    PreAllocMethod::PopulateWrite => {
+                        let ret = libc::madvise(
+                            phys_address.cast(),
+                            range_len,
+                            libc::MADV_POPULATE_WRITE,
+                        );
+                        if ret == 0 {
+                            Ok(())
+                        } else if io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+                            // The host was not probed, or its kernel predates the advice.
+                            METRICS.faascale_mem.pre_alloc_touch_fallbacks.inc();
+                            touch_range(phys_address.cast(), range_len);
+                            Ok(())
+                        } else {
+                            Err(madvise_fail())
+                        }
+                    }
+                    PreAllocMethod::Touch => {
+                        METRICS.faascale_mem.pre_alloc_touch_fallbacks.inc();
+                        touch_range(phys_address.cast(), range_len);
+                        Ok(())
+                    }
+                };
+                if interleaved {
+                    // The pages faulted in later by the guest are placed as in the other blocks.
+                    let reset = match numa_node {
+                        Some(node) => prefer_node(phys_address, range_len, node),
+                        None => reset_range_policy(phys_address, range_len),
+                    };
+                    if let Err(err) = reset {
+                        METRICS.faascale_mem.interleave_fails.inc();
+                        log::error!("Error resetting the memory policy: {}{}", err, trace_id);
+                    }
+                    if result.is_ok() {
+                        METRICS.faascale_mem.interleaved_bytes.add(range_len);
+                        timings.interleaved_pages =
+                            range_len as u64 >> VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+                    }
                 }
-                log::info!("pre-mem-alloc at guest_phys_addr:{} with memory_size:{}, took {}ms", guest_address.0, range_len as u64, start_time.elapsed().as_millis());
+                result?;
+                let elapsed = start_time.elapsed();
+                timings.pre_alloc_mem = Some(elapsed);
+                log::info!("pre-mem-alloc at guest_phys_addr:{} with memory_size:{}, took {}ms{}", guest_address.0, range_len as u64, elapsed.as_millis(), trace_id);
             }
 
-            // ################# for testing by guest-kernel
-            libc::memcpy(phys_address.cast(), "KINGDO".as_ptr() as *const libc::c_void, 6);
+            // Zero the range explicitly, whatever the backing of the guest memory guarantees.
+            if scrub {
+                let start_time = std::time::Instant::now();
+                libc::memset(phys_address.cast(), 0, range_len);
+                timings.scrub = Some(start_time.elapsed());
+                METRICS.faascale_mem.scrubbed_bytes.add(range_len);
+            }
 
             //################# pre handle tdp-pagefault for per faascale-block-page #################
-            if pre_tdp_alloc{
-                let start_time = std::time::Instant::now();
-                // ioctl syscall is disabled while vcpu is running, we should disable the seccomp filter,
-                // details can be found in  https://github.com/firecracker-microvm/firecracker/blob/main/docs/seccompiler.md
-                libc::ioctl(get_global_vm_fd(), KVM_PREALLOC_USER_MEMORY_REGION() as libc::c_int,
-                            &kvm_userspace_prealloc_memory_region {
-                                guest_phys_addr: guest_address.0,
-                                memory_size: range_len as u64,
-                            },
-                );
-                log::info!("pre-tdp-fault use vmfd({}), at guest_phys_addr:{} with memory_size:{}, took {}ms",get_global_vm_fd(), guest_address.0, range_len as u64, start_time.elapsed().as_millis());
+            // The faults are pre-handled by the caller, along with the ones of the contiguous
+            // blocks.
+            if let Some(prefault_batch) = prefault_batch {
+                prefault_batch.add(slot, range);
             }
         };
 
-        Ok(())
+        Ok(timings)
     } else {
         Err(RemoveRegionError::RegionNotFound)
     }
 }
 
+/// Removes `range` one KVM memory slot at a time, once the whole range is known to lie
+/// within the slots. The host memory is released with the advice of `mode`.
 pub(crate) fn remove_range(
     guest_memory: &GuestMemoryMmap,
     range: (GuestAddress, u64),
-    restored: bool,
+    mut overlays: Option<&mut MmapOverlays>,
+    mode: FaascaleMemDepopulateMode,
+) -> std::result::Result<(), RemoveRegionError> {
+    for (_, piece) in split_at_memslots(guest_memory, range)? {
+        remove_slot_range(guest_memory, piece, overlays.as_deref_mut(), mode)?;
+    }
+    Ok(())
+}
+
+// Removes the `range` lying within one KVM memory slot.
+fn remove_slot_range(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+    overlays: Option<&mut MmapOverlays>,
+    mode: FaascaleMemDepopulateMode,
 ) -> std::result::Result<(), RemoveRegionError> {
     let (guest_address, range_len) = range;
 
     if let Some(region) = guest_memory.find_region(guest_address) {
-        if guest_address.0 + range_len > region.start_addr().0 + region.len() {
+        if !range_in_region(region, range) {
             return Err(RemoveRegionError::MalformedRange);
         }
         let phys_address = guest_memory
             .get_host_address(guest_address)
             .map_err(|_| RemoveRegionError::AddressTranslation)?;
+        check_hugetlb_alignment(region, phys_address, range_len)?;
 
         // Mmap a new anonymous region over the present one in order to create a hole.
         // This workaround is (only) needed after resuming from a snapshot because the guest memory
         // is mmaped from file as private and there is no `madvise` flag that works for this case.
-        if restored {
+        if let Some(overlays) = overlays {
             // SAFETY: The address and length are known to be valid.
             let ret = unsafe {
                 libc::mmap(
                     phys_address.cast(),
                     range_len as usize,
                     libc::PROT_READ | libc::PROT_WRITE,
-                    libc::MAP_FIXED | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                    overlay_flags(region),
                     -1,
                     0,
                 )
             };
             if ret == libc::MAP_FAILED {
-                return Err(RemoveRegionError::MmapFail(io::Error::last_os_error()));
+                return Err(mmap_fail());
             }
+            overlays.insert(guest_address, range_len);
         };
 
         // Madvise the region in order to mark it as not used.
-        // SAFETY: The address and length are known to be valid.
-        let ret = unsafe {
-            let range_len = range_len as usize;
-            libc::madvise(phys_address.cast(), range_len, libc::MADV_DONTNEED)
+        let advise = |advice| {
+            // SAFETY: The address and length are known to be valid.
+            unsafe { libc::madvise(phys_address.cast(), range_len as usize, advice) }
         };
+        let mut ret = advise(depopulate_advice(mode));
+        // The lazier advices are refused on some backings, such as shared or hugetlb memory,
+        // whose pages are then freed right away.
+        if ret < 0
+            && mode != FaascaleMemDepopulateMode::DontNeed
+            && io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL)
+        {
+            METRICS.faascale_mem.depopulate_mode_fallbacks.inc();
+            ret = advise(libc::MADV_DONTNEED);
+        }
         if ret < 0 {
-            return Err(RemoveRegionError::MadviseFail(io::Error::last_os_error()));
+            return Err(madvise_fail());
         }
 
         Ok(())
     } else {
         Err(RemoveRegionError::RegionNotFound)
     }
-}
\ No newline at end of file
+}
+
+// Advice of `madvise` releasing the pages in the depopulate `mode`.
+fn depopulate_advice(mode: FaascaleMemDepopulateMode) -> libc::c_int {
+    match mode {
+        FaascaleMemDepopulateMode::DontNeed => libc::MADV_DONTNEED,
+        FaascaleMemDepopulateMode::Free => libc::MADV_FREE,
+        FaascaleMemDepopulateMode::Cold => libc::MADV_COLD,
+        FaascaleMemDepopulateMode::Pageout => libc::MADV_PAGEOUT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use utils::vm_memory::test_utils::create_anon_guest_memory;
+    use utils::vm_memory::Bytes;
+
+    use super::*;
+
+    // First pfn past 4GiB.
+    const PFN_4GIB: u32 = 1 << (32 - VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+
+    // Two adjacent slots meeting at 4GiB, like the guest memory below the MMIO gap and the one
+    // past it when they are contiguous.
+    fn boundary_mem() -> GuestMemoryMmap {
+        create_anon_guest_memory(
+            &[
+                (GuestAddress(0xffff_0000), 0x10000),
+                (GuestAddress(0x1_0000_0000), 0x10000),
+            ],
+            false,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pfn_ranges() {
+        let mut ranges = PfnRanges::default();
+        let check = |ranges: &PfnRanges, expected: &[(u64, u64)]| {
+            assert_eq!(ranges.ranges().collect::<Vec<_>>(), expected);
+            assert_eq!(
+                ranges.num_pages(),
+                expected.iter().map(|(start, end)| end - start).sum::<u64>()
+            );
+        };
+
+        ranges.insert((0x10, 0x10));
+        ranges.insert((0x40, 0x10));
+        check(&ranges, &[(0x10, 0x20), (0x40, 0x50)]);
+        // Overlapping and adjacent blocks are merged.
+        ranges.insert((0x18, 0x10));
+        ranges.insert((0x28, 0x18));
+        check(&ranges, &[(0x10, 0x50)]);
+        ranges.insert((0x20, 0x8));
+        check(&ranges, &[(0x10, 0x50)]);
+        assert_eq!(ranges.overlap_pages((0x0, 0x20)), 0x10);
+        assert!(!ranges.overlaps((0x50, 0x10)));
+
+        // Removing a block splits the ranges it partially covers.
+        ranges.remove((0x20, 0x10));
+        check(&ranges, &[(0x10, 0x20), (0x30, 0x50)]);
+        ranges.remove((0x0, 0x18));
+        ranges.remove((0x48, 0x100));
+        check(&ranges, &[(0x18, 0x20), (0x30, 0x48)]);
+        assert_eq!(
+            ranges.intersection((0x1c, 0x20)),
+            vec![(0x1c, 0x4), (0x30, 0xc)]
+        );
+        assert_eq!(ranges.intersection((0x20, 0x10)), vec![]);
+        ranges.remove((0x0, 0x100));
+        check(&ranges, &[]);
+    }
+
+    #[test]
+    fn test_split_at_memslots() {
+        // Two adjacent slots, followed by a gap and a third slot.
+        let mem = create_anon_guest_memory(
+            &[
+                (GuestAddress(0), 0x10000),
+                (GuestAddress(0x10000), 0x10000),
+                (GuestAddress(0x40000), 0x10000),
+            ],
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            split_at_memslots(&mem, (GuestAddress(0x1000), 0x2000)).unwrap(),
+            vec![(0, (GuestAddress(0x1000), 0x2000))]
+        );
+        assert_eq!(
+            split_at_memslots(&mem, (GuestAddress(0xf000), 0x2000)).unwrap(),
+            vec![
+                (0, (GuestAddress(0xf000), 0x1000)),
+                (1, (GuestAddress(0x10000), 0x1000))
+            ]
+        );
+        assert!(split_at_memslots(&mem, (GuestAddress(0x1000), 0))
+            .unwrap()
+            .is_empty());
+
+        // The first address outside of the slots is reported.
+        assert!(matches!(
+            split_at_memslots(&mem, (GuestAddress(0x1f000), 0x2000)),
+            Err(RemoveRegionError::OutsideMemslot(GuestAddress(0x20000)))
+        ));
+        assert!(matches!(
+            split_at_memslots(&mem, (GuestAddress(0x30000), 0x1000)),
+            Err(RemoveRegionError::OutsideMemslot(GuestAddress(0x30000)))
+        ));
+        assert!(matches!(
+            split_at_memslots(&mem, (GuestAddress(u64::MAX), 0x1000)),
+            Err(RemoveRegionError::MalformedRange)
+        ));
+    }
+
+    #[test]
+    fn test_advise_mergeable() {
+        let mem = create_anon_guest_memory(
+            &[(GuestAddress(0), 0x10000), (GuestAddress(0x10000), 0x10000)],
+            false,
+        )
+        .unwrap();
+
+        // Ranges spanning two slots are advised one slot at a time.
+        let advised = METRICS.faascale_mem.ksm_advised_bytes.count();
+        advise_mergeable(&mem, (GuestAddress(0xf000), 0x2000), true).unwrap();
+        assert!(METRICS.faascale_mem.ksm_advised_bytes.count() >= advised + 0x2000);
+        let unadvised = METRICS.faascale_mem.ksm_unadvised_bytes.count();
+        advise_mergeable(&mem, (GuestAddress(0xf000), 0x2000), false).unwrap();
+        assert!(METRICS.faascale_mem.ksm_unadvised_bytes.count() >= unadvised + 0x2000);
+
+        assert!(matches!(
+            advise_mergeable(&mem, (GuestAddress(0x1f000), 0x2000), true),
+            Err(RemoveRegionError::OutsideMemslot(GuestAddress(0x20000)))
+        ));
+    }
+
+    #[test]
+    fn test_pfn_ranges_narrow_blocks() {
+        // The end of narrow blocks does not fit in 32 bits past the 16TiB of guest memory their
+        // pfns address.
+        let cfg = ProptestConfig::with_cases(1000);
+        proptest!(cfg, |(pfn in any::<u32>(), num_pages in any::<u32>())| {
+            let block = (u64::from(pfn), u64::from(num_pages));
+            prop_assert_eq!(block_bounds(block), (block.0, block.0 + block.1));
+
+            let mut ranges = PfnRanges::default();
+            ranges.insert(block);
+            prop_assert_eq!(ranges.num_pages(), block.1);
+            prop_assert_eq!(ranges.overlap_pages(block), block.1);
+            prop_assert_eq!(ranges.overlaps(block), num_pages != 0);
+            ranges.remove(block);
+            prop_assert_eq!(ranges.num_pages(), 0);
+        });
+
+        let mut ranges = PfnRanges::default();
+        ranges.insert((u64::from(u32::MAX), u64::from(u32::MAX)));
+        assert_eq!(ranges.num_pages(), 0xffff_ffff);
+        ranges.remove((0x1_0000_0000, 0x10));
+        assert_eq!(
+            ranges.ranges().collect::<Vec<_>>(),
+            vec![(0xffff_ffff, 0x1_0000_0000), (0x1_0000_0010, 0x1_ffff_fffe)]
+        );
+        assert_eq!(ranges.num_pages(), 0xffff_ffef);
+
+        // Wide blocks reaching the end of the pfns are clamped instead of wrapping around.
+        assert_eq!(block_bounds((u64::MAX - 1, 4)), (u64::MAX - 1, u64::MAX));
+    }
+
+    #[test]
+    fn test_range_in_region() {
+        let mem = boundary_mem();
+        let low = mem.find_region(GuestAddress(0xffff_0000)).unwrap();
+        let high = mem.find_region(GuestAddress(0x1_0000_0000)).unwrap();
+
+        assert!(range_in_region(low, (GuestAddress(0xffff_f000), 0x1000)));
+        assert!(!range_in_region(low, (GuestAddress(0xffff_f000), 0x2000)));
+        assert!(!range_in_region(low, (GuestAddress(0xffff_f000), u64::MAX)));
+        assert!(range_in_region(
+            high,
+            (GuestAddress(0x1_0000_0000), 0x10000)
+        ));
+        assert!(!range_in_region(high, (GuestAddress(0xffff_f000), 0x1000)));
+        assert!(!range_in_region(
+            high,
+            (GuestAddress(0x1_0000_f000), u64::MAX)
+        ));
+    }
+
+    #[test]
+    fn test_split_at_memslots_4gib_boundary() {
+        let mem = boundary_mem();
+
+        // Narrow blocks starting below 4GiB and ending above it are split at 4GiB, with the
+        // length of both pieces computed on 64 bits.
+        let cfg = ProptestConfig::with_cases(1000);
+        proptest!(cfg, |(pfn in PFN_4GIB - 0x10..PFN_4GIB, end in PFN_4GIB + 1..=PFN_4GIB + 0x10)| {
+            let start = u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+            let range = (
+                GuestAddress(start),
+                u64::from(end - pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+            );
+            let pieces = split_at_memslots(&mem, range).unwrap();
+            prop_assert_eq!(
+                pieces,
+                vec![
+                    (0, (GuestAddress(start), 0x1_0000_0000 - start)),
+                    (
+                        1,
+                        (
+                            GuestAddress(0x1_0000_0000),
+                            u64::from(end - PFN_4GIB) << VIRTIO_FAASCALE_MEM_PFN_SHIFT
+                        )
+                    ),
+                ]
+            );
+            // The block spans two regions, it has no single host frame.
+            prop_assert_eq!(host_pfn(&mem, range), None);
+        });
+
+        // Ranges ending exactly at 4GiB stay in the slot below it.
+        assert_eq!(
+            split_at_memslots(&mem, (GuestAddress(0xffff_f000), 0x1000)).unwrap(),
+            vec![(0, (GuestAddress(0xffff_f000), 0x1000))]
+        );
+        assert!(matches!(
+            split_at_memslots(&mem, (GuestAddress(0xffff_f000), 0x1_0000_2000)),
+            Err(RemoveRegionError::OutsideMemslot(GuestAddress(
+                0x1_0001_0000
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_remove_range_modes() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+
+        mem.write_obj(0xdead_beef_u32, GuestAddress(0x1000))
+            .unwrap();
+        remove_range(
+            &mem,
+            (GuestAddress(0), 0x10000),
+            None,
+            FaascaleMemDepopulateMode::DontNeed,
+        )
+        .unwrap();
+        assert_eq!(mem.read_obj::<u32>(GuestAddress(0x1000)).unwrap(), 0);
+
+        // The pages only deactivated keep their contents.
+        mem.write_obj(0xdead_beef_u32, GuestAddress(0x1000))
+            .unwrap();
+        remove_range(
+            &mem,
+            (GuestAddress(0), 0x10000),
+            None,
+            FaascaleMemDepopulateMode::Cold,
+        )
+        .unwrap();
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(0x1000)).unwrap(),
+            0xdead_beef
+        );
+
+        for mode in [
+            FaascaleMemDepopulateMode::Free,
+            FaascaleMemDepopulateMode::Pageout,
+        ] {
+            remove_range(&mem, (GuestAddress(0), 0x10000), None, mode).unwrap();
+        }
+        assert!(matches!(
+            remove_range(
+                &mem,
+                (GuestAddress(0xf000), 0x2000),
+                None,
+                FaascaleMemDepopulateMode::Free
+            ),
+            Err(RemoveRegionError::OutsideMemslot(GuestAddress(0x10000)))
+        ));
+    }
+
+    #[test]
+    fn test_touch_range() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        mem.write_obj(0xdead_beef_u32, GuestAddress(0x1000))
+            .unwrap();
+        let host_address = mem.get_host_address(GuestAddress(0)).unwrap();
+
+        // Every page is faulted in, and the contents are kept.
+        touch_range(host_address, 0x10000);
+        // SAFETY: `sysconf` has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut residency = vec![0u8; 0x10000 / page_size];
+        // SAFETY: The range is mapped, and the vector holds a byte per page.
+        let ret = unsafe { libc::mincore(host_address.cast(), 0x10000, residency.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        assert!(residency.iter().all(|&page| page & 1 != 0));
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(0x1000)).unwrap(),
+            0xdead_beef
+        );
+        assert_eq!(mem.read_obj::<u32>(GuestAddress(0x2000)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_parse_thp_backed_bytes() {
+        let smaps = "\
+7f0000000000-7f0000400000 rw-p 00000000 00:00 0
+Size:               4096 kB
+AnonHugePages:      2048 kB
+VmFlags: rd wr mr mw me ac hg
+7f0000400000-7f0000800000 rw-p 00000000 00:00 0
+Size:               4096 kB
+AnonHugePages:      4096 kB
+7f1000000000-7f1000200000 rw-p 00000000 00:00 0
+AnonHugePages:      2048 kB
+";
+        // Only the mappings holding guest memory are counted, even when split by the advice.
+        let regions = [(0x7f00_0000_0000, 0x7f00_0080_0000)];
+        assert_eq!(parse_thp_backed_bytes(smaps, &regions), 6 << 20);
+        assert_eq!(parse_thp_backed_bytes(smaps, &[]), 0);
+        assert_eq!(parse_thp_backed_bytes("", &regions), 0);
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Populate activity of the guest right after boot.
+//!
+//! A booting guest populates most of the memory it needs in a burst: the kernel, the init
+//! process and the workload all fault in their working set. The warm-up ends with the first
+//! quiet period without any populate request, and is reported with the memory populated
+//! until then, which characterizes the cold start of the guest image.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::MIB_TO_4K_PAGES;
+
+/// Time without populate requests after which the boot warm-up is over.
+pub const BOOT_WARMUP_QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+/// Populate activity of the guest from the device activation until it first quiesced.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemBootWarmup {
+    /// Time from the device activation until the last populate request of the warm-up, in
+    /// milliseconds.
+    pub duration_ms: u64,
+    /// Memory populated during the warm-up, in MiB.
+    pub populated_mib: u64,
+}
+
+/// Tracks the populate requests until the warm-up is over.
+#[derive(Debug, Default)]
+pub(crate) struct BootWarmupTracker {
+    activated_at: Option<Instant>,
+    last_request_at: Option<Instant>,
+    populated_pages: u64,
+    report: Option<FaascaleMemBootWarmup>,
+}
+
+impl BootWarmupTracker {
+    /// Starts the warm-up.
+    pub fn activate(&mut self, now: Instant) {
+        self.activated_at = Some(now);
+    }
+
+    /// Records a populate request. Requests coming after the warm-up are not counted.
+    pub fn request(&mut self, now: Instant) {
+        if self.report.is_none() {
+            self.report = self.report(now);
+        }
+        if self.report.is_none() && self.activated_at.is_some() {
+            self.last_request_at = Some(now);
+        }
+    }
+
+    /// Adds the pages populated for the latest request to the warm-up.
+    pub fn populated(&mut self, num_pages: u64) {
+        if self.report.is_none() && self.activated_at.is_some() {
+            self.populated_pages += num_pages;
+        }
+    }
+
+    /// Returns the report of the warm-up, once it is over.
+    pub fn report(&self, now: Instant) -> Option<FaascaleMemBootWarmup> {
+        if self.report.is_some() {
+            return self.report.clone();
+        }
+        let activated_at = self.activated_at?;
+        let last_request_at = self.last_request_at.unwrap_or(activated_at);
+        if now.saturating_duration_since(last_request_at) < BOOT_WARMUP_QUIET_PERIOD {
+            return None;
+        }
+        Some(FaascaleMemBootWarmup {
+            duration_ms: u64::try_from(
+                last_request_at
+                    .saturating_duration_since(activated_at)
+                    .as_millis(),
+            )
+            .unwrap_or(u64::MAX),
+            populated_mib: self.populated_pages / u64::from(MIB_TO_4K_PAGES),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_warmup() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut tracker = BootWarmupTracker::default();
+
+        // Nothing is tracked before the activation.
+        tracker.request(at(0));
+        tracker.populated(256);
+        assert_eq!(tracker.report(at(1000)), None);
+
+        tracker.activate(at(0));
+        tracker.request(at(100));
+        tracker.populated(512);
+        tracker.request(at(300));
+        tracker.populated(256);
+        assert_eq!(tracker.report(at(400)), None);
+        let report = FaascaleMemBootWarmup {
+            duration_ms: 300,
+            populated_mib: 3,
+        };
+        assert_eq!(tracker.report(at(800)), Some(report.clone()));
+
+        // The requests after the first quiet period are left out.
+        tracker.request(at(1000));
+        tracker.populated(256);
+        assert_eq!(tracker.report(at(1000)), Some(report.clone()));
+        assert_eq!(tracker.report(at(5000)), Some(report));
+    }
+
+    #[test]
+    fn test_boot_warmup_without_requests() {
+        let start = Instant::now();
+        let mut tracker = BootWarmupTracker::default();
+        tracker.activate(start);
+        assert_eq!(tracker.report(start), None);
+        assert_eq!(
+            tracker.report(start + BOOT_WARMUP_QUIET_PERIOD),
+            Some(FaascaleMemBootWarmup::default())
+        );
+    }
+}
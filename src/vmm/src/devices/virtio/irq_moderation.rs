@@ -0,0 +1,251 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Adaptive moderation of the used buffer notifications of the memory devices.
+//!
+//! The memory given back to the guest, on balloon deflate or faascale-mem depopulate, is often
+//! streamed by the guest driver in many small requests, and raising an interrupt at the end of
+//! every processing pass costs the guest an exit per handful of requests. While the guest
+//! submits requests at a high rate, the moderator holds the notification of a pass back so that
+//! the next passes share it. A held notification is raised by the timer of the moderator once
+//! the hold window elapses, so no request waits longer than `MAX_HOLD` for its completion.
+//!
+//! The hold window adapts to the guest. A kick received while a notification is held shows the
+//! guest is not waiting on it, and widens the window. A notification raised by the timer shows
+//! it may be, and narrows the window, down to suspending the moderation for a while. The window
+//! never exceeds the time the guest takes to react to a notification, measured from the
+//! notification to the next kick of the queue, so that a guest waiting on a held notification
+//! is delayed at most by as much again.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use timerfd::{SetTimeFlags, TimerState};
+use utils::clock::{Clock, Timer};
+
+/// Longest a notification is held back.
+pub const MAX_HOLD: Duration = Duration::from_millis(1);
+// Shortest hold window, the timer is not worth arming for less.
+const MIN_HOLD: Duration = Duration::from_micros(20);
+// Number of passes notified right away once the moderation is suspended.
+const SUSPEND_PASSES: u32 = 64;
+// Weight of the history in the moving averages, as a power of two.
+const AVERAGE_SHIFT: u32 = 3;
+
+/// Moderates the used buffer notifications of a queue.
+#[derive(Debug)]
+pub struct InterruptModerator {
+    enabled: bool,
+    timer: Box<dyn Timer>,
+    // Whether a notification is held back, until the timer raises it.
+    held: bool,
+    // Current hold window.
+    window: Duration,
+    // Number of passes left to notify right away.
+    suspended_passes: u32,
+    // When the last pass completed requests.
+    last_pass: Option<Instant>,
+    // When the last notification was raised, until the guest reacts to it.
+    last_notification: Option<Instant>,
+    // Moving averages of the interval between the passes and of the guest reaction, in
+    // microseconds.
+    pass_interval_us: Option<u64>,
+    reaction_us: Option<u64>,
+}
+
+impl InterruptModerator {
+    /// Creates a moderator, notifying every pass unless `enabled`, whose timer runs on `clock`.
+    pub fn new(enabled: bool, clock: &dyn Clock) -> io::Result<Self> {
+        Ok(InterruptModerator {
+            enabled,
+            timer: clock.timer()?,
+            held: false,
+            window: MIN_HOLD,
+            suspended_passes: 0,
+            last_pass: None,
+            last_notification: None,
+            pass_interval_us: None,
+            reaction_us: None,
+        })
+    }
+
+    /// Whether the notifications are moderated.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enables or disables the moderation. A notification held back is still raised by the
+    /// timer.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Whether a notification is held back.
+    pub fn held(&self) -> bool {
+        self.held
+    }
+
+    /// Records a kick of the queue by the guest.
+    pub fn kicked(&mut self, now: Instant) {
+        if self.held {
+            // The guest submits requests without waiting for the held notification.
+            self.window = (self.window * 2).min(MAX_HOLD);
+        } else if let Some(notified) = self.last_notification.take() {
+            update_average(
+                &mut self.reaction_us,
+                now.saturating_duration_since(notified),
+            );
+        }
+    }
+
+    /// Records a processing pass which completed requests, and returns whether its
+    /// notification must be raised now, along with the one held back, if any.
+    pub fn pass_completed(&mut self, now: Instant) -> bool {
+        if let Some(last_pass) = self.last_pass.replace(now) {
+            update_average(
+                &mut self.pass_interval_us,
+                now.saturating_duration_since(last_pass),
+            );
+        }
+        match self.hold_window() {
+            Some(window) => {
+                // The first notification held back sets the deadline of the next ones.
+                if !self.held {
+                    self.held = true;
+                    self.timer
+                        .set_state(TimerState::Oneshot(window), SetTimeFlags::Default);
+                }
+                false
+            }
+            None => {
+                self.suspended_passes = self.suspended_passes.saturating_sub(1);
+                self.notified(now);
+                true
+            }
+        }
+    }
+
+    /// Handles the expiry of the timer, and returns whether the notification held back must be
+    /// raised now.
+    pub fn timer_expired(&mut self, now: Instant) -> bool {
+        self.timer.read();
+        if !self.held {
+            return false;
+        }
+        // The guest did not come back within the window, it may be waiting on the notification.
+        self.window /= 2;
+        if self.window < MIN_HOLD {
+            self.window = MIN_HOLD;
+            self.suspended_passes = SUSPEND_PASSES;
+        }
+        self.notified(now);
+        true
+    }
+
+    // The window of a notification held back now, if the guest submits requests fast enough for
+    // the next passes to share it.
+    fn hold_window(&self) -> Option<Duration> {
+        if !self.enabled || self.suspended_passes > 0 {
+            return None;
+        }
+        let pass_interval = Duration::from_micros(self.pass_interval_us?);
+        let reaction = Duration::from_micros(self.reaction_us?);
+        if pass_interval > MAX_HOLD {
+            return None;
+        }
+        Some(self.window.min(reaction).max(MIN_HOLD))
+    }
+
+    fn notified(&mut self, now: Instant) {
+        if self.held {
+            self.held = false;
+            self.timer
+                .set_state(TimerState::Disarmed, SetTimeFlags::Default);
+        }
+        self.last_notification = Some(now);
+    }
+}
+
+impl AsRawFd for InterruptModerator {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+fn update_average(average: &mut Option<u64>, sample: Duration) {
+    let sample = u64::try_from(sample.as_micros()).unwrap_or(u64::MAX);
+    *average = Some(match *average {
+        Some(average) => average - (average >> AVERAGE_SHIFT) + (sample >> AVERAGE_SHIFT),
+        None => sample,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::clock::ManualClock;
+
+    use super::*;
+
+    fn at(start: Instant, us: u64) -> Instant {
+        start + Duration::from_micros(us)
+    }
+
+    #[test]
+    fn test_disabled_moderator() {
+        let mut moderator = InterruptModerator::new(false, &ManualClock::new()).unwrap();
+        let start = Instant::now();
+        for us in 0..10 {
+            moderator.kicked(at(start, us * 10));
+            assert!(moderator.pass_completed(at(start, us * 10)));
+        }
+        assert!(!moderator.held());
+        assert!(!moderator.timer_expired(at(start, 100)));
+    }
+
+    #[test]
+    fn test_interrupt_moderator() {
+        let clock = ManualClock::new();
+        let mut moderator = InterruptModerator::new(true, &clock).unwrap();
+        let start = clock.now();
+
+        // Nothing is known of the guest yet.
+        assert!(moderator.pass_completed(start));
+        moderator.kicked(at(start, 100));
+        // The guest submits requests fast enough.
+        assert!(!moderator.pass_completed(at(start, 100)));
+        assert!(moderator.held());
+        // The timer goes off once the hold window elapsed.
+        clock.advance(MIN_HOLD - Duration::from_micros(1));
+        assert_eq!(moderator.timer.read(), 0);
+        clock.advance(Duration::from_micros(1));
+        assert_eq!(moderator.timer.read(), 1);
+
+        // The guest does not wait for the held notification, the next passes share it.
+        moderator.kicked(at(start, 120));
+        assert!(!moderator.pass_completed(at(start, 120)));
+        assert_eq!(moderator.window, MIN_HOLD * 2);
+        assert!(moderator.timer_expired(at(start, 200)));
+        assert!(!moderator.held());
+        assert_eq!(moderator.window, MIN_HOLD);
+        assert!(!moderator.timer_expired(at(start, 210)));
+
+        // The guest keeps waiting on the held notifications, the moderation is suspended.
+        moderator.kicked(at(start, 300));
+        assert!(!moderator.pass_completed(at(start, 300)));
+        assert!(moderator.timer_expired(at(start, 320)));
+        for pass in 0..SUSPEND_PASSES {
+            let now = at(start, 400 + u64::from(pass));
+            moderator.kicked(now);
+            assert!(moderator.pass_completed(now));
+        }
+        moderator.kicked(at(start, 500));
+        assert!(!moderator.pass_completed(at(start, 500)));
+
+        // Passes too far apart to share a notification are notified right away.
+        let mut moderator = InterruptModerator::new(true, &ManualClock::new()).unwrap();
+        assert!(moderator.pass_completed(start));
+        moderator.kicked(at(start, 5000));
+        assert!(moderator.pass_completed(at(start, 5000)));
+    }
+}
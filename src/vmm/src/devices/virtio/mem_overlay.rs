@@ -0,0 +1,202 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Accounting of the anonymous mappings laid over the guest memory of restored microVMs.
+//!
+//! The guest memory of a restored microVM is a private file mapping of the snapshot, so the
+//! memory devices give pages back to the host by mapping anonymous memory over them. Each of
+//! these overlays may split the guest memory mapping into more VMAs.
+
+use std::collections::BTreeMap;
+
+use utils::errno;
+use utils::vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+/// Largest gap between two overlays that is overlaid to merge them. Overlaying a gap copies its
+/// content into anonymous memory, so only small gaps are worth it.
+pub const MMAP_OVERLAY_MAX_GAP: u64 = 2 << 20;
+
+/// Anonymous mappings laid over the guest memory, by guest physical address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MmapOverlays {
+    // Disjoint overlays, by start address. Adjacent overlays are merged.
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl MmapOverlays {
+    /// Records an overlay of `len` bytes starting at `addr`.
+    pub fn insert(&mut self, addr: GuestAddress, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let mut start = addr.raw_value();
+        let mut end = start.saturating_add(len);
+
+        // Absorb the overlays overlapping or touching the new one.
+        let touching: Vec<(u64, u64)> = self
+            .ranges
+            .range(..=end)
+            .rev()
+            .take_while(|&(&other_start, &other_len)| other_start + other_len >= start)
+            .map(|(&other_start, &other_len)| (other_start, other_len))
+            .collect();
+        for (other_start, other_len) in touching {
+            self.ranges.remove(&other_start);
+            start = start.min(other_start);
+            end = end.max(other_start + other_len);
+        }
+        self.ranges.insert(start, end - start);
+    }
+
+    /// Records all the overlays of `other`.
+    pub fn extend(&mut self, other: &MmapOverlays) {
+        for (start, len) in other.iter() {
+            self.insert(start, len);
+        }
+    }
+
+    /// Iterates over the overlays, in address order.
+    pub fn iter(&self) -> impl Iterator<Item = (GuestAddress, u64)> + '_ {
+        self.ranges
+            .iter()
+            .map(|(&start, &len)| (GuestAddress(start), len))
+    }
+
+    /// Number of disjoint overlays.
+    pub fn count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Total size of the overlays, in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.ranges.values().sum()
+    }
+
+    /// Returns the gaps of at most `max_gap` bytes between two overlays of the same guest
+    /// memory region. Overlaying them turns the surrounding overlays into a single mapping.
+    pub fn gaps(&self, guest_memory: &GuestMemoryMmap, max_gap: u64) -> Vec<(GuestAddress, u64)> {
+        self.iter()
+            .zip(self.iter().skip(1))
+            .filter_map(|((start, len), (next_start, _))| {
+                let gap_start = start.unchecked_add(len);
+                let gap_len = next_start.raw_value() - gap_start.raw_value();
+                let region = guest_memory.find_region(start)?;
+                let same_region = next_start < region.start_addr().unchecked_add(region.len());
+                (gap_len <= max_gap && same_region).then_some((gap_start, gap_len))
+            })
+            .collect()
+    }
+}
+
+/// Maps anonymous memory over `range` of the guest memory, keeping its content. The guest
+/// must not access the range meanwhile.
+pub(crate) fn overlay_range(
+    guest_memory: &GuestMemoryMmap,
+    range: (GuestAddress, u64),
+) -> Result<(), errno::Error> {
+    let (guest_address, range_len) = range;
+    let region = guest_memory
+        .find_region(guest_address)
+        .ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+    if guest_address.0 + range_len > region.start_addr().0 + region.len() {
+        return Err(errno::Error::new(libc::EINVAL));
+    }
+    let host_address = guest_memory
+        .get_host_address(guest_address)
+        .map_err(|_| errno::Error::new(libc::EINVAL))?;
+
+    let mut content = vec![0u8; range_len as usize];
+    // SAFETY: The address and length are known to be valid.
+    unsafe {
+        std::ptr::copy_nonoverlapping(host_address, content.as_mut_ptr(), content.len());
+        let ret = libc::mmap(
+            host_address.cast(),
+            content.len(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_FIXED | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+            -1,
+            0,
+        );
+        if ret == libc::MAP_FAILED {
+            return Err(errno::Error::last());
+        }
+        std::ptr::copy_nonoverlapping(content.as_ptr(), host_address, content.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::vm_memory::test_utils::create_anon_guest_memory;
+    use utils::vm_memory::Bytes;
+
+    use super::*;
+    use crate::devices::virtio::test_utils::default_mem;
+
+    #[test]
+    fn test_mmap_overlays_insert() {
+        let mut overlays = MmapOverlays::default();
+        overlays.insert(GuestAddress(0x1000), 0x1000);
+        overlays.insert(GuestAddress(0x4000), 0x1000);
+        overlays.insert(GuestAddress(0x4000), 0);
+        assert_eq!(overlays.count(), 2);
+        assert_eq!(overlays.bytes(), 0x2000);
+
+        // Overlapping and adjacent overlays are merged.
+        overlays.insert(GuestAddress(0x1800), 0x1000);
+        overlays.insert(GuestAddress(0x2800), 0x1800);
+        assert_eq!(
+            overlays.iter().collect::<Vec<_>>(),
+            vec![(GuestAddress(0x1000), 0x4000)]
+        );
+
+        let mut other = MmapOverlays::default();
+        other.insert(GuestAddress(0x8000), 0x1000);
+        overlays.extend(&other);
+        assert_eq!(overlays.count(), 2);
+        assert_eq!(overlays.bytes(), 0x5000);
+    }
+
+    #[test]
+    fn test_mmap_overlays_gaps() {
+        let mem = create_anon_guest_memory(
+            &[(GuestAddress(0), 0x10000), (GuestAddress(0x11000), 0x10000)],
+            false,
+        )
+        .unwrap();
+        let mut overlays = MmapOverlays::default();
+        overlays.insert(GuestAddress(0x1000), 0x1000);
+        overlays.insert(GuestAddress(0x3000), 0x1000);
+        overlays.insert(GuestAddress(0x8000), 0x1000);
+        // The gap between overlays of different regions is not part of the guest memory.
+        overlays.insert(GuestAddress(0xf000), 0x1000);
+        overlays.insert(GuestAddress(0x11000), 0x1000);
+
+        assert_eq!(
+            overlays.gaps(&mem, 0x1000),
+            vec![(GuestAddress(0x2000), 0x1000)]
+        );
+        assert_eq!(
+            overlays.gaps(&mem, 0x4000),
+            vec![
+                (GuestAddress(0x2000), 0x1000),
+                (GuestAddress(0x4000), 0x4000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overlay_range() {
+        let mem = default_mem();
+        mem.write_obj::<u64>(0xdead_beef, GuestAddress(0x2000))
+            .unwrap();
+
+        overlay_range(&mem, (GuestAddress(0x2000), 0x1000)).unwrap();
+        assert_eq!(
+            mem.read_obj::<u64>(GuestAddress(0x2000)).unwrap(),
+            0xdead_beef
+        );
+
+        assert!(overlay_range(&mem, (GuestAddress(0xf000), 0x2000)).is_err());
+    }
+}
@@ -14,11 +14,15 @@ pub mod balloon;
 pub mod block;
 pub mod device;
 mod iovec;
+pub mod irq_moderation;
+pub mod mem_overlay;
 mod mmio;
 pub mod net;
+pub mod pause_gate;
 pub mod persist;
 mod queue;
 pub mod rng;
+pub mod stats_delta;
 pub mod test_utils;
 pub mod vsock;
 
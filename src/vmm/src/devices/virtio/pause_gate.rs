@@ -0,0 +1,44 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pause state of the microVM, shared by the Vmm with the memory devices.
+//!
+//! While the vCPUs are paused, typically because a snapshot is pending, the memory devices
+//! leave the requests of the guest in their queues instead of changing the guest memory being
+//! saved. The deferred requests are processed once the microVM is resumed.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Whether the microVM is paused. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct VmPauseGate(Arc<AtomicBool>);
+
+impl VmPauseGate {
+    /// Records whether the microVM is paused.
+    pub fn set_paused(&self, paused: bool) {
+        self.0.store(paused, Ordering::SeqCst);
+    }
+
+    /// Whether the microVM is paused.
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_gate() {
+        let gate = VmPauseGate::default();
+        let shared = gate.clone();
+        assert!(!shared.is_paused());
+
+        gate.set_paused(true);
+        assert!(shared.is_paused());
+        gate.set_paused(false);
+        assert!(!shared.is_paused());
+    }
+}
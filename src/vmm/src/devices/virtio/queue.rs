@@ -17,6 +17,9 @@ use utils::vm_memory::{
 pub(super) const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 pub(super) const VIRTQ_DESC_F_WRITE: u16 = 0x2;
 
+/// Number of descriptor chains popped at once by the devices draining their queues in batches.
+pub const POP_BATCH_SIZE: u16 = 32;
+
 // GuestMemoryMmap::read_obj_from_addr() will be used to fetch the descriptor,
 // which has an explicit constraint that the entire descriptor doesn't
 // cross the page boundary. Otherwise the descriptor may be splitted into
@@ -313,8 +316,9 @@ impl Queue {
         self.len(mem) == 0
     }
 
-    /// Pop the first available descriptor chain from the avail ring.
-    pub fn pop<'b>(&mut self, mem: &'b GuestMemoryMmap) -> Option<DescriptorChain<'b>> {
+    /// Returns the number of yet-to-be-popped descriptor chains in the avail ring, after
+    /// checking that the driver did not make more of them available than fit in the queue.
+    fn checked_len(&self, mem: &GuestMemoryMmap) -> u16 {
         let len = self.len(mem);
         // The number of descriptor chain heads to process should always
         // be smaller or equal to the queue size, as the driver should
@@ -329,13 +333,36 @@ impl Queue {
             panic!("The number of available virtio descriptors is greater than queue size!");
         }
 
-        if len == 0 {
+        len
+    }
+
+    /// Pop the first available descriptor chain from the avail ring.
+    pub fn pop<'b>(&mut self, mem: &'b GuestMemoryMmap) -> Option<DescriptorChain<'b>> {
+        if self.checked_len(mem) == 0 {
             return None;
         }
 
         self.do_pop_unchecked(mem)
     }
 
+    /// Pop up to `max_n` available descriptor chains from the avail ring, reading the avail
+    /// index once for the whole batch.
+    pub fn pop_batch<'b>(
+        &mut self,
+        mem: &'b GuestMemoryMmap,
+        max_n: u16,
+    ) -> Vec<DescriptorChain<'b>> {
+        let len = min(self.checked_len(mem), max_n);
+        let mut batch = Vec::with_capacity(usize::from(len));
+        for _ in 0..len {
+            match self.do_pop_unchecked(mem) {
+                Some(head) => batch.push(head),
+                None => break,
+            }
+        }
+        batch
+    }
+
     /// Try to pop the first available descriptor chain from the avail ring.
     /// If no descriptor is available, enable notifications.
     pub fn pop_or_enable_notification<'b>(
@@ -353,6 +380,24 @@ impl Queue {
         self.do_pop_unchecked(mem)
     }
 
+    /// Try to pop up to `max_n` available descriptor chains from the avail ring.
+    /// If no descriptor is available, enable notifications.
+    pub fn pop_batch_or_enable_notification<'b>(
+        &mut self,
+        mem: &'b GuestMemoryMmap,
+        max_n: u16,
+    ) -> Vec<DescriptorChain<'b>> {
+        if !self.uses_notif_suppression {
+            return self.pop_batch(mem, max_n);
+        }
+
+        if self.try_enable_notification(mem) {
+            return Vec::new();
+        }
+
+        self.pop_batch(mem, max_n)
+    }
+
     /// Pop the first available descriptor chain from the avail ring.
     ///
     /// # Important
@@ -408,6 +453,16 @@ impl Queue {
         self.next_avail -= Wrapping(1);
     }
 
+    /// Reads the descriptor chain at `index` of the descriptor table again, for a device to
+    /// complete a chain it popped earlier and did not keep.
+    pub fn descriptor_chain<'b>(
+        &self,
+        mem: &'b GuestMemoryMmap,
+        index: u16,
+    ) -> Option<DescriptorChain<'b>> {
+        DescriptorChain::checked_new(mem, self.desc_table, self.actual_size(), index)
+    }
+
     /// Puts an available descriptor head into the used ring for use by the guest.
     pub fn add_used(
         &mut self,
@@ -863,6 +918,76 @@ pub(crate) mod tests {
         q.pop_or_enable_notification(m);
     }
 
+    #[test]
+    fn test_pop_batch() {
+        let m = &default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+
+        // Five single-descriptor chains.
+        for j in 0..5 {
+            vq.dtable[j].set(0x1000 * (j + 1) as u64, 0x1000, 0, 0);
+            vq.avail.ring[j].set(j as u16);
+        }
+        vq.avail.idx.set(5);
+
+        // The batches are capped, and pop the chains in order.
+        let batch = q.pop_batch(m, 3);
+        assert_eq!(
+            batch.iter().map(|head| head.index).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(q.len(m), 2);
+        let batch = q.pop_batch(m, 3);
+        assert_eq!(
+            batch.iter().map(|head| head.index).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert!(q.pop_batch(m, 3).is_empty());
+
+        // Undone pops are popped again by the next batch.
+        q.undo_pop();
+        assert_eq!(q.pop_batch(m, 3)[0].index, 4);
+
+        // Popping stops at the first invalid chain.
+        vq.dtable[5].set(0x6000, 0x1000, 0, 0);
+        vq.avail.ring[5].set(5);
+        vq.avail.ring[6].set(16);
+        vq.avail.idx.set(7);
+        assert_eq!(q.pop_batch(m, 3).len(), 1);
+        assert_eq!(q.len(m), 1);
+    }
+
+    #[test]
+    fn test_pop_batch_or_enable_notification() {
+        let m = &default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+
+        for j in 0..3 {
+            vq.dtable[j].set(0x1000 * (j + 1) as u64, 0x1000, 0, 0);
+            vq.avail.ring[j].set(j as u16);
+        }
+        vq.avail.idx.set(3);
+
+        // Without notification suppression, the notifications are never enabled.
+        assert_eq!(q.pop_batch_or_enable_notification(m, 2).len(), 2);
+        assert_eq!(q.pop_batch_or_enable_notification(m, 2).len(), 1);
+        assert!(q.pop_batch_or_enable_notification(m, 2).is_empty());
+        assert_eq!(q.avail_event(m), 0);
+
+        // With notification suppression, the notifications are enabled once the avail ring is
+        // drained.
+        q.enable_notif_suppression();
+        vq.dtable[3].set(0x4000, 0x1000, 0, 0);
+        vq.avail.ring[3].set(3);
+        vq.avail.idx.set(4);
+        assert_eq!(q.pop_batch_or_enable_notification(m, 2).len(), 1);
+        assert_eq!(q.avail_event(m), 0);
+        assert!(q.pop_batch_or_enable_notification(m, 2).is_empty());
+        assert_eq!(q.avail_event(m), 4);
+    }
+
     #[test]
     fn test_add_used() {
         let m = &default_mem();
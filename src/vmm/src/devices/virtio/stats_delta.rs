@@ -0,0 +1,74 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Activity of the cumulative counters reported by the guest between two statistics samples.
+//!
+//! Counters like the swap and page fault ones only ever grow while the guest runs, and most
+//! consumers only care about how much they grew since the previous sample. The memory devices
+//! report this growth, and its rate, next to the absolute values.
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Growth of a cumulative guest counter since the previous sample.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CounterDelta {
+    /// Growth of the counter.
+    pub delta: u64,
+    /// Average growth per second over the interval between the samples.
+    pub per_second: u64,
+}
+
+impl CounterDelta {
+    /// Returns the growth of a counter from its `previous` to its `current` value, sampled
+    /// `interval` apart. There is none if either sample lacks the counter, or if the counter
+    /// went backwards, as it does when the guest reboots.
+    pub fn between(
+        previous: Option<u64>,
+        current: Option<u64>,
+        interval: Duration,
+    ) -> Option<Self> {
+        let delta = current?.checked_sub(previous?)?;
+        let per_second = match interval.as_millis() {
+            0 => 0,
+            millis => u64::try_from(u128::from(delta) * 1000 / millis).unwrap_or(u64::MAX),
+        };
+        Some(CounterDelta { delta, per_second })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_delta() {
+        let interval = Duration::from_millis(500);
+        assert_eq!(
+            CounterDelta::between(Some(100), Some(150), interval),
+            Some(CounterDelta {
+                delta: 50,
+                per_second: 100
+            })
+        );
+        assert_eq!(
+            CounterDelta::between(Some(100), Some(100), interval),
+            Some(CounterDelta::default())
+        );
+
+        // No rate without an interval.
+        assert_eq!(
+            CounterDelta::between(Some(100), Some(150), Duration::ZERO),
+            Some(CounterDelta {
+                delta: 50,
+                per_second: 0
+            })
+        );
+
+        // Missing samples and counters reset by the guest have no delta.
+        assert_eq!(CounterDelta::between(None, Some(150), interval), None);
+        assert_eq!(CounterDelta::between(Some(100), None, interval), None);
+        assert_eq!(CounterDelta::between(Some(150), Some(100), interval), None);
+    }
+}
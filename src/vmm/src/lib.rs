@@ -83,13 +83,37 @@ use crate::cpu_config::templates::CpuConfiguration;
 use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
 use crate::devices::legacy::{IER_RDA_BIT, IER_RDA_OFFSET};
+#[cfg(feature = "balloon")]
 use crate::devices::virtio::balloon::Error as BalloonError;
-use crate::devices::virtio::faascale_mem::Error as FaascaleMemError;
-use crate::devices::virtio::{Balloon, FaascaleMem, BalloonConfig, FaascaleMemConfig, BalloonStats, FaascaleMemStats, Block, MmioTransport, Net, BALLOON_DEV_ID, TYPE_BALLOON, TYPE_FAASCALE_MEM, TYPE_BLOCK, TYPE_NET, FAASCALE_MEM_DEV_ID};
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::faascale_mem::{
+    Error as FaascaleMemError, FaascaleMemBlocks, FaascaleMemBudget, FaascaleMemConfigSpace,
+    FaascaleMemErrors, FaascaleMemEstimate, FaascaleMemFootprint, FaascaleMemHealth,
+    FaascaleMemHeatmap, FaascaleMemWarmReport, VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+};
+#[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+use crate::devices::virtio::mem_overlay::{overlay_range, MmapOverlays, MMAP_OVERLAY_MAX_GAP};
+use crate::devices::virtio::pause_gate::VmPauseGate;
+#[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+use crate::devices::virtio::MmioTransport;
+#[cfg(feature = "balloon")]
+use crate::devices::virtio::{
+    Balloon, BalloonConfig, BalloonConfigSpace, BalloonStats, BALLOON_DEV_ID, TYPE_BALLOON,
+};
+use crate::devices::virtio::{Block, Net, TYPE_BLOCK, TYPE_NET};
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::{
+    FaascaleMem, FaascaleMemConfig, FaascaleMemStats, HotplugSlot, FAASCALE_MEM_DEV_ID,
+    FAASCALE_MEM_HOTPLUG_SLOT_ID, TYPE_FAASCALE_MEM, TYPE_HOTPLUG_SLOT,
+};
 use crate::devices::BusDevice;
 use crate::memory_snapshot::SnapshotMemory;
 use crate::persist::{MicrovmState, MicrovmStateError, VmInfo};
 use crate::vmm_config::instance_info::{InstanceInfo, VmState};
+use crate::vmm_config::memory_devices::{
+    MemoryDevicesError, MemoryDevicesQuiesce, MemoryDevicesQuiesceToken, MemoryDevicesVerification,
+};
+use crate::vmm_config::snapshot::SnapshotMemoryInfo;
 use crate::vstate::vcpu::VcpuState;
 pub use crate::vstate::vcpu::{Vcpu, VcpuConfig, VcpuEvent, VcpuHandle, VcpuResponse};
 pub use crate::vstate::vm::Vm;
@@ -157,6 +181,10 @@ pub enum Error {
     /// Device manager error.
     #[error("{0}")]
     DeviceManager(device_manager::mmio::Error),
+    /// The faascale-mem device cannot be attached to the microVM.
+    #[cfg(feature = "faascale-mem")]
+    #[error("Cannot attach the faascale-mem device: {0:?}")]
+    FaascaleMem(FaascaleMemError),
     /// Cannot fetch the KVM dirty bitmap.
     #[error("Error getting the KVM dirty bitmap. {0}")]
     DirtyBitmap(kvm_ioctls::Error),
@@ -342,6 +370,19 @@ pub struct Vmm {
     mmio_device_manager: MMIODeviceManager,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
+
+    // Pending quiesce request of the memory devices.
+    memory_devices_quiesce: MemoryDevicesQuiesce,
+    // Pause state of the microVM shared with the memory devices, which defer their requests
+    // while it is paused.
+    pause_gate: VmPauseGate,
+    // Anonymous mappings laid over the guest memory to consolidate the memory devices ones.
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    consolidation_overlays: MmapOverlays,
+    // Guest memory of the snapshot the microVM was loaded from.
+    snapshot_memory_info: Option<SnapshotMemoryInfo>,
+    // Devices hot-plugged since the event manager last took them.
+    hotplugged_subscribers: Vec<Arc<Mutex<dyn MutEventSubscriber>>>,
 }
 
 impl Vmm {
@@ -405,11 +446,28 @@ impl Vmm {
         // Wait for vCPUs to initialize their TLS before moving forward.
         barrier.wait();
 
+        // The faascale-mem device is optional.
+        #[cfg(feature = "faascale-mem")]
+        let _ = self.with_faascale_mem(|faascale_mem| {
+            faascale_mem.set_vcpu_tids(&self.vcpu_tids());
+            Ok(())
+        });
+
         Ok(())
     }
 
+    /// Returns the thread ids of the vCPU threads started so far.
+    pub fn vcpu_tids(&self) -> Vec<libc::pid_t> {
+        self.vcpus_handles
+            .iter()
+            .filter_map(VcpuHandle::tid)
+            .collect()
+    }
+
     /// Sends a resume command to the vCPUs.
     pub fn resume_vm(&mut self) -> Result<()> {
+        self.pause_gate.set_paused(false);
+        self.process_deferred_memory_device_events();
         self.mmio_device_manager.kick_devices();
 
         // Send the events.
@@ -450,6 +508,7 @@ impl Vmm {
             return Err(Error::VcpuMessage);
         }
 
+        self.pause_gate.set_paused(true);
         self.instance_info.state = VmState::Paused;
         Ok(())
     }
@@ -705,6 +764,7 @@ impl Vmm {
     }
 
     /// Returns a reference to the balloon device if present.
+    #[cfg(feature = "balloon")]
     pub fn balloon_config(&self) -> std::result::Result<BalloonConfig, BalloonError> {
         if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID)
         {
@@ -732,6 +792,7 @@ impl Vmm {
     }
 
     /// Returns a reference to the faascale-mem device if present.
+    #[cfg(feature = "faascale-mem")]
     pub fn faascale_mem_config(&self) -> std::result::Result<FaascaleMemConfig, FaascaleMemError> {
         if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_FAASCALE_MEM), FAASCALE_MEM_DEV_ID)
         {
@@ -761,6 +822,7 @@ impl Vmm {
     /// Returns the latest balloon statistics if they are enabled.
     /// 获取最新的stats，需要注意的是，再返回之前，需要通过configspace来更新前四项的信息
     /// 并且该函数仅仅在stats_polling_interval_s>0时才有效
+    #[cfg(feature = "balloon")]
     pub fn latest_balloon_stats(&self) -> std::result::Result<BalloonStats, BalloonError> {
         if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID)
         {
@@ -789,7 +851,14 @@ impl Vmm {
         }
     }
 
+    /// Dumps the config space of the balloon device.
+    #[cfg(feature = "balloon")]
+    pub fn balloon_config_space(&self) -> std::result::Result<BalloonConfigSpace, BalloonError> {
+        self.with_balloon(|balloon| Ok(balloon.config_space_info()))
+    }
+
     /// Returns the latest faascale-mem statistics if they are enabled.
+    #[cfg(feature = "faascale-mem")]
     pub fn latest_faascale_mem_stats(&self) -> std::result::Result<FaascaleMemStats, FaascaleMemError> {
         if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_FAASCALE_MEM), FAASCALE_MEM_DEV_ID)
         {
@@ -818,48 +887,67 @@ impl Vmm {
         }
     }
 
+    /// Asks the guest to report fresh faascale-mem statistics right away, for
+    /// `poll_faascale_mem_stats` to collect them.
+    #[cfg(feature = "faascale-mem")]
+    pub fn start_faascale_mem_stats_poll(&self) -> std::result::Result<(), FaascaleMemError> {
+        self.with_faascale_mem(FaascaleMem::start_stats_poll)
+    }
+
+    /// The faascale-mem statistics the guest reported since `start_faascale_mem_stats_poll`,
+    /// or `None` while it has not answered. With `give_up`, an unanswered poll fails instead.
+    #[cfg(feature = "faascale-mem")]
+    pub fn poll_faascale_mem_stats(
+        &self,
+        give_up: bool,
+    ) -> std::result::Result<Option<FaascaleMemStats>, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| faascale_mem.take_polled_stats(give_up))
+    }
+
+    /// Asks the guest to report fresh faascale-mem statistics right away, without waiting for
+    /// them.
+    #[cfg(feature = "faascale-mem")]
+    pub fn refresh_faascale_mem_stats(&self) -> std::result::Result<(), FaascaleMemError> {
+        self.with_faascale_mem(FaascaleMem::refresh_stats)
+    }
+
     /// Updates configuration for the balloon device target size.
     /// 当用户修改了balloon的大小时，会触发这个函数，此函数会调用balloon的update_size，以修改configspace中的信息，然后通知guest读取
     /// configspace中，用户要求的最新的balloon的大小，从而inflate或者deflate气球
+    /// The target size is clamped so that the guest keeps the memory floor of the device, the
+    /// applied target size is returned.
+    #[cfg(feature = "balloon")]
     pub fn update_balloon_config(
         &mut self,
         amount_mib: u32,
-    ) -> std::result::Result<(), BalloonError> {
+    ) -> std::result::Result<u32, BalloonError> {
         // The balloon cannot have a target size greater than the size of
         // the guest memory.
-        if u64::from(amount_mib) > mem_size_mib(self.guest_memory()) {
+        let guest_mem_mib = mem_size_mib(self.guest_memory());
+        if u64::from(amount_mib) > guest_mem_mib {
             return Err(BalloonError::TooManyPagesRequested);
         }
 
-        if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID)
-        {
-            {
-                let virtio_device = busdev
-                    .lock()
-                    .expect("Poisoned lock")
-                    .as_any()
-                    .downcast_ref::<MmioTransport>()
-                    // Only MmioTransport implements BusDevice at this point.
-                    .expect("Unexpected BusDevice type")
-                    .device();
-
-                virtio_device
-                    .lock()
-                    .expect("Poisoned lock")
-                    .as_mut_any()
-                    .downcast_mut::<Balloon>()
-                    .unwrap()
-                    .update_size(amount_mib)?;
-
-                Ok(())
+        self.with_balloon(|balloon| {
+            let target_mib = balloon.floor_clamped_size(amount_mib, guest_mem_mib);
+            if target_mib < amount_mib {
+                METRICS.balloon.floor_clamps.inc();
+                warn!(
+                    "Balloon target size of {} MiB clamped to {} MiB to keep the {} MiB guest \
+                     memory floor.",
+                    amount_mib,
+                    target_mib,
+                    balloon.min_guest_mib()
+                );
             }
-        } else {
-            Err(BalloonError::DeviceNotFound)
-        }
+            balloon.update_size(target_mib)?;
+            Ok(target_mib)
+        })
     }
 
     /// Updates configuration for the balloon device as described in `balloon_stats_update`.
     /// 如果balloon的stats_polling_interval_s配置发生了变化，则会触发该函数，并最终调用update_stats_polling_interval来修改timeTD
+    #[cfg(feature = "balloon")]
     pub fn update_balloon_stats_config(
         &mut self,
         stats_polling_interval_s: u16,
@@ -891,6 +979,7 @@ impl Vmm {
     }
 
     /// Updates configuration for the faascale-mem device as described in `balloon_stats_update`.
+    #[cfg(feature = "faascale-mem")]
     pub fn update_faascale_mem_stats_config(
         &mut self,
         stats_polling_interval_s: u16,
@@ -921,6 +1010,481 @@ impl Vmm {
         }
     }
 
+    /// Runs `f` on the balloon device, if present.
+    #[cfg(feature = "balloon")]
+    fn with_balloon<T, F>(&self, f: F) -> std::result::Result<T, BalloonError>
+    where
+        F: FnOnce(&mut Balloon) -> std::result::Result<T, BalloonError>,
+    {
+        let busdev = self
+            .get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID)
+            .ok_or(BalloonError::DeviceNotFound)?;
+        let virtio_device = busdev
+            .lock()
+            .expect("Poisoned lock")
+            .as_any()
+            .downcast_ref::<MmioTransport>()
+            // Only MmioTransport implements BusDevice at this point.
+            .expect("Unexpected BusDevice type")
+            .device();
+
+        let mut locked_device = virtio_device.lock().expect("Poisoned lock");
+        f(locked_device
+            .as_mut_any()
+            .downcast_mut::<Balloon>()
+            .unwrap())
+    }
+
+    /// Hot-plugs `faascale_mem` in the slot reserved at boot. The device waits for the guest to
+    /// probe the slot again, and for the event manager to take it from
+    /// `take_hotplugged_subscribers`. The device must not be in latency mode, whose poller
+    /// thread is only started at boot.
+    #[cfg(feature = "faascale-mem")]
+    pub fn hotplug_faascale_mem(&mut self, faascale_mem: Arc<Mutex<FaascaleMem>>) -> Result<()> {
+        // The host was probed when reserving the slot, the filters forbid the probes by now.
+        let mut capabilities = None;
+        self.mmio_device_manager
+            .with_virtio_device_with_id(
+                TYPE_HOTPLUG_SLOT,
+                FAASCALE_MEM_HOTPLUG_SLOT_ID,
+                |slot: &mut HotplugSlot| {
+                    capabilities = Some(slot.capabilities());
+                    Ok(())
+                },
+            )
+            .map_err(|_| Error::DeviceManager(device_manager::mmio::Error::HotplugSlotNotFound))?;
+        {
+            let mut locked_faascale_mem = faascale_mem.lock().expect("Poisoned lock");
+            locked_faascale_mem
+                .check_guest_memory(&self.guest_memory, self.uffd.is_some())
+                .map_err(Error::FaascaleMem)?;
+            locked_faascale_mem.set_vm_fd(self.vm.shared_fd().clone());
+            locked_faascale_mem.set_vcpu_tids(&self.vcpu_tids());
+            if let Some(capabilities) = capabilities {
+                locked_faascale_mem.set_capabilities(capabilities);
+            }
+            locked_faascale_mem.set_pause_gate(self.pause_gate.clone());
+        }
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        let mmio_device = MmioTransport::new(self.guest_memory.clone(), faascale_mem.clone());
+        self.mmio_device_manager
+            .hotplug_mmio_virtio(
+                self.vm.fd(),
+                (
+                    DeviceType::Virtio(TYPE_HOTPLUG_SLOT),
+                    FAASCALE_MEM_HOTPLUG_SLOT_ID.to_string(),
+                ),
+                FAASCALE_MEM_DEV_ID.to_string(),
+                mmio_device,
+            )
+            .map_err(Error::DeviceManager)?;
+        self.hotplugged_subscribers.push(faascale_mem);
+        info!("faascale-mem device hot-plugged.");
+        Ok(())
+    }
+
+    /// Returns the devices hot-plugged since the last call, for the event manager to handle
+    /// their events.
+    pub fn take_hotplugged_subscribers(&mut self) -> Vec<Arc<Mutex<dyn MutEventSubscriber>>> {
+        std::mem::take(&mut self.hotplugged_subscribers)
+    }
+
+    /// Runs `f` on the faascale-mem device, if present.
+    #[cfg(feature = "faascale-mem")]
+    fn with_faascale_mem<T, F>(&self, f: F) -> std::result::Result<T, FaascaleMemError>
+    where
+        F: FnOnce(&mut FaascaleMem) -> std::result::Result<T, FaascaleMemError>,
+    {
+        let busdev = self
+            .get_bus_device(DeviceType::Virtio(TYPE_FAASCALE_MEM), FAASCALE_MEM_DEV_ID)
+            .ok_or(FaascaleMemError::DeviceNotFound)?;
+        let virtio_device = busdev
+            .lock()
+            .expect("Poisoned lock")
+            .as_any()
+            .downcast_ref::<MmioTransport>()
+            // Only MmioTransport implements BusDevice at this point.
+            .expect("Unexpected BusDevice type")
+            .device();
+
+        let mut locked_device = virtio_device.lock().expect("Poisoned lock");
+        let faascale_mem = locked_device
+            .as_mut_any()
+            .downcast_mut::<FaascaleMem>()
+            .unwrap();
+        let result = f(faascale_mem);
+        // The API requests resizing or reconfiguring the device change the published state.
+        faascale_mem.publish_mmds();
+        result
+    }
+
+    /// Pins or unpins a range of guest pages against depopulation by the faascale-mem device.
+    /// The range must lie within the guest memory.
+    #[cfg(feature = "faascale-mem")]
+    pub fn update_faascale_mem_pin(
+        &mut self,
+        start_pfn: u32,
+        num_pages: u32,
+        pinned: bool,
+    ) -> std::result::Result<(), FaascaleMemError> {
+        let in_memory = self.guest_memory().check_range(
+            utils::vm_memory::GuestAddress(u64::from(start_pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT),
+            (num_pages as usize) << VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+        );
+        self.with_faascale_mem(|faascale_mem| {
+            if !in_memory {
+                return Err(FaascaleMemError::PinOutsideMemory);
+            }
+            faascale_mem.update_pinned_range((start_pfn, num_pages), pinned);
+            Ok(())
+        })
+    }
+
+    /// Locks a populated range of guest pages into host memory, or unlocks it, within the
+    /// locking budget of the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn update_faascale_mem_mlock(
+        &mut self,
+        start_pfn: u32,
+        num_pages: u32,
+        locked: bool,
+    ) -> std::result::Result<(), FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| {
+            faascale_mem.update_mlocked_range((start_pfn, num_pages), locked)
+        })
+    }
+
+    /// Updates the rate limiter of the populate and depopulate requests of the faascale-mem
+    /// device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn update_faascale_mem_rate_limiter(
+        &mut self,
+        rl_bytes: BucketUpdate,
+        rl_ops: BucketUpdate,
+    ) -> std::result::Result<(), FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| {
+            faascale_mem.update_rate_limiter(rl_bytes, rl_ops);
+            Ok(())
+        })
+    }
+
+    /// Reclaims guest memory populated through the faascale-mem device, either by removing the
+    /// range of `num_pages` from `start_pfn` from the host side or by asking the guest to release
+    /// `release_mib` of memory. A range missing its start or its size is refused.
+    #[cfg(feature = "faascale-mem")]
+    pub fn depopulate_faascale_mem(
+        &mut self,
+        start_pfn: Option<u32>,
+        num_pages: Option<u32>,
+        release_mib: Option<u32>,
+    ) -> std::result::Result<(), FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| match (start_pfn, num_pages, release_mib) {
+            (Some(start_pfn), Some(num_pages), None) => {
+                faascale_mem.depopulate_range((start_pfn, num_pages))
+            }
+            (None, None, Some(release_mib)) => faascale_mem.request_release(release_mib),
+            _ => Err(FaascaleMemError::InvalidDepopulateRequest),
+        })
+    }
+
+    /// Populates guest memory ahead of the guest through the faascale-mem device, either the
+    /// range of `num_pages` from `start_pfn`, the `(start pfn, number of pages)` blocks, or
+    /// `amount_mib` of the memory not populated yet. A range missing its start or its size is
+    /// refused.
+    #[cfg(feature = "faascale-mem")]
+    pub fn populate_faascale_mem(
+        &mut self,
+        start_pfn: Option<u32>,
+        num_pages: Option<u32>,
+        amount_mib: Option<u32>,
+        blocks: &[(u32, u32)],
+    ) -> std::result::Result<FaascaleMemWarmReport, FaascaleMemError> {
+        self.with_faascale_mem(
+            |faascale_mem| match (start_pfn, num_pages, amount_mib, blocks) {
+                (Some(start_pfn), Some(num_pages), None, []) => {
+                    faascale_mem.prepopulate_blocks(&[(start_pfn, num_pages)])
+                }
+                (None, None, Some(amount_mib), []) => faascale_mem.prepopulate_amount(amount_mib),
+                (None, None, None, [_, ..]) => faascale_mem.prepopulate_blocks(blocks),
+                _ => Err(FaascaleMemError::InvalidPopulateRequest),
+            },
+        )
+    }
+
+    /// Faults in the memory populated through the faascale-mem device of a restored clone from
+    /// the snapshot memory file, within the `(start pfn, number of pages)` blocks if any.
+    #[cfg(feature = "faascale-mem")]
+    pub fn rehydrate_faascale_mem(
+        &mut self,
+        blocks: &[(u32, u32)],
+    ) -> std::result::Result<FaascaleMemWarmReport, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| faascale_mem.rehydrate(blocks))
+    }
+
+    /// Fences or unfences the faascale-mem device against populate requests.
+    #[cfg(feature = "faascale-mem")]
+    pub fn update_faascale_mem_fence(
+        &mut self,
+        fenced: bool,
+    ) -> std::result::Result<(), FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| {
+            faascale_mem.set_fenced(fenced);
+            Ok(())
+        })
+    }
+
+    /// Turns the population policies of the faascale-mem device on or off, leaving the ones
+    /// given as `None` unchanged.
+    #[cfg(feature = "faascale-mem")]
+    pub fn update_faascale_mem_population(
+        &mut self,
+        pre_alloc_mem: Option<bool>,
+        pre_tdp_fault: Option<bool>,
+    ) -> std::result::Result<(), FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| {
+            if let Some(pre_alloc_mem) = pre_alloc_mem {
+                faascale_mem.set_pre_alloc_mem(pre_alloc_mem);
+            }
+            if let Some(pre_tdp_fault) = pre_tdp_fault {
+                faascale_mem.set_pre_tdp_fault(pre_tdp_fault);
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs the internal consistency checks of the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn faascale_mem_health(&self) -> std::result::Result<FaascaleMemHealth, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| Ok(faascale_mem.health()))
+    }
+
+    /// Returns the host memory backing the guest memory populated through the faascale-mem
+    /// device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn faascale_mem_footprint(
+        &self,
+    ) -> std::result::Result<FaascaleMemFootprint, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| Ok(faascale_mem.footprint()))
+    }
+
+    /// Returns the guest memory populated through the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn faascale_mem_blocks(&self) -> std::result::Result<FaascaleMemBlocks, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| Ok(faascale_mem.blocks_info()))
+    }
+
+    /// Returns the populate and depopulate activity of the faascale-mem device, aggregated
+    /// over buckets of the guest physical address space.
+    #[cfg(feature = "faascale-mem")]
+    pub fn faascale_mem_heatmap(
+        &self,
+    ) -> std::result::Result<FaascaleMemHeatmap, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| Ok(faascale_mem.heatmap()))
+    }
+
+    /// Returns the recent populate and depopulate failures of the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn faascale_mem_errors(&self) -> std::result::Result<FaascaleMemErrors, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| Ok(faascale_mem.errors()))
+    }
+
+    /// Estimates whether and how fast the faascale-mem device could populate up to
+    /// `target_mib`.
+    #[cfg(feature = "faascale-mem")]
+    pub fn faascale_mem_estimate(
+        &self,
+        target_mib: u32,
+    ) -> std::result::Result<FaascaleMemEstimate, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| Ok(faascale_mem.estimate(target_mib)))
+    }
+
+    /// Dumps the config space of the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn faascale_mem_config_space(
+        &self,
+    ) -> std::result::Result<FaascaleMemConfigSpace, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| Ok(faascale_mem.config_space_info()))
+    }
+
+    /// Asks the guest to grow or shrink the memory populated through the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn update_faascale_mem_config(
+        &mut self,
+        target_mib: u32,
+    ) -> std::result::Result<(), FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| faascale_mem.update_size(target_mib))
+    }
+
+    /// Offers a new memory budget to the guest through the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn update_faascale_mem_budget(
+        &mut self,
+        budget_mib: u32,
+    ) -> std::result::Result<(), FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| faascale_mem.update_budget(budget_mib))
+    }
+
+    /// Returns the memory budget negotiated by the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    pub fn faascale_mem_budget(&self) -> std::result::Result<FaascaleMemBudget, FaascaleMemError> {
+        self.with_faascale_mem(|faascale_mem| faascale_mem.budget())
+    }
+
+    /// Stops the processing of the balloon and faascale-mem queues, and returns the token
+    /// resuming it. The requests being processed when the call is made are completed first.
+    pub fn quiesce_memory_devices(
+        &mut self,
+    ) -> std::result::Result<MemoryDevicesQuiesceToken, MemoryDevicesError> {
+        let token = self.memory_devices_quiesce.quiesce()?;
+        self.set_memory_devices_quiesced(true);
+        Ok(token)
+    }
+
+    /// Restarts the processing of the memory devices queues stopped by the quiesce request
+    /// identified by `token`.
+    pub fn resume_memory_devices(
+        &mut self,
+        token: MemoryDevicesQuiesceToken,
+    ) -> std::result::Result<(), MemoryDevicesError> {
+        self.memory_devices_quiesce.resume(token)?;
+        self.set_memory_devices_quiesced(false);
+        Ok(())
+    }
+
+    // Shares the pause state of the microVM with the memory devices.
+    fn share_pause_gate(&self) {
+        #[cfg(feature = "balloon")]
+        let _ = self.with_balloon(|balloon| {
+            balloon.set_pause_gate(self.pause_gate.clone());
+            Ok(())
+        });
+        #[cfg(feature = "faascale-mem")]
+        let _ = self.with_faascale_mem(|faascale_mem| {
+            faascale_mem.set_pause_gate(self.pause_gate.clone());
+            Ok(())
+        });
+    }
+
+    // Processes the requests the memory devices deferred while the microVM was paused.
+    fn process_deferred_memory_device_events(&self) {
+        #[cfg(feature = "balloon")]
+        let _ = self.with_balloon(|balloon| {
+            balloon.process_deferred_events();
+            Ok(())
+        });
+        #[cfg(feature = "faascale-mem")]
+        let _ = self.with_faascale_mem(|faascale_mem| {
+            faascale_mem.process_deferred_events();
+            Ok(())
+        });
+    }
+
+    fn set_memory_devices_quiesced(&self, quiesced: bool) {
+        // Locking a device waits for the requests it is processing, including those of the
+        // faascale-mem populate poller.
+        // The memory devices are optional.
+        #[cfg(feature = "balloon")]
+        let _ = self.with_balloon(|balloon| {
+            balloon.set_quiesced(quiesced);
+            Ok(())
+        });
+        #[cfg(feature = "faascale-mem")]
+        let _ = self.with_faascale_mem(|faascale_mem| {
+            faascale_mem.set_quiesced(quiesced);
+            Ok(())
+        });
+        #[cfg(not(any(feature = "balloon", feature = "faascale-mem")))]
+        let _ = quiesced;
+    }
+
+    /// Describes the guest memory of the snapshot the microVM was loaded from, if any.
+    pub fn snapshot_memory_info(&self) -> Option<SnapshotMemoryInfo> {
+        self.snapshot_memory_info.clone()
+    }
+
+    /// Returns the anonymous mappings laid over the guest memory of a restored microVM by the
+    /// memory devices.
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    pub fn memory_overlays(&self) -> MmapOverlays {
+        let mut overlays = self.consolidation_overlays.clone();
+        #[cfg(feature = "balloon")]
+        let _ = self.with_balloon(|balloon| {
+            overlays.extend(balloon.mmap_overlays());
+            Ok(())
+        });
+        #[cfg(feature = "faascale-mem")]
+        let _ = self.with_faascale_mem(|faascale_mem| {
+            overlays.extend(faascale_mem.mmap_overlays());
+            Ok(())
+        });
+        overlays
+    }
+
+    /// Maps anonymous memory over the small gaps between the overlays laid over the guest
+    /// memory by the memory devices, so that they are merged into fewer mappings. The microVM
+    /// must be paused, as the content of the gaps is copied over.
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    pub fn consolidate_memory_overlays(&mut self) -> std::result::Result<(), MemoryDevicesError> {
+        if self.instance_info.state != VmState::Paused {
+            return Err(MemoryDevicesError::VmNotPaused);
+        }
+
+        let overlays = self.memory_overlays();
+        for gap in overlays.gaps(&self.guest_memory, MMAP_OVERLAY_MAX_GAP) {
+            overlay_range(&self.guest_memory, gap)
+                .map_err(MemoryDevicesError::ConsolidateOverlays)?;
+            self.consolidation_overlays.insert(gap.0, gap.1);
+        }
+        info!(
+            "Consolidated {} memory overlays into {}.",
+            overlays.count(),
+            self.memory_overlays().count()
+        );
+        Ok(())
+    }
+
+    /// Cross-checks the config space values, range trackers and guest reported statistics of
+    /// the balloon and faascale-mem devices, against each other and against the guest memory.
+    pub fn verify_memory_devices(&self) -> MemoryDevicesVerification {
+        let mut verification = MemoryDevicesVerification::default();
+        let guest_mem_mib = mem_size_mib(&self.guest_memory);
+        let quiesced = self.memory_devices_quiesce.is_quiesced();
+        let quiesce_detail = |device_quiesced: bool| {
+            format!(
+                "the device is {}quiesced while the memory devices are {}quiesced",
+                if device_quiesced { "" } else { "not " },
+                if quiesced { "" } else { "not " }
+            )
+        };
+        #[cfg(feature = "balloon")]
+        let _ = self.with_balloon(|balloon| {
+            let mut failed = balloon.verify(guest_mem_mib);
+            if balloon.is_quiesced() != quiesced {
+                failed.push(("quiesce_state", quiesce_detail(balloon.is_quiesced())));
+            }
+            verification.add("balloon", failed);
+            Ok(())
+        });
+        #[cfg(feature = "faascale-mem")]
+        let _ = self.with_faascale_mem(|faascale_mem| {
+            let mut failed = faascale_mem.verify(guest_mem_mib);
+            if faascale_mem.is_quiesced() != quiesced {
+                failed.push(("quiesce_state", quiesce_detail(faascale_mem.is_quiesced())));
+            }
+            verification.add("faascale-mem", failed);
+            Ok(())
+        });
+        #[cfg(not(any(feature = "balloon", feature = "faascale-mem")))]
+        let _ = (guest_mem_mib, quiesce_detail);
+        if !verification.consistent {
+            warn!(
+                "The memory devices are inconsistent: {:?}",
+                verification.inconsistencies
+            );
+        }
+        verification
+    }
+
     /// Signals Vmm to stop and exit.
     pub fn stop(&mut self, exit_code: FcExitCode) {
         // To avoid cycles, all teardown paths take the following route:
@@ -1018,7 +1582,7 @@ impl Drop for Vmm {
         }
 
         // Write the metrics before exiting.
-        if let Err(err) = METRICS.write() {
+        if let Err(err) = METRICS.write_and_push() {
             error!("Failed to write metrics while stopping: {}", err);
         }
 
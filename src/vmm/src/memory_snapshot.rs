@@ -7,7 +7,7 @@ use std::fs::File;
 use std::io::SeekFrom;
 
 use utils::vm_memory::{
-    Bitmap, FileOffset, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
+    Bitmap, Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
     GuestMemoryRegion, MemoryRegionAddress, WriteVolatile,
 };
 use utils::{errno, get_page_size};
@@ -38,6 +38,17 @@ pub struct GuestMemoryState {
     pub regions: Vec<GuestMemoryRegionState>,
 }
 
+/// Describes how much guest memory a dump wrote out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDumpStats {
+    /// Number of pages written.
+    pub pages_written: u64,
+    /// Number of zero pages left as holes instead of being written.
+    pub pages_skipped: u64,
+    /// Number of bytes written.
+    pub bytes_written: u64,
+}
+
 /// Defines the interface for snapshotting memory.
 pub trait SnapshotMemory
 where
@@ -47,12 +58,18 @@ where
     fn describe(&self) -> GuestMemoryState;
     /// Dumps all contents of GuestMemoryMmap to a writer.
     fn dump<T: WriteVolatile>(&self, writer: &mut T) -> std::result::Result<(), Error>;
+    /// Dumps the non-zero pages of GuestMemoryMmap to a writer, seeking over the zero ones.
+    /// The writer must already span the whole guest memory and read back as zeroes.
+    fn dump_sparse<T: WriteVolatile + std::io::Seek>(
+        &self,
+        writer: &mut T,
+    ) -> std::result::Result<MemoryDumpStats, Error>;
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
     fn dump_dirty<T: WriteVolatile + std::io::Seek>(
         &self,
         writer: &mut T,
         dirty_bitmap: &DirtyBitmap,
-    ) -> std::result::Result<(), Error>;
+    ) -> std::result::Result<MemoryDumpStats, Error>;
     /// Creates a GuestMemoryMmap given a `file` containing the data
     /// and a `state` containing mapping information.
     fn restore(
@@ -106,12 +123,68 @@ impl SnapshotMemory for GuestMemoryMmap {
             .map_err(Error::WriteMemory)
     }
 
+    /// Dumps the non-zero pages of GuestMemoryMmap to a writer, seeking over the zero ones.
+    fn dump_sparse<T: WriteVolatile + std::io::Seek>(
+        &self,
+        writer: &mut T,
+    ) -> std::result::Result<MemoryDumpStats, Error> {
+        let mut stats = MemoryDumpStats::default();
+        let mut writer_offset = 0;
+        let page_size = get_page_size()?;
+        let mut page = vec![0u8; page_size];
+
+        self.iter()
+            .try_for_each(|region| {
+                let mut write_size = 0;
+                let mut batch_start: u64 = 0;
+
+                for page_offset in (0..region.len()).step_by(page_size) {
+                    region.read_slice(&mut page, MemoryRegionAddress(page_offset))?;
+                    if page.iter().any(|&byte| byte != 0) {
+                        // We are at the start of a new batch of non-zero pages.
+                        if write_size == 0 {
+                            // Seek forward over the hole.
+                            writer
+                                .seek(SeekFrom::Start(writer_offset + page_offset))
+                                .map_err(GuestMemoryError::IOError)?;
+                            batch_start = page_offset;
+                        }
+                        write_size += page_size;
+                        stats.pages_written += 1;
+                    } else {
+                        if write_size > 0 {
+                            // We are at the end of a batch of non-zero pages.
+                            writer.write_all_volatile(
+                                &region.get_slice(MemoryRegionAddress(batch_start), write_size)?,
+                            )?;
+                            write_size = 0;
+                        }
+                        stats.pages_skipped += 1;
+                    }
+                }
+
+                if write_size > 0 {
+                    writer.write_all_volatile(
+                        &region.get_slice(MemoryRegionAddress(batch_start), write_size)?,
+                    )?;
+                }
+                writer_offset += region.len();
+
+                Ok(())
+            })
+            .map_err(Error::WriteMemory)?;
+
+        stats.bytes_written = stats.pages_written * page_size as u64;
+        Ok(stats)
+    }
+
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
     fn dump_dirty<T: WriteVolatile + std::io::Seek>(
         &self,
         writer: &mut T,
         dirty_bitmap: &DirtyBitmap,
-    ) -> std::result::Result<(), Error> {
+    ) -> std::result::Result<MemoryDumpStats, Error> {
+        let mut stats = MemoryDumpStats::default();
         let mut writer_offset = 0;
         let page_size = get_page_size()?;
 
@@ -138,6 +211,7 @@ impl SnapshotMemory for GuestMemoryMmap {
                                 dirty_batch_start = page_offset as u64;
                             }
                             write_size += page_size;
+                            stats.pages_written += 1;
                         } else if write_size > 0 {
                             // We are at the end of a batch of dirty pages.
                             writer.write_all_volatile(
@@ -164,7 +238,10 @@ impl SnapshotMemory for GuestMemoryMmap {
 
                 Ok(())
             })
-            .map_err(Error::WriteMemory)
+            .map_err(Error::WriteMemory)?;
+
+        stats.bytes_written = stats.pages_written * page_size as u64;
+        Ok(stats)
     }
 
     /// Creates a GuestMemoryMmap backed by a `file` if present, otherwise backed
@@ -370,4 +447,52 @@ mod tests {
             assert_eq!(expected_first_region, diff_file_content);
         }
     }
+
+    #[test]
+    fn test_dump_sparse() {
+        let page_size: usize = get_page_size().unwrap();
+
+        // Two regions of two pages each, with a one page gap between them.
+        let mem_regions = [
+            (None, GuestAddress(0), page_size * 2),
+            (None, GuestAddress(page_size as u64 * 3), page_size * 2),
+        ];
+        let guest_memory = utils::vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+
+        // Only the second page of the first region and the first page of the second region
+        // hold data.
+        let ones = vec![1u8; page_size];
+        let twos = vec![2u8; page_size];
+        let zeros = vec![0u8; page_size];
+        guest_memory
+            .write(&ones[..], GuestAddress(page_size as u64))
+            .unwrap();
+        guest_memory
+            .write(&twos[..], GuestAddress(page_size as u64 * 3))
+            .unwrap();
+
+        let mut file = TempFile::new().unwrap().into_file();
+        file.set_len(page_size as u64 * 4).unwrap();
+        let stats = guest_memory.dump_sparse(&mut file).unwrap();
+        assert_eq!(
+            stats,
+            MemoryDumpStats {
+                pages_written: 2,
+                pages_skipped: 2,
+                bytes_written: page_size as u64 * 2,
+            }
+        );
+
+        let mut file_content = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut file_content).unwrap();
+        let expected_content = [
+            zeros.as_slice(),
+            ones.as_slice(),
+            twos.as_slice(),
+            zeros.as_slice(),
+        ]
+        .concat();
+        assert_eq!(expected_content, file_content);
+    }
 }
@@ -3,8 +3,9 @@
 
 //! Defines state structures for saving/restoring a Firecracker microVM.
 
-use std::fs::{File, OpenOptions};
+use std::fs::{File, Metadata, OpenOptions};
 use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
 use std::path::Path;
@@ -16,7 +17,7 @@ use serde::Serialize;
 use snapshot::Snapshot;
 use userfaultfd::{FeatureFlags, Uffd, UffdBuilder};
 use utils::sock_ctrl_msg::ScmSocket;
-use utils::vm_memory::{GuestMemory, GuestMemoryMmap};
+use utils::vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
@@ -30,17 +31,19 @@ use crate::cpu_config::x86_64::cpuid::common::get_vendor_id_from_host;
 #[cfg(target_arch = "x86_64")]
 use crate::cpu_config::x86_64::cpuid::CpuidTrait;
 use crate::device_manager::persist::{DeviceStates, Error as DevicePersistError};
+use crate::devices::virtio::faascale_mem::VIRTIO_FAASCALE_MEM_PFN_SHIFT;
 use crate::devices::virtio::TYPE_NET;
-use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::memory_snapshot::{GuestMemoryState, MemoryDumpStats, SnapshotMemory};
 use crate::resources::VmResources;
 #[cfg(target_arch = "x86_64")]
 use crate::version_map::FC_V0_23_SNAP_VERSION;
 use crate::version_map::{FC_V1_0_SNAP_VERSION, FC_V1_1_SNAP_VERSION, FC_VERSION_TO_SNAP_VERSION};
 use crate::vmm_config::boot_source::BootSourceConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
-use crate::vmm_config::machine_config::MAX_SUPPORTED_VCPUS;
+use crate::vmm_config::machine_config::{HugePageConfig, MAX_SUPPORTED_VCPUS};
 use crate::vmm_config::snapshot::{
-    CreateSnapshotParams, LoadSnapshotParams, MemBackendType, SnapshotType,
+    CreateSnapshotParams, LoadSnapshotParams, MemBackendType, SnapshotCreateInfo,
+    SnapshotMemoryInfo, SnapshotMemoryRange, SnapshotType,
 };
 use crate::vstate::vcpu::{VcpuSendEventError, VcpuState};
 use crate::vstate::vm::VmState;
@@ -68,6 +71,9 @@ pub struct VmInfo {
     /// Boot source information.
     #[version(start = 2, default_fn = "def_boot_source", ser_fn = "ser_boot_source")]
     pub boot_source: BootSourceConfig,
+    /// Host pages backing the guest memory.
+    #[version(start = 3, default_fn = "def_huge_pages", ser_fn = "ser_huge_pages")]
+    pub huge_pages: HugePageConfig,
 }
 
 impl VmInfo {
@@ -103,6 +109,18 @@ impl VmInfo {
         warn!("Saving to older snapshot version, boot source information will not be saved.");
         Ok(())
     }
+
+    fn def_huge_pages(_: u16) -> HugePageConfig {
+        HugePageConfig::None
+    }
+
+    fn ser_huge_pages(&mut self, _target_version: u16) -> VersionizeResult<()> {
+        // v1.4 and older versions do not include huge pages info.
+        if !self.huge_pages.is_none() {
+            warn!("Saving to older snapshot version, huge pages information will not be saved.");
+        }
+        Ok(())
+    }
 }
 
 impl From<&VmResources> for VmInfo {
@@ -112,6 +130,7 @@ impl From<&VmResources> for VmInfo {
             smt: value.vm_config.smt,
             cpu_template: StaticCpuTemplate::from(&value.vm_config.cpu_template),
             boot_source: value.boot_source_config().clone(),
+            huge_pages: value.vm_config.huge_pages,
         }
     }
 }
@@ -228,13 +247,13 @@ pub enum CreateSnapshotError {
     TooManyDevices(usize),
 }
 
-/// Creates a Microvm snapshot.
+/// Creates a Microvm snapshot and describes the files written.
 pub fn create_snapshot(
     vmm: &mut Vmm,
     vm_info: &VmInfo,
     params: &CreateSnapshotParams,
     version_map: VersionMap,
-) -> std::result::Result<(), CreateSnapshotError> {
+) -> std::result::Result<SnapshotCreateInfo, CreateSnapshotError> {
     // Fail early from invalid target version.
     let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, vmm)?;
 
@@ -242,16 +261,38 @@ pub fn create_snapshot(
         .save_state(vm_info)
         .map_err(CreateSnapshotError::MicrovmState)?;
 
-    snapshot_state_to_file(
+    let snapshot_metadata = snapshot_state_to_file(
         &microvm_state,
         &params.snapshot_path,
         snapshot_data_version,
         version_map,
     )?;
 
-    snapshot_memory_to_file(vmm, &params.mem_file_path, &params.snapshot_type)?;
+    let (dump_stats, mem_metadata) = snapshot_memory_to_file(
+        vmm,
+        &params.mem_file_path,
+        &params.snapshot_type,
+        params.sparse,
+    )?;
 
-    Ok(())
+    let info = SnapshotCreateInfo {
+        mem_pages_written: dump_stats.pages_written,
+        mem_pages_skipped: dump_stats.pages_skipped,
+        mem_bytes_written: dump_stats.bytes_written,
+        snapshot_file_size: snapshot_metadata.len(),
+        mem_file_size: mem_metadata.len(),
+        // `st_blocks` is always counted in 512 byte units.
+        mem_file_allocated_bytes: mem_metadata.blocks() * 512,
+    };
+    info!(
+        "Snapshot memory: {} pages written, {} zero pages skipped, {} of {} bytes allocated.",
+        info.mem_pages_written,
+        info.mem_pages_skipped,
+        info.mem_file_allocated_bytes,
+        info.mem_file_size
+    );
+
+    Ok(info)
 }
 
 fn snapshot_state_to_file(
@@ -259,7 +300,7 @@ fn snapshot_state_to_file(
     snapshot_path: &Path,
     snapshot_data_version: u16,
     version_map: VersionMap,
-) -> std::result::Result<(), CreateSnapshotError> {
+) -> std::result::Result<Metadata, CreateSnapshotError> {
     use self::CreateSnapshotError::*;
     let mut snapshot_file = OpenOptions::new()
         .create(true)
@@ -277,14 +318,18 @@ fn snapshot_state_to_file(
         .map_err(|err| SnapshotBackingFile("flush", err))?;
     snapshot_file
         .sync_all()
-        .map_err(|err| SnapshotBackingFile("sync_all", err))
+        .map_err(|err| SnapshotBackingFile("sync_all", err))?;
+    snapshot_file
+        .metadata()
+        .map_err(|err| SnapshotBackingFile("metadata", err))
 }
 
 fn snapshot_memory_to_file(
     vmm: &Vmm,
     mem_file_path: &Path,
     snapshot_type: &SnapshotType,
-) -> std::result::Result<(), CreateSnapshotError> {
+    sparse: bool,
+) -> std::result::Result<(MemoryDumpStats, Metadata), CreateSnapshotError> {
     use self::CreateSnapshotError::*;
     let mut file = OpenOptions::new()
         .write(true)
@@ -298,19 +343,35 @@ fn snapshot_memory_to_file(
     file.set_len(mem_size_mib * 1024 * 1024)
         .map_err(|err| MemoryBackingFile("set_length", err))?;
 
-    match snapshot_type {
+    // The file reads back as zeroes, so sparse full snapshots can leave zero pages as holes.
+    let dump_stats = match snapshot_type {
         SnapshotType::Diff => {
             let dirty_bitmap = vmm.get_dirty_bitmap().map_err(DirtyBitmap)?;
             vmm.guest_memory()
                 .dump_dirty(&mut file, &dirty_bitmap)
                 .map_err(Memory)
         }
-        SnapshotType::Full => vmm.guest_memory().dump(&mut file).map_err(Memory),
+        SnapshotType::Full if sparse => vmm.guest_memory().dump_sparse(&mut file).map_err(Memory),
+        SnapshotType::Full => {
+            let page_size = utils::get_page_size()
+                .map_err(|err| Memory(crate::memory_snapshot::Error::PageSize(err)))?;
+            vmm.guest_memory().dump(&mut file).map_err(Memory)?;
+            let bytes_written = mem_size_mib * 1024 * 1024;
+            Ok(MemoryDumpStats {
+                pages_written: bytes_written / page_size as u64,
+                pages_skipped: 0,
+                bytes_written,
+            })
+        }
     }?;
     file.flush()
         .map_err(|err| MemoryBackingFile("flush", err))?;
     file.sync_all()
-        .map_err(|err| MemoryBackingFile("sync_all", err))
+        .map_err(|err| MemoryBackingFile("sync_all", err))?;
+    let metadata = file
+        .metadata()
+        .map_err(|err| MemoryBackingFile("metadata", err))?;
+    Ok((dump_stats, metadata))
 }
 
 /// Validate the microVM version and translate it to its corresponding snapshot data format.
@@ -458,6 +519,9 @@ pub enum SnapShotStateSanityCheckError {
     #[error("Failed to validate vCPU manufacturer id: {0}")]
     #[cfg(target_arch = "aarch64")]
     ValidateCpuManufacturerId(#[from] ValidateCpuManufacturerIdError),
+    /// The snapshot holds a device whose support is not compiled into this build.
+    #[error("The snapshot holds a {0} device, but this build has no {0} support.")]
+    MissingFeature(&'static str),
 }
 
 /// Performs sanity checks against the state file and returns specific errors.
@@ -478,6 +542,18 @@ pub fn snapshot_state_sanity_check(
         return Err(SnapShotStateSanityCheckError::NoMemory);
     }
 
+    // Devices can only be restored if this build was compiled with their support.
+    #[cfg(not(feature = "balloon"))]
+    if microvm_state.device_states.balloon_device.is_some() {
+        return Err(SnapShotStateSanityCheckError::MissingFeature("balloon"));
+    }
+    #[cfg(not(feature = "faascale-mem"))]
+    if microvm_state.device_states.faascale_mem_device.is_some() {
+        return Err(SnapShotStateSanityCheckError::MissingFeature(
+            "faascale-mem",
+        ));
+    }
+
     #[cfg(target_arch = "x86_64")]
     validate_cpu_vendor(microvm_state)?;
     #[cfg(target_arch = "aarch64")]
@@ -523,6 +599,8 @@ pub fn restore_from_snapshot(
     version_map: VersionMap,
     vm_resources: &mut VmResources,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, RestoreFromSnapshotError> {
+    let snapshot_data_version =
+        snapshot_data_version_from_file(&params.snapshot_path, &version_map)?;
     let microvm_state = snapshot_state_from_file(&params.snapshot_path, version_map)?;
 
     // Some sanity checks before building the microvm.
@@ -532,9 +610,11 @@ pub fn restore_from_snapshot(
     let mem_state = &microvm_state.memory_state;
     let track_dirty_pages = params.enable_diff_snapshots;
 
+    let huge_pages = microvm_state.vm_info.huge_pages;
+
     let (guest_memory, uffd) = match params.mem_backend.backend_type {
         MemBackendType::File => (
-            guest_memory_from_file(mem_backend_path, mem_state, track_dirty_pages)
+            guest_memory_from_file(mem_backend_path, mem_state, track_dirty_pages, huge_pages)
                 .map_err(RestoreFromSnapshotGuestMemoryError::File)?,
             None,
         ),
@@ -542,13 +622,15 @@ pub fn restore_from_snapshot(
             mem_backend_path,
             mem_state,
             track_dirty_pages,
+            huge_pages,
             // We enable the UFFD_FEATURE_EVENT_REMOVE feature only if a balloon device
             // is present in the microVM state.
             microvm_state.device_states.balloon_device.is_some(),
         )
         .map_err(RestoreFromSnapshotGuestMemoryError::Uffd)?,
     };
-    builder::build_microvm_from_snapshot(
+    let memory_info = snapshot_memory_info(params, snapshot_data_version, &microvm_state);
+    let vmm = builder::build_microvm_from_snapshot(
         instance_info,
         event_manager,
         microvm_state,
@@ -558,7 +640,55 @@ pub fn restore_from_snapshot(
         seccomp_filters,
         vm_resources,
     )
-    .map_err(RestoreFromSnapshotError::Build)
+    .map_err(RestoreFromSnapshotError::Build)?;
+    vmm.lock().expect("Poisoned lock").snapshot_memory_info = Some(memory_info);
+    Ok(vmm)
+}
+
+// Describes the guest memory of the snapshot being loaded.
+fn snapshot_memory_info(
+    params: &LoadSnapshotParams,
+    snapshot_data_version: u16,
+    microvm_state: &MicrovmState,
+) -> SnapshotMemoryInfo {
+    let firecracker_version = FC_VERSION_TO_SNAP_VERSION
+        .iter()
+        .find(|(_, &version)| version == snapshot_data_version)
+        .map(|(fc_version, _)| fc_version.clone());
+    let mem_file_metadata = match params.mem_backend.backend_type {
+        MemBackendType::File => std::fs::metadata(&params.mem_backend.backend_path).ok(),
+        MemBackendType::Uffd => None,
+    };
+    let faascale_populated_ranges = microvm_state
+        .device_states
+        .faascale_mem_device
+        .as_ref()
+        .map(|faascale_mem| {
+            faascale_mem
+                .device_state
+                .populated_ranges()
+                .map(|(start, end)| SnapshotMemoryRange {
+                    start_addr: start << VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+                    size_bytes: (end - start) << VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+                })
+                .collect()
+        });
+
+    SnapshotMemoryInfo {
+        snapshot_data_version,
+        firecracker_version,
+        mem_backend_type: params.mem_backend.backend_type,
+        mem_size_bytes: microvm_state
+            .memory_state
+            .regions
+            .iter()
+            .map(|region| region.size as u64)
+            .sum(),
+        mem_file_size: mem_file_metadata.as_ref().map(Metadata::len),
+        mem_file_hole_bytes: mem_file_metadata
+            .map(|metadata| metadata.len().saturating_sub(metadata.blocks() * 512)),
+        faascale_populated_ranges,
+    }
 }
 
 /// Error type for [`snapshot_state_from_file`]
@@ -575,6 +705,16 @@ pub enum SnapshotStateFromFileError {
     Load(#[from] snapshot::Error),
 }
 
+fn snapshot_data_version_from_file(
+    snapshot_path: &Path,
+    version_map: &VersionMap,
+) -> std::result::Result<u16, SnapshotStateFromFileError> {
+    let mut snapshot_reader =
+        File::open(snapshot_path).map_err(SnapshotStateFromFileError::Open)?;
+    Snapshot::get_data_version(&mut snapshot_reader, version_map)
+        .map_err(SnapshotStateFromFileError::Load)
+}
+
 fn snapshot_state_from_file(
     snapshot_path: &Path,
     version_map: VersionMap,
@@ -596,13 +736,20 @@ pub enum GuestMemoryFromFileError {
     /// Failed to restore guest memory.
     #[error("Failed to restore guest memory: {0}")]
     Restore(#[from] crate::memory_snapshot::Error),
+    /// The guest memory is backed by huge pages, which a memory file cannot be mapped onto.
+    #[error("Guest memory backed by huge pages can only be restored through a userfaultfd.")]
+    HugePages,
 }
 
 fn guest_memory_from_file(
     mem_file_path: &Path,
     mem_state: &GuestMemoryState,
     track_dirty_pages: bool,
+    huge_pages: HugePageConfig,
 ) -> std::result::Result<GuestMemoryMmap, GuestMemoryFromFileError> {
+    if !huge_pages.is_none() {
+        return Err(GuestMemoryFromFileError::HugePages);
+    }
     let mem_file = File::open(mem_file_path)?;
     let guest_mem = GuestMemoryMmap::restore(Some(&mem_file), mem_state, track_dirty_pages)?;
     Ok(guest_mem)
@@ -632,9 +779,26 @@ fn guest_memory_from_uffd(
     mem_uds_path: &Path,
     mem_state: &GuestMemoryState,
     track_dirty_pages: bool,
+    huge_pages: HugePageConfig,
     enable_balloon: bool,
 ) -> std::result::Result<(GuestMemoryMmap, Option<Uffd>), GuestMemoryFromUffdError> {
-    let guest_memory = GuestMemoryMmap::restore(None, mem_state, track_dirty_pages)?;
+    let guest_memory = match huge_pages.page_size() {
+        // The page fault handler fills the huge pages like the regular ones.
+        Some(huge_page_size) => {
+            let regions = mem_state
+                .regions
+                .iter()
+                .map(|region| (GuestAddress(region.base_address), region.size))
+                .collect::<Vec<_>>();
+            utils::vm_memory::create_hugetlb_guest_memory(
+                &regions,
+                track_dirty_pages,
+                huge_page_size,
+            )
+            .map_err(memory_snapshot::Error::CreateMemory)?
+        }
+        None => GuestMemoryMmap::restore(None, mem_state, track_dirty_pages)?,
+    };
 
     let mut uffd_builder = UffdBuilder::new();
 
@@ -738,15 +902,18 @@ mod tests {
     use utils::tempfile::TempFile;
 
     use super::*;
+    #[cfg(feature = "balloon")]
+    use crate::builder::tests::insert_balloon_device;
     use crate::builder::tests::{
-        default_kernel_cmdline, default_vmm, insert_balloon_device, insert_block_devices,
-        insert_net_device, insert_vsock_device, CustomBlockConfig,
+        default_kernel_cmdline, default_vmm, insert_block_devices, insert_net_device,
+        insert_vsock_device, CustomBlockConfig,
     };
     #[cfg(target_arch = "aarch64")]
     use crate::construct_kvm_mpidrs;
     use crate::memory_snapshot::SnapshotMemory;
     use crate::version_map::{FC_VERSION_TO_SNAP_VERSION, VERSION_MAP};
-    use crate::vmm_config::balloon::BalloonDeviceConfig;
+    #[cfg(feature = "balloon")]
+    use crate::vmm_config::balloon::{BalloonDeflatePrefetch, BalloonDeviceConfig};
     use crate::vmm_config::drive::CacheType;
     use crate::vmm_config::net::NetworkInterfaceConfig;
     use crate::vmm_config::vsock::tests::default_config;
@@ -761,12 +928,22 @@ mod tests {
         let mut cmdline = default_kernel_cmdline();
 
         // Add a balloon device.
-        let balloon_config = BalloonDeviceConfig {
-            amount_mib: 0,
-            deflate_on_oom: false,
-            stats_polling_interval_s: 0,
-        };
-        insert_balloon_device(&mut vmm, &mut cmdline, &mut event_manager, balloon_config);
+        #[cfg(feature = "balloon")]
+        insert_balloon_device(
+            &mut vmm,
+            &mut cmdline,
+            &mut event_manager,
+            BalloonDeviceConfig {
+                amount_mib: 0,
+                deflate_on_oom: false,
+                stats_polling_interval_s: 0,
+                deflate_prefetch: BalloonDeflatePrefetch::None,
+                strict_stats: false,
+                interrupt_moderation: false,
+                min_guest_mib: 0,
+                config_epoch: 0,
+            },
+        );
 
         // Add a block device.
         let drive_id = String::from("root");
@@ -814,6 +991,7 @@ mod tests {
         assert_eq!(states.block_devices.len(), 1);
         assert_eq!(states.net_devices.len(), 1);
         assert!(states.vsock_device.is_some());
+        #[cfg(feature = "balloon")]
         assert!(states.balloon_device.is_some());
 
         let memory_state = vmm.guest_memory().describe();
@@ -13,7 +13,9 @@ use utils::net::ipv4addr::is_link_local_valid;
 
 use crate::cpu_config::templates::CustomCpuTemplate;
 use crate::device_manager::persist::SharedDeviceType;
+#[cfg(feature = "balloon")]
 use crate::vmm_config::balloon::*;
+#[cfg(feature = "faascale-mem")]
 use crate::vmm_config::faascale_mem::*;
 use crate::vmm_config::boot_source::{
     BootConfig, BootSource, BootSourceConfig, BootSourceConfigError,
@@ -36,9 +38,11 @@ type Result<E> = std::result::Result<(), E>;
 #[derive(Debug, thiserror::Error, derive_more::From)]
 pub enum Error {
     /// Balloon device configuration error.
+    #[cfg(feature = "balloon")]
     #[error("Balloon device error: {0}")]
     BalloonDevice(BalloonConfigError),
     /// Faascale-mem device configuration error.
+    #[cfg(feature = "faascale-mem")]
     #[error("FaascaleMem device error: {0}")]
     FaascaleMemDevice(FaascaleMemConfigError),
     /// Block device configuration error.
@@ -82,10 +86,15 @@ pub enum Error {
 /// Used for configuring a vmm from one single json passed to the Firecracker process.
 #[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct VmmConfig {
+    #[cfg(feature = "balloon")]
     #[serde(rename = "balloon")]
     balloon_device: Option<BalloonDeviceConfig>,
+    #[cfg(feature = "faascale-mem")]
     #[serde(rename = "faascale-mem")]
     faascale_mem_device: Option<FaascaleMemDeviceConfig>,
+    #[cfg(feature = "faascale-mem")]
+    #[serde(rename = "faascale-mem-hotplug-slot")]
+    faascale_mem_hotplug_slot: Option<FaascaleMemHotplugSlotConfig>,
     #[serde(rename = "drives")]
     block_devices: Vec<BlockDeviceConfig>,
     #[serde(rename = "boot-source")]
@@ -121,8 +130,10 @@ pub struct VmResources {
     /// The vsock device.
     pub vsock: VsockBuilder,
     /// The balloon device.
+    #[cfg(feature = "balloon")]
     pub balloon: BalloonBuilder,
     /// The faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
     pub faascale_mem: FaascaleMemBuilder,
     /// The network devices builder.
     pub net_builder: NetBuilder,
@@ -185,14 +196,21 @@ impl VmResources {
             resources.set_vsock_device(vsock_config)?;
         }
 
+        #[cfg(feature = "balloon")]
         if let Some(balloon_config) = vmm_config.balloon_device {
             resources.set_balloon_device(balloon_config)?;
         }
 
+        #[cfg(feature = "faascale-mem")]
         if let Some(faascale_mem_config) = vmm_config.faascale_mem_device {
             resources.set_faascale_mem_device(faascale_mem_config)?;
         }
 
+        #[cfg(feature = "faascale-mem")]
+        if let Some(hotplug_slot_config) = vmm_config.faascale_mem_hotplug_slot {
+            resources.set_faascale_mem_hotplug_slot(hotplug_slot_config);
+        }
+
         // Init the data store from file, if present.
         if let Some(data) = metadata_json {
             resources.locked_mmds_or_default().put_data(
@@ -238,10 +256,12 @@ impl VmResources {
                 self.net_builder.add_device(network);
             }
 
+            #[cfg(feature = "balloon")]
             SharedDeviceType::Balloon(balloon) => {
                 self.balloon.set_device(balloon);
             }
 
+            #[cfg(feature = "faascale-mem")]
             SharedDeviceType::FaascaleMem(faascale_mem) => {
                 self.faascale_mem.set_device(faascale_mem);
             }
@@ -279,16 +299,32 @@ impl VmResources {
         self.vm_config.update(update)?;
 
         // The VM cannot have a memory size smaller than the target size
-        // of the balloon device, if present.
-        if self.balloon.get().is_some()
-            && self.vm_config.mem_size_mib
-                < self
-                    .balloon
-                    .get_config()
-                    .map_err(|_| VmConfigError::InvalidVmState)?
-                    .amount_mib as usize
-        {
-            return Err(VmConfigError::IncompatibleBalloonSize);
+        // of the balloon device, if present, and the guest memory floor.
+        #[cfg(feature = "balloon")]
+        if self.balloon.get().is_some() {
+            let balloon_config = self
+                .balloon
+                .get_config()
+                .map_err(|_| VmConfigError::InvalidVmState)?;
+            if self.vm_config.mem_size_mib
+                < balloon_config.amount_mib as usize + balloon_config.min_guest_mib as usize
+            {
+                return Err(VmConfigError::IncompatibleBalloonSize);
+            }
+        }
+
+        // Nor can it be backed by huge pages the devices would release in smaller pieces.
+        if !self.vm_config.huge_pages.is_none() {
+            #[cfg(feature = "balloon")]
+            if self.balloon.get().is_some() {
+                return Err(VmConfigError::IncompatibleHugePages);
+            }
+            #[cfg(feature = "faascale-mem")]
+            if let Ok(faascale_mem_config) = self.faascale_mem.get_config() {
+                if !faascale_mem_config.supports_huge_pages() {
+                    return Err(VmConfigError::IncompatibleHugePages);
+                }
+            }
         }
 
         Ok(())
@@ -343,6 +379,7 @@ impl VmResources {
     }
 
     /// Sets a balloon device to be attached when the VM starts.
+    #[cfg(feature = "balloon")]
     pub fn set_balloon_device(
         &mut self,
         config: BalloonDeviceConfig,
@@ -352,18 +389,48 @@ impl VmResources {
         if config.amount_mib as usize > self.vm_config.mem_size_mib {
             return Err(BalloonConfigError::TooManyPagesRequested);
         }
+        // Nor can it start out reclaiming memory below the guest memory floor.
+        if config.amount_mib as usize + config.min_guest_mib as usize > self.vm_config.mem_size_mib
+        {
+            return Err(BalloonConfigError::GuestFloorTooLarge);
+        }
+        // Nor release pieces of the huge pages backing the guest memory.
+        if !self.vm_config.huge_pages.is_none() {
+            return Err(BalloonConfigError::HugePages);
+        }
 
         self.balloon.set(config)
     }
 
     /// Sets a faascale-mem device to be attached when the VM starts.
+    #[cfg(feature = "faascale-mem")]
     pub fn set_faascale_mem_device(
         &mut self,
         config: FaascaleMemDeviceConfig,
     ) -> Result<FaascaleMemConfigError> {
+        self.check_faascale_mem_huge_pages(&config)?;
         self.faascale_mem.set(config)
     }
 
+    /// Checks that a faascale-mem device configured as `config` can serve the huge pages backing
+    /// the guest memory, if any.
+    #[cfg(feature = "faascale-mem")]
+    pub fn check_faascale_mem_huge_pages(
+        &self,
+        config: &FaascaleMemDeviceConfig,
+    ) -> Result<FaascaleMemConfigError> {
+        if !self.vm_config.huge_pages.is_none() && !config.supports_huge_pages() {
+            return Err(FaascaleMemConfigError::HugePages);
+        }
+        Ok(())
+    }
+
+    /// Reserves, or not, the slot to hot-plug a faascale-mem device after boot.
+    #[cfg(feature = "faascale-mem")]
+    pub fn set_faascale_mem_hotplug_slot(&mut self, config: FaascaleMemHotplugSlotConfig) {
+        self.faascale_mem.set_hotplug_slot(config)
+    }
+
     /// Obtains the boot source hooks (kernel fd, command line creation and validation).
     pub fn build_boot_source(
         &mut self,
@@ -486,8 +553,29 @@ impl VmResources {
 impl From<&VmResources> for VmmConfig {
     fn from(resources: &VmResources) -> Self {
         VmmConfig {
-            balloon_device: resources.balloon.get_config().ok(),
-            faascale_mem_device: resources.faascale_mem.get_config().ok(),
+            // The configuration epochs belong to the running devices and cannot be set on
+            // new ones.
+            #[cfg(feature = "balloon")]
+            balloon_device: resources
+                .balloon
+                .get_config()
+                .ok()
+                .map(|config| BalloonDeviceConfig {
+                    config_epoch: 0,
+                    ..config
+                }),
+            #[cfg(feature = "faascale-mem")]
+            faascale_mem_device: resources.faascale_mem.get_config().ok().map(|config| {
+                FaascaleMemDeviceConfig {
+                    config_epoch: 0,
+                    ..config
+                }
+            }),
+            #[cfg(feature = "faascale-mem")]
+            faascale_mem_hotplug_slot: resources
+                .faascale_mem
+                .hotplug_slot()
+                .then(|| FaascaleMemHotplugSlotConfig { reserved: true }),
             block_devices: resources.block.configs(),
             boot_source: resources.boot_source_config().clone(),
             cpu_config: None,
@@ -521,7 +609,7 @@ mod tests {
         BootConfig, BootSource, BootSourceConfig, DEFAULT_KERNEL_CMDLINE,
     };
     use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, FileEngineType};
-    use crate::vmm_config::machine_config::{MachineConfig, VmConfigError};
+    use crate::vmm_config::machine_config::{HugePageConfig, MachineConfig, VmConfigError};
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::RateLimiterConfig;
@@ -595,7 +683,9 @@ mod tests {
             boot_source: default_boot_cfg(),
             block: default_blocks(),
             vsock: Default::default(),
+            #[cfg(feature = "balloon")]
             balloon: Default::default(),
+            #[cfg(feature = "faascale-mem")]
             faascale_mem: Default::default(),
             net_builder: default_net_builder(),
             mmds: None,
@@ -1367,6 +1457,7 @@ mod tests {
             #[cfg(target_arch = "aarch64")]
             cpu_template: Some(StaticCpuTemplate::V1N1),
             track_dirty_pages: Some(false),
+            huge_pages: Some(HugePageConfig::Hugetlbfs2M),
         };
 
         assert_ne!(
@@ -1399,20 +1490,38 @@ mod tests {
             Err(VmConfigError::InvalidMemorySize)
         );
 
-        // Incompatible mem_size_mib with balloon size.
-        vm_resources.vm_config.mem_size_mib = 128;
-        vm_resources
-            .set_balloon_device(BalloonDeviceConfig {
-                amount_mib: 100,
-                deflate_on_oom: false,
-                stats_polling_interval_s: 0,
-            })
-            .unwrap();
-        aux_vm_config.mem_size_mib = Some(90);
+        // mem_size_mib not made of whole huge pages.
+        aux_vm_config.mem_size_mib = Some(513);
         assert_eq!(
             vm_resources.update_vm_config(&aux_vm_config),
-            Err(VmConfigError::IncompatibleBalloonSize)
+            Err(VmConfigError::InvalidMemorySize)
         );
+        aux_vm_config.huge_pages = Some(HugePageConfig::None);
+        vm_resources.update_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.vm_config.mem_size_mib, 513);
+
+        // Incompatible mem_size_mib with balloon size.
+        #[cfg(feature = "balloon")]
+        {
+            vm_resources.vm_config.mem_size_mib = 128;
+            vm_resources
+                .set_balloon_device(BalloonDeviceConfig {
+                    amount_mib: 100,
+                    deflate_on_oom: false,
+                    stats_polling_interval_s: 0,
+                    deflate_prefetch: BalloonDeflatePrefetch::None,
+                    strict_stats: false,
+                    interrupt_moderation: false,
+                    min_guest_mib: 0,
+                    config_epoch: 0,
+                })
+                .unwrap();
+            aux_vm_config.mem_size_mib = Some(90);
+            assert_eq!(
+                vm_resources.update_vm_config(&aux_vm_config),
+                Err(VmConfigError::IncompatibleBalloonSize)
+            );
+        }
 
         // mem_size_mib compatible with balloon size.
         aux_vm_config.mem_size_mib = Some(256);
@@ -1420,6 +1529,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_set_balloon_device() {
         let mut vm_resources = default_vm_resources();
         vm_resources.balloon = BalloonBuilder::new();
@@ -1427,6 +1537,11 @@ mod tests {
             amount_mib: 100,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            deflate_prefetch: BalloonDeflatePrefetch::None,
+            strict_stats: false,
+            interrupt_moderation: false,
+            min_guest_mib: 0,
+            config_epoch: 0,
         };
         assert!(vm_resources.balloon.get().is_none());
         vm_resources
@@ -1447,7 +1562,101 @@ mod tests {
         let mut vm_resources = default_vm_resources();
         vm_resources.balloon = BalloonBuilder::new();
         new_balloon_cfg.amount_mib = 256;
-        assert!(vm_resources.set_balloon_device(new_balloon_cfg).is_err());
+        assert!(vm_resources
+            .set_balloon_device(new_balloon_cfg.clone())
+            .is_err());
+
+        new_balloon_cfg.amount_mib = 64;
+        new_balloon_cfg.min_guest_mib = 96;
+        assert!(matches!(
+            vm_resources.set_balloon_device(new_balloon_cfg),
+            Err(BalloonConfigError::GuestFloorTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_huge_pages_compatibility() {
+        let huge_pages = |huge_pages| MachineConfigUpdate {
+            vcpu_count: None,
+            mem_size_mib: None,
+            smt: None,
+            cpu_template: None,
+            track_dirty_pages: None,
+            huge_pages: Some(huge_pages),
+        };
+
+        // The balloon cannot release pieces of huge pages.
+        #[cfg(feature = "balloon")]
+        {
+            let balloon_cfg = BalloonDeviceConfig {
+                amount_mib: 0,
+                deflate_on_oom: false,
+                stats_polling_interval_s: 0,
+                deflate_prefetch: BalloonDeflatePrefetch::None,
+                strict_stats: false,
+                interrupt_moderation: false,
+                min_guest_mib: 0,
+                config_epoch: 0,
+            };
+            let mut vm_resources = default_vm_resources();
+            vm_resources.balloon = BalloonBuilder::new();
+            vm_resources
+                .update_vm_config(&huge_pages(HugePageConfig::Hugetlbfs2M))
+                .unwrap();
+            assert!(matches!(
+                vm_resources.set_balloon_device(balloon_cfg.clone()),
+                Err(BalloonConfigError::HugePages)
+            ));
+
+            let mut vm_resources = default_vm_resources();
+            vm_resources.balloon = BalloonBuilder::new();
+            vm_resources.set_balloon_device(balloon_cfg).unwrap();
+            assert_eq!(
+                vm_resources.update_vm_config(&huge_pages(HugePageConfig::Hugetlbfs2M)),
+                Err(VmConfigError::IncompatibleHugePages)
+            );
+        }
+
+        // Nor can the pool, the THP policies or the lazy depopulate modes of faascale-mem.
+        #[cfg(feature = "faascale-mem")]
+        {
+            let mut vm_resources = default_vm_resources();
+            vm_resources
+                .update_vm_config(&huge_pages(HugePageConfig::Hugetlbfs2M))
+                .unwrap();
+            assert!(matches!(
+                vm_resources.set_faascale_mem_device(FaascaleMemDeviceConfig {
+                    thp_policy: FaascaleMemThpPolicy::Always,
+                    ..Default::default()
+                }),
+                Err(FaascaleMemConfigError::HugePages)
+            ));
+            assert!(matches!(
+                vm_resources.set_faascale_mem_device(FaascaleMemDeviceConfig {
+                    depopulate_mode: FaascaleMemDepopulateMode::Free,
+                    ..Default::default()
+                }),
+                Err(FaascaleMemConfigError::HugePages)
+            ));
+            vm_resources
+                .set_faascale_mem_device(FaascaleMemDeviceConfig::default())
+                .unwrap();
+
+            let mut vm_resources = default_vm_resources();
+            vm_resources
+                .set_faascale_mem_device(FaascaleMemDeviceConfig {
+                    thp_policy: FaascaleMemThpPolicy::Collapse,
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(
+                vm_resources.update_vm_config(&huge_pages(HugePageConfig::Hugetlbfs2M)),
+                Err(VmConfigError::IncompatibleHugePages)
+            );
+            vm_resources
+                .update_vm_config(&huge_pages(HugePageConfig::None))
+                .unwrap();
+        }
     }
 
     #[test]
@@ -22,29 +22,47 @@ use super::{
 };
 use crate::builder::StartMicrovmError;
 use crate::cpu_config::templates::{CustomCpuTemplate, GuestConfigError};
+#[cfg(feature = "faascale-mem")]
+use crate::devices::virtio::faascale_mem::Error as FaascaleMemError;
 use crate::persist::{CreateSnapshotError, RestoreFromSnapshotError, VmInfo};
 use crate::resources::VmmConfig;
 use crate::version_map::VERSION_MAP;
+#[cfg(feature = "balloon")]
 use crate::vmm_config::balloon::{
-    BalloonConfigError, BalloonDeviceConfig, BalloonStats, BalloonUpdateConfig,
-    BalloonUpdateStatsConfig,
-};
-use crate::vmm_config::faascale_mem::{
-    FaascaleMemConfigError, FaascaleMemDeviceConfig, FaascaleMemStats,
-    FaascaleMemUpdateStatsConfig,
+    BalloonConfigError, BalloonConfigSpace, BalloonDeviceConfig, BalloonStats,
+    BalloonUpdateClamped, BalloonUpdateConfig, BalloonUpdateStatsConfig,
 };
 use crate::vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
 use crate::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig, DriveError};
 use crate::vmm_config::entropy::{EntropyDeviceConfig, EntropyDeviceError};
+#[cfg(feature = "faascale-mem")]
+use crate::vmm_config::faascale_mem::{
+    FaascaleMemBlocks, FaascaleMemBudget, FaascaleMemBudgetConfig, FaascaleMemBuilder,
+    FaascaleMemConfigError, FaascaleMemConfigSpace, FaascaleMemDepopulateConfig,
+    FaascaleMemDeviceConfig, FaascaleMemErrors, FaascaleMemEstimate, FaascaleMemEstimateConfig,
+    FaascaleMemFenceConfig, FaascaleMemFootprint, FaascaleMemHealth, FaascaleMemHeatmap,
+    FaascaleMemHotplugSlotConfig, FaascaleMemMetadata, FaascaleMemMlockConfig,
+    FaascaleMemPinConfig, FaascaleMemPollStatsConfig, FaascaleMemPopulateConfig,
+    FaascaleMemPopulateMode, FaascaleMemPopulationConfig, FaascaleMemRateLimiterConfig,
+    FaascaleMemRehydrateConfig, FaascaleMemStats, FaascaleMemUpdateConfig,
+    FaascaleMemUpdateStatsConfig, FaascaleMemWarmReport,
+};
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError};
 use crate::vmm_config::machine_config::{MachineConfig, MachineConfigUpdate, VmConfigError};
+#[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+use crate::vmm_config::memory_devices::MemoryOverlaysInfo;
+use crate::vmm_config::memory_devices::{
+    MemoryDevicesError, MemoryDevicesQuiesceToken, MemoryDevicesVerification,
+};
 use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
     NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
 };
-use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use crate::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, SnapshotCreateInfo, SnapshotMemoryInfo, SnapshotType,
+};
 use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
 use crate::vmm_config::{self, RateLimiterUpdate};
 use crate::{EventManager, FcExitCode};
@@ -62,21 +80,68 @@ pub enum VmmAction {
     /// Configure the metrics using as input the `MetricsConfig`. This action can only be called
     /// before the microVM has booted.
     ConfigureMetrics(MetricsConfig),
+    /// Merge the anonymous mappings laid over the guest memory by the memory devices. This
+    /// action can only be called after the microVM has booted and only when the microVM is in
+    /// `Paused` state.
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    ConsolidateMemoryOverlays,
     /// Create a snapshot using as input the `CreateSnapshotParams`. This action can only be called
     /// after the microVM has booted and only when the microVM is in `Paused` state.
     CreateSnapshot(CreateSnapshotParams),
     /// Get the balloon device configuration.
+    #[cfg(feature = "balloon")]
     GetBalloonConfig,
     /// Get the ballon device latest statistics.
+    #[cfg(feature = "balloon")]
     GetBalloonStats,
+    /// Dump the balloon device config space, for debugging.
+    #[cfg(feature = "balloon")]
+    GetBalloonConfigSpace,
     /// Get the faascale-mem device configuration.
+    #[cfg(feature = "faascale-mem")]
     GetFaascaleMemConfig,
     /// Get the faascale-mem device latest statistics.
+    #[cfg(feature = "faascale-mem")]
     GetFaascaleMemStats,
+    /// Run the faascale-mem device internal consistency checks.
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemHealth,
+    /// Get the memory budget negotiated by the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemBudget,
+    /// Get the host memory backing the guest memory populated through the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemFootprint,
+    /// Get the guest memory blocks populated through the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemBlocks,
+    /// Get the populate and depopulate activity of the faascale-mem device over the guest
+    /// physical address space, after microVM start.
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemHeatmap,
+    /// Get the recent populate and depopulate failures of the faascale-mem device, after
+    /// microVM start.
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemErrors,
+    /// Estimate whether and how fast the faascale-mem device could populate up to a target.
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemEstimate(FaascaleMemEstimateConfig),
+    /// Dump the faascale-mem device config space, for debugging.
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemConfigSpace,
+    /// Describe the statistics and configuration fields of the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    GetFaascaleMemMetadata,
+    /// Get the anonymous mappings laid over the guest memory by the memory devices.
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    GetMemoryOverlays,
     /// Get complete microVM configuration in JSON format.
     GetFullVmConfig,
     /// Get MMDS contents.
     GetMMDS,
+    /// Get the guest memory metadata of the snapshot the microVM was loaded from. This action
+    /// can only be called after the microVM was loaded from a snapshot.
+    GetSnapshotMemoryInfo,
     /// Get the machine configuration of the microVM.
     GetVmMachineConfig,
     /// Get microVM instance information.
@@ -104,16 +169,28 @@ pub enum VmmAction {
     PutMMDS(Value),
     /// Configure the guest vCPU features.
     PutCpuConfiguration(CustomCpuTemplate),
+    /// Stop processing the balloon and faascale-mem queues, after microVM start.
+    QuiesceMemoryDevices,
     /// Resume the guest, by resuming the microVM VCPUs.
     Resume,
+    /// Resume processing the memory devices queues stopped by the quiesce request
+    /// identified by the `MemoryDevicesQuiesceToken`.
+    ResumeMemoryDevices(MemoryDevicesQuiesceToken),
     /// Set the balloon device or update the one that already exists using the
     /// `BalloonDeviceConfig` as input. This action can only be called before the microVM
     /// has booted.
+    #[cfg(feature = "balloon")]
     SetBalloonDevice(BalloonDeviceConfig),
     /// Set the faascale-mem device or update the one that already exists using the
-    /// `FaascaleMemDeviceConfig` as input. This action can only be called before the microVM
-    /// has booted.
+    /// `FaascaleMemDeviceConfig` as input. After boot, the device is hot-plugged in the slot
+    /// reserved with `SetFaascaleMemHotplugSlot`.
+    #[cfg(feature = "faascale-mem")]
     SetFaascaleMemDevice(FaascaleMemDeviceConfig),
+    /// Reserve, or not, the slot to hot-plug the faascale-mem device after boot using the
+    /// `FaascaleMemHotplugSlotConfig` as input. This action can only be called before the microVM
+    /// has booted.
+    #[cfg(feature = "faascale-mem")]
+    SetFaascaleMemHotplugSlot(FaascaleMemHotplugSlotConfig),
     /// Set the MMDS configuration.
     SetMmdsConfiguration(MmdsConfig),
     /// Set the vsock device or update the one that already exists using the
@@ -130,11 +207,58 @@ pub enum VmmAction {
     #[cfg(target_arch = "x86_64")]
     SendCtrlAltDel,
     /// Update the balloon size, after microVM start.
+    #[cfg(feature = "balloon")]
     UpdateBalloon(BalloonUpdateConfig),
     /// Update the balloon statistics polling interval, after microVM start.
+    #[cfg(feature = "balloon")]
     UpdateBalloonStatistics(BalloonUpdateStatsConfig),
+    /// Update the memory the guest keeps populated through the faascale-mem device, after
+    /// microVM start.
+    #[cfg(feature = "faascale-mem")]
+    UpdateFaascaleMem(FaascaleMemUpdateConfig),
     /// Update the faascale-mem statistics polling interval, after microVM start.
+    #[cfg(feature = "faascale-mem")]
     UpdateFaascaleMemStatistics(FaascaleMemUpdateStatsConfig),
+    /// Pin or unpin a range of guest memory against depopulation, after microVM start.
+    #[cfg(feature = "faascale-mem")]
+    UpdateFaascaleMemPin(FaascaleMemPinConfig),
+    /// Lock a populated range of guest memory into host memory or unlock it, after microVM
+    /// start.
+    #[cfg(feature = "faascale-mem")]
+    UpdateFaascaleMemMlock(FaascaleMemMlockConfig),
+    /// Update the rate limiter of the populate and depopulate requests of the guest, after
+    /// microVM start.
+    #[cfg(feature = "faascale-mem")]
+    UpdateFaascaleMemRateLimiter(FaascaleMemRateLimiterConfig),
+    /// Fence or unfence the faascale-mem device against populate requests, after microVM
+    /// start.
+    #[cfg(feature = "faascale-mem")]
+    UpdateFaascaleMemFence(FaascaleMemFenceConfig),
+    /// Turn the population policies of the faascale-mem device on or off, after microVM start.
+    #[cfg(feature = "faascale-mem")]
+    UpdateFaascaleMemPopulation(FaascaleMemPopulationConfig),
+    /// Offer a new memory budget to the guest, after microVM start.
+    #[cfg(feature = "faascale-mem")]
+    UpdateFaascaleMemBudget(FaascaleMemBudgetConfig),
+    /// Reclaim guest memory populated through the faascale-mem device, after microVM start.
+    #[cfg(feature = "faascale-mem")]
+    DepopulateFaascaleMem(FaascaleMemDepopulateConfig),
+    /// Ask the guest to report fresh faascale-mem statistics right away, after microVM start.
+    #[cfg(feature = "faascale-mem")]
+    PollFaascaleMemStats(FaascaleMemPollStatsConfig),
+    /// Ask the guest to report fresh faascale-mem statistics without waiting for them, after
+    /// microVM start.
+    #[cfg(feature = "faascale-mem")]
+    RefreshFaascaleMemStats,
+    /// Populate guest memory through the faascale-mem device ahead of the guest, to warm up
+    /// the microVM before an invocation or the template of a warm pool before it is
+    /// snapshotted, after microVM start.
+    #[cfg(feature = "faascale-mem")]
+    PopulateFaascaleMem(FaascaleMemPopulateConfig),
+    /// Fault in the guest memory populated in the template of a clone restored from its
+    /// snapshot, before the clone is resumed.
+    #[cfg(feature = "faascale-mem")]
+    RehydrateFaascaleMem(FaascaleMemRehydrateConfig),
     /// Update existing block device properties such as `path_on_host` or `rate_limiter`.
     UpdateBlockDevice(BlockDeviceUpdateConfig),
     /// Update a network interface, after microVM start. Currently, the only updatable properties
@@ -143,6 +267,9 @@ pub enum VmmAction {
     /// Update the microVM configuration (memory & vcpu) using `VmUpdateConfig` as input. This
     /// action can only be called before the microVM has booted.
     UpdateVmConfiguration(MachineConfigUpdate),
+    /// Cross-check the config space values, range trackers and guest reported statistics of the
+    /// memory devices. This action can only be called after the microVM has booted.
+    VerifyMemoryDevices,
 }
 
 /// Wrapper for all errors associated with VMM actions.
@@ -150,9 +277,11 @@ pub enum VmmAction {
 pub enum VmmActionError {
     /// The action `SetBalloonDevice` failed because of bad user input.
     #[error("{0}")]
+    #[cfg(feature = "balloon")]
     BalloonConfig(BalloonConfigError),
     /// The action `SetFaascaleMemDevice` failed because of bad user input.
     #[error("{0}")]
+    #[cfg(feature = "faascale-mem")]
     FaascaleMemConfig(FaascaleMemConfigError),
     /// The action `ConfigureBootSource` failed because of bad user input.
     #[error("{0}")]
@@ -183,6 +312,10 @@ pub enum VmmActionError {
     /// input.
     #[error("{0}")]
     MachineConfig(VmConfigError),
+    /// One of the actions `QuiesceMemoryDevices`, `ResumeMemoryDevices` or
+    /// `ConsolidateMemoryOverlays` failed.
+    #[error("{0}")]
+    MemoryDevices(MemoryDevicesError),
     /// The action `ConfigureMetrics` failed because of bad user input.
     #[error("{0}")]
     Metrics(MetricsConfigError),
@@ -200,6 +333,10 @@ pub enum VmmActionError {
     /// The action `InsertNetworkDevice` failed because of bad user input.
     #[error("{0}")]
     NetworkConfig(NetworkInterfaceError),
+    /// The action `GetSnapshotMemoryInfo` failed because the microVM was not loaded from a
+    /// snapshot.
+    #[error("The microVM was not loaded from a snapshot.")]
+    NotLoadedFromSnapshot,
     /// The requested operation is not supported.
     #[error("The requested operation is not supported: {0}")]
     NotSupported(String),
@@ -222,23 +359,75 @@ pub enum VmmActionError {
 #[derive(Debug, PartialEq, Eq)]
 pub enum VmmData {
     /// The balloon device configuration.
+    #[cfg(feature = "balloon")]
     BalloonConfig(BalloonDeviceConfig),
     /// The latest balloon device statistics.
+    #[cfg(feature = "balloon")]
     BalloonStats(BalloonStats),
+    /// The balloon device config space.
+    #[cfg(feature = "balloon")]
+    BalloonConfigSpace(BalloonConfigSpace),
+    /// The balloon update clamped to the guest memory floor.
+    #[cfg(feature = "balloon")]
+    BalloonUpdateClamped(BalloonUpdateClamped),
     /// The balloon device configuration.
+    #[cfg(feature = "faascale-mem")]
     FaascaleMemConfig(FaascaleMemDeviceConfig),
     /// The latest faascale-mem device statistics.
+    #[cfg(feature = "faascale-mem")]
     FaascaleMemStats(FaascaleMemStats),
+    /// The faascale-mem device health report.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemHealth(FaascaleMemHealth),
+    /// The memory budget negotiated by the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemBudget(FaascaleMemBudget),
+    /// The host memory footprint of the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemFootprint(FaascaleMemFootprint),
+    /// The guest memory blocks populated through the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemBlocks(FaascaleMemBlocks),
+    /// The activity heatmap of the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemHeatmap(FaascaleMemHeatmap),
+    /// The recent populate and depopulate failures of the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemErrors(FaascaleMemErrors),
+    /// The outcome of populating guest memory ahead of the guest, or of faulting in the blocks
+    /// of a warm pool clone.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemWarmReport(FaascaleMemWarmReport),
+    /// The capacity estimate of a scale-up of the faascale-mem device.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemEstimate(FaascaleMemEstimate),
+    /// The faascale-mem device config space.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemConfigSpace(FaascaleMemConfigSpace),
+    /// The descriptions of the faascale-mem statistics and configuration fields.
+    #[cfg(feature = "faascale-mem")]
+    FaascaleMemMetadata(FaascaleMemMetadata),
     /// No data is sent on the channel.
     Empty,
     /// The complete microVM configuration in JSON format.
     FullVmConfig(VmmConfig),
     /// The microVM configuration represented by `VmConfig`.
     MachineConfiguration(MachineConfig),
+    /// The token resuming the quiesced memory devices.
+    MemoryDevicesQuiesced(MemoryDevicesQuiesceToken),
+    /// The inconsistencies found by the cross-checks of the memory devices.
+    MemoryDevicesVerification(MemoryDevicesVerification),
+    /// The anonymous mappings laid over the guest memory by the memory devices.
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    MemoryOverlays(MemoryOverlaysInfo),
     /// Mmds contents.
     MmdsValue(serde_json::Value),
     /// The microVM instance information.
     InstanceInformation(InstanceInfo),
+    /// The files written when creating a snapshot.
+    SnapshotCreated(SnapshotCreateInfo),
+    /// The guest memory metadata of the snapshot the microVM was loaded from.
+    SnapshotMemoryInfo(SnapshotMemoryInfo),
     /// The microVM version.
     VmmVersion(String),
 }
@@ -420,8 +609,14 @@ impl<'a> PrebootApiController<'a> {
             ConfigureMetrics(metrics_cfg) => vmm_config::metrics::init_metrics(metrics_cfg)
                 .map(|()| VmmData::Empty)
                 .map_err(VmmActionError::Metrics),
+            #[cfg(feature = "balloon")]
             GetBalloonConfig => self.balloon_config(),
+            #[cfg(feature = "faascale-mem")]
             GetFaascaleMemConfig => self.faascale_mem_config(),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemMetadata => {
+                Ok(VmmData::FaascaleMemMetadata(FaascaleMemMetadata::default()))
+            }
             GetFullVmConfig => {
                 warn!(
                     "If the VM was restored from snapshot, boot-source, machine-config.smt, and \
@@ -445,8 +640,12 @@ impl<'a> PrebootApiController<'a> {
                 self.set_custom_cpu_template(custom_cpu_template)
             }
             PutMMDS(value) => self.put_mmds(value),
+            #[cfg(feature = "balloon")]
             SetBalloonDevice(config) => self.set_balloon_device(config),
+            #[cfg(feature = "faascale-mem")]
             SetFaascaleMemDevice(config) => self.set_faascale_mem_device(config),
+            #[cfg(feature = "faascale-mem")]
+            SetFaascaleMemHotplugSlot(config) => self.set_faascale_mem_hotplug_slot(config),
             SetVsockDevice(config) => self.set_vsock_device(config),
             SetMmdsConfiguration(config) => self.set_mmds_config(config),
             StartMicroVm => self.start_microvm(),
@@ -455,20 +654,52 @@ impl<'a> PrebootApiController<'a> {
             // Operations not allowed pre-boot.
             CreateSnapshot(_)
             | FlushMetrics
+            | GetSnapshotMemoryInfo
             | Pause
+            | QuiesceMemoryDevices
             | Resume
-            | GetBalloonStats
+            | ResumeMemoryDevices(_)
+            | UpdateBlockDevice(_)
+            | UpdateNetworkInterface(_)
+            | VerifyMemoryDevices => Err(VmmActionError::OperationNotSupportedPreBoot),
+            #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+            ConsolidateMemoryOverlays | GetMemoryOverlays => {
+                Err(VmmActionError::OperationNotSupportedPreBoot)
+            }
+            #[cfg(feature = "balloon")]
+            GetBalloonStats
+            | GetBalloonConfigSpace
             | UpdateBalloon(_)
-            | UpdateBalloonStatistics(_)
-            | GetFaascaleMemStats
+            | UpdateBalloonStatistics(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemStats
+            | GetFaascaleMemHealth
+            | GetFaascaleMemBudget
+            | GetFaascaleMemFootprint
+            | GetFaascaleMemBlocks
+            | GetFaascaleMemHeatmap
+            | GetFaascaleMemErrors
+            | GetFaascaleMemEstimate(_)
+            | GetFaascaleMemConfigSpace
+            | UpdateFaascaleMem(_)
             | UpdateFaascaleMemStatistics(_)
-            | UpdateBlockDevice(_)
-            | UpdateNetworkInterface(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
+            | UpdateFaascaleMemPin(_)
+            | UpdateFaascaleMemMlock(_)
+            | UpdateFaascaleMemRateLimiter(_)
+            | UpdateFaascaleMemFence(_)
+            | UpdateFaascaleMemPopulation(_)
+            | UpdateFaascaleMemBudget(_)
+            | DepopulateFaascaleMem(_)
+            | PollFaascaleMemStats(_)
+            | RefreshFaascaleMemStats
+            | PopulateFaascaleMem(_)
+            | RehydrateFaascaleMem(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => Err(VmmActionError::OperationNotSupportedPreBoot),
         }
     }
 
+    #[cfg(feature = "balloon")]
     fn balloon_config(&mut self) -> ActionResult {
         self.vm_resources
             .balloon
@@ -477,6 +708,7 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::BalloonConfig)
     }
 
+    #[cfg(feature = "faascale-mem")]
     fn faascale_mem_config(&mut self) -> ActionResult {
         self.vm_resources
             .faascale_mem
@@ -501,6 +733,7 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::NetworkConfig)
     }
 
+    #[cfg(feature = "balloon")]
     fn set_balloon_device(&mut self, cfg: BalloonDeviceConfig) -> ActionResult {
         self.boot_path = true;
         self.vm_resources
@@ -509,6 +742,7 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::BalloonConfig)
     }
 
+    #[cfg(feature = "faascale-mem")]
     fn set_faascale_mem_device(&mut self, cfg: FaascaleMemDeviceConfig) -> ActionResult {
         self.boot_path = true;
         self.vm_resources
@@ -517,6 +751,13 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::FaascaleMemConfig)
     }
 
+    #[cfg(feature = "faascale-mem")]
+    fn set_faascale_mem_hotplug_slot(&mut self, cfg: FaascaleMemHotplugSlotConfig) -> ActionResult {
+        self.boot_path = true;
+        self.vm_resources.set_faascale_mem_hotplug_slot(cfg);
+        Ok(VmmData::Empty)
+    }
+
     fn set_boot_source(&mut self, cfg: BootSourceConfig) -> ActionResult {
         self.boot_path = true;
         self.vm_resources
@@ -639,6 +880,18 @@ impl<'a> PrebootApiController<'a> {
     }
 }
 
+// The response to a poll of the faascale-mem statistics, `None` while it is left unanswered.
+#[cfg(feature = "faascale-mem")]
+fn stats_poll_response(
+    result: result::Result<Option<FaascaleMemStats>, FaascaleMemError>,
+) -> Option<ActionResult> {
+    result.transpose().map(|result| {
+        result
+            .map(VmmData::FaascaleMemStats)
+            .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err)))
+    })
+}
+
 /// Enables RPC interaction with a running Firecracker VMM.
 pub struct RuntimeApiController {
     vmm: Arc<Mutex<Vmm>>,
@@ -657,8 +910,17 @@ impl RuntimeApiController {
         use self::VmmAction::*;
         match request {
             // Supported operations allowed post-boot.
+            #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+            ConsolidateMemoryOverlays => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .consolidate_memory_overlays()
+                .map(|_| VmmData::Empty)
+                .map_err(VmmActionError::MemoryDevices),
             CreateSnapshot(snapshot_create_cfg) => self.create_snapshot(&snapshot_create_cfg),
             FlushMetrics => self.flush_metrics(),
+            #[cfg(feature = "balloon")]
             GetBalloonConfig => self
                 .vmm
                 .lock()
@@ -666,6 +928,7 @@ impl RuntimeApiController {
                 .balloon_config()
                 .map(|state| VmmData::BalloonConfig(BalloonDeviceConfig::from(state)))
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
+            #[cfg(feature = "balloon")]
             GetBalloonStats => self
                 .vmm
                 .lock()
@@ -673,6 +936,15 @@ impl RuntimeApiController {
                 .latest_balloon_stats()
                 .map(VmmData::BalloonStats)
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
+            #[cfg(feature = "balloon")]
+            GetBalloonConfigSpace => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .balloon_config_space()
+                .map(VmmData::BalloonConfigSpace)
+                .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
             GetFaascaleMemConfig => self
                 .vmm
                 .lock()
@@ -680,6 +952,11 @@ impl RuntimeApiController {
                 .faascale_mem_config()
                 .map(|state| VmmData::FaascaleMemConfig(FaascaleMemDeviceConfig::from(state)))
                 .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemMetadata => {
+                Ok(VmmData::FaascaleMemMetadata(FaascaleMemMetadata::default()))
+            }
+            #[cfg(feature = "faascale-mem")]
             GetFaascaleMemStats => self
                 .vmm
                 .lock()
@@ -687,8 +964,83 @@ impl RuntimeApiController {
                 .latest_faascale_mem_stats()
                 .map(VmmData::FaascaleMemStats)
                 .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemHealth => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .faascale_mem_health()
+                .map(VmmData::FaascaleMemHealth)
+                .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemBudget => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .faascale_mem_budget()
+                .map(VmmData::FaascaleMemBudget)
+                .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemFootprint => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .faascale_mem_footprint()
+                .map(VmmData::FaascaleMemFootprint)
+                .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemBlocks => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .faascale_mem_blocks()
+                .map(VmmData::FaascaleMemBlocks)
+                .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemHeatmap => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .faascale_mem_heatmap()
+                .map(VmmData::FaascaleMemHeatmap)
+                .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemErrors => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .faascale_mem_errors()
+                .map(VmmData::FaascaleMemErrors)
+                .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemEstimate(estimate_cfg) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .faascale_mem_estimate(estimate_cfg.target_mib)
+                .map(VmmData::FaascaleMemEstimate)
+                .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(feature = "faascale-mem")]
+            GetFaascaleMemConfigSpace => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .faascale_mem_config_space()
+                .map(VmmData::FaascaleMemConfigSpace)
+                .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+            #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+            GetMemoryOverlays => Ok(VmmData::MemoryOverlays(MemoryOverlaysInfo::from(
+                &self.vmm.lock().expect("Poisoned lock").memory_overlays(),
+            ))),
             GetFullVmConfig => Ok(VmmData::FullVmConfig((&self.vm_resources).into())),
             GetMMDS => self.get_mmds(),
+            GetSnapshotMemoryInfo => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .snapshot_memory_info()
+                .map(VmmData::SnapshotMemoryInfo)
+                .ok_or(VmmActionError::NotLoadedFromSnapshot),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(MachineConfig::from(
                 &self.vm_resources.vm_config,
             ))),
@@ -701,32 +1053,176 @@ impl RuntimeApiController {
             PatchMMDS(value) => self.patch_mmds(value),
             Pause => self.pause(),
             PutMMDS(value) => self.put_mmds(value),
+            QuiesceMemoryDevices => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .quiesce_memory_devices()
+                .map(VmmData::MemoryDevicesQuiesced)
+                .map_err(VmmActionError::MemoryDevices),
             Resume => self.resume(),
+            ResumeMemoryDevices(token) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .resume_memory_devices(token)
+                .map(|_| VmmData::Empty)
+                .map_err(VmmActionError::MemoryDevices),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => self.send_ctrl_alt_del(),
-            UpdateBalloon(balloon_update) => self
+            #[cfg(feature = "balloon")]
+            UpdateBalloon(balloon_update) => self.update_balloon_config(balloon_update),
+            #[cfg(feature = "balloon")]
+            UpdateBalloonStatistics(balloon_stats_update) => {
+                self.update_balloon_stats_config(balloon_stats_update)
+            }
+            #[cfg(feature = "faascale-mem")]
+            UpdateFaascaleMem(update_cfg) => self
                 .vmm
                 .lock()
                 .expect("Poisoned lock")
-                .update_balloon_config(balloon_update.amount_mib)
+                .update_faascale_mem_config(update_cfg.target_mib)
                 .map(|_| VmmData::Empty)
-                .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
-            UpdateBalloonStatistics(balloon_stats_update) => self
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
+            #[cfg(feature = "faascale-mem")]
+            UpdateFaascaleMemStatistics(faascale_mem_stats_update) => {
+                self.update_faascale_mem_stats_config(faascale_mem_stats_update)
+            }
+            #[cfg(feature = "faascale-mem")]
+            UpdateFaascaleMemPin(pin_cfg) => self
                 .vmm
                 .lock()
                 .expect("Poisoned lock")
-                .update_balloon_stats_config(balloon_stats_update.stats_polling_interval_s)
+                .update_faascale_mem_pin(pin_cfg.start_pfn, pin_cfg.num_pages, pin_cfg.pinned)
                 .map(|_| VmmData::Empty)
-                .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
-            UpdateFaascaleMemStatistics(faascale_mem_stats_update) => self
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
+            #[cfg(feature = "faascale-mem")]
+            UpdateFaascaleMemMlock(mlock_cfg) => self
                 .vmm
                 .lock()
                 .expect("Poisoned lock")
-                .update_faascale_mem_stats_config(faascale_mem_stats_update.stats_polling_interval_s)
+                .update_faascale_mem_mlock(
+                    mlock_cfg.start_pfn,
+                    mlock_cfg.num_pages,
+                    mlock_cfg.locked,
+                )
                 .map(|_| VmmData::Empty)
-                .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))),
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
+            #[cfg(feature = "faascale-mem")]
+            UpdateFaascaleMemRateLimiter(rate_limiter_cfg) => {
+                let update = RateLimiterUpdate::from(rate_limiter_cfg.rate_limiter);
+                self.vmm
+                    .lock()
+                    .expect("Poisoned lock")
+                    .update_faascale_mem_rate_limiter(update.bandwidth, update.ops)
+                    .map(|_| VmmData::Empty)
+                    .map_err(|err| {
+                        VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                    })
+            }
+            #[cfg(feature = "faascale-mem")]
+            UpdateFaascaleMemFence(fence_cfg) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .update_faascale_mem_fence(fence_cfg.fenced)
+                .map(|_| VmmData::Empty)
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
+            #[cfg(feature = "faascale-mem")]
+            UpdateFaascaleMemPopulation(population_cfg) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .update_faascale_mem_population(
+                    population_cfg.pre_alloc_mem,
+                    population_cfg.pre_tdp_fault,
+                )
+                .map(|_| VmmData::Empty)
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
+            #[cfg(feature = "faascale-mem")]
+            UpdateFaascaleMemBudget(budget_cfg) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .update_faascale_mem_budget(budget_cfg.budget_mib)
+                .map(|_| VmmData::Empty)
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
+            #[cfg(feature = "faascale-mem")]
+            DepopulateFaascaleMem(depopulate_cfg) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .depopulate_faascale_mem(
+                    depopulate_cfg.start_pfn,
+                    depopulate_cfg.num_pages,
+                    depopulate_cfg.release_mib,
+                )
+                .map(|_| VmmData::Empty)
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
+            #[cfg(feature = "faascale-mem")]
+            // Only the API server adapter waits for the guest to answer, on the event loop. Here
+            // an unanswered poll fails at once.
+            PollFaascaleMemStats(_) => self
+                .start_faascale_mem_stats_poll()
+                .or_else(|| self.poll_faascale_mem_stats(true))
+                .expect("A poll given up on is answered"),
+            #[cfg(feature = "faascale-mem")]
+            RefreshFaascaleMemStats => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .refresh_faascale_mem_stats()
+                .map(|_| VmmData::Empty)
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
+            #[cfg(feature = "faascale-mem")]
+            PopulateFaascaleMem(populate_cfg) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .populate_faascale_mem(
+                    populate_cfg.start_pfn,
+                    populate_cfg.num_pages,
+                    populate_cfg.amount_mib,
+                    &populate_cfg.blocks(),
+                )
+                .map(VmmData::FaascaleMemWarmReport)
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
+            #[cfg(feature = "faascale-mem")]
+            RehydrateFaascaleMem(rehydrate_cfg) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .rehydrate_faascale_mem(&rehydrate_cfg.blocks())
+                .map(VmmData::FaascaleMemWarmReport)
+                .map_err(|err| {
+                    VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err))
+                }),
             UpdateBlockDevice(new_cfg) => self.update_block_device(new_cfg),
             UpdateNetworkInterface(netif_update) => self.update_net_rate_limiters(netif_update),
+            VerifyMemoryDevices => Ok(VmmData::MemoryDevicesVerification(
+                self.vmm
+                    .lock()
+                    .expect("Poisoned lock")
+                    .verify_memory_devices(),
+            )),
 
             // Operations not allowed post-boot.
             ConfigureBootSource(_)
@@ -736,13 +1232,17 @@ impl RuntimeApiController {
             | InsertNetworkDevice(_)
             | LoadSnapshot(_)
             | PutCpuConfiguration(_)
-            | SetBalloonDevice(_)
-            | SetFaascaleMemDevice(_)
             | SetVsockDevice(_)
             | SetMmdsConfiguration(_)
             | SetEntropyDevice(_)
             | StartMicroVm
             | UpdateVmConfiguration(_) => Err(VmmActionError::OperationNotSupportedPostBoot),
+            #[cfg(feature = "balloon")]
+            SetBalloonDevice(_) => Err(VmmActionError::OperationNotSupportedPostBoot),
+            #[cfg(feature = "faascale-mem")]
+            SetFaascaleMemDevice(config) => self.hotplug_faascale_mem_device(config),
+            #[cfg(feature = "faascale-mem")]
+            SetFaascaleMemHotplugSlot(_) => Err(VmmActionError::OperationNotSupportedPostBoot),
         }
     }
 
@@ -751,6 +1251,36 @@ impl RuntimeApiController {
         Self { vmm, vm_resources }
     }
 
+    /// Asks the guest to report fresh faascale-mem statistics. Returns the response right away
+    /// when the poll fails or the guest already answered, and `None` when it is left to
+    /// `poll_faascale_mem_stats`.
+    #[cfg(feature = "faascale-mem")]
+    pub fn start_faascale_mem_stats_poll(&mut self) -> Option<ActionResult> {
+        let result = self
+            .vmm
+            .lock()
+            .expect("Poisoned lock")
+            .start_faascale_mem_stats_poll();
+        stats_poll_response(result.and_then(|()| {
+            self.vmm
+                .lock()
+                .expect("Poisoned lock")
+                .poll_faascale_mem_stats(false)
+        }))
+    }
+
+    /// The response to the poll started by `start_faascale_mem_stats_poll`, or `None` while the
+    /// guest has not answered. With `give_up`, an unanswered poll fails instead.
+    #[cfg(feature = "faascale-mem")]
+    pub fn poll_faascale_mem_stats(&mut self, give_up: bool) -> Option<ActionResult> {
+        let result = self
+            .vmm
+            .lock()
+            .expect("Poisoned lock")
+            .poll_faascale_mem_stats(give_up);
+        stats_poll_response(result)
+    }
+
     /// Pauses the microVM by pausing the vCPUs.
     pub fn pause(&mut self) -> ActionResult {
         let pause_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
@@ -785,7 +1315,7 @@ impl RuntimeApiController {
     fn flush_metrics(&mut self) -> ActionResult {
         // FIXME: we're losing the bool saying whether metrics were actually written.
         METRICS
-            .write()
+            .write_and_push()
             .map(|_| VmmData::Empty)
             .map_err(super::Error::Metrics)
             .map_err(VmmActionError::InternalVmm)
@@ -818,7 +1348,7 @@ impl RuntimeApiController {
         let vm_info = VmInfo::from(&self.vm_resources);
         let create_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
 
-        create_snapshot(
+        let snapshot_info = create_snapshot(
             &mut locked_vmm,
             &vm_info,
             create_params,
@@ -847,7 +1377,11 @@ impl RuntimeApiController {
                 );
             }
         }
-        Ok(VmmData::Empty)
+        if create_params.sparse {
+            Ok(VmmData::SnapshotCreated(snapshot_info))
+        } else {
+            Ok(VmmData::Empty)
+        }
     }
 
     /// Updates block device properties:
@@ -873,6 +1407,134 @@ impl RuntimeApiController {
         Ok(VmmData::Empty)
     }
 
+    /// Checks that a conditional balloon update targets the current configuration epoch.
+    #[cfg(feature = "balloon")]
+    fn check_balloon_config_epoch(
+        vmm: &mut Vmm,
+        if_match_epoch: Option<u64>,
+    ) -> Result<(), BalloonConfigError> {
+        if let Some(expected) = if_match_epoch {
+            let current = vmm.balloon_config()?.config_epoch;
+            if current != expected {
+                return Err(BalloonConfigError::ConfigEpochMismatch { expected, current });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a conditional faascale-mem update targets the current configuration epoch.
+    #[cfg(feature = "faascale-mem")]
+    fn check_faascale_mem_config_epoch(
+        vmm: &mut Vmm,
+        if_match_epoch: Option<u64>,
+    ) -> Result<(), FaascaleMemConfigError> {
+        if let Some(expected) = if_match_epoch {
+            let current = vmm.faascale_mem_config()?.config_epoch;
+            if current != expected {
+                return Err(FaascaleMemConfigError::ConfigEpochMismatch { expected, current });
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the balloon target size as described in `update`.
+    #[cfg(feature = "balloon")]
+    fn update_balloon_config(&mut self, update: BalloonUpdateConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        Self::check_balloon_config_epoch(&mut vmm, update.if_match_epoch)?;
+        vmm.update_balloon_config(update.amount_mib)
+            .map(|amount_mib| {
+                if amount_mib < update.amount_mib {
+                    VmmData::BalloonUpdateClamped(BalloonUpdateClamped::new(
+                        update.amount_mib,
+                        amount_mib,
+                    ))
+                } else {
+                    VmmData::Empty
+                }
+            })
+            .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err)))
+    }
+
+    /// Updates the balloon statistics polling interval as described in `update`.
+    #[cfg(feature = "balloon")]
+    fn update_balloon_stats_config(&mut self, update: BalloonUpdateStatsConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        Self::check_balloon_config_epoch(&mut vmm, update.if_match_epoch)?;
+        vmm.update_balloon_stats_config(update.stats_polling_interval_s)
+            .map(|_| VmmData::Empty)
+            .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err)))
+    }
+
+    /// Updates the faascale-mem statistics polling interval as described in `update`.
+    #[cfg(feature = "faascale-mem")]
+    fn update_faascale_mem_stats_config(
+        &mut self,
+        update: FaascaleMemUpdateStatsConfig,
+    ) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        Self::check_faascale_mem_config_epoch(&mut vmm, update.if_match_epoch)?;
+        vmm.update_faascale_mem_stats_config(update.stats_polling_interval_s)
+            .map(|_| VmmData::Empty)
+            .map_err(|err| VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::from(err)))
+    }
+
+    /// Hot-plugs a faascale-mem device configured as `cfg` in the slot reserved at boot.
+    #[cfg(feature = "faascale-mem")]
+    fn hotplug_faascale_mem_device(&mut self, cfg: FaascaleMemDeviceConfig) -> ActionResult {
+        if self.vm_resources.faascale_mem.get().is_some() {
+            return Err(VmmActionError::FaascaleMemConfig(
+                FaascaleMemConfigError::DeviceAlreadyAttached,
+            ));
+        }
+        // The populate poller of the latency mode is a thread spawned at boot only.
+        if cfg.latency_mode {
+            return Err(VmmActionError::FaascaleMemConfig(
+                FaascaleMemConfigError::HotplugLatencyMode,
+            ));
+        }
+        // So is the fault handler thread of the lazy populate mode.
+        if cfg.populate_mode == FaascaleMemPopulateMode::Lazy {
+            return Err(VmmActionError::FaascaleMemConfig(
+                FaascaleMemConfigError::HotplugLazyPopulate,
+            ));
+        }
+        // The counters are opened before the seccomp filters are loaded.
+        if cfg.perf_sampling {
+            return Err(VmmActionError::FaascaleMemConfig(
+                FaascaleMemConfigError::HotplugPerfSampling,
+            ));
+        }
+        // So is the host memory pool, reserved with mappings the filters refuse.
+        if cfg.pool.is_some() {
+            return Err(VmmActionError::FaascaleMemConfig(
+                FaascaleMemConfigError::HotplugPool,
+            ));
+        }
+        // The activity observer is only started at boot.
+        if cfg.vsock_observer_port.is_some() {
+            return Err(VmmActionError::FaascaleMemConfig(
+                FaascaleMemConfigError::HotplugVsockObserver,
+            ));
+        }
+        self.vm_resources
+            .check_faascale_mem_huge_pages(&cfg)
+            .map_err(VmmActionError::FaascaleMemConfig)?;
+        let faascale_mem =
+            FaascaleMemBuilder::build(cfg).map_err(VmmActionError::FaascaleMemConfig)?;
+        faascale_mem
+            .lock()
+            .expect("Poisoned lock")
+            .set_mmds(self.vm_resources.mmds.clone());
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .hotplug_faascale_mem(faascale_mem.clone())
+            .map_err(VmmActionError::InternalVmm)?;
+        self.vm_resources.faascale_mem.set_device(faascale_mem);
+        Ok(VmmData::Empty)
+    }
+
     /// Updates configuration for an emulated net device as described in `new_cfg`.
     fn update_net_rate_limiters(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
         self.vmm
@@ -902,35 +1564,53 @@ mod tests {
     use super::*;
     use crate::cpu_config::templates::test_utils::build_test_template;
     use crate::cpu_config::templates::{CpuTemplateType, StaticCpuTemplate};
+    #[cfg(feature = "balloon")]
     use crate::devices::virtio::balloon::{BalloonConfig, Error as BalloonError};
+    #[cfg(feature = "faascale-mem")]
+    use crate::devices::virtio::faascale_mem::{Error as FaascaleMemError, FaascaleMemConfig};
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    use crate::devices::virtio::mem_overlay::MmapOverlays;
     use crate::devices::virtio::rng::Error as EntropyError;
     use crate::devices::virtio::VsockError;
+    #[cfg(feature = "balloon")]
     use crate::vmm_config::balloon::BalloonBuilder;
     use crate::vmm_config::drive::{CacheType, FileEngineType};
+    #[cfg(feature = "faascale-mem")]
+    use crate::vmm_config::faascale_mem::FaascaleMemTemplateBlock;
     use crate::vmm_config::logger::LoggerLevel;
     use crate::vmm_config::machine_config::VmConfig;
     use crate::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
     use crate::vmm_config::vsock::VsockBuilder;
+    #[cfg(feature = "faascale-mem")]
+    use crate::vmm_config::{RateLimiterConfig, TokenBucketConfig};
     use crate::HTTP_MAX_PAYLOAD_SIZE;
 
     impl PartialEq for VmmActionError {
         fn eq(&self, other: &VmmActionError) -> bool {
             use VmmActionError::*;
+            match (self, other) {
+                #[cfg(feature = "balloon")]
+                (BalloonConfig(_), BalloonConfig(_)) => return true,
+                #[cfg(feature = "faascale-mem")]
+                (FaascaleMemConfig(_), FaascaleMemConfig(_)) => return true,
+                _ => (),
+            }
             matches!(
                 (self, other),
-                (BalloonConfig(_), BalloonConfig(_))
-                    | (BootSource(_), BootSource(_))
+                (BootSource(_), BootSource(_))
                     | (CreateSnapshot(_), CreateSnapshot(_))
                     | (DriveConfig(_), DriveConfig(_))
                     | (InternalVmm(_), InternalVmm(_))
                     | (LoadSnapshot(_), LoadSnapshot(_))
                     | (Logger(_), Logger(_))
                     | (MachineConfig(_), MachineConfig(_))
+                    | (MemoryDevices(_), MemoryDevices(_))
                     | (Metrics(_), Metrics(_))
                     | (Mmds(_), Mmds(_))
                     | (MmdsLimitExceeded(_), MmdsLimitExceeded(_))
                     | (MmdsConfig(_), MmdsConfig(_))
                     | (NetworkConfig(_), NetworkConfig(_))
+                    | (NotLoadedFromSnapshot, NotLoadedFromSnapshot)
                     | (NotSupported(_), NotSupported(_))
                     | (OperationNotSupportedPostBoot, OperationNotSupportedPostBoot)
                     | (OperationNotSupportedPreBoot, OperationNotSupportedPreBoot)
@@ -945,9 +1625,12 @@ mod tests {
     #[derive(Default)]
     pub struct MockVmRes {
         pub vm_config: VmConfig,
+        #[cfg(feature = "balloon")]
         pub balloon: BalloonBuilder,
         pub vsock: VsockBuilder,
+        #[cfg(feature = "balloon")]
         balloon_config_called: bool,
+        #[cfg(feature = "balloon")]
         balloon_set: bool,
         boot_src: BootSourceConfig,
         boot_cfg_set: bool,
@@ -963,6 +1646,7 @@ mod tests {
     }
 
     impl MockVmRes {
+        #[cfg(feature = "balloon")]
         pub fn balloon_config(&mut self) -> Result<BalloonConfig, BalloonError> {
             if self.force_errors {
                 return Err(BalloonError::DeviceNotFound);
@@ -992,6 +1676,7 @@ mod tests {
             Ok(())
         }
 
+        #[cfg(feature = "balloon")]
         pub fn set_balloon_device(
             &mut self,
             _: BalloonDeviceConfig,
@@ -1111,6 +1796,7 @@ mod tests {
                 smt: value.vm_config.smt,
                 cpu_template: StaticCpuTemplate::from(&value.vm_config.cpu_template),
                 boot_source: value.boot_source_config().clone(),
+                huge_pages: value.vm_config.huge_pages,
             }
         }
     }
@@ -1118,16 +1804,76 @@ mod tests {
     // Mock `Vmm` used for testing.
     #[derive(Debug, Default, PartialEq, Eq)]
     pub struct MockVmm {
+        #[cfg(feature = "balloon")]
         pub balloon_config_called: bool,
+        #[cfg(feature = "balloon")]
         pub latest_balloon_stats_called: bool,
+        #[cfg(feature = "balloon")]
+        pub balloon_config_space_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub faascale_mem_config_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub faascale_mem_health_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub faascale_mem_budget_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub faascale_mem_footprint_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub faascale_mem_blocks_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub faascale_mem_heatmap_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub faascale_mem_errors_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub faascale_mem_estimate_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub faascale_mem_config_space_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub latest_faascale_mem_stats_called: bool,
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        pub consolidate_memory_overlays_called: bool,
         pub pause_called: bool,
+        pub quiesce_memory_devices_called: bool,
         pub resume_called: bool,
+        pub resume_memory_devices_called: bool,
         #[cfg(target_arch = "x86_64")]
         pub send_ctrl_alt_del_called: bool,
+        #[cfg(feature = "balloon")]
         pub update_balloon_config_called: bool,
+        #[cfg(feature = "balloon")]
         pub update_balloon_stats_config_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub update_faascale_mem_config_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub update_faascale_mem_stats_config_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub update_faascale_mem_pin_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub update_faascale_mem_mlock_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub update_faascale_mem_rate_limiter_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub update_faascale_mem_fence_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub update_faascale_mem_population_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub update_faascale_mem_budget_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub depopulate_faascale_mem_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub poll_faascale_mem_stats_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub refresh_faascale_mem_stats_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub populate_faascale_mem_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub rehydrate_faascale_mem_called: bool,
+        #[cfg(feature = "faascale-mem")]
+        pub hotplug_faascale_mem_called: bool,
         pub update_block_device_path_called: bool,
         pub update_net_rate_limiters_called: bool,
+        pub verify_memory_devices_called: bool,
+        pub snapshot_memory_info: Option<SnapshotMemoryInfo>,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
     }
@@ -1160,6 +1906,7 @@ mod tests {
             Ok(())
         }
 
+        #[cfg(feature = "balloon")]
         pub fn balloon_config(&mut self) -> Result<BalloonConfig, BalloonError> {
             if self.force_errors {
                 return Err(BalloonError::DeviceNotFound);
@@ -1168,6 +1915,7 @@ mod tests {
             Ok(BalloonConfig::default())
         }
 
+        #[cfg(feature = "balloon")]
         pub fn latest_balloon_stats(&mut self) -> Result<BalloonStats, BalloonError> {
             if self.force_errors {
                 return Err(BalloonError::DeviceNotFound);
@@ -1176,14 +1924,26 @@ mod tests {
             Ok(BalloonStats::default())
         }
 
-        pub fn update_balloon_config(&mut self, _: u32) -> Result<(), BalloonError> {
+        #[cfg(feature = "balloon")]
+        pub fn balloon_config_space(&mut self) -> Result<BalloonConfigSpace, BalloonError> {
+            if self.force_errors {
+                return Err(BalloonError::DeviceNotFound);
+            }
+            self.balloon_config_space_called = true;
+            Ok(BalloonConfigSpace::default())
+        }
+
+        #[cfg(feature = "balloon")]
+        pub fn update_balloon_config(&mut self, amount_mib: u32) -> Result<u32, BalloonError> {
             if self.force_errors {
                 return Err(BalloonError::DeviceNotFound);
             }
             self.update_balloon_config_called = true;
-            Ok(())
+            // The guest memory floor of the mock leaves room for a balloon of 128 MiB.
+            Ok(amount_mib.min(128))
         }
 
+        #[cfg(feature = "balloon")]
         pub fn update_balloon_stats_config(&mut self, _: u16) -> Result<(), BalloonError> {
             if self.force_errors {
                 return Err(BalloonError::DeviceNotFound);
@@ -1192,49 +1952,368 @@ mod tests {
             Ok(())
         }
 
-        pub fn update_block_device_path(&mut self, _: &str, _: String) -> Result<(), VmmError> {
+        #[cfg(feature = "faascale-mem")]
+        pub fn faascale_mem_config(&mut self) -> Result<FaascaleMemConfig, FaascaleMemError> {
             if self.force_errors {
-                return Err(VmmError::DeviceManager(
-                    crate::device_manager::mmio::Error::InvalidDeviceType,
-                ));
+                return Err(FaascaleMemError::DeviceNotFound);
             }
-            self.update_block_device_path_called = true;
+            self.faascale_mem_config_called = true;
+            Ok(FaascaleMemConfig::default())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn latest_faascale_mem_stats(&mut self) -> Result<FaascaleMemStats, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.latest_faascale_mem_stats_called = true;
+            Ok(FaascaleMemStats::default())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn faascale_mem_health(&mut self) -> Result<FaascaleMemHealth, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.faascale_mem_health_called = true;
+            Ok(FaascaleMemHealth::default())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn faascale_mem_footprint(&mut self) -> Result<FaascaleMemFootprint, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.faascale_mem_footprint_called = true;
+            Ok(FaascaleMemFootprint::default())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn faascale_mem_blocks(&mut self) -> Result<FaascaleMemBlocks, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.faascale_mem_blocks_called = true;
+            Ok(FaascaleMemBlocks::default())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn faascale_mem_heatmap(&mut self) -> Result<FaascaleMemHeatmap, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.faascale_mem_heatmap_called = true;
+            Ok(FaascaleMemHeatmap::default())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn faascale_mem_errors(&mut self) -> Result<FaascaleMemErrors, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.faascale_mem_errors_called = true;
+            Ok(FaascaleMemErrors::default())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn faascale_mem_estimate(
+            &mut self,
+            target_mib: u32,
+        ) -> Result<FaascaleMemEstimate, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.faascale_mem_estimate_called = true;
+            Ok(FaascaleMemEstimate {
+                target_mib,
+                ..Default::default()
+            })
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn faascale_mem_config_space(
+            &mut self,
+        ) -> Result<FaascaleMemConfigSpace, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.faascale_mem_config_space_called = true;
+            Ok(FaascaleMemConfigSpace::default())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn update_faascale_mem_stats_config(&mut self, _: u16) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.update_faascale_mem_stats_config_called = true;
             Ok(())
         }
 
-        pub fn update_block_rate_limiter(
+        #[cfg(feature = "faascale-mem")]
+        pub fn update_faascale_mem_pin(
             &mut self,
-            _: &str,
-            _: rate_limiter::BucketUpdate,
-            _: rate_limiter::BucketUpdate,
-        ) -> Result<(), VmmError> {
+            _: u32,
+            _: u32,
+            _: bool,
+        ) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.update_faascale_mem_pin_called = true;
             Ok(())
         }
 
-        pub fn update_net_rate_limiters(
+        #[cfg(feature = "faascale-mem")]
+        pub fn update_faascale_mem_mlock(
+            &mut self,
+            _: u32,
+            _: u32,
+            _: bool,
+        ) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.update_faascale_mem_mlock_called = true;
+            Ok(())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn update_faascale_mem_rate_limiter(
             &mut self,
-            _: &str,
-            _: rate_limiter::BucketUpdate,
-            _: rate_limiter::BucketUpdate,
             _: rate_limiter::BucketUpdate,
             _: rate_limiter::BucketUpdate,
-        ) -> Result<(), VmmError> {
+        ) -> Result<(), FaascaleMemError> {
             if self.force_errors {
-                return Err(VmmError::DeviceManager(
-                    crate::device_manager::mmio::Error::InvalidDeviceType,
-                ));
+                return Err(FaascaleMemError::DeviceNotFound);
             }
-            self.update_net_rate_limiters_called = true;
+            self.update_faascale_mem_rate_limiter_called = true;
             Ok(())
         }
 
-        pub fn instance_info(&self) -> InstanceInfo {
-            InstanceInfo::default()
+        #[cfg(feature = "faascale-mem")]
+        pub fn update_faascale_mem_fence(&mut self, _: bool) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.update_faascale_mem_fence_called = true;
+            Ok(())
         }
 
-        pub fn version(&self) -> String {
-            String::default()
-        }
+        #[cfg(feature = "faascale-mem")]
+        pub fn update_faascale_mem_population(
+            &mut self,
+            _: Option<bool>,
+            _: Option<bool>,
+        ) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.update_faascale_mem_population_called = true;
+            Ok(())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn depopulate_faascale_mem(
+            &mut self,
+            _: Option<u32>,
+            _: Option<u32>,
+            _: Option<u32>,
+        ) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.depopulate_faascale_mem_called = true;
+            Ok(())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn start_faascale_mem_stats_poll(&mut self) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.poll_faascale_mem_stats_called = true;
+            Ok(())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn poll_faascale_mem_stats(
+            &mut self,
+            _: bool,
+        ) -> Result<Option<FaascaleMemStats>, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            Ok(Some(FaascaleMemStats::default()))
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn refresh_faascale_mem_stats(&mut self) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.refresh_faascale_mem_stats_called = true;
+            Ok(())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn populate_faascale_mem(
+            &mut self,
+            _: Option<u32>,
+            _: Option<u32>,
+            _: Option<u32>,
+            blocks: &[(u32, u32)],
+        ) -> Result<FaascaleMemWarmReport, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.populate_faascale_mem_called = true;
+            Ok(FaascaleMemWarmReport {
+                blocks: blocks.len() as u64,
+                ..Default::default()
+            })
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn update_faascale_mem_config(&mut self, _: u32) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.update_faascale_mem_config_called = true;
+            Ok(())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn rehydrate_faascale_mem(
+            &mut self,
+            _: &[(u32, u32)],
+        ) -> Result<FaascaleMemWarmReport, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.rehydrate_faascale_mem_called = true;
+            Ok(FaascaleMemWarmReport::default())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn hotplug_faascale_mem(
+            &mut self,
+            _: Arc<Mutex<crate::devices::virtio::FaascaleMem>>,
+        ) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::HotplugSlotNotFound,
+                ));
+            }
+            self.hotplug_faascale_mem_called = true;
+            Ok(())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn update_faascale_mem_budget(&mut self, _: u32) -> Result<(), FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.update_faascale_mem_budget_called = true;
+            Ok(())
+        }
+
+        #[cfg(feature = "faascale-mem")]
+        pub fn faascale_mem_budget(&mut self) -> Result<FaascaleMemBudget, FaascaleMemError> {
+            if self.force_errors {
+                return Err(FaascaleMemError::DeviceNotFound);
+            }
+            self.faascale_mem_budget_called = true;
+            Ok(FaascaleMemBudget::default())
+        }
+
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        pub fn memory_overlays(&self) -> MmapOverlays {
+            MmapOverlays::default()
+        }
+
+        pub fn snapshot_memory_info(&self) -> Option<SnapshotMemoryInfo> {
+            self.snapshot_memory_info.clone()
+        }
+
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        pub fn consolidate_memory_overlays(&mut self) -> Result<(), MemoryDevicesError> {
+            if self.force_errors {
+                return Err(MemoryDevicesError::VmNotPaused);
+            }
+            self.consolidate_memory_overlays_called = true;
+            Ok(())
+        }
+
+        pub fn verify_memory_devices(&mut self) -> MemoryDevicesVerification {
+            self.verify_memory_devices_called = true;
+            MemoryDevicesVerification::default()
+        }
+
+        pub fn quiesce_memory_devices(
+            &mut self,
+        ) -> Result<MemoryDevicesQuiesceToken, MemoryDevicesError> {
+            if self.force_errors {
+                return Err(MemoryDevicesError::AlreadyQuiesced);
+            }
+            self.quiesce_memory_devices_called = true;
+            Ok(MemoryDevicesQuiesceToken { token: 1 })
+        }
+
+        pub fn resume_memory_devices(
+            &mut self,
+            _: MemoryDevicesQuiesceToken,
+        ) -> Result<(), MemoryDevicesError> {
+            if self.force_errors {
+                return Err(MemoryDevicesError::NotQuiesced);
+            }
+            self.resume_memory_devices_called = true;
+            Ok(())
+        }
+
+        pub fn update_block_device_path(&mut self, _: &str, _: String) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::InvalidDeviceType,
+                ));
+            }
+            self.update_block_device_path_called = true;
+            Ok(())
+        }
+
+        pub fn update_block_rate_limiter(
+            &mut self,
+            _: &str,
+            _: rate_limiter::BucketUpdate,
+            _: rate_limiter::BucketUpdate,
+        ) -> Result<(), VmmError> {
+            Ok(())
+        }
+
+        pub fn update_net_rate_limiters(
+            &mut self,
+            _: &str,
+            _: rate_limiter::BucketUpdate,
+            _: rate_limiter::BucketUpdate,
+            _: rate_limiter::BucketUpdate,
+            _: rate_limiter::BucketUpdate,
+        ) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::InvalidDeviceType,
+                ));
+            }
+            self.update_net_rate_limiters_called = true;
+            Ok(())
+        }
+
+        pub fn instance_info(&self) -> InstanceInfo {
+            InstanceInfo::default()
+        }
+
+        pub fn version(&self) -> String {
+            String::default()
+        }
     }
 
     // Need to redefine this since the non-test one uses real VmResources
@@ -1255,8 +2334,8 @@ mod tests {
         _: &VmInfo,
         _: &CreateSnapshotParams,
         _: versionize::VersionMap,
-    ) -> std::result::Result<(), CreateSnapshotError> {
-        Ok(())
+    ) -> std::result::Result<SnapshotCreateInfo, CreateSnapshotError> {
+        Ok(SnapshotCreateInfo::default())
     }
 
     // Need to redefine this since the non-test one uses real Vmm
@@ -1360,6 +2439,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_preboot_get_balloon_config() {
         let req = VmmAction::GetBalloonConfig;
         let expected_cfg = BalloonDeviceConfig::default();
@@ -1400,6 +2480,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_preboot_set_balloon_dev() {
         let req = VmmAction::SetBalloonDevice(BalloonDeviceConfig::default());
         check_preboot_request(req, |result, vm_res| {
@@ -1810,20 +2891,168 @@ mod tests {
             VmmAction::Resume,
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        #[cfg(feature = "balloon")]
         check_preboot_request_err(
             VmmAction::GetBalloonStats,
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        #[cfg(feature = "balloon")]
+        check_preboot_request_err(
+            VmmAction::GetBalloonConfigSpace,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "balloon")]
         check_preboot_request_err(
-            VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 }),
+            VmmAction::UpdateBalloon(BalloonUpdateConfig {
+                amount_mib: 0,
+                if_match_epoch: None,
+            }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        #[cfg(feature = "balloon")]
         check_preboot_request_err(
             VmmAction::UpdateBalloonStatistics(BalloonUpdateStatsConfig {
                 stats_polling_interval_s: 0,
+                if_match_epoch: None,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::GetFaascaleMemHealth,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::GetFaascaleMemFootprint,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::GetFaascaleMemBlocks,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::GetFaascaleMemHeatmap,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::GetFaascaleMemErrors,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::GetFaascaleMemEstimate(FaascaleMemEstimateConfig { target_mib: 512 }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::GetFaascaleMemConfigSpace,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::UpdateFaascaleMemPin(FaascaleMemPinConfig {
+                start_pfn: 0,
+                num_pages: 1,
+                pinned: true,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::UpdateFaascaleMemMlock(FaascaleMemMlockConfig {
+                start_pfn: 0,
+                num_pages: 1,
+                locked: true,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::UpdateFaascaleMemRateLimiter(FaascaleMemRateLimiterConfig::default()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::UpdateFaascaleMemFence(FaascaleMemFenceConfig { fenced: true }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::UpdateFaascaleMemPopulation(FaascaleMemPopulationConfig::default()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::UpdateFaascaleMem(FaascaleMemUpdateConfig { target_mib: 256 }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::UpdateFaascaleMemBudget(FaascaleMemBudgetConfig { budget_mib: 512 }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::DepopulateFaascaleMem(FaascaleMemDepopulateConfig {
+                release_mib: Some(64),
+                ..Default::default()
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::PollFaascaleMemStats(FaascaleMemPollStatsConfig::default()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::RefreshFaascaleMemStats,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::PopulateFaascaleMem(FaascaleMemPopulateConfig {
+                amount_mib: Some(64),
+                ..Default::default()
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::RehydrateFaascaleMem(FaascaleMemRehydrateConfig::default()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(feature = "faascale-mem")]
+        check_preboot_request_err(
+            VmmAction::GetFaascaleMemBudget,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        check_preboot_request_err(
+            VmmAction::ConsolidateMemoryOverlays,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+        check_preboot_request_err(
+            VmmAction::GetMemoryOverlays,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::GetSnapshotMemoryInfo,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::QuiesceMemoryDevices,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::ResumeMemoryDevices(MemoryDevicesQuiesceToken { token: 1 }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig::default()),
             VmmActionError::OperationNotSupportedPreBoot,
@@ -1836,12 +3065,17 @@ mod tests {
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::VerifyMemoryDevices,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::CreateSnapshot(CreateSnapshotParams {
                 snapshot_type: SnapshotType::Full,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
                 version: None,
+                sparse: false,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
@@ -1979,51 +3213,186 @@ mod tests {
         check_runtime_request_err(req, VmmActionError::InternalVmm(VmmError::VcpuResume));
     }
 
-    #[cfg(target_arch = "x86_64")]
     #[test]
-    fn test_runtime_ctrl_alt_del() {
-        let req = VmmAction::SendCtrlAltDel;
+    fn test_runtime_quiesce_memory_devices() {
+        let req = VmmAction::QuiesceMemoryDevices;
         check_runtime_request(req, |result, vmm| {
-            assert_eq!(result, Ok(VmmData::Empty));
-            assert!(vmm.send_ctrl_alt_del_called)
+            assert_eq!(
+                result,
+                Ok(VmmData::MemoryDevicesQuiesced(MemoryDevicesQuiesceToken {
+                    token: 1
+                }))
+            );
+            assert!(vmm.quiesce_memory_devices_called)
         });
 
-        let req = VmmAction::SendCtrlAltDel;
+        let req = VmmAction::QuiesceMemoryDevices;
         check_runtime_request_err(
             req,
-            VmmActionError::InternalVmm(VmmError::I8042Error(
-                crate::devices::legacy::I8042DeviceError::InternalBufferFull,
-            )),
+            VmmActionError::MemoryDevices(MemoryDevicesError::AlreadyQuiesced),
         );
     }
 
     #[test]
-    fn test_runtime_balloon_config() {
-        let req = VmmAction::GetBalloonConfig;
-        check_runtime_request(req, |result, vmm| {
+    fn test_runtime_get_snapshot_memory_info() {
+        // The microVM was booted, not loaded from a snapshot.
+        let req = VmmAction::GetSnapshotMemoryInfo;
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Err(VmmActionError::NotLoadedFromSnapshot));
+        });
+
+        let info = SnapshotMemoryInfo {
+            snapshot_data_version: 9,
+            firecracker_version: Some("1.5.0".to_string()),
+            mem_backend_type: MemBackendType::File,
+            mem_size_bytes: 128 << 20,
+            mem_file_size: Some(128 << 20),
+            mem_file_hole_bytes: Some(96 << 20),
+            faascale_populated_ranges: None,
+        };
+        let vmm = Arc::new(Mutex::new(MockVmm {
+            snapshot_memory_info: Some(info.clone()),
+            ..Default::default()
+        }));
+        let mut runtime = RuntimeApiController::new(MockVmRes::default(), vmm);
+        assert_eq!(
+            runtime.handle_request(VmmAction::GetSnapshotMemoryInfo),
+            Ok(VmmData::SnapshotMemoryInfo(info))
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    fn test_runtime_get_memory_overlays() {
+        let req = VmmAction::GetMemoryOverlays;
+        check_runtime_request(req, |result, _| {
             assert_eq!(
                 result,
-                Ok(VmmData::BalloonConfig(BalloonDeviceConfig::default()))
+                Ok(VmmData::MemoryOverlays(MemoryOverlaysInfo::default()))
             );
-            assert!(vmm.balloon_config_called)
         });
-
-        let req = VmmAction::GetBalloonConfig;
-        check_runtime_request_err(
-            req,
-            VmmActionError::BalloonConfig(BalloonConfigError::DeviceNotFound),
-        );
     }
 
     #[test]
-    fn test_runtime_latest_balloon_stats() {
-        let req = VmmAction::GetBalloonStats;
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    fn test_runtime_consolidate_memory_overlays() {
+        let req = VmmAction::ConsolidateMemoryOverlays;
         check_runtime_request(req, |result, vmm| {
-            assert_eq!(result, Ok(VmmData::BalloonStats(BalloonStats::default())));
-            assert!(vmm.latest_balloon_stats_called)
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.consolidate_memory_overlays_called)
         });
 
-        let req = VmmAction::GetBalloonStats;
+        let req = VmmAction::ConsolidateMemoryOverlays;
+        check_runtime_request_err(
+            req,
+            VmmActionError::MemoryDevices(MemoryDevicesError::VmNotPaused),
+        );
+    }
+
+    #[test]
+    fn test_runtime_verify_memory_devices() {
+        let req = VmmAction::VerifyMemoryDevices;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::MemoryDevicesVerification(
+                    MemoryDevicesVerification::default()
+                ))
+            );
+            assert!(vmm.verify_memory_devices_called)
+        });
+    }
+
+    #[test]
+    fn test_runtime_resume_memory_devices() {
+        let req = VmmAction::ResumeMemoryDevices(MemoryDevicesQuiesceToken { token: 1 });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.resume_memory_devices_called)
+        });
+
+        let req = VmmAction::ResumeMemoryDevices(MemoryDevicesQuiesceToken { token: 1 });
+        check_runtime_request_err(
+            req,
+            VmmActionError::MemoryDevices(MemoryDevicesError::NotQuiesced),
+        );
+    }
+
+    #[test]
+    fn test_runtime_create_snapshot() {
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            version: None,
+            sparse: false,
+        });
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Ok(VmmData::Empty));
+        });
+
+        // Only sparse snapshots describe the files written.
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            version: None,
+            sparse: true,
+        });
+        check_runtime_request(req, |result, _| {
+            assert_eq!(
+                result,
+                Ok(VmmData::SnapshotCreated(SnapshotCreateInfo::default()))
+            );
+        });
+
+        // Diff snapshots need dirty page tracking.
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Diff,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            version: None,
+            sparse: false,
+        });
+        check_runtime_request(req, |result, _| {
+            assert_eq!(
+                result,
+                Err(VmmActionError::NotSupported(String::new()))
+            );
+        });
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_runtime_ctrl_alt_del() {
+        let req = VmmAction::SendCtrlAltDel;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.send_ctrl_alt_del_called)
+        });
+
+        let req = VmmAction::SendCtrlAltDel;
+        check_runtime_request_err(
+            req,
+            VmmActionError::InternalVmm(VmmError::I8042Error(
+                crate::devices::legacy::I8042DeviceError::InternalBufferFull,
+            )),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "balloon")]
+    fn test_runtime_balloon_config() {
+        let req = VmmAction::GetBalloonConfig;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::BalloonConfig(BalloonDeviceConfig::default()))
+            );
+            assert!(vmm.balloon_config_called)
+        });
+
+        let req = VmmAction::GetBalloonConfig;
         check_runtime_request_err(
             req,
             VmmActionError::BalloonConfig(BalloonConfigError::DeviceNotFound),
@@ -2031,14 +3400,70 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
+    fn test_runtime_latest_balloon_stats() {
+        let req = VmmAction::GetBalloonStats;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::BalloonStats(BalloonStats::default())));
+            assert!(vmm.latest_balloon_stats_called)
+        });
+
+        let req = VmmAction::GetBalloonStats;
+        check_runtime_request_err(
+            req,
+            VmmActionError::BalloonConfig(BalloonConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "balloon")]
+    fn test_runtime_balloon_config_space() {
+        let req = VmmAction::GetBalloonConfigSpace;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::BalloonConfigSpace(BalloonConfigSpace::default()))
+            );
+            assert!(vmm.balloon_config_space_called)
+        });
+
+        let req = VmmAction::GetBalloonConfigSpace;
+        check_runtime_request_err(
+            req,
+            VmmActionError::BalloonConfig(BalloonConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "balloon")]
     fn test_runtime_update_balloon_config() {
-        let req = VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 });
+        let req = VmmAction::UpdateBalloon(BalloonUpdateConfig {
+            amount_mib: 0,
+            if_match_epoch: None,
+        });
         check_runtime_request(req, |result, vmm| {
             assert_eq!(result, Ok(VmmData::Empty));
             assert!(vmm.update_balloon_config_called)
         });
 
-        let req = VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 });
+        let req = VmmAction::UpdateBalloon(BalloonUpdateConfig {
+            amount_mib: 256,
+            if_match_epoch: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::BalloonUpdateClamped(BalloonUpdateClamped::new(
+                    256, 128
+                )))
+            );
+            assert!(vmm.update_balloon_config_called)
+        });
+
+        let req = VmmAction::UpdateBalloon(BalloonUpdateConfig {
+            amount_mib: 0,
+            if_match_epoch: None,
+        });
         check_runtime_request_err(
             req,
             VmmActionError::BalloonConfig(BalloonConfigError::DeviceNotFound),
@@ -2046,9 +3471,11 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "balloon")]
     fn test_runtime_update_balloon_stats_config() {
         let req = VmmAction::UpdateBalloonStatistics(BalloonUpdateStatsConfig {
             stats_polling_interval_s: 0,
+            if_match_epoch: None,
         });
         check_runtime_request(req, |result, vmm| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -2057,6 +3484,7 @@ mod tests {
 
         let req = VmmAction::UpdateBalloonStatistics(BalloonUpdateStatsConfig {
             stats_polling_interval_s: 0,
+            if_match_epoch: None,
         });
         check_runtime_request_err(
             req,
@@ -2064,6 +3492,503 @@ mod tests {
         );
     }
 
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_faascale_mem_health() {
+        let req = VmmAction::GetFaascaleMemHealth;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemHealth(FaascaleMemHealth::default()))
+            );
+            assert!(vmm.faascale_mem_health_called)
+        });
+
+        let req = VmmAction::GetFaascaleMemHealth;
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_faascale_mem_footprint() {
+        let req = VmmAction::GetFaascaleMemFootprint;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemFootprint(
+                    FaascaleMemFootprint::default()
+                ))
+            );
+            assert!(vmm.faascale_mem_footprint_called)
+        });
+
+        let req = VmmAction::GetFaascaleMemFootprint;
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_faascale_mem_blocks() {
+        let req = VmmAction::GetFaascaleMemBlocks;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemBlocks(FaascaleMemBlocks::default()))
+            );
+            assert!(vmm.faascale_mem_blocks_called)
+        });
+
+        let req = VmmAction::GetFaascaleMemBlocks;
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_faascale_mem_heatmap() {
+        let req = VmmAction::GetFaascaleMemHeatmap;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemHeatmap(FaascaleMemHeatmap::default()))
+            );
+            assert!(vmm.faascale_mem_heatmap_called)
+        });
+
+        let req = VmmAction::GetFaascaleMemHeatmap;
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_faascale_mem_errors() {
+        let req = VmmAction::GetFaascaleMemErrors;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemErrors(FaascaleMemErrors::default()))
+            );
+            assert!(vmm.faascale_mem_errors_called)
+        });
+
+        let req = VmmAction::GetFaascaleMemErrors;
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_faascale_mem_metadata() {
+        // The descriptions do not depend on the device, before or after boot.
+        check_preboot_request(VmmAction::GetFaascaleMemMetadata, |result, _| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemMetadata(FaascaleMemMetadata::default()))
+            );
+        });
+        check_runtime_request(VmmAction::GetFaascaleMemMetadata, |result, _| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemMetadata(FaascaleMemMetadata::default()))
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_faascale_mem_estimate() {
+        let req = VmmAction::GetFaascaleMemEstimate(FaascaleMemEstimateConfig { target_mib: 512 });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemEstimate(FaascaleMemEstimate {
+                    target_mib: 512,
+                    ..Default::default()
+                }))
+            );
+            assert!(vmm.faascale_mem_estimate_called)
+        });
+
+        let req = VmmAction::GetFaascaleMemEstimate(FaascaleMemEstimateConfig { target_mib: 512 });
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_faascale_mem_config_space() {
+        let req = VmmAction::GetFaascaleMemConfigSpace;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemConfigSpace(
+                    FaascaleMemConfigSpace::default()
+                ))
+            );
+            assert!(vmm.faascale_mem_config_space_called)
+        });
+
+        let req = VmmAction::GetFaascaleMemConfigSpace;
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_update_faascale_mem_pin() {
+        let req = VmmAction::UpdateFaascaleMemPin(FaascaleMemPinConfig {
+            start_pfn: 0x6000,
+            num_pages: 256,
+            pinned: true,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_faascale_mem_pin_called)
+        });
+
+        let req = VmmAction::UpdateFaascaleMemPin(FaascaleMemPinConfig {
+            start_pfn: 0x6000,
+            num_pages: 256,
+            pinned: false,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_update_faascale_mem_mlock() {
+        let req = VmmAction::UpdateFaascaleMemMlock(FaascaleMemMlockConfig {
+            start_pfn: 0x6000,
+            num_pages: 16,
+            locked: true,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_faascale_mem_mlock_called)
+        });
+
+        let req = VmmAction::UpdateFaascaleMemMlock(FaascaleMemMlockConfig {
+            start_pfn: 0x6000,
+            num_pages: 16,
+            locked: false,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_update_faascale_mem_rate_limiter() {
+        let req = VmmAction::UpdateFaascaleMemRateLimiter(FaascaleMemRateLimiterConfig {
+            rate_limiter: Some(RateLimiterConfig {
+                bandwidth: None,
+                ops: Some(TokenBucketConfig {
+                    size: 1000,
+                    one_time_burst: None,
+                    refill_time: 1000,
+                }),
+            }),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_faascale_mem_rate_limiter_called)
+        });
+
+        let req = VmmAction::UpdateFaascaleMemRateLimiter(FaascaleMemRateLimiterConfig::default());
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_update_faascale_mem_fence() {
+        let req = VmmAction::UpdateFaascaleMemFence(FaascaleMemFenceConfig { fenced: true });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_faascale_mem_fence_called)
+        });
+
+        let req = VmmAction::UpdateFaascaleMemFence(FaascaleMemFenceConfig { fenced: false });
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_update_faascale_mem_population() {
+        let req = VmmAction::UpdateFaascaleMemPopulation(FaascaleMemPopulationConfig {
+            pre_alloc_mem: Some(true),
+            pre_tdp_fault: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_faascale_mem_population_called)
+        });
+
+        let req = VmmAction::UpdateFaascaleMemPopulation(FaascaleMemPopulationConfig::default());
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_depopulate_faascale_mem() {
+        let req = VmmAction::DepopulateFaascaleMem(FaascaleMemDepopulateConfig {
+            start_pfn: Some(0x6000),
+            num_pages: Some(256),
+            release_mib: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.depopulate_faascale_mem_called)
+        });
+
+        let req = VmmAction::DepopulateFaascaleMem(FaascaleMemDepopulateConfig {
+            release_mib: Some(64),
+            ..Default::default()
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_poll_faascale_mem_stats() {
+        let req = VmmAction::PollFaascaleMemStats(FaascaleMemPollStatsConfig {
+            timeout_ms: Some(10),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemStats(FaascaleMemStats::default()))
+            );
+            assert!(vmm.poll_faascale_mem_stats_called)
+        });
+
+        let req = VmmAction::PollFaascaleMemStats(FaascaleMemPollStatsConfig::default());
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_refresh_faascale_mem_stats() {
+        check_runtime_request(VmmAction::RefreshFaascaleMemStats, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.refresh_faascale_mem_stats_called)
+        });
+
+        check_runtime_request_err(
+            VmmAction::RefreshFaascaleMemStats,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_populate_faascale_mem() {
+        let populate_cfg = FaascaleMemPopulateConfig {
+            amount_mib: Some(64),
+            ..Default::default()
+        };
+        let req = VmmAction::PopulateFaascaleMem(populate_cfg.clone());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemWarmReport(
+                    FaascaleMemWarmReport::default()
+                ))
+            );
+            assert!(vmm.populate_faascale_mem_called)
+        });
+
+        let req = VmmAction::PopulateFaascaleMem(populate_cfg);
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_update_faascale_mem_config() {
+        let req = VmmAction::UpdateFaascaleMem(FaascaleMemUpdateConfig { target_mib: 256 });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_faascale_mem_config_called)
+        });
+
+        let req = VmmAction::UpdateFaascaleMem(FaascaleMemUpdateConfig { target_mib: 256 });
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_warm_template_faascale_mem() {
+        let populate_cfg = FaascaleMemPopulateConfig {
+            blocks: vec![FaascaleMemTemplateBlock {
+                start_pfn: 0x6000,
+                num_pages: 256,
+            }],
+            ..Default::default()
+        };
+        let req = VmmAction::PopulateFaascaleMem(populate_cfg.clone());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemWarmReport(FaascaleMemWarmReport {
+                    blocks: 1,
+                    ..Default::default()
+                }))
+            );
+            assert!(vmm.populate_faascale_mem_called)
+        });
+        let req = VmmAction::PopulateFaascaleMem(populate_cfg);
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+
+        let req = VmmAction::RehydrateFaascaleMem(FaascaleMemRehydrateConfig::default());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemWarmReport(
+                    FaascaleMemWarmReport::default()
+                ))
+            );
+            assert!(vmm.rehydrate_faascale_mem_called)
+        });
+        let req = VmmAction::RehydrateFaascaleMem(FaascaleMemRehydrateConfig::default());
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "faascale-mem")]
+    fn test_runtime_faascale_mem_budget() {
+        let req = VmmAction::UpdateFaascaleMemBudget(FaascaleMemBudgetConfig { budget_mib: 512 });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_faascale_mem_budget_called)
+        });
+
+        let req = VmmAction::UpdateFaascaleMemBudget(FaascaleMemBudgetConfig { budget_mib: 512 });
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+
+        let req = VmmAction::GetFaascaleMemBudget;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::FaascaleMemBudget(FaascaleMemBudget::default()))
+            );
+            assert!(vmm.faascale_mem_budget_called)
+        });
+
+        let req = VmmAction::GetFaascaleMemBudget;
+        check_runtime_request_err(
+            req,
+            VmmActionError::FaascaleMemConfig(FaascaleMemConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "balloon", feature = "faascale-mem"))]
+    fn test_runtime_conditional_memory_device_update() {
+        // The mocked devices are always at epoch 0.
+        let req = VmmAction::UpdateBalloon(BalloonUpdateConfig {
+            amount_mib: 0,
+            if_match_epoch: Some(0),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_balloon_config_called)
+        });
+
+        let req = VmmAction::UpdateBalloonStatistics(BalloonUpdateStatsConfig {
+            stats_polling_interval_s: 0,
+            if_match_epoch: Some(1),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert!(matches!(
+                result,
+                Err(VmmActionError::BalloonConfig(
+                    BalloonConfigError::ConfigEpochMismatch {
+                        expected: 1,
+                        current: 0
+                    }
+                ))
+            ));
+            assert!(!vmm.update_balloon_stats_config_called)
+        });
+
+        let req = VmmAction::UpdateFaascaleMemStatistics(FaascaleMemUpdateStatsConfig {
+            stats_polling_interval_s: 0,
+            if_match_epoch: Some(0),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_faascale_mem_stats_config_called)
+        });
+
+        let req = VmmAction::UpdateFaascaleMemStatistics(FaascaleMemUpdateStatsConfig {
+            stats_polling_interval_s: 0,
+            if_match_epoch: Some(3),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert!(matches!(
+                result,
+                Err(VmmActionError::FaascaleMemConfig(
+                    FaascaleMemConfigError::ConfigEpochMismatch {
+                        expected: 3,
+                        current: 0
+                    }
+                ))
+            ));
+            assert!(!vmm.update_faascale_mem_stats_config_called)
+        });
+    }
+
     #[test]
     fn test_runtime_update_block_device_path() {
         let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
@@ -2130,6 +4055,7 @@ mod tests {
         check_runtime_request_err(
             VmmAction::ConfigureMetrics(MetricsConfig {
                 metrics_path: PathBuf::new(),
+                statsd: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2164,6 +4090,7 @@ mod tests {
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
+        #[cfg(feature = "balloon")]
         check_runtime_request_err(
             VmmAction::SetBalloonDevice(BalloonDeviceConfig::default()),
             VmmActionError::OperationNotSupportedPostBoot,
@@ -2262,8 +4189,11 @@ mod tests {
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
-        let req = VmmAction::SetBalloonDevice(BalloonDeviceConfig::default());
-        verify_load_snap_disallowed_after_boot_resources(req, "SetBalloonDevice");
+        #[cfg(feature = "balloon")]
+        {
+            let req = VmmAction::SetBalloonDevice(BalloonDeviceConfig::default());
+            verify_load_snap_disallowed_after_boot_resources(req, "SetBalloonDevice");
+        }
 
         let req = VmmAction::SetVsockDevice(VsockDeviceConfig {
             vsock_id: Some(String::new()),
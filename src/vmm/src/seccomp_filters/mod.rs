@@ -8,6 +8,12 @@ use std::sync::Arc;
 use seccompiler::{deserialize_binary, BpfThreadMap, DeserializationError, InstallationError};
 
 const THREAD_CATEGORIES: [&str; 3] = ["vmm", "api", "vcpu"];
+// Thread categories a filter file may leave out.
+const OPTIONAL_THREAD_CATEGORIES: [&str; 1] = [FAASCALE_MEM_VMM_CATEGORY];
+
+/// Filter of the VMM thread of a microVM with a faascale-mem device, or a slot to hot-plug one
+/// in. It also allows the TDP pre-fault ioctl the device issues from the VMM thread.
+pub const FAASCALE_MEM_VMM_CATEGORY: &str = "vmm_faascale_mem";
 
 // This byte limit is passed to `bincode` to guard against a potential memory
 // allocation DOS caused by binary filters that are too large.
@@ -107,6 +113,7 @@ fn get_empty_filters() -> BpfThreadMap {
     map.insert("vmm".to_string(), Arc::new(vec![]));
     map.insert("api".to_string(), Arc::new(vec![]));
     map.insert("vcpu".to_string(), Arc::new(vec![]));
+    map.insert(FAASCALE_MEM_VMM_CATEGORY.to_string(), Arc::new(vec![]));
     map
 }
 
@@ -119,9 +126,11 @@ fn get_custom_filters<R: Read>(reader: R) -> Result<BpfThreadMap, FilterError> {
 
 /// Return an error if the BpfThreadMap contains invalid thread categories.
 fn filter_thread_categories(map: BpfThreadMap) -> Result<BpfThreadMap, FilterError> {
-    let (filters, invalid_filters): (BpfThreadMap, BpfThreadMap) = map
-        .into_iter()
-        .partition(|(k, _)| THREAD_CATEGORIES.contains(&k.as_str()));
+    let (filters, invalid_filters): (BpfThreadMap, BpfThreadMap) =
+        map.into_iter().partition(|(k, _)| {
+            THREAD_CATEGORIES.contains(&k.as_str())
+                || OPTIONAL_THREAD_CATEGORIES.contains(&k.as_str())
+        });
     if !invalid_filters.is_empty() {
         // build the error message
         let mut thread_categories_string =
@@ -156,16 +165,18 @@ mod tests {
     #[test]
     fn test_get_filters() {
         let mut filters = get_filters(SeccompConfig::Advanced).unwrap();
-        assert_eq!(filters.len(), 3);
+        assert_eq!(filters.len(), 4);
         assert!(filters.remove("vmm").is_some());
         assert!(filters.remove("api").is_some());
         assert!(filters.remove("vcpu").is_some());
+        assert!(filters.remove(FAASCALE_MEM_VMM_CATEGORY).is_some());
 
         let mut filters = get_filters(SeccompConfig::None).unwrap();
-        assert_eq!(filters.len(), 3);
+        assert_eq!(filters.len(), 4);
         assert_eq!(filters.remove("vmm").unwrap().len(), 0);
         assert_eq!(filters.remove("api").unwrap().len(), 0);
         assert_eq!(filters.remove("vcpu").unwrap().len(), 0);
+        assert_eq!(filters.remove(FAASCALE_MEM_VMM_CATEGORY).unwrap().len(), 0);
 
         let file = TempFile::new().unwrap().into_file();
 
@@ -182,6 +193,15 @@ mod tests {
 
         assert_eq!(filter_thread_categories(map).unwrap().len(), 3);
 
+        // optional category
+        let mut map = BpfThreadMap::new();
+        map.insert("vcpu".to_string(), Arc::new(vec![]));
+        map.insert("vmm".to_string(), Arc::new(vec![]));
+        map.insert("api".to_string(), Arc::new(vec![]));
+        map.insert(FAASCALE_MEM_VMM_CATEGORY.to_string(), Arc::new(vec![]));
+
+        assert_eq!(filter_thread_categories(map).unwrap().len(), 4);
+
         // invalid categories
         let mut map = BpfThreadMap::new();
         map.insert("vcpu".to_string(), Arc::new(vec![]));
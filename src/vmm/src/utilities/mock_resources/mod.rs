@@ -7,6 +7,8 @@ use std::path::PathBuf;
 use crate::cpu_config::templates::CustomCpuTemplate;
 use crate::resources::VmResources;
 use crate::vmm_config::boot_source::BootSourceConfig;
+#[cfg(feature = "faascale-mem")]
+use crate::vmm_config::faascale_mem::FaascaleMemDeviceConfig;
 use crate::vmm_config::machine_config::{MachineConfig, MachineConfigUpdate};
 
 pub const DEFAULT_BOOT_ARGS: &str = "reboot=k panic=1 pci=off";
@@ -84,6 +86,12 @@ impl MockVmResources {
         self
     }
 
+    #[cfg(feature = "faascale-mem")]
+    pub fn with_faascale_mem(mut self, faascale_mem_cfg: FaascaleMemDeviceConfig) -> Self {
+        self.0.set_faascale_mem_device(faascale_mem_cfg).unwrap();
+        self
+    }
+
     pub fn set_cpu_template(&mut self, cpu_template: CustomCpuTemplate) {
         self.0.vm_config.set_custom_cpu_template(cpu_template);
     }
@@ -9,6 +9,8 @@ use crate::resources::VmResources;
 use crate::seccomp_filters::{get_filters, SeccompConfig};
 use crate::utilities::mock_resources::{MockBootSourceConfig, MockVmConfig, MockVmResources};
 use crate::vmm_config::boot_source::BootSourceConfig;
+#[cfg(feature = "faascale-mem")]
+use crate::vmm_config::faascale_mem::FaascaleMemDeviceConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::{EventManager, Vmm};
 
@@ -17,9 +19,6 @@ pub fn create_vmm(
     is_diff: bool,
     boot_microvm: bool,
 ) -> (Arc<Mutex<Vmm>>, EventManager) {
-    let mut event_manager = EventManager::new().unwrap();
-    let empty_seccomp_filters = get_filters(SeccompConfig::None).unwrap();
-
     let boot_source_cfg = MockBootSourceConfig::new().with_default_boot_args();
     #[cfg(target_arch = "aarch64")]
     let boot_source_cfg: BootSourceConfig = boot_source_cfg.into();
@@ -37,9 +36,16 @@ pub fn create_vmm(
         mock_vm_res.into()
     };
 
+    build_vmm(&resources, boot_microvm)
+}
+
+fn build_vmm(resources: &VmResources, boot_microvm: bool) -> (Arc<Mutex<Vmm>>, EventManager) {
+    let mut event_manager = EventManager::new().unwrap();
+    let empty_seccomp_filters = get_filters(SeccompConfig::None).unwrap();
+
     let vmm = build_microvm_for_boot(
         &InstanceInfo::default(),
-        &resources,
+        resources,
         &mut event_manager,
         &empty_seccomp_filters,
     )
@@ -60,6 +66,20 @@ pub fn default_vmm_no_boot(kernel_image: Option<&str>) -> (Arc<Mutex<Vmm>>, Even
     create_vmm(kernel_image, false, false)
 }
 
+/// Builds a microVM with a faascale-mem device attached, without starting the vCPUs.
+#[cfg(feature = "faascale-mem")]
+pub fn faascale_mem_vmm(
+    faascale_mem_cfg: FaascaleMemDeviceConfig,
+) -> (Arc<Mutex<Vmm>>, EventManager) {
+    let boot_source_cfg = MockBootSourceConfig::new().with_default_boot_args();
+    let resources: VmResources = MockVmResources::new()
+        .with_boot_source(boot_source_cfg.into())
+        .with_faascale_mem(faascale_mem_cfg)
+        .into();
+
+    build_vmm(&resources, false)
+}
+
 #[cfg(target_arch = "x86_64")]
 pub fn dirty_tracking_vmm(kernel_image: Option<&str>) -> (Arc<Mutex<Vmm>>, EventManager) {
     create_vmm(kernel_image, true, true)
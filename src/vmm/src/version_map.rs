@@ -9,7 +9,9 @@ use lazy_static::lazy_static;
 use versionize::{VersionMap, Versionize};
 
 use crate::device_manager::persist::DeviceStates;
+use crate::devices::virtio::balloon::persist::BalloonState;
 use crate::devices::virtio::block::persist::BlockState;
+use crate::devices::virtio::faascale_mem::persist::FaascaleMemState;
 use crate::devices::virtio::net::persist::NetConfigSpaceState;
 use crate::devices::virtio::QueueState;
 use crate::persist::VmInfo;
@@ -72,10 +74,11 @@ lazy_static! {
         // v1.4 state change mappings.
         version_map.new_version().set_type_version(DeviceStates::type_id(), 4);
 
-        // v1.5 - no changes introduced, but we need to bump as mapping
-        // between firecracker minor versions and snapshot versions needs
-        // to be 1-to-1 (see below)
-        version_map.new_version();
+        // v1.5 state change mappings.
+        version_map.new_version().set_type_version(FaascaleMemState::type_id(), 2);
+        version_map.set_type_version(DeviceStates::type_id(), 5);
+        version_map.set_type_version(BalloonState::type_id(), 2);
+        version_map.set_type_version(VmInfo::type_id(), 3);
 
         version_map
     };
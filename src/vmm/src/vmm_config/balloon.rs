@@ -6,7 +6,9 @@ use std::sync::{Arc, Mutex};
 
 use serde::{Deserialize, Serialize};
 
-pub use crate::devices::virtio::balloon::device::BalloonStats;
+pub use crate::devices::virtio::balloon::device::{
+    BalloonConfigSpace, BalloonDeflatePrefetch, BalloonStats,
+};
 pub use crate::devices::virtio::BALLOON_DEV_ID;
 use crate::devices::virtio::{Balloon, BalloonConfig};
 
@@ -23,6 +25,10 @@ pub enum BalloonConfigError {
     InvalidStatsUpdate,
     /// Amount of pages requested is too large.
     TooManyPagesRequested,
+    /// The guest memory floor does not leave room for the balloon target size.
+    GuestFloorTooLarge,
+    /// The guest memory is backed by huge pages, which the balloon cannot release.
+    HugePages,
     /// The user polled the statistics of a balloon device that
     /// does not have the statistics enabled.
     StatsNotFound,
@@ -30,6 +36,15 @@ pub enum BalloonConfigError {
     CreateFailure(crate::devices::virtio::balloon::Error),
     /// Failed to update the configuration of the ballon device.
     UpdateFailure(std::io::Error),
+    /// The configuration epoch given in a conditional update is stale.
+    ConfigEpochMismatch {
+        /// Epoch the caller expected the device to be at.
+        expected: u64,
+        /// Current epoch of the device.
+        current: u64,
+    },
+    /// The user tried to set the configuration epoch, which only the device updates.
+    ReadOnlyConfigEpoch,
 }
 
 impl fmt::Display for BalloonConfigError {
@@ -43,6 +58,14 @@ impl fmt::Display for BalloonConfigError {
             ),
             InvalidStatsUpdate => write!(f, "Cannot enable/disable the statistics after boot."),
             TooManyPagesRequested => write!(f, "Amount of pages requested is too large."),
+            GuestFloorTooLarge => write!(
+                f,
+                "The guest memory floor and the balloon target size exceed the guest memory."
+            ),
+            HugePages => write!(
+                f,
+                "The balloon device cannot release guest memory backed by huge pages."
+            ),
             StatsNotFound => write!(f, "Statistics for the balloon device are not enabled"),
             CreateFailure(err) => write!(f, "Error creating the balloon device: {:?}", err),
             UpdateFailure(err) => write!(
@@ -50,6 +73,12 @@ impl fmt::Display for BalloonConfigError {
                 "Error updating the balloon device configuration: {:?}",
                 err
             ),
+            ConfigEpochMismatch { expected, current } => write!(
+                f,
+                "The balloon configuration epoch is {}, but the update expected {}.",
+                current, expected
+            ),
+            ReadOnlyConfigEpoch => write!(f, "The balloon configuration epoch cannot be set."),
         }
     }
 }
@@ -68,6 +97,24 @@ pub struct BalloonDeviceConfig {
     /// Interval in seconds between refreshing statistics.
     #[serde(default)]
     pub stats_polling_interval_s: u16,
+    /// Prefetch policy for the memory returned to the guest on deflate.
+    #[serde(default)]
+    pub deflate_prefetch: BalloonDeflatePrefetch,
+    /// Reject a statistics descriptor holding a tag unknown to the device, instead of skipping
+    /// the tag. Meant for developing guest drivers.
+    #[serde(default)]
+    pub strict_stats: bool,
+    /// Hold the deflate notifications back while the guest streams its requests, for the next
+    /// ones to share.
+    #[serde(default)]
+    pub interrupt_moderation: bool,
+    /// Guest memory in MiB the balloon never reclaims. Larger runtime target sizes are clamped.
+    #[serde(default)]
+    pub min_guest_mib: u32,
+    /// Number of successful updates that changed the device configuration.
+    /// Reported by the API and rejected when configuring the device.
+    #[serde(default)]
+    pub config_epoch: u64,
 }
 
 impl From<BalloonConfig> for BalloonDeviceConfig {
@@ -76,6 +123,11 @@ impl From<BalloonConfig> for BalloonDeviceConfig {
             amount_mib: state.amount_mib,
             deflate_on_oom: state.deflate_on_oom,
             stats_polling_interval_s: state.stats_polling_interval_s,
+            deflate_prefetch: state.deflate_prefetch,
+            strict_stats: state.strict_stats,
+            interrupt_moderation: state.interrupt_moderation,
+            min_guest_mib: state.min_guest_mib,
+            config_epoch: state.config_epoch,
         }
     }
 }
@@ -87,6 +139,35 @@ impl From<BalloonConfig> for BalloonDeviceConfig {
 pub struct BalloonUpdateConfig {
     /// Target balloon size in MiB.
     pub amount_mib: u32,
+    /// Only apply the update if the device is at this configuration epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_match_epoch: Option<u64>,
+}
+
+/// Outcome of a balloon update whose target size was clamped to keep the guest memory floor.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BalloonUpdateClamped {
+    /// Target balloon size in MiB requested by the update.
+    pub requested_mib: u32,
+    /// Target balloon size in MiB applied to the device.
+    pub amount_mib: u32,
+    /// Why the requested size was not applied.
+    pub warning: String,
+}
+
+impl BalloonUpdateClamped {
+    /// Reports a `requested_mib` target size clamped to `amount_mib`.
+    pub fn new(requested_mib: u32, amount_mib: u32) -> Self {
+        BalloonUpdateClamped {
+            requested_mib,
+            amount_mib,
+            warning: format!(
+                "The balloon target size was clamped from {} MiB to {} MiB to keep the guest \
+                 memory floor.",
+                requested_mib, amount_mib
+            ),
+        }
+    }
 }
 
 /// The data fed into a balloon statistics interval update request.
@@ -98,6 +179,9 @@ pub struct BalloonUpdateConfig {
 pub struct BalloonUpdateStatsConfig {
     /// Interval in seconds between refreshing statistics.
     pub stats_polling_interval_s: u16,
+    /// Only apply the update if the device is at this configuration epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_match_epoch: Option<u64>,
 }
 
 /// A builder for `Balloon` devices from 'BalloonDeviceConfig'.
@@ -115,14 +199,22 @@ impl BalloonBuilder {
     /// Inserts a Balloon device in the store.
     /// If an entry already exists, it will overwrite it.
     pub fn set(&mut self, cfg: BalloonDeviceConfig) -> Result<()> {
-        self.inner = Some(Arc::new(Mutex::new(Balloon::new(
+        if cfg.config_epoch != 0 {
+            return Err(BalloonConfigError::ReadOnlyConfigEpoch);
+        }
+        let mut balloon = Balloon::new(
             cfg.amount_mib,
             cfg.deflate_on_oom,
             cfg.stats_polling_interval_s,
             // `restored` flag is false because this code path
             // is never called by snapshot restore functionality.
             false,
-        )?)));
+            cfg.deflate_prefetch,
+            cfg.strict_stats,
+            cfg.min_guest_mib,
+        )?;
+        balloon.set_interrupt_moderation(cfg.interrupt_moderation);
+        self.inner = Some(Arc::new(Mutex::new(balloon)));
 
         Ok(())
     }
@@ -155,6 +247,11 @@ pub(crate) mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            deflate_prefetch: BalloonDeflatePrefetch::None,
+            strict_stats: false,
+            interrupt_moderation: false,
+            min_guest_mib: 0,
+            config_epoch: 0,
         }
     }
 
@@ -173,19 +270,42 @@ pub(crate) mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            deflate_prefetch: BalloonDeflatePrefetch::None,
+            strict_stats: false,
+            interrupt_moderation: false,
+            min_guest_mib: 0,
+            config_epoch: 0,
         };
         assert_eq!(default_balloon_config, balloon_config);
         let mut builder = BalloonBuilder::new();
         assert!(builder.get().is_none());
 
+        // Only the device updates the configuration epoch.
+        assert!(matches!(
+            builder.set(BalloonDeviceConfig {
+                config_epoch: 1,
+                ..balloon_config.clone()
+            }),
+            Err(BalloonConfigError::ReadOnlyConfigEpoch)
+        ));
+        assert!(builder.get().is_none());
+
         builder.set(balloon_config).unwrap();
         assert_eq!(builder.get().unwrap().lock().unwrap().num_pages(), 0);
         assert_eq!(builder.get_config().unwrap(), default_balloon_config);
 
-        let _update_config = BalloonUpdateConfig { amount_mib: 5 };
+        let _update_config = BalloonUpdateConfig {
+            amount_mib: 5,
+            if_match_epoch: None,
+        };
         let _stats_update_config = BalloonUpdateStatsConfig {
             stats_polling_interval_s: 5,
+            if_match_epoch: Some(1),
         };
+
+        let clamped = BalloonUpdateClamped::new(200, 128);
+        assert_eq!(clamped.requested_mib, 200);
+        assert_eq!(clamped.amount_mib, 128);
     }
 
     #[test]
@@ -194,12 +314,22 @@ pub(crate) mod tests {
             amount_mib: 5,
             deflate_on_oom: false,
             stats_polling_interval_s: 3,
+            deflate_prefetch: BalloonDeflatePrefetch::None,
+            strict_stats: false,
+            interrupt_moderation: false,
+            min_guest_mib: 0,
+            config_epoch: 0,
         };
 
         let actual_balloon_config = BalloonDeviceConfig::from(BalloonConfig {
             amount_mib: 5,
             deflate_on_oom: false,
             stats_polling_interval_s: 3,
+            deflate_prefetch: BalloonDeflatePrefetch::None,
+            strict_stats: false,
+            interrupt_moderation: false,
+            min_guest_mib: 0,
+            config_epoch: 0,
         });
 
         assert_eq!(expected_balloon_config, actual_balloon_config);
@@ -227,14 +357,27 @@ pub(crate) mod tests {
         let err = TooManyPagesRequested;
         let _ = format!("{}{:?}", err, err);
 
+        let err = GuestFloorTooLarge;
+        let _ = format!("{}{:?}", err, err);
+
         let err = StatsNotFound;
         let _ = format!("{}{:?}", err, err);
+
+        let err = ConfigEpochMismatch {
+            expected: 1,
+            current: 2,
+        };
+        let _ = format!("{}{:?}", err, err);
+
+        let err = ReadOnlyConfigEpoch;
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
     fn test_set_device() {
         let mut builder = BalloonBuilder::new();
-        let balloon = Balloon::new(0, true, 0, true).unwrap();
+        let balloon =
+            Balloon::new(0, true, 0, true, BalloonDeflatePrefetch::None, false, 0).unwrap();
         builder.set_device(Arc::new(Mutex::new(balloon)));
         assert!(builder.inner.is_some());
     }
@@ -1,14 +1,58 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::fmt;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{cmp, fmt};
 
 use serde::{Deserialize, Serialize};
 
-pub use crate::devices::virtio::faascale_mem::device::FaascaleMemStats;
-pub use crate::devices::virtio::FAASCALE_MEM_DEV_ID;
+use super::RateLimiterConfig;
+pub use crate::devices::virtio::faascale_mem::budget::{BudgetNegotiationState, FaascaleMemBudget};
+pub use crate::devices::virtio::faascale_mem::capabilities::FaascaleMemCapabilities;
+pub use crate::devices::virtio::faascale_mem::device::{
+    FaascaleMemBlock, FaascaleMemBlocks, FaascaleMemConfigSpace, FaascaleMemDepopulateMode,
+    FaascaleMemFootprint, FaascaleMemHealth, FaascaleMemStats, FaascaleMemStatsFreshness,
+    FaascaleMemThpPolicy,
+};
+pub use crate::devices::virtio::faascale_mem::error_log::{
+    FaascaleMemErrorRecord, FaascaleMemErrors, FaascaleMemOperation,
+};
+pub use crate::devices::virtio::faascale_mem::estimate::{
+    FaascaleMemEstimate, FaascaleMemEstimateLimit,
+};
+pub use crate::devices::virtio::faascale_mem::experiment::{
+    FaascaleMemExperiment, FaascaleMemPopulatePolicy,
+};
+pub use crate::devices::virtio::faascale_mem::latency::{
+    FaascaleMemPopulateLatency, LatencyHistogram,
+};
+pub use crate::devices::virtio::faascale_mem::heatmap::{
+    FaascaleMemHeatmap, FaascaleMemHeatmapBucket,
+};
+pub use crate::devices::virtio::faascale_mem::interleave::FaascaleMemInterleaveConfig;
+pub use crate::devices::virtio::faascale_mem::lazy::FaascaleMemPopulateMode;
+pub use crate::devices::virtio::faascale_mem::metadata::{
+    FaascaleMemFieldCadence, FaascaleMemFieldMetadata, FaascaleMemFieldSource, FaascaleMemMetadata,
+};
+pub use crate::devices::virtio::faascale_mem::mlock::FaascaleMemMlockUsage;
+pub use crate::devices::virtio::faascale_mem::mmds_publish::FaascaleMemMmdsSummary;
+pub use crate::devices::virtio::faascale_mem::observer::FaascaleMemObservedActivity;
+pub use crate::devices::virtio::faascale_mem::policy::{
+    FaascaleMemPolicyConfig, FaascaleMemPolicyDecision,
+};
+pub use crate::devices::virtio::faascale_mem::polling::FaascaleMemPollingAdaptation;
+pub use crate::devices::virtio::faascale_mem::pool::{
+    FaascaleMemPoolConfig, FaascaleMemPoolKind, FaascaleMemPoolUsage,
+};
+pub use crate::devices::virtio::faascale_mem::scheduling::{
+    FaascaleMemIoniceClass, FaascaleMemWorkerScheduling, FaascaleMemWorkerSchedulingConfig,
+};
+pub use crate::devices::virtio::faascale_mem::template::FaascaleMemWarmReport;
+pub use crate::devices::virtio::faascale_mem::warmup::FaascaleMemBootWarmup;
 use crate::devices::virtio::{FaascaleMem, FaascaleMemConfig};
+pub use crate::devices::virtio::{FAASCALE_MEM_DEV_ID, POPULATE_TRACKER_MAX_ENTRIES};
 
 type MutexFaascaleMem = Arc<Mutex<FaascaleMem>>;
 
@@ -17,9 +61,22 @@ type MutexFaascaleMem = Arc<Mutex<FaascaleMem>>;
 pub enum FaascaleMemConfigError {
     /// The user made a request on an inexistent faascale-mem device.
     DeviceNotFound,
+    /// The user tried to hot-plug a faascale-mem device while one is already attached.
+    DeviceAlreadyAttached,
+    /// The user tried to hot-plug a faascale-mem device in latency mode.
+    HotplugLatencyMode,
+    /// The user tried to hot-plug a faascale-mem device in the lazy populate mode.
+    HotplugLazyPopulate,
+    /// The user tried to hot-plug a faascale-mem device sampling the performance counters.
+    HotplugPerfSampling,
+    /// The user tried to hot-plug a faascale-mem device with a host memory pool.
+    HotplugPool,
+    /// The user tried to hot-plug a faascale-mem device with a vsock observer port.
+    HotplugVsockObserver,
     /// Device not activated yet.
     DeviceNotActive,
-    /// The user tried to enable/disable the statistics after boot.
+    /// The user tried to enable/disable the statistics of a device restored without a
+    /// statistics queue.
     InvalidStatsUpdate,
     /// Amount of pages requested is too large.
     TooManyPagesRequested,
@@ -28,8 +85,23 @@ pub enum FaascaleMemConfigError {
     StatsNotFound,
     /// Failed to create a faascale-mem device.
     CreateFailure(crate::devices::virtio::faascale_mem::Error),
+    /// Failed to create the rate limiter of the populate and depopulate requests.
+    #[from(ignore)]
+    CreateRateLimiter(std::io::Error),
     /// Failed to update the configuration of the ballon device.
     UpdateFailure(std::io::Error),
+    /// The configuration epoch given in a conditional update is stale.
+    ConfigEpochMismatch {
+        /// Epoch the caller expected the device to be at.
+        expected: u64,
+        /// Current epoch of the device.
+        current: u64,
+    },
+    /// The user tried to set the configuration epoch, which only the device updates.
+    ReadOnlyConfigEpoch,
+    /// The guest memory is backed by huge pages, which the configuration would release in
+    /// smaller pieces.
+    HugePages,
 }
 
 impl fmt::Display for FaascaleMemConfigError {
@@ -37,19 +109,60 @@ impl fmt::Display for FaascaleMemConfigError {
         use self::FaascaleMemConfigError::*;
         match self {
             DeviceNotFound => write!(f, "No faascale-mem device found."),
+            DeviceAlreadyAttached => write!(f, "A faascale-mem device is already attached."),
+            HotplugLatencyMode => write!(
+                f,
+                "The latency mode is only supported for faascale-mem devices attached at boot."
+            ),
+            HotplugLazyPopulate => write!(
+                f,
+                "The lazy populate mode is only supported for faascale-mem devices attached at \
+                 boot."
+            ),
+            HotplugPerfSampling => write!(
+                f,
+                "The performance counters can only be sampled by faascale-mem devices attached at \
+                 boot."
+            ),
+            HotplugPool => write!(
+                f,
+                "The host memory pool is only supported for faascale-mem devices attached at boot."
+            ),
+            HotplugVsockObserver => write!(
+                f,
+                "The vsock observer is only supported for faascale-mem devices attached at boot."
+            ),
             DeviceNotActive => write!(
                 f,
                 "Device is inactive, check if faascale driver is enabled in guest kernel."
             ),
-            InvalidStatsUpdate => write!(f, "Cannot enable/disable the statistics after boot."),
+            InvalidStatsUpdate => write!(
+                f,
+                "Cannot enable/disable the statistics of a device restored without a statistics \
+                 queue."
+            ),
             TooManyPagesRequested => write!(f, "Amount of pages requested is too large."),
             StatsNotFound => write!(f, "Statistics for the faascale-mem device are not enabled"),
             CreateFailure(err) => write!(f, "Error creating the faascale-mem device: {:?}", err),
+            CreateRateLimiter(err) => {
+                write!(f, "Error creating the faascale-mem rate limiter: {:?}", err)
+            }
             UpdateFailure(err) => write!(
                 f,
                 "Error updating the faascale-mem device configuration: {:?}",
                 err
             ),
+            ConfigEpochMismatch { expected, current } => write!(
+                f,
+                "The faascale-mem configuration epoch is {}, but the update expected {}.",
+                current, expected
+            ),
+            ReadOnlyConfigEpoch => write!(f, "The faascale-mem configuration epoch cannot be set."),
+            HugePages => write!(
+                f,
+                "The host memory pool, the THP policies and the lazy depopulate modes cannot be \
+                 used with guest memory backed by huge pages."
+            ),
         }
     }
 }
@@ -70,6 +183,251 @@ pub struct FaascaleMemDeviceConfig {
     /// If need to pre handle tdp fault for faascale blocks
     #[serde(default)]
     pub pre_tdp_fault: bool,
+    /// Transparent huge page advice given to every populated block.
+    #[serde(default)]
+    pub thp_policy: FaascaleMemThpPolicy,
+    /// Upper bound on the number of ranges remembered to deduplicate populate requests,
+    /// 0 disables the deduplication. Defaults to `POPULATE_TRACKER_MAX_ENTRIES`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub populate_tracker_max_entries: Option<u32>,
+    /// Handle the populate queue on a dedicated thread instead of the VMM event loop.
+    #[serde(default)]
+    pub latency_mode: bool,
+    /// Log the dTLB misses of the vCPU threads and the TDP mappings added by the TDP pre-fault of
+    /// each populated block. Only used with `pre_tdp_fault`.
+    #[serde(default)]
+    pub perf_sampling: bool,
+    /// Memory budget in MiB first offered to the guest. Enables the budget negotiation, the
+    /// budget can then be offered again after boot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_mib: Option<u32>,
+    /// Split the populate blocks between two population policies, overriding
+    /// `pre_alloc_mem` and `pre_tdp_fault`. The outcome of each variant is reported in the
+    /// metrics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<FaascaleMemExperiment>,
+    /// Host memory reserved when the device is created, which backs the populated blocks
+    /// first, in chunks of 2 MiB. Blocks fall back to the normal allocation once it is
+    /// exhausted. Only guest memory of anonymous private 4K pages can be backed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<FaascaleMemPoolConfig>,
+    /// Reject a statistics descriptor holding a tag unknown to the device, instead of skipping
+    /// the tag. Meant for developing guest drivers.
+    #[serde(default)]
+    pub strict_stats: bool,
+    /// Shortest interval in milliseconds the statistics are polled at. Enables the adaptation
+    /// of the polling interval: it is shortened down to this value while the available memory
+    /// of the guest drops quickly, and goes back to `stats_polling_interval_s` once stable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats_polling_min_interval_ms: Option<u32>,
+    /// Most memory in MiB the guest may have populated at once, enforced by the host whatever
+    /// budget the guest agreed on. Populate requests going over it are refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_populated_mib: Option<u32>,
+    /// Size in MiB of the cache holding the compressed contents of the blocks depopulated by
+    /// the guest. A block populated again during the same guest driver session gets its
+    /// contents back instead of zero-filled pages. Encrypted guests are never cached.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_cache_mib: Option<u32>,
+    /// Interleave the blocks of at least `min_block_mib` across the given host NUMA nodes when
+    /// pre-allocating them, for heaps too large for the node of the VMM. Only applies with
+    /// `pre_alloc_mem`, the pages faulted in later by the guest are placed as usual.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interleave: Option<FaascaleMemInterleaveConfig>,
+    /// Host NUMA node, below 64, to place the populated blocks on, typically the one the vCPUs
+    /// are pinned to. The node is preferred, the memory still comes from the others once it
+    /// runs out. The large blocks configured to be interleaved are interleaved instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub numa_node: Option<u32>,
+    /// Advice releasing the host memory of the blocks the guest depopulates. The lazier modes
+    /// leave the old contents in place for cheaper repopulations, or reclaim the memory to swap.
+    #[serde(default)]
+    pub depopulate_mode: FaascaleMemDepopulateMode,
+    /// Most memory in MiB that may be locked into host memory at once, keeping latency-critical
+    /// blocks from being swapped out. Enables the locking of the populated blocks the guest
+    /// flags, or the host names through the API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mlock_budget_mib: Option<u32>,
+    /// Limits on the populate and depopulate requests of the guest: the `ops` bucket counts
+    /// blocks and the `bandwidth` bucket the bytes of guest memory they span. The throttled
+    /// requests wait in their queue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limiter: Option<RateLimiterConfig>,
+    /// Publish the memory populated by the guest, its limits and its memory pressure under the
+    /// `faascale_mem` key of the MMDS data store whenever they change, for guest agents to read
+    /// their memory budget. Needs MMDS to be configured.
+    #[serde(default)]
+    pub mmds_publish: bool,
+    /// Path of the file the contents of the blocks depopulated by the guest are written to,
+    /// truncated when the device is created. A block populated again during the same guest
+    /// driver session gets its contents back instead of zero-filled pages, however long ago it
+    /// was depopulated. Excludes `block_cache_mib`, and encrypted guests are never spilled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spill_path: Option<PathBuf>,
+    /// Zero the blocks explicitly once populated, for compliance regimes requiring it even
+    /// though anonymous memory is handed out zeroed. Encrypted guests are left out.
+    #[serde(default)]
+    pub scrub_on_populate: bool,
+    /// Return the populate, depopulate and control requests the device failed to complete to
+    /// the guest, with an error status when negotiated, instead of only counting them. Left
+    /// alone, the leaked requests slowly exhaust their queue.
+    #[serde(default)]
+    pub complete_leaked_descriptors: bool,
+    /// Hold the depopulate notifications back while the guest streams its requests, for the
+    /// next ones to share.
+    #[serde(default)]
+    pub interrupt_moderation: bool,
+    /// Program run on every statistics update, deciding the cap on the populated memory, the
+    /// memory the guest is asked to release and the budget offered to it. Failed runs and
+    /// decisions are logged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<FaascaleMemPolicyConfig>,
+    /// Debugging aid writing a canary at the head of the populated blocks and reading it back,
+    /// with the outcome reported by the statistics. The canary is left for the guest to check,
+    /// so this alters the guest memory and must stay off in production.
+    #[serde(default)]
+    pub populate_verification: bool,
+    /// Advise the populated blocks mergeable, for KSM to deduplicate the pages the guest shares
+    /// with co-located microVMs, such as those of a common function runtime. The blocks are
+    /// advised unmergeable once depopulated. KSM must be running on the host. Cannot be used
+    /// along `ksm_idle`.
+    #[serde(default)]
+    pub ksm_mergeable: bool,
+    /// Advise the guest memory mergeable, but for the populated blocks, which are advised
+    /// unmergeable for the pages KSM merged to be copied back. The blocks are advised mergeable
+    /// again once depopulated, which lets KSM deduplicate the idle memory of similar microVMs.
+    /// KSM must be running on the host.
+    #[serde(default)]
+    pub ksm_idle: bool,
+    /// Nice value, I/O priority and cgroup of the populate poller, to keep its memory churn
+    /// from slowing the vCPUs down. Only applies in `latency_mode`, the requests are otherwise
+    /// handled on the VMM thread.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_scheduling: Option<FaascaleMemWorkerSchedulingConfig>,
+    /// Host vsock port answering the guest connections with the memory activity of the microVM,
+    /// as a line of JSON, for in-guest runtimes to adapt their allocations to the host-side
+    /// costs. Needs vsock to be configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vsock_observer_port: Option<u32>,
+    /// Path of the memory file of a template microVM, laid out as the snapshot memory files.
+    /// The blocks populated by the guest get the contents the template has at their range,
+    /// instead of zero-filled pages, for warm language runtimes to start with their state in
+    /// place. Encrypted guests are never filled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template_path: Option<PathBuf>,
+    /// How the blocks populated by the guest are backed. In the `Lazy` mode, the populate
+    /// requests are acknowledged right away and the pages are backed when the guest first
+    /// touches them, through userfaultfd. The population policy, the scrubbing and the
+    /// interleaving do not apply to these blocks, and hugetlb backed or encrypted guest memory
+    /// is still populated eagerly. Not supported on hot-plugged devices.
+    #[serde(default)]
+    pub populate_mode: FaascaleMemPopulateMode,
+    /// Depopulate the memory populated by the guest when its driver resets the device, e.g. on
+    /// a kexec or a driver reload, for guests whose next driver starts without any populated
+    /// memory. The blocks pinned through the API stay populated.
+    #[serde(default)]
+    pub depopulate_on_reset: bool,
+    /// Number of successful updates that changed the device configuration.
+    /// Reported by the API and rejected when configuring the device.
+    #[serde(default)]
+    pub config_epoch: u64,
+    /// Memory populated by the guest, in MiB, to compare with `max_populated_mib`.
+    /// Reported by the API and ignored when configuring the device.
+    #[serde(default)]
+    pub populated_mib: u64,
+    /// Memory the guest driver is asked to keep populated, in MiB.
+    /// Reported by the API and ignored when configuring the device.
+    #[serde(default)]
+    pub target_mib: u32,
+    /// Populate activity of the guest from boot until it first quiesced, once it did.
+    /// Reported by the API and ignored when configuring the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_warmup: Option<FaascaleMemBootWarmup>,
+    /// Polling interval in use, when it is being adapted to the memory pressure of the guest.
+    /// Reported by the API and ignored when configuring the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats_polling_adaptation: Option<FaascaleMemPollingAdaptation>,
+    /// Support of the host for the system calls behind `pre_alloc_mem` and `pre_tdp_fault`,
+    /// probed at boot. Reported by the API and ignored when configuring the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<FaascaleMemCapabilities>,
+    /// Decisions of the latest successful run of the policy program.
+    /// Reported by the API and ignored when configuring the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy_decision: Option<FaascaleMemPolicyDecision>,
+    /// Scheduling of the populate poller, as read back from the host once it applied
+    /// `worker_scheduling`. Reported by the API and ignored when configuring the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied_worker_scheduling: Option<FaascaleMemWorkerScheduling>,
+}
+
+impl FaascaleMemDeviceConfig {
+    /// Whether the device can serve guest memory backed by huge pages. The pool hands out 4K
+    /// pages, the THP policies hold back the 4K pieces of the depopulated huge pages, and the
+    /// lazy depopulate modes only apply to 4K pages.
+    pub fn supports_huge_pages(&self) -> bool {
+        self.pool.is_none()
+            && matches!(
+                self.thp_policy,
+                FaascaleMemThpPolicy::System | FaascaleMemThpPolicy::Never
+            )
+            && self.depopulate_mode == FaascaleMemDepopulateMode::DontNeed
+    }
+
+    /// A configuration with every field set, the optional ones included, so that the tests
+    /// walking the serialized fields see all of them. Every new field must be set here.
+    #[cfg(test)]
+    pub(crate) fn fully_populated() -> Self {
+        FaascaleMemDeviceConfig {
+            stats_polling_interval_s: 1,
+            pre_alloc_mem: true,
+            pre_tdp_fault: true,
+            thp_policy: FaascaleMemThpPolicy::default(),
+            populate_tracker_max_entries: Some(1),
+            latency_mode: true,
+            perf_sampling: true,
+            budget_mib: Some(1),
+            experiment: Some(FaascaleMemExperiment::default()),
+            pool: Some(FaascaleMemPoolConfig {
+                size_mib: 1,
+                kind: FaascaleMemPoolKind::default(),
+            }),
+            strict_stats: true,
+            stats_polling_min_interval_ms: Some(1),
+            max_populated_mib: Some(1),
+            block_cache_mib: Some(1),
+            interleave: Some(FaascaleMemInterleaveConfig {
+                min_block_mib: 1,
+                nodes: vec![0],
+            }),
+            numa_node: Some(0),
+            depopulate_mode: FaascaleMemDepopulateMode::default(),
+            mlock_budget_mib: Some(1),
+            rate_limiter: Some(RateLimiterConfig::default()),
+            mmds_publish: true,
+            spill_path: Some(PathBuf::from("spill")),
+            scrub_on_populate: true,
+            complete_leaked_descriptors: true,
+            interrupt_moderation: true,
+            policy: Some(FaascaleMemPolicyConfig::default()),
+            populate_verification: true,
+            ksm_mergeable: true,
+            ksm_idle: true,
+            worker_scheduling: Some(FaascaleMemWorkerSchedulingConfig::default()),
+            vsock_observer_port: Some(52),
+            template_path: Some(PathBuf::from("template")),
+            populate_mode: FaascaleMemPopulateMode::default(),
+            depopulate_on_reset: true,
+            config_epoch: 1,
+            populated_mib: 1,
+            target_mib: 1,
+            boot_warmup: Some(FaascaleMemBootWarmup::default()),
+            stats_polling_adaptation: Some(FaascaleMemPollingAdaptation::default()),
+            capabilities: Some(FaascaleMemCapabilities::default()),
+            policy_decision: Some(FaascaleMemPolicyDecision::default()),
+            applied_worker_scheduling: Some(FaascaleMemWorkerScheduling::default()),
+        }
+    }
 }
 
 impl From<FaascaleMemConfig> for FaascaleMemDeviceConfig {
@@ -78,47 +436,312 @@ impl From<FaascaleMemConfig> for FaascaleMemDeviceConfig {
             stats_polling_interval_s: state.stats_polling_interval_s,
             pre_alloc_mem: state.pre_alloc_mem,
             pre_tdp_fault: state.pre_tdp_fault,
+            thp_policy: state.thp_policy,
+            populate_tracker_max_entries: Some(state.populate_tracker_max_entries),
+            latency_mode: state.latency_mode,
+            perf_sampling: state.perf_sampling,
+            budget_mib: state.budget_mib,
+            experiment: state.experiment,
+            pool: state.pool,
+            strict_stats: state.strict_stats,
+            stats_polling_min_interval_ms: state.stats_polling_min_interval_ms,
+            max_populated_mib: state.max_populated_mib,
+            block_cache_mib: state.block_cache_mib,
+            interleave: state.interleave,
+            numa_node: state.numa_node,
+            depopulate_mode: state.depopulate_mode,
+            mlock_budget_mib: state.mlock_budget_mib,
+            rate_limiter: state.rate_limiter,
+            mmds_publish: state.mmds_publish,
+            spill_path: state.spill_path,
+            scrub_on_populate: state.scrub_on_populate,
+            complete_leaked_descriptors: state.complete_leaked_descriptors,
+            interrupt_moderation: state.interrupt_moderation,
+            policy: state.policy,
+            populate_verification: state.populate_verification,
+            ksm_mergeable: state.ksm_mergeable,
+            ksm_idle: state.ksm_idle,
+            worker_scheduling: state.worker_scheduling,
+            vsock_observer_port: state.vsock_observer_port,
+            template_path: state.template_path,
+            populate_mode: state.populate_mode,
+            depopulate_on_reset: state.depopulate_on_reset,
+            config_epoch: state.config_epoch,
+            populated_mib: state.populated_mib,
+            target_mib: state.target_mib,
+            boot_warmup: state.boot_warmup,
+            stats_polling_adaptation: state.stats_polling_adaptation,
+            capabilities: state.capabilities,
+            policy_decision: state.policy_decision,
+            applied_worker_scheduling: state.applied_worker_scheduling,
         }
     }
 }
 
 
 /// The data fed into a faascale-mem statistics interval update request.
-/// Note that the state of the statistics cannot be changed from ON to OFF
-/// or vice versa after boot, only the interval of polling can be changed
-/// if the statistics were activated in the device configuration.
+/// An interval of 0 disables the statistics, which can be enabled again
+/// after boot with a non-zero interval.
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct FaascaleMemUpdateStatsConfig {
     /// Interval in seconds between refreshing statistics.
     pub stats_polling_interval_s: u16,
+    /// Only apply the update if the device is at this configuration epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_match_epoch: Option<u64>,
+}
+
+/// Time the host waits by default for the guest to report fresh faascale-mem statistics, in
+/// milliseconds.
+pub const DEFAULT_STATS_POLL_TIMEOUT_MS: u32 = 100;
+/// Longest time the host waits for the guest to report fresh faascale-mem statistics, in
+/// milliseconds. The microVM keeps processing its other events meanwhile.
+pub const MAX_STATS_POLL_TIMEOUT_MS: u32 = 1000;
+
+/// The data fed into a request collecting fresh faascale-mem statistics right away, instead of
+/// at the next polling interval.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemPollStatsConfig {
+    /// Time to wait for the guest to report the statistics, in milliseconds. Defaults to
+    /// `DEFAULT_STATS_POLL_TIMEOUT_MS` and is capped at `MAX_STATS_POLL_TIMEOUT_MS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u32>,
+}
+
+impl FaascaleMemPollStatsConfig {
+    /// Time to wait for the guest to report the statistics.
+    pub fn timeout(&self) -> Duration {
+        let timeout_ms = self.timeout_ms.unwrap_or(DEFAULT_STATS_POLL_TIMEOUT_MS);
+        Duration::from_millis(u64::from(cmp::min(timeout_ms, MAX_STATS_POLL_TIMEOUT_MS)))
+    }
+}
+
+/// The data fed into a faascale-mem pin request. Depopulate requests from the guest
+/// overlapping a pinned range are refused until the range is unpinned.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemPinConfig {
+    /// First guest page frame of the range.
+    pub start_pfn: u32,
+    /// Number of pages in the range.
+    pub num_pages: u32,
+    /// Whether to pin or unpin the range.
+    pub pinned: bool,
+}
+
+/// The data fed into a faascale-mem lock request, locking a populated range of guest pages
+/// into host memory or unlocking it.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemMlockConfig {
+    /// First guest page frame of the range.
+    pub start_pfn: u32,
+    /// Number of pages in the range.
+    pub num_pages: u32,
+    /// Whether to lock or unlock the range.
+    pub locked: bool,
+}
+
+/// The data fed into a faascale-mem rate limiter update request. The buckets left out keep
+/// their limits.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemRateLimiterConfig {
+    /// New limits on the populate and depopulate requests of the guest.
+    pub rate_limiter: Option<RateLimiterConfig>,
+}
+
+/// The data fed into a faascale-mem fence request. A fenced device refuses the populate
+/// requests of the guest until it is unfenced.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemFenceConfig {
+    /// Whether to fence or unfence the device. The fence is advisory, a guest without the
+    /// STATUS feature is not told its populate requests are refused.
+    pub fenced: bool,
+}
+
+/// The data fed into a faascale-mem population policy update request. The flags left out
+/// keep their value, and the blocks already populated are left as they are.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemPopulationConfig {
+    /// Whether to pre-allocate the host memory of the populated blocks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_alloc_mem: Option<bool>,
+    /// Whether to pre-handle the TDP faults of the populated blocks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_tdp_fault: Option<bool>,
+}
+
+/// The data fed into a faascale-mem update request. The guest driver is asked to grow or
+/// shrink the memory it keeps populated to the target.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemUpdateConfig {
+    /// Memory the guest driver is asked to keep populated, in MiB.
+    pub target_mib: u32,
+}
+
+/// The data fed into a faascale-mem hot-plug slot request. A microVM booted without a
+/// faascale-mem device gets a slot the device can be hot-plugged in after boot.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemHotplugSlotConfig {
+    /// Whether to reserve the slot.
+    pub reserved: bool,
+}
+
+/// The data fed into a faascale-mem budget offer. The guest keeps the budget it agreed on
+/// before until it acknowledges the new offer.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemBudgetConfig {
+    /// Memory budget offered to the guest, in MiB.
+    pub budget_mib: u32,
+}
+
+/// The data fed into a faascale-mem capacity estimate, given by the query string of the
+/// request.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemEstimateConfig {
+    /// Memory the guest would hold populated after the scale-up, in MiB.
+    pub target_mib: u32,
+}
+
+/// The data fed into a faascale-mem depopulate request of the host. Either removes the range
+/// given by `start_pfn` and `num_pages` without involving the guest, discarding its content,
+/// or asks the guest to release `release_mib` of the memory it populated.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemDepopulateConfig {
+    /// First guest page frame of the range to remove.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_pfn: Option<u32>,
+    /// Number of pages in the range to remove.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_pages: Option<u32>,
+    /// Memory the guest is asked to release, in MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_mib: Option<u32>,
+}
+
+/// The data fed into a faascale-mem populate request of the host, warming up the microVM
+/// before an invocation arrives or the template of a warm pool before it is snapshotted.
+/// Populates either the range given by `start_pfn` and `num_pages`, the `blocks`, or
+/// `amount_mib` of the guest memory not populated yet.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemPopulateConfig {
+    /// First guest page frame of the range to populate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_pfn: Option<u32>,
+    /// Number of pages in the range to populate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_pages: Option<u32>,
+    /// Memory to populate, in MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_mib: Option<u32>,
+    /// Blocks to populate.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocks: Vec<FaascaleMemTemplateBlock>,
+}
+
+impl FaascaleMemPopulateConfig {
+    /// The blocks to populate, as `(start pfn, number of pages)` blocks.
+    pub fn blocks(&self) -> Vec<(u32, u32)> {
+        template_blocks(&self.blocks)
+    }
+}
+
+/// A guest range of a warm pool template, given by its first page frame and number of pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemTemplateBlock {
+    /// First guest page frame of the range.
+    pub start_pfn: u32,
+    /// Number of pages in the range.
+    pub num_pages: u32,
+}
+
+/// The data fed into a faascale-mem rehydrate request, faulting in the memory populated in the
+/// template of a clone restored from its snapshot before the clone is resumed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaascaleMemRehydrateConfig {
+    /// Blocks to fault in the populated memory of, all the populated memory if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocks: Vec<FaascaleMemTemplateBlock>,
+}
+
+impl FaascaleMemRehydrateConfig {
+    /// The blocks to fault in, as `(start pfn, number of pages)` blocks.
+    pub fn blocks(&self) -> Vec<(u32, u32)> {
+        template_blocks(&self.blocks)
+    }
+}
+
+fn template_blocks(blocks: &[FaascaleMemTemplateBlock]) -> Vec<(u32, u32)> {
+    blocks
+        .iter()
+        .map(|block| (block.start_pfn, block.num_pages))
+        .collect()
 }
 
 /// A builder for `MutexFaascale` devices from 'FaascaleMemDeviceConfig'.
 #[cfg_attr(not(test), derive(Default))]
 pub struct FaascaleMemBuilder {
     inner: Option<MutexFaascaleMem>,
+    hotplug_slot: bool,
 }
 
 impl FaascaleMemBuilder {
     /// Creates an empty MutexFaascale Store.
     pub fn new() -> Self {
-        Self { inner: None }
+        Self {
+            inner: None,
+            hotplug_slot: false,
+        }
     }
 
     /// Inserts a MutexFaascale device in the store.
     /// If an entry already exists, it will overwrite it.
     pub fn set(&mut self, cfg: FaascaleMemDeviceConfig) -> Result<()> {
-        self.inner = Some(Arc::new(Mutex::new(FaascaleMem::new(
-            cfg.stats_polling_interval_s,
+        self.inner = Some(Self::build(cfg)?);
+        Ok(())
+    }
+
+    /// Creates a MutexFaascale device without storing it.
+    pub fn build(cfg: FaascaleMemDeviceConfig) -> Result<MutexFaascaleMem> {
+        if cfg.config_epoch != 0 {
+            return Err(FaascaleMemConfigError::ReadOnlyConfigEpoch);
+        }
+        let rate_limiter = cfg
+            .rate_limiter
+            .map(RateLimiterConfig::try_into)
+            .transpose()
+            .map_err(FaascaleMemConfigError::CreateRateLimiter)?;
+        Ok(Arc::new(Mutex::new(FaascaleMem::new(
+            &cfg,
             // `restored` flag is false because this code path
             // is never called by snapshot restore functionality.
             false,
-            cfg.pre_alloc_mem,
-            cfg.pre_tdp_fault
-        )?)));
+            rate_limiter.unwrap_or_default(),
+        )?)))
+    }
 
-        Ok(())
+    /// Reserves, or not, the slot to hot-plug a device after boot. The slot is only reserved
+    /// when no device is attached at boot.
+    pub fn set_hotplug_slot(&mut self, cfg: FaascaleMemHotplugSlotConfig) {
+        self.hotplug_slot = cfg.reserved;
+    }
+
+    /// Whether the slot to hot-plug a device after boot is reserved.
+    pub fn hotplug_slot(&self) -> bool {
+        self.hotplug_slot
     }
 
     /// Inserts an existing faascale-mem device.
@@ -3,6 +3,8 @@
 use std::fmt;
 
 use serde::{de, Deserialize, Serialize};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
+use versionize_derive::Versionize;
 
 use crate::cpu_config::templates::{CpuTemplateType, CustomCpuTemplate, StaticCpuTemplate};
 
@@ -15,11 +17,20 @@ pub const MAX_SUPPORTED_VCPUS: u8 = 32;
 /// Errors associated with configuring the microVM.
 #[derive(Debug, thiserror::Error, PartialEq, Eq)]
 pub enum VmConfigError {
-    /// The memory size is smaller than the target size set in the balloon device configuration.
+    /// The memory size is smaller than the target size set in the balloon device configuration
+    /// and the guest memory floor.
     #[error(
-        "The memory size (MiB) is smaller than the previously set balloon device target size."
+        "The memory size (MiB) is smaller than the previously set balloon device target size and \
+         guest memory floor."
     )]
     IncompatibleBalloonSize,
+    /// The guest memory cannot be backed by huge pages, which the previously set balloon device
+    /// or faascale-mem device would release in smaller pieces.
+    #[error(
+        "The guest memory cannot be backed by huge pages with the previously set balloon device \
+         or faascale-mem device configuration."
+    )]
+    IncompatibleHugePages,
     /// The memory size is invalid. The memory can only be an unsigned integer.
     #[error("The memory size (MiB) is invalid.")]
     InvalidMemorySize,
@@ -39,6 +50,32 @@ pub enum VmConfigError {
     InvalidVmState,
 }
 
+/// Host pages backing the guest memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, Versionize)]
+pub enum HugePageConfig {
+    /// Regular pages, which the host may still back with transparent huge pages.
+    #[default]
+    None,
+    /// 2 MiB pages taken from the hugetlb pool of the host.
+    #[serde(rename = "2M")]
+    Hugetlbfs2M,
+}
+
+impl HugePageConfig {
+    /// Returns `true` if the guest memory is backed by regular pages.
+    pub fn is_none(&self) -> bool {
+        *self == HugePageConfig::None
+    }
+
+    /// Size in bytes of the hugetlb pages backing the guest memory, if any.
+    pub fn page_size(&self) -> Option<usize> {
+        match self {
+            HugePageConfig::None => None,
+            HugePageConfig::Hugetlbfs2M => Some(2 << 20),
+        }
+    }
+}
+
 /// Struct used in PUT `/machine-config` API call.
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -57,6 +94,9 @@ pub struct MachineConfig {
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(default)]
     pub track_dirty_pages: bool,
+    /// Host pages backing the guest memory.
+    #[serde(default, skip_serializing_if = "HugePageConfig::is_none")]
+    pub huge_pages: HugePageConfig,
 }
 
 impl Default for MachineConfig {
@@ -70,8 +110,13 @@ impl fmt::Display for MachineConfig {
         write!(
             f,
             "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?}, \"smt\": {:?}, \"cpu_template\": \
-             {:?}, \"track_dirty_pages\": {:?} }}",
-            self.vcpu_count, self.mem_size_mib, self.smt, self.cpu_template, self.track_dirty_pages
+             {:?}, \"track_dirty_pages\": {:?}, \"huge_pages\": {:?} }}",
+            self.vcpu_count,
+            self.mem_size_mib,
+            self.smt,
+            self.cpu_template,
+            self.track_dirty_pages,
+            self.huge_pages
         )
     }
 }
@@ -108,6 +153,9 @@ pub struct MachineConfigUpdate {
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub track_dirty_pages: Option<bool>,
+    /// Host pages backing the guest memory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub huge_pages: Option<HugePageConfig>,
 }
 
 impl MachineConfigUpdate {
@@ -120,6 +168,7 @@ impl MachineConfigUpdate {
             && self.cpu_template.is_none()
             && self.smt.is_none()
             && self.track_dirty_pages.is_none()
+            && self.huge_pages.is_none()
         {
             return true;
         }
@@ -136,6 +185,7 @@ impl From<MachineConfig> for MachineConfigUpdate {
             smt: Some(cfg.smt),
             cpu_template: Some(cfg.cpu_template),
             track_dirty_pages: Some(cfg.track_dirty_pages),
+            huge_pages: Some(cfg.huge_pages),
         }
     }
 }
@@ -153,6 +203,8 @@ pub struct VmConfig {
     pub cpu_template: Option<CpuTemplateType>,
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     pub track_dirty_pages: bool,
+    /// Host pages backing the guest memory.
+    pub huge_pages: HugePageConfig,
 }
 
 impl VmConfig {
@@ -189,7 +241,16 @@ impl VmConfig {
             return Err(VmConfigError::InvalidMemorySize);
         }
 
+        // The guest memory has to be made of whole huge pages.
+        let huge_pages = update.huge_pages.unwrap_or(self.huge_pages);
+        if let Some(page_size) = huge_pages.page_size() {
+            if (mem_size_mib << 20) % page_size != 0 {
+                return Err(VmConfigError::InvalidMemorySize);
+            }
+        }
+
         self.mem_size_mib = mem_size_mib;
+        self.huge_pages = huge_pages;
 
         if let Some(cpu_template) = update.cpu_template {
             self.cpu_template = match cpu_template {
@@ -214,6 +275,7 @@ impl Default for VmConfig {
             smt: false,
             cpu_template: None,
             track_dirty_pages: false,
+            huge_pages: HugePageConfig::None,
         }
     }
 }
@@ -226,6 +288,7 @@ impl From<&VmConfig> for MachineConfig {
             smt: value.smt,
             cpu_template: (&value.cpu_template).into(),
             track_dirty_pages: value.track_dirty_pages,
+            huge_pages: value.huge_pages,
         }
     }
 }
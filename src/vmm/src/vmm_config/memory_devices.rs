@@ -0,0 +1,217 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use utils::errno;
+
+#[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+use crate::devices::virtio::mem_overlay::MmapOverlays;
+
+/// Errors associated with the operations spanning the memory devices.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MemoryDevicesError {
+    /// The memory devices are already quiesced.
+    #[error("The memory devices are already quiesced.")]
+    AlreadyQuiesced,
+    /// The memory devices are not quiesced.
+    #[error("The memory devices are not quiesced.")]
+    NotQuiesced,
+    /// The token given to resume the memory devices does not match the quiesce request.
+    #[error("The quiesce token {0} does not match the current quiesce request.")]
+    TokenMismatch(u64),
+    /// The operation requires the microVM to be paused.
+    #[error("The microVM must be paused to consolidate the memory overlays.")]
+    VmNotPaused,
+    /// Mapping anonymous memory over a gap between two overlays failed.
+    #[error("Failed to consolidate the memory overlays: {0}")]
+    ConsolidateOverlays(errno::Error),
+}
+
+/// Anonymous mappings laid over the guest memory of a restored microVM by the memory devices.
+#[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryOverlaysInfo {
+    /// Number of disjoint overlays.
+    pub overlay_count: u64,
+    /// Total size of the overlays, in bytes.
+    pub overlay_bytes: u64,
+}
+
+#[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+impl From<&MmapOverlays> for MemoryOverlaysInfo {
+    fn from(overlays: &MmapOverlays) -> Self {
+        MemoryOverlaysInfo {
+            overlay_count: overlays.count() as u64,
+            overlay_bytes: overlays.bytes(),
+        }
+    }
+}
+
+/// A disagreement found by the cross-checks of the memory devices state.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MemoryDevicesInconsistency {
+    /// Device the check ran on, `balloon` or `faascale-mem`.
+    pub device: String,
+    /// Name of the failed check.
+    pub check: String,
+    /// What the check found.
+    pub detail: String,
+}
+
+/// Outcome of the cross-checks of the config space, range trackers and guest reported
+/// statistics of the memory devices.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MemoryDevicesVerification {
+    /// Whether no inconsistency was found.
+    pub consistent: bool,
+    /// The inconsistencies found.
+    pub inconsistencies: Vec<MemoryDevicesInconsistency>,
+}
+
+impl Default for MemoryDevicesVerification {
+    fn default() -> Self {
+        MemoryDevicesVerification {
+            consistent: true,
+            inconsistencies: Vec::new(),
+        }
+    }
+}
+
+impl MemoryDevicesVerification {
+    /// Records the failed checks of `device`, given as `(check, detail)` pairs.
+    pub fn add(&mut self, device: &str, failed: Vec<(&str, String)>) {
+        for (check, detail) in failed {
+            self.inconsistencies.push(MemoryDevicesInconsistency {
+                device: device.to_string(),
+                check: check.to_string(),
+                detail,
+            });
+        }
+        self.consistent = self.inconsistencies.is_empty();
+    }
+}
+
+/// Returned by a quiesce request of the memory devices, and given back to resume them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryDevicesQuiesceToken {
+    /// Identifies the quiesce request.
+    pub token: u64,
+}
+
+/// Tracks the quiesce requests of the memory devices.
+#[derive(Debug, Default)]
+pub struct MemoryDevicesQuiesce {
+    // Token of the pending quiesce request, if any.
+    current: Option<u64>,
+    // Number of quiesce requests made so far, used to hand out distinct tokens.
+    requests: u64,
+}
+
+impl MemoryDevicesQuiesce {
+    /// Starts a quiesce request and returns its token.
+    pub fn quiesce(&mut self) -> Result<MemoryDevicesQuiesceToken, MemoryDevicesError> {
+        if self.current.is_some() {
+            return Err(MemoryDevicesError::AlreadyQuiesced);
+        }
+        self.requests += 1;
+        self.current = Some(self.requests);
+        Ok(MemoryDevicesQuiesceToken {
+            token: self.requests,
+        })
+    }
+
+    /// Ends the quiesce request identified by `token`.
+    pub fn resume(&mut self, token: MemoryDevicesQuiesceToken) -> Result<(), MemoryDevicesError> {
+        match self.current {
+            None => Err(MemoryDevicesError::NotQuiesced),
+            Some(current) if current != token.token => {
+                Err(MemoryDevicesError::TokenMismatch(token.token))
+            }
+            Some(_) => {
+                self.current = None;
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the memory devices are quiesced.
+    pub fn is_quiesced(&self) -> bool {
+        self.current.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    use utils::vm_memory::GuestAddress;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_devices_quiesce() {
+        let mut quiesce = MemoryDevicesQuiesce::default();
+        assert!(!quiesce.is_quiesced());
+        assert_eq!(
+            quiesce.resume(MemoryDevicesQuiesceToken { token: 1 }),
+            Err(MemoryDevicesError::NotQuiesced)
+        );
+
+        let token = quiesce.quiesce().unwrap();
+        assert!(quiesce.is_quiesced());
+        assert_eq!(quiesce.quiesce(), Err(MemoryDevicesError::AlreadyQuiesced));
+        assert_eq!(
+            quiesce.resume(MemoryDevicesQuiesceToken {
+                token: token.token + 1
+            }),
+            Err(MemoryDevicesError::TokenMismatch(token.token + 1))
+        );
+        quiesce.resume(token).unwrap();
+        assert!(!quiesce.is_quiesced());
+
+        // Every quiesce request gets its own token.
+        assert_ne!(quiesce.quiesce().unwrap(), token);
+    }
+
+    #[test]
+    #[cfg(any(feature = "balloon", feature = "faascale-mem"))]
+    fn test_memory_overlays_info() {
+        let mut overlays = MmapOverlays::default();
+        assert_eq!(
+            MemoryOverlaysInfo::from(&overlays),
+            MemoryOverlaysInfo::default()
+        );
+
+        overlays.insert(GuestAddress(0x1000), 0x2000);
+        overlays.insert(GuestAddress(0x8000), 0x1000);
+        assert_eq!(
+            MemoryOverlaysInfo::from(&overlays),
+            MemoryOverlaysInfo {
+                overlay_count: 2,
+                overlay_bytes: 0x3000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_memory_devices_verification() {
+        let mut verification = MemoryDevicesVerification::default();
+        verification.add("balloon", Vec::new());
+        assert!(verification.consistent);
+        assert!(verification.inconsistencies.is_empty());
+
+        verification.add(
+            "faascale-mem",
+            vec![("held_back_not_populated", "2 pages".to_string())],
+        );
+        assert!(!verification.consistent);
+        assert_eq!(
+            verification.inconsistencies,
+            vec![MemoryDevicesInconsistency {
+                device: "faascale-mem".to_string(),
+                check: "held_back_not_populated".to_string(),
+                detail: "2 pages".to_string(),
+            }]
+        );
+    }
+}
@@ -2,10 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 //! Auxiliary module for configuring the metrics system.
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
-use logger::METRICS;
+use logger::{StatsdSink, LOGGER, METRICS};
 use serde::{Deserialize, Serialize};
 
 use super::{open_file_nonblock, FcLineWriter};
@@ -15,6 +17,27 @@ use super::{open_file_nonblock, FcLineWriter};
 pub struct MetricsConfig {
     /// Named pipe or file used as output for metrics.
     pub metrics_path: PathBuf,
+    /// Statsd server the memory device metrics are also pushed to, at each flush.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statsd: Option<StatsdConfig>,
+}
+
+fn default_statsd_prefix() -> String {
+    "firecracker".to_owned()
+}
+
+/// Describes the statsd server the memory device metrics are pushed to.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    /// UDP address of the statsd server.
+    pub address: SocketAddr,
+    /// Prefix of the metric names.
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+    /// Tags attached to the metrics. The microVM id is attached as `vm_id`, unless set here.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
 }
 
 /// Errors associated with actions on the `MetricsConfig`.
@@ -35,13 +58,39 @@ impl Display for MetricsConfigError {
 
 /// Configures the metrics as described in `metrics_cfg`.
 pub fn init_metrics(metrics_cfg: MetricsConfig) -> std::result::Result<(), MetricsConfigError> {
+    let statsd_sink = metrics_cfg
+        .statsd
+        .map(|statsd| {
+            let mut tags = statsd.tags;
+            let instance_id = LOGGER.instance_id();
+            if !instance_id.is_empty() {
+                tags.entry("vm_id".to_owned()).or_insert(instance_id);
+            }
+            let tags = tags
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect::<Vec<_>>();
+            StatsdSink::new(statsd.address, &statsd.prefix, &tags).map_err(|err| {
+                MetricsConfigError::InitializationFailure(format!(
+                    "Cannot open the statsd socket: {}",
+                    err
+                ))
+            })
+        })
+        .transpose()?;
     let writer = FcLineWriter::new(
         open_file_nonblock(&metrics_cfg.metrics_path)
             .map_err(|err| MetricsConfigError::InitializationFailure(err.to_string()))?,
     );
     METRICS
         .init(Box::new(writer))
-        .map_err(|err| MetricsConfigError::InitializationFailure(err.to_string()))
+        .map_err(|err| MetricsConfigError::InitializationFailure(err.to_string()))?;
+    if let Some(sink) = statsd_sink {
+        METRICS
+            .init_statsd(sink)
+            .map_err(|err| MetricsConfigError::InitializationFailure(err.to_string()))?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -55,6 +104,7 @@ mod tests {
         // Error case: initializing metrics with invalid pipe returns error.
         let desc = MetricsConfig {
             metrics_path: PathBuf::from("not_found_file_metrics"),
+            statsd: None,
         };
         assert!(init_metrics(desc).is_err());
 
@@ -62,12 +112,51 @@ mod tests {
         let metrics_file = TempFile::new().unwrap();
         let desc = MetricsConfig {
             metrics_path: metrics_file.as_path().to_path_buf(),
+            statsd: None,
         };
 
         assert!(init_metrics(desc.clone()).is_ok());
         assert!(init_metrics(desc).is_err());
     }
 
+    #[test]
+    fn test_statsd_config() {
+        let cfg: MetricsConfig = serde_json::from_str(
+            r#"{
+                "metrics_path": "metrics",
+                "statsd": { "address": "127.0.0.1:8125" }
+            }"#,
+        )
+        .unwrap();
+        let statsd = cfg.statsd.unwrap();
+        assert_eq!(statsd.address, "127.0.0.1:8125".parse().unwrap());
+        assert_eq!(statsd.prefix, "firecracker");
+        assert!(statsd.tags.is_empty());
+
+        let cfg: MetricsConfig = serde_json::from_str(
+            r#"{
+                "metrics_path": "metrics",
+                "statsd": {
+                    "address": "[::1]:8125",
+                    "prefix": "fc",
+                    "tags": { "vm_id": "vm0" }
+                }
+            }"#,
+        )
+        .unwrap();
+        let statsd = cfg.statsd.unwrap();
+        assert_eq!(statsd.prefix, "fc");
+        assert_eq!(statsd.tags["vm_id"], "vm0");
+
+        // The statsd server is optional, and needs a valid address.
+        let cfg: MetricsConfig = serde_json::from_str(r#"{ "metrics_path": "metrics" }"#).unwrap();
+        assert!(cfg.statsd.is_none());
+        assert!(serde_json::from_str::<MetricsConfig>(
+            r#"{ "metrics_path": "metrics", "statsd": { "address": "statsd" } }"#
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_error_display() {
         assert_eq!(
@@ -12,8 +12,10 @@ use rate_limiter::{BucketUpdate, RateLimiter, TokenBucket};
 use serde::{Deserialize, Serialize};
 
 /// Wrapper for configuring the faascale-mem device.
+#[cfg(feature = "faascale-mem")]
 pub mod faascale_mem;
 /// Wrapper for configuring the balloon device.
+#[cfg(feature = "balloon")]
 pub mod balloon;
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
@@ -27,6 +29,8 @@ pub mod instance_info;
 pub mod logger;
 /// Wrapper for configuring the memory and CPU of the microVM.
 pub mod machine_config;
+/// Wrapper for quiescing the memory devices attached to the microVM.
+pub mod memory_devices;
 /// Wrapper for configuring the metrics.
 pub mod metrics;
 /// Wrapper for configuring the MMDS.
@@ -156,7 +160,7 @@ impl From<&RateLimiter> for RateLimiterConfig {
 
 impl RateLimiterConfig {
     // Option<T> already implements From<T> so we have to use a custom one.
-    fn into_option(self) -> Option<RateLimiterConfig> {
+    pub(crate) fn into_option(self) -> Option<RateLimiterConfig> {
         if self.bandwidth.is_some() || self.ops.is_some() {
             Some(self)
         } else {
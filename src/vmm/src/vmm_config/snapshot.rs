@@ -23,7 +23,7 @@ pub enum SnapshotType {
 /// 1) A file that contains the guest memory to be loaded,
 /// 2) An UDS where a custom page-fault handler process is listening for
 ///    the UFFD set up by Firecracker to handle its guest memory page faults.
-#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum MemBackendType {
     /// Guest memory contents will be loaded from a file.
     File,
@@ -46,6 +46,62 @@ pub struct CreateSnapshotParams {
     /// Optional field for the microVM version. The default
     /// value is the current version.
     pub version: Option<String>,
+    /// Leave the zero pages of a full snapshot as holes in the memory file and
+    /// respond with a `SnapshotCreateInfo`. Defaults to `false`.
+    #[serde(default)]
+    pub sparse: bool,
+}
+
+/// Describes the files written when creating a snapshot.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SnapshotCreateInfo {
+    /// Number of guest memory pages written to the memory file.
+    pub mem_pages_written: u64,
+    /// Number of zero guest memory pages, such as the ones depopulated by the guest, left as
+    /// holes in the memory file. Diff snapshots write every dirty page and never skip any.
+    pub mem_pages_skipped: u64,
+    /// Number of guest memory bytes written to the memory file.
+    pub mem_bytes_written: u64,
+    /// Size in bytes of the microVM state file.
+    pub snapshot_file_size: u64,
+    /// Apparent size in bytes of the guest memory file.
+    pub mem_file_size: u64,
+    /// Disk space in bytes allocated to the guest memory file.
+    pub mem_file_allocated_bytes: u64,
+}
+
+/// A range of guest physical memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SnapshotMemoryRange {
+    /// Guest physical address of the range.
+    pub start_addr: u64,
+    /// Size in bytes of the range.
+    pub size_bytes: u64,
+}
+
+/// Describes the guest memory of the snapshot the microVM was loaded from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SnapshotMemoryInfo {
+    /// Data format version of the microVM state file.
+    pub snapshot_data_version: u16,
+    /// Firecracker release matching the data format version.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firecracker_version: Option<String>,
+    /// Backend serving the guest memory.
+    pub mem_backend_type: MemBackendType,
+    /// Size in bytes of the guest memory described by the microVM state.
+    pub mem_size_bytes: u64,
+    /// Apparent size in bytes of the guest memory file. Only reported for the `File` backend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mem_file_size: Option<u64>,
+    /// Bytes of the guest memory file left as holes, which take no disk space. Only reported
+    /// for the `File` backend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mem_file_hole_bytes: Option<u64>,
+    /// Guest memory populated through the faascale-mem device when the snapshot was created.
+    /// Only reported if the microVM has a faascale-mem device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub faascale_populated_ranges: Option<Vec<SnapshotMemoryRange>>,
 }
 
 /// Stores the configuration that will be used for loading a snapshot.
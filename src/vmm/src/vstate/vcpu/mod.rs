@@ -6,7 +6,7 @@
 // found in the THIRD-PARTY file.
 
 use std::cell::Cell;
-use std::sync::atomic::{fence, Ordering};
+use std::sync::atomic::{fence, AtomicI32, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 #[cfg(test)]
 use std::sync::Mutex;
@@ -237,12 +237,19 @@ impl Vcpu {
     ) -> std::result::Result<VcpuHandle, StartThreadedError> {
         let event_sender = self.event_sender.take().expect("vCPU already started");
         let response_receiver = self.response_receiver.take().unwrap();
+        let tid = Arc::new(AtomicI32::new(0));
+        let thread_tid = tid.clone();
         let vcpu_thread = thread::Builder::new()
             .name(format!("fc_vcpu {}", self.kvm_vcpu.index))
             .spawn(move || {
                 let filter = &*seccomp_filter;
                 self.init_thread_local_data()
                     .expect("Cannot cleanly initialize vcpu TLS.");
+                // SAFETY: The call has no side effect.
+                thread_tid.store(
+                    unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t,
+                    Ordering::Release,
+                );
                 // Synchronization to make sure thread local data is initialized.
                 barrier.wait();
                 self.run(filter);
@@ -252,6 +259,7 @@ impl Vcpu {
             event_sender,
             response_receiver,
             vcpu_thread,
+            tid,
         ))
     }
 
@@ -642,6 +650,8 @@ pub struct VcpuHandle {
     // Rust JoinHandles have to be wrapped in Option if you ever plan on 'join()'ing them.
     // We want to be able to join these threads in tests.
     vcpu_thread: Option<thread::JoinHandle<()>>,
+    // Thread id of the vCPU thread, set by the thread before it waits on the start barrier.
+    tid: Arc<AtomicI32>,
 }
 
 /// Error type for [`VcpuHandle::send_event`].
@@ -661,15 +671,18 @@ impl VcpuHandle {
     /// + `event_sender`: [`Sender`] to communicate [`VcpuEvent`] to control the vcpu.
     /// + `response_received`: [`Received`] from which the vcpu's responses can be read.
     /// + `vcpu_thread`: A [`JoinHandle`] for the vcpu thread.
+    /// + `tid`: The thread id of the vcpu thread, `0` until the thread sets it.
     pub fn new(
         event_sender: Sender<VcpuEvent>,
         response_receiver: Receiver<VcpuResponse>,
         vcpu_thread: thread::JoinHandle<()>,
+        tid: Arc<AtomicI32>,
     ) -> Self {
         Self {
             event_sender,
             response_receiver,
             vcpu_thread: Some(vcpu_thread),
+            tid,
         }
     }
     /// Sends event to vCPU.
@@ -695,6 +708,14 @@ impl VcpuHandle {
     pub fn response_receiver(&self) -> &Receiver<VcpuResponse> {
         &self.response_receiver
     }
+
+    /// Returns the thread id of the vcpu thread, once the thread started.
+    pub fn tid(&self) -> Option<libc::pid_t> {
+        match self.tid.load(Ordering::Acquire) {
+            0 => None,
+            tid => Some(tid),
+        }
+    }
 }
 
 // Wait for the Vcpu thread to finish execution
@@ -1104,6 +1125,17 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_vcpu_tid() {
+        let (vcpu_handle, _vcpu_exit_evt) = vcpu_configured_for_boot();
+
+        // The vCPU thread sets its id before the start barrier.
+        // SAFETY: The call has no side effect.
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+        assert!(vcpu_handle.tid().is_some());
+        assert_ne!(vcpu_handle.tid(), Some(tid));
+    }
+
     #[test]
     fn test_vcpu_pause_resume() {
         let (vcpu_handle, vcpu_exit_evt) = vcpu_configured_for_boot();
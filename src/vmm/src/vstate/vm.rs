@@ -6,6 +6,7 @@
 // found in the THIRD-PARTY file.
 
 use std::result;
+use std::sync::Arc;
 
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
@@ -110,7 +111,7 @@ pub type Result<T> = result::Result<T, Error>;
 
 /// A wrapper around creating and using a VM.
 pub struct Vm {
-    fd: VmFd,
+    fd: Arc<VmFd>,
 
     // X86 specific fields.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -178,6 +179,12 @@ impl Vm {
     pub fn fd(&self) -> &VmFd {
         &self.fd
     }
+
+    /// Gets a handle on the kvm file descriptor of this VM, for the devices issuing VM ioctls
+    /// of their own.
+    pub fn shared_fd(&self) -> &Arc<VmFd> {
+        &self.fd
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -188,7 +195,7 @@ impl Vm {
         let vm_fd = kvm.create_vm().map_err(Error::VmFd)?;
 
         Ok(Vm {
-            fd: vm_fd,
+            fd: Arc::new(vm_fd),
             irqchip_handle: None,
         })
     }
@@ -249,7 +256,7 @@ impl Vm {
         let msrs_to_save = crate::arch::x86_64::msr::get_msrs_to_save(kvm)?;
 
         Ok(Vm {
-            fd: vm_fd,
+            fd: Arc::new(vm_fd),
             supported_cpuid,
             msrs_to_save,
         })
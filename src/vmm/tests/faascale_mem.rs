@@ -0,0 +1,2651 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![cfg(feature = "faascale-mem")]
+
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use event_manager::EventManager;
+use logger::{IncMetric, LevelFilter, LOGGER, METRICS};
+use snapshot::Persist;
+use userfaultfd::UffdBuilder;
+use utils::tempfile::TempFile;
+use utils::vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm::devices::virtio::faascale_mem::persist::FaascaleMemConstructorArgs;
+use vmm::devices::virtio::faascale_mem::test_utils::{
+    faascale_mem_device, populated_ranges, use_manual_clock, StubGuestDriver,
+};
+use vmm::devices::virtio::faascale_mem::{
+    BudgetNegotiationState, EncryptedMemoryBackend, Error as FaascaleMemError, FaascaleMem,
+    FaascaleMemCapabilities, FaascaleMemStatsFreshness, FaascaleMemThpPolicy, MemoryEncryptionKind,
+    BOOT_WARMUP_QUIET_PERIOD, CONTROL_INDEX, DEPOPULATE_INDEX, FAASCALE_STATS_INDEX,
+    POPULATE_CANARY, POPULATE_DEDUP_WINDOW, POPULATE_INDEX, QUEUE_SIZE,
+    VIRTIO_FAASCALE_MEM_BLOCK_F_PINNED, VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_1G,
+    VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_2M, VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_4K,
+    VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_SHIFT, VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+};
+use vmm::devices::virtio::pause_gate::VmPauseGate;
+use vmm::devices::virtio::VirtioDevice;
+use vmm::utilities::test_utils::faascale_mem_vmm;
+use vmm::vmm_config::faascale_mem::{
+    FaascaleMemDeviceConfig, FaascaleMemEstimateLimit, FaascaleMemExperiment,
+    FaascaleMemPolicyConfig, FaascaleMemPolicyDecision, FaascaleMemPollingAdaptation,
+    FaascaleMemPopulateMode, FaascaleMemPopulatePolicy,
+};
+use vmm::vmm_config::memory_devices::{MemoryDevicesError, MemoryDevicesQuiesceToken};
+use vmm::vmm_config::{RateLimiterConfig, TokenBucketConfig};
+
+// Where the stub driver keeps its rings and descriptor payloads.
+const DRIVER_START: GuestAddress = GuestAddress(0x400_0000);
+// Blocks handed to the device, well clear of the driver footprint.
+const BLOCKS: &[(u32, u32)] = &[(0x6000, 256), (0x6200, 16)];
+
+// Pumps the event loop until `done` holds, so that the device handles the requests in the same
+// way it would while the microVM is running.
+fn run_until(event_manager: &mut EventManager, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if done() {
+            return;
+        }
+        event_manager.run_with_timeout(10).unwrap();
+    }
+    assert!(done(), "faascale-mem device did not process the requests");
+}
+
+#[test]
+fn test_faascale_mem_populate_depopulate() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    let (start, len) = driver.footprint();
+    for &(pfn, _) in BLOCKS {
+        assert!(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT >= start.0 + len);
+    }
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    // Let the device register its queue events.
+    event_manager.run_with_timeout(10).unwrap();
+    assert!(vmm.lock().unwrap().faascale_mem_health().unwrap().healthy);
+
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    // The populate verification leaves the canary at the head of every populated block.
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+    let stats = vmm.lock().unwrap().latest_faascale_mem_stats().unwrap();
+    assert_eq!(stats.populate_verified_blocks, Some(BLOCKS.len() as u64));
+    assert_eq!(stats.populate_verification_failures, None);
+    let footprint = vmm.lock().unwrap().faascale_mem_footprint().unwrap();
+    assert_eq!(footprint.populated_mib, 1);
+    assert_eq!(footprint.pool, None);
+    let blocks = vmm.lock().unwrap().faascale_mem_blocks().unwrap();
+    assert_eq!(blocks.populated_mib, 1);
+    assert_eq!(
+        blocks
+            .blocks
+            .iter()
+            .map(|block| (block.start_pfn, block.num_pages))
+            .collect::<Vec<_>>(),
+        BLOCKS
+            .iter()
+            .map(|&(pfn, num_pages)| (u64::from(pfn), u64::from(num_pages)))
+            .collect::<Vec<_>>()
+    );
+
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    driver.check_all_used(DEPOPULATE_INDEX);
+    // Depopulated blocks read back as zero.
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), [0u8; 8]);
+    }
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_footprint()
+            .unwrap()
+            .populated_mib,
+        0
+    );
+    assert!(vmm
+        .lock()
+        .unwrap()
+        .faascale_mem_blocks()
+        .unwrap()
+        .blocks
+        .is_empty());
+
+    // Requests keep flowing after the descriptors wrap around.
+    for i in 1..=u32::from(QUEUE_SIZE) + 1 {
+        driver.populate(&*device.lock().unwrap(), &BLOCKS[1..]);
+        run_until(&mut event_manager, || {
+            u32::from(driver.used_count(POPULATE_INDEX)) == i + 1
+        });
+    }
+    assert_eq!(
+        driver.used_count(POPULATE_INDEX),
+        driver.avail_count(POPULATE_INDEX)
+    );
+}
+
+#[test]
+fn test_faascale_mem_boot_warmup() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig::default());
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let clock = use_manual_clock(&device);
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    // The warm-up goes on until the guest stops populating memory for a while.
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .boot_warmup,
+        None
+    );
+    clock.advance(BOOT_WARMUP_QUIET_PERIOD);
+    let warmup = vmm
+        .lock()
+        .unwrap()
+        .faascale_mem_config()
+        .unwrap()
+        .boot_warmup
+        .unwrap();
+    assert_eq!(warmup.populated_mib, 1);
+
+    // Later populate requests are left out of the report.
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[1..]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .boot_warmup,
+        Some(warmup)
+    );
+}
+
+#[test]
+fn test_faascale_mem_capabilities() {
+    let (vmm, _event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig::default());
+
+    // The host is probed when the device is attached to the microVM.
+    let capabilities = vmm
+        .lock()
+        .unwrap()
+        .faascale_mem_config()
+        .unwrap()
+        .capabilities
+        .unwrap();
+    let host = FaascaleMemCapabilities::probe(-1);
+    assert_eq!(capabilities.madv_populate_write, host.madv_populate_write);
+    assert_eq!(capabilities.process_madvise, host.process_madvise);
+}
+
+#[test]
+fn test_faascale_mem_populate_dedup() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let clock = use_manual_clock(&device);
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    let marker = *b"GUESTMEM";
+    let write_marker = || {
+        for &(pfn, _) in BLOCKS {
+            let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+            mem.write_obj(marker, addr).unwrap();
+        }
+    };
+    write_marker();
+
+    // A retry of the same blocks within the window completes without populating them again.
+    clock.advance(POPULATE_DEDUP_WINDOW / 2);
+    let dedup_hits = METRICS.faascale_mem.populate_dedup_hits.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    assert!(METRICS.faascale_mem.populate_dedup_hits.count() >= dedup_hits + BLOCKS.len());
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), marker);
+    }
+
+    // Past the window, the same blocks are populated again.
+    clock.advance(POPULATE_DEDUP_WINDOW);
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 3
+    });
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+    write_marker();
+
+    // Once depopulated, the same blocks are populated again, even within the window.
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 4
+    });
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+}
+
+#[test]
+fn test_faascale_mem_populate_tracker_bound() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_tracker_max_entries: Some(1),
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    let inner_block = (0x6080, 16);
+    let inner_addr = GuestAddress(u64::from(inner_block.0) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    let marker = *b"GUESTMEM";
+    let mut populate = |driver: &mut StubGuestDriver, blocks: &[(u32, u32)]| {
+        let used = driver.used_count(POPULATE_INDEX);
+        driver.populate(&*device.lock().unwrap(), blocks);
+        run_until(&mut event_manager, || {
+            driver.used_count(POPULATE_INDEX) == used + 1
+        });
+    };
+
+    // Adjacent blocks are merged into a single tracked range.
+    let merges = METRICS.faascale_mem.tracker_merges.count();
+    populate(&mut driver, &[(0x6000, 128), (0x6080, 128)]);
+    assert!(METRICS.faascale_mem.tracker_merges.count() > merges);
+    mem.write_obj(marker, inner_addr).unwrap();
+    populate(&mut driver, &[inner_block]);
+    assert_eq!(mem.read_obj::<[u8; 8]>(inner_addr).unwrap(), marker);
+
+    // Tracking another range spills the merged one, whose blocks are populated again.
+    let spills = METRICS.faascale_mem.tracker_spills.count();
+    populate(&mut driver, &[(0x6400, 16)]);
+    assert!(METRICS.faascale_mem.tracker_spills.count() > spills);
+    populate(&mut driver, &[inner_block]);
+    assert_eq!(
+        mem.read_obj::<[u8; 8]>(inner_addr).unwrap(),
+        POPULATE_CANARY
+    );
+    driver.check_all_used(POPULATE_INDEX);
+}
+
+#[test]
+fn test_faascale_mem_pin() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The guest pins the first block while populating it, the host pins the second one.
+    let (pfn, npages) = BLOCKS[0];
+    driver.populate(
+        &*device.lock().unwrap(),
+        &[
+            (pfn, npages | VIRTIO_FAASCALE_MEM_BLOCK_F_PINNED),
+            BLOCKS[1],
+        ],
+    );
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    let (pfn, npages) = BLOCKS[1];
+    vmm.lock()
+        .unwrap()
+        .update_faascale_mem_pin(pfn, npages, true)
+        .unwrap();
+    let pinned_pages = BLOCKS
+        .iter()
+        .map(|&(_, npages)| u64::from(npages))
+        .sum::<u64>();
+    assert_eq!(
+        device
+            .lock()
+            .unwrap()
+            .as_any()
+            .downcast_ref::<FaascaleMem>()
+            .unwrap()
+            .pinned_pages(),
+        pinned_pages
+    );
+
+    // Depopulating the pinned blocks is refused, their content stays in place.
+    let refusals = METRICS.faascale_mem.depopulate_pinned_refusals.count();
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert!(METRICS.faascale_mem.depopulate_pinned_refusals.count() >= refusals + BLOCKS.len());
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+
+    // The pins are saved in snapshots.
+    let state = device
+        .lock()
+        .unwrap()
+        .as_any()
+        .downcast_ref::<FaascaleMem>()
+        .unwrap()
+        .save();
+    let vm_fd = Arc::new(kvm_ioctls::Kvm::new().unwrap().create_vm().unwrap());
+    let restored = FaascaleMem::restore(
+        FaascaleMemConstructorArgs {
+            mem: mem.clone(),
+            vm_fd,
+        },
+        &state,
+    )
+    .unwrap();
+    assert_eq!(restored.pinned_pages(), pinned_pages);
+
+    // Ranges outside the guest memory cannot be pinned.
+    let end_pfn = u32::try_from(mem.last_addr().0 >> VIRTIO_FAASCALE_MEM_PFN_SHIFT).unwrap();
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .update_faascale_mem_pin(end_pfn, 2, true),
+        Err(FaascaleMemError::PinOutsideMemory)
+    ));
+
+    // Once unpinned, the blocks are depopulated.
+    for &(pfn, npages) in BLOCKS {
+        vmm.lock()
+            .unwrap()
+            .update_faascale_mem_pin(pfn, npages, false)
+            .unwrap();
+    }
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 2
+    });
+    driver.check_all_used(DEPOPULATE_INDEX);
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), [0u8; 8]);
+    }
+}
+
+#[test]
+fn test_faascale_mem_fence() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let head = |(pfn, _): (u32, u32)| {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        mem.read_obj::<[u8; 8]>(addr).unwrap()
+    };
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    assert_eq!(head(BLOCKS[0]), POPULATE_CANARY);
+
+    // The populate requests of a fenced device are acknowledged, but refused.
+    vmm.lock().unwrap().update_faascale_mem_fence(true).unwrap();
+    assert!(vmm.lock().unwrap().faascale_mem_footprint().unwrap().fenced);
+    let refusals = METRICS.faascale_mem.populate_fenced_refusals.count();
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[1..]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    assert!(METRICS.faascale_mem.populate_fenced_refusals.count() > refusals);
+    assert_eq!(head(BLOCKS[1]), [0u8; 8]);
+
+    // The guest can still give memory back.
+    driver.depopulate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert_eq!(head(BLOCKS[0]), [0u8; 8]);
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_footprint()
+            .unwrap()
+            .populated_mib,
+        0
+    );
+
+    // Once unfenced, the guest grows again.
+    vmm.lock()
+        .unwrap()
+        .update_faascale_mem_fence(false)
+        .unwrap();
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[1..]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 3
+    });
+    assert_eq!(head(BLOCKS[1]), POPULATE_CANARY);
+}
+
+#[test]
+fn test_faascale_mem_rate_limiter() {
+    // A single block every 100ms.
+    let config = FaascaleMemDeviceConfig {
+        rate_limiter: Some(RateLimiterConfig {
+            bandwidth: None,
+            ops: Some(TokenBucketConfig {
+                size: 1,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+        }),
+        ..Default::default()
+    };
+    let (vmm, mut event_manager) = faascale_mem_vmm(config);
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The second request is left in the queue.
+    let throttled = METRICS.faascale_mem.rate_limiter_throttled_events.count();
+    for &block in BLOCKS {
+        driver.populate(&*device.lock().unwrap(), &[block]);
+    }
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    assert!(METRICS.faascale_mem.rate_limiter_throttled_events.count() > throttled);
+    assert_eq!(
+        populated_ranges(&device.lock().unwrap()),
+        vec![(0x6000, 0x6100)]
+    );
+
+    // Until the rate limiter replenishes.
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    assert_eq!(
+        populated_ranges(&device.lock().unwrap()),
+        vec![(0x6000, 0x6100), (0x6200, 0x6210)]
+    );
+}
+
+#[test]
+fn test_faascale_mem_host_depopulate() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let head = |(pfn, _): (u32, u32)| {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        mem.read_obj::<[u8; 8]>(addr).unwrap()
+    };
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+
+    // The host removes the first block without the guest.
+    let (pfn, npages) = BLOCKS[0];
+    vmm.lock()
+        .unwrap()
+        .depopulate_faascale_mem(Some(pfn), Some(npages), None)
+        .unwrap();
+    assert_eq!(head(BLOCKS[0]), [0u8; 8]);
+    assert_eq!(head(BLOCKS[1]), POPULATE_CANARY);
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_footprint()
+            .unwrap()
+            .populated_mib,
+        0
+    );
+
+    // Pinned ranges are left alone.
+    let (pfn, npages) = BLOCKS[1];
+    vmm.lock()
+        .unwrap()
+        .update_faascale_mem_pin(pfn, npages, true)
+        .unwrap();
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .depopulate_faascale_mem(Some(pfn), Some(npages), None),
+        Err(FaascaleMemError::DepopulatePinned)
+    ));
+    assert_eq!(head(BLOCKS[1]), POPULATE_CANARY);
+
+    // The guest is asked to release memory through the config space.
+    vmm.lock()
+        .unwrap()
+        .depopulate_faascale_mem(None, None, Some(4))
+        .unwrap();
+    let mut release = [0u8; 4];
+    device.lock().unwrap().read_config(16, &mut release);
+    assert_eq!(release, (4u32 * 256).to_ne_bytes());
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config_space()
+            .unwrap()
+            .release_pages,
+        4 * 256
+    );
+
+    // A request gives either a whole range or an amount to release.
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .depopulate_faascale_mem(None, None, None),
+        Err(FaascaleMemError::InvalidDepopulateRequest)
+    ));
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .depopulate_faascale_mem(Some(pfn), Some(npages), Some(4)),
+        Err(FaascaleMemError::InvalidDepopulateRequest)
+    ));
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .depopulate_faascale_mem(Some(pfn), None, None),
+        Err(FaascaleMemError::InvalidDepopulateRequest)
+    ));
+}
+
+#[test]
+fn test_faascale_mem_driver_reset() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        stats_polling_interval_s: 1,
+        budget_mib: Some(2),
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let head = |(pfn, _): (u32, u32)| {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        mem.read_obj::<[u8; 8]>(addr).unwrap()
+    };
+
+    // The first driver agrees on a budget and pins the first block, the host pins the second.
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+    driver.ack_budget(&*device.lock().unwrap(), 1, 2 * 256);
+    let (pfn, npages) = BLOCKS[0];
+    driver.populate(
+        &*device.lock().unwrap(),
+        &[
+            (pfn, npages | VIRTIO_FAASCALE_MEM_BLOCK_F_PINNED),
+            BLOCKS[1],
+        ],
+    );
+    run_until(&mut event_manager, || {
+        driver.used_count(CONTROL_INDEX) == 1 && driver.used_count(POPULATE_INDEX) == 1
+    });
+    let (pfn, npages) = BLOCKS[1];
+    vmm.lock()
+        .unwrap()
+        .update_faascale_mem_pin(pfn, npages, true)
+        .unwrap();
+    assert_eq!(
+        vmm.lock().unwrap().faascale_mem_budget().unwrap().state,
+        BudgetNegotiationState::Agreed
+    );
+
+    // The guest kexecs, the driver resets the device.
+    let resets = METRICS.faascale_mem.driver_resets.count();
+    let irq_status = device.lock().unwrap().interrupt_status();
+    assert_ne!(irq_status.load(Ordering::SeqCst), 0);
+    assert!(device.lock().unwrap().reset().is_some());
+    assert!(!device.lock().unwrap().is_activated());
+    assert_eq!(irq_status.load(Ordering::SeqCst), 0);
+    assert!(METRICS.faascale_mem.driver_resets.count() > resets);
+    {
+        let locked_device = device.lock().unwrap();
+        let faascale_mem = locked_device
+            .as_any()
+            .downcast_ref::<FaascaleMem>()
+            .unwrap();
+        assert_eq!(faascale_mem.driver_resets(), 1);
+        assert_eq!(faascale_mem.pinned_pages(), u64::from(BLOCKS[1].1));
+    }
+    let budget = vmm.lock().unwrap().faascale_mem_budget().unwrap();
+    assert_eq!(budget.state, BudgetNegotiationState::Offered);
+    assert_eq!(budget.offered_epoch, 2);
+    assert_eq!(budget.agreed_mib, None);
+    assert_eq!(budget.populated_mib, 1);
+    assert_eq!(head(BLOCKS[0]), POPULATE_CANARY);
+
+    // The next driver can give back the block pinned by the previous one, but not the block
+    // pinned by the host.
+    let mut driver = StubGuestDriver::new(&mem, GuestAddress(0x500_0000), QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert_eq!(head(BLOCKS[0]), [0u8; 8]);
+    assert_eq!(head(BLOCKS[1]), POPULATE_CANARY);
+
+    driver.ack_budget(&*device.lock().unwrap(), 2, 256);
+    run_until(&mut event_manager, || driver.used_count(CONTROL_INDEX) == 1);
+    let budget = vmm.lock().unwrap().faascale_mem_budget().unwrap();
+    assert_eq!(budget.state, BudgetNegotiationState::Agreed);
+    assert_eq!(budget.agreed_mib, Some(1));
+}
+
+#[test]
+fn test_faascale_mem_depopulate_on_reset() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        depopulate_on_reset: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let head = |(pfn, _): (u32, u32)| {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        mem.read_obj::<[u8; 8]>(addr).unwrap()
+    };
+    let populated_blocks = || {
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_blocks()
+            .unwrap()
+            .blocks
+            .iter()
+            .map(|block| (block.start_pfn, block.num_pages))
+            .collect::<Vec<_>>()
+    };
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    let (pfn, npages) = BLOCKS[1];
+    vmm.lock()
+        .unwrap()
+        .update_faascale_mem_pin(pfn, npages, true)
+        .unwrap();
+
+    // The reset depopulates the memory of the driver, but for the block pinned by the host.
+    let depopulated = METRICS.faascale_mem.reset_depopulated_pages.count();
+    assert!(device.lock().unwrap().reset().is_some());
+    assert!(METRICS.faascale_mem.reset_depopulated_pages.count() >= depopulated + 256);
+    assert_eq!(head(BLOCKS[0]), [0u8; 8]);
+    assert_eq!(head(BLOCKS[1]), POPULATE_CANARY);
+    assert_eq!(populated_blocks(), [(u64::from(pfn), u64::from(npages))]);
+
+    // The next driver populates the same block right away, which is not taken for a request
+    // re-submitted by the previous driver.
+    let mut driver = StubGuestDriver::new(&mem, GuestAddress(0x500_0000), QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    assert_eq!(head(BLOCKS[0]), POPULATE_CANARY);
+    assert_eq!(populated_blocks().len(), 2);
+}
+
+#[test]
+fn test_faascale_mem_quiesce() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The populate request queued while the device is quiesced is left pending.
+    let token = vmm.lock().unwrap().quiesce_memory_devices().unwrap();
+    assert_eq!(
+        vmm.lock().unwrap().quiesce_memory_devices(),
+        Err(MemoryDevicesError::AlreadyQuiesced)
+    );
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    for _ in 0..10 {
+        event_manager.run_with_timeout(10).unwrap();
+    }
+    assert_eq!(driver.used_count(POPULATE_INDEX), 0);
+
+    // Resuming processes it, only with the token of the quiesce request.
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .resume_memory_devices(MemoryDevicesQuiesceToken {
+                token: token.token + 1
+            }),
+        Err(MemoryDevicesError::TokenMismatch(token.token + 1))
+    );
+    vmm.lock().unwrap().resume_memory_devices(token).unwrap();
+    driver.check_all_used(POPULATE_INDEX);
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+}
+
+#[test]
+fn test_faascale_mem_populate_outside_memslots() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The block runs past the end of the guest memory, none of it is populated.
+    let end_pfn = u32::try_from((mem.last_addr().0 + 1) >> VIRTIO_FAASCALE_MEM_PFN_SHIFT).unwrap();
+    let block = (end_pfn - 8, 16);
+    driver.populate(&*device.lock().unwrap(), &[block]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    assert!(populated_ranges(&device.lock().unwrap()).is_empty());
+    let addr = GuestAddress(u64::from(block.0) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), [0u8; 8]);
+}
+
+#[test]
+fn test_faascale_mem_paused_vm() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The populate request queued while the microVM is paused is deferred.
+    vmm.lock().unwrap().pause_vm().unwrap();
+    let deferred_events = METRICS.faascale_mem.paused_deferred_events.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    for _ in 0..10 {
+        event_manager.run_with_timeout(10).unwrap();
+    }
+    assert!(METRICS.faascale_mem.paused_deferred_events.count() > deferred_events);
+    device.lock().unwrap().process_deferred_events();
+    assert_eq!(driver.used_count(POPULATE_INDEX), 0);
+
+    // Processed once the microVM is resumed. The guest is not actually resumed, the device is
+    // handed a gate of its own instead.
+    let pause_gate = VmPauseGate::default();
+    device.lock().unwrap().set_pause_gate(pause_gate.clone());
+    pause_gate.set_paused(true);
+    device.lock().unwrap().process_deferred_events();
+    assert_eq!(driver.used_count(POPULATE_INDEX), 0);
+    pause_gate.set_paused(false);
+    device.lock().unwrap().process_deferred_events();
+    driver.check_all_used(POPULATE_INDEX);
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+}
+
+#[test]
+fn test_faascale_mem_latency_mode() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        latency_mode: true,
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The poller thread handles the populate kick without the event loop running.
+    let wakeups = METRICS.faascale_mem.populate_poller_wakeups.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while driver.used_count(POPULATE_INDEX) != 1 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    driver.check_all_used(POPULATE_INDEX);
+    assert!(METRICS.faascale_mem.populate_poller_wakeups.count() > wakeups);
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+
+    // The other queues are still served by the event loop.
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    driver.check_all_used(DEPOPULATE_INDEX);
+}
+
+#[test]
+fn test_faascale_mem_granularity_hints() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    // The stub driver negotiates the granularity hints along with the other features.
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    let hint = |granularity| granularity << VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_SHIFT;
+    // The 2M block spans whole huge pages whatever the host alignment of guest memory.
+    let blocks = [
+        (0x6000, 16 | hint(VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_4K)),
+        (
+            0x6400,
+            1024 | hint(VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_2M),
+        ),
+        (0x6800, 16 | hint(VIRTIO_FAASCALE_MEM_BLOCK_GRANULARITY_1G)),
+    ];
+    driver.populate(&*device.lock().unwrap(), &blocks);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    // The hint bits are not part of the page count.
+    for &(pfn, _) in &blocks {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+
+    let stats = vmm.lock().unwrap().latest_faascale_mem_stats().unwrap();
+    assert_eq!(stats.advised_4k_blocks, Some(1));
+    // Gigantic pages are not available to guest memory and always fall back, while the advice
+    // of transparent huge pages depends on the host configuration.
+    assert!(stats.granularity_fallbacks.unwrap() >= 1);
+    assert_eq!(
+        stats.advised_2m_blocks.unwrap_or(0) + stats.granularity_fallbacks.unwrap(),
+        2
+    );
+}
+
+// Log destination keeping the lines written for the test to read them back.
+#[derive(Clone, Default)]
+struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_faascale_mem_trace_ids() {
+    // No other test of this binary initializes the logger.
+    let logs = LogCapture::default();
+    LOGGER.set_max_level(LevelFilter::Debug);
+    LOGGER.init(String::new(), Box::new(logs.clone())).unwrap();
+
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE).with_trace_ids();
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // A zero trace ID leaves the block untraced.
+    let blocks = [(0x6000, 256, 0x0123_4567_89ab_cdef), (0x6200, 16, 0)];
+    driver.populate_traced(&*device.lock().unwrap(), &blocks);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    // The trace IDs are not mistaken for blocks.
+    for &(pfn, _, _) in &blocks {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+    // They reach the host logs of the traced block only.
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains(&format!(
+        "populating block: start_pfn={}, size=256, trace_id=0x0123456789abcdef\n",
+        0x6000
+    )));
+    assert!(logs.contains(&format!(
+        "populating block: start_pfn={}, size=16\n",
+        0x6200
+    )));
+    assert!(vmm.lock().unwrap().faascale_mem_health().unwrap().healthy);
+
+    // Depopulate requests keep the plain block format.
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    driver.check_all_used(DEPOPULATE_INDEX);
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), [0u8; 8]);
+    }
+}
+
+#[test]
+fn test_faascale_mem_wide_blocks() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE).with_wide_blocks();
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The flags sit in the upper half of the 64-bit page count. The last block lies beyond the
+    // guest physical address space and is skipped.
+    let pinned = u64::from(VIRTIO_FAASCALE_MEM_BLOCK_F_PINNED) << 32;
+    let blocks = [
+        (0x6000, 256 | pinned),
+        (0x6200, 16),
+        (u64::MAX >> VIRTIO_FAASCALE_MEM_PFN_SHIFT, 16),
+    ];
+    driver.populate_wide(&*device.lock().unwrap(), &blocks);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+    assert_eq!(
+        populated_ranges(&device.lock().unwrap()),
+        vec![(0x6000, 0x6100), (0x6200, 0x6210)]
+    );
+    assert_eq!(
+        device
+            .lock()
+            .unwrap()
+            .as_any()
+            .downcast_ref::<FaascaleMem>()
+            .unwrap()
+            .pinned_pages(),
+        256
+    );
+
+    // Depopulate requests use the wide blocks too, the pinned block stays in place.
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    driver.check_all_used(DEPOPULATE_INDEX);
+    assert_eq!(
+        populated_ranges(&device.lock().unwrap()),
+        vec![(0x6000, 0x6100)]
+    );
+}
+
+#[test]
+fn test_faascale_mem_experiment() {
+    let experiment = FaascaleMemExperiment {
+        variant_a: FaascaleMemPopulatePolicy::default(),
+        variant_b: FaascaleMemPopulatePolicy {
+            pre_alloc_mem: true,
+            pre_tdp_fault: false,
+        },
+        variant_b_percent: 100,
+    };
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        experiment: Some(experiment),
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let config = vmm.lock().unwrap().faascale_mem_config().unwrap();
+    assert_eq!(config.experiment, Some(experiment));
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // Every block goes to variant B, which pre-allocates the memory of the blocks.
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    for &(pfn, _) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    }
+    let stats = vmm.lock().unwrap().latest_faascale_mem_stats().unwrap();
+    let results = stats.experiment.unwrap();
+    assert_eq!(results.variant_a.populate_count, 0);
+    assert_eq!(results.variant_a.latency.count, 0);
+    assert_eq!(results.variant_b.populate_count, BLOCKS.len() as u64);
+    assert_eq!(results.variant_b.populate_fails, 0);
+    assert!(results.variant_b.page_faults > 0);
+    assert_eq!(results.variant_b.latency.count, BLOCKS.len() as u64);
+
+    // The statistics report the time spent populating, including the pre-allocation.
+    let latency = stats.populate_latency.unwrap();
+    assert_eq!(latency.total.count, BLOCKS.len() as u64);
+    assert_eq!(latency.pre_alloc_mem.count, BLOCKS.len() as u64);
+    assert_eq!(latency.pre_tdp_fault.count, 0);
+    assert_eq!(
+        latency.total.buckets.iter().sum::<u64>(),
+        latency.total.count
+    );
+    assert!(latency.total.sum_us >= latency.pre_alloc_mem.sum_us);
+}
+
+#[test]
+fn test_faascale_mem_depopulate_batching() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        thp_policy: FaascaleMemThpPolicy::Always,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let clock = use_manual_clock(&device);
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The guest depopulates 4 MiB in 256 KiB pieces, which cover at least one huge page of the
+    // host mapping.
+    let addr = |pfn: u32| GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    let start = 0x6000;
+    let huge_page_start = (start..start + 512)
+        .find(|&pfn| mem.get_host_address(addr(pfn)).unwrap() as u64 % 0x20_0000 == 0)
+        .unwrap();
+    for pfn in [start, huge_page_start] {
+        mem.write_obj(*b"GUESTW", addr(pfn)).unwrap();
+    }
+    let pieces: Vec<(u32, u32)> = (0..16).map(|i| (start + i * 64, 64)).collect();
+    let splits_avoided = METRICS.faascale_mem.thp_splits_avoided.count();
+    driver.depopulate(&*device.lock().unwrap(), &pieces);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    driver.check_all_used(DEPOPULATE_INDEX);
+
+    // The huge page is released at once instead of piece by piece.
+    assert!(METRICS.faascale_mem.thp_splits_avoided.count() > splits_avoided);
+    assert_eq!(
+        mem.read_obj::<[u8; 6]>(addr(huge_page_start)).unwrap(),
+        [0u8; 6]
+    );
+    if huge_page_start == start {
+        return;
+    }
+
+    // The pieces of the huge page the range starts in are held back, until the batching
+    // timer of 1 s expires, without the guest kicking a queue.
+    assert_eq!(mem.read_obj::<[u8; 6]>(addr(start)).unwrap(), *b"GUESTW");
+    clock.advance(Duration::from_millis(1100));
+    run_until(&mut event_manager, || {
+        mem.read_obj::<[u8; 6]>(addr(start)).unwrap() == [0u8; 6]
+    });
+
+    // The pieces held back when the microVM is snapshotted are released first.
+    mem.write_obj(*b"GUESTW", addr(start)).unwrap();
+    driver.depopulate(&*device.lock().unwrap(), &[(start, 16)]);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 2
+    });
+    assert_eq!(mem.read_obj::<[u8; 6]>(addr(start)).unwrap(), *b"GUESTW");
+    let mut locked_device = device.lock().unwrap();
+    let faascale_mem = locked_device
+        .as_mut_any()
+        .downcast_mut::<FaascaleMem>()
+        .unwrap();
+    faascale_mem.prepare_save();
+    assert_eq!(mem.read_obj::<[u8; 6]>(addr(start)).unwrap(), [0u8; 6]);
+}
+
+// Returns the `VmFlags` of the mapping of this process holding `host_addr`.
+fn vm_flags(host_addr: u64) -> String {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+    let mut in_mapping = false;
+    for line in smaps.lines() {
+        if let Some((start, end)) = line
+            .split_whitespace()
+            .next()
+            .and_then(|range| range.split_once('-'))
+        {
+            if let (Ok(start), Ok(end)) =
+                (u64::from_str_radix(start, 16), u64::from_str_radix(end, 16))
+            {
+                in_mapping = (start..end).contains(&host_addr);
+                continue;
+            }
+        }
+        if let Some(flags) = line.strip_prefix("VmFlags:") {
+            if in_mapping {
+                return flags.to_string();
+            }
+        }
+    }
+    panic!("No mapping holds {:#x}", host_addr);
+}
+
+#[test]
+fn test_faascale_mem_thp_policy() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        thp_policy: FaascaleMemThpPolicy::Threshold,
+        populate_verification: true,
+        stats_polling_interval_s: 1,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    assert_eq!(
+        device.lock().unwrap().thp_policy(),
+        FaascaleMemThpPolicy::Threshold
+    );
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The 4 MiB block covers at least one huge page, the 64 KiB one is kept on regular pages.
+    let large_block = (0x6000, 1024);
+    let small_block = (0x7000, 16);
+    let advised_bytes = METRICS.faascale_mem.thp_advised_bytes.count();
+    driver.populate(&*device.lock().unwrap(), &[large_block, small_block]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.check_all_used(POPULATE_INDEX);
+
+    assert!(METRICS.faascale_mem.thp_advised_bytes.count() >= advised_bytes + 0x20_0000);
+    // What the host actually backed with huge pages is read from the mappings on the stats
+    // timer.
+    run_until(&mut event_manager, || {
+        device.lock().unwrap().footprint().thp_backed_mib.is_some()
+    });
+    let addr = |pfn: u32| GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    let small_host_addr = mem.get_host_address(addr(small_block.0)).unwrap() as u64;
+    assert!(vm_flags(small_host_addr)
+        .split_whitespace()
+        .any(|flag| flag == "nh"));
+    for pfn in [large_block.0, small_block.0] {
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr(pfn)).unwrap(), POPULATE_CANARY);
+    }
+}
+
+// Encryption backend recording the ranges it is handed.
+#[derive(Default)]
+struct StubEncryptionBackend {
+    supported: bool,
+    populated: Arc<Mutex<Vec<(GuestAddress, u64)>>>,
+    depopulated: Arc<Mutex<Vec<(GuestAddress, u64)>>>,
+    fail_depopulate: bool,
+}
+
+impl EncryptedMemoryBackend for StubEncryptionBackend {
+    fn kind(&self) -> MemoryEncryptionKind {
+        MemoryEncryptionKind::SevSnp
+    }
+
+    fn is_supported(&self) -> bool {
+        self.supported
+    }
+
+    fn populate(&mut self, _mem: &GuestMemoryMmap, range: (GuestAddress, u64)) -> io::Result<()> {
+        self.populated.lock().unwrap().push(range);
+        Ok(())
+    }
+
+    fn depopulate(&mut self, _mem: &GuestMemoryMmap, range: (GuestAddress, u64)) -> io::Result<()> {
+        if self.fail_depopulate {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        self.depopulated.lock().unwrap().push(range);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_faascale_mem_encryption_backend() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig::default());
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let range = |&(pfn, num_pages): &(u32, u32)| {
+        (
+            GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT),
+            u64::from(num_pages) << VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+        )
+    };
+
+    // Backends for encryption technologies the host lacks are refused.
+    assert!(matches!(
+        device
+            .lock()
+            .unwrap()
+            .set_encryption_backend(Box::new(StubEncryptionBackend::default())),
+        Err(FaascaleMemError::MemoryEncryptionUnsupported)
+    ));
+    assert_eq!(device.lock().unwrap().memory_encryption(), None);
+
+    let backend = StubEncryptionBackend {
+        supported: true,
+        ..Default::default()
+    };
+    let (populated, depopulated) = (backend.populated.clone(), backend.depopulated.clone());
+    device
+        .lock()
+        .unwrap()
+        .set_encryption_backend(Box::new(backend))
+        .unwrap();
+    assert_eq!(
+        device.lock().unwrap().memory_encryption(),
+        Some(MemoryEncryptionKind::SevSnp)
+    );
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The blocks go through the backend instead of the plain population path, which would
+    // mark their heads.
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    assert_eq!(
+        *populated.lock().unwrap(),
+        BLOCKS.iter().map(range).collect::<Vec<_>>()
+    );
+    for block in BLOCKS {
+        assert_eq!(mem.read_obj::<[u8; 6]>(range(block).0).unwrap(), [0u8; 6]);
+    }
+
+    // The blocks are given back to the backend before they are released.
+    for block in BLOCKS {
+        mem.write_obj(*b"GUESTW", range(block).0).unwrap();
+    }
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert_eq!(
+        *depopulated.lock().unwrap(),
+        BLOCKS.iter().map(range).collect::<Vec<_>>()
+    );
+    for block in BLOCKS {
+        assert_eq!(mem.read_obj::<[u8; 6]>(range(block).0).unwrap(), [0u8; 6]);
+    }
+
+    // Blocks the backend cannot give back are not released.
+    device
+        .lock()
+        .unwrap()
+        .set_encryption_backend(Box::new(StubEncryptionBackend {
+            supported: true,
+            fail_depopulate: true,
+            ..Default::default()
+        }))
+        .unwrap();
+    for block in BLOCKS {
+        mem.write_obj(*b"GUESTW", range(block).0).unwrap();
+    }
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 2
+    });
+    for block in BLOCKS {
+        assert_eq!(mem.read_obj::<[u8; 6]>(range(block).0).unwrap(), *b"GUESTW");
+    }
+}
+
+#[test]
+fn test_faascale_mem_stats() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        stats_polling_interval_s: 1,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // Free memory and total memory, around a statistic unknown to the device which is skipped.
+    let unknown_tags = METRICS.faascale_mem.stats_unknown_tags.count();
+    driver.provide_stats(
+        &*device.lock().unwrap(),
+        &[(4, 0x1000), (0x42, 7), (5, 0x8000)],
+    );
+    run_until(&mut event_manager, || {
+        vmm.lock()
+            .unwrap()
+            .latest_faascale_mem_stats()
+            .unwrap()
+            .total_memory
+            .is_some()
+    });
+    let stats = vmm.lock().unwrap().latest_faascale_mem_stats().unwrap();
+    assert_eq!(stats.free_memory, Some(0x1000));
+    assert_eq!(stats.total_memory, Some(0x8000));
+    assert!(METRICS.faascale_mem.stats_unknown_tags.count() > unknown_tags);
+    // The buffer is held by the device until the next polling interval.
+    assert_eq!(driver.used_count(FAASCALE_STATS_INDEX), 0);
+
+    // The stats timer hands the buffer back to the driver.
+    run_until(&mut event_manager, || {
+        driver.used_count(FAASCALE_STATS_INDEX) == 1
+    });
+    driver.check_all_used(FAASCALE_STATS_INDEX);
+}
+
+#[test]
+fn test_faascale_mem_poll_stats_now() {
+    // The stats timer does not tick during the test.
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        stats_polling_interval_s: 60,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    driver.provide_stats(&*device.lock().unwrap(), &[(4, 0x1000)]);
+    run_until(&mut event_manager, || {
+        vmm.lock()
+            .unwrap()
+            .latest_faascale_mem_stats()
+            .unwrap()
+            .free_memory
+            .is_some()
+    });
+
+    // The buffer held by the device goes back to the driver right away. The poll is not
+    // answered while the driver has not reported, and fails once given up on.
+    let timeouts = METRICS.faascale_mem.stats_poll_timeouts.count();
+    vmm.lock().unwrap().start_faascale_mem_stats_poll().unwrap();
+    assert_eq!(driver.used_count(FAASCALE_STATS_INDEX), 1);
+    assert_eq!(
+        vmm.lock().unwrap().poll_faascale_mem_stats(false).unwrap(),
+        None
+    );
+    assert!(matches!(
+        vmm.lock().unwrap().poll_faascale_mem_stats(true),
+        Err(FaascaleMemError::StatisticsPollTimeout)
+    ));
+    assert!(METRICS.faascale_mem.stats_poll_timeouts.count() > timeouts);
+
+    // A report the driver already sent is returned without waiting for the event loop, and
+    // its buffer is held until the next collection.
+    driver.provide_stats(&*device.lock().unwrap(), &[(4, 0x2000)]);
+    vmm.lock().unwrap().start_faascale_mem_stats_poll().unwrap();
+    let stats = vmm
+        .lock()
+        .unwrap()
+        .poll_faascale_mem_stats(false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(stats.free_memory, Some(0x2000));
+    assert_eq!(driver.used_count(FAASCALE_STATS_INDEX), 1);
+}
+
+#[test]
+fn test_faascale_mem_refresh_stats() {
+    // The stats timer does not tick during the test.
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        stats_polling_interval_s: 60,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    driver.provide_stats(&*device.lock().unwrap(), &[(4, 0x1000)]);
+    run_until(&mut event_manager, || {
+        vmm.lock()
+            .unwrap()
+            .latest_faascale_mem_stats()
+            .unwrap()
+            .free_memory
+            .is_some()
+    });
+
+    // The buffer held by the device goes back to the driver without waiting for its answer.
+    vmm.lock().unwrap().refresh_faascale_mem_stats().unwrap();
+    assert_eq!(driver.used_count(FAASCALE_STATS_INDEX), 1);
+
+    // Nothing more is asked until the driver answers, which the event loop collects.
+    vmm.lock().unwrap().refresh_faascale_mem_stats().unwrap();
+    assert_eq!(driver.used_count(FAASCALE_STATS_INDEX), 1);
+    driver.provide_stats(&*device.lock().unwrap(), &[(4, 0x2000)]);
+    run_until(&mut event_manager, || {
+        vmm.lock()
+            .unwrap()
+            .latest_faascale_mem_stats()
+            .unwrap()
+            .free_memory
+            == Some(0x2000)
+    });
+}
+
+#[test]
+fn test_faascale_mem_enable_stats_after_boot() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig::default());
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The statistics queue is inert while the statistics are disabled, the buffer of the
+    // driver is held without being read.
+    driver.provide_stats(&*device.lock().unwrap(), &[(4, 0x1000)]);
+    event_manager.run_with_timeout(10).unwrap();
+    assert!(vmm.lock().unwrap().latest_faascale_mem_stats().is_err());
+    assert_eq!(driver.used_count(FAASCALE_STATS_INDEX), 0);
+
+    // Enabling the statistics hands the buffer back to the driver right away.
+    vmm.lock()
+        .unwrap()
+        .update_faascale_mem_stats_config(1)
+        .unwrap();
+    assert_eq!(driver.used_count(FAASCALE_STATS_INDEX), 1);
+    driver.provide_stats(&*device.lock().unwrap(), &[(4, 0x2000)]);
+    run_until(&mut event_manager, || {
+        vmm.lock()
+            .unwrap()
+            .latest_faascale_mem_stats()
+            .unwrap()
+            .free_memory
+            .is_some()
+    });
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .latest_faascale_mem_stats()
+            .unwrap()
+            .free_memory,
+        Some(0x2000)
+    );
+
+    // Disabling them again disarms the timer and keeps the buffer of the driver.
+    vmm.lock()
+        .unwrap()
+        .update_faascale_mem_stats_config(0)
+        .unwrap();
+    let used = driver.used_count(FAASCALE_STATS_INDEX);
+    event_manager.run_with_timeout(1100).unwrap();
+    assert_eq!(driver.used_count(FAASCALE_STATS_INDEX), used);
+    assert!(matches!(
+        vmm.lock().unwrap().start_faascale_mem_stats_poll(),
+        Err(FaascaleMemError::StatisticsDisabled)
+    ));
+}
+
+#[test]
+fn test_faascale_mem_strict_stats() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        stats_polling_interval_s: 1,
+        strict_stats: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // An unknown statistic rejects the rest of the buffer.
+    let update_fails = METRICS.faascale_mem.stats_update_fails.count();
+    driver.provide_stats(
+        &*device.lock().unwrap(),
+        &[(4, 0x1000), (0x42, 7), (5, 0x8000)],
+    );
+    run_until(&mut event_manager, || {
+        METRICS.faascale_mem.stats_update_fails.count() > update_fails
+    });
+    let stats = vmm.lock().unwrap().latest_faascale_mem_stats().unwrap();
+    assert_eq!(stats.free_memory, Some(0x1000));
+    assert_eq!(stats.total_memory, None);
+}
+
+#[test]
+fn test_faascale_mem_stats_polling_adaptation() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        stats_polling_interval_s: 1,
+        stats_polling_min_interval_ms: Some(250),
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    let adaptation = |effective_ms| {
+        Some(FaascaleMemPollingAdaptation {
+            baseline_ms: 1000,
+            effective_ms,
+            min_ms: 250,
+        })
+    };
+    let mut provide_available_mib = |available_mib: u64| {
+        let updates = METRICS.faascale_mem.stats_updates_count.count();
+        driver.provide_stats(&*device.lock().unwrap(), &[(6, available_mib << 20)]);
+        run_until(&mut event_manager, || {
+            METRICS.faascale_mem.stats_updates_count.count() > updates
+        });
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .stats_polling_adaptation
+    };
+
+    // The interval shortens while the available memory drops quickly, down to the minimum.
+    assert_eq!(provide_available_mib(100), adaptation(1000));
+    assert_eq!(provide_available_mib(80), adaptation(500));
+    assert_eq!(provide_available_mib(60), adaptation(250));
+    assert_eq!(provide_available_mib(40), adaptation(250));
+    // It goes back to the configured one once the available memory is stable.
+    assert_eq!(provide_available_mib(39), adaptation(500));
+    assert_eq!(provide_available_mib(39), adaptation(1000));
+    assert_eq!(provide_available_mib(42), adaptation(1000));
+}
+
+#[test]
+fn test_faascale_mem_policy() {
+    let program = "
+        populate_cap_mib = populated_mib + max(available_mib - 100, 0)
+        release_mib = if(available_mib < 150, 8, 0)
+        budget_mib = min(available_mib, 128)
+    ";
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        stats_polling_interval_s: 1,
+        budget_mib: Some(64),
+        policy: Some(FaascaleMemPolicyConfig {
+            program: program.to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    let mut provide_available_mib = |available_mib: u64| {
+        let runs = METRICS.faascale_mem.policy_runs.count();
+        driver.provide_stats(&*device.lock().unwrap(), &[(6, available_mib << 20)]);
+        run_until(&mut event_manager, || {
+            METRICS.faascale_mem.policy_runs.count() > runs
+        });
+        vmm.lock().unwrap().faascale_mem_config().unwrap()
+    };
+
+    // Each statistics update runs the policy, whose decisions are applied and reported.
+    let config = provide_available_mib(300);
+    assert_eq!(
+        config.policy_decision,
+        Some(FaascaleMemPolicyDecision {
+            populate_cap_mib: Some(200),
+            release_mib: Some(0),
+            budget_mib: Some(128),
+        })
+    );
+    assert_eq!(config.max_populated_mib, Some(200));
+    assert_eq!(config.budget_mib, Some(128));
+    assert_eq!(device.lock().unwrap().config_space_info().release_pages, 0);
+
+    let config = provide_available_mib(120);
+    assert_eq!(config.max_populated_mib, Some(20));
+    assert_eq!(config.budget_mib, Some(120));
+    assert_eq!(
+        device.lock().unwrap().config_space_info().release_pages,
+        8 * 256
+    );
+    assert_eq!(config.policy.unwrap().program, program);
+}
+
+#[test]
+fn test_faascale_mem_restored_stats() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        stats_polling_interval_s: 1,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    let restore = || {
+        let state = device
+            .lock()
+            .unwrap()
+            .as_any()
+            .downcast_ref::<FaascaleMem>()
+            .unwrap()
+            .save();
+        let vm_fd = Arc::new(kvm_ioctls::Kvm::new().unwrap().create_vm().unwrap());
+        FaascaleMem::restore(
+            FaascaleMemConstructorArgs {
+                mem: mem.clone(),
+                vm_fd,
+            },
+            &state,
+        )
+        .unwrap()
+    };
+
+    // Without any report in the snapshot, the host estimates the memory of the guest.
+    let mut restored = restore();
+    let populated_bytes = BLOCKS
+        .iter()
+        .map(|&(_, npages)| u64::from(npages) << VIRTIO_FAASCALE_MEM_PFN_SHIFT)
+        .sum::<u64>();
+    let stats = restored.latest_stats().unwrap();
+    assert_eq!(stats.freshness, FaascaleMemStatsFreshness::Simulated);
+    assert_eq!(stats.total_memory, Some(populated_bytes));
+    assert_eq!(stats.free_memory, Some(0));
+    assert_eq!(stats.available_memory, Some(0));
+
+    // Reported figures are kept, and only marked stale.
+    driver.provide_stats(&*device.lock().unwrap(), &[(4, 0x1000), (5, 0x8000)]);
+    run_until(&mut event_manager, || {
+        vmm.lock()
+            .unwrap()
+            .latest_faascale_mem_stats()
+            .unwrap()
+            .total_memory
+            .is_some()
+    });
+    let stats = vmm.lock().unwrap().latest_faascale_mem_stats().unwrap();
+    assert_eq!(stats.freshness, FaascaleMemStatsFreshness::Live);
+    let mut restored = restore();
+    let stats = restored.latest_stats().unwrap();
+    assert_eq!(stats.freshness, FaascaleMemStatsFreshness::RestoredStale);
+    assert_eq!(stats.free_memory, Some(0x1000));
+    assert_eq!(stats.total_memory, Some(0x8000));
+}
+
+#[test]
+fn test_faascale_mem_budget() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        stats_polling_interval_s: 1,
+        budget_mib: Some(2),
+        populate_verification: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The budget configured at boot is offered in the config space.
+    let mut offer = [0u8; 8];
+    device.lock().unwrap().read_config(8, &mut offer);
+    assert_eq!(offer[..4], (2u32 * 256).to_ne_bytes());
+    assert_eq!(offer[4..], 1u32.to_ne_bytes());
+    let config_space = vmm.lock().unwrap().faascale_mem_config_space().unwrap();
+    assert_eq!(config_space.raw.len(), 40);
+    assert!(config_space.raw.ends_with("000200000100000000000000"));
+    assert_eq!(config_space.budget_pages, 2 * 256);
+    assert_eq!(config_space.budget_epoch, 1);
+    let budget = vmm.lock().unwrap().faascale_mem_budget().unwrap();
+    assert_eq!(budget.state, BudgetNegotiationState::Offered);
+    assert_eq!(budget.agreed_mib, None);
+
+    // The guest settles for less than offered.
+    driver.ack_budget(&*device.lock().unwrap(), 1, 256);
+    run_until(&mut event_manager, || {
+        driver.used_count(CONTROL_INDEX) == 1
+    });
+    let budget = vmm.lock().unwrap().faascale_mem_budget().unwrap();
+    assert_eq!(budget.state, BudgetNegotiationState::Agreed);
+    assert_eq!(budget.agreed_mib, Some(1));
+
+    // The second block goes over the agreed budget.
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    let heads = BLOCKS.iter().map(|&(pfn, _)| {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        mem.read_obj::<[u8; 8]>(addr).unwrap()
+    });
+    assert_eq!(heads.collect::<Vec<_>>(), vec![POPULATE_CANARY, [0u8; 8]]);
+    let budget = vmm.lock().unwrap().faascale_mem_budget().unwrap();
+    assert_eq!(budget.populated_mib, 1);
+    assert_eq!(budget.violations, 1);
+
+    // A larger budget only applies once the guest acknowledges it.
+    vmm.lock().unwrap().update_faascale_mem_budget(2).unwrap();
+    assert_eq!(
+        vmm.lock().unwrap().faascale_mem_budget().unwrap().state,
+        BudgetNegotiationState::Offered
+    );
+    driver.ack_budget(&*device.lock().unwrap(), 2, 2 * 256);
+    run_until(&mut event_manager, || {
+        driver.used_count(CONTROL_INDEX) == 2
+    });
+    driver.check_all_used(CONTROL_INDEX);
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[1..]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    let addr = GuestAddress(u64::from(BLOCKS[1].0) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    assert_eq!(mem.read_obj::<[u8; 8]>(addr).unwrap(), POPULATE_CANARY);
+    let budget = vmm.lock().unwrap().faascale_mem_budget().unwrap();
+    assert_eq!(budget.agreed_mib, Some(2));
+    assert_eq!(budget.violations, 1);
+
+    // The negotiation is saved in snapshots, and the restored device keeps enforcing it.
+    let state = device
+        .lock()
+        .unwrap()
+        .as_any()
+        .downcast_ref::<FaascaleMem>()
+        .unwrap()
+        .save();
+    let vm_fd = Arc::new(kvm_ioctls::Kvm::new().unwrap().create_vm().unwrap());
+    let restored = FaascaleMem::restore(
+        FaascaleMemConstructorArgs {
+            mem: mem.clone(),
+            vm_fd,
+        },
+        &state,
+    )
+    .unwrap();
+    assert_eq!(restored.budget().unwrap(), budget);
+    let mut offer = [0u8; 8];
+    restored.read_config(8, &mut offer);
+    assert_eq!(offer[..4], (2u32 * 256).to_ne_bytes());
+    assert_eq!(offer[4..], 2u32.to_ne_bytes());
+}
+
+#[test]
+fn test_faascale_mem_update_size() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig::default());
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    // There is no guest driver to read the target yet.
+    assert!(matches!(
+        vmm.lock().unwrap().update_faascale_mem_config(16),
+        Err(FaascaleMemError::DeviceNotActive)
+    ));
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    let epoch = vmm
+        .lock()
+        .unwrap()
+        .faascale_mem_config()
+        .unwrap()
+        .config_epoch;
+    vmm.lock().unwrap().update_faascale_mem_config(16).unwrap();
+    // The guest reads the target from `num_pages`, at the start of the config space.
+    let mut num_pages = [0u8; 4];
+    device.lock().unwrap().read_config(0, &mut num_pages);
+    assert_eq!(num_pages, (16u32 * 256).to_ne_bytes());
+    let config = vmm.lock().unwrap().faascale_mem_config().unwrap();
+    assert_eq!(config.target_mib, 16);
+    assert_eq!(config.config_epoch, epoch + 1);
+
+    assert!(matches!(
+        vmm.lock().unwrap().update_faascale_mem_config(u32::MAX),
+        Err(FaascaleMemError::TooManyPagesRequested)
+    ));
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .target_mib,
+        16
+    );
+}
+
+#[test]
+fn test_faascale_mem_max_populated() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        max_populated_mib: Some(1),
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The cap applies without any budget negotiated, the second block goes over it.
+    let refusals = METRICS.faascale_mem.max_populated_refusals.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    assert_eq!(
+        populated_ranges(&device.lock().unwrap()),
+        vec![(0x6000, 0x6100)]
+    );
+    assert!(METRICS.faascale_mem.max_populated_refusals.count() > refusals);
+    let config = vmm.lock().unwrap().faascale_mem_config().unwrap();
+    assert_eq!(config.max_populated_mib, Some(1));
+    assert_eq!(config.populated_mib, 1);
+
+    // Populating the same block again adds no page, and depopulating makes room for others.
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    assert_eq!(
+        populated_ranges(&device.lock().unwrap()),
+        vec![(0x6000, 0x6100)]
+    );
+    driver.depopulate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[1..]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 3
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    assert_eq!(
+        populated_ranges(&device.lock().unwrap()),
+        vec![(0x6200, 0x6210)]
+    );
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .populated_mib,
+        0
+    );
+}
+
+#[test]
+fn test_faascale_mem_block_cache() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        block_cache_mib: Some(4),
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .block_cache_mib,
+        Some(4)
+    );
+
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    let addr = GuestAddress(0x6001 << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    mem.write_obj(0x1234_5678_9abc_def0_u64, addr).unwrap();
+
+    // The pages given back read as zero, until the guest populates the same block again.
+    driver.depopulate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert_eq!(mem.read_obj::<u64>(addr).unwrap(), 0);
+    let hits = METRICS.faascale_mem.block_cache_hits.count();
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    assert_eq!(mem.read_obj::<u64>(addr).unwrap(), 0x1234_5678_9abc_def0);
+    assert!(METRICS.faascale_mem.block_cache_hits.count() > hits);
+}
+
+#[test]
+fn test_faascale_mem_host_populate() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig::default());
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // A range is populated as given.
+    let report = vmm
+        .lock()
+        .unwrap()
+        .populate_faascale_mem(Some(BLOCKS[0].0), Some(BLOCKS[0].1), None, &[])
+        .unwrap();
+    assert_eq!(report.blocks, 1);
+    assert_eq!(report.pages, 256);
+
+    // An amount is taken from the lowest memory not populated yet.
+    let report = vmm
+        .lock()
+        .unwrap()
+        .populate_faascale_mem(None, None, Some(2), &[])
+        .unwrap();
+    assert_eq!(report.pages, 2 * 256);
+    assert_eq!(report.failed_blocks, 0);
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_footprint()
+            .unwrap()
+            .populated_mib,
+        3
+    );
+
+    // A request gives either a whole range or an amount to populate.
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .populate_faascale_mem(None, None, None, &[]),
+        Err(FaascaleMemError::InvalidPopulateRequest)
+    ));
+    assert!(matches!(
+        vmm.lock().unwrap().populate_faascale_mem(
+            Some(BLOCKS[0].0),
+            Some(BLOCKS[0].1),
+            Some(2),
+            &[]
+        ),
+        Err(FaascaleMemError::InvalidPopulateRequest)
+    ));
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .populate_faascale_mem(None, Some(BLOCKS[0].1), None, &[]),
+        Err(FaascaleMemError::InvalidPopulateRequest)
+    ));
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .populate_faascale_mem(None, None, Some(2), BLOCKS),
+        Err(FaascaleMemError::InvalidPopulateRequest)
+    ));
+
+    // A fenced device refuses to populate memory.
+    vmm.lock().unwrap().update_faascale_mem_fence(true).unwrap();
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .populate_faascale_mem(None, None, Some(2), &[]),
+        Err(FaascaleMemError::DeviceFenced)
+    ));
+}
+
+#[test]
+fn test_faascale_mem_spill() {
+    let spill = TempFile::new().unwrap();
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        spill_path: Some(spill.as_path().to_path_buf()),
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .spill_path,
+        Some(spill.as_path().to_path_buf())
+    );
+
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    let addrs: Vec<_> = BLOCKS
+        .iter()
+        .map(|block| GuestAddress(u64::from(block.0 + 1) << VIRTIO_FAASCALE_MEM_PFN_SHIFT))
+        .collect();
+    mem.write_obj(0x1234_5678_9abc_def0_u64, addrs[0]).unwrap();
+    mem.write_obj(0x0fed_cba9_8765_4321_u64, addrs[1]).unwrap();
+
+    // The pages given back read as zero, until the guest populates them again.
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert_eq!(mem.read_obj::<u64>(addrs[0]).unwrap(), 0);
+    assert_eq!(mem.read_obj::<u64>(addrs[1]).unwrap(), 0);
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_footprint()
+            .unwrap()
+            .spilled_mib,
+        Some(1)
+    );
+
+    let restored = METRICS.faascale_mem.spill_restored_pages.count();
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[1..]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    assert_eq!(mem.read_obj::<u64>(addrs[0]).unwrap(), 0);
+    assert_eq!(
+        mem.read_obj::<u64>(addrs[1]).unwrap(),
+        0x0fed_cba9_8765_4321
+    );
+
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 3
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    assert_eq!(
+        mem.read_obj::<u64>(addrs[0]).unwrap(),
+        0x1234_5678_9abc_def0
+    );
+    assert!(METRICS.faascale_mem.spill_restored_pages.count() >= restored + 272);
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_footprint()
+            .unwrap()
+            .spilled_mib,
+        Some(0)
+    );
+}
+
+#[test]
+fn test_faascale_mem_template() {
+    // The template is laid out as a snapshot memory file, the first region of the guest memory
+    // starting at its head.
+    let template = TempFile::new().unwrap();
+    let addrs: Vec<_> = BLOCKS
+        .iter()
+        .map(|block| GuestAddress(u64::from(block.0 + 1) << VIRTIO_FAASCALE_MEM_PFN_SHIFT))
+        .collect();
+    let file = template.as_file();
+    file.write_all_at(&0x1234_5678_9abc_def0_u64.to_ne_bytes(), addrs[0].0)
+        .unwrap();
+    file.write_all_at(&0x0fed_cba9_8765_4321_u64.to_ne_bytes(), addrs[1].0)
+        .unwrap();
+    // The file ends within the second block, whose last pages are only zero-filled.
+    file.set_len(u64::from(BLOCKS[1].0 + 3) << VIRTIO_FAASCALE_MEM_PFN_SHIFT)
+        .unwrap();
+
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        template_path: Some(template.as_path().to_path_buf()),
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    assert_eq!(mem.iter().next().unwrap().start_addr(), GuestAddress(0));
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .template_path,
+        Some(template.as_path().to_path_buf())
+    );
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+    let filled = METRICS.faascale_mem.template_filled_pages.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    assert_eq!(
+        mem.read_obj::<u64>(addrs[0]).unwrap(),
+        0x1234_5678_9abc_def0
+    );
+    assert_eq!(
+        mem.read_obj::<u64>(addrs[1]).unwrap(),
+        0x0fed_cba9_8765_4321
+    );
+    assert_eq!(
+        METRICS.faascale_mem.template_filled_pages.count(),
+        filled + 256 + 3
+    );
+
+    // The pages given back read as zero, and get the template contents once populated again.
+    mem.write_obj(0u64, addrs[0]).unwrap();
+    driver.depopulate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert_eq!(mem.read_obj::<u64>(addrs[0]).unwrap(), 0);
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    assert_eq!(
+        mem.read_obj::<u64>(addrs[0]).unwrap(),
+        0x1234_5678_9abc_def0
+    );
+}
+
+#[test]
+fn test_faascale_mem_lazy_populate() {
+    // Creating a userfaultfd handling the kernel faults may be refused to unprivileged users.
+    if UffdBuilder::new().user_mode_only(false).create().is_err() {
+        return;
+    }
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        populate_mode: FaascaleMemPopulateMode::Lazy,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    assert_eq!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .populate_mode,
+        FaascaleMemPopulateMode::Lazy
+    );
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+    let registered = METRICS.faascale_mem.lazy_registered_bytes.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.check_all_used(POPULATE_INDEX);
+    assert_eq!(
+        METRICS.faascale_mem.lazy_registered_bytes.count(),
+        registered + (272 << VIRTIO_FAASCALE_MEM_PFN_SHIFT)
+    );
+
+    // The first touch of a page is served by the fault handler with a zero-filled page.
+    let addr = GuestAddress(u64::from(BLOCKS[0].0 + 1) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    let faults = METRICS.faascale_mem.lazy_faults.count();
+    assert_eq!(mem.read_obj::<u64>(addr).unwrap(), 0);
+    assert_eq!(METRICS.faascale_mem.lazy_faults.count(), faults + 1);
+    mem.write_obj(0x1234_5678_9abc_def0_u64, addr).unwrap();
+
+    // The pages given back read as zero, and are no longer handed to the fault handler.
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    driver.check_all_used(DEPOPULATE_INDEX);
+    assert_eq!(mem.read_obj::<u64>(addr).unwrap(), 0);
+    assert_eq!(METRICS.faascale_mem.lazy_faults.count(), faults + 1);
+}
+
+#[test]
+fn test_faascale_mem_scrub_on_populate() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        scrub_on_populate: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    assert!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .scrub_on_populate
+    );
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    let scrubbed_bytes = METRICS.faascale_mem.scrubbed_bytes.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.check_all_used(POPULATE_INDEX);
+
+    // The blocks are left zeroed.
+    let mut len = 0;
+    for &(pfn, npages) in BLOCKS {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+        assert_eq!(mem.read_obj::<[u8; 6]>(addr).unwrap(), [0u8; 6]);
+        len += u64::from(npages) << VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+    }
+    assert!(METRICS.faascale_mem.scrubbed_bytes.count() >= scrubbed_bytes + len as usize);
+    let latency = vmm
+        .lock()
+        .unwrap()
+        .latest_faascale_mem_stats()
+        .unwrap()
+        .populate_latency
+        .unwrap();
+    assert_eq!(latency.scrub.count, BLOCKS.len() as u64);
+}
+
+#[test]
+fn test_faascale_mem_ksm_mergeable() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        ksm_mergeable: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    assert!(
+        vmm.lock()
+            .unwrap()
+            .faascale_mem_config()
+            .unwrap()
+            .ksm_mergeable
+    );
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+    let len: usize = BLOCKS
+        .iter()
+        .map(|&(_, npages)| (npages as usize) << VIRTIO_FAASCALE_MEM_PFN_SHIFT)
+        .sum();
+
+    // The populated blocks are advised mergeable, and unmergeable once given back.
+    let advised_bytes = METRICS.faascale_mem.ksm_advised_bytes.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    assert!(METRICS.faascale_mem.ksm_advised_bytes.count() >= advised_bytes + len);
+
+    let unadvised_bytes = METRICS.faascale_mem.ksm_unadvised_bytes.count();
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert!(METRICS.faascale_mem.ksm_unadvised_bytes.count() >= unadvised_bytes + len);
+    driver.check_all_used(POPULATE_INDEX);
+    driver.check_all_used(DEPOPULATE_INDEX);
+}
+
+#[test]
+fn test_faascale_mem_estimate() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        max_populated_mib: Some(2),
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // The time a scale-up takes is unknown until the guest populated memory.
+    let estimate = vmm.lock().unwrap().faascale_mem_estimate(1).unwrap();
+    assert!(estimate.satisfiable);
+    assert_eq!(estimate.additional_mib, 1);
+    assert_eq!(estimate.estimated_ms, None);
+    // The guest memory is not backed by hugetlb pages.
+    assert_eq!(estimate.hugepages_free_mib, None);
+
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[..1]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    let estimate = vmm.lock().unwrap().faascale_mem_estimate(2).unwrap();
+    assert!(estimate.satisfiable);
+    assert_eq!(estimate.populated_mib, 1);
+    assert_eq!(estimate.additional_mib, 1);
+    assert!(estimate.max_mib.unwrap() <= 2);
+    assert!(estimate.populate_mib_per_s.is_some());
+    assert!(estimate.estimated_ms.is_some());
+
+    // The cap of the host keeps the guest from growing further.
+    let estimate = vmm.lock().unwrap().faascale_mem_estimate(4).unwrap();
+    assert!(!estimate.satisfiable);
+    assert!(estimate
+        .limited_by
+        .contains(&FaascaleMemEstimateLimit::MaxPopulated));
+
+    // A fenced device cannot grow at all, but targets already reached stay satisfied.
+    vmm.lock().unwrap().update_faascale_mem_fence(true).unwrap();
+    let estimate = vmm.lock().unwrap().faascale_mem_estimate(2).unwrap();
+    assert_eq!(estimate.limited_by, vec![FaascaleMemEstimateLimit::Fenced]);
+    let estimate = vmm.lock().unwrap().faascale_mem_estimate(1).unwrap();
+    assert!(estimate.satisfiable);
+}
+
+#[test]
+fn test_faascale_mem_warm_template() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig::default());
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    // The blocks are populated once the guest driver is up.
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .populate_faascale_mem(None, None, None, BLOCKS),
+        Err(FaascaleMemError::DeviceNotActive)
+    ));
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    let prepopulated_pages = METRICS.faascale_mem.prepopulated_pages.count();
+    let report = vmm
+        .lock()
+        .unwrap()
+        .populate_faascale_mem(None, None, None, &[BLOCKS[0], BLOCKS[1], (0xffff_0000, 16)])
+        .unwrap();
+    assert_eq!(report.blocks, 2);
+    assert_eq!(report.pages, 256 + 16);
+    // The block outside of the guest memory is skipped.
+    assert_eq!(report.failed_blocks, 1);
+    assert!(METRICS.faascale_mem.prepopulated_pages.count() >= prepopulated_pages + 256 + 16);
+    assert_eq!(
+        device.lock().unwrap().populated_blocks(),
+        vec![(0x6000, 256), (0x6200, 16)]
+    );
+
+    // The guest asking for the blocks right away finds them in place.
+    let dedup_hits = METRICS.faascale_mem.populate_dedup_hits.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    assert!(METRICS.faascale_mem.populate_dedup_hits.count() >= dedup_hits + BLOCKS.len());
+
+    // Clones fault in all the populated memory, or the part of it within the blocks asked for.
+    let report = vmm.lock().unwrap().rehydrate_faascale_mem(&[]).unwrap();
+    assert_eq!(report.blocks, 2);
+    assert_eq!(report.pages, 256 + 16);
+    assert_eq!(report.failed_blocks, 0);
+    let report = vmm
+        .lock()
+        .unwrap()
+        .rehydrate_faascale_mem(&[(0x6080, 0x200)])
+        .unwrap();
+    assert_eq!(report.blocks, 2);
+    assert_eq!(report.pages, 0x80 + 16);
+
+    // A fenced device refuses to populate memory.
+    vmm.lock().unwrap().update_faascale_mem_fence(true).unwrap();
+    assert!(matches!(
+        vmm.lock()
+            .unwrap()
+            .populate_faascale_mem(None, None, None, BLOCKS),
+        Err(FaascaleMemError::DeviceFenced)
+    ));
+}
+
+#[test]
+fn test_faascale_mem_zeroed_populate() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        thp_policy: FaascaleMemThpPolicy::Always,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let addr = |pfn: u32| GuestAddress(u64::from(pfn) << VIRTIO_FAASCALE_MEM_PFN_SHIFT);
+    let marker = *b"GUESTMEM";
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE).with_zeroed();
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // A retried block is zero-filled again, although it is not populated a second time.
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    for &(pfn, _) in BLOCKS {
+        mem.write_obj(marker, addr(pfn)).unwrap();
+    }
+    let zeroed_bytes = METRICS.faascale_mem.recycled_zeroed_bytes.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    for &(pfn, _) in BLOCKS {
+        assert_eq!(mem.read_obj::<[u8; 8]>(addr(pfn)).unwrap(), [0u8; 8]);
+    }
+    assert!(
+        METRICS.faascale_mem.recycled_zeroed_bytes.count() >= zeroed_bytes + ((256 + 16) << 12)
+    );
+
+    // The piece of a huge page held back since the guest depopulated it still holds what the
+    // guest left in it, until it is populated again.
+    let (start, _) = BLOCKS[0];
+    mem.write_obj(marker, addr(start)).unwrap();
+    driver.depopulate(&*device.lock().unwrap(), &[(start, 64)]);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert_eq!(mem.read_obj::<[u8; 8]>(addr(start)).unwrap(), marker);
+    driver.populate(&*device.lock().unwrap(), &[(start, 64)]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 3
+    });
+    assert_eq!(mem.read_obj::<[u8; 8]>(addr(start)).unwrap(), [0u8; 8]);
+    driver.check_all_used(POPULATE_INDEX);
+}
+
+#[test]
+fn test_faascale_mem_ksm_idle() {
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        ksm_idle: true,
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+    let config = vmm.lock().unwrap().faascale_mem_config().unwrap();
+    assert!(config.ksm_idle);
+
+    // The guest memory is advised mergeable once the driver is up.
+    let mem_len: usize = mem.iter().map(|region| region.len() as usize).sum();
+    let advised_bytes = METRICS.faascale_mem.ksm_advised_bytes.count();
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+    assert!(METRICS.faascale_mem.ksm_advised_bytes.count() >= advised_bytes + mem_len);
+    let len: usize = BLOCKS
+        .iter()
+        .map(|&(_, npages)| (npages as usize) << VIRTIO_FAASCALE_MEM_PFN_SHIFT)
+        .sum();
+
+    // The populated blocks are advised unmergeable, and mergeable again once given back.
+    let unadvised_bytes = METRICS.faascale_mem.ksm_unadvised_bytes.count();
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    assert!(METRICS.faascale_mem.ksm_unadvised_bytes.count() >= unadvised_bytes + len);
+
+    let advised_bytes = METRICS.faascale_mem.ksm_advised_bytes.count();
+    driver.depopulate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    assert!(METRICS.faascale_mem.ksm_advised_bytes.count() >= advised_bytes + len);
+    driver.check_all_used(POPULATE_INDEX);
+    driver.check_all_used(DEPOPULATE_INDEX);
+}
+
+#[test]
+fn test_verify_memory_devices() {
+    let spill = TempFile::new().unwrap();
+    let (vmm, mut event_manager) = faascale_mem_vmm(FaascaleMemDeviceConfig {
+        spill_path: Some(spill.as_path().to_path_buf()),
+        ..Default::default()
+    });
+    let mem = vmm.lock().unwrap().guest_memory().clone();
+    let device = faascale_mem_device(&vmm.lock().unwrap());
+
+    let mut driver = StubGuestDriver::new(&mem, DRIVER_START, QUEUE_SIZE);
+    driver.activate(&mut *device.lock().unwrap()).unwrap();
+    event_manager.run_with_timeout(10).unwrap();
+
+    // Spilling and reading back the blocks keeps the trackers in step.
+    driver.populate(&*device.lock().unwrap(), BLOCKS);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 1
+    });
+    driver.depopulate(&*device.lock().unwrap(), &BLOCKS[1..]);
+    run_until(&mut event_manager, || {
+        driver.used_count(DEPOPULATE_INDEX) == 1
+    });
+    let verification = vmm.lock().unwrap().verify_memory_devices();
+    assert!(verification.consistent, "{:?}", verification);
+    driver.populate(&*device.lock().unwrap(), &BLOCKS[1..]);
+    run_until(&mut event_manager, || {
+        driver.used_count(POPULATE_INDEX) == 2
+    });
+    assert!(vmm.lock().unwrap().verify_memory_devices().consistent);
+
+    // A device resumed behind the back of the quiesce request.
+    let token = vmm.lock().unwrap().quiesce_memory_devices().unwrap();
+    assert!(vmm.lock().unwrap().verify_memory_devices().consistent);
+    device.lock().unwrap().set_quiesced(false);
+    let verification = vmm.lock().unwrap().verify_memory_devices();
+    assert!(!verification.consistent);
+    assert_eq!(verification.inconsistencies.len(), 1);
+    assert_eq!(verification.inconsistencies[0].device, "faascale-mem");
+    assert_eq!(verification.inconsistencies[0].check, "quiesce_state");
+
+    vmm.lock().unwrap().resume_memory_devices(token).unwrap();
+    assert!(vmm.lock().unwrap().verify_memory_devices().consistent);
+}
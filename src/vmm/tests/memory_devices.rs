@@ -0,0 +1,357 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Simulation of a guest driving the balloon and the faascale-mem devices over the same guest
+//! memory.
+//!
+//! The guest hands each page to at most one device at a time: it populates and depopulates
+//! blocks through faascale-mem, and inflates and deflates the balloon with other pages, all
+//! within one window of its memory. The simulation keeps track of who owns every page of the
+//! window and of what the guest last wrote in it, and checks after every request that neither
+//! device touched the pages of the other one, and that the faascale-mem device accounts for
+//! exactly the pages populated through it.
+
+#![cfg(all(feature = "balloon", feature = "faascale-mem"))]
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use utils::vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use vmm::devices::virtio::balloon::test_utils::{check_request_completion, set_request};
+use vmm::devices::virtio::faascale_mem::test_utils::{populated_ranges, StubGuestDriver};
+use vmm::devices::virtio::faascale_mem::{
+    FaascaleMem, DEPOPULATE_INDEX, POPULATE_INDEX, VIRTIO_FAASCALE_MEM_PFN_SHIFT,
+};
+use vmm::devices::virtio::test_utils::{single_region_mem, VirtQueue};
+use vmm::devices::virtio::{
+    Balloon, BalloonDeflatePrefetch, VirtioDevice, DEFLATE_INDEX, INFLATE_INDEX,
+};
+use vmm::vmm_config::faascale_mem::{FaascaleMemBuilder, FaascaleMemDeviceConfig};
+
+// The stub faascale-mem driver needs more room than `default_mem` has.
+const MEM_SIZE: usize = 0x200_0000;
+// Where the balloon rings and the pfns of its requests are laid out.
+const BALLOON_QUEUES_START: u64 = 0x1_0000;
+const BALLOON_QUEUE_AREA_SIZE: u64 = 0x1_0000;
+const BALLOON_PFNS: GuestAddress = GuestAddress(0x8_0000);
+const BALLOON_QUEUE_SIZE: u16 = 256;
+// Where the stub faascale-mem driver keeps its rings and descriptor payloads.
+const DRIVER_START: GuestAddress = GuestAddress(0x10_0000);
+const DRIVER_QUEUE_SIZE: u16 = 256;
+// Pages the guest hands to the devices, clear of the rings.
+const WINDOW_START: u32 = 0x1000;
+const WINDOW_PAGES: u32 = 64;
+// Longest run of pages handed over in one request.
+const MAX_RUN: u32 = 8;
+// Offset in each page of the value last written by the guest, past the mark the faascale-mem
+// device leaves at the head of the blocks it populates.
+const CONTENT_OFFSET: u64 = 0x100;
+const PAGE_SIZE: u64 = 1 << VIRTIO_FAASCALE_MEM_PFN_SHIFT;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Owner {
+    Guest,
+    FaascaleMem,
+    Balloon,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Page {
+    owner: Owner,
+    // Value the page holds at `CONTENT_OFFSET`, zero once released to the host.
+    content: u64,
+}
+
+const UNTOUCHED_PAGE: Page = Page {
+    owner: Owner::Guest,
+    content: 0,
+};
+
+// Deterministic pseudo-random sequence, so that failures can be replayed.
+struct XorShift(u64);
+
+impl XorShift {
+    fn below(&mut self, bound: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % u64::from(bound)) as u32
+    }
+}
+
+struct Simulation<'a> {
+    mem: &'a GuestMemoryMmap,
+    balloon: Balloon,
+    // Inflate and deflate queues, and the number of requests made on each.
+    balloon_queues: Vec<VirtQueue<'a>>,
+    balloon_requests: [usize; 2],
+    faascale_mem: Arc<Mutex<FaascaleMem>>,
+    driver: StubGuestDriver<'a>,
+    pages: BTreeMap<u32, Page>,
+    generation: u64,
+}
+
+impl<'a> Simulation<'a> {
+    fn new(mem: &'a GuestMemoryMmap) -> Self {
+        let mut balloon =
+            Balloon::new(0, false, 0, false, BalloonDeflatePrefetch::None, false, 0).unwrap();
+        let balloon_queues = [INFLATE_INDEX, DEFLATE_INDEX]
+            .iter()
+            .map(|&index| {
+                VirtQueue::new(
+                    GuestAddress(BALLOON_QUEUES_START + index as u64 * BALLOON_QUEUE_AREA_SIZE),
+                    mem,
+                    BALLOON_QUEUE_SIZE,
+                )
+            })
+            .collect::<Vec<_>>();
+        balloon.set_acked_features(balloon.avail_features());
+        for (queue, virt_queue) in balloon.queues_mut().iter_mut().zip(balloon_queues.iter()) {
+            *queue = virt_queue.create_queue();
+        }
+        balloon.activate(mem.clone()).unwrap();
+
+        let mut builder = FaascaleMemBuilder::new();
+        builder.set(FaascaleMemDeviceConfig::default()).unwrap();
+        let faascale_mem = builder.get().unwrap().clone();
+        let driver = StubGuestDriver::new(mem, DRIVER_START, DRIVER_QUEUE_SIZE);
+        driver.activate(&mut *faascale_mem.lock().unwrap()).unwrap();
+
+        let pages = (WINDOW_START..WINDOW_START + WINDOW_PAGES)
+            .map(|pfn| (pfn, UNTOUCHED_PAGE))
+            .collect();
+
+        Simulation {
+            mem,
+            balloon,
+            balloon_queues,
+            balloon_requests: [0; 2],
+            faascale_mem,
+            driver,
+            pages,
+            generation: 0,
+        }
+    }
+
+    // Returns the pages from `start` on, at most `len` of them, as long as they belong to
+    // `owner`.
+    fn run(&self, start: u32, len: u32, owner: Owner) -> Option<(u32, u32)> {
+        let len = (start..start + len)
+            .take_while(|pfn| self.pages.get(pfn).map(|page| page.owner) == Some(owner))
+            .count() as u32;
+        (len > 0).then_some((start, len))
+    }
+
+    // The guest writes a new value in a page it can use.
+    fn touch(&mut self, pfn: u32) {
+        let page = self.pages.get_mut(&pfn).unwrap();
+        assert_ne!(page.owner, Owner::Balloon);
+        self.generation += 1;
+        page.content = self.generation;
+        self.mem
+            .write_obj(page.content, page_address(pfn, CONTENT_OFFSET))
+            .unwrap();
+    }
+
+    fn hand_over(&mut self, (start, len): (u32, u32), from: Owner, to: Owner) {
+        for pfn in start..start + len {
+            let page = self.pages.get_mut(&pfn).unwrap();
+            assert_eq!(page.owner, from);
+            page.owner = to;
+        }
+    }
+
+    // Forgets what the pages held, as the host released them.
+    fn released(&mut self, (start, len): (u32, u32)) {
+        for pfn in start..start + len {
+            self.pages.get_mut(&pfn).unwrap().content = 0;
+        }
+    }
+
+    fn populate(&mut self, block: (u32, u32)) {
+        self.hand_over(block, Owner::Guest, Owner::FaascaleMem);
+        {
+            let mut faascale_mem = self.faascale_mem.lock().unwrap();
+            self.driver.populate(&*faascale_mem, &[block]);
+            faascale_mem.process_virtio_queues();
+        }
+        // The guest uses the memory it populated.
+        for pfn in block.0..block.0 + block.1 {
+            self.touch(pfn);
+        }
+    }
+
+    fn depopulate(&mut self, block: (u32, u32)) {
+        self.hand_over(block, Owner::FaascaleMem, Owner::Guest);
+        {
+            let mut faascale_mem = self.faascale_mem.lock().unwrap();
+            self.driver.depopulate(&*faascale_mem, &[block]);
+            faascale_mem.process_virtio_queues();
+        }
+        self.released(block);
+    }
+
+    fn inflate(&mut self, pages: &[(u32, u32)]) {
+        for &run in pages {
+            self.hand_over(run, Owner::Guest, Owner::Balloon);
+        }
+        self.balloon_request(INFLATE_INDEX, pages);
+        for &run in pages {
+            self.released(run);
+        }
+    }
+
+    fn deflate(&mut self, pages: &[(u32, u32)]) {
+        for &run in pages {
+            self.hand_over(run, Owner::Balloon, Owner::Guest);
+        }
+        self.balloon_request(DEFLATE_INDEX, pages);
+    }
+
+    fn balloon_request(&mut self, queue_index: usize, pages: &[(u32, u32)]) {
+        let pfns = pages
+            .iter()
+            .flat_map(|&(start, len)| start..start + len)
+            .collect::<Vec<_>>();
+        for (i, pfn) in pfns.iter().enumerate() {
+            self.mem
+                .write_obj(*pfn, BALLOON_PFNS.unchecked_add(i as u64 * 4))
+                .unwrap();
+        }
+
+        let queue = &self.balloon_queues[queue_index];
+        let idx = self.balloon_requests[queue_index];
+        set_request(queue, idx, BALLOON_PFNS.0, pfns.len() as u32 * 4, 0);
+        self.balloon.process_virtio_queues();
+        check_request_completion(queue, idx);
+        self.balloon_requests[queue_index] += 1;
+    }
+
+    // Checks that the pages hold what their owner left in them, that the faascale-mem device
+    // accounts for exactly the pages populated through it, and that the cross-checks of both
+    // devices pass.
+    fn check(&self) {
+        for (&pfn, page) in self.pages.iter() {
+            assert_eq!(
+                self.mem
+                    .read_obj::<u64>(page_address(pfn, CONTENT_OFFSET))
+                    .unwrap(),
+                page.content,
+                "unexpected content in page {:#x} owned by {:?}",
+                pfn,
+                page.owner
+            );
+        }
+
+        let mut expected: Vec<(u64, u64)> = Vec::new();
+        for (&pfn, page) in self.pages.iter() {
+            if page.owner != Owner::FaascaleMem {
+                continue;
+            }
+            match expected.last_mut() {
+                Some((_, end)) if *end == u64::from(pfn) => *end += 1,
+                _ => expected.push((u64::from(pfn), u64::from(pfn) + 1)),
+            }
+        }
+        assert_eq!(
+            populated_ranges(&self.faascale_mem.lock().unwrap()),
+            expected
+        );
+
+        let mem_size_mib = MEM_SIZE as u64 >> 20;
+        let failed = self.balloon.verify(mem_size_mib);
+        assert!(failed.is_empty(), "{:?}", failed);
+        let failed = self.faascale_mem.lock().unwrap().verify(mem_size_mib);
+        assert!(failed.is_empty(), "{:?}", failed);
+
+        self.driver.check_all_used(POPULATE_INDEX);
+        self.driver.check_all_used(DEPOPULATE_INDEX);
+    }
+}
+
+fn page_address(pfn: u32, offset: u64) -> GuestAddress {
+    GuestAddress(u64::from(pfn) * PAGE_SIZE + offset)
+}
+
+#[test]
+fn test_memory_devices_adjacent_ranges() {
+    let mem = single_region_mem(MEM_SIZE);
+    let mut sim = Simulation::new(&mem);
+    for pfn in WINDOW_START..WINDOW_START + WINDOW_PAGES {
+        sim.touch(pfn);
+    }
+
+    // The balloon takes the pages right around a populated block, in one request.
+    let block = (WINDOW_START + 8, 8);
+    sim.populate(block);
+    sim.inflate(&[(WINDOW_START + 6, 2), (WINDOW_START + 16, 2)]);
+    sim.check();
+
+    // Depopulating the middle of the block leaves its ends and the balloon alone.
+    sim.depopulate((WINDOW_START + 10, 4));
+    sim.check();
+
+    // The pages the balloon gives back are populated along with the depopulated ones.
+    sim.deflate(&[(WINDOW_START + 6, 2), (WINDOW_START + 16, 2)]);
+    sim.populate((WINDOW_START + 6, 2));
+    sim.populate((WINDOW_START + 10, 4));
+    sim.populate((WINDOW_START + 16, 2));
+    sim.check();
+
+    // The balloon takes pages depopulated right before.
+    sim.depopulate((WINDOW_START + 6, 12));
+    sim.inflate(&[(WINDOW_START + 6, 12)]);
+    sim.check();
+}
+
+#[test]
+fn test_memory_devices_interleaved_requests() {
+    const STEPS: usize = 400;
+
+    let mem = single_region_mem(MEM_SIZE);
+    let mut sim = Simulation::new(&mem);
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    let mut requests = [0usize; 4];
+
+    for _ in 0..STEPS {
+        let start = WINDOW_START + rng.below(WINDOW_PAGES);
+        let len = 1 + rng.below(MAX_RUN);
+        match rng.below(5) {
+            0 => {
+                if let Some(block) = sim.run(start, len, Owner::Guest) {
+                    sim.populate(block);
+                    requests[0] += 1;
+                }
+            }
+            1 => {
+                if let Some(block) = sim.run(start, len, Owner::FaascaleMem) {
+                    sim.depopulate(block);
+                    requests[1] += 1;
+                }
+            }
+            2 => {
+                if let Some(run) = sim.run(start, len, Owner::Guest) {
+                    sim.inflate(&[run]);
+                    requests[2] += 1;
+                }
+            }
+            3 => {
+                if let Some(run) = sim.run(start, len, Owner::Balloon) {
+                    sim.deflate(&[run]);
+                    requests[3] += 1;
+                }
+            }
+            _ => {
+                if sim.run(start, 1, Owner::Balloon).is_none() {
+                    sim.touch(start);
+                }
+            }
+        }
+        sim.check();
+    }
+
+    // Every kind of request was exercised, without wrapping the balloon queues around.
+    assert!(requests.iter().all(|&count| count > 0), "{:?}", requests);
+    assert!(requests[2] < usize::from(BALLOON_QUEUE_SIZE));
+    assert!(requests[3] < usize::from(BALLOON_QUEUE_SIZE));
+}
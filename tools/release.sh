@@ -151,7 +151,11 @@ for file in "${ARTIFACTS[@]}"; do
     cp -v "$CARGO_TARGET_DIR/$file" "$RELEASE_DIR/$file-$SUFFIX"
     strip-and-split-debuginfo "$RELEASE_DIR/$file-$SUFFIX"
 done
-cp -v "resources/seccomp/$CARGO_TARGET.json" "$RELEASE_DIR/seccomp-filter-$SUFFIX.json"
+# The filter of the VMM thread of a microVM with a faascale-mem device is the `vmm` one extended
+# with the rules of the device, as in the build script of the vmm crate.
+jq --slurpfile faascale_mem "resources/seccomp/faascale_mem/$CARGO_TARGET.json" \
+    '. + {vmm_faascale_mem: (.vmm | .filter += $faascale_mem[0].vmm_faascale_mem.filter)}' \
+    "resources/seccomp/$CARGO_TARGET.json" >"$RELEASE_DIR/seccomp-filter-$SUFFIX.json"
 # Copy over arch independent assets
 cp -v -t "$RELEASE_DIR" LICENSE NOTICE THIRD-PARTY
 check_swagger_artifact src/api_server/swagger/firecracker.yaml "$VERSION"